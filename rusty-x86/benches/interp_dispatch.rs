@@ -0,0 +1,61 @@
+//! The interpreter on a hot loop, decoding every instruction as it goes (`run`) vs reusing the decoded blocks
+//! (`run_cached`)
+//!
+//! `cargo bench --no-default-features --features interp --bench interp_dispatch`
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use rusty_x86::assemble_x86;
+use rusty_x86::handler::NullHandler;
+use rusty_x86::interp::{Interpreter, StepResult};
+use rusty_x86::types::FullSizeGeneralPurposeRegister::*;
+
+const CODE_ADDR: u32 = 0x1000;
+const STACK_TOP: u32 = 0x8000;
+
+fn interpreter() -> Interpreter<NullHandler> {
+    let code = assemble_x86!(
+        ; mov ecx, 1000
+        ; xor eax, eax
+        ; ->again:
+        ; mov edx, ecx
+        ; lea ebx, [eax + edx * 4 + 3]
+        ; mov esi, ebx
+        ; lea eax, [esi + 1]
+        ; mov edi, 0x2000
+        ; mov [edi], eax
+        ; dec ecx
+        ; jnz ->again
+        ; ret
+    );
+    let mut memory = vec![0; 0x8000];
+    memory[CODE_ADDR as usize..][..code.len()].copy_from_slice(&code);
+    Interpreter::new(memory, NullHandler)
+}
+
+fn run(interp: &mut Interpreter<NullHandler>, cached: bool) {
+    interp.context.eip = CODE_ADDR;
+    interp.context.set_gp_reg(ESP, STACK_TOP - 4);
+    let result = if cached {
+        interp.run_cached(usize::MAX)
+    } else {
+        interp.run(usize::MAX)
+    };
+    assert_eq!(result, StepResult::Returned);
+    black_box(interp.context.get_gp_reg(EAX));
+}
+
+fn dispatch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("hot_loop");
+
+    let mut interp = interpreter();
+    group.bench_function("decode_every_step", |b| b.iter(|| run(&mut interp, false)));
+
+    // the blocks are decoded on the first iteration, the rest only look them up
+    let mut interp = interpreter();
+    group.bench_function("cached_blocks", |b| b.iter(|| run(&mut interp, true)));
+
+    group.finish();
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
@@ -0,0 +1,21 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // catches the host faults on the guest memory (see src/runtime.rs)
+    #[cfg(feature = "llvm")]
+    if std::env::var_os("CARGO_CFG_UNIX").is_some() {
+        println!("cargo:rerun-if-changed=src/fault_guard.c");
+
+        cc::Build::new()
+            .file("src/fault_guard.c")
+            .warnings_into_errors(true)
+            .compile("rusty_x86_fault_guard");
+    }
+
+    // tests/capi.rs builds the C API test program for the same target
+    #[cfg(feature = "capi")]
+    println!(
+        "cargo:rustc-env=RUSTY_X86_TARGET={}",
+        std::env::var("TARGET").unwrap()
+    );
+}
@@ -0,0 +1,69 @@
+//! Runs a DOS .COM program (see src/dos.rs), with the console on stdin & stdout
+//!
+//! ```sh
+//! echo DOS | cargo run --example dos_com -- rusty-x86/tests/fixtures/hello.com
+//! ```
+//!
+//! The whole stdin is read up front, and a newline is the Enter (`\r`) to the program. Exits with the exit code of
+//! the program
+
+use std::io::{self, Read, Write};
+use std::process::exit;
+
+use rusty_x86::config::Recompiler;
+use rusty_x86::dos::DosHandler;
+use rusty_x86::ir::CodeMode;
+use rusty_x86::runtime::ExitReason;
+
+fn main() {
+    let path = match std::env::args().nth(1) {
+        Some(path) => path,
+        None => {
+            eprintln!("usage: dos_com <program.com>");
+            exit(2);
+        }
+    };
+    let program = std::fs::read(&path).unwrap_or_else(|e| {
+        eprintln!("could not read {}: {}", path, e);
+        exit(2);
+    });
+
+    let mut input = Vec::new();
+    io::stdin().read_to_end(&mut input).unwrap();
+    let input: Vec<u8> = input
+        .into_iter()
+        .map(|c| if c == b'\n' { b'\r' } else { c })
+        .collect();
+
+    let mut runtime = Recompiler::builder()
+        .code_mode(CodeMode::Bits16)
+        .build_runtime(DosHandler::new(&input))
+        .unwrap();
+    let entry = runtime.load_com(&program).unwrap_or_else(|e| {
+        eprintln!("could not load {}: {}", path, e);
+        exit(2);
+    });
+    let exit_reason = runtime.run(entry);
+
+    let output: Vec<u8> = runtime
+        .handler
+        .output
+        .iter()
+        .copied()
+        .filter(|&c| c != b'\r')
+        .collect();
+    io::stdout().write_all(&output).unwrap();
+    println!();
+
+    match exit_reason {
+        ExitReason::GuestExit { code } => exit(code as i32),
+        ExitReason::Returned => exit(0),
+        exit_reason => {
+            eprintln!(
+                "stopped at 0x{:04x}: {:?}",
+                runtime.context.eip, exit_reason
+            );
+            exit(1);
+        }
+    }
+}
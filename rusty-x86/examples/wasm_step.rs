@@ -0,0 +1,60 @@
+//! Single-stepping x86 code in the browser
+//!
+//! ```sh
+//! cargo build --example wasm_step --no-default-features --features wasm --target wasm32-unknown-unknown
+//! wasm-bindgen --target web --out-dir pkg target/wasm32-unknown-unknown/debug/examples/wasm_step.wasm
+//! ```
+//!
+//! ```js
+//! const cpu = new Stepper(memory, 0x1000, 0x8000);
+//! console.log(JSON.parse(cpu.step())); // {"result":"continue","diff":{"eax":[0,42],"eip":[4096,4101]}}
+//! ```
+
+use rusty_x86::handler::NullHandler;
+use rusty_x86::interp::{context_diff_json, Interpreter, StepResult};
+use rusty_x86::types::FullSizeGeneralPurposeRegister;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+pub struct Stepper {
+    interp: Interpreter<NullHandler>,
+}
+
+#[wasm_bindgen]
+impl Stepper {
+    /// `memory` is the guest memory starting at address 0
+    #[wasm_bindgen(constructor)]
+    pub fn new(memory: Vec<u8>, eip: u32, esp: u32) -> Stepper {
+        let mut interp = Interpreter::new(memory, NullHandler);
+        interp.context.eip = eip;
+        interp
+            .context
+            .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, esp);
+        Stepper { interp }
+    }
+
+    /// Executes one instruction, returns `{"result": ..., "diff": {registers that changed}}`
+    pub fn step(&mut self) -> String {
+        let before = self.interp.context.clone();
+        let result = match self.interp.step() {
+            StepResult::Continue => "continue".to_string(),
+            StepResult::Returned => "returned".to_string(),
+            StepResult::HostRequest => "host_request".to_string(),
+            StepResult::GuestExit { code } => format!("exit: {}", code),
+            StepResult::Fault(fault) => format!("fault: {:?}", fault),
+        };
+        format!(
+            "{{\"result\":\"{}\",\"diff\":{}}}",
+            result,
+            context_diff_json(&before, &self.interp.context)
+        )
+    }
+
+    pub fn eip(&self) -> u32 {
+        self.interp.context.eip
+    }
+
+    pub fn memory(&self) -> Vec<u8> {
+        self.interp.memory.clone()
+    }
+}
@@ -0,0 +1,158 @@
+#ifndef RUSTY_X86_H
+#define RUSTY_X86_H
+
+/*
+ * Generated by cbindgen from src/capi.rs, do not edit!
+ * Regenerate with `RUSTY_X86_BLESS=1 cargo test --features capi --test capi`
+ */
+
+#include <stdint.h>
+#include <stddef.h>
+
+#define RUSTY_X86_PROT_READ 1
+
+#define RUSTY_X86_PROT_WRITE 2
+
+#define RUSTY_X86_PROT_EXECUTE 4
+
+// EFLAGS.IF, kept outside of `CpuContext::flags`
+#define EFLAGS_IF_BIT 9
+
+// EFLAGS.AC, also kept outside of `CpuContext::flags` (which is one byte per `Flag`, and all 8 are taken)
+#define EFLAGS_AC_BIT 18
+
+// Bit 1 of EFLAGS reads as 1
+#define EFLAGS_FIXED (1 << 1)
+
+// The value of a poisoned flag in `CpuContext::flags`, only stored with `UndefinedFlagsPolicy::Strict`
+#define FLAG_UNDEFINED 255
+
+// All the exceptions masked, 64-bit precision, rounding to nearest
+#define RUSTY_X86_FPU_CONTROL_DEFAULT 895
+
+// The power-on MXCSR: all the exceptions masked, rounding to nearest
+#define RUSTY_X86_MXCSR_DEFAULT 8064
+
+// Generated code is running normally
+#define RUSTY_X86_EXIT_NONE 0
+
+// The host (a runtime helper) asked to stop the execution
+#define RUSTY_X86_EXIT_HOST_REQUEST 1
+
+// The guest code raised an exception (see `CpuContext::fault_vector`)
+#define RUSTY_X86_EXIT_FAULT 2
+
+// The guest ended itself (`exit`, `ExitProcess`) with `CpuContext::exit_code`
+#define RUSTY_X86_EXIT_GUEST 3
+
+// The #PF error code bit telling that the fault happened fetching an instruction (`CpuContext::fault_error_code`)
+#define RUSTY_X86_PF_INSTRUCTION_FETCH (1 << 4)
+
+typedef enum RustyX86ExitReason {
+  // The entry function returned
+  RUSTY_X86_EXIT_REASON_RETURNED = 0,
+  // A callback set `exit` in the context (or there was an interrupt without a callback)
+  RUSTY_X86_EXIT_REASON_HOST_REQUEST = 1,
+  // The guest raised an exception, see `fault_vector` & `fault_address` in the context
+  RUSTY_X86_EXIT_REASON_FAULT = 2,
+  // The guest ended itself, see `exit_code` in the context
+  RUSTY_X86_EXIT_REASON_GUEST_EXIT = 3,
+} RustyX86ExitReason;
+
+typedef enum RustyX86Status {
+  RUSTY_X86_STATUS_OK = 0,
+  // Null pointer or garbage in the flags
+  RUSTY_X86_STATUS_INVALID_ARGUMENT = 1,
+  // The host OS didn't give us the memory
+  RUSTY_X86_STATUS_MAP_FAILED = 2,
+  // Something panicked inside of the call (including the callbacks)
+  RUSTY_X86_STATUS_PANIC = 3,
+} RustyX86Status;
+
+// Opaque handle
+typedef struct RustyX86Runtime RustyX86Runtime;
+
+typedef struct RustyX86CpuContext {
+  uint32_t gp_regs[8];
+  uint8_t flags[8];
+  uint32_t eip;
+  uint32_t exit;
+  uint32_t fault_vector;
+  uint32_t fault_address;
+  uint64_t fpu_regs[8];
+  uint16_t fpu_control;
+  uint16_t fpu_status;
+  uint16_t fpu_tag;
+  uint64_t xmm_regs[16];
+  uint32_t mxcsr;
+  uint32_t flag_sources[8];
+  uint32_t fault_site;
+  uint32_t interrupt_flag;
+  uint32_t fault_error_code;
+  uint32_t alignment_check;
+  uint32_t eflags;
+  uint32_t flag_storage;
+  uint32_t shadow_flags;
+  uint32_t exit_code;
+} RustyX86CpuContext;
+
+typedef void (*RustyX86InterruptCallback)(void *user_data,
+                                          struct RustyX86CpuContext *ctx,
+                                          uint8_t vector);
+
+// `sysenter`: the context has the registers, execution resumes at its `eip` after the callback
+typedef void (*RustyX86FastSyscallCallback)(void *user_data, struct RustyX86CpuContext *ctx);
+
+// `size` is in bytes (1, 2 or 4) for both of the port callbacks
+typedef uint32_t (*RustyX86PortInCallback)(void *user_data,
+                                           struct RustyX86CpuContext *ctx,
+                                           uint16_t port,
+                                           uint8_t size);
+
+typedef void (*RustyX86PortOutCallback)(void *user_data,
+                                        struct RustyX86CpuContext *ctx,
+                                        uint16_t port,
+                                        uint8_t size,
+                                        uint32_t value);
+
+// Any callback can be NULL, then the default behaviour is used:
+// interrupts & syscalls stop the execution, port reads return all ones and port writes are ignored
+typedef struct RustyX86Callbacks {
+  void *user_data;
+  RustyX86InterruptCallback interrupt;
+  RustyX86FastSyscallCallback fast_syscall;
+  RustyX86PortInCallback port_in;
+  RustyX86PortOutCallback port_out;
+} RustyX86Callbacks;
+
+// Creates a runtime with an empty guest address space, stores it into `*out`
+enum RustyX86Status rusty_x86_runtime_new(struct RustyX86Runtime **out);
+
+// Destroys the runtime (NULL is fine)
+void rusty_x86_runtime_free(struct RustyX86Runtime *runtime);
+
+// Copies `len` bytes from `data` into the guest at `addr` and protects the pages with `prot` (RUSTY_X86_PROT_* flags)
+//
+// `data` may be NULL if `len` is 0. Executable mappings will be translated by `rusty_x86_run`
+enum RustyX86Status rusty_x86_map_memory(struct RustyX86Runtime *runtime,
+                                         uint32_t addr,
+                                         const uint8_t *data,
+                                         size_t len,
+                                         uint32_t prot);
+
+// The guest CPU state. Valid until the runtime is destroyed, feel free to read & write it between the runs
+struct RustyX86CpuContext *rusty_x86_context(struct RustyX86Runtime *runtime);
+
+// Registers an additional basic block start (for targets of indirect jumps, for example)
+enum RustyX86Status rusty_x86_add_entry_point(struct RustyX86Runtime *runtime, uint32_t addr);
+
+// Replaces all the callbacks at once. `callbacks` is copied
+enum RustyX86Status rusty_x86_set_callbacks(struct RustyX86Runtime *runtime,
+                                            const struct RustyX86Callbacks *callbacks);
+
+// Translates the mapped code and runs it from `entry`. Why it stopped is stored into `*exit_reason` (if not NULL)
+enum RustyX86Status rusty_x86_run(struct RustyX86Runtime *runtime,
+                                  uint32_t entry,
+                                  enum RustyX86ExitReason *exit_reason);
+
+#endif /* RUSTY_X86_H */
@@ -0,0 +1,527 @@
+//! A heap in the guest address space, for the host-side emulation of `malloc`/`HeapAlloc` & co
+//!
+//! The allocator only does the bookkeeping of its region, the memory itself is passed to every call
+//! (the interpreter's `Vec<u8>` or `GuestMemory`), so it can be used with both
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+
+/// Guest memory the allocator writes the fill patterns & the guards to
+///
+/// It only ever touches its own region, so that should be mapped read-write
+pub trait AllocatorMemory {
+    fn read(&self, addr: u32, data: &mut [u8]);
+    fn write(&mut self, addr: u32, data: &[u8]);
+}
+
+/// The whole address space starting from the guest address 0 (like `Interpreter::memory`)
+impl AllocatorMemory for [u8] {
+    fn read(&self, addr: u32, data: &mut [u8]) {
+        data.copy_from_slice(&self[addr as usize..][..data.len()])
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) {
+        self[addr as usize..][..data.len()].copy_from_slice(data)
+    }
+}
+
+impl AllocatorMemory for Vec<u8> {
+    fn read(&self, addr: u32, data: &mut [u8]) {
+        self.as_slice().read(addr, data)
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) {
+        self.as_mut_slice().write(addr, data)
+    }
+}
+
+/// The allocator region is mapped read-write by `GuestAllocator::map`, so the accesses can't fail
+#[cfg(feature = "llvm")]
+impl AllocatorMemory for crate::runtime::GuestMemory {
+    fn read(&self, addr: u32, data: &mut [u8]) {
+        self.read_bytes(addr, data).unwrap()
+    }
+
+    fn write(&mut self, addr: u32, data: &[u8]) {
+        self.write_bytes(addr, data).unwrap()
+    }
+}
+
+/// The debugging aids, all of them cost some memory or time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct AllocatorOptions {
+    /// Fill the fresh allocations with this (instead of leaving whatever was there)
+    pub alloc_fill: Option<u8>,
+    /// Fill the freed memory with this, to make use-after-free visible
+    pub free_fill: Option<u8>,
+    /// Bytes of `GUARD_FILL` around every allocation, checked on `free`/`realloc` & by `check_guards`
+    pub guard_size: u32,
+}
+
+impl AllocatorOptions {
+    /// Everything on, with the patterns MSVC's debug heap uses
+    pub fn debug() -> Self {
+        Self {
+            alloc_fill: Some(0xcd),
+            free_fill: Some(0xdd),
+            guard_size: 16,
+        }
+    }
+}
+
+pub const GUARD_FILL: u8 = 0xfd;
+
+/// Alignment used by `realloc` for the fresh allocations (what `malloc` guarantees on i386)
+pub const DEFAULT_ALIGN: u32 = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllocError {
+    /// No free block large enough
+    OutOfMemory { size: u32, align: u32 },
+    /// Alignment should be a power of two
+    InvalidAlign(u32),
+    /// Not something returned by `alloc` (or already freed)
+    InvalidPointer(u32),
+    /// The guest wrote past (or before) the allocation at `ptr`, first clobbered byte is at `address`
+    GuardCorrupted { ptr: u32, address: u32 },
+}
+
+impl Display for AllocError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        use AllocError::*;
+        match self {
+            OutOfMemory { size, align } => write!(
+                f,
+                "no free block for 0x{:x} bytes aligned to 0x{:x}",
+                size, align
+            ),
+            InvalidAlign(align) => write!(f, "alignment 0x{:x} is not a power of two", align),
+            InvalidPointer(ptr) => write!(f, "0x{:08x} is not a live allocation", ptr),
+            GuardCorrupted { ptr, address } => write!(
+                f,
+                "guard of the allocation at 0x{:08x} is corrupted at 0x{:08x}",
+                ptr, address
+            ),
+        }
+    }
+}
+
+impl std::error::Error for AllocError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Allocation {
+    // the whole block, including the guards & the alignment padding
+    block: u32,
+    block_end: u32,
+    size: u32,
+    align: u32,
+}
+
+/// First-fit allocator with a free list of coalesced blocks. Nothing clever, but the guest heaps are small
+pub struct GuestAllocator {
+    base: u32,
+    end: u32,
+    options: AllocatorOptions,
+    // start -> end of the free blocks, adjacent ones are always merged
+    free: BTreeMap<u32, u32>,
+    // pointer given to the guest -> its block
+    live: BTreeMap<u32, Allocation>,
+}
+
+impl GuestAllocator {
+    /// Manages `[base, base + size)`, which should already be mapped
+    pub fn new(base: u32, size: u32, options: AllocatorOptions) -> Self {
+        let end = base
+            .checked_add(size)
+            .expect("heap past the end of the address space");
+        let mut free = BTreeMap::new();
+        if size != 0 {
+            free.insert(base, end);
+        }
+        Self {
+            base,
+            end,
+            options,
+            free,
+            live: BTreeMap::new(),
+        }
+    }
+
+    /// Maps `[base, base + size)` read-write & puts the allocator there
+    #[cfg(feature = "llvm")]
+    pub fn map(
+        memory: &mut crate::runtime::GuestMemory,
+        base: u32,
+        size: u32,
+        options: AllocatorOptions,
+    ) -> region::Result<Self> {
+        memory.map(
+            base,
+            crate::memory_image::Protection::READ_WRITE,
+            &vec![0; size as usize],
+        )?;
+        Ok(Self::new(base, size, options))
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn end(&self) -> u32 {
+        self.end
+    }
+
+    pub fn alloc<M: AllocatorMemory + ?Sized>(
+        &mut self,
+        memory: &mut M,
+        size: u32,
+        align: u32,
+    ) -> Result<u32, AllocError> {
+        if !align.is_power_of_two() {
+            return Err(AllocError::InvalidAlign(align));
+        }
+        let oom = AllocError::OutOfMemory { size, align };
+        let guard = self.options.guard_size;
+        // zero-sized allocations still get a unique pointer
+        let footprint = size.max(1).checked_add(guard).ok_or_else(|| oom.clone())?;
+
+        let (start, end, ptr) = self
+            .free
+            .iter()
+            .find_map(|(&start, &end)| {
+                let ptr = (start as u64 + guard as u64).next_multiple_of(align as u64);
+                let block_end = ptr + footprint as u64;
+                (block_end <= end as u64).then_some((start, end, ptr as u32))
+            })
+            .ok_or(oom)?;
+
+        let block = ptr - guard;
+        let block_end = ptr + footprint;
+        self.free.remove(&start);
+        if start < block {
+            self.free.insert(start, block);
+        }
+        if block_end < end {
+            self.free.insert(block_end, end);
+        }
+        self.live.insert(
+            ptr,
+            Allocation {
+                block,
+                block_end,
+                size,
+                align,
+            },
+        );
+
+        if guard != 0 {
+            memory.write(block, &vec![GUARD_FILL; guard as usize]);
+            memory.write(
+                ptr + size,
+                &vec![GUARD_FILL; (block_end - ptr - size) as usize],
+            );
+        }
+        if let Some(fill) = self.options.alloc_fill {
+            memory.write(ptr, &vec![fill; size as usize]);
+        }
+
+        Ok(ptr)
+    }
+
+    pub fn free<M: AllocatorMemory + ?Sized>(
+        &mut self,
+        memory: &mut M,
+        ptr: u32,
+    ) -> Result<(), AllocError> {
+        // free(NULL) is fine
+        if ptr == 0 {
+            return Ok(());
+        }
+        let allocation = *self.live.get(&ptr).ok_or(AllocError::InvalidPointer(ptr))?;
+        // the block stays allocated if the guards are broken, so that the report still sees it
+        self.check_allocation(memory, ptr, &allocation)?;
+        self.live.remove(&ptr);
+
+        if let Some(fill) = self.options.free_fill {
+            memory.write(
+                allocation.block,
+                &vec![fill; (allocation.block_end - allocation.block) as usize],
+            );
+        }
+        self.release(allocation.block, allocation.block_end);
+        Ok(())
+    }
+
+    /// Always moves the data to a fresh allocation, keeping the original alignment.
+    /// `realloc(0, size)` is `alloc` & `realloc(ptr, 0)` is `free` (returning 0), like in C
+    pub fn realloc<M: AllocatorMemory + ?Sized>(
+        &mut self,
+        memory: &mut M,
+        ptr: u32,
+        new_size: u32,
+    ) -> Result<u32, AllocError> {
+        if ptr == 0 {
+            return self.alloc(memory, new_size, DEFAULT_ALIGN);
+        }
+        let allocation = *self.live.get(&ptr).ok_or(AllocError::InvalidPointer(ptr))?;
+        if new_size == 0 {
+            self.free(memory, ptr)?;
+            return Ok(0);
+        }
+        self.check_allocation(memory, ptr, &allocation)?;
+
+        let new_ptr = self.alloc(memory, new_size, allocation.align)?;
+        let mut data = vec![0; allocation.size.min(new_size) as usize];
+        memory.read(ptr, &mut data);
+        memory.write(new_ptr, &data);
+        self.free(memory, ptr)?;
+        Ok(new_ptr)
+    }
+
+    /// Size requested for a live allocation
+    pub fn size_of(&self, ptr: u32) -> Option<u32> {
+        self.live.get(&ptr).map(|a| a.size)
+    }
+
+    /// Checks the guards of all the live allocations
+    pub fn check_guards<M: AllocatorMemory + ?Sized>(&self, memory: &M) -> Vec<AllocError> {
+        self.live
+            .iter()
+            .filter_map(|(&ptr, allocation)| self.check_allocation(memory, ptr, allocation).err())
+            .collect()
+    }
+
+    /// The live allocations, to be looked at when the guest exits
+    pub fn leak_report(&self) -> LeakReport {
+        LeakReport {
+            allocations: self.live.iter().map(|(&ptr, a)| (ptr, a.size)).collect(),
+        }
+    }
+
+    fn check_allocation<M: AllocatorMemory + ?Sized>(
+        &self,
+        memory: &M,
+        ptr: u32,
+        allocation: &Allocation,
+    ) -> Result<(), AllocError> {
+        if self.options.guard_size == 0 {
+            return Ok(());
+        }
+
+        let check = |start: u32, end: u32| {
+            let mut data = vec![0; (end - start) as usize];
+            memory.read(start, &mut data);
+            match data.iter().position(|&b| b != GUARD_FILL) {
+                Some(offset) => Err(AllocError::GuardCorrupted {
+                    ptr,
+                    address: start + offset as u32,
+                }),
+                None => Ok(()),
+            }
+        };
+
+        check(allocation.block, ptr)?;
+        check(ptr + allocation.size, allocation.block_end)
+    }
+
+    fn release(&mut self, mut start: u32, mut end: u32) {
+        if let Some((&prev_start, &prev_end)) = self.free.range(..start).next_back() {
+            if prev_end == start {
+                self.free.remove(&prev_start);
+                start = prev_start;
+            }
+        }
+        if let Some(next_end) = self.free.remove(&end) {
+            end = next_end;
+        }
+        self.free.insert(start, end);
+    }
+}
+
+/// (pointer, size) of everything not freed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeakReport {
+    pub allocations: Vec<(u32, u32)>,
+}
+
+impl LeakReport {
+    pub fn is_empty(&self) -> bool {
+        self.allocations.is_empty()
+    }
+
+    pub fn total_size(&self) -> u64 {
+        self.allocations.iter().map(|&(_, size)| size as u64).sum()
+    }
+}
+
+impl Display for LeakReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} bytes in {} allocations leaked",
+            self.total_size(),
+            self.allocations.len()
+        )?;
+        for (ptr, size) in &self.allocations {
+            writeln!(f, "  0x{:08x}: 0x{:x} bytes", ptr, size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AllocError, AllocatorOptions, GuestAllocator, GUARD_FILL};
+
+    const HEAP: u32 = 0x1000;
+    const HEAP_SIZE: u32 = 0x1000;
+
+    fn new_heap(options: AllocatorOptions) -> (GuestAllocator, Vec<u8>) {
+        (
+            GuestAllocator::new(HEAP, HEAP_SIZE, options),
+            vec![0; (HEAP + HEAP_SIZE) as usize],
+        )
+    }
+
+    #[test_log::test]
+    fn alignment() {
+        for options in [AllocatorOptions::default(), AllocatorOptions::debug()] {
+            let (mut heap, mut mem) = new_heap(options);
+            for align in [1, 2, 8, 16, 0x100] {
+                for size in [0, 1, 3, 0x11] {
+                    let ptr = heap.alloc(&mut mem, size, align).unwrap();
+                    assert_eq!(ptr % align, 0, "{:x} {:x}", size, align);
+                    assert!(ptr >= HEAP && ptr + size <= HEAP + HEAP_SIZE);
+                }
+            }
+        }
+
+        let (mut heap, mut mem) = new_heap(AllocatorOptions::default());
+        assert_eq!(heap.alloc(&mut mem, 4, 3), Err(AllocError::InvalidAlign(3)));
+    }
+
+    #[test_log::test]
+    fn no_overlap() {
+        let (mut heap, mut mem) = new_heap(AllocatorOptions::debug());
+        let mut ranges = vec![];
+        for size in [1, 0x20, 0x7, 0x40, 0] {
+            let ptr = heap.alloc(&mut mem, size, 4).unwrap();
+            ranges.push((ptr, ptr + size.max(1)));
+        }
+        ranges.sort();
+        for pair in ranges.windows(2) {
+            assert!(pair[0].1 <= pair[1].0, "{:x?}", pair);
+        }
+    }
+
+    #[test_log::test]
+    fn reuse_after_free() {
+        let (mut heap, mut mem) = new_heap(AllocatorOptions::default());
+
+        let whole = heap.alloc(&mut mem, HEAP_SIZE, 1).unwrap();
+        assert_eq!(whole, HEAP);
+        assert!(matches!(
+            heap.alloc(&mut mem, 1, 1),
+            Err(AllocError::OutOfMemory { .. })
+        ));
+        heap.free(&mut mem, whole).unwrap();
+
+        // the split blocks are merged back on free
+        let a = heap.alloc(&mut mem, 0x100, 8).unwrap();
+        let b = heap.alloc(&mut mem, 0x100, 8).unwrap();
+        let c = heap.alloc(&mut mem, 0x100, 8).unwrap();
+        heap.free(&mut mem, b).unwrap();
+        assert_eq!(heap.alloc(&mut mem, 0x80, 8).unwrap(), b);
+        heap.free(&mut mem, b).unwrap();
+        heap.free(&mut mem, a).unwrap();
+        heap.free(&mut mem, c).unwrap();
+        assert_eq!(heap.alloc(&mut mem, HEAP_SIZE, 1).unwrap(), HEAP);
+
+        assert_eq!(heap.free(&mut mem, b), Err(AllocError::InvalidPointer(b)));
+        assert_eq!(heap.free(&mut mem, 0), Ok(()));
+    }
+
+    #[test_log::test]
+    fn realloc() {
+        let (mut heap, mut mem) = new_heap(AllocatorOptions::debug());
+
+        let ptr = heap.realloc(&mut mem, 0, 4).unwrap();
+        assert_eq!(ptr % 8, 0);
+        mem[ptr as usize..][..4].copy_from_slice(&[1, 2, 3, 4]);
+
+        let grown = heap.realloc(&mut mem, ptr, 0x20).unwrap();
+        assert_eq!(heap.size_of(grown), Some(0x20));
+        assert_eq!(heap.size_of(ptr), None);
+        assert_eq!(mem[grown as usize..][..5], [1, 2, 3, 4, 0xcd]);
+        // freed memory is filled
+        assert_eq!(mem[ptr as usize], 0xdd);
+
+        let shrunk = heap.realloc(&mut mem, grown, 2).unwrap();
+        assert_eq!(mem[shrunk as usize..][..3], [1, 2, GUARD_FILL]);
+
+        assert_eq!(heap.realloc(&mut mem, shrunk, 0), Ok(0));
+        assert!(heap.leak_report().is_empty());
+    }
+
+    #[test_log::test]
+    fn guard_corruption() {
+        let (mut heap, mut mem) = new_heap(AllocatorOptions::debug());
+
+        let a = heap.alloc(&mut mem, 10, 4).unwrap();
+        let b = heap.alloc(&mut mem, 10, 4).unwrap();
+        assert!(heap.check_guards(&mem).is_empty());
+
+        // an off-by-one write past the end of a & one before the start of b
+        mem[(a + 10) as usize] = 0;
+        mem[(b - 3) as usize] = 0;
+        assert_eq!(
+            heap.check_guards(&mem),
+            vec![
+                AllocError::GuardCorrupted {
+                    ptr: a,
+                    address: a + 10
+                },
+                AllocError::GuardCorrupted {
+                    ptr: b,
+                    address: b - 3
+                },
+            ]
+        );
+        assert_eq!(
+            heap.free(&mut mem, a),
+            Err(AllocError::GuardCorrupted {
+                ptr: a,
+                address: a + 10
+            })
+        );
+        // still live, so that it shows up in the report
+        assert_eq!(heap.size_of(a), Some(10));
+
+        // without the guards nothing is checked
+        let (mut heap, mut mem) = new_heap(AllocatorOptions::default());
+        let a = heap.alloc(&mut mem, 10, 4).unwrap();
+        mem[(a + 10) as usize] = 0;
+        assert_eq!(heap.free(&mut mem, a), Ok(()));
+    }
+
+    #[test_log::test]
+    fn leak_report() {
+        let (mut heap, mut mem) = new_heap(AllocatorOptions::debug());
+
+        let a = heap.alloc(&mut mem, 0x10, 8).unwrap();
+        let b = heap.alloc(&mut mem, 0x24, 8).unwrap();
+        let c = heap.alloc(&mut mem, 3, 8).unwrap();
+        heap.free(&mut mem, b).unwrap();
+
+        let report = heap.leak_report();
+        assert_eq!(report.allocations, vec![(a, 0x10), (c, 3)]);
+        assert_eq!(report.total_size(), 0x13);
+        assert_eq!(
+            report.to_string(),
+            format!(
+                "19 bytes in 2 allocations leaked\n  0x{:08x}: 0x10 bytes\n  0x{:08x}: 0x3 bytes\n",
+                a, c
+            )
+        );
+    }
+}
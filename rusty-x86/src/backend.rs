@@ -1,4 +1,12 @@
-use crate::types::{Flag, IntType, MemoryOperand, Operand, Register, SegmentRegister};
+use crate::handler::{GuestFault, InterruptVectorTable};
+use crate::ir::{CodeMode, Instr};
+use crate::memory_image::Protection;
+use crate::segmentation::{default_segment, SegmentationMode, SegmentationPolicy};
+use crate::system_registers::SystemRegisterProfile;
+use crate::types::{
+    Flag, FlagStorage, FpuWord, IntType, MemoryOperand, Operand, Register, SegmentRegister,
+    UndefinedFlagsPolicy,
+};
 
 pub trait IntValue: Clone + Copy {
     fn size(&self) -> IntType;
@@ -19,6 +27,23 @@ pub enum ComparisonType {
     SignedLessOrEqual,
 }
 
+/// All but `Unordered` are false when either side is a NaN
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FloatComparisonType {
+    Equal,
+    Less,
+    Greater,
+    Unordered,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    NearestEven,
+    Down,
+    Up,
+    Zero,
+}
+
 pub trait Builder {
     type IntValue: IntValue;
     type BoolValue: BoolValue;
@@ -58,10 +83,48 @@ pub trait Builder {
 
     fn load_flag(&mut self, flag: Flag) -> Self::BoolValue;
     fn store_flag(&mut self, flag: Flag, value: Self::BoolValue);
+    /// With `FlagStorage::Packed`: all the flags at once, laid out like `CpuContext::eflags`
+    fn load_packed_flags(&mut self) -> Self::IntValue;
+    fn store_packed_flags(&mut self, value: Self::IntValue);
+    /// EFLAGS.IF (`CpuContext::interrupt_flag`)
+    fn load_interrupt_flag(&mut self) -> Self::BoolValue;
+    fn store_interrupt_flag(&mut self, value: Self::BoolValue);
+    /// EFLAGS.AC (`CpuContext::alignment_check`)
+    fn load_alignment_check_flag(&mut self) -> Self::BoolValue;
+    fn store_alignment_check_flag(&mut self, value: Self::BoolValue);
 
     // TODO: not everything fits into IntType box... like 80-bit floats, for example.......
     fn load_memory(&mut self, size: IntType, address: Self::IntValue) -> Self::IntValue;
     fn store_memory(&mut self, address: Self::IntValue, value: Self::IntValue);
+    /// Reads the byte at `address` for the faults it might raise, without anything optimizing the read away
+    fn probe_memory(&mut self, address: Self::IntValue) {
+        self.load_memory(IntType::I8, address);
+    }
+    /// A store that won't be read back soon (movnt*), the hint is only for the host
+    fn store_memory_nontemporal(&mut self, address: Self::IntValue, value: Self::IntValue) {
+        self.store_memory(address, value)
+    }
+    /// Swaps `value` with the one in memory at `address` as one atomic access (the locked `xchg`), returns the old one
+    fn exchange_memory(
+        &mut self,
+        address: Self::IntValue,
+        value: Self::IntValue,
+    ) -> Self::IntValue {
+        let old = self.load_memory(value.size(), address);
+        self.store_memory(address, value);
+        old
+    }
+
+    // x87 registers by their physical number (I32 in 0..8), the values are I64 (doubles)
+    fn load_fpu_register(&mut self, index: Self::IntValue) -> Self::IntValue;
+    fn store_fpu_register(&mut self, index: Self::IntValue, value: Self::IntValue);
+    fn load_fpu_word(&mut self, word: FpuWord) -> Self::IntValue;
+    fn store_fpu_word(&mut self, word: FpuWord, value: Self::IntValue);
+    // XMM registers as two I64 halves
+    fn load_xmm_half(&mut self, index: u8, high: bool) -> Self::IntValue;
+    fn store_xmm_half(&mut self, index: u8, high: bool, value: Self::IntValue);
+    fn load_mxcsr(&mut self) -> Self::IntValue;
+    fn store_mxcsr(&mut self, value: Self::IntValue);
 
     fn add(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue;
     fn int_neg(&mut self, val: Self::IntValue) -> Self::IntValue;
@@ -74,6 +137,14 @@ pub trait Builder {
     fn shl(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue;
     fn lshr(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue;
     fn ashr(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue;
+    // the amount is of the same type as the value and is taken modulo its width
+    fn rotl(&mut self, val: Self::IntValue, amount: Self::IntValue) -> Self::IntValue;
+    fn rotr(&mut self, val: Self::IntValue, amount: Self::IntValue) -> Self::IntValue;
+    // the number of the trailing (leading) zero bits, of the same type as the value. Undefined for zero
+    fn cttz(&mut self, val: Self::IntValue) -> Self::IntValue;
+    fn ctlz(&mut self, val: Self::IntValue) -> Self::IntValue;
+    // the bytes of the value in the reverse order
+    fn bswap(&mut self, val: Self::IntValue) -> Self::IntValue;
     fn udiv(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue;
     fn sdiv(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue;
 
@@ -97,6 +168,24 @@ pub trait Builder {
     fn usub_overflow(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::BoolValue;
     fn ssub_overflow(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::BoolValue;
 
+    // floating point values are passed around as their bits: I32 is a float, I64 is a double
+    fn float_sqrt(&mut self, val: Self::IntValue) -> Self::IntValue;
+    fn float_round(&mut self, val: Self::IntValue, mode: RoundingMode) -> Self::IntValue;
+    /// float -> double
+    fn float_extend(&mut self, val: Self::IntValue) -> Self::IntValue;
+    /// double -> float, rounding to nearest
+    fn float_truncate(&mut self, val: Self::IntValue) -> Self::IntValue;
+    /// Rounds towards zero, the result is unspecified if it doesn't fit into `to`
+    fn float_to_int(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue;
+    /// Signed integer -> float (`to` is I32) or double (I64), rounding to nearest
+    fn int_to_float(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue;
+    fn fcmp(
+        &mut self,
+        cmp: FloatComparisonType,
+        lhs: Self::IntValue,
+        rhs: Self::IntValue,
+    ) -> Self::BoolValue;
+
     fn zext(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue;
     fn sext(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue;
     fn trunc(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue;
@@ -109,6 +198,7 @@ pub trait Builder {
     ) -> Self::BoolValue;
 
     fn direct_call(&mut self, target: u32, next_eip: u32);
+    fn indirect_call(&mut self, target: Self::IntValue, next_eip: u32);
 
     fn select(
         &mut self,
@@ -125,6 +215,60 @@ pub trait Builder {
 
     fn trap(&mut self);
 
+    /// Called before lowering each instruction
+    fn begin_instruction(&mut self, instr: &Instr);
+    /// Address of the instruction being lowered
+    fn instruction_start(&self) -> u32;
+    /// Address right after it: what `call` pushes, what the EIP-relative computations are relative to
+    fn instruction_end(&self) -> u32;
+
+    fn segmentation(&self) -> &SegmentationPolicy;
+    fn code_mode(&self) -> CodeMode;
+    /// Whether the SSE alignment requirements are enforced (see `TranslationOptions::strict_alignment`)
+    fn strict_alignment(&self) -> bool;
+    /// Whether the misaligned accesses raise #AC while EFLAGS.AC is set (see `TranslationOptions::alignment_checks`)
+    fn alignment_checks(&self) -> bool;
+    fn undefined_flags(&self) -> UndefinedFlagsPolicy;
+    /// Where the flags are kept (see `TranslationOptions::flag_storage`)
+    fn flag_storage(&self) -> FlagStorage;
+    fn interrupt_vectors(&self) -> &InterruptVectorTable;
+    fn system_registers(&self) -> &SystemRegisterProfile;
+
+    /// Marks the flag as undefined by the current instruction (stores `FLAG_UNDEFINED`)
+    fn poison_flag(&mut self, flag: Flag);
+
+    /// For the flags the instruction leaves undefined: `relaxed` is what the hardware would usually have there
+    /// (`None` if it doesn't touch the flag at all)
+    fn store_undefined_flag(&mut self, flag: Flag, relaxed: Option<Self::BoolValue>)
+    where
+        Self: Sized,
+    {
+        match (self.undefined_flags(), relaxed) {
+            (UndefinedFlagsPolicy::Relaxed, Some(value)) => self.store_flag(flag, value),
+            (UndefinedFlagsPolicy::Relaxed, None) => {}
+            (UndefinedFlagsPolicy::Strict, _) => self.poison_flag(flag),
+        }
+    }
+
+    /// Stops the execution with an exception at the current instruction.
+    /// Whatever is emitted after this (up to the end of the instruction) is never executed
+    fn raise_fault(&mut self, fault: GuestFault, address: Self::IntValue);
+    /// #PF, with the error code (`CpuContext::fault_error_code`)
+    fn raise_page_fault(&mut self, address: Self::IntValue, error_code: u32);
+
+    // those are serviced by the runtime (the embedder, actually)
+    fn interrupt(&mut self, vector: u8, next_eip: u32);
+    /// `sysenter`: the handler may send us somewhere other than `next_eip` (by changing `CpuContext::eip`)
+    fn fast_syscall(&mut self, next_eip: u32);
+    fn port_in(&mut self, port: Self::IntValue, size: IntType) -> Self::IntValue;
+    fn port_out(&mut self, port: Self::IntValue, value: Self::IntValue);
+    /// A write to CRn with `ControlRegisterWrites::Record`
+    fn control_register_write(&mut self, register: u8, value: Self::IntValue);
+    /// `wrmsr` with `ControlRegisterWrites::Record`, the value is an I64
+    fn msr_write(&mut self, index: Self::IntValue, value: Self::IntValue);
+    /// `clflush` of the line with the (linear) address in it
+    fn cache_flush(&mut self, address: Self::IntValue);
+
     // fn r#while<C, B>(&mut self, cond: C, body: B)
     // where
     //     C: FnOnce(&mut Self) -> Self::BoolValue,
@@ -136,20 +280,20 @@ pub trait Builder {
         B: Fn(&mut Self) -> Self::BoolValue,
         Self: Sized;
 
+    /// The offset part of the address (what lea computes), the segment is not looked at
+    /// With the 16-bit registers (the 0x67 prefix) the address is computed in 16 bits & wraps around at 64 KiB
     fn compute_memory_operand_address(&mut self, op: MemoryOperand) -> Self::IntValue {
-        use SegmentRegister::*;
-        match op.segment {
-            None => {}
-            // we assume that those segments are mapped __as usual__
-            Some(CS | DS | ES | SS) => {}
-            // and those map to special regions (TLS, TEB, whatever, it depends on OS)
-            Some(FS | GS) => todo!(),
-        }
+        // the displacement is a sign-extended 32-bit value, the address wraps around at 4 GiB
+        let mut res = self.make_u32(op.displacement as u32);
 
-        let mut res = self.make_i32(i32::try_from(op.displacement).unwrap());
+        let address_size = op
+            .base
+            .or(op.index)
+            .map_or(IntType::I32, |register| register.size());
 
         if let Some(base) = op.base {
             let base_val = self.load_register(base);
+            let base_val = self.zext(base_val, IntType::I32);
             res = self.add(res, base_val);
         }
 
@@ -158,13 +302,62 @@ pub trait Builder {
             let scale = self.make_int_value(index.size(), scale as u64, false);
             let index_val = self.load_register(index);
             let scaled_val = self.mul(scale, index_val);
+            let scaled_val = self.zext(scaled_val, IntType::I32);
             res = self.add(res, scaled_val);
         }
 
+        if address_size == IntType::I16 {
+            let offset = self.trunc(res, IntType::I16);
+            res = self.zext(offset, IntType::I32);
+        }
+
         res
     }
 
-    fn load_operand(&mut self, operand: Operand) -> Self::IntValue {
+    /// The address in the guest memory accessed by the operand. Checks the access if the segmentation policy asks for it
+    fn compute_linear_address(&mut self, op: MemoryOperand, access: Protection) -> Self::IntValue
+    where
+        Self: Sized,
+    {
+        let offset = self.compute_memory_operand_address(op);
+
+        let segment = op.segment.unwrap_or_else(|| default_segment(&op));
+        let mode = self.segmentation().mode;
+        let descriptor = self
+            .segmentation()
+            .segment(segment)
+            .unwrap_or_else(|| panic!("{:?} is not set up in the segmentation policy", segment));
+
+        if mode == SegmentationMode::Checked {
+            if !descriptor.rights.contains(access) {
+                self.raise_fault(GuestFault::GeneralProtection, offset);
+            }
+
+            // the last accessed byte should be within the limit (computing in 64 bits, so no overflow)
+            let last_byte = self.zext(offset, IntType::I64);
+            let size = op.size.unwrap().byte_width() as u64;
+            let last_byte = self.add(last_byte, self.make_u64(size - 1));
+            let limit = self.make_u64(descriptor.limit as u64);
+            let out_of_limit = self.icmp(ComparisonType::UnsignedGreater, last_byte, limit);
+            self.ifelse(
+                out_of_limit,
+                |builder| builder.raise_fault(GuestFault::GeneralProtection, offset),
+                |_| {},
+            );
+        }
+
+        if descriptor.base != 0 {
+            let base = self.make_u32(descriptor.base);
+            self.add(offset, base)
+        } else {
+            offset
+        }
+    }
+
+    fn load_operand(&mut self, operand: Operand) -> Self::IntValue
+    where
+        Self: Sized,
+    {
         match operand {
             Operand::Register(reg) => self.load_register(reg),
             Operand::RegisterPair(hi, lo) => {
@@ -188,17 +381,20 @@ pub trait Builder {
             Operand::Immediate32(v) => self.make_u32(v),
             Operand::Immediate64(v) => self.make_u64(v),
             Operand::Memory(op) => {
-                let addr = self.compute_memory_operand_address(op);
+                let addr = self.compute_linear_address(op, Protection::READ);
                 self.load_memory(op.size.unwrap(), addr)
             }
             op => panic!("Unsupported load operand: {:?}", op),
         }
     }
-    fn store_operand(&mut self, operand: Operand, value: Self::IntValue) {
+    fn store_operand(&mut self, operand: Operand, value: Self::IntValue)
+    where
+        Self: Sized,
+    {
         match operand {
             Operand::Register(reg) => self.store_register(reg, value),
             Operand::Memory(op) => {
-                let addr = self.compute_memory_operand_address(op);
+                let addr = self.compute_linear_address(op, Protection::WRITE);
                 assert_eq!(op.size.unwrap(), value.size());
                 self.store_memory(addr, value)
             }
@@ -219,31 +415,76 @@ pub trait Builder {
         }
     }
 
+    /// EDX:EAX as one I64 (the double-sized operand of mul, div & friends)
+    fn read_edx_eax(&mut self) -> Self::IntValue
+    where
+        Self: Sized,
+    {
+        self.load_operand(Operand::RegisterPair(Register::EDX, Register::EAX))
+    }
+
+    /// Splits an I64 into EDX (the high half) & EAX (the low one)
+    fn write_edx_eax(&mut self, value: Self::IntValue)
+    where
+        Self: Sized,
+    {
+        self.store_operand(Operand::RegisterPair(Register::EDX, Register::EAX), value)
+    }
+
+    /// ESP, or SP for `CodeMode::Bits16`: the 16-bit stack wraps around at 64 KiB, leaving the upper half of ESP alone
+    fn stack_pointer(&self) -> Register {
+        match self.code_mode() {
+            CodeMode::Bits32 => Register::ESP,
+            CodeMode::Bits16 => Register::SP,
+        }
+    }
+
+    /// Where in the guest memory the stack pointer value `sp` points to (SS:SP)
+    fn stack_address(&mut self, sp: Self::IntValue) -> Self::IntValue {
+        let sp = self.zext(sp, IntType::I32);
+
+        let base = self
+            .segmentation()
+            .segment(SegmentRegister::SS)
+            .expect("SS always has a descriptor")
+            .base;
+        if base != 0 {
+            let base = self.make_u32(base);
+            self.add(sp, base)
+        } else {
+            sp
+        }
+    }
+
     #[allow(clippy::clone_on_copy)]
     fn push(&mut self, val: Self::IntValue) {
+        let sp_reg = self.stack_pointer();
         let size = val.size().byte_width();
-        let size = self.make_u32(size as u32);
+        let size = self.make_int_value(sp_reg.size(), size as u64, false);
 
-        let esp = self.load_register(Register::ESP);
-        let esp = self.sub(esp, size);
+        let sp = self.load_register(sp_reg);
+        let sp = self.sub(sp, size);
         // clone is unneeded, but Clion doesn't have a clue
-        self.store_register(Register::ESP, esp.clone());
+        self.store_register(sp_reg, sp.clone());
 
-        self.store_memory(esp, val);
+        let address = self.stack_address(sp);
+        self.store_memory(address, val);
     }
 
     #[allow(clippy::clone_on_copy)]
     fn pop(&mut self, size: IntType) -> Self::IntValue {
+        let sp_reg = self.stack_pointer();
         let size_bytes = size.byte_width();
-        let size_bytes = self.make_u32(size_bytes as u32);
+        let size_bytes = self.make_int_value(sp_reg.size(), size_bytes as u64, false);
 
-        let esp = self.load_register(Register::ESP);
+        let sp = self.load_register(sp_reg);
 
         // clone is unneeded, but Clion doesn't have a clue
-        let val = self.load_memory(size, esp.clone());
+        let address = self.stack_address(sp.clone());
+        let val = self.load_memory(size, address);
 
-        let esp = self.add(esp, size_bytes);
-        self.store_register(Register::ESP, esp);
+        let sp = self.add(sp, size_bytes);
+        self.store_register(sp_reg, sp);
 
         val
     }
@@ -0,0 +1,269 @@
+//! C API for embedding the recompiler into non-Rust hosts
+//!
+//! The header is generated by cbindgen into `include/rusty_x86.h` (tests/capi.rs checks that it is up-to-date)
+//!
+//! No panic is allowed to cross the FFI boundary: every function catches them and returns `RUSTY_X86_STATUS_PANIC`.
+//! After that the runtime should be considered poisoned (only `rusty_x86_runtime_free` is safe to call)
+//!
+//! All the pointers passed in should either be NULL or valid, the usual C rules
+#![allow(clippy::missing_safety_doc)]
+
+use std::ffi::c_void;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+use crate::memory_image::Protection;
+use crate::runtime::{ExitReason, Runtime, RuntimeHandler};
+use crate::types::{CpuContext, IntType, EXIT_HOST_REQUEST};
+
+pub const RUSTY_X86_PROT_READ: u32 = 1;
+pub const RUSTY_X86_PROT_WRITE: u32 = 2;
+pub const RUSTY_X86_PROT_EXECUTE: u32 = 4;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RustyX86Status {
+    Ok = 0,
+    /// Null pointer or garbage in the flags
+    InvalidArgument = 1,
+    /// The host OS didn't give us the memory
+    MapFailed = 2,
+    /// Something panicked inside of the call (including the callbacks)
+    Panic = 3,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RustyX86ExitReason {
+    /// The entry function returned
+    Returned = 0,
+    /// A callback set `exit` in the context (or there was an interrupt without a callback)
+    HostRequest = 1,
+    /// The guest raised an exception, see `fault_vector` & `fault_address` in the context
+    Fault = 2,
+    /// The guest ended itself, see `exit_code` in the context
+    GuestExit = 3,
+}
+
+impl From<ExitReason> for RustyX86ExitReason {
+    fn from(r: ExitReason) -> Self {
+        match r {
+            ExitReason::Returned => RustyX86ExitReason::Returned,
+            ExitReason::HostRequest => RustyX86ExitReason::HostRequest,
+            ExitReason::Fault(_) => RustyX86ExitReason::Fault,
+            ExitReason::GuestExit { .. } => RustyX86ExitReason::GuestExit,
+        }
+    }
+}
+
+pub type RustyX86InterruptCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, ctx: *mut CpuContext, vector: u8)>;
+/// `sysenter`: the context has the registers, execution resumes at its `eip` after the callback
+pub type RustyX86FastSyscallCallback =
+    Option<unsafe extern "C" fn(user_data: *mut c_void, ctx: *mut CpuContext)>;
+/// `size` is in bytes (1, 2 or 4) for both of the port callbacks
+pub type RustyX86PortInCallback = Option<
+    unsafe extern "C" fn(user_data: *mut c_void, ctx: *mut CpuContext, port: u16, size: u8) -> u32,
+>;
+pub type RustyX86PortOutCallback = Option<
+    unsafe extern "C" fn(
+        user_data: *mut c_void,
+        ctx: *mut CpuContext,
+        port: u16,
+        size: u8,
+        value: u32,
+    ),
+>;
+
+/// Any callback can be NULL, then the default behaviour is used:
+/// interrupts & syscalls stop the execution, port reads return all ones and port writes are ignored
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct RustyX86Callbacks {
+    pub user_data: *mut c_void,
+    pub interrupt: RustyX86InterruptCallback,
+    pub fast_syscall: RustyX86FastSyscallCallback,
+    pub port_in: RustyX86PortInCallback,
+    pub port_out: RustyX86PortOutCallback,
+}
+
+impl Default for RustyX86Callbacks {
+    fn default() -> Self {
+        Self {
+            user_data: std::ptr::null_mut(),
+            interrupt: None,
+            fast_syscall: None,
+            port_in: None,
+            port_out: None,
+        }
+    }
+}
+
+struct CallbackHandler(RustyX86Callbacks);
+
+impl RuntimeHandler for CallbackHandler {
+    fn interrupt(&mut self, ctx: &mut CpuContext, vector: u8) {
+        match self.0.interrupt {
+            Some(cb) => unsafe { cb(self.0.user_data, ctx, vector) },
+            None => ctx.exit = EXIT_HOST_REQUEST,
+        }
+    }
+
+    fn fast_syscall(&mut self, ctx: &mut CpuContext) {
+        match self.0.fast_syscall {
+            Some(cb) => unsafe { cb(self.0.user_data, ctx) },
+            None => ctx.exit = EXIT_HOST_REQUEST,
+        }
+    }
+
+    fn port_in(&mut self, ctx: &mut CpuContext, port: u16, size: IntType) -> u32 {
+        match self.0.port_in {
+            Some(cb) => unsafe { cb(self.0.user_data, ctx, port, size.byte_width()) },
+            None => u32::MAX,
+        }
+    }
+
+    fn port_out(&mut self, ctx: &mut CpuContext, port: u16, size: IntType, value: u32) {
+        if let Some(cb) = self.0.port_out {
+            unsafe { cb(self.0.user_data, ctx, port, size.byte_width(), value) }
+        }
+    }
+}
+
+/// Opaque handle
+pub struct RustyX86Runtime(Runtime<CallbackHandler>);
+
+fn guard(f: impl FnOnce() -> RustyX86Status) -> RustyX86Status {
+    catch_unwind(AssertUnwindSafe(f)).unwrap_or(RustyX86Status::Panic)
+}
+
+/// Creates a runtime with an empty guest address space, stores it into `*out`
+#[no_mangle]
+pub unsafe extern "C" fn rusty_x86_runtime_new(out: *mut *mut RustyX86Runtime) -> RustyX86Status {
+    guard(|| {
+        if out.is_null() {
+            return RustyX86Status::InvalidArgument;
+        }
+        match Runtime::new(CallbackHandler(RustyX86Callbacks::default())) {
+            Ok(runtime) => {
+                *out = Box::into_raw(Box::new(RustyX86Runtime(runtime)));
+                RustyX86Status::Ok
+            }
+            Err(_) => RustyX86Status::MapFailed,
+        }
+    })
+}
+
+/// Destroys the runtime (NULL is fine)
+#[no_mangle]
+pub unsafe extern "C" fn rusty_x86_runtime_free(runtime: *mut RustyX86Runtime) {
+    if !runtime.is_null() {
+        // not much we can do if it panics, so just swallow it
+        let _ = catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(runtime))));
+    }
+}
+
+/// Copies `len` bytes from `data` into the guest at `addr` and protects the pages with `prot` (RUSTY_X86_PROT_* flags)
+///
+/// `data` may be NULL if `len` is 0. Executable mappings will be translated by `rusty_x86_run`
+#[no_mangle]
+pub unsafe extern "C" fn rusty_x86_map_memory(
+    runtime: *mut RustyX86Runtime,
+    addr: u32,
+    data: *const u8,
+    len: usize,
+    prot: u32,
+) -> RustyX86Status {
+    guard(|| {
+        let runtime = match runtime.as_mut() {
+            Some(r) => r,
+            None => return RustyX86Status::InvalidArgument,
+        };
+        if (data.is_null() && len != 0)
+            || prot & !(RUSTY_X86_PROT_READ | RUSTY_X86_PROT_WRITE | RUSTY_X86_PROT_EXECUTE) != 0
+        {
+            return RustyX86Status::InvalidArgument;
+        }
+
+        let data = if len == 0 {
+            &[] as &[u8]
+        } else {
+            std::slice::from_raw_parts(data, len)
+        };
+
+        let mut protection = Protection::NONE;
+        if prot & RUSTY_X86_PROT_READ != 0 {
+            protection |= Protection::READ;
+        }
+        if prot & RUSTY_X86_PROT_WRITE != 0 {
+            protection |= Protection::WRITE;
+        }
+        if prot & RUSTY_X86_PROT_EXECUTE != 0 {
+            protection |= Protection::EXECUTE;
+        }
+
+        match runtime.0.map(addr, protection, data) {
+            Ok(()) => RustyX86Status::Ok,
+            Err(_) => RustyX86Status::MapFailed,
+        }
+    })
+}
+
+/// The guest CPU state. Valid until the runtime is destroyed, feel free to read & write it between the runs
+#[no_mangle]
+pub unsafe extern "C" fn rusty_x86_context(runtime: *mut RustyX86Runtime) -> *mut CpuContext {
+    match runtime.as_mut() {
+        Some(r) => &mut r.0.context,
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Registers an additional basic block start (for targets of indirect jumps, for example)
+#[no_mangle]
+pub unsafe extern "C" fn rusty_x86_add_entry_point(
+    runtime: *mut RustyX86Runtime,
+    addr: u32,
+) -> RustyX86Status {
+    guard(|| match runtime.as_mut() {
+        Some(r) => {
+            r.0.add_entry_point(addr);
+            RustyX86Status::Ok
+        }
+        None => RustyX86Status::InvalidArgument,
+    })
+}
+
+/// Replaces all the callbacks at once. `callbacks` is copied
+#[no_mangle]
+pub unsafe extern "C" fn rusty_x86_set_callbacks(
+    runtime: *mut RustyX86Runtime,
+    callbacks: *const RustyX86Callbacks,
+) -> RustyX86Status {
+    guard(|| match (runtime.as_mut(), callbacks.as_ref()) {
+        (Some(r), Some(callbacks)) => {
+            r.0.handler = CallbackHandler(*callbacks);
+            RustyX86Status::Ok
+        }
+        _ => RustyX86Status::InvalidArgument,
+    })
+}
+
+/// Translates the mapped code and runs it from `entry`. Why it stopped is stored into `*exit_reason` (if not NULL)
+#[no_mangle]
+pub unsafe extern "C" fn rusty_x86_run(
+    runtime: *mut RustyX86Runtime,
+    entry: u32,
+    exit_reason: *mut RustyX86ExitReason,
+) -> RustyX86Status {
+    guard(|| {
+        let runtime = match runtime.as_mut() {
+            Some(r) => r,
+            None => return RustyX86Status::InvalidArgument,
+        };
+
+        let reason = runtime.0.run(entry);
+        if let Some(exit_reason) = exit_reason.as_mut() {
+            *exit_reason = reason.into();
+        }
+        RustyX86Status::Ok
+    })
+}
@@ -0,0 +1,672 @@
+//! All the knobs of the translation pipeline in one place
+//!
+//! ```ignore
+//! let config = Recompiler::builder()
+//!     .opt_level(OptLevel::None)
+//!     .per_instruction(true)
+//!     .instruction_hook(true)
+//!     .build()?;
+//! ```
+//!
+//! Inconsistent combinations are rejected by `build` instead of doing something surprising at run time
+
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+use crate::handler::InterruptVectorTable;
+use crate::ir::{CodeMode, InvalidOpcodePolicy, UnsupportedInstructionPolicy};
+use crate::segmentation::SegmentationPolicy;
+use crate::system_registers::SystemRegisterProfile;
+use crate::types::{FlagStorage, UndefinedFlagsPolicy};
+
+/// Size of the whole 32-bit address space
+pub const FULL_MEMORY_SIZE: u64 = 0x1_0000_0000;
+
+/// Way longer than any basic block a compiler would produce
+pub const DEFAULT_MAX_BLOCK_INSTRUCTIONS: usize = 2048;
+
+/// Optimization level for the JIT (maps onto the LLVM ones)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OptLevel {
+    None,
+    Less,
+    Default,
+    Aggressive,
+}
+
+/// Options affecting the generated code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranslationOptions {
+    /// Direct jumps call the target basic block directly. If disabled - everything goes through the dispatcher
+    pub block_chaining: bool,
+    /// End a basic block after every instruction
+    pub per_instruction: bool,
+    /// Cut the basic blocks longer than this (in instructions) into pieces, chained with a direct jump. Keeps the
+    /// LLVM functions made of unrolled or generated straight-line code small enough to compile quickly
+    pub max_block_instructions: usize,
+    /// Call `RuntimeHandler::instruction` before every basic block (which is every instruction with `per_instruction`)
+    pub instruction_hook: bool,
+    /// Rewrite the common idioms into cheaper forms before lowering (see peephole.rs)
+    pub peephole: bool,
+    /// Skip storing the flags that are overwritten before anything reads them, looking across the basic blocks
+    /// (see liveness.rs). The flags in the context at a fault might be stale with this
+    pub flag_liveness: bool,
+    /// Resolve the memory accesses with an address known at the translation time (`mov eax, imm; mov ebx, [eax]`)
+    /// while translating: no bounds, wraparound or alignment checks for the ones that pass them (see constprop.rs)
+    pub constant_addresses: bool,
+    /// Jump through the switch tables in read-only memory with a direct branch to every case (see switch_table.rs)
+    pub switch_tables: bool,
+    /// Lower the body of a small leaf function in place of a direct call to it (see inlining.rs). Not done in the
+    /// per-instruction mode & with the coverage, which want to see the leaf run on its own
+    pub inline_leaves: bool,
+    /// Keep a copy of every flag stored, dead or not (`CpuContext::shadow_flags`), & compare it with the real one
+    /// at every flag read & when returning to the host. A disagreement raises `GuestFault::FlagMismatch`: what
+    /// `flag_liveness` skipped storing was needed after all. Slow, for the tests
+    pub shadow_flags: bool,
+    /// Guest memory accesses at or above this address trap. `None` means no checks at all (the whole 4 GiB are reserved)
+    pub memory_limit: Option<u64>,
+    /// What the segment registers point to & whether the accesses are checked against the limits
+    pub segmentation: SegmentationPolicy,
+    /// How the bytes that are not an instruction are decoded
+    pub invalid_opcodes: InvalidOpcodePolicy,
+    /// What happens to the valid instructions we can't translate
+    pub unsupported_instructions: UnsupportedInstructionPolicy,
+    /// 32-bit or 16-bit code, for all of it
+    pub code_mode: CodeMode,
+    /// SSE instructions requiring aligned memory operands (movntps & co) raise #GP on unaligned ones.
+    /// Otherwise they just work, like their unaligned counterparts
+    pub strict_alignment: bool,
+    /// What ends up in the flags the instructions leave undefined
+    pub undefined_flags: UndefinedFlagsPolicy,
+    /// Where the generated code keeps the flags. The context it runs on has to keep them in the same place
+    /// (`Runtime` makes it so)
+    pub flag_storage: FlagStorage,
+    /// Number the memory accesses & note the current one in `CpuContext::fault_site`, so that a host page fault
+    /// on the guest memory can be attributed to the exact guest instruction (see `llvm::FaultSites`)
+    pub fault_sites: bool,
+    /// An access crossing the top of the address space (`mov eax, [0xfffffffe]`) wraps around to address 0,
+    /// byte by byte, like on the hardware. Otherwise it faults: the bounds check traps, and in the flat mode it
+    /// hits the guard page after the host reservation
+    pub address_wraparound: bool,
+    /// Running out of the executable code - falling through past the end of a region, a jump to a page that's not
+    /// executable - raises `GuestFault::PageFault` with `PF_INSTRUCTION_FETCH` once execution gets there. Otherwise
+    /// the block just ends there & returns to the host, which the code snippets without a `ret` rely on.
+    /// An instruction cut in half by the end of the code faults either way
+    pub fetch_faults: bool,
+    /// Check the alignment of every 2, 4 & 8-byte access, raising `GuestFault::AlignmentCheck` on a misaligned one
+    /// while `CpuContext::alignment_check` (EFLAGS.AC) is set. As if CR0.AM was set & the guest ran in ring 3.
+    /// Also good for finding the misaligned accesses the guest makes unintentionally
+    pub alignment_checks: bool,
+    /// Count the executions of every guest basic block, see coverage.rs (and `Runtime::coverage`)
+    pub coverage: bool,
+    /// Check every write to ESP against this address, calling `RuntimeHandler::stack_soft_limit` the first time it
+    /// goes below (the stack of `Runtime::setup_stack` can have its own)
+    pub stack_soft_limit: Option<u32>,
+    /// Software interrupts handled by the guest code instead of the host
+    pub interrupt_vectors: InterruptVectorTable,
+    /// What the control registers read as & what happens to the writes
+    pub system_registers: SystemRegisterProfile,
+}
+
+impl Default for TranslationOptions {
+    fn default() -> Self {
+        Self {
+            block_chaining: true,
+            per_instruction: false,
+            max_block_instructions: DEFAULT_MAX_BLOCK_INSTRUCTIONS,
+            instruction_hook: false,
+            peephole: false,
+            flag_liveness: false,
+            constant_addresses: false,
+            switch_tables: false,
+            inline_leaves: false,
+            shadow_flags: false,
+            memory_limit: None,
+            segmentation: SegmentationPolicy::default(),
+            invalid_opcodes: InvalidOpcodePolicy::default(),
+            unsupported_instructions: UnsupportedInstructionPolicy::default(),
+            code_mode: CodeMode::default(),
+            strict_alignment: false,
+            undefined_flags: UndefinedFlagsPolicy::default(),
+            flag_storage: FlagStorage::default(),
+            fault_sites: false,
+            address_wraparound: false,
+            fetch_faults: false,
+            alignment_checks: false,
+            coverage: false,
+            stack_soft_limit: None,
+            interrupt_vectors: InterruptVectorTable::default(),
+            system_registers: SystemRegisterProfile::default(),
+        }
+    }
+}
+
+/// Validated configuration, made by `RecompilerBuilder::build`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RecompilerConfig {
+    pub opt_level: OptLevel,
+    /// How much of the guest address space is backed by the host reservation
+    pub memory_size: u64,
+    /// Additional basic block starts (targets of indirect jumps, for example)
+    pub entry_points: Vec<u32>,
+    pub translation: TranslationOptions,
+    /// Where the translated code is kept between the runs (see `llvm::cache`)
+    pub translation_cache: Option<PathBuf>,
+    /// `Runtime::write_code` remembers what it overwrote, for `Runtime::revert_patches`
+    pub patch_log: bool,
+    /// `Runtime::write_data` fails on the pages the guest can't write to, instead of writing through the protection
+    pub strict_data_writes: bool,
+    /// How many entries `Runtime::translate_many` tries at once, each on its own thread with its own LLVM context
+    pub translation_threads: usize,
+}
+
+impl Default for RecompilerConfig {
+    fn default() -> Self {
+        Self {
+            opt_level: OptLevel::Aggressive,
+            memory_size: FULL_MEMORY_SIZE,
+            entry_points: Vec::new(),
+            translation: TranslationOptions::default(),
+            translation_cache: None,
+            patch_log: false,
+            strict_data_writes: false,
+            translation_threads: 1,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Instruction hooks are only called per-basic-block unless the per-instruction mode is on
+    InstructionHookWithoutPerInstruction,
+    /// The instruction hook would see the flags the liveness analysis decided not to store
+    FlagLivenessWithInstructionHook,
+    /// `FLAG_UNDEFINED` takes a byte, there is only a bit per flag in the packed storage
+    StrictUndefinedFlagsWithPackedStorage,
+    /// Should be a non-zero multiple of the page size, not more than 4 GiB
+    InvalidMemorySize(u64),
+    /// Without bounds checks an access past the end of a smaller memory would hit random host memory
+    MemorySizeWithoutBoundsChecking(u64),
+    /// Entry point does not fit in the configured memory
+    EntryPointOutOfMemory(u32),
+    /// A basic block has at least one instruction
+    ZeroMaxBlockInstructions,
+    /// `Runtime::translate_many` needs a thread to translate on
+    ZeroTranslationThreads,
+    /// The host didn't give us the memory for the guest
+    #[cfg(feature = "llvm")]
+    MemoryReservation(region::Error),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        use ConfigError::*;
+        match self {
+            InstructionHookWithoutPerInstruction => write!(
+                f,
+                "instruction hooks require the per-instruction compilation mode (call .per_instruction(true))"
+            ),
+            FlagLivenessWithInstructionHook => write!(
+                f,
+                "flag liveness can't be used with instruction hooks, as they would see stale flags"
+            ),
+            StrictUndefinedFlagsWithPackedStorage => write!(
+                f,
+                "the strict undefined flags policy needs the flags to be stored as bytes"
+            ),
+            InvalidMemorySize(size) => write!(
+                f,
+                "memory size 0x{:x} should be a non-zero multiple of 0x1000 not larger than 4 GiB",
+                size
+            ),
+            MemorySizeWithoutBoundsChecking(size) => write!(
+                f,
+                "memory size 0x{:x} is less than 4 GiB, which requires bounds checking (call .bounds_checking(true))",
+                size
+            ),
+            EntryPointOutOfMemory(addr) => {
+                write!(f, "entry point 0x{:08x} is outside of the guest memory", addr)
+            }
+            ZeroMaxBlockInstructions => {
+                write!(f, "the maximum block length should be at least one instruction")
+            }
+            ZeroTranslationThreads => {
+                write!(f, "the translation needs at least one thread")
+            }
+            #[cfg(feature = "llvm")]
+            MemoryReservation(e) => write!(f, "failed to reserve the guest memory: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+pub struct Recompiler;
+
+impl Recompiler {
+    pub fn builder() -> RecompilerBuilder {
+        RecompilerBuilder::default()
+    }
+}
+
+#[derive(Default)]
+pub struct RecompilerBuilder {
+    config: RecompilerConfig,
+    bounds_checking: bool,
+}
+
+impl RecompilerBuilder {
+    pub fn opt_level(mut self, opt_level: OptLevel) -> Self {
+        self.config.opt_level = opt_level;
+        self
+    }
+
+    pub fn memory_size(mut self, size: u64) -> Self {
+        self.config.memory_size = size;
+        self
+    }
+
+    pub fn bounds_checking(mut self, enabled: bool) -> Self {
+        self.bounds_checking = enabled;
+        self
+    }
+
+    pub fn block_chaining(mut self, enabled: bool) -> Self {
+        self.config.translation.block_chaining = enabled;
+        self
+    }
+
+    pub fn per_instruction(mut self, enabled: bool) -> Self {
+        self.config.translation.per_instruction = enabled;
+        self
+    }
+
+    pub fn max_block_instructions(mut self, max: usize) -> Self {
+        self.config.translation.max_block_instructions = max;
+        self
+    }
+
+    pub fn instruction_hook(mut self, enabled: bool) -> Self {
+        self.config.translation.instruction_hook = enabled;
+        self
+    }
+
+    pub fn peephole(mut self, enabled: bool) -> Self {
+        self.config.translation.peephole = enabled;
+        self
+    }
+
+    pub fn flag_liveness(mut self, enabled: bool) -> Self {
+        self.config.translation.flag_liveness = enabled;
+        self
+    }
+
+    pub fn constant_addresses(mut self, enabled: bool) -> Self {
+        self.config.translation.constant_addresses = enabled;
+        self
+    }
+
+    pub fn switch_tables(mut self, enabled: bool) -> Self {
+        self.config.translation.switch_tables = enabled;
+        self
+    }
+
+    pub fn inline_leaves(mut self, enabled: bool) -> Self {
+        self.config.translation.inline_leaves = enabled;
+        self
+    }
+
+    pub fn shadow_flags(mut self, enabled: bool) -> Self {
+        self.config.translation.shadow_flags = enabled;
+        self
+    }
+
+    pub fn coverage(mut self, enabled: bool) -> Self {
+        self.config.translation.coverage = enabled;
+        self
+    }
+
+    /// Costs a compare & a branch per write to ESP. `Runtime::setup_stack` uses it unless it's given its own limit
+    pub fn stack_soft_limit(mut self, limit: Option<u32>) -> Self {
+        self.config.translation.stack_soft_limit = limit;
+        self
+    }
+
+    pub fn segmentation(mut self, policy: SegmentationPolicy) -> Self {
+        self.config.translation.segmentation = policy;
+        self
+    }
+
+    pub fn invalid_opcodes(mut self, policy: InvalidOpcodePolicy) -> Self {
+        self.config.translation.invalid_opcodes = policy;
+        self
+    }
+
+    /// With `UnsupportedInstructionPolicy::Trap` a binary with an odd instruction here & there still translates,
+    /// and only fails if that instruction is actually executed
+    pub fn unsupported_instructions(mut self, policy: UnsupportedInstructionPolicy) -> Self {
+        self.config.translation.unsupported_instructions = policy;
+        self
+    }
+
+    pub fn code_mode(mut self, mode: CodeMode) -> Self {
+        self.config.translation.code_mode = mode;
+        self
+    }
+
+    pub fn strict_alignment(mut self, enabled: bool) -> Self {
+        self.config.translation.strict_alignment = enabled;
+        self
+    }
+
+    pub fn undefined_flags(mut self, policy: UndefinedFlagsPolicy) -> Self {
+        self.config.translation.undefined_flags = policy;
+        self
+    }
+
+    pub fn flag_storage(mut self, storage: FlagStorage) -> Self {
+        self.config.translation.flag_storage = storage;
+        self
+    }
+
+    /// Turn the accesses to the unmapped guest memory into `GuestFault::PageFault` (instead of crashing the host).
+    /// Costs a store per memory access & installs a SIGSEGV handler
+    pub fn fault_sites(mut self, enabled: bool) -> Self {
+        self.config.translation.fault_sites = enabled;
+        self
+    }
+
+    /// Costs a compare per multi-byte access
+    pub fn address_wraparound(mut self, enabled: bool) -> Self {
+        self.config.translation.address_wraparound = enabled;
+        self
+    }
+
+    pub fn fetch_faults(mut self, enabled: bool) -> Self {
+        self.config.translation.fetch_faults = enabled;
+        self
+    }
+
+    /// Costs a load, a test & a branch per multi-byte access
+    pub fn alignment_checks(mut self, enabled: bool) -> Self {
+        self.config.translation.alignment_checks = enabled;
+        self
+    }
+
+    pub fn interrupt_vectors(mut self, table: InterruptVectorTable) -> Self {
+        self.config.translation.interrupt_vectors = table;
+        self
+    }
+
+    pub fn system_registers(mut self, profile: SystemRegisterProfile) -> Self {
+        self.config.translation.system_registers = profile;
+        self
+    }
+
+    pub fn translation_cache(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.config.translation_cache = Some(dir.into());
+        self
+    }
+
+    pub fn patch_log(mut self, enabled: bool) -> Self {
+        self.config.patch_log = enabled;
+        self
+    }
+
+    pub fn strict_data_writes(mut self, enabled: bool) -> Self {
+        self.config.strict_data_writes = enabled;
+        self
+    }
+
+    pub fn translation_threads(mut self, threads: usize) -> Self {
+        self.config.translation_threads = threads;
+        self
+    }
+
+    pub fn entry_point(mut self, addr: u32) -> Self {
+        self.config.entry_points.push(addr);
+        self
+    }
+
+    pub fn build(self) -> Result<RecompilerConfig, ConfigError> {
+        let mut config = self.config;
+
+        let size = config.memory_size;
+        if size == 0 || size > FULL_MEMORY_SIZE || !size.is_multiple_of(0x1000) {
+            return Err(ConfigError::InvalidMemorySize(size));
+        }
+        if size < FULL_MEMORY_SIZE && !self.bounds_checking {
+            return Err(ConfigError::MemorySizeWithoutBoundsChecking(size));
+        }
+        if let Some(&addr) = config.entry_points.iter().find(|&&a| a as u64 >= size) {
+            return Err(ConfigError::EntryPointOutOfMemory(addr));
+        }
+
+        if config.translation.max_block_instructions == 0 {
+            return Err(ConfigError::ZeroMaxBlockInstructions);
+        }
+        if config.translation_threads == 0 {
+            return Err(ConfigError::ZeroTranslationThreads);
+        }
+        if config.translation.instruction_hook && !config.translation.per_instruction {
+            return Err(ConfigError::InstructionHookWithoutPerInstruction);
+        }
+        if config.translation.instruction_hook && config.translation.flag_liveness {
+            return Err(ConfigError::FlagLivenessWithInstructionHook);
+        }
+        if config.translation.undefined_flags == UndefinedFlagsPolicy::Strict
+            && config.translation.flag_storage == FlagStorage::Packed
+        {
+            return Err(ConfigError::StrictUndefinedFlagsWithPackedStorage);
+        }
+
+        config.translation.memory_limit = if self.bounds_checking {
+            Some(size)
+        } else {
+            None
+        };
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConfigError, OptLevel, Recompiler, RecompilerConfig, FULL_MEMORY_SIZE};
+    use crate::ir::UnsupportedInstructionPolicy;
+    use crate::segmentation::{SegmentationMode, SegmentationPolicy};
+    use crate::system_registers::SystemRegisterProfile;
+    use crate::types::{FlagStorage, UndefinedFlagsPolicy};
+
+    #[test_log::test]
+    fn defaults() {
+        let config = Recompiler::builder().build().unwrap();
+        assert_eq!(config, RecompilerConfig::default());
+        assert_eq!(config.translation.memory_limit, None);
+    }
+
+    #[test_log::test]
+    fn full_config() {
+        let config = Recompiler::builder()
+            .opt_level(OptLevel::None)
+            .memory_size(0x100000)
+            .bounds_checking(true)
+            .block_chaining(false)
+            .per_instruction(true)
+            .max_block_instructions(100)
+            .instruction_hook(true)
+            .peephole(true)
+            .constant_addresses(true)
+            .switch_tables(true)
+            .segmentation(SegmentationPolicy::checked())
+            .unsupported_instructions(UnsupportedInstructionPolicy::Trap)
+            .strict_alignment(true)
+            .undefined_flags(UndefinedFlagsPolicy::Strict)
+            .fault_sites(true)
+            .address_wraparound(true)
+            .fetch_faults(true)
+            .alignment_checks(true)
+            .coverage(true)
+            .stack_soft_limit(Some(0x7000))
+            .system_registers(SystemRegisterProfile {
+                user_mode: true,
+                ..SystemRegisterProfile::default()
+            })
+            .translation_cache("/tmp/rusty-x86")
+            .translation_threads(4)
+            .entry_point(0x1000)
+            .build()
+            .unwrap();
+
+        assert_eq!(config.opt_level, OptLevel::None);
+        assert_eq!(config.memory_size, 0x100000);
+        assert_eq!(config.entry_points, vec![0x1000]);
+        assert_eq!(config.translation.memory_limit, Some(0x100000));
+        assert!(!config.translation.block_chaining);
+        assert!(config.translation.per_instruction);
+        assert_eq!(config.translation.max_block_instructions, 100);
+        assert!(config.translation.instruction_hook);
+        assert!(config.translation.peephole);
+        assert!(config.translation.constant_addresses);
+        assert!(config.translation.switch_tables);
+        assert!(config.translation.strict_alignment);
+        assert!(config.translation.fault_sites);
+        assert!(config.translation.address_wraparound);
+        assert!(config.translation.fetch_faults);
+        assert!(config.translation.alignment_checks);
+        assert!(config.translation.coverage);
+        assert_eq!(config.translation.stack_soft_limit, Some(0x7000));
+        assert!(config.translation.system_registers.user_mode);
+        assert_eq!(
+            config.translation_cache.as_deref(),
+            Some(std::path::Path::new("/tmp/rusty-x86"))
+        );
+        assert_eq!(config.translation_threads, 4);
+        assert_eq!(
+            config.translation.undefined_flags,
+            UndefinedFlagsPolicy::Strict
+        );
+        assert_eq!(
+            config.translation.segmentation.mode,
+            SegmentationMode::Checked
+        );
+        assert_eq!(
+            config.translation.unsupported_instructions,
+            UnsupportedInstructionPolicy::Trap
+        );
+    }
+
+    #[test_log::test]
+    fn bounds_checking_full_memory() {
+        // allowed, though not very useful
+        let config = Recompiler::builder().bounds_checking(true).build().unwrap();
+        assert_eq!(config.translation.memory_limit, Some(FULL_MEMORY_SIZE));
+    }
+
+    #[test_log::test]
+    fn hook_without_per_instruction() {
+        let err = Recompiler::builder()
+            .instruction_hook(true)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::InstructionHookWithoutPerInstruction
+        ));
+        assert_eq!(
+            err.to_string(),
+            "instruction hooks require the per-instruction compilation mode (call .per_instruction(true))"
+        );
+    }
+
+    #[test_log::test]
+    fn zero_max_block_instructions() {
+        let err = Recompiler::builder()
+            .max_block_instructions(0)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::ZeroMaxBlockInstructions));
+    }
+
+    #[test_log::test]
+    fn zero_translation_threads() {
+        let err = Recompiler::builder()
+            .translation_threads(0)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::ZeroTranslationThreads));
+    }
+
+    #[test_log::test]
+    fn flag_liveness() {
+        let config = Recompiler::builder().flag_liveness(true).build().unwrap();
+        assert!(config.translation.flag_liveness);
+
+        let err = Recompiler::builder()
+            .per_instruction(true)
+            .instruction_hook(true)
+            .flag_liveness(true)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::FlagLivenessWithInstructionHook));
+    }
+
+    #[test_log::test]
+    fn packed_flags() {
+        let config = Recompiler::builder()
+            .flag_storage(FlagStorage::Packed)
+            .build()
+            .unwrap();
+        assert_eq!(config.translation.flag_storage, FlagStorage::Packed);
+
+        let err = Recompiler::builder()
+            .flag_storage(FlagStorage::Packed)
+            .undefined_flags(UndefinedFlagsPolicy::Strict)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::StrictUndefinedFlagsWithPackedStorage
+        ));
+    }
+
+    #[test_log::test]
+    fn bad_memory_size() {
+        for size in [0, 0x1234, FULL_MEMORY_SIZE + 0x1000] {
+            let err = Recompiler::builder()
+                .memory_size(size)
+                .bounds_checking(true)
+                .build()
+                .unwrap_err();
+            assert!(matches!(err, ConfigError::InvalidMemorySize(s) if s == size));
+        }
+    }
+
+    #[test_log::test]
+    fn small_memory_needs_bounds_checking() {
+        let err = Recompiler::builder()
+            .memory_size(0x10000)
+            .build()
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            ConfigError::MemorySizeWithoutBoundsChecking(0x10000)
+        ));
+    }
+
+    #[test_log::test]
+    fn entry_point_out_of_memory() {
+        let err = Recompiler::builder()
+            .memory_size(0x10000)
+            .bounds_checking(true)
+            .entry_point(0x1000)
+            .entry_point(0x20000)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, ConfigError::EntryPointOutOfMemory(0x20000)));
+        assert_eq!(
+            err.to_string(),
+            "entry point 0x00020000 is outside of the guest memory"
+        );
+    }
+}
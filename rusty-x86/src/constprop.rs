@@ -0,0 +1,290 @@
+//! Folds the memory operands with a constant address, like in `mov eax, 0x1000; mov ebx, [eax + 8]`
+//!
+//! Walks the block forward keeping track of the 32-bit registers holding a value known at the translation time
+//! (set by `mov r, imm` & a few other simple forms), and rewrites the memory operands computed from those registers
+//! only into `[disp32]`. The backend then sees a constant address & resolves the access once while translating (see
+//! `LlvmBuilder::constant_address`) instead of checking it every time it runs.
+//!
+//! The domain is just "constant or unknown" per register. Anything we don't model forgets all of them, so every
+//! call, handler or string instruction is a barrier; the guest-visible state ends up exactly the same as without the
+//! rewrite
+
+use crate::ir::{Instr, Mnemonic};
+use crate::peephole::CompilationStats;
+use crate::segmentation::default_segment;
+use crate::types::{
+    FullSizeGeneralPurposeRegister, IntType, MemoryOperand, Operand, Register, SegmentRegister,
+};
+
+/// What is known about the registers at some point of the block
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct KnownRegisters([Option<u32>; 8]);
+
+impl KnownRegisters {
+    /// The value of a full-size register, if it's known
+    pub fn get(&self, register: Register) -> Option<u32> {
+        if register.size() != IntType::I32 {
+            return None;
+        }
+        self.0[register.base_register() as usize]
+    }
+
+    fn set(&mut self, register: Register, value: Option<u32>) {
+        let base = register.base_register();
+        // a write to the low part leaves the rest of the register alone, but we don't bother.
+        // ESP is never tracked: `push` & `pop` move it in between computing the address & accessing it
+        self.0[base as usize] = match value {
+            Some(value)
+                if register.size() == IntType::I32
+                    && base != FullSizeGeneralPurposeRegister::ESP =>
+            {
+                Some(value)
+            }
+            _ => None,
+        };
+    }
+
+    fn forget_operand(&mut self, operand: &Operand) {
+        if let Operand::Register(register) = operand {
+            self.set(*register, None);
+        }
+    }
+
+    fn forget_all(&mut self) {
+        *self = Self::default();
+    }
+
+    /// The operand computed from the known registers only, as `[disp32]`
+    pub fn fold(&self, mem: &MemoryOperand) -> Option<MemoryOperand> {
+        if mem.base.is_none() && mem.index.is_none() {
+            return None;
+        }
+        let base = match mem.base {
+            Some(base) => self.get(base)?,
+            None => 0,
+        };
+        let index = match mem.index {
+            Some(index) => self.get(index)?.wrapping_mul(mem.scale as u32),
+            None => 0,
+        };
+        let address = base
+            .wrapping_add(index)
+            .wrapping_add(mem.displacement as u32);
+
+        // `[ebp + x]` & `[esp + x]` are SS-relative, which has to stay that way without the base
+        let segment = match mem.segment {
+            None if default_segment(mem) == SegmentRegister::SS => Some(SegmentRegister::SS),
+            segment => segment,
+        };
+        Some(MemoryOperand {
+            segment,
+            ..MemoryOperand::absolute(address, mem.size)
+        })
+    }
+
+    /// Updates the state with the effects of the instruction
+    fn step(&mut self, instr: &Instr) {
+        use Mnemonic::*;
+
+        match (instr.mnemonic, instr.operands.as_slice()) {
+            (Mov, [Operand::Register(dst), Operand::Immediate32(value)]) => {
+                self.set(*dst, Some(*value))
+            }
+            (Mov, [Operand::Register(dst), Operand::Register(src)]) => {
+                self.set(*dst, self.get(*src))
+            }
+            (ZeroReg, [Operand::Register(dst)]) => self.set(*dst, Some(0)),
+            (AddNoFlags, [Operand::Register(dst), Operand::Immediate32(value)]) => {
+                self.set(*dst, self.get(*dst).map(|v| v.wrapping_add(*value)))
+            }
+            (Nop | Cmp | Test | Bt | Jcc(_) | TestJcc(_) | CmpJcc(_), _) => {}
+            (
+                Mov | Movzx | Movsx | Lea | Add | Adc | Sub | Sbb | And | Or | Xor | Not | Neg
+                | Inc | Dec | Shl | Shr | Sar | Shld | Shrd | Rol | Ror | Bts | Btr | Btc | Bsf
+                | Bsr | Bswap | Cmovcc(_) | Setcc(_) | SetccZx(_),
+                [dst, ..],
+            ) => self.forget_operand(dst),
+            (CmpCmovcc(_), [_, _, dst, _]) => self.forget_operand(dst),
+            (Xchg, [a, b]) => {
+                self.forget_operand(a);
+                self.forget_operand(b);
+            }
+            (Push, _) => {}
+            (Pop, [dst]) => self.forget_operand(dst),
+            _ => self.forget_all(),
+        }
+    }
+}
+
+/// Rewrites the memory operands with a constant address in the block
+pub fn fold_addresses(mut block: Vec<Instr>, stats: &mut CompilationStats) -> Vec<Instr> {
+    let mut known = KnownRegisters::default();
+    for instr in &mut block {
+        for operand in &mut instr.operands {
+            if let Operand::Memory(mem) = operand {
+                if let Some(folded) = known.fold(mem) {
+                    *mem = folded;
+                    stats.constant_addresses += 1;
+                }
+            }
+        }
+        known.step(instr);
+    }
+    block
+}
+
+#[cfg(test)]
+mod tests {
+    use super::fold_addresses;
+    use crate::assemble_x86;
+    use crate::ir::{
+        decode_block, CodeMode, Instr, InvalidOpcodePolicy, UnsupportedInstructionPolicy,
+    };
+    use crate::peephole::{self, CompilationStats};
+
+    fn decode(code: &[u8]) -> Vec<Instr> {
+        let block = decode_block(
+            code,
+            0x100,
+            usize::MAX,
+            InvalidOpcodePolicy::Fault,
+            UnsupportedInstructionPolicy::Fail,
+            CodeMode::Bits32,
+        )
+        .unwrap();
+        peephole::optimize(block, &mut CompilationStats::default())
+    }
+
+    fn folded(code: &[u8]) -> (Vec<String>, usize) {
+        let mut stats = CompilationStats::default();
+        let block = fold_addresses(decode(code), &mut stats);
+        (
+            block.iter().map(|i| i.to_string()).collect(),
+            stats.constant_addresses,
+        )
+    }
+
+    #[test_log::test]
+    fn folds() {
+        let (block, count) = folded(&assemble_x86!(
+            ; mov eax, 0x100000
+            ; mov ebx, [eax + 8]
+            ; mov ecx, eax
+            ; mov DWORD [ecx + eax * 2 - 4], 1
+            ; xor edx, edx
+            ; lea edx, [edx + 0x20]
+            ; mov esi, [edx]
+            ; mov ebp, 0x2000
+            ; mov edi, [ebp + 4]
+        ));
+        assert_eq!(count, 4);
+        assert_eq!(block[1], "mov ebx, dword [0x100008]");
+        assert_eq!(block[3], "mov dword [0x2ffffc], dword 0x1");
+        assert_eq!(block[6], "mov esi, dword [0x20]");
+        assert_eq!(block[8], "mov edi, dword [ss:0x2004]");
+    }
+
+    #[test_log::test]
+    fn forgets() {
+        let (block, count) = folded(&assemble_x86!(
+            ; mov eax, 0x100000
+            ; mov al, 1
+            ; mov ebx, [eax]
+            ; mov eax, 0x100000
+            ; add eax, 4
+            ; mov ebx, [eax]
+            ; mov eax, 0x100000
+            ; call ->next
+            ; ->next:
+            ; mov ebx, [eax]
+            ; mov eax, 0x100000
+            ; mov ebx, [ebx + eax]
+            ; pop eax
+            ; mov ebx, [eax]
+        ));
+        assert_eq!(count, 0);
+        assert_eq!(block[2], "mov ebx, dword [eax]");
+    }
+
+    #[cfg(feature = "interp")]
+    mod differential {
+        use super::decode;
+        use crate::assemble_x86;
+        use crate::constprop::fold_addresses;
+        use crate::handler::NullHandler;
+        use crate::interp::{Interpreter, StepResult};
+        use crate::peephole::CompilationStats;
+        use crate::types::{CpuContext, FullSizeGeneralPurposeRegister::*};
+
+        fn run(block: &[crate::ir::Instr], initial: &CpuContext) -> (CpuContext, Vec<u8>) {
+            let mut interp = Interpreter::new(vec![0; 0x1000], NullHandler);
+            interp.context = initial.clone();
+            for instr in block {
+                assert_eq!(interp.execute(instr), StepResult::Continue);
+            }
+            (interp.context, interp.memory)
+        }
+
+        /// Runs the block with and without the folding from a bunch of different states
+        fn check(code: &[u8]) {
+            let block = decode(code);
+            let mut stats = CompilationStats::default();
+            let folded = fold_addresses(block.clone(), &mut stats);
+            assert_ne!(stats.constant_addresses, 0, "nothing was folded");
+
+            for value in [0, 0x10, 0x7f, 0x200] {
+                let mut initial = CpuContext::default();
+                for reg in [EAX, EBX, ECX, EDX, ESI, EDI] {
+                    initial.set_gp_reg(reg, value);
+                }
+                initial.set_gp_reg(ESP, 0x800);
+                initial.set_gp_reg(EBP, 0x900);
+
+                assert_eq!(
+                    run(&block, &initial),
+                    run(&folded, &initial),
+                    "mismatch with value = 0x{:x}",
+                    value
+                );
+            }
+        }
+
+        #[test_log::test]
+        fn loads_and_stores() {
+            check(&assemble_x86!(
+                ; mov eax, 0x100
+                ; mov ebx, [eax + 8]
+                ; mov [eax + 0x10], ebx
+                ; add DWORD [eax], 3
+            ));
+            check(&assemble_x86!(
+                ; mov esi, 0x40
+                ; mov edi, esi
+                ; movzx ecx, WORD [esi + edi * 4]
+                ; mov [edi + ecx], cl
+            ));
+            check(&assemble_x86!(
+                ; xor edx, edx
+                ; lea edx, [edx + 0x300]
+                ; cmp DWORD [edx], 5
+                ; push DWORD [edx + 4]
+                ; mov ebp, 0x700
+                ; pop DWORD [ebp - 4]
+            ));
+        }
+
+        #[test_log::test]
+        fn partially_known() {
+            // `ebx` is only known in between
+            check(&assemble_x86!(
+                ; mov ebx, 0x100
+                ; mov eax, [ebx + ecx]
+                ; mov ecx, [ebx]
+                ; mov bl, 0x20
+                ; mov edx, [ebx]
+                ; add ebx, eax
+                ; mov edx, [ebx]
+            ));
+        }
+    }
+}
@@ -0,0 +1,234 @@
+//! Which guest basic blocks ran & how many times, for the reverse-engineering tools
+//!
+//! With `TranslationOptions::coverage` every guest basic block counts its executions as it starts. A translated
+//! block doesn't stop at a conditional branch or a call, so the code after those is counted as a basic block of
+//! its own (see `sub_blocks`): the numbers don't depend on how the blocks were chained together.
+//!
+//! The result can be exported as DRcov (what lighthouse & co load) or as a plain CSV:
+//!
+//! ```ignore
+//! runtime.run(entry);
+//! runtime.coverage().write_drcov(&mut File::create("trace.drcov")?)?;
+//! ```
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use crate::ir::Instr;
+
+/// A guest basic block as counted by the coverage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockExtent {
+    /// Of the first instruction of the block in the translated block
+    pub index: usize,
+    pub address: u32,
+    /// In bytes
+    pub size: u32,
+}
+
+/// Splits a translated block into the basic blocks the coverage counts: a new one starts after every branch
+pub fn sub_blocks(block: &[Instr]) -> Vec<BlockExtent> {
+    let mut res: Vec<BlockExtent> = Vec::new();
+    let mut starts_block = true;
+    for (index, instr) in block.iter().enumerate() {
+        if starts_block {
+            res.push(BlockExtent {
+                index,
+                address: instr.ip,
+                size: 0,
+            });
+        }
+        let current = res.last_mut().unwrap();
+        current.size = instr.next_ip().wrapping_sub(current.address);
+        starts_block = instr.mnemonic.is_branch();
+    }
+    res
+}
+
+/// Where the code was loaded from, so that the tools can match the blocks to their disassembly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CoverageModule {
+    pub base: u32,
+    pub end: u32,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockHits {
+    pub size: u32,
+    pub hits: u64,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    /// The blocks outside of all of them are left out of the DRcov export
+    pub modules: Vec<CoverageModule>,
+    /// By the address of the block
+    pub blocks: BTreeMap<u32, BlockHits>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Counts an execution of the block
+    pub fn record(&mut self, address: u32, size: u32) {
+        self.blocks
+            .entry(address)
+            .or_insert(BlockHits { size, hits: 0 })
+            .hits += 1;
+    }
+
+    pub fn hits(&self, address: u32) -> u64 {
+        self.blocks.get(&address).map_or(0, |block| block.hits)
+    }
+
+    fn module_of(&self, address: u32) -> Option<(usize, &CoverageModule)> {
+        self.modules
+            .iter()
+            .enumerate()
+            .find(|(_, module)| module.base <= address && address < module.end)
+    }
+
+    /// DRcov version 2: a text header with the module table, then a binary record for every block that ran
+    /// (`u32` offset from the module base, `u16` size, `u16` module id, all little-endian). DRcov has no hit
+    /// counts, that's what `write_csv` is for
+    pub fn write_drcov(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "DRCOV VERSION: 2")?;
+        writeln!(w, "DRCOV FLAVOR: drcov")?;
+        writeln!(w, "Module Table: version 2, count {}", self.modules.len())?;
+        writeln!(
+            w,
+            "Columns: id, base, end, entry, checksum, timestamp, path"
+        )?;
+        for (id, module) in self.modules.iter().enumerate() {
+            writeln!(
+                w,
+                "{}, 0x{:08x}, 0x{:08x}, 0x{:016x}, 0x{:08x}, 0x{:08x}, {}",
+                id, module.base, module.end, 0, 0, 0, module.name
+            )?;
+        }
+
+        let records: Vec<_> = self
+            .blocks
+            .iter()
+            .filter_map(|(&address, block)| {
+                let (id, module) = self.module_of(address)?;
+                Some((address - module.base, block.size.min(u16::MAX as u32), id))
+            })
+            .collect();
+        writeln!(w, "BB Table: {} bbs", records.len())?;
+        for (offset, size, id) in records {
+            w.write_all(&offset.to_le_bytes())?;
+            w.write_all(&(size as u16).to_le_bytes())?;
+            w.write_all(&(id as u16).to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// `address,count` a line, with a header
+    pub fn write_csv(&self, w: &mut impl Write) -> io::Result<()> {
+        writeln!(w, "address,count")?;
+        for (address, block) in &self.blocks {
+            writeln!(w, "0x{:08x},{}", address, block.hits)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{sub_blocks, BlockExtent, Coverage, CoverageModule};
+    use crate::assemble_x86;
+    use crate::ir::{decode_block, CodeMode, InvalidOpcodePolicy, UnsupportedInstructionPolicy};
+
+    #[test_log::test]
+    fn split_at_branches() {
+        let code = assemble_x86!(
+            ; cmp eax, 1
+            ; je ->end
+            ; mov ebx, 2
+            ; call ->end
+            ; inc ebx
+            ; ->end:
+            ; ret
+        );
+        let block = decode_block(
+            &code,
+            0x1000,
+            usize::MAX,
+            InvalidOpcodePolicy::Fault,
+            UnsupportedInstructionPolicy::Fail,
+            CodeMode::Bits32,
+        )
+        .unwrap();
+
+        let extent = |index, address, size| BlockExtent {
+            index,
+            address,
+            size,
+        };
+        assert_eq!(
+            sub_blocks(&block),
+            vec![
+                // cmp, je (near)
+                extent(0, 0x1000, 11),
+                // mov, call
+                extent(2, 0x100b, 10),
+                // inc, ret
+                extent(4, 0x1015, 2),
+            ]
+        );
+    }
+
+    fn fixture() -> Coverage {
+        let mut coverage = Coverage::new();
+        coverage.modules.push(CoverageModule {
+            base: 0x1000,
+            end: 0x2000,
+            name: "guest.exe".to_string(),
+        });
+        coverage.record(0x1010, 5);
+        coverage.record(0x1000, 0x10);
+        coverage.record(0x1010, 5);
+        // not in any module
+        coverage.record(0x3000, 1);
+        coverage
+    }
+
+    #[test_log::test]
+    fn drcov_layout() {
+        let mut out = Vec::new();
+        fixture().write_drcov(&mut out).unwrap();
+
+        let header = "\
+DRCOV VERSION: 2
+DRCOV FLAVOR: drcov
+Module Table: version 2, count 1
+Columns: id, base, end, entry, checksum, timestamp, path
+0, 0x00001000, 0x00002000, 0x0000000000000000, 0x00000000, 0x00000000, guest.exe
+BB Table: 2 bbs
+";
+        assert_eq!(&out[..header.len()], header.as_bytes());
+        assert_eq!(
+            &out[header.len()..],
+            &[
+                0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x00, 0x00, // 0x1000, 16 bytes, module 0
+                0x10, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, // 0x1010, 5 bytes, module 0
+            ]
+        );
+    }
+
+    #[test_log::test]
+    fn csv() {
+        let mut out = Vec::new();
+        fixture().write_csv(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "address,count\n0x00001000,1\n0x00001010,2\n0x00003000,1\n"
+        );
+        assert_eq!(fixture().hits(0x1010), 2);
+        assert_eq!(fixture().hits(0x1020), 0);
+    }
+}
@@ -1,8 +1,12 @@
+use crate::ir::{DecodeError, Decoder, Mnemonic};
+use crate::memory_image::MemoryImage;
 use crate::types::{IntType, MemoryOperand, Operand, Register, SegmentRegister};
 use iced_x86::{
-    Formatter, Instruction, MemorySize, NasmFormatter, OpKind, Register as IcedRegister,
+    DecoderOptions, Formatter, Instruction, MemorySize, NasmFormatter, OpKind,
+    Register as IcedRegister,
 };
-use std::fmt::Write;
+use std::collections::BTreeMap;
+use std::fmt::Display;
 
 fn get_register(iced_register: IcedRegister) -> Register {
     use Register::*;
@@ -71,13 +75,20 @@ pub fn get_operand(instr: &Instruction, operand: u32) -> Operand {
     let op_kind = instr.op_kind(operand);
 
     match op_kind {
-        OpKind::Register => Register(get_register(instr.op_register(operand))),
+        OpKind::Register => match instr.op_register(operand) {
+            reg if reg.is_st() => FpuRegister(reg.number() as u8),
+            reg if reg.is_xmm() => Xmm(reg.number() as u8),
+            reg if reg.is_mm() => Mmx(reg.number() as u8),
+            reg if reg.is_cr() => ControlRegister(reg.number() as u8),
+            reg => Register(get_register(reg)),
+        },
 
-        OpKind::NearBranch16 => panic!("unsupported branch address size (16)"),
+        // IP wraps around at 64 KiB, iced takes care of it
+        OpKind::NearBranch16 => Immediate32(instr.near_branch16() as u32),
         OpKind::NearBranch32 => Immediate32(instr.near_branch32()),
         OpKind::NearBranch64 => panic!("unsupported branch address size (64)"),
 
-        OpKind::FarBranch16 => panic!("unsupported far branch address size (16)"),
+        OpKind::FarBranch16 => FarBranch(instr.far_branch_selector(), instr.far_branch16() as u32),
         OpKind::FarBranch32 => FarBranch(instr.far_branch_selector(), instr.far_branch32()),
 
         OpKind::Immediate8 => Immediate8(instr.immediate8()),
@@ -91,7 +102,11 @@ pub fn get_operand(instr: &Instruction, operand: u32) -> Operand {
         OpKind::Immediate8to64 => Immediate64(instr.immediate8to64() as u64),
         OpKind::Immediate32to64 => Immediate64(instr.immediate32to64() as u64),
 
-        OpKind::Memory | OpKind::MemoryESEDI | OpKind::MemorySegESI => {
+        OpKind::Memory
+        | OpKind::MemoryESEDI
+        | OpKind::MemorySegESI
+        | OpKind::MemoryESDI
+        | OpKind::MemorySegSI => {
             let memory_size = match instr.memory_size() {
                 MemorySize::UInt8 => Some(IntType::I8),
                 MemorySize::UInt16 => Some(IntType::I16),
@@ -103,20 +118,69 @@ pub fn get_operand(instr: &Instruction, operand: u32) -> Operand {
                 MemorySize::Int32 | MemorySize::DwordOffset => Some(IntType::I32),
                 MemorySize::Int64 => Some(IntType::I64),
 
+                // bound: a pair of signed values (lower, upper), read in one go
+                MemorySize::Bound16_WordWord => Some(IntType::I32),
+                MemorySize::Bound32_DwordDword => Some(IntType::I64),
+
+                // x87 loads & stores, the bits are converted by the instruction itself
+                MemorySize::Float32 => Some(IntType::I32),
+                MemorySize::Float64 => Some(IntType::I64),
+
+                // SSE, the lanes are split by the instruction
+                MemorySize::UInt128
+                | MemorySize::Packed128_UInt8
+                | MemorySize::Packed128_Int8
+                | MemorySize::Packed128_UInt16
+                | MemorySize::Packed128_Int16
+                | MemorySize::Packed128_UInt32
+                | MemorySize::Packed128_Int32
+                | MemorySize::Packed128_UInt64
+                | MemorySize::Packed128_Int64
+                | MemorySize::Packed128_Float32
+                | MemorySize::Packed128_Float64 => Some(IntType::I128),
+                // an mmx register or the low half of an xmm one
+                MemorySize::Packed64_UInt8
+                | MemorySize::Packed64_Int8
+                | MemorySize::Packed64_UInt16
+                | MemorySize::Packed64_Int16
+                | MemorySize::Packed64_UInt32
+                | MemorySize::Packed64_Int32
+                | MemorySize::Packed64_Float32 => Some(IntType::I64),
+
                 MemorySize::Unknown => None,
 
                 s => panic!("Unsupported memory size: {:?}", s),
             };
 
             let op = match op_kind {
-                OpKind::Memory => MemoryOperand {
-                    base: get_opt_register(instr.memory_base()),
-                    displacement: instr.memory_displacement32() as i32 as i64,
-                    scale: instr.memory_index_scale() as u8,
-                    index: get_opt_register(instr.memory_index()),
-                    size: memory_size,
-                    segment: get_opt_segment(instr.segment_prefix()),
-                },
+                OpKind::Memory => {
+                    // the SIB irregularities are resolved by iced: index=100b is no index (whatever the scale bits
+                    // say), base=101b with mod=00 is no base & a disp32
+                    let index = get_opt_register(instr.memory_index());
+                    assert_ne!(index, Some(super::Register::ESP), "ESP can't be an index");
+                    let base = get_opt_register(instr.memory_base());
+                    // iced gives the displacements of the 16-bit addresses (`[bx-8]`) zero-extended from 16 bits
+                    let displacement = instr.memory_displacement32();
+                    let displacement = match base.or(index) {
+                        Some(register) if register.size() == IntType::I16 => {
+                            displacement as u16 as i16 as i64
+                        }
+                        _ => displacement as i32 as i64,
+                    };
+                    MemoryOperand {
+                        base,
+                        displacement,
+                        // normalized, so that all the encodings of the same address compare equal
+                        scale: if index.is_some() {
+                            instr.memory_index_scale() as u8
+                        } else {
+                            1
+                        },
+                        index,
+                        size: memory_size,
+                        segment: get_opt_segment(instr.segment_prefix()),
+                    }
+                }
                 OpKind::MemoryESEDI => MemoryOperand {
                     base: Some(super::Register::EDI),
                     displacement: 0,
@@ -133,6 +197,23 @@ pub fn get_operand(instr: &Instruction, operand: u32) -> Operand {
                     size: memory_size,
                     segment: get_opt_segment(instr.segment_prefix()),
                 },
+                // the string instructions with the 0x67 prefix
+                OpKind::MemoryESDI => MemoryOperand {
+                    base: Some(super::Register::DI),
+                    displacement: 0,
+                    scale: 0,
+                    index: None,
+                    size: memory_size,
+                    segment: Some(SegmentRegister::ES),
+                },
+                OpKind::MemorySegSI => MemoryOperand {
+                    base: Some(super::Register::SI),
+                    displacement: 0,
+                    scale: 0,
+                    index: None,
+                    size: memory_size,
+                    segment: get_opt_segment(instr.segment_prefix()),
+                },
                 _ => unreachable!(),
             };
             Memory(op)
@@ -183,18 +264,183 @@ impl Operands for Instruction {
     }
 }
 
-pub fn disassemble(code: &[u8], base_addr: u64) -> String {
-    let mut decoder = iced_x86::Decoder::with_ip(32, code, base_addr, 0);
-    let mut formatter = NasmFormatter::new();
-    let mut output = String::new();
-    let mut instruction = Instruction::default();
-    while decoder.can_decode() {
-        decoder.decode_out(&mut instruction);
-        write!(output, "{:08X} ", instruction.ip()).unwrap();
+/// Names of the guest addresses, for annotating the branch targets in a listing
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SymbolTable {
+    symbols: BTreeMap<u32, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, address: u32, name: impl Into<String>) {
+        self.symbols.insert(address, name.into());
+    }
+
+    /// `name` if there is a symbol right at `address`, `name+0x10` if `address` is after it
+    pub fn describe(&self, address: u32) -> Option<String> {
+        let (&start, name) = self.symbols.range(..=address).next_back()?;
+        Some(match address - start {
+            0 => name.clone(),
+            offset => format!("{}+0x{:x}", name, offset),
+        })
+    }
+}
+
+/// One instruction of a listing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DisasmLine {
+    pub address: u32,
+    pub bytes: Vec<u8>,
+    /// Intel syntax (see intel_syntax.rs)
+    pub text: String,
+    /// Where the branch goes, if it goes to a known symbol
+    pub target_symbol: Option<String>,
+    /// The bytes are not an instruction (or are cut short by the end of the memory)
+    pub invalid: bool,
+}
+
+impl Display for DisasmLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = self
+            .bytes
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(f, "{:08x}  {:<24} ", self.address, bytes)?;
+        if self.invalid {
+            write!(f, "(bad)")?;
+        } else {
+            write!(f, "{}", self.text)?;
+        }
+        if let Some(symbol) = &self.target_symbol {
+            write!(f, " <{}>", symbol)?;
+        }
+        Ok(())
+    }
+}
+
+/// What iced makes of the instruction at the start of `code`, for the ones we don't decode into an `Instr`:
+/// the length & the text
+fn iced_text(code: &[u8], address: u32) -> Option<(usize, String)> {
+    let instr = iced_x86::Decoder::with_ip(32, code, address as u64, DecoderOptions::NONE).decode();
+    if instr.is_invalid() {
+        return None;
+    }
+    let mut text = String::new();
+    NasmFormatter::new().format(&instr, &mut text);
+    Some((instr.len(), text))
+}
+
+/// Decodes up to `len` bytes of `memory` starting at `start`, or until the readable memory ends
+pub fn disassemble(
+    memory: &MemoryImage,
+    start: u32,
+    len: u32,
+    symbols: &SymbolTable,
+) -> Vec<DisasmLine> {
+    let code = memory.read_all_at(start);
+    let code = &code[..code.len().min(len as usize)];
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let address = start.wrapping_add(offset as u32);
+        let rest = &code[offset..];
+        let line = |len: usize, text: String, target_symbol| DisasmLine {
+            address,
+            bytes: rest[..len].to_vec(),
+            text,
+            target_symbol,
+            invalid: false,
+        };
+
+        let line = match Decoder::new(rest, address).decode() {
+            Ok(instr) if instr.mnemonic != Mnemonic::Invalid => {
+                let target = match instr.operands.last() {
+                    Some(Operand::Immediate32(target)) if instr.mnemonic.is_branch() => {
+                        symbols.describe(*target)
+                    }
+                    _ => None,
+                };
+                line(instr.len as usize, instr.to_string(), target)
+            }
+            // `ud2` & co are `Invalid` too, and the instructions we don't translate are still worth listing
+            Ok(_) | Err(DecodeError::Unsupported { .. }) => match iced_text(rest, address) {
+                Some((len, text)) => line(len, text, None),
+                // a byte at a time, so that the decoding gets back in sync with the instructions after it
+                None => DisasmLine {
+                    invalid: true,
+                    ..line(1, String::new(), None)
+                },
+            },
+            // the rest is the beginning of an instruction
+            Err(_) => DisasmLine {
+                invalid: true,
+                ..line(rest.len(), String::new(), None)
+            },
+        };
+        offset += line.bytes.len();
+        lines.push(line);
+    }
+    lines
+}
+
+/// `disassemble`, a line per instruction
+pub fn listing(memory: &MemoryImage, start: u32, len: u32, symbols: &SymbolTable) -> String {
+    disassemble(memory, start, len, symbols)
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disassemble, listing, SymbolTable};
+    use crate::memory_image::MemoryImage;
+
+    #[test_log::test]
+    fn fixture_listing() {
+        let code = [
+            0xf3, 0xa5, // rep movsd
+            0xf0, 0x01, 0x18, // lock add [eax], ebx
+            0x8b, 0x44, 0x8b, 0x10, // mov eax, [ebx+ecx*4+0x10]
+            0x0f, 0xa2, // cpuid
+            0x0f, 0x0b, // ud2
+            0xfe, 0xf8, // not an instruction (inc/dec with /7), then clc
+            0xe8, 0xed, 0xff, 0xff, 0xff, // call 0x1001
+            0x75, 0xea, // jne 0x1000
+            0xc3, // ret
+            0x8b, // mov, cut short
+        ];
+        let image = MemoryImage::from_code_region(0x1000, &code);
+        let mut symbols = SymbolTable::new();
+        symbols.insert(0x1000, "start");
 
-        formatter.format(&instruction, &mut output);
+        assert_eq!(
+            listing(&image, 0x1000, 0x100, &symbols),
+            "\
+00001000  f3 a5                    rep movsd
+00001002  f0 01 18                 lock add dword [eax], ebx
+00001005  8b 44 8b 10              mov eax, dword [ebx+ecx*4+0x10]
+00001009  0f a2                    cpuid
+0000100b  0f 0b                    ud2
+0000100d  fe                       (bad)
+0000100e  f8                       clc
+0000100f  e8 ed ff ff ff           call 0x1001 <start+0x1>
+00001014  75 ea                    jne 0x1000 <start>
+00001016  c3                       ret
+00001017  8b                       (bad)
+"
+        );
 
-        writeln!(output).unwrap();
+        // stops at `len`
+        let lines = disassemble(&image, 0x1005, 6, &symbols);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].bytes, vec![0x0f, 0xa2]);
+        assert!(!lines[1].invalid);
     }
-    output
 }
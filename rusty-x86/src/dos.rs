@@ -0,0 +1,139 @@
+//! Just enough of DOS to run a .COM program: the program segment & the console functions of `int 21h`
+//!
+//! A .COM program is a raw image loaded at offset 0x100 of a 64 KiB segment, after the PSP, with all the segment
+//! registers pointing to that segment & SP at the top of it. `Runtime::load_com` sets it up with the segment at
+//! address 0: the recompiled code keeps ESP & the return addresses as linear addresses, which are the offsets the
+//! program sees only there. The runtime has to be in the 16-bit mode with the flat segmentation (the default):
+//!
+//! ```ignore
+//! let mut runtime = Recompiler::builder()
+//!     .code_mode(CodeMode::Bits16)
+//!     .build_runtime(DosHandler::new(b"input"))?;
+//! let entry = runtime.load_com(&program)?;
+//! let exit = runtime.run(entry); // ExitReason::GuestExit { code } from `int 21h/4Ch`
+//! io::stdout().write_all(&runtime.handler.output)?;
+//! ```
+//!
+//! The console is in memory: `DosHandler` reads the keyboard input from a buffer & collects the output
+
+use std::collections::VecDeque;
+
+use crate::handler::{guest_exit, RuntimeHandler};
+use crate::runtime::with_active_memory;
+use crate::types::{CpuContext, FullSizeGeneralPurposeRegister::*, EXIT_HOST_REQUEST};
+
+/// Where the program segment is (as a real-mode segment)
+pub const COM_SEGMENT: u16 = 0;
+/// Offset of the program in the segment, right after the PSP
+pub const COM_LOAD_OFFSET: u32 = 0x100;
+pub const COM_SEGMENT_SIZE: u32 = 0x10000;
+/// SP at the start, with a word of zero on the stack: a `ret` goes to the `int 20h` at the start of the PSP
+pub const COM_INITIAL_SP: u32 = COM_SEGMENT_SIZE - 2;
+/// The largest program that fits in the segment along with the PSP & the initial stack
+pub const COM_MAX_SIZE: usize = (COM_INITIAL_SP - COM_LOAD_OFFSET) as usize;
+
+/// `int 20h`, ends the program with code 0
+pub const VECTOR_TERMINATE: u8 = 0x20;
+/// `int 21h`, the function in AH
+pub const VECTOR_DOS: u8 = 0x21;
+
+/// Reads a character into AL, echoing it
+pub const DOS_READ_CHAR: u8 = 0x01;
+/// Writes the character in DL
+pub const DOS_WRITE_CHAR: u8 = 0x02;
+/// Writes the string at DS:DX, up to the `$` ending it
+pub const DOS_WRITE_STRING: u8 = 0x09;
+/// Ends the program with the code in AL
+pub const DOS_EXIT: u8 = 0x4c;
+
+/// What a read gives once the input runs out: Ctrl-Z, the end of file
+pub const DOS_EOF: u8 = 0x1a;
+
+/// The PSP, as much of it as a program would look at
+pub(crate) fn program_segment_prefix() -> [u8; COM_LOAD_OFFSET as usize] {
+    let mut psp = [0; COM_LOAD_OFFSET as usize];
+    // int 20h, where a `ret` from the program ends up
+    psp[..2].copy_from_slice(&[0xcd, VECTOR_TERMINATE]);
+    // the segment after the memory of the program
+    let end = (COM_SEGMENT as u32 + (COM_SEGMENT_SIZE >> 4)) as u16;
+    psp[2..4].copy_from_slice(&end.to_le_bytes());
+    // an empty command tail
+    psp[0x81] = b'\r';
+    psp
+}
+
+fn set_al(ctx: &mut CpuContext, value: u8) {
+    let eax = ctx.get_gp_reg(EAX);
+    ctx.set_gp_reg(EAX, eax & !0xff | value as u32);
+}
+
+/// `RuntimeHandler` of a DOS program, with the console in memory. Other interrupts stop the execution
+#[derive(Debug, Default)]
+pub struct DosHandler {
+    /// What the program is yet to read from the keyboard
+    pub input: VecDeque<u8>,
+    /// What it wrote to the screen
+    pub output: Vec<u8>,
+}
+
+impl DosHandler {
+    pub fn new(input: &[u8]) -> Self {
+        Self {
+            input: input.iter().copied().collect(),
+            output: Vec::new(),
+        }
+    }
+
+    /// Services an `int 21h` function, returns whether it is one of those supported
+    pub fn int21(&mut self, ctx: &mut CpuContext) -> bool {
+        let function = (ctx.get_gp_reg(EAX) >> 8) as u8;
+        match function {
+            DOS_READ_CHAR => {
+                let char = self.input.pop_front().unwrap_or(DOS_EOF);
+                self.output.push(char);
+                set_al(ctx, char);
+            }
+            DOS_WRITE_CHAR => {
+                let char = ctx.get_gp_reg(EDX) as u8;
+                self.output.push(char);
+                set_al(ctx, char);
+            }
+            DOS_WRITE_STRING => {
+                let base = (COM_SEGMENT as u32) << 4;
+                let dx = ctx.get_gp_reg(EDX);
+                // the offset wraps around within the segment, an unmapped byte ends the string like the `$` does
+                let string = with_active_memory(|memory| {
+                    (0..COM_SEGMENT_SIZE)
+                        .map_while(|i| memory.read_u8(base + (dx.wrapping_add(i) & 0xffff)).ok())
+                        .take_while(|&char| char != b'$')
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+                self.output.extend_from_slice(&string);
+                set_al(ctx, b'$');
+            }
+            DOS_EXIT => {
+                let code = ctx.get_gp_reg(EAX) & 0xff;
+                guest_exit(ctx, code);
+            }
+            _ => return false,
+        }
+        true
+    }
+}
+
+impl RuntimeHandler for DosHandler {
+    fn interrupt(&mut self, ctx: &mut CpuContext, vector: u8) {
+        let handled = match vector {
+            VECTOR_TERMINATE => {
+                guest_exit(ctx, 0);
+                true
+            }
+            VECTOR_DOS => self.int21(ctx),
+            _ => false,
+        };
+        if !handled {
+            ctx.exit = EXIT_HOST_REQUEST;
+        }
+    }
+}
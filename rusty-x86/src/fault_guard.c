@@ -0,0 +1,84 @@
+// Turns the host page faults on the guest memory into a return from rusty_x86_guarded_enter (see src/runtime.rs)
+//
+// This is in C because of sigsetjmp: the generated code has nothing to unwind, so jumping over it is fine
+
+#include <setjmp.h>
+#include <signal.h>
+#include <stddef.h>
+#include <stdint.h>
+
+typedef uint32_t (*rusty_x86_entry_fn)(void *ctx, uint8_t *mem, uint32_t eip);
+
+// the innermost guarded call on this thread
+static _Thread_local sigjmp_buf *active_jmp;
+static _Thread_local uintptr_t guest_base;
+static _Thread_local uintptr_t guest_size;
+static _Thread_local uintptr_t fault_offset;
+
+static struct sigaction previous_segv;
+static struct sigaction previous_bus;
+
+static void on_fault(int sig, siginfo_t *info, void *ucontext) {
+    uintptr_t addr = (uintptr_t)info->si_addr;
+    if (active_jmp != NULL && addr - guest_base < guest_size) {
+        fault_offset = addr - guest_base;
+        siglongjmp(*active_jmp, 1);
+    }
+
+    // not ours, let whoever was there before deal with it
+    struct sigaction *previous = sig == SIGSEGV ? &previous_segv : &previous_bus;
+    if (previous->sa_flags & SA_SIGINFO) {
+        previous->sa_sigaction(sig, info, ucontext);
+    } else if (previous->sa_handler == SIG_DFL || previous->sa_handler == SIG_IGN) {
+        // the faulting instruction is retried on return, and this time it kills us as usual
+        sigaction(sig, previous, NULL);
+    } else {
+        previous->sa_handler(sig);
+    }
+}
+
+int rusty_x86_install_fault_handler(void) {
+    struct sigaction action = {0};
+    action.sa_sigaction = on_fault;
+    action.sa_flags = SA_SIGINFO;
+    sigemptyset(&action.sa_mask);
+
+    if (sigaction(SIGSEGV, &action, &previous_segv) != 0) {
+        return -1;
+    }
+    // that's what an access past the end of a mapped file gives
+    if (sigaction(SIGBUS, &action, &previous_bus) != 0) {
+        return -1;
+    }
+    return 0;
+}
+
+// Returns 1 if an access to [mem, mem + mem_size) faulted, with the guest address in *fault_address.
+// Otherwise returns 0 & the exit the entry returned in *exit
+int rusty_x86_guarded_enter(rusty_x86_entry_fn entry, void *ctx, uint8_t *mem, uint64_t mem_size,
+                            uint32_t eip, uint32_t *exit, uint32_t *fault_address) {
+    sigjmp_buf jmp;
+    sigjmp_buf *previous_jmp = active_jmp;
+    uintptr_t previous_base = guest_base;
+    uintptr_t previous_size = guest_size;
+
+    // the mask is restored on the jump, as the signal is still blocked in the handler
+    if (sigsetjmp(jmp, 1) != 0) {
+        active_jmp = previous_jmp;
+        guest_base = previous_base;
+        guest_size = previous_size;
+        *fault_address = (uint32_t)fault_offset;
+        return 1;
+    }
+
+    guest_base = (uintptr_t)mem;
+    guest_size = (uintptr_t)mem_size;
+    active_jmp = &jmp;
+
+    *exit = entry(ctx, mem, eip);
+
+    active_jmp = previous_jmp;
+    guest_base = previous_base;
+    guest_size = previous_size;
+    return 0;
+}
@@ -0,0 +1,554 @@
+//! The arithmetic flags, computed on plain integers
+//!
+//! This is the specification: the interpreter uses these functions directly, and the lowering in `codegen_instr`
+//! builds the same formulas out of the `Builder` primitives (`uadd_overflow` & co). The operands are `u64`s holding
+//! a value of `width` (the bits above it are ignored), so every width goes through the same code
+
+use crate::types::IntType;
+
+/// The flags an add or a sub sets, along with the result
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ArithFlags {
+    /// Truncated to the width
+    pub result: u64,
+    pub cf: bool,
+    pub pf: bool,
+    pub af: bool,
+    pub zf: bool,
+    pub sf: bool,
+    pub of: bool,
+}
+
+pub fn mask(width: IntType) -> u64 {
+    match width {
+        IntType::I64 => u64::MAX,
+        IntType::I128 => panic!("128-bit values are handled as two halves"),
+        width => (1u64 << width.bit_width()) - 1,
+    }
+}
+
+/// `value` (of `width`) sign-extended to 64 bits
+pub fn sign_extend(value: u64, width: IntType) -> i64 {
+    let shift = 64 - width.bit_width() as u32;
+    ((value << shift) as i64) >> shift
+}
+
+fn result_flags(result: u64, width: IntType) -> ArithFlags {
+    ArithFlags {
+        result,
+        // PF only looks at the low byte, whatever the width
+        pf: (result as u8).count_ones() & 1 == 0,
+        zf: result == 0,
+        sf: result >> (width.bit_width() - 1) & 1 != 0,
+        ..ArithFlags::default()
+    }
+}
+
+/// `add` (`carry_in` = false) and `adc`
+pub fn add(lhs: u64, rhs: u64, carry_in: bool, width: IntType) -> ArithFlags {
+    let mask = mask(width);
+    let (lhs, rhs) = (lhs & mask, rhs & mask);
+    let wide = lhs as u128 + rhs as u128 + carry_in as u128;
+    let result = wide as u64 & mask;
+
+    let signed =
+        sign_extend(lhs, width) as i128 + sign_extend(rhs, width) as i128 + carry_in as i128;
+
+    ArithFlags {
+        cf: wide > mask as u128,
+        af: (lhs ^ rhs ^ result) & 0x10 != 0,
+        of: signed != sign_extend(result, width) as i128,
+        ..result_flags(result, width)
+    }
+}
+
+/// `sub`/`cmp` (`borrow_in` = false) and `sbb`
+pub fn sub(lhs: u64, rhs: u64, borrow_in: bool, width: IntType) -> ArithFlags {
+    let mask = mask(width);
+    let (lhs, rhs) = (lhs & mask, rhs & mask);
+    let result = lhs.wrapping_sub(rhs).wrapping_sub(borrow_in as u64) & mask;
+
+    let signed =
+        sign_extend(lhs, width) as i128 - sign_extend(rhs, width) as i128 - borrow_in as i128;
+
+    ArithFlags {
+        cf: (lhs as u128) < rhs as u128 + borrow_in as u128,
+        af: (lhs ^ rhs ^ result) & 0x10 != 0,
+        of: signed != sign_extend(result, width) as i128,
+        ..result_flags(result, width)
+    }
+}
+
+/// `inc`: `add value, 1`, except that CF is left alone (`cf` is its value before)
+pub fn inc(value: u64, cf: bool, width: IntType) -> ArithFlags {
+    ArithFlags {
+        cf,
+        ..add(value, 1, false, width)
+    }
+}
+
+/// `dec`: `sub value, 1`, except that CF is left alone (`cf` is its value before)
+pub fn dec(value: u64, cf: bool, width: IntType) -> ArithFlags {
+    ArithFlags {
+        cf,
+        ..sub(value, 1, false, width)
+    }
+}
+
+/// What a double precision shift leaves, along with the result. AF is undefined, PF is not computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DoubleShiftFlags {
+    /// Truncated to the width
+    pub result: u64,
+    pub cf: bool,
+    pub zf: bool,
+    pub sf: bool,
+    /// Defined for the 1-bit shifts only: whether the sign has changed. For the rest it's whether the last step
+    /// changed it
+    pub of: bool,
+}
+
+/// `shld` (`left`) & `shrd`: `dst` shifted by `count`, with the bits of `src` coming in. The count is masked to 5
+/// bits, `None` if that gives 0: nothing changes then, the flags included
+///
+/// A 16-bit count above 16 is undefined in the manual. Intel shifts the `dst:src:dst` triple then, and so do we
+pub fn double_shift(
+    dst: u64,
+    src: u64,
+    count: u8,
+    left: bool,
+    width: IntType,
+) -> Option<DoubleShiftFlags> {
+    let count = (count & 0x1f) as u32;
+    if count == 0 {
+        return None;
+    }
+    let bits = width.bit_width() as u32;
+    let (dst, src) = (dst & mask(width), src & mask(width));
+
+    // the bits shifted through: the result is taken from the upper half for shld & from the lower one for shrd
+    let ring = match (width, left) {
+        (IntType::I16, _) => dst << 32 | src << 16 | dst,
+        (_, true) => dst << 32 | src,
+        (_, false) => src << 32 | dst,
+    };
+    // the value after `count - 1` steps gives the last bit shifted out
+    let (result, before) = if left {
+        (ring << count >> 32, ring << (count - 1) >> 32)
+    } else {
+        (ring >> count, ring >> (count - 1))
+    };
+    let (result, before) = (result & mask(width), before & mask(width));
+    let msb = |value: u64| value >> (bits - 1) & 1 != 0;
+
+    Some(DoubleShiftFlags {
+        result,
+        cf: if left { msb(before) } else { before & 1 != 0 },
+        zf: result == 0,
+        sf: msb(result),
+        of: msb(before ^ result),
+    })
+}
+
+/// What a rotate (through carry or not) leaves. SF, ZF, AF & PF are not affected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RotateFlags {
+    /// Truncated to the width
+    pub result: u64,
+    pub cf: bool,
+    /// Defined for the 1-bit rotates only: whether the sign has changed. The same formula is used for the rest
+    pub of: bool,
+}
+
+/// `rcl` (`left`) & `rcr`: the value and CF rotated as one `width + 1` bit ring, a bit at a time like in the manual.
+/// `count` is the one encoded, it's masked to 5 bits here. `None` if the masked count is 0: nothing changes then,
+/// the flags included. A count that is a multiple of the ring size leaves the value & CF as they were
+pub fn rotate_through_carry(
+    value: u64,
+    count: u8,
+    cf: bool,
+    left: bool,
+    width: IntType,
+) -> Option<RotateFlags> {
+    let count = count & 0x1f;
+    if count == 0 {
+        return None;
+    }
+    let bits = width.bit_width() as u32;
+    let value = value & mask(width);
+
+    let (mut result, mut carry) = (value, cf);
+    for _ in 0..count as u32 % (bits + 1) {
+        let carry_out = if left {
+            result >> (bits - 1) & 1 != 0
+        } else {
+            result & 1 != 0
+        };
+        result = if left {
+            (result << 1 | carry as u64) & mask(width)
+        } else {
+            result >> 1 | (carry as u64) << (bits - 1)
+        };
+        carry = carry_out;
+    }
+
+    Some(RotateFlags {
+        result,
+        cf: carry,
+        of: (value ^ result) >> (bits - 1) & 1 != 0,
+    })
+}
+
+/// `rol` (`left`) & `ror`: CF is the bit that went around last. The count is masked to 5 bits and `None` if that gives
+/// 0, but then it's taken modulo the width: rol al, 8 changes nothing but the flags
+pub fn rotate(value: u64, count: u8, left: bool, width: IntType) -> Option<RotateFlags> {
+    let count = count & 0x1f;
+    if count == 0 {
+        return None;
+    }
+    let bits = width.bit_width() as u32;
+    let value = value & mask(width);
+    let count = count as u32 % bits;
+
+    let result = if count == 0 {
+        value
+    } else if left {
+        (value << count | value >> (bits - count)) & mask(width)
+    } else {
+        (value >> count | value << (bits - count)) & mask(width)
+    };
+    let msb = |value: u64| value >> (bits - 1) & 1 != 0;
+
+    // OF is defined for the 1-bit rotates only, the same formula is used for the rest
+    Some(if left {
+        let cf = result & 1 != 0;
+        RotateFlags {
+            result,
+            cf,
+            of: msb(result) ^ cf,
+        }
+    } else {
+        RotateFlags {
+            result,
+            cf: msb(result),
+            of: msb(result) ^ (result >> (bits - 2) & 1 != 0),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add, dec, double_shift, inc, mask, rotate, rotate_through_carry, sub, ArithFlags};
+    use crate::types::IntType;
+    use proptest::prelude::*;
+
+    /// The 8-bit flags the way the hardware manuals tabulate them: from the 9-bit result and the nibble carry
+    fn reference8(lhs: u8, rhs: u8, carry: bool, subtract: bool) -> ArithFlags {
+        let (l, r, c) = (lhs as i32, rhs as i32, carry as i32);
+        let wide = if subtract { l - r - c } else { l + r + c };
+        let nibble = if subtract {
+            (l & 0xf) - (r & 0xf) - c
+        } else {
+            (l & 0xf) + (r & 0xf) + c
+        };
+        let (sl, sr) = (lhs as i8 as i32, rhs as i8 as i32);
+        let signed = if subtract { sl - sr - c } else { sl + sr + c };
+        let result = wide as u8;
+
+        ArithFlags {
+            result: result as u64,
+            cf: !(0..=0xff).contains(&wide),
+            pf: result.count_ones() & 1 == 0,
+            af: !(0..=0xf).contains(&nibble),
+            zf: result == 0,
+            sf: result & 0x80 != 0,
+            of: !(-0x80..=0x7f).contains(&signed),
+        }
+    }
+
+    #[test_log::test]
+    fn exhaustive_8bit() {
+        for lhs in 0..=0xffu8 {
+            for rhs in 0..=0xffu8 {
+                for carry in [false, true] {
+                    assert_eq!(
+                        add(lhs as u64, rhs as u64, carry, IntType::I8),
+                        reference8(lhs, rhs, carry, false),
+                        "adc {:#x}, {:#x}, cf={}",
+                        lhs,
+                        rhs,
+                        carry
+                    );
+                    assert_eq!(
+                        sub(lhs as u64, rhs as u64, carry, IntType::I8),
+                        reference8(lhs, rhs, carry, true),
+                        "sbb {:#x}, {:#x}, cf={}",
+                        lhs,
+                        rhs,
+                        carry
+                    );
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn known_values() {
+        // 0x7fffffff + 1: signed overflow only
+        let f = add(0x7fffffff, 1, false, IntType::I32);
+        assert_eq!(
+            (f.result, f.cf, f.of, f.sf, f.af),
+            (0x80000000, false, true, true, true)
+        );
+        // 0xffff + 0 + 1 wraps to zero
+        let f = add(0xffff, 0, true, IntType::I16);
+        assert_eq!(
+            (f.result, f.cf, f.of, f.zf, f.pf),
+            (0, true, false, true, true)
+        );
+        // 0 - 0 - 1 borrows
+        let f = sub(0, 0, true, IntType::I32);
+        assert_eq!(
+            (f.result, f.cf, f.of, f.sf),
+            (0xffffffff, true, false, true)
+        );
+        // 127 - (-1) - 1 = 127: the two partial subtractions overflow and cancel out
+        let f = sub(0x7f, 0xff, true, IntType::I8);
+        assert_eq!((f.result, f.cf, f.of), (0x7f, true, false));
+        // the bits above the width don't matter
+        assert_eq!(
+            add(0x1234_0001, 0xff, false, IntType::I8),
+            add(1, 0xff, false, IntType::I8)
+        );
+    }
+
+    #[test_log::test]
+    fn inc_dec_overflow() {
+        for width in [IntType::I8, IntType::I16, IntType::I32] {
+            let max = mask(width) >> 1;
+            let min = max + 1;
+            for value in [0, 1, max - 1, max, min, min + 1, mask(width)] {
+                for cf in [false, true] {
+                    let f = inc(value, cf, width);
+                    assert_eq!((f.of, f.cf), (value == max, cf), "inc {:#x}", value);
+                    assert_eq!(f.zf, value == mask(width));
+                    let f = dec(value, cf, width);
+                    assert_eq!((f.of, f.cf), (value == min, cf), "dec {:#x}", value);
+                    assert_eq!(f.zf, value == 1);
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn rotate_through_carry_ring() {
+        for width in [IntType::I8, IntType::I16, IntType::I32] {
+            let bits = width.bit_width() as u64;
+            let msb = |value: u64| value >> (bits - 1) & 1 != 0;
+            for value in [0, 1, 0x5a, 0x80, 0x8001, 0x8000_0000, mask(width)] {
+                let value = value & mask(width);
+                for cf in [false, true] {
+                    for left in [false, true] {
+                        assert_eq!(rotate_through_carry(value, 0, cf, left, width), None);
+                        assert_eq!(rotate_through_carry(value, 0x20, cf, left, width), None);
+
+                        // the manual's OF for the 1-bit forms: after the rotate for rcl, before it for rcr
+                        let f = rotate_through_carry(value, 1, cf, left, width).unwrap();
+                        let of = if left {
+                            msb(f.result) ^ f.cf
+                        } else {
+                            msb(value) ^ cf
+                        };
+                        assert_eq!(f.of, of, "{:#x}, cf={}, left={}", value, cf, left);
+
+                        // all the way around the ring
+                        if bits < 32 {
+                            let f = rotate_through_carry(value, bits as u8 + 1, cf, left, width);
+                            assert_eq!(f.map(|f| (f.result, f.cf)), Some((value, cf)));
+                        }
+                        // a right rotate undoes a left one
+                        for count in 1..=31 {
+                            let l = rotate_through_carry(value, count, cf, true, width).unwrap();
+                            let r =
+                                rotate_through_carry(l.result, count, l.cf, false, width).unwrap();
+                            assert_eq!((r.result, r.cf), (value, cf));
+                        }
+                    }
+                }
+            }
+        }
+
+        // rcl al, 9: back where it started. rcr ax, 17 likewise
+        let f = rotate_through_carry(0x81, 9, true, true, IntType::I8).unwrap();
+        assert_eq!((f.result, f.cf), (0x81, true));
+        // rcl al, 10 = rcl al, 1
+        let f = rotate_through_carry(0x81, 10, false, true, IntType::I8).unwrap();
+        assert_eq!((f.result, f.cf, f.of), (0x02, true, true));
+        // rcr ax, 20 = rcr ax, 3
+        let f = rotate_through_carry(0x0006, 20, true, false, IntType::I16).unwrap();
+        assert_eq!((f.result, f.cf), (0xa000, true));
+    }
+
+    #[test_log::test]
+    fn rotate_matches_single_steps() {
+        for width in [IntType::I8, IntType::I16, IntType::I32] {
+            let bits = width.bit_width() as u32;
+            for value in [
+                0,
+                1,
+                0x5a,
+                0x80,
+                0x8001,
+                0x4000_0000,
+                0x8000_0000,
+                mask(width),
+            ] {
+                let value = value & mask(width);
+                for left in [false, true] {
+                    assert_eq!(rotate(value, 0, left, width), None);
+                    assert_eq!(rotate(value, 0x40, left, width), None);
+
+                    // a bit at a time, like in the manual: CF is the bit that went around
+                    let mut result = value;
+                    for count in 1..=31u8 {
+                        let (out, rest) = if left {
+                            (result >> (bits - 1) & 1, result << 1)
+                        } else {
+                            (result & 1, result >> 1)
+                        };
+                        result = if left {
+                            (rest | out) & mask(width)
+                        } else {
+                            rest | out << (bits - 1)
+                        };
+
+                        let f = rotate(value, count, left, width).unwrap();
+                        assert_eq!(
+                            (f.result, f.cf),
+                            (result, out != 0),
+                            "{:#x} by {}",
+                            value,
+                            count
+                        );
+                        // the count is masked before anything else
+                        assert_eq!(rotate(value, count | 0xe0, left, width), Some(f));
+                    }
+
+                    // the manual's OF for the 1-bit forms: whether the sign has changed
+                    let f = rotate(value, 1, left, width).unwrap();
+                    assert_eq!(f.of, (value ^ f.result) >> (bits - 1) & 1 != 0);
+                }
+            }
+        }
+
+        // ror al, 8: the value stays, CF is its top bit
+        let f = rotate(0x81, 8, false, IntType::I8).unwrap();
+        assert_eq!((f.result, f.cf, f.of), (0x81, true, true));
+        // rol ax, 20 = rol ax, 4
+        let f = rotate(0x1234, 20, true, IntType::I16).unwrap();
+        assert_eq!((f.result, f.cf, f.of), (0x2341, true, true));
+    }
+
+    #[test_log::test]
+    fn double_shift_concatenation() {
+        for width in [IntType::I16, IntType::I32] {
+            let bits = width.bit_width() as u32;
+            for (dst, src) in [
+                (0x1234_5678, 0x9abc_def0),
+                (0x8000_0001, 0x0000_8001),
+                (0, u64::MAX),
+                (u64::MAX, 0),
+            ] {
+                let (dst, src) = (dst & mask(width), src & mask(width));
+                for left in [false, true] {
+                    assert_eq!(double_shift(dst, src, 0, left, width), None);
+                    assert_eq!(double_shift(dst, src, 0x20, left, width), None);
+                }
+
+                // up to the width it's the operands side by side, shifted as one
+                for count in 1..=bits.min(31) {
+                    let (d, s) = (dst as u128, src as u128);
+                    let f = double_shift(dst, src, count as u8, true, width).unwrap();
+                    let expected = (d << bits | s) << count >> bits & mask(width) as u128;
+                    assert_eq!(
+                        f.result as u128, expected,
+                        "shld {:#x}, {:#x}, {}",
+                        dst, src, count
+                    );
+                    assert_eq!(f.cf, dst >> (bits - count) & 1 != 0);
+                    assert_eq!((f.zf, f.sf), (f.result == 0, f.result >> (bits - 1) != 0));
+
+                    let f = double_shift(dst, src, count as u8, false, width).unwrap();
+                    let expected = (s << bits | d) >> count & mask(width) as u128;
+                    assert_eq!(
+                        f.result as u128, expected,
+                        "shrd {:#x}, {:#x}, {}",
+                        dst, src, count
+                    );
+                    assert_eq!(f.cf, dst >> (count - 1) & 1 != 0);
+                }
+
+                // the manual's OF for the 1-bit forms: whether the sign has changed
+                for left in [false, true] {
+                    let f = double_shift(dst, src, 1, left, width).unwrap();
+                    assert_eq!(f.of, (dst ^ f.result) >> (bits - 1) & 1 != 0);
+                }
+            }
+        }
+
+        // past 16 bits the word forms go on into the destination again: dst:src:dst
+        let f = double_shift(0x1234, 0x5678, 20, false, IntType::I16).unwrap();
+        assert_eq!((f.result, f.cf), (0x4567, true));
+        let f = double_shift(0x1234, 0x5678, 20, true, IntType::I16).unwrap();
+        assert_eq!((f.result, f.cf), (0x6781, true));
+        // and 32 = 0 for the dwords
+        let f = double_shift(0x1234_5678, 0x9abc_def0, 0x24, true, IntType::I32).unwrap();
+        assert_eq!(f.result, 0x2345_6789);
+    }
+
+    /// The same formulas at `width`, spelled in the wider arithmetic of Rust
+    fn check_wide(lhs: u64, rhs: u64, carry: bool, width: IntType) {
+        let bits = width.bit_width() as u32;
+        let m = (1u128 << bits) - 1;
+        let (l, r, c) = (lhs as u128 & m, rhs as u128 & m, carry as u128);
+        let sext = |v: u128| ((v << (128 - bits)) as i128) >> (128 - bits);
+
+        let f = add(lhs, rhs, carry, width);
+        let sum = l + r + c;
+        assert_eq!(f.result as u128, sum & m);
+        assert_eq!(f.cf, sum > m);
+        assert_eq!(f.of, sext(l) + sext(r) + c as i128 != sext(sum & m));
+        assert_eq!(f.af, (l & 0xf) + (r & 0xf) + c > 0xf);
+        assert_eq!(f.sf, sext(sum & m) < 0);
+
+        let f = sub(lhs, rhs, carry, width);
+        let diff = l.wrapping_sub(r).wrapping_sub(c);
+        assert_eq!(f.result as u128, diff & m);
+        assert_eq!(f.cf, l < r + c);
+        assert_eq!(f.of, sext(l) - sext(r) - c as i128 != sext(diff & m));
+        assert_eq!(f.af, (l & 0xf) < (r & 0xf) + c);
+        assert_eq!(f.zf, diff & m == 0);
+    }
+
+    /// Values near the edges, where the flags change
+    fn interesting() -> impl Strategy<Value = u64> {
+        prop_oneof![
+            any::<u64>(),
+            (0..0x20u64),
+            (0..0x20u64).prop_map(|v| 0u64.wrapping_sub(v)),
+            (0..0x20u64, 0..4u32).prop_map(|(v, s)| (0x8000u64 << (s * 8)).wrapping_sub(v)),
+            (0..0x20u64, 0..4u32).prop_map(|(v, s)| (0x8000u64 << (s * 8)) + v),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(4096))]
+
+        #[test]
+        fn sampled_wide(lhs in interesting(), rhs in interesting(), carry in any::<bool>()) {
+            for width in [IntType::I16, IntType::I32, IntType::I64] {
+                check_wide(lhs, rhs, carry, width);
+            }
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! The x87 register stack on top of `CpuContext::fpu_regs` and the instructions using it
+//!
+//! The registers hold doubles instead of the 80-bit extended values, so the precision control is ignored
+//! and everything is rounded to 53 bits. The tag word only tells the empty registers from the rest:
+//! pushing onto a non-empty one gives the indefinite NaN (as the masked stack overflow does), underflow is not detected
+//!
+//! The MMX registers live here too: MMi is the physical register i
+
+use crate::backend::{Builder, ComparisonType, IntValue, RoundingMode};
+use crate::ir::{Instr, Mnemonic};
+use crate::types::{FpuWord, IntType, Operand, FPU_CONTROL_DEFAULT};
+use crate::{operands, operands_ty};
+
+const TOP_SHIFT: u16 = 11;
+const TAG_EMPTY: u16 = 0b11;
+const SIGN_BIT: u64 = 1 << 63;
+
+/// The rounding control field of the control word
+const RC_SHIFT: u16 = 10;
+
+/// Invalid operation, stack fault & C1 (set for an overflow)
+const STACK_OVERFLOW_STATUS: u16 = 1 | 1 << 6 | 1 << 9;
+/// What the invalid operations produce when masked
+const INDEFINITE: u64 = 0xfff8_0000_0000_0000;
+
+/// Physical number of ST(0) (I32)
+fn top<B: Builder>(builder: &mut B) -> B::IntValue {
+    let status = builder.load_fpu_word(FpuWord::Status);
+    let top = builder.lshr(status, builder.make_u16(TOP_SHIFT));
+    let top = builder.int_and(top, builder.make_u16(7));
+    builder.zext(top, IntType::I32)
+}
+
+fn set_top<B: Builder>(builder: &mut B, top: B::IntValue) {
+    let top = builder.trunc(top, IntType::I16);
+    let top = builder.shl(top, builder.make_u16(TOP_SHIFT));
+
+    let status = builder.load_fpu_word(FpuWord::Status);
+    let status = builder.int_and(status, builder.make_u16(!(7 << TOP_SHIFT)));
+    let status = builder.int_or(status, top);
+    builder.store_fpu_word(FpuWord::Status, status);
+}
+
+/// Physical number of ST(i)
+fn physical<B: Builder>(builder: &mut B, top: B::IntValue, i: u8) -> B::IntValue {
+    let index = builder.add(top, builder.make_u32(i as u32));
+    builder.int_and(index, builder.make_u32(7))
+}
+
+fn set_tag<B: Builder>(builder: &mut B, index: B::IntValue, tag: u16) {
+    let shift = builder.trunc(index, IntType::I16);
+    let shift = builder.shl(shift, builder.make_u16(1));
+
+    let mask = builder.shl(builder.make_u16(0b11), shift);
+    let mask = builder.int_not(mask);
+    let tag = builder.shl(builder.make_u16(tag), shift);
+
+    let tags = builder.load_fpu_word(FpuWord::Tag);
+    let tags = builder.int_and(tags, mask);
+    let tags = builder.int_or(tags, tag);
+    builder.store_fpu_word(FpuWord::Tag, tags);
+}
+
+pub fn load_st<B: Builder>(builder: &mut B, i: u8) -> B::IntValue {
+    let top = top(builder);
+    let index = physical(builder, top, i);
+    builder.load_fpu_register(index)
+}
+
+pub fn store_st<B: Builder>(builder: &mut B, i: u8, value: B::IntValue) {
+    let top = top(builder);
+    let index = physical(builder, top, i);
+    builder.store_fpu_register(index, value);
+}
+
+fn tag<B: Builder>(builder: &mut B, index: B::IntValue) -> B::IntValue {
+    let shift = builder.trunc(index, IntType::I16);
+    let shift = builder.shl(shift, builder.make_u16(1));
+
+    let tags = builder.load_fpu_word(FpuWord::Tag);
+    let tag = builder.lshr(tags, shift);
+    builder.int_and(tag, builder.make_u16(0b11))
+}
+
+pub fn push<B: Builder>(builder: &mut B, value: B::IntValue) {
+    let top = top(builder);
+    let top = physical(builder, top, 7);
+
+    let tag = tag(builder, top);
+    let overflow = builder.icmp(ComparisonType::NotEqual, tag, builder.make_u16(TAG_EMPTY));
+    let value = builder.select(overflow, builder.make_u64(INDEFINITE), value);
+    let status = builder.load_fpu_word(FpuWord::Status);
+    let overflow_status = builder.int_or(status, builder.make_u16(STACK_OVERFLOW_STATUS));
+    let status = builder.select(overflow, overflow_status, status);
+    builder.store_fpu_word(FpuWord::Status, status);
+
+    set_top(builder, top);
+    builder.store_fpu_register(top, value);
+    set_tag(builder, top, 0);
+}
+
+pub fn pop<B: Builder>(builder: &mut B) -> B::IntValue {
+    let top = top(builder);
+    let value = builder.load_fpu_register(top);
+    set_tag(builder, top, TAG_EMPTY);
+    let next = physical(builder, top, 1);
+    set_top(builder, next);
+    value
+}
+
+/// `fwait` & the waiting forms (`fstsw` & co) raise the pending unmasked exceptions here.
+/// The exceptions are not modeled (they all behave as masked), so there is nothing to check yet
+fn wait<B: Builder>(_builder: &mut B) {}
+
+/// Every MMX instruction but emms resets TOP & marks all the registers valid
+pub fn enter_mmx<B: Builder>(builder: &mut B) {
+    set_top(builder, builder.make_u32(0));
+    builder.store_fpu_word(FpuWord::Tag, builder.make_u16(0));
+}
+
+/// Marks all the registers empty, so that the x87 code can use them after MMX
+pub fn emms<B: Builder>(builder: &mut B) {
+    builder.store_fpu_word(FpuWord::Tag, builder.make_u16(0xffff));
+}
+
+pub fn load_mmx<B: Builder>(builder: &mut B, i: u8) -> B::IntValue {
+    builder.load_fpu_register(builder.make_u32(i as u32))
+}
+
+pub fn store_mmx<B: Builder>(builder: &mut B, i: u8, value: B::IntValue) {
+    builder.store_fpu_register(builder.make_u32(i as u32), value);
+}
+
+/// Rounds a float or a double to an integral value in the mode selected by `rc`,
+/// a rounding control field value (the same in the x87 control word & MXCSR)
+pub fn round_by_control<B: Builder>(
+    builder: &mut B,
+    value: B::IntValue,
+    rc: B::IntValue,
+) -> B::IntValue {
+    // the field values go in the same order as the modes
+    let modes = [
+        RoundingMode::NearestEven,
+        RoundingMode::Down,
+        RoundingMode::Up,
+        RoundingMode::Zero,
+    ];
+    let mut res = builder.float_round(value, RoundingMode::NearestEven);
+    for (field, mode) in modes.into_iter().enumerate().skip(1) {
+        let rounded = builder.float_round(value, mode);
+        let field = builder.make_int_value(rc.size(), field as u64, false);
+        let selected = builder.icmp(ComparisonType::Equal, rc, field);
+        res = builder.select(selected, rounded, res);
+    }
+    res
+}
+
+/// Reads a memory operand or ST(i) as a double
+fn load_float<B: Builder>(builder: &mut B, operand: Operand) -> B::IntValue {
+    match operand {
+        Operand::FpuRegister(i) => load_st(builder, i),
+        Operand::Memory(_) => {
+            let value = builder.load_operand(operand);
+            match value.size() {
+                IntType::I32 => builder.float_extend(value),
+                _ => value,
+            }
+        }
+        op => panic!("Unsupported x87 operand: {:?}", op),
+    }
+}
+
+fn store_float<B: Builder>(builder: &mut B, operand: Operand, value: B::IntValue) {
+    match operand {
+        Operand::FpuRegister(i) => store_st(builder, i, value),
+        Operand::Memory(_) => {
+            let value = match operand.size() {
+                IntType::I32 => builder.float_truncate(value),
+                _ => value,
+            };
+            builder.store_operand(operand, value)
+        }
+        op => panic!("Unsupported x87 operand: {:?}", op),
+    }
+}
+
+/// Applies `f` to ST(0) in place
+fn map_st0<B: Builder>(builder: &mut B, f: impl FnOnce(&mut B, B::IntValue) -> B::IntValue) {
+    let value = load_st(builder, 0);
+    let value = f(builder, value);
+    store_st(builder, 0, value);
+}
+
+pub fn codegen_fpu_instr<B: Builder>(builder: &mut B, instr: &Instr) {
+    use Mnemonic::*;
+
+    match instr.mnemonic {
+        Fld => {
+            operands!([src], instr);
+
+            // read before the push, `fld st7` loads what becomes ST(0)
+            let value = load_float(builder, src);
+            push(builder, value);
+        }
+        Fst | Fstp => {
+            operands!([dst], instr);
+
+            let value = load_st(builder, 0);
+            store_float(builder, dst, value);
+            if instr.mnemonic == Fstp {
+                pop(builder);
+            }
+        }
+        Fldz => {
+            operands!([], instr);
+            push(builder, builder.make_u64(0.0f64.to_bits()));
+        }
+        Fld1 => {
+            operands!([], instr);
+            push(builder, builder.make_u64(1.0f64.to_bits()));
+        }
+        Fldpi => {
+            operands!([], instr);
+            push(builder, builder.make_u64(std::f64::consts::PI.to_bits()));
+        }
+        Fxch => {
+            operands!([st0, sti], instr);
+
+            let i = match (st0, sti) {
+                (Operand::FpuRegister(0), Operand::FpuRegister(i)) => i,
+                _ => panic!("Expected fxch operands to be st0, st(i)"),
+            };
+
+            let st0 = load_st(builder, 0);
+            let sti = load_st(builder, i);
+            store_st(builder, 0, sti);
+            store_st(builder, i, st0);
+        }
+        Fchs => {
+            operands!([], instr);
+            map_st0(builder, |builder, v| {
+                builder.int_xor(v, builder.make_u64(SIGN_BIT))
+            });
+        }
+        Fabs => {
+            operands!([], instr);
+            map_st0(builder, |builder, v| {
+                builder.int_and(v, builder.make_u64(!SIGN_BIT))
+            });
+        }
+        Fsqrt => {
+            operands!([], instr);
+            map_st0(builder, |builder, v| builder.float_sqrt(v));
+        }
+        Frndint => {
+            operands!([], instr);
+
+            let control = builder.load_fpu_word(FpuWord::Control);
+            let rc = builder.lshr(control, builder.make_u16(RC_SHIFT));
+            let rc = builder.int_and(rc, builder.make_u16(0b11));
+
+            map_st0(builder, |builder, v| round_by_control(builder, v, rc));
+        }
+        Wait => {
+            operands!([], instr);
+            wait(builder);
+        }
+        Fnstsw | Fstsw => {
+            operands!([dst], instr);
+            if instr.mnemonic == Fstsw {
+                wait(builder);
+            }
+            let value = builder.load_fpu_word(FpuWord::Status);
+            builder.store_operand(dst, value);
+        }
+        Fldcw => {
+            operands!([src], instr);
+            let value = builder.load_operand(src);
+            builder.store_fpu_word(FpuWord::Control, value);
+        }
+        Fnstcw | Fstcw => {
+            operands!([dst], instr);
+            if instr.mnemonic == Fstcw {
+                wait(builder);
+            }
+            let value = builder.load_fpu_word(FpuWord::Control);
+            builder.store_operand(dst, value);
+        }
+        Fninit | Finit => {
+            operands!([], instr);
+            if instr.mnemonic == Finit {
+                wait(builder);
+            }
+            builder.store_fpu_word(FpuWord::Control, builder.make_u16(FPU_CONTROL_DEFAULT));
+            builder.store_fpu_word(FpuWord::Status, builder.make_u16(0));
+            builder.store_fpu_word(FpuWord::Tag, builder.make_u16(0xffff));
+        }
+        m => panic!("Not an x87 instruction: {:?}", m),
+    }
+}
@@ -0,0 +1,270 @@
+//! The interface between the executed code and the embedder (shared by all the execution engines)
+
+use std::collections::BTreeMap;
+
+use log::warn;
+
+use crate::types::{
+    CpuContext, Flag, FullSizeGeneralPurposeRegister, IntType, EXIT_GUEST, EXIT_HOST_REQUEST,
+};
+
+/// Why did the execution stop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The entry function returned to its caller
+    Returned,
+    /// A handler asked to stop (by setting `CpuContext::exit`); `CpuContext::eip` is where it happened
+    HostRequest,
+    /// The guest did something the CPU would raise an exception for; `CpuContext::eip` points to the faulting instruction
+    /// and `CpuContext::fault_address` has the offending address (if there is one)
+    Fault(GuestFault),
+    /// The guest ended itself (see `guest_exit`), all the guest calls in progress are abandoned. The context is as
+    /// the exit left it
+    GuestExit { code: u32 },
+}
+
+/// What `GuestFault::Unimplemented` has in `CpuContext::fault_vector`: no exception uses it
+pub const VECTOR_UNIMPLEMENTED: u8 = 0xff;
+/// What `GuestFault::FlagMismatch` has in `CpuContext::fault_vector`, with the `Flag` in `CpuContext::fault_address`
+pub const VECTOR_FLAG_MISMATCH: u8 = 0xfe;
+
+/// Encoding of a single instruction
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+pub struct InstructionBytes {
+    len: u8,
+    data: [u8; 15],
+}
+
+impl InstructionBytes {
+    /// x86 instructions are at most 15 bytes long, the rest is cut off
+    pub fn new(bytes: &[u8]) -> Self {
+        let len = bytes.len().min(15);
+        let mut data = [0; 15];
+        data[..len].copy_from_slice(&bytes[..len]);
+        Self {
+            len: len as u8,
+            data,
+        }
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+}
+
+impl std::fmt::Debug for InstructionBytes {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02x?}", self.as_slice())
+    }
+}
+
+/// Exceptions raised by the guest code (we don't have an IDT, so these just stop the execution)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuestFault {
+    /// #DE: division by zero, or the quotient doesn't fit
+    DivideError,
+    /// #BR: `bound` found the index out of range
+    BoundRange,
+    /// #UD: the bytes are not an instruction (or it's `ud2`)
+    InvalidOpcode,
+    /// #GP: segment limit or rights violation, far transfer through a bad selector
+    GeneralProtection,
+    /// #PF: access to the unmapped guest memory. Only caught with `RecompilerBuilder::fault_sites`
+    PageFault,
+    /// A #PF in the guard page right under the stack of `Runtime::setup_stack`: the guest ran out of the stack.
+    /// `esp` is where it was at the faulting access, `base..top` is the stack
+    StackOverflow { esp: u32, base: u32, top: u32 },
+    /// #AC: a misaligned access with EFLAGS.AC set. Only with `RecompilerBuilder::alignment_checks`
+    AlignmentCheck,
+    /// The execution got to an instruction we can't translate (see `UnsupportedInstructionPolicy::Trap`).
+    /// Not a real exception: the CPUs that don't have the instruction raise #UD
+    Unimplemented { eip: u32, bytes: InstructionBytes },
+    /// A flag disagrees with its shadow copy at `eip`, the instruction reading it (or the one the execution returned
+    /// to the host at). Only with `TranslationOptions::shadow_flags`, and it's a translation bug, not a guest one
+    FlagMismatch { eip: u32, flag: Flag },
+}
+
+impl GuestFault {
+    /// The x86 exception vector, stored into `CpuContext::fault_vector`
+    pub fn vector(self) -> u8 {
+        match self {
+            GuestFault::DivideError => 0,
+            GuestFault::BoundRange => 5,
+            GuestFault::InvalidOpcode => 6,
+            GuestFault::GeneralProtection => 13,
+            GuestFault::PageFault | GuestFault::StackOverflow { .. } => 14,
+            GuestFault::AlignmentCheck => 17,
+            GuestFault::Unimplemented { .. } => VECTOR_UNIMPLEMENTED,
+            GuestFault::FlagMismatch { .. } => VECTOR_FLAG_MISMATCH,
+        }
+    }
+
+    /// `None` for `VECTOR_UNIMPLEMENTED` & `VECTOR_FLAG_MISMATCH` too: the vector alone doesn't say what the
+    /// instruction was
+    pub fn from_vector(vector: u8) -> Option<Self> {
+        match vector {
+            0 => Some(GuestFault::DivideError),
+            5 => Some(GuestFault::BoundRange),
+            6 => Some(GuestFault::InvalidOpcode),
+            13 => Some(GuestFault::GeneralProtection),
+            14 => Some(GuestFault::PageFault),
+            17 => Some(GuestFault::AlignmentCheck),
+            _ => None,
+        }
+    }
+}
+
+/// Software interrupts the guest handles itself, for the guests that bring their own handlers (test ROMs, DOS-style
+/// programs). The vectors not in the table go to `RuntimeHandler::interrupt` as usual
+///
+/// `int n` pushes EFLAGS, CS & the return EIP like a 32-bit interrupt gate without a privilege change and
+/// transfers to the handler, which returns with `iretd`. There are no segments to speak of, so a zero goes in place of CS
+///
+/// `iretd` takes whatever is on the stack, so a handler (or any other code) can resume somewhere else by rewriting
+/// the frame. Such a target has to be an entry point to be reachable from the recompiled code
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InterruptVectorTable {
+    /// Guest address of the handler for each vector
+    pub handlers: BTreeMap<u8, u32>,
+    /// Clear IF on entry, like an interrupt gate does (a trap gate leaves it alone)
+    pub clear_if: bool,
+}
+
+impl InterruptVectorTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_handler(&mut self, vector: u8, handler: u32) {
+        self.handlers.insert(vector, handler);
+    }
+
+    pub fn handler(&self, vector: u8) -> Option<u32> {
+        self.handlers.get(&vector).copied()
+    }
+}
+
+/// Ends the execution with `ExitReason::GuestExit`, for a handler servicing `exit` or the like. The generated code
+/// returns to the host as soon as the handler does, just like with `EXIT_HOST_REQUEST`
+pub fn guest_exit(ctx: &mut CpuContext, code: u32) {
+    ctx.exit_code = code;
+    ctx.exit = EXIT_GUEST;
+}
+
+/// `exit` in the i386 Linux syscall numbering
+pub const LINUX_SYS_EXIT: u32 = 1;
+/// `exit_group` in the i386 Linux syscall numbering
+pub const LINUX_SYS_EXIT_GROUP: u32 = 252;
+
+/// For `RuntimeHandler::interrupt`: `int 0x80` with `exit` or `exit_group` in EAX ends the execution with the code
+/// in EBX. Returns whether it was one of them, the rest of the syscalls are left to the caller
+pub fn linux_exit_syscall(ctx: &mut CpuContext, vector: u8) -> bool {
+    let number = ctx.get_gp_reg(FullSizeGeneralPurposeRegister::EAX);
+    if vector != 0x80 || !matches!(number, LINUX_SYS_EXIT | LINUX_SYS_EXIT_GROUP) {
+        return false;
+    }
+    let code = ctx.get_gp_reg(FullSizeGeneralPurposeRegister::EBX);
+    guest_exit(ctx, code);
+    true
+}
+
+/// The software interrupt test programs use as a checkpoint: `int 0xfd` (`CHECKPOINT_INSTRUCTION`). It's an ordinary
+/// `int`, so it reaches `RuntimeHandler::interrupt` (unless the `InterruptVectorTable` has the vector) & the guest
+/// goes on from the next instruction once the handler returns, unlike with `ud2` and the other faults
+pub const VECTOR_CHECKPOINT: u8 = 0xfd;
+/// The encoding of `int VECTOR_CHECKPOINT`, for the assemblers that can't take the vector as an imm8
+pub const CHECKPOINT_INSTRUCTION: [u8; 2] = [0xcd, VECTOR_CHECKPOINT];
+
+/// For `RuntimeHandler::interrupt`: keeps a copy of the context at a checkpoint, the guest continues after it.
+/// Returns whether it was one
+pub fn record_checkpoint(ctx: &CpuContext, vector: u8, checkpoints: &mut Vec<CpuContext>) -> bool {
+    if vector != VECTOR_CHECKPOINT {
+        return false;
+    }
+    checkpoints.push(ctx.clone());
+    true
+}
+
+/// Services the stuff the recompiled code can't do on its own
+///
+/// To stop the execution from inside of a handler set `ctx.exit` to `EXIT_HOST_REQUEST`
+pub trait RuntimeHandler {
+    /// `int n`, `int3`. `ctx.eip` points to the next instruction
+    ///
+    /// There is no IDT (unless there's an `InterruptVectorTable`), so by default we just stop
+    fn interrupt(&mut self, ctx: &mut CpuContext, vector: u8) {
+        let _ = vector;
+        ctx.exit = EXIT_HOST_REQUEST;
+    }
+
+    /// `sysenter`. `ctx.eip` points to the next instruction; the syscall number is in EAX and (on NT) EDX points
+    /// to the arguments on the user stack
+    ///
+    /// Execution resumes at `ctx.eip` with `ctx.gp_regs` as the handler left them, so the handler can emulate
+    /// the kernel's `sysexit` by setting EIP to EDX and ESP to ECX. By default we just stop, like with `interrupt`
+    fn fast_syscall(&mut self, ctx: &mut CpuContext) {
+        ctx.exit = EXIT_HOST_REQUEST;
+    }
+
+    /// A flag left undefined by the instruction at `source` is read by the one at `ctx.eip`
+    /// (only with `UndefinedFlagsPolicy::Strict`). The read gives false
+    ///
+    /// It's a bug somewhere, so by default we stop
+    fn undefined_flag(&mut self, ctx: &mut CpuContext, flag: Flag, source: u32) {
+        let _ = (flag, source);
+        ctx.exit = EXIT_HOST_REQUEST;
+    }
+
+    /// `in`. Only the low `size` bits of the result are used
+    ///
+    /// Nothing is connected by default, so reads return all ones (like an open bus does)
+    fn port_in(&mut self, ctx: &mut CpuContext, port: u16, size: IntType) -> u32 {
+        let _ = (ctx, port, size);
+        u32::MAX
+    }
+
+    /// `out`. `value` is zero-extended from `size`
+    fn port_out(&mut self, ctx: &mut CpuContext, port: u16, size: IntType, value: u32) {
+        let _ = (ctx, port, size, value);
+    }
+
+    /// `mov crN, r32` or `lmsw` (then `register` is 0 & `value` is the whole new CR0), with
+    /// `ControlRegisterWrites::Record`. The guest keeps reading the values from the `SystemRegisterProfile`
+    fn control_register_write(&mut self, ctx: &mut CpuContext, register: u8, value: u32) {
+        let _ = (ctx, register, value);
+    }
+
+    /// `wrmsr` with `ControlRegisterWrites::Record`. `rdmsr` keeps reading the values from the
+    /// `SystemRegisterProfile`
+    fn msr_write(&mut self, ctx: &mut CpuContext, index: u32, value: u64) {
+        let _ = (ctx, index, value);
+    }
+
+    /// `clflush` of the cache line with `address` in it, for the embedders that keep track of the caches or the code
+    /// modified by the guest. Nothing to do by default
+    fn cache_flush(&mut self, ctx: &mut CpuContext, address: u32) {
+        let _ = (ctx, address);
+    }
+
+    /// ESP went below the soft limit of the stack set up by `Runtime::setup_stack`, for the first time. The execution
+    /// goes on, nothing has faulted (yet). Leave `ctx.exit` alone: this is the middle of an instruction.
+    /// `ctx.eip` might be anywhere in the basic block
+    fn stack_soft_limit(&mut self, ctx: &mut CpuContext, esp: u32) {
+        let _ = ctx;
+        warn!(
+            "the guest stack went down to 0x{:08x}, past the soft limit",
+            esp
+        );
+    }
+
+    /// Called before every basic block (every instruction in the per-instruction mode) if enabled in the config.
+    /// `ctx.eip` is the address of the instruction
+    fn instruction(&mut self, ctx: &mut CpuContext) {
+        let _ = ctx;
+    }
+}
+
+/// Handler that leaves everything to the defaults
+pub struct NullHandler;
+
+impl RuntimeHandler for NullHandler {}
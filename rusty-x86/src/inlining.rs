@@ -0,0 +1,123 @@
+//! Inlining the tiny leaf functions into their callers
+//!
+//! Compiled code is full of functions like `mov eax, [ecx + 4]; ret`, and called through `call_basic_block` the
+//! `call`/`ret` pair costs more than the body. With `TranslationOptions::inline_leaves` a direct call to a function
+//! that is a single block ending in `ret` has the body lowered in its place: the return address is neither pushed
+//! nor popped, the execution just continues after the `call`. The leaf is still translated on its own for the
+//! other ways to get there.
+//!
+//! A leaf qualifies when nothing in it can tell the difference: it doesn't touch the stack (so nothing sees the
+//! missing return address) and every instruction but the `ret` continues to the next one. A fault in the inlined
+//! body is attributed to the instruction of the leaf, like in the real one, but ESP is 4 higher there: good enough
+//! to report the fault, not to resume from it.
+//!
+//! The bodies are copies, so the translation records where every leaf ended up (`Translation::inlined_leaves`):
+//! a change to the leaf has to throw away its callers too.
+
+use crate::ir::{Instr, Mnemonic};
+use crate::types::{FullSizeGeneralPurposeRegister, Operand, Register};
+
+/// Past this the call overhead doesn't matter much, and the copies start to add up
+pub const MAX_LEAF_INSTRUCTIONS: usize = 8;
+
+/// The instructions of `block` without the `ret`, if it's a leaf that can be inlined
+pub fn leaf_body(block: &[Instr]) -> Option<&[Instr]> {
+    let (ret, body) = block.split_last()?;
+    // `ret imm16` pops the arguments as well
+    if ret.mnemonic != Mnemonic::Ret
+        || !ret.operands.is_empty()
+        || body.len() > MAX_LEAF_INSTRUCTIONS
+    {
+        return None;
+    }
+    body.iter().all(inlinable).then_some(body)
+}
+
+fn inlinable(instr: &Instr) -> bool {
+    use Mnemonic::*;
+
+    let mnemonic = instr.mnemonic;
+    if mnemonic.is_branch() || mnemonic.ends_block() || mnemonic.uses_stack() {
+        return false;
+    }
+    // these go out to the handler, which might stop the execution in the middle of the leaf
+    if matches!(
+        mnemonic,
+        MovCr | Lmsw | Rdmsr | Wrmsr | Rdpmc | Invd | Wbinvd | Invlpg | Sysenter | In | Out
+    ) {
+        return false;
+    }
+    !instr.operands.iter().any(|operand| match operand {
+        Operand::Register(reg) => is_stack_pointer(*reg),
+        Operand::RegisterPair(hi, lo) => is_stack_pointer(*hi) || is_stack_pointer(*lo),
+        Operand::Memory(mem) => mem.base.into_iter().chain(mem.index).any(is_stack_pointer),
+        _ => false,
+    })
+}
+
+fn is_stack_pointer(reg: Register) -> bool {
+    reg.base_register() == FullSizeGeneralPurposeRegister::ESP
+}
+
+#[cfg(test)]
+mod tests {
+    use super::leaf_body;
+    use crate::assemble_x86;
+    use crate::ir::{decode_block, CodeMode, InvalidOpcodePolicy, UnsupportedInstructionPolicy};
+
+    fn body_len(code: &[u8]) -> Option<usize> {
+        let block = decode_block(
+            code,
+            0x1000,
+            usize::MAX,
+            InvalidOpcodePolicy::Fault,
+            UnsupportedInstructionPolicy::Fail,
+            CodeMode::Bits32,
+        )
+        .unwrap();
+        leaf_body(&block).map(|body| body.len())
+    }
+
+    #[test_log::test]
+    fn leaves() {
+        assert_eq!(body_len(&assemble_x86!(; ret)), Some(0));
+        assert_eq!(
+            body_len(&assemble_x86!(
+                ; mov eax, [ecx + 4]
+                ; add eax, edx
+                ; ret
+            )),
+            Some(2)
+        );
+        // the stack is only seen through EBP
+        assert_eq!(
+            body_len(&assemble_x86!(
+                ; mov eax, [ebp + 8]
+                ; ret
+            )),
+            Some(1)
+        );
+    }
+
+    #[test_log::test]
+    fn not_leaves() {
+        // the arguments
+        assert_eq!(body_len(&assemble_x86!(; mov eax, [esp + 4]; ret)), None);
+        assert_eq!(body_len(&assemble_x86!(; lea eax, [ebx + esp]; ret)), None);
+        assert_eq!(body_len(&assemble_x86!(; mov eax, esp; ret)), None);
+        assert_eq!(body_len(&assemble_x86!(; push ebx; pop eax; ret)), None);
+        assert_eq!(body_len(&assemble_x86!(; ret 8)), None);
+        assert_eq!(body_len(&assemble_x86!(; int 0x2e; ret)), None);
+        // not a single block
+        assert_eq!(
+            body_len(&assemble_x86!(; test eax, eax; je >skip; inc eax; skip:; ret)),
+            None
+        );
+        assert_eq!(body_len(&assemble_x86!(; jmp >next; next:; ret)), None);
+        assert_eq!(body_len(&assemble_x86!(; inc eax; jmp eax)), None);
+        assert_eq!(
+            body_len(&[0x90; 9].iter().chain(&[0xc3]).copied().collect::<Vec<_>>()),
+            None
+        );
+    }
+}
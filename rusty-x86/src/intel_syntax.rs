@@ -0,0 +1,760 @@
+//! Intel (NASM-flavoured) syntax for operands: `dword [fs:eax+ebx*4+0x10]`, `edx:eax`, `byte 0x2a`
+//!
+//! Display produces the canonical form and FromStr accepts it back (along with some less canonical variations,
+//! like upper-case registers, decimal numbers and `*1` scales)
+//!
+//! Canonical form details:
+//! - immediates and memory operands carry a size prefix (when the size is known), registers don't
+//! - zero displacement is omitted, unless there is nothing else in the brackets
+//! - scale 1 is omitted, unless there is no base (`[ebx*1]` is an index, while `[ebx]` is a base)
+//! - displacement without base & index is an absolute address, so it's shown as u32, otherwise as signed
+
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use strum::IntoEnumIterator;
+
+use crate::types::{IntType, MemoryOperand, Operand, Register, SegmentRegister};
+
+impl Register {
+    pub fn name(self) -> &'static str {
+        use Register::*;
+        match self {
+            EAX => "eax",
+            EBX => "ebx",
+            ECX => "ecx",
+            EDX => "edx",
+            ESP => "esp",
+            EBP => "ebp",
+            ESI => "esi",
+            EDI => "edi",
+            AX => "ax",
+            BX => "bx",
+            CX => "cx",
+            DX => "dx",
+            SP => "sp",
+            BP => "bp",
+            SI => "si",
+            DI => "di",
+            AH => "ah",
+            BH => "bh",
+            CH => "ch",
+            DH => "dh",
+            AL => "al",
+            BL => "bl",
+            CL => "cl",
+            DL => "dl",
+        }
+    }
+}
+
+impl SegmentRegister {
+    pub fn name(self) -> &'static str {
+        use SegmentRegister::*;
+        match self {
+            CS => "cs",
+            DS => "ds",
+            ES => "es",
+            FS => "fs",
+            GS => "gs",
+            SS => "ss",
+        }
+    }
+}
+
+impl IntType {
+    /// NASM size keyword
+    pub fn size_keyword(self) -> &'static str {
+        use IntType::*;
+        match self {
+            I8 => "byte",
+            I16 => "word",
+            I32 => "dword",
+            I64 => "qword",
+            I128 => "oword",
+        }
+    }
+}
+
+impl Display for Register {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Display for SegmentRegister {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Display for MemoryOperand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if let Some(size) = self.size {
+            write!(f, "{} ", size.size_keyword())?;
+        }
+        f.write_str("[")?;
+        if let Some(segment) = self.segment {
+            write!(f, "{}:", segment)?;
+        }
+
+        let mut empty = true;
+        if let Some(base) = self.base {
+            write!(f, "{}", base)?;
+            empty = false;
+        }
+        if let Some(index) = self.index {
+            if !empty {
+                f.write_str("+")?;
+            }
+            write!(f, "{}", index)?;
+            if self.scale != 1 || self.base.is_none() {
+                write!(f, "*{}", self.scale)?;
+            }
+            empty = false;
+        }
+
+        if empty {
+            write!(f, "0x{:x}", self.displacement as u32)?;
+        } else if self.displacement < 0 {
+            write!(f, "-0x{:x}", self.displacement.unsigned_abs())?;
+        } else if self.displacement > 0 {
+            write!(f, "+0x{:x}", self.displacement)?;
+        }
+
+        f.write_str("]")
+    }
+}
+
+impl Display for Operand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Register(reg) => write!(f, "{}", reg),
+            Operand::RegisterPair(hi, lo) => write!(f, "{}:{}", hi, lo),
+            Operand::Immediate8(v) => write!(f, "byte 0x{:x}", v),
+            Operand::Immediate16(v) => write!(f, "word 0x{:x}", v),
+            Operand::Immediate32(v) => write!(f, "dword 0x{:x}", v),
+            Operand::Immediate64(v) => write!(f, "qword 0x{:x}", v),
+            Operand::FarBranch(selector, offset) => write!(f, "0x{:x}:0x{:x}", selector, offset),
+            Operand::FpuRegister(i) => write!(f, "st{}", i),
+            Operand::Xmm(i) => write!(f, "xmm{}", i),
+            Operand::Mmx(i) => write!(f, "mm{}", i),
+            Operand::ControlRegister(i) => write!(f, "cr{}", i),
+            Operand::Memory(mem) => write!(f, "{}", mem),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseOperandError {
+    /// byte offset in the input where things went wrong
+    pub position: usize,
+    pub message: String,
+}
+
+impl Display for ParseOperandError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} (at offset {})", self.message, self.position)
+    }
+}
+
+impl std::error::Error for ParseOperandError {}
+
+/// Cursor over the input string. Whitespace is skipped before every token
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self { input, pos: 0 }
+    }
+
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseOperandError> {
+        Err(ParseOperandError {
+            position: self.pos,
+            message: message.into(),
+        })
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.input[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Current position (after the whitespace), used to point at the start of a token in errors
+    fn mark(&mut self) -> usize {
+        self.skip_whitespace();
+        self.pos
+    }
+
+    fn rest(&mut self) -> &'a str {
+        self.skip_whitespace();
+        &self.input[self.pos..]
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<(), ParseOperandError> {
+        if self.eat(c) {
+            Ok(())
+        } else {
+            match self.peek() {
+                Some(found) => self.error(format!("expected `{}`, found `{}`", c, found)),
+                None => self.error(format!("expected `{}`, found end of input", c)),
+            }
+        }
+    }
+
+    /// Takes an identifier-like word (letters & digits), doesn't consume anything if there is none
+    fn peek_word(&mut self) -> &'a str {
+        let rest = self.rest();
+        let len = rest
+            .find(|c: char| !c.is_ascii_alphanumeric())
+            .unwrap_or(rest.len());
+        &rest[..len]
+    }
+
+    fn try_word<T>(&mut self, lookup: impl Fn(&str) -> Option<T>) -> Option<T> {
+        let word = self.peek_word();
+        let res = lookup(&word.to_ascii_lowercase());
+        if res.is_some() {
+            self.pos += word.len();
+        }
+        res
+    }
+
+    fn try_size(&mut self) -> Option<IntType> {
+        self.try_word(|w| match w {
+            "byte" => Some(IntType::I8),
+            "word" => Some(IntType::I16),
+            "dword" => Some(IntType::I32),
+            "qword" => Some(IntType::I64),
+            "oword" => Some(IntType::I128),
+            _ => None,
+        })
+    }
+
+    fn try_register(&mut self) -> Option<Register> {
+        self.try_word(|w| Register::iter().find(|r| r.name() == w))
+    }
+
+    /// `st0`..`st7`
+    fn try_fpu_register(&mut self) -> Option<u8> {
+        self.try_numbered_register("st")
+    }
+
+    /// `xmm0`..`xmm7`
+    fn try_xmm_register(&mut self) -> Option<u8> {
+        self.try_numbered_register("xmm")
+    }
+
+    /// `mm0`..`mm7`
+    fn try_mmx_register(&mut self) -> Option<u8> {
+        self.try_numbered_register("mm")
+    }
+
+    /// `cr0`..`cr7`
+    fn try_control_register(&mut self) -> Option<u8> {
+        self.try_numbered_register("cr")
+    }
+
+    fn try_numbered_register(&mut self, prefix: &str) -> Option<u8> {
+        self.try_word(|w| match w.strip_prefix(prefix)?.parse() {
+            Ok(i) if i < 8 && w.len() == prefix.len() + 1 => Some(i),
+            _ => None,
+        })
+    }
+
+    fn try_segment(&mut self) -> Option<SegmentRegister> {
+        self.try_word(|w| SegmentRegister::iter().find(|r| r.name() == w))
+    }
+
+    /// `0x2a` or `42`
+    fn number(&mut self) -> Result<u64, ParseOperandError> {
+        let word = self.peek_word();
+        if word.is_empty() {
+            return match self.peek() {
+                Some(c) => self.error(format!("expected a number, found `{}`", c)),
+                None => self.error("expected a number, found end of input"),
+            };
+        }
+
+        let res = match word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")) {
+            Some(hex) => u64::from_str_radix(hex, 16),
+            None => word.parse(),
+        };
+
+        match res {
+            Ok(v) => {
+                self.pos += word.len();
+                Ok(v)
+            }
+            Err(e) => self.error(format!("invalid number `{}`: {}", word, e)),
+        }
+    }
+
+    fn memory(&mut self, size: Option<IntType>) -> Result<MemoryOperand, ParseOperandError> {
+        self.expect('[')?;
+
+        let segment_start = self.mark();
+        let mut segment = self.try_segment();
+        if segment.is_some() && !self.eat(':') {
+            // nope, that wasn't a segment override
+            self.pos = segment_start;
+            segment = None;
+        }
+
+        let mut base = None;
+        let mut index = None;
+        let mut scale = 1;
+        let mut displacement: Option<i64> = None;
+
+        let mut negative = false;
+        loop {
+            let term_start = self.mark();
+            if let Some(reg) = self.try_register() {
+                if negative {
+                    self.pos = term_start;
+                    return self.error("registers can't be subtracted");
+                }
+                if reg.size() != IntType::I32 {
+                    self.pos = term_start;
+                    return self.error(format!(
+                        "only 32-bit registers can be used in addresses, found `{}`",
+                        reg
+                    ));
+                }
+
+                if self.eat('*') {
+                    let scale_start = self.mark();
+                    let s = self.number()?;
+                    if !matches!(s, 1 | 2 | 4 | 8) {
+                        self.pos = scale_start;
+                        return self.error(format!("scale should be 1, 2, 4 or 8, found {}", s));
+                    }
+                    if index.is_some() {
+                        self.pos = term_start;
+                        return self.error("more than one index register");
+                    }
+                    index = Some(reg);
+                    scale = s as u8;
+                } else if base.is_none() {
+                    base = Some(reg);
+                } else if index.is_none() {
+                    index = Some(reg);
+                } else {
+                    return self.error("too many registers");
+                }
+            } else {
+                let value = self.number()?;
+                if displacement.is_some() {
+                    self.pos = term_start;
+                    return self.error("more than one displacement");
+                }
+                if value > u32::MAX as u64 {
+                    self.pos = term_start;
+                    return self
+                        .error(format!("displacement 0x{:x} doesn't fit in 32 bits", value));
+                }
+                // the addresses are 32-bit & wrap around, so `+0xfffffff8` is the same thing as `-8`
+                let value = value as u32;
+                let value = if negative {
+                    value.wrapping_neg()
+                } else {
+                    value
+                };
+                displacement = Some(value as i32 as i64);
+            }
+
+            if self.eat('+') {
+                negative = false;
+            } else if self.eat('-') {
+                negative = true;
+            } else {
+                break;
+            }
+        }
+
+        self.expect(']')?;
+
+        Ok(MemoryOperand {
+            base,
+            displacement: displacement.unwrap_or(0),
+            scale,
+            index,
+            size,
+            segment,
+        })
+    }
+
+    fn operand(&mut self) -> Result<Operand, ParseOperandError> {
+        let size_start = self.mark();
+        let size = self.try_size();
+
+        let res = if self.peek() == Some('[') {
+            Operand::Memory(self.memory(size)?)
+        } else if let Some(reg) = self.try_register() {
+            if size.is_some() {
+                self.pos = size_start;
+                return self.error("registers don't take a size prefix");
+            }
+            if self.eat(':') {
+                let lo_start = self.mark();
+                match self.try_register() {
+                    Some(lo) if lo.size() == reg.size() => Operand::RegisterPair(reg, lo),
+                    Some(lo) => {
+                        self.pos = lo_start;
+                        return self.error(format!(
+                            "register pair halves should be the same size, found `{}:{}`",
+                            reg, lo
+                        ));
+                    }
+                    None => return self.error("expected a register after `:`"),
+                }
+            } else {
+                Operand::Register(reg)
+            }
+        } else if let Some(reg) = self
+            .try_fpu_register()
+            .map(Operand::FpuRegister)
+            .or_else(|| self.try_xmm_register().map(Operand::Xmm))
+            .or_else(|| self.try_mmx_register().map(Operand::Mmx))
+            .or_else(|| self.try_control_register().map(Operand::ControlRegister))
+        {
+            if size.is_some() {
+                self.pos = size_start;
+                return self.error("registers don't take a size prefix");
+            }
+            reg
+        } else {
+            let number_start = self.mark();
+            let value = self.number()?;
+
+            if self.eat(':') {
+                if size.is_some() {
+                    self.pos = size_start;
+                    return self.error("far pointers don't take a size prefix");
+                }
+                let selector = match u16::try_from(value) {
+                    Ok(s) => s,
+                    Err(_) => {
+                        self.pos = number_start;
+                        return self.error(format!(
+                            "segment selector 0x{:x} doesn't fit in 16 bits",
+                            value
+                        ));
+                    }
+                };
+                let offset_start = self.mark();
+                let offset = self.number()?;
+                match u32::try_from(offset) {
+                    Ok(offset) => Operand::FarBranch(selector, offset),
+                    Err(_) => {
+                        self.pos = offset_start;
+                        return self.error(format!(
+                            "far pointer offset 0x{:x} doesn't fit in 32 bits",
+                            offset
+                        ));
+                    }
+                }
+            } else {
+                let fits = |max: u64| -> Result<u64, ParseOperandError> {
+                    if value <= max {
+                        Ok(value)
+                    } else {
+                        Err(ParseOperandError {
+                            position: number_start,
+                            message: format!(
+                                "immediate 0x{:x} doesn't fit in the operand size",
+                                value
+                            ),
+                        })
+                    }
+                };
+                match size {
+                    Some(IntType::I8) => Operand::Immediate8(fits(u8::MAX as u64)? as u8),
+                    Some(IntType::I16) => Operand::Immediate16(fits(u16::MAX as u64)? as u16),
+                    Some(IntType::I32) => Operand::Immediate32(fits(u32::MAX as u64)? as u32),
+                    Some(IntType::I64) => Operand::Immediate64(value),
+                    Some(IntType::I128) => {
+                        self.pos = size_start;
+                        return self.error("there are no 128-bit immediates");
+                    }
+                    None => {
+                        self.pos = number_start;
+                        return self
+                            .error("immediates need a size prefix (byte, word, dword or qword)");
+                    }
+                }
+            }
+        };
+
+        if !self.rest().is_empty() {
+            let rest = self.rest();
+            return self.error(format!("unexpected trailing input `{}`", rest));
+        }
+
+        Ok(res)
+    }
+}
+
+fn parse_whole<T>(
+    s: &str,
+    what: &str,
+    parse: impl FnOnce(&mut Parser) -> Option<T>,
+) -> Result<T, ParseOperandError> {
+    let mut parser = Parser::new(s);
+    match parse(&mut parser) {
+        Some(res) if parser.rest().is_empty() => Ok(res),
+        _ => Err(ParseOperandError {
+            position: 0,
+            message: format!("`{}` is not a {}", s.trim(), what),
+        }),
+    }
+}
+
+impl FromStr for Register {
+    type Err = ParseOperandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_whole(s, "register", |p| p.try_register())
+    }
+}
+
+impl FromStr for SegmentRegister {
+    type Err = ParseOperandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_whole(s, "segment register", |p| p.try_segment())
+    }
+}
+
+impl FromStr for MemoryOperand {
+    type Err = ParseOperandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.parse()? {
+            Operand::Memory(mem) => Ok(mem),
+            op => Err(ParseOperandError {
+                position: 0,
+                message: format!("expected a memory operand, found `{}`", op),
+            }),
+        }
+    }
+}
+
+impl FromStr for Operand {
+    type Err = ParseOperandError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parser::new(s).operand()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use crate::types::{IntType, MemoryOperand, Operand, Register, SegmentRegister};
+    use strum::IntoEnumIterator;
+
+    fn mem(
+        base: Option<Register>,
+        index: Option<Register>,
+        scale: u8,
+        displacement: i64,
+        size: Option<IntType>,
+        segment: Option<SegmentRegister>,
+    ) -> Operand {
+        Operand::Memory(MemoryOperand {
+            base,
+            displacement,
+            scale,
+            index,
+            size,
+            segment,
+        })
+    }
+
+    #[test_log::test]
+    #[rustfmt::skip]
+    fn display() {
+        use Register::*;
+        use IntType::*;
+
+        assert_eq!(Operand::Register(EAX).to_string(), "eax");
+        assert_eq!(Operand::RegisterPair(EDX, EAX).to_string(), "edx:eax");
+        assert_eq!(Operand::Immediate8(42).to_string(), "byte 0x2a");
+        assert_eq!(Operand::Immediate32(0xdeadbeef).to_string(), "dword 0xdeadbeef");
+        assert_eq!(Operand::FarBranch(0x23, 0x401000).to_string(), "0x23:0x401000");
+        assert_eq!(Operand::FpuRegister(1).to_string(), "st1");
+        assert_eq!(Operand::Xmm(7).to_string(), "xmm7");
+        assert_eq!(Operand::Mmx(2).to_string(), "mm2");
+        assert_eq!(Operand::ControlRegister(3).to_string(), "cr3");
+
+        assert_eq!(mem(Some(EAX), Some(EBX), 4, 0x10, Some(I32), None).to_string(), "dword [eax+ebx*4+0x10]");
+        // scale 1 is omitted
+        assert_eq!(mem(Some(EAX), Some(EBX), 1, 0, Some(I32), None).to_string(), "dword [eax+ebx]");
+        // ...unless there is no base
+        assert_eq!(mem(None, Some(EBX), 1, 0, Some(I32), None).to_string(), "dword [ebx*1]");
+        assert_eq!(mem(None, Some(EBX), 8, 0x20, None, None).to_string(), "[ebx*8+0x20]");
+        // no base - absolute address
+        assert_eq!(mem(None, None, 1, 0x1000, Some(I8), None).to_string(), "byte [0x1000]");
+        assert_eq!(mem(None, None, 1, -0x10000, Some(I8), None).to_string(), "byte [0xffff0000]");
+        assert_eq!(mem(None, None, 1, 0, Some(I8), None).to_string(), "byte [0x0]");
+        // negative displacement
+        assert_eq!(mem(Some(EBP), None, 1, -8, Some(I16), None).to_string(), "word [ebp-0x8]");
+        assert_eq!(mem(Some(ESP), None, 1, 0, Some(I64), Some(SegmentRegister::FS)).to_string(), "qword [fs:esp]");
+    }
+
+    #[test_log::test]
+    #[rustfmt::skip]
+    fn parse() {
+        use Register::*;
+        use IntType::*;
+
+        assert_eq!("EAX".parse::<Operand>().unwrap(), Operand::Register(EAX));
+        assert_eq!(" edx : eax ".parse::<Operand>().unwrap(), Operand::RegisterPair(EDX, EAX));
+        assert_eq!("byte 42".parse::<Operand>().unwrap(), Operand::Immediate8(42));
+        assert_eq!("ST3".parse::<Operand>().unwrap(), Operand::FpuRegister(3));
+        assert_eq!("mm5".parse::<Operand>().unwrap(), Operand::Mmx(5));
+        assert_eq!("CR0".parse::<Operand>().unwrap(), Operand::ControlRegister(0));
+        assert_eq!("oword [eax]".parse::<Operand>().unwrap(), mem(Some(EAX), None, 1, 0, Some(I128), None));
+        assert_eq!("dword [ eax + ebx * 4 + 16 ]".parse::<Operand>().unwrap(), mem(Some(EAX), Some(EBX), 4, 0x10, Some(I32), None));
+        assert_eq!("[ebx*1]".parse::<Operand>().unwrap(), mem(None, Some(EBX), 1, 0, None, None));
+        assert_eq!("[eax+ebx*1]".parse::<Operand>().unwrap(), mem(Some(EAX), Some(EBX), 1, 0, None, None));
+        assert_eq!("[0x10+eax]".parse::<Operand>().unwrap(), mem(Some(EAX), None, 1, 0x10, None, None));
+        assert_eq!("[ebp-8]".parse::<Operand>().unwrap(), mem(Some(EBP), None, 1, -8, None, None));
+        assert_eq!("[0xffff0000]".parse::<Operand>().unwrap(), mem(None, None, 1, -0x10000, None, None));
+        // the displacements are 32-bit, sign-extended like the decoder does
+        assert_eq!("[eax+0xfffffff8]".parse::<Operand>().unwrap(), mem(Some(EAX), None, 1, -8, None, None));
+        assert_eq!("[ebx+ecx*4-0x100]".parse::<Operand>().unwrap(), mem(Some(EBX), Some(ECX), 4, -0x100, None, None));
+        assert_eq!("[esi-0x80000000]".parse::<Operand>().unwrap(), mem(Some(ESI), None, 1, i32::MIN as i64, None, None));
+        assert_eq!("[esi-0xffffffff]".parse::<Operand>().unwrap(), mem(Some(ESI), None, 1, 1, None, None));
+        assert_eq!("word [gs:0x30]".parse::<Operand>().unwrap(), mem(None, None, 1, 0x30, Some(I16), Some(SegmentRegister::GS)));
+
+        assert_eq!("fs".parse::<SegmentRegister>().unwrap(), SegmentRegister::FS);
+        assert_eq!("cl".parse::<Register>().unwrap(), CL);
+    }
+
+    #[test_log::test]
+    #[rustfmt::skip]
+    fn parse_errors() {
+        fn err(s: &str) -> String {
+            s.parse::<Operand>().unwrap_err().to_string()
+        }
+
+        assert_eq!(err("42"), "immediates need a size prefix (byte, word, dword or qword) (at offset 0)");
+        assert_eq!(err("oword 1"), "there are no 128-bit immediates (at offset 0)");
+        assert_eq!(err("byte 0x100"), "immediate 0x100 doesn't fit in the operand size (at offset 5)");
+        assert_eq!(err("dword eax"), "registers don't take a size prefix (at offset 0)");
+        assert_eq!(err("[eax+ebx*3]"), "scale should be 1, 2, 4 or 8, found 3 (at offset 9)");
+        assert_eq!(err("[ax]"), "only 32-bit registers can be used in addresses, found `ax` (at offset 1)");
+        assert_eq!(err("[eax-ebx]"), "registers can't be subtracted (at offset 5)");
+        assert_eq!(err("[eax"), "expected `]`, found end of input (at offset 4)");
+        assert_eq!(err("[eax] ebx"), "unexpected trailing input `ebx` (at offset 6)");
+        assert_eq!(err("eax:bl"), "register pair halves should be the same size, found `eax:bl` (at offset 4)");
+        assert_eq!(err("[eax+zzz]"), "invalid number `zzz`: invalid digit found in string (at offset 5)");
+        assert_eq!(err("[eax-0x100000000]"), "displacement 0x100000000 doesn't fit in 32 bits (at offset 5)");
+
+        assert_eq!("xmm0".parse::<Register>().unwrap_err().to_string(), "`xmm0` is not a register (at offset 0)");
+    }
+
+    fn any_register() -> impl Strategy<Value = Register> {
+        prop::sample::select(Register::iter().collect::<Vec<_>>())
+    }
+
+    fn any_address_register() -> impl Strategy<Value = Register> {
+        prop::sample::select(
+            Register::iter()
+                .filter(|r| r.size() == IntType::I32)
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn any_size() -> impl Strategy<Value = IntType> {
+        prop_oneof![
+            Just(IntType::I8),
+            Just(IntType::I16),
+            Just(IntType::I32),
+            Just(IntType::I64),
+            Just(IntType::I128)
+        ]
+    }
+
+    fn any_memory() -> impl Strategy<Value = MemoryOperand> {
+        (
+            prop::option::of(any_address_register()),
+            prop::option::of((
+                any_address_register(),
+                prop::sample::select(vec![1u8, 2, 4, 8]),
+            )),
+            any::<i32>(),
+            prop::option::of(any_size()),
+            prop::option::of(prop::sample::select(
+                SegmentRegister::iter().collect::<Vec<_>>(),
+            )),
+        )
+            .prop_map(|(base, index, displacement, size, segment)| MemoryOperand {
+                base,
+                displacement: displacement as i64,
+                // the decoder says 1 when there is no index
+                scale: index.map_or(1, |(_, scale)| scale),
+                index: index.map(|(index, _)| index),
+                size,
+                segment,
+            })
+    }
+
+    fn any_operand() -> impl Strategy<Value = Operand> {
+        prop_oneof![
+            any_register().prop_map(Operand::Register),
+            any_register()
+                .prop_flat_map(|hi| {
+                    let same_size = Register::iter().filter(|r| r.size() == hi.size());
+                    (
+                        Just(hi),
+                        prop::sample::select(same_size.collect::<Vec<_>>()),
+                    )
+                })
+                .prop_map(|(hi, lo)| Operand::RegisterPair(hi, lo)),
+            any::<u8>().prop_map(Operand::Immediate8),
+            any::<u16>().prop_map(Operand::Immediate16),
+            any::<u32>().prop_map(Operand::Immediate32),
+            any::<u64>().prop_map(Operand::Immediate64),
+            (any::<u16>(), any::<u32>()).prop_map(|(s, o)| Operand::FarBranch(s, o)),
+            (0u8..8).prop_map(Operand::FpuRegister),
+            (0u8..8).prop_map(Operand::Xmm),
+            (0u8..8).prop_map(Operand::Mmx),
+            (0u8..8).prop_map(Operand::ControlRegister),
+            any_memory().prop_map(Operand::Memory),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn operand_round_trip(op in any_operand()) {
+            let s = op.to_string();
+            prop_assert_eq!(s.parse::<Operand>().unwrap(), op, "{}", s);
+        }
+
+        #[test]
+        fn memory_operand_round_trip(op in any_memory()) {
+            let s = op.to_string();
+            prop_assert_eq!(s.parse::<MemoryOperand>().unwrap(), op, "{}", s);
+        }
+    }
+}
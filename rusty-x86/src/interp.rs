@@ -0,0 +1,4994 @@
+//! A (slow) interpreter implementing the same `Builder` interface as the LLVM backend
+//!
+//! Values are just numbers, so every builder call is executed right away.
+//! Doesn't need anything from the OS: the guest memory is a plain `Vec<u8>` starting at guest address 0,
+//! so it works on wasm32-unknown-unknown
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::backend::{
+    BoolValue, Builder, ComparisonType, FloatComparisonType, IntValue, RoundingMode,
+};
+use crate::flags::{self, mask};
+use crate::handler::{GuestFault, InterruptVectorTable, RuntimeHandler};
+use crate::ir::{
+    decode_block, CodeMode, Condition, DecodeError, Decoder, Instr, InvalidOpcodePolicy, Mnemonic,
+    UnsupportedInstructionPolicy,
+};
+use crate::liveness::FlagSet;
+use crate::segmentation::SegmentationPolicy;
+use crate::system_registers::SystemRegisterProfile;
+use crate::types::{
+    ControlFlow, CpuContext, Flag, FlagStorage, FpuWord, FullSizeGeneralPurposeRegister, IntType,
+    Operand, Register, UndefinedFlagsPolicy, EXIT_GUEST, EXIT_NONE, FLAG_UNDEFINED,
+    PF_INSTRUCTION_FETCH,
+};
+use strum::IntoEnumIterator;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterpValue {
+    pub ty: IntType,
+    // always truncated to ty
+    pub bits: u64,
+}
+
+impl InterpValue {
+    pub fn new(ty: IntType, bits: u64) -> Self {
+        Self {
+            ty,
+            bits: bits & mask(ty),
+        }
+    }
+
+    fn signed(self) -> i64 {
+        flags::sign_extend(self.bits, self.ty)
+    }
+}
+
+impl IntValue for InterpValue {
+    fn size(&self) -> IntType {
+        self.ty
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterpBool(pub bool);
+
+impl BoolValue for InterpBool {}
+
+/// Why an instruction couldn't complete
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InterpFault {
+    /// The access doesn't fit in the memory vector
+    MemoryOutOfBounds { address: u32, size: u8 },
+    /// Division by zero or the quotient doesn't fit
+    DivideError,
+    /// The generated code asked to trap
+    Trap,
+    /// Couldn't decode an instruction at eip (or it's one we don't support)
+    InvalidInstruction,
+    /// The guest code raised an exception
+    Guest { fault: GuestFault, address: u32 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    /// The instruction was executed, eip points to the next one
+    Continue,
+    /// `ret` from the outermost function, eip is left pointing at the `ret`
+    Returned,
+    /// The handler set `CpuContext::exit`, eip points to the next instruction
+    HostRequest,
+    /// The handler ended the execution with `handler::guest_exit`, eip points to the next instruction
+    GuestExit { code: u32 },
+    /// Nothing was changed in the context (though the memory writes done before the fault stay), eip points to the faulting instruction
+    Fault(InterpFault),
+}
+
+pub struct Interpreter<H: RuntimeHandler> {
+    pub context: CpuContext,
+    pub memory: Vec<u8>,
+    pub handler: H,
+    pub segmentation: SegmentationPolicy,
+    pub invalid_opcodes: InvalidOpcodePolicy,
+    pub unsupported_instructions: UnsupportedInstructionPolicy,
+    pub code_mode: CodeMode,
+    pub strict_alignment: bool,
+    /// Stores to these flags are skipped, set before `execute` to apply the flag liveness (see liveness.rs)
+    pub dead_flags: FlagSet,
+    pub undefined_flags: UndefinedFlagsPolicy,
+    /// See `TranslationOptions::address_wraparound`
+    pub address_wraparound: bool,
+    /// See `TranslationOptions::alignment_checks`
+    pub alignment_checks: bool,
+    pub interrupt_vectors: InterruptVectorTable,
+    pub system_registers: SystemRegisterProfile,
+    /// The blocks decoded by `run_cached`
+    pub block_cache: BlockCache,
+
+    // the instruction being executed, from its start to the start of the next one
+    instruction_bounds: (u32, u32),
+    // return addresses of the calls executed so far (the recompiled code uses the host stack for this)
+    call_stack: Vec<u32>,
+    // set by the calls (and by fast_syscall when the handler moves EIP), overrides the next eip
+    call_target: Option<u32>,
+    fault: Option<InterpFault>,
+}
+
+impl<H: RuntimeHandler> Interpreter<H> {
+    pub fn new(memory: Vec<u8>, handler: H) -> Self {
+        Self {
+            context: CpuContext::default(),
+            memory,
+            handler,
+            segmentation: SegmentationPolicy::default(),
+            invalid_opcodes: InvalidOpcodePolicy::default(),
+            unsupported_instructions: UnsupportedInstructionPolicy::default(),
+            code_mode: CodeMode::default(),
+            strict_alignment: false,
+            dead_flags: FlagSet::empty(),
+            undefined_flags: UndefinedFlagsPolicy::default(),
+            address_wraparound: false,
+            alignment_checks: false,
+            interrupt_vectors: InterruptVectorTable::default(),
+            system_registers: SystemRegisterProfile::default(),
+            block_cache: BlockCache::default(),
+            instruction_bounds: (0, 0),
+            call_stack: Vec::new(),
+            call_target: None,
+            fault: None,
+        }
+    }
+
+    fn set_fault(&mut self, fault: InterpFault) {
+        // the first one is the interesting one
+        self.fault.get_or_insert(fault);
+    }
+
+    /// Executes a single instruction at `context.eip`
+    pub fn step(&mut self) -> StepResult {
+        let eip = self.context.eip;
+        let code = self.memory.get(eip as usize..).unwrap_or(&[]);
+        match Decoder::new(code, eip)
+            .code_mode(self.code_mode)
+            .invalid_opcodes(self.invalid_opcodes)
+            .unsupported_instructions(self.unsupported_instructions)
+            .decode()
+        {
+            Ok(instr) => self.execute(&instr),
+            Err(DecodeError::Truncated { ip, end }) => self.execute(&Instr::fetch_fault(ip, end)),
+            Err(_) => StepResult::Fault(InterpFault::InvalidInstruction),
+        }
+    }
+
+    /// Executes an already decoded instruction, as if it was located at `instr.ip`
+    pub fn execute(&mut self, instr: &Instr) -> StepResult {
+        self.context.eip = instr.ip;
+        self.handler.instruction(&mut self.context);
+
+        let saved_context = self.context.clone();
+        let saved_call_stack_len = self.call_stack.len();
+        self.context.exit = EXIT_NONE;
+        self.fault = None;
+        self.call_target = None;
+
+        let flow = crate::codegen_instr(self, instr);
+
+        if let Some(fault) = self.fault.take() {
+            self.context = saved_context;
+            self.call_stack.truncate(saved_call_stack_len);
+            return StepResult::Fault(fault);
+        }
+
+        let next_eip = instr.next_ip();
+        let result = match flow {
+            ControlFlow::NextInstruction => {
+                self.context.eip = self.call_target.take().unwrap_or(next_eip);
+                StepResult::Continue
+            }
+            ControlFlow::DirectJump(target) => {
+                self.context.eip = target;
+                StepResult::Continue
+            }
+            ControlFlow::IndirectJump(target) => {
+                self.context.eip = target.bits as u32;
+                StepResult::Continue
+            }
+            ControlFlow::Conditional(cond, target) => {
+                self.context.eip = if cond.0 { target } else { next_eip };
+                StepResult::Continue
+            }
+            ControlFlow::Switch(index, targets, fallback) => {
+                self.context.eip = targets
+                    .get(index.bits as usize)
+                    .copied()
+                    .unwrap_or(fallback.bits as u32);
+                StepResult::Continue
+            }
+            ControlFlow::Return => match self.call_stack.pop() {
+                Some(ret) => {
+                    self.context.eip = ret;
+                    StepResult::Continue
+                }
+                None => StepResult::Returned,
+            },
+        };
+
+        if self.context.exit != EXIT_NONE {
+            // interrupt() & friends have already set eip to the next instruction
+            return self.exit_result();
+        }
+        result
+    }
+
+    /// Steps until something other than `Continue` happens or `max_steps` instructions are executed
+    pub fn run(&mut self, max_steps: usize) -> StepResult {
+        for _ in 0..max_steps {
+            match self.step() {
+                StepResult::Continue => {}
+                r => return r,
+            }
+        }
+        StepResult::Continue
+    }
+
+    /// `run`, but every block is decoded once & kept in `block_cache`, with the simplest instructions executed
+    /// without the builder calls (see `FastOp`)
+    ///
+    /// The cache doesn't notice the code changing (or the decoding policies), call `BlockCache::invalidate` then
+    pub fn run_cached(&mut self, max_steps: usize) -> StepResult {
+        let mut steps = 0;
+        while steps < max_steps {
+            let block = match self.cached_block(self.context.eip) {
+                Some(block) => block,
+                // `step` makes sense of whatever is wrong with the code
+                None => {
+                    steps += 1;
+                    match self.step() {
+                        StepResult::Continue => continue,
+                        r => return r,
+                    }
+                }
+            };
+            for cached in block.iter() {
+                if steps == max_steps {
+                    break;
+                }
+                steps += 1;
+                match self.execute_cached(cached) {
+                    StepResult::Continue => {}
+                    r => return r,
+                }
+                // a jump, the rest of the block is not executed
+                if self.context.eip != cached.instr.next_ip() {
+                    break;
+                }
+            }
+        }
+        StepResult::Continue
+    }
+
+    fn cached_block(&mut self, eip: u32) -> Option<Rc<[CachedInstr]>> {
+        if let Some(block) = self.block_cache.blocks.get(&eip) {
+            self.block_cache.stats.hits += 1;
+            return Some(block.clone());
+        }
+
+        let code = self.memory.get(eip as usize..).unwrap_or(&[]);
+        let block = decode_block(
+            code,
+            eip,
+            MAX_CACHED_BLOCK_LEN,
+            self.invalid_opcodes,
+            self.unsupported_instructions,
+            self.code_mode,
+        )
+        .ok()
+        .filter(|block| !block.is_empty())?;
+
+        self.block_cache.stats.misses += 1;
+        let block: Rc<[CachedInstr]> = block.into_iter().map(CachedInstr::new).collect();
+        self.block_cache.blocks.insert(eip, block.clone());
+        Some(block)
+    }
+
+    /// `execute`, skipping `codegen_instr` if there's a fast form of the instruction
+    fn execute_cached(&mut self, cached: &CachedInstr) -> StepResult {
+        let instr = &cached.instr;
+        if let FastOp::Generic = cached.op {
+            return self.execute(instr);
+        }
+        self.block_cache.stats.fast_instructions += 1;
+
+        self.context.eip = instr.ip;
+        self.handler.instruction(&mut self.context);
+        self.context.exit = EXIT_NONE;
+
+        let ctx = &mut self.context;
+        let next_eip = instr.next_ip();
+        self.context.eip = match cached.op {
+            FastOp::MovReg { dst, src } => {
+                ctx.set_gp_reg(dst, ctx.get_gp_reg(src));
+                next_eip
+            }
+            FastOp::MovImm { dst, value } => {
+                ctx.set_gp_reg(dst, value);
+                next_eip
+            }
+            FastOp::Lea {
+                dst,
+                base,
+                index,
+                scale,
+                displacement,
+            } => {
+                let base = base.map_or(0, |base| ctx.get_gp_reg(base));
+                let index = index.map_or(0, |index| ctx.get_gp_reg(index));
+                let address = displacement
+                    .wrapping_add(base)
+                    .wrapping_add(index.wrapping_mul(scale as u32));
+                ctx.set_gp_reg(dst, address);
+                next_eip
+            }
+            FastOp::Jcc { condition, target } => {
+                // reading a poisoned flag goes to the handler
+                self.begin_instruction(instr);
+                self.fault = None;
+                let taken = crate::evaluate_cc(self, condition);
+                if let Some(fault) = self.fault.take() {
+                    return StepResult::Fault(fault);
+                }
+                if taken.0 {
+                    target
+                } else {
+                    next_eip
+                }
+            }
+            FastOp::Generic => unreachable!(),
+        };
+
+        if self.context.exit != EXIT_NONE {
+            return self.exit_result();
+        }
+        StepResult::Continue
+    }
+
+    /// Why the handler stopped the execution
+    fn exit_result(&self) -> StepResult {
+        match self.context.exit {
+            EXIT_GUEST => StepResult::GuestExit {
+                code: self.context.exit_code,
+            },
+            _ => StepResult::HostRequest,
+        }
+    }
+
+    /// #AC for a misaligned access (see `TranslationOptions::alignment_checks`)
+    fn check_alignment(&mut self, address: u32, size: IntType) {
+        let checked = matches!(size, IntType::I16 | IntType::I32 | IntType::I64);
+        if self.alignment_checks
+            && self.context.alignment_check != 0
+            && checked
+            && address & (size.byte_width() as u32 - 1) != 0
+        {
+            self.set_fault(InterpFault::Guest {
+                fault: GuestFault::AlignmentCheck,
+                address,
+            });
+        }
+    }
+
+    /// Whether the access crosses the top of the address space & should be split into the wrapped bytes
+    fn wraps_around(&self, address: u32, size: IntType) -> bool {
+        self.address_wraparound && address.checked_add(size.byte_width() as u32 - 1).is_none()
+    }
+
+    fn access(&mut self, address: u32, size: IntType) -> Option<std::ops::Range<usize>> {
+        let start = address as usize;
+        let end = start + size.byte_width() as usize;
+        if end > self.memory.len() {
+            self.set_fault(InterpFault::MemoryOutOfBounds {
+                address,
+                size: size.byte_width(),
+            });
+            None
+        } else {
+            Some(start..end)
+        }
+    }
+}
+
+/// A block is split in pieces of at most this many instructions, so that a long run of zeros (`add [eax], al`) is not
+/// decoded to the end of the memory at once
+const MAX_CACHED_BLOCK_LEN: usize = 256;
+
+/// The instructions `run_cached` executes right away, without `codegen_instr`: they don't touch the memory and don't
+/// write the flags, so none of the checks done by the builder calls apply
+#[derive(Debug, Clone, Copy)]
+enum FastOp {
+    /// `mov r32, r32`
+    MovReg {
+        dst: FullSizeGeneralPurposeRegister,
+        src: FullSizeGeneralPurposeRegister,
+    },
+    /// `mov r32, imm32`
+    MovImm {
+        dst: FullSizeGeneralPurposeRegister,
+        value: u32,
+    },
+    /// `lea r32, [base + index * scale + displacement]` with a 32-bit address
+    Lea {
+        dst: FullSizeGeneralPurposeRegister,
+        base: Option<FullSizeGeneralPurposeRegister>,
+        index: Option<FullSizeGeneralPurposeRegister>,
+        scale: u8,
+        displacement: u32,
+    },
+    /// `jcc rel`
+    Jcc { condition: Condition, target: u32 },
+    /// Through `execute`
+    Generic,
+}
+
+impl FastOp {
+    fn new(instr: &Instr) -> Self {
+        Self::of(instr).unwrap_or(FastOp::Generic)
+    }
+
+    fn of(instr: &Instr) -> Option<Self> {
+        let full = |register: Register| {
+            (register.size() == IntType::I32).then(|| register.base_register())
+        };
+        let full_or_none = |register: Option<Register>| match register {
+            Some(register) => full(register).map(Some),
+            None => Some(None),
+        };
+
+        Some(match (instr.mnemonic, instr.operands.as_slice()) {
+            (Mnemonic::Mov, [Operand::Register(dst), Operand::Register(src)]) => FastOp::MovReg {
+                dst: full(*dst)?,
+                src: full(*src)?,
+            },
+            (Mnemonic::Mov, [Operand::Register(dst), Operand::Immediate32(value)]) => {
+                FastOp::MovImm {
+                    dst: full(*dst)?,
+                    value: *value,
+                }
+            }
+            (Mnemonic::Lea, [Operand::Register(dst), Operand::Memory(mem)]) => FastOp::Lea {
+                dst: full(*dst)?,
+                base: full_or_none(mem.base)?,
+                index: full_or_none(mem.index)?,
+                scale: mem.scale,
+                displacement: mem.displacement as u32,
+            },
+            (Mnemonic::Jcc(condition), [Operand::Immediate32(target)]) => FastOp::Jcc {
+                condition,
+                target: *target,
+            },
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct CachedInstr {
+    instr: Instr,
+    op: FastOp,
+}
+
+impl CachedInstr {
+    fn new(instr: Instr) -> Self {
+        Self {
+            op: FastOp::new(&instr),
+            instr,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    /// Every miss is a decoding
+    pub misses: u64,
+    /// Executed without `codegen_instr`
+    pub fast_instructions: u64,
+}
+
+/// The decoded blocks of `Interpreter::run_cached`, by the address of the first instruction
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    blocks: HashMap<u32, Rc<[CachedInstr]>>,
+    stats: BlockCacheStats,
+}
+
+impl BlockCache {
+    pub fn stats(&self) -> BlockCacheStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Forgets all the blocks, for when the code in the memory changes
+    pub fn invalidate(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+/// Registers & flags that differ between the two contexts as a JSON object:
+/// `{"eax":[1,2],"flags":{"ZF":[false,true]},"eip":[4096,4101]}`
+pub fn context_diff_json(before: &CpuContext, after: &CpuContext) -> String {
+    let mut res = String::from("{");
+    let mut sep = "";
+
+    for reg in FullSizeGeneralPurposeRegister::iter() {
+        let (old, new) = (before.get_gp_reg(reg), after.get_gp_reg(reg));
+        if old != new {
+            let name = format!("{:?}", reg).to_ascii_lowercase();
+            write!(res, "{}\"{}\":[{},{}]", sep, name, old, new).unwrap();
+            sep = ",";
+        }
+    }
+
+    let flags: Vec<_> = Flag::iter()
+        .filter(|&f| before.get_flag(f) != after.get_flag(f))
+        .collect();
+    if !flags.is_empty() {
+        write!(res, "{}\"flags\":{{", sep).unwrap();
+        for (i, flag) in flags.iter().enumerate() {
+            let name = match flag {
+                Flag::Carry => "CF",
+                Flag::Parity => "PF",
+                Flag::AuxiliaryCarry => "AF",
+                Flag::Zero => "ZF",
+                Flag::Sign => "SF",
+                Flag::Overflow => "OF",
+                Flag::Direction => "DF",
+                Flag::Id => "ID",
+            };
+            let comma = if i == 0 { "" } else { "," };
+            write!(
+                res,
+                "{}\"{}\":[{},{}]",
+                comma,
+                name,
+                before.get_flag(*flag),
+                after.get_flag(*flag)
+            )
+            .unwrap();
+        }
+        res.push('}');
+        sep = ",";
+    }
+
+    if before.eip != after.eip {
+        write!(res, "{}\"eip\":[{},{}]", sep, before.eip, after.eip).unwrap();
+    }
+
+    res.push('}');
+    res
+}
+
+impl<H: RuntimeHandler> Builder for Interpreter<H> {
+    type IntValue = InterpValue;
+    type BoolValue = InterpBool;
+
+    fn make_int_value(&self, ty: IntType, value: u64, sign_extend: bool) -> Self::IntValue {
+        // this matches what LLVM's const_int does: only the low bits are used anyway
+        let _ = sign_extend;
+        InterpValue::new(ty, value)
+    }
+
+    fn make_true(&self) -> Self::BoolValue {
+        InterpBool(true)
+    }
+
+    fn make_false(&self) -> Self::BoolValue {
+        InterpBool(false)
+    }
+
+    fn load_register(&mut self, register: Register) -> Self::IntValue {
+        let mut val = self.context.get_gp_reg(register.base_register()) as u64;
+        if register.is_hi_reg() {
+            val >>= 8;
+        }
+        InterpValue::new(register.size(), val)
+    }
+
+    fn store_register(&mut self, register: Register, value: Self::IntValue) {
+        assert_eq!(register.size(), value.ty);
+
+        let base = register.base_register();
+        let shift = if register.is_hi_reg() { 8 } else { 0 };
+        let mask = (mask(register.size()) as u32) << shift;
+
+        let old = self.context.get_gp_reg(base);
+        let new = (old & !mask) | ((value.bits as u32) << shift);
+        self.context.set_gp_reg(base, new);
+    }
+
+    fn load_flag(&mut self, flag: Flag) -> Self::BoolValue {
+        if self.context.flag_storage() == FlagStorage::Bytes
+            && self.context.flags[flag as usize] == FLAG_UNDEFINED
+        {
+            let source = self.context.flag_sources[flag as usize];
+            self.handler.undefined_flag(&mut self.context, flag, source);
+            return InterpBool(false);
+        }
+        InterpBool(self.context.get_flag(flag))
+    }
+
+    fn store_flag(&mut self, flag: Flag, value: Self::BoolValue) {
+        if !self.dead_flags.contains(flag.into()) {
+            self.context.set_flag(flag, value.0)
+        }
+    }
+
+    fn load_packed_flags(&mut self) -> Self::IntValue {
+        debug_assert_eq!(self.context.flag_storage(), FlagStorage::Packed);
+        InterpValue::new(IntType::I32, self.context.eflags as u64)
+    }
+
+    fn store_packed_flags(&mut self, value: Self::IntValue) {
+        debug_assert_eq!(self.context.flag_storage(), FlagStorage::Packed);
+        self.context.eflags = value.bits as u32;
+    }
+
+    fn load_interrupt_flag(&mut self) -> Self::BoolValue {
+        InterpBool(self.context.interrupt_flag != 0)
+    }
+
+    fn store_interrupt_flag(&mut self, value: Self::BoolValue) {
+        self.context.interrupt_flag = value.0 as u32;
+    }
+
+    fn load_alignment_check_flag(&mut self) -> Self::BoolValue {
+        InterpBool(self.context.alignment_check != 0)
+    }
+
+    fn store_alignment_check_flag(&mut self, value: Self::BoolValue) {
+        self.context.alignment_check = value.0 as u32;
+    }
+
+    fn load_memory(&mut self, size: IntType, address: Self::IntValue) -> Self::IntValue {
+        let address = address.bits as u32;
+        self.check_alignment(address, size);
+        if self.wraps_around(address, size) {
+            let bits = (0..size.byte_width() as u32).rev().fold(0, |bits, i| {
+                let byte_address = InterpValue::new(IntType::I32, address.wrapping_add(i) as u64);
+                (bits << 8) | self.load_memory(IntType::I8, byte_address).bits
+            });
+            return InterpValue::new(size, bits);
+        }
+        match self.access(address, size) {
+            Some(range) => {
+                let mut bytes = [0u8; 8];
+                bytes[..range.len()].copy_from_slice(&self.memory[range]);
+                InterpValue::new(size, u64::from_le_bytes(bytes))
+            }
+            None => InterpValue::new(size, 0),
+        }
+    }
+
+    fn store_memory(&mut self, address: Self::IntValue, value: Self::IntValue) {
+        let address = address.bits as u32;
+        self.check_alignment(address, value.ty);
+        // don't write anything after a fault
+        if self.fault.is_some() {
+            return;
+        }
+        if self.wraps_around(address, value.ty) {
+            // all of the bytes are checked before anything is written
+            let ranges: Option<Vec<_>> = (0..value.ty.byte_width() as u32)
+                .map(|i| self.access(address.wrapping_add(i), IntType::I8))
+                .collect();
+            for (range, byte) in ranges.into_iter().flatten().zip(value.bits.to_le_bytes()) {
+                self.memory[range][0] = byte;
+            }
+            return;
+        }
+        if let Some(range) = self.access(address, value.ty) {
+            let len = range.len();
+            self.memory[range].copy_from_slice(&value.bits.to_le_bytes()[..len]);
+        }
+    }
+
+    fn load_fpu_register(&mut self, index: Self::IntValue) -> Self::IntValue {
+        InterpValue::new(IntType::I64, self.context.fpu_regs[index.bits as usize])
+    }
+
+    fn store_fpu_register(&mut self, index: Self::IntValue, value: Self::IntValue) {
+        assert_eq!(value.ty, IntType::I64);
+        self.context.fpu_regs[index.bits as usize] = value.bits;
+    }
+
+    fn load_fpu_word(&mut self, word: FpuWord) -> Self::IntValue {
+        let val = match word {
+            FpuWord::Control => self.context.fpu_control,
+            FpuWord::Status => self.context.fpu_status,
+            FpuWord::Tag => self.context.fpu_tag,
+        };
+        InterpValue::new(IntType::I16, val as u64)
+    }
+
+    fn store_fpu_word(&mut self, word: FpuWord, value: Self::IntValue) {
+        assert_eq!(value.ty, IntType::I16);
+        let val = value.bits as u16;
+        match word {
+            FpuWord::Control => self.context.fpu_control = val,
+            FpuWord::Status => self.context.fpu_status = val,
+            FpuWord::Tag => self.context.fpu_tag = val,
+        }
+    }
+
+    fn load_xmm_half(&mut self, index: u8, high: bool) -> Self::IntValue {
+        InterpValue::new(
+            IntType::I64,
+            self.context.xmm_regs[2 * index as usize + high as usize],
+        )
+    }
+
+    fn store_xmm_half(&mut self, index: u8, high: bool, value: Self::IntValue) {
+        assert_eq!(value.ty, IntType::I64);
+        self.context.xmm_regs[2 * index as usize + high as usize] = value.bits;
+    }
+
+    fn load_mxcsr(&mut self) -> Self::IntValue {
+        InterpValue::new(IntType::I32, self.context.mxcsr as u64)
+    }
+
+    fn store_mxcsr(&mut self, value: Self::IntValue) {
+        assert_eq!(value.ty, IntType::I32);
+        self.context.mxcsr = value.bits as u32;
+    }
+
+    fn add(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        InterpValue::new(lhs.ty, lhs.bits.wrapping_add(rhs.bits))
+    }
+
+    fn int_neg(&mut self, val: Self::IntValue) -> Self::IntValue {
+        InterpValue::new(val.ty, val.bits.wrapping_neg())
+    }
+
+    fn sub(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        InterpValue::new(lhs.ty, lhs.bits.wrapping_sub(rhs.bits))
+    }
+
+    fn mul(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        InterpValue::new(lhs.ty, lhs.bits.wrapping_mul(rhs.bits))
+    }
+
+    fn int_not(&mut self, val: Self::IntValue) -> Self::IntValue {
+        InterpValue::new(val.ty, !val.bits)
+    }
+
+    fn int_or(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        InterpValue::new(lhs.ty, lhs.bits | rhs.bits)
+    }
+
+    fn int_and(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        InterpValue::new(lhs.ty, lhs.bits & rhs.bits)
+    }
+
+    fn int_xor(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        InterpValue::new(lhs.ty, lhs.bits ^ rhs.bits)
+    }
+
+    // LLVM says the result is poison when shifting by the bit width or more
+    // we just do the "natural" thing
+
+    fn shl(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        let bits = if rhs.bits >= 64 {
+            0
+        } else {
+            lhs.bits << rhs.bits
+        };
+        InterpValue::new(lhs.ty, bits)
+    }
+
+    fn lshr(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        let bits = if rhs.bits >= 64 {
+            0
+        } else {
+            lhs.bits >> rhs.bits
+        };
+        InterpValue::new(lhs.ty, bits)
+    }
+
+    fn ashr(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        let bits = lhs.signed() >> rhs.bits.min(63);
+        InterpValue::new(lhs.ty, bits as u64)
+    }
+
+    fn rotl(&mut self, val: Self::IntValue, amount: Self::IntValue) -> Self::IntValue {
+        let width = val.ty.bit_width() as u64;
+        let amount = amount.bits % width;
+        let bits = if amount == 0 {
+            val.bits
+        } else {
+            val.bits << amount | val.bits >> (width - amount)
+        };
+        InterpValue::new(val.ty, bits)
+    }
+
+    fn rotr(&mut self, val: Self::IntValue, amount: Self::IntValue) -> Self::IntValue {
+        let width = val.ty.bit_width() as u64;
+        let amount = (width - amount.bits % width) % width;
+        self.rotl(val, InterpValue::new(val.ty, amount))
+    }
+
+    fn cttz(&mut self, val: Self::IntValue) -> Self::IntValue {
+        let width = val.ty.bit_width() as u32;
+        InterpValue::new(val.ty, val.bits.trailing_zeros().min(width) as u64)
+    }
+
+    fn ctlz(&mut self, val: Self::IntValue) -> Self::IntValue {
+        let width = val.ty.bit_width() as u32;
+        InterpValue::new(val.ty, (val.bits.leading_zeros() - (64 - width)) as u64)
+    }
+
+    fn bswap(&mut self, val: Self::IntValue) -> Self::IntValue {
+        let width = val.ty.bit_width() as u32;
+        InterpValue::new(val.ty, val.bits.swap_bytes() >> (64 - width))
+    }
+
+    fn udiv(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        match lhs.bits.checked_div(rhs.bits) {
+            Some(r) => InterpValue::new(lhs.ty, r),
+            None => {
+                self.set_fault(InterpFault::DivideError);
+                InterpValue::new(lhs.ty, 0)
+            }
+        }
+    }
+
+    fn sdiv(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
+        let (l, r) = (lhs.signed(), rhs.signed());
+        // the quotient has to fit in the type, not just in i64
+        let min = -(1i128 << (lhs.ty.bit_width() - 1));
+        let max = (1i128 << (lhs.ty.bit_width() - 1)) - 1;
+        match (l as i128).checked_div(r as i128) {
+            Some(q) if (min..=max).contains(&q) => InterpValue::new(lhs.ty, q as u64),
+            _ => {
+                self.set_fault(InterpFault::DivideError);
+                InterpValue::new(lhs.ty, 0)
+            }
+        }
+    }
+
+    fn extract_bit(&mut self, val: Self::IntValue, bit: Self::IntValue) -> Self::BoolValue {
+        InterpBool((val.bits >> bit.bits) & 1 != 0)
+    }
+
+    fn bool_not(&mut self, val: Self::BoolValue) -> Self::BoolValue {
+        InterpBool(!val.0)
+    }
+
+    fn bool_or(&mut self, lhs: Self::BoolValue, rhs: Self::BoolValue) -> Self::BoolValue {
+        InterpBool(lhs.0 || rhs.0)
+    }
+
+    fn bool_and(&mut self, lhs: Self::BoolValue, rhs: Self::BoolValue) -> Self::BoolValue {
+        InterpBool(lhs.0 && rhs.0)
+    }
+
+    fn bool_xor(&mut self, lhs: Self::BoolValue, rhs: Self::BoolValue) -> Self::BoolValue {
+        InterpBool(lhs.0 != rhs.0)
+    }
+
+    fn uadd_overflow(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::BoolValue {
+        InterpBool(flags::add(lhs.bits, rhs.bits, false, lhs.ty).cf)
+    }
+
+    fn sadd_overflow(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::BoolValue {
+        InterpBool(flags::add(lhs.bits, rhs.bits, false, lhs.ty).of)
+    }
+
+    fn usub_overflow(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::BoolValue {
+        InterpBool(flags::sub(lhs.bits, rhs.bits, false, lhs.ty).cf)
+    }
+
+    fn ssub_overflow(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::BoolValue {
+        InterpBool(flags::sub(lhs.bits, rhs.bits, false, lhs.ty).of)
+    }
+
+    fn float_sqrt(&mut self, val: Self::IntValue) -> Self::IntValue {
+        match val.ty {
+            IntType::I32 => InterpValue::new(
+                IntType::I32,
+                f32::from_bits(val.bits as u32).sqrt().to_bits() as u64,
+            ),
+            IntType::I64 => {
+                InterpValue::new(IntType::I64, f64::from_bits(val.bits).sqrt().to_bits())
+            }
+            ty => panic!("No floating point type of size {:?}", ty),
+        }
+    }
+
+    fn float_round(&mut self, val: Self::IntValue, mode: RoundingMode) -> Self::IntValue {
+        let round = |v: f64| match mode {
+            RoundingMode::NearestEven => v.round_ties_even(),
+            RoundingMode::Down => v.floor(),
+            RoundingMode::Up => v.ceil(),
+            RoundingMode::Zero => v.trunc(),
+        };
+        match val.ty {
+            // every float is exactly representable as a double, and so is the rounded one
+            IntType::I32 => {
+                let v = round(f32::from_bits(val.bits as u32) as f64);
+                InterpValue::new(IntType::I32, (v as f32).to_bits() as u64)
+            }
+            IntType::I64 => {
+                InterpValue::new(IntType::I64, round(f64::from_bits(val.bits)).to_bits())
+            }
+            ty => panic!("No floating point type of size {:?}", ty),
+        }
+    }
+
+    fn float_extend(&mut self, val: Self::IntValue) -> Self::IntValue {
+        assert_eq!(val.ty, IntType::I32);
+        let v = f32::from_bits(val.bits as u32) as f64;
+        InterpValue::new(IntType::I64, v.to_bits())
+    }
+
+    fn float_truncate(&mut self, val: Self::IntValue) -> Self::IntValue {
+        assert_eq!(val.ty, IntType::I64);
+        let v = f64::from_bits(val.bits) as f32;
+        InterpValue::new(IntType::I32, v.to_bits() as u64)
+    }
+
+    fn float_to_int(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue {
+        let v = match val.ty {
+            IntType::I32 => f32::from_bits(val.bits as u32) as f64,
+            IntType::I64 => f64::from_bits(val.bits),
+            ty => panic!("No floating point type of size {:?}", ty),
+        };
+        InterpValue::new(to, v as i64 as u64)
+    }
+
+    fn int_to_float(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue {
+        match to {
+            IntType::I32 => InterpValue::new(to, (val.signed() as f32).to_bits() as u64),
+            IntType::I64 => InterpValue::new(to, (val.signed() as f64).to_bits()),
+            ty => panic!("No floating point type of size {:?}", ty),
+        }
+    }
+
+    fn fcmp(
+        &mut self,
+        cmp: FloatComparisonType,
+        lhs: Self::IntValue,
+        rhs: Self::IntValue,
+    ) -> Self::BoolValue {
+        assert_eq!(lhs.ty, rhs.ty);
+        // comparing floats as doubles gives the same answers
+        let as_double = |v: InterpValue| match v.ty {
+            IntType::I32 => f32::from_bits(v.bits as u32) as f64,
+            IntType::I64 => f64::from_bits(v.bits),
+            ty => panic!("No floating point type of size {:?}", ty),
+        };
+        let (lhs, rhs) = (as_double(lhs), as_double(rhs));
+        InterpBool(match cmp {
+            FloatComparisonType::Equal => lhs == rhs,
+            FloatComparisonType::Less => lhs < rhs,
+            FloatComparisonType::Greater => lhs > rhs,
+            FloatComparisonType::Unordered => lhs.is_nan() || rhs.is_nan(),
+        })
+    }
+
+    fn zext(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue {
+        InterpValue::new(to, val.bits)
+    }
+
+    fn sext(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue {
+        InterpValue::new(to, val.signed() as u64)
+    }
+
+    fn trunc(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue {
+        InterpValue::new(to, val.bits)
+    }
+
+    fn icmp(
+        &mut self,
+        cmp: ComparisonType,
+        lhs: Self::IntValue,
+        rhs: Self::IntValue,
+    ) -> Self::BoolValue {
+        use ComparisonType::*;
+        let (u1, u2) = (lhs.bits, rhs.bits);
+        let (s1, s2) = (lhs.signed(), rhs.signed());
+        InterpBool(match cmp {
+            Equal => u1 == u2,
+            NotEqual => u1 != u2,
+            UnsignedGreater => u1 > u2,
+            UnsignedGreaterOrEqual => u1 >= u2,
+            UnsignedLess => u1 < u2,
+            UnsignedLessOrEqual => u1 <= u2,
+            SignedGreater => s1 > s2,
+            SignedGreaterOrEqual => s1 >= s2,
+            SignedLess => s1 < s2,
+            SignedLessOrEqual => s1 <= s2,
+        })
+    }
+
+    fn direct_call(&mut self, target: u32, next_eip: u32) {
+        // the return address is already pushed to the guest stack, but ret doesn't look at it (yet)
+        self.call_stack.push(next_eip);
+        self.call_target = Some(target);
+    }
+
+    fn indirect_call(&mut self, target: Self::IntValue, next_eip: u32) {
+        self.direct_call(target.bits as u32, next_eip)
+    }
+
+    fn select(
+        &mut self,
+        cond: Self::BoolValue,
+        iftrue: Self::IntValue,
+        iffalse: Self::IntValue,
+    ) -> Self::IntValue {
+        if cond.0 {
+            iftrue
+        } else {
+            iffalse
+        }
+    }
+
+    fn ifelse<T, F>(&mut self, cond: Self::BoolValue, iftrue: T, iffalse: F)
+    where
+        T: FnOnce(&mut Self),
+        F: FnOnce(&mut Self),
+    {
+        if cond.0 {
+            iftrue(self)
+        } else {
+            iffalse(self)
+        }
+    }
+
+    fn trap(&mut self) {
+        self.set_fault(InterpFault::Trap)
+    }
+
+    fn begin_instruction(&mut self, instr: &Instr) {
+        self.instruction_bounds = (instr.ip, instr.next_ip());
+    }
+
+    fn instruction_start(&self) -> u32 {
+        self.instruction_bounds.0
+    }
+
+    fn instruction_end(&self) -> u32 {
+        self.instruction_bounds.1
+    }
+
+    fn segmentation(&self) -> &SegmentationPolicy {
+        &self.segmentation
+    }
+
+    fn code_mode(&self) -> CodeMode {
+        self.code_mode
+    }
+
+    fn strict_alignment(&self) -> bool {
+        self.strict_alignment
+    }
+
+    fn alignment_checks(&self) -> bool {
+        self.alignment_checks
+    }
+
+    fn undefined_flags(&self) -> UndefinedFlagsPolicy {
+        self.undefined_flags
+    }
+
+    /// Whatever the context holds
+    fn flag_storage(&self) -> FlagStorage {
+        self.context.flag_storage()
+    }
+
+    fn interrupt_vectors(&self) -> &InterruptVectorTable {
+        &self.interrupt_vectors
+    }
+
+    fn system_registers(&self) -> &SystemRegisterProfile {
+        &self.system_registers
+    }
+
+    fn poison_flag(&mut self, flag: Flag) {
+        if !self.dead_flags.contains(flag.into()) {
+            assert_eq!(
+                self.context.flag_storage(),
+                FlagStorage::Bytes,
+                "no room for FLAG_UNDEFINED in the packed flags"
+            );
+            self.context.flags[flag as usize] = FLAG_UNDEFINED;
+            self.context.flag_sources[flag as usize] = self.context.eip;
+        }
+    }
+
+    fn raise_fault(&mut self, fault: GuestFault, address: Self::IntValue) {
+        match fault {
+            // the same thing udiv & sdiv report
+            GuestFault::DivideError => self.set_fault(InterpFault::DivideError),
+            fault => self.set_fault(InterpFault::Guest {
+                fault,
+                address: address.bits as u32,
+            }),
+        }
+    }
+
+    fn raise_page_fault(&mut self, address: Self::IntValue, error_code: u32) {
+        // the memory is flat & fully accessible, the only page fault is running off its end while fetching
+        debug_assert_eq!(error_code, PF_INSTRUCTION_FETCH);
+        self.raise_fault(GuestFault::PageFault, address)
+    }
+
+    fn interrupt(&mut self, vector: u8, next_eip: u32) {
+        self.context.eip = next_eip;
+        self.handler.interrupt(&mut self.context, vector);
+    }
+
+    fn fast_syscall(&mut self, next_eip: u32) {
+        self.context.eip = next_eip;
+        self.handler.fast_syscall(&mut self.context);
+        if self.context.eip != next_eip {
+            self.call_target = Some(self.context.eip);
+        }
+    }
+
+    fn port_in(&mut self, port: Self::IntValue, size: IntType) -> Self::IntValue {
+        let val = self
+            .handler
+            .port_in(&mut self.context, port.bits as u16, size);
+        InterpValue::new(size, val as u64)
+    }
+
+    fn port_out(&mut self, port: Self::IntValue, value: Self::IntValue) {
+        self.handler.port_out(
+            &mut self.context,
+            port.bits as u16,
+            value.ty,
+            value.bits as u32,
+        )
+    }
+
+    fn control_register_write(&mut self, register: u8, value: Self::IntValue) {
+        self.handler
+            .control_register_write(&mut self.context, register, value.bits as u32)
+    }
+
+    fn cache_flush(&mut self, address: Self::IntValue) {
+        // the generated code never gets here if the probe before it faulted
+        if self.fault.is_none() {
+            self.handler
+                .cache_flush(&mut self.context, address.bits as u32);
+        }
+    }
+
+    fn msr_write(&mut self, index: Self::IntValue, value: Self::IntValue) {
+        self.handler
+            .msr_write(&mut self.context, index.bits as u32, value.bits)
+    }
+
+    fn repeat_until<B>(&mut self, body: B)
+    where
+        B: Fn(&mut Self) -> Self::BoolValue,
+    {
+        // same as the LLVM one: repeat while the body says so
+        // (and stop if something went wrong, otherwise we might spin forever)
+        while body(self).0 && self.fault.is_none() && self.context.exit == EXIT_NONE {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{context_diff_json, InterpFault, Interpreter, StepResult};
+    use crate::assemble_x86;
+    use crate::backend::Builder;
+    use crate::handler::InterruptVectorTable;
+    use crate::handler::{GuestFault, InstructionBytes, NullHandler, RuntimeHandler};
+    use crate::ir::{
+        CodeMode, Condition, Decoder, Instr, Mnemonic, Prefixes, UnsupportedInstructionPolicy,
+    };
+    use crate::memory_image::Protection;
+    use crate::segmentation::{SegmentDescriptor, SegmentationMode, SegmentationPolicy};
+    use crate::system_registers::{
+        ControlRegisterWrites, SystemRegisterProfile, UnknownMsrReads, CR4_PCE, MSR_PLATFORM_INFO,
+    };
+    use crate::types::{
+        CpuContext, Flag, FlagStorage, FullSizeGeneralPurposeRegister::*, IntType, MemoryOperand,
+        Operand, Register, SegmentRegister, UndefinedFlagsPolicy, EXIT_HOST_REQUEST,
+    };
+    use strum::IntoEnumIterator;
+
+    const CODE_ADDR: u32 = 0x1000;
+    const STACK_TOP: u32 = 0x8000;
+
+    /// The flags are kept where `FlagStorage::TEST_ENV` says, to run the tests with both storages
+    fn interpreter<H: RuntimeHandler>(code: &[u8], handler: H) -> Interpreter<H> {
+        let mut memory = vec![0; 0x8000];
+        memory[CODE_ADDR as usize..][..code.len()].copy_from_slice(code);
+
+        let mut interp = Interpreter::new(memory, handler);
+        interp.context.set_flag_storage(FlagStorage::from_env());
+        interp.context.eip = CODE_ADDR;
+        interp.context.set_gp_reg(ESP, STACK_TOP - 4);
+        interp
+    }
+
+    #[test_log::test]
+    fn straight_line() {
+        let code = assemble_x86!(
+            ; mov eax, 40
+            ; mov ebx, 2
+            ; add eax, ebx
+            ; mov [0x100], eax
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(EAX), 42);
+        assert_eq!(interp.memory[0x100..0x104], [42, 0, 0, 0]);
+        assert!(!interp.context.get_flag(Flag::Zero));
+    }
+
+    #[test_log::test]
+    fn partial_registers() {
+        let code = assemble_x86!(
+            ; mov eax, 0x11223344
+            ; mov ah, -0x56
+            ; mov bx, 0x5566
+            ; mov ebx, eax
+            ; mov bx, 0x5566
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x1122aa44);
+        assert_eq!(interp.context.get_gp_reg(EBX), 0x11225566);
+    }
+
+    #[test_log::test]
+    fn loop_and_call() {
+        let code = assemble_x86!(
+            ; mov ecx, 5
+            ; xor eax, eax
+            ; ->again:
+            ; call ->add_two
+            ; sub ecx, 1
+            ; jne ->again
+            ; ret
+            ; ->add_two:
+            ; add eax, 2
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        assert_eq!(interp.run(1000), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(EAX), 10);
+        assert_eq!(interp.context.get_gp_reg(ECX), 0);
+        assert!(interp.context.get_flag(Flag::Zero));
+        // the outermost ret has popped the return address of our caller
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP);
+    }
+
+    /// A loop with a bit of everything: the fast forms, memory, flags, a call & a jump into the middle of a block
+    fn hot_loop() -> Vec<u8> {
+        assemble_x86!(
+            ; mov ecx, 50
+            ; xor eax, eax
+            ; mov esi, 0x2000
+            ; ->again:
+            ; mov edx, ecx
+            ; lea ebx, [eax + edx * 4 + 3]
+            ; mov [esi + ecx * 4], ebx
+            ; call ->mix
+            ; cmp ecx, 25
+            ; jbe ->skip
+            ; add eax, [esi + ecx * 4]
+            ; ->skip:
+            ; lea eax, [eax + 1]
+            ; dec ecx
+            ; jnz ->again
+            ; mov edi, eax
+            ; ret
+            ; ->mix:
+            ; xor eax, ebx
+            ; mov ebp, 7
+            ; ret
+        )
+    }
+
+    #[test_log::test]
+    fn cached_run_matches_stepping() {
+        let code = hot_loop();
+        let mut stepping = interpreter(&code, NullHandler);
+        assert_eq!(stepping.run(100000), StepResult::Returned);
+
+        let mut cached = interpreter(&code, NullHandler);
+        assert_eq!(cached.run_cached(100000), StepResult::Returned);
+        assert_eq!(cached.context, stepping.context);
+        assert_eq!(cached.memory, stepping.memory);
+
+        let stats = cached.block_cache.stats();
+        assert!(stats.hits > 100, "{:?}", stats);
+        assert!(stats.misses <= 10, "{:?}", stats);
+        assert!(stats.fast_instructions > 200, "{:?}", stats);
+
+        // stopping after the same number of instructions, wherever it's in the block
+        for max_steps in [0, 1, 3, 7, 8, 20, 33] {
+            let mut stepping = interpreter(&code, NullHandler);
+            let mut cached = interpreter(&code, NullHandler);
+            assert_eq!(stepping.run(max_steps), StepResult::Continue);
+            assert_eq!(cached.run_cached(max_steps), StepResult::Continue);
+            assert_eq!(
+                cached.context, stepping.context,
+                "after {} steps",
+                max_steps
+            );
+        }
+    }
+
+    #[test_log::test]
+    fn cached_block_invalidation() {
+        let code = assemble_x86!(
+            ; mov eax, 1
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        assert_eq!(interp.run_cached(10), StepResult::Returned);
+        assert_eq!(interp.block_cache.len(), 1);
+
+        // mov eax, 2
+        interp.memory[CODE_ADDR as usize + 1] = 2;
+        let rerun = |interp: &mut Interpreter<NullHandler>| {
+            interp.context.eip = CODE_ADDR;
+            interp.context.set_gp_reg(ESP, STACK_TOP - 4);
+            assert_eq!(interp.run_cached(10), StepResult::Returned);
+            interp.context.get_gp_reg(EAX)
+        };
+        // the cached block is still the old one
+        assert_eq!(rerun(&mut interp), 1);
+        interp.block_cache.invalidate();
+        assert_eq!(rerun(&mut interp), 2);
+        assert_eq!(interp.block_cache.stats().misses, 2);
+    }
+
+    #[test_log::test]
+    fn cached_faults() {
+        // the fault leaves eip at the faulting instruction, in the middle of the block
+        let code = assemble_x86!(
+            ; mov eax, 0x10000
+            ; mov ebx, 5
+            ; mov ecx, [eax]
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        assert!(matches!(
+            interp.run_cached(10),
+            StepResult::Fault(InterpFault::MemoryOutOfBounds { .. })
+        ));
+        assert_eq!(interp.context.eip, CODE_ADDR + 10);
+        assert_eq!(interp.context.get_gp_reg(EBX), 5);
+
+        // nothing to decode at all, the same #PF as without the cache
+        interp.context.eip = 0x10000;
+        assert_eq!(
+            interp.run_cached(10),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::PageFault,
+                address: 0x10000
+            })
+        );
+    }
+
+    #[test_log::test]
+    fn faults() {
+        let code = assemble_x86!(
+            ; mov eax, 1
+            ; mov [0x7fffffff], eax
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        assert_eq!(interp.step(), StepResult::Continue);
+        let before = interp.context.clone();
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::MemoryOutOfBounds {
+                address: 0x7fffffff,
+                size: 4
+            })
+        );
+        // nothing changed, we are still at the faulting instruction
+        assert_eq!(interp.context, before);
+
+        let code = assemble_x86!(
+            ; xor edx, edx
+            ; xor ecx, ecx
+            ; div ecx
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        assert_eq!(interp.run(10), StepResult::Fault(InterpFault::DivideError));
+    }
+
+    #[test_log::test]
+    fn address_wraparound() {
+        let code = assemble_x86!(
+            ; mov eax, [-2]
+            ; mov bx, [-1]
+            ; mov cl, [-1]
+            ; mov edx, 0x44332211
+            ; mov [-3], edx
+            ; mov WORD [-1], 0x5566
+            ; mov BYTE [-1], 0x77
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        // the whole address space (zeroed by the allocator, so only the touched pages are actually allocated)
+        let mut memory = vec![0; 1 << 32];
+        memory[..interp.memory.len()].copy_from_slice(&interp.memory);
+        interp.memory = memory;
+        interp.memory[0xfffffffc..].copy_from_slice(&[0xfc, 0xfd, 0xfe, 0xff]);
+        interp.memory[..4].copy_from_slice(&[0x00, 0x01, 0x02, 0x03]);
+
+        // by default an access crossing the top faults
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::MemoryOutOfBounds {
+                address: 0xfffffffe,
+                size: 4
+            })
+        );
+
+        interp.address_wraparound = true;
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x0100fffe);
+        assert_eq!(interp.context.get_gp_reg(EBX) & 0xffff, 0x00ff);
+        assert_eq!(interp.context.get_gp_reg(ECX) & 0xff, 0xff);
+        assert_eq!(interp.memory[0xfffffffc..], [0xfc, 0x11, 0x22, 0x77]);
+        assert_eq!(interp.memory[..4], [0x55, 0x01, 0x02, 0x03]);
+
+        // with a smaller memory the top half faults, and nothing is written to the bottom one
+        let code = assemble_x86!(
+            ; mov [-2], eax
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.address_wraparound = true;
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::MemoryOutOfBounds {
+                address: 0xfffffffe,
+                size: 1
+            })
+        );
+        assert_eq!(interp.memory[..2], [0, 0]);
+    }
+
+    #[test_log::test]
+    fn guest_interrupts() {
+        let code = assemble_x86!(
+            ; cmp eax, eax
+            ; stc
+            ; int 0x21
+            ; mov ebx, [0x3000]
+            ; int 0x21
+            ; ret
+        );
+        let handler = assemble_x86!(
+            ; inc DWORD [0x3000]
+            ; mov eax, [esp + 8]
+            ; mov [0x3004], eax
+            ; clc
+            ; iretd
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.memory[0x2000..][..handler.len()].copy_from_slice(&handler);
+        let mut table = InterruptVectorTable::new();
+        table.set_handler(0x21, 0x2000);
+        table.clear_if = true;
+        interp.interrupt_vectors = table;
+
+        // cmp, stc, int
+        assert_eq!(interp.run(3), StepResult::Continue);
+        assert_eq!(interp.context.eip, 0x2000);
+        assert_eq!(interp.context.interrupt_flag, 0);
+        // EIP, the CS placeholder & EFLAGS
+        let esp = interp.context.get_gp_reg(ESP) as usize;
+        assert_eq!(esp, STACK_TOP as usize - 4 - 12);
+        let frame: Vec<u32> = interp.memory[esp..esp + 12]
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()))
+            .collect();
+        assert_eq!(frame, vec![CODE_ADDR + 5, 0, 0x247]);
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.memory[0x3000..0x3004], 2u32.to_le_bytes());
+        // the first iretd came back right after its int
+        assert_eq!(interp.context.get_gp_reg(EBX), 1);
+        // CF=1 PF=1 ZF=1 IF=1 (`cmp eax, eax` is a zero idiom), as seen by the second handler
+        assert_eq!(interp.memory[0x3004..0x3008], 0x247u32.to_le_bytes());
+        // the handler's inc & clc are undone
+        assert!(interp.context.get_flag(Flag::Carry));
+        assert!(interp.context.get_flag(Flag::Zero));
+        assert_eq!(interp.context.interrupt_flag, 1);
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP);
+
+        // without the table it's the host's business
+        let mut interp = interpreter(&code, NullHandler);
+        assert_eq!(interp.run(100), StepResult::HostRequest);
+        assert_eq!(interp.context.eip, CODE_ADDR + 5);
+    }
+
+    #[test_log::test]
+    fn iretd_frame() {
+        let code = assemble_x86!(
+            ; clc
+            // everything but IF, the reserved bits included
+            ; push DWORD -0x201
+            ; push DWORD 0
+            ; push DWORD 0x2000
+            ; iretd
+            ; mov eax, 1
+            ; ret
+        );
+        let marker = assemble_x86!(
+            ; mov eax, 2
+            ; int 0x21
+            ; ret
+        );
+        let handler = assemble_x86!(
+            ; mov ebx, [esp + 8]
+            ; mov [0x3000], ebx
+            ; iretd
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.memory[0x2000..][..marker.len()].copy_from_slice(&marker);
+        interp.memory[0x2100..][..handler.len()].copy_from_slice(&handler);
+        let mut table = InterruptVectorTable::new();
+        table.set_handler(0x21, 0x2100);
+        interp.interrupt_vectors = table;
+
+        // clc, the frame, iretd
+        assert_eq!(interp.run(5), StepResult::Continue);
+        assert_eq!(interp.context.eip, 0x2000);
+        assert!(interp.context.get_flag(Flag::Carry));
+        assert!(interp.context.get_flag(Flag::Direction));
+        assert!(interp.context.get_flag(Flag::Overflow));
+        assert_eq!(interp.context.interrupt_flag, 0);
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP - 4);
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(EAX), 2);
+        // only the flags we keep made it through (AC included), and bit 1 is back
+        assert_eq!(interp.memory[0x3000..0x3004], 0x240cc7u32.to_le_bytes());
+    }
+
+    #[test_log::test]
+    fn bits16_boot_sector() {
+        const LOAD_ADDR: u32 = 0x7c00;
+        const HANDLER_ADDR: u32 = 0x7c3a;
+        let code = include_bytes!("../tests/fixtures/boot_sector.bin");
+        let mut memory = vec![0; 0x10000];
+        memory[LOAD_ADDR as usize..][..code.len()].copy_from_slice(code);
+
+        let mut interp = Interpreter::new(memory, NullHandler);
+        interp.context.set_flag_storage(FlagStorage::from_env());
+        interp.code_mode = CodeMode::Bits16;
+        interp
+            .segmentation
+            .set_segment(SegmentRegister::DS, SegmentDescriptor::real_mode(0x0800));
+        interp
+            .segmentation
+            .set_segment(SegmentRegister::ES, SegmentDescriptor::real_mode(0x0900));
+        let mut table = InterruptVectorTable::new();
+        table.set_handler(0x40, HANDLER_ADDR);
+        interp.interrupt_vectors = table;
+        interp.context.eip = LOAD_ADDR;
+        interp.context.set_gp_reg(ESP, LOAD_ADDR);
+        interp.context.set_gp_reg(EBX, 0xaaaa_0000);
+        interp.context.set_gp_reg(EDX, 0x5555_0000);
+
+        // the int 0x20 at the end goes to the host, which stops there
+        assert_eq!(interp.run(1000), StepResult::HostRequest);
+        assert_eq!(interp.context.eip, 0x7c2d);
+
+        // rep stosw to ES:0, the dword store & [bx + si] to DS
+        assert_eq!(interp.memory[0x9000..0x9010], [0x34, 0x12].repeat(8));
+        assert_eq!(interp.memory[0x9010..0x9012], [0, 0]);
+        assert_eq!(interp.memory[0x8010..0x8014], 0xdeadbeefu32.to_le_bytes());
+        assert_eq!(interp.memory[0x8022], 0x55);
+        // bx = 0x20 + 0xbeef in the call, + 1 in the interrupt handler.
+        // dx = 0xbeef through push & pop, + 4 + 3 + 2 + 1 in the loop
+        assert_eq!(interp.memory[0x8030..0x8034], [0x10, 0xbf, 0xf9, 0xbe]);
+
+        // only the low halves are written by the 16-bit instructions
+        let ctx = &interp.context;
+        assert_eq!(ctx.get_gp_reg(EAX), 0xdeadbeef);
+        assert_eq!(ctx.get_gp_reg(EBX), 0xaaaa_bf10);
+        assert_eq!(ctx.get_gp_reg(EDX), 0x5555_bef9);
+        assert_eq!(ctx.get_gp_reg(ECX), 0);
+        assert_eq!(ctx.get_gp_reg(EDI), 0x10);
+        // the 16-bit interrupt frame is popped (it's where the return address was): IP, CS & FLAGS with IF & ZF
+        assert_eq!(ctx.get_gp_reg(ESP), LOAD_ADDR);
+        assert_eq!(
+            interp.memory[0x7bfa..0x7c00],
+            [0x23, 0x7c, 0, 0, 0x42, 0x02]
+        );
+        assert_eq!(ctx.interrupt_flag, 1);
+    }
+
+    #[test_log::test]
+    fn bits16_stack_segment() {
+        const LOAD_ADDR: u32 = 0x7c00;
+        let code = include_bytes!("../tests/fixtures/stack16.bin");
+        let mut memory = vec![0; 0x20000];
+        memory[LOAD_ADDR as usize..][..code.len()].copy_from_slice(code);
+
+        let mut interp = Interpreter::new(memory, NullHandler);
+        interp.context.set_flag_storage(FlagStorage::from_env());
+        interp.code_mode = CodeMode::Bits16;
+        interp
+            .segmentation
+            .set_segment(SegmentRegister::SS, SegmentDescriptor::real_mode(0x1000));
+        interp
+            .segmentation
+            .set_segment(SegmentRegister::DS, SegmentDescriptor::real_mode(0x0700));
+        interp.context.eip = LOAD_ADDR;
+        interp.context.set_gp_reg(ESP, 0x1234_0000);
+
+        assert_eq!(interp.run(100), StepResult::HostRequest);
+        assert_eq!(interp.context.eip, 0x7c13);
+
+        // the push at SP = 0 went to SS:fffe, the flat address is untouched
+        assert_eq!(interp.memory[0x1fffe..0x20000], 0xbeefu16.to_le_bytes());
+        assert_eq!(interp.memory[0xfffe..0x10000], [0, 0]);
+        assert_eq!(interp.memory[0x7010..0x7012], 0xfffeu16.to_le_bytes());
+        // the return address is right under it
+        assert_eq!(interp.memory[0x1fffc..0x1fffe], 0x7c10u16.to_le_bytes());
+
+        let ctx = &interp.context;
+        assert_eq!(ctx.get_gp_reg(EBX) & 0xffff, 0xbeef);
+        assert_eq!(ctx.get_gp_reg(ECX) & 0xffff, 0xbeef);
+        assert_eq!(ctx.get_gp_reg(EDX) & 0xffff, 0x7c10);
+        // SP wrapped around both ways, the upper half of ESP stays
+        assert_eq!(ctx.get_gp_reg(ESP), 0x1234_0000);
+    }
+
+    #[test_log::test]
+    fn push_pop_flags() {
+        // pushfd & popfd are `pushf` & `popf` to the assembler
+        let code = assemble_x86!(
+            ; mov eax, 1
+            ; cmp eax, 2
+            ; pushf
+            ; mov ebx, [esp]
+            ; xor ecx, ecx
+            ; popf
+            // OF set & SF cleared in the image
+            ; pushfw
+            ; or WORD [esp], 0x800
+            ; and WORD [esp], -0x81
+            ; popfw
+            ; ret
+        );
+
+        for storage in [FlagStorage::Bytes, FlagStorage::Packed] {
+            let mut interp = interpreter(&code, NullHandler);
+            interp.context.set_flag_storage(storage);
+            assert_eq!(interp.run(100), StepResult::Returned);
+            // CF=1 SF=1 IF=1 & the reserved bit 1
+            assert_eq!(interp.context.get_gp_reg(EBX), 0x283);
+            assert!(interp.context.get_flag(Flag::Carry));
+            assert!(!interp.context.get_flag(Flag::Zero));
+            assert!(!interp.context.get_flag(Flag::Sign));
+            assert!(interp.context.get_flag(Flag::Overflow));
+            assert_eq!(interp.context.interrupt_flag, 1);
+            assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP);
+        }
+    }
+
+    #[test_log::test]
+    fn zero_idiom_pushfd() {
+        let code = assemble_x86!(
+            ; mov eax, 1
+            ; cmp eax, 2
+            ; xor eax, eax
+            ; pushf
+            ; pop ebx
+            ; cmp eax, 2
+            ; sub ecx, ecx
+            ; pushf
+            ; pop edx
+            ; ret
+        );
+
+        for storage in [FlagStorage::Bytes, FlagStorage::Packed] {
+            let mut interp = interpreter(&code, NullHandler);
+            interp.context.set_flag_storage(storage);
+            assert_eq!(interp.run(100), StepResult::Returned);
+            // ZF=1 PF=1 IF=1 & the reserved bit 1, CF & SF of the cmp are gone
+            assert_eq!(interp.context.get_gp_reg(EBX), 0x246);
+            assert_eq!(interp.context.get_gp_reg(EDX), 0x246);
+        }
+    }
+
+    #[test_log::test]
+    fn push_pop_all() {
+        let code = assemble_x86!(
+            ; mov eax, 1
+            ; mov ecx, 2
+            ; mov edx, 3
+            ; mov ebx, 4
+            ; mov ebp, 6
+            ; mov esi, 7
+            ; mov edi, 8
+            ; pushad
+            ; mov eax, [esp + 12]
+            ; mov [0x4000], eax
+            ; mov eax, [esp + 28]
+            ; mov [0x4004], eax
+            ; add DWORD [esp], 0x10
+            // the saved ESP is skipped
+            ; mov DWORD [esp + 12], 0
+            ; popad
+            // pushaw & popaw, the assembler doesn't have them. BX & the skipped SP this time
+            ; .bytes [0x66_u8, 0x60].iter().copied()
+            ; mov DWORD [esp + 6], 0x0055_0066
+            ; .bytes [0x66_u8, 0x61].iter().copied()
+            ; ret
+        );
+
+        let mut interp = interpreter(&code, NullHandler);
+        assert_eq!(interp.run(100), StepResult::Returned);
+        // ESP right before the pushad: the return address is still on the stack
+        assert_eq!(interp.memory[0x4000..0x4004], (STACK_TOP - 4).to_le_bytes());
+        assert_eq!(interp.memory[0x4004..0x4008], 1u32.to_le_bytes());
+        assert_eq!(interp.context.get_gp_reg(EAX), 1);
+        assert_eq!(interp.context.get_gp_reg(ECX), 2);
+        assert_eq!(interp.context.get_gp_reg(EDX), 3);
+        assert_eq!(interp.context.get_gp_reg(EBX), 0x55);
+        assert_eq!(interp.context.get_gp_reg(EBP), 6);
+        assert_eq!(interp.context.get_gp_reg(ESI), 7);
+        assert_eq!(interp.context.get_gp_reg(EDI), 0x18);
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP);
+    }
+
+    #[test_log::test]
+    fn packed_flag_storage() {
+        let code = assemble_x86!(
+            ; mov eax, -1
+            ; add eax, 1
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.context.set_flag_storage(FlagStorage::Packed);
+        assert_eq!(interp.run(100), StepResult::Returned);
+
+        // in their places in EFLAGS, and nothing in the bytes
+        let bits = |flags: &[Flag]| flags.iter().fold(0, |bits, f| bits | 1 << f.eflags_bit());
+        let set = bits(&[Flag::Carry, Flag::Zero]);
+        assert_eq!(interp.context.eflags & set, set);
+        assert_eq!(
+            interp.context.eflags & bits(&[Flag::Sign, Flag::Overflow]),
+            0
+        );
+        assert_eq!(interp.context.flags, [0; 8]);
+
+        // and back to the bytes with the same values
+        let packed = interp.context.clone();
+        interp.context.set_flag_storage(FlagStorage::Bytes);
+        assert_eq!(interp.context.eflags, 0);
+        for flag in Flag::iter() {
+            assert_eq!(
+                interp.context.get_flag(flag),
+                packed.get_flag(flag),
+                "{}",
+                flag
+            );
+        }
+    }
+
+    #[test_log::test]
+    fn fetch_past_the_end() {
+        let code = assemble_x86!(
+            ; nop
+            ; mov eax, 1
+        );
+        let mut interp = interpreter(&[], NullHandler);
+        // the mov is missing its last byte
+        let len = interp.memory.len();
+        let start = len - code.len() + 1;
+        interp.memory[start..].copy_from_slice(&code[..code.len() - 1]);
+        interp.context.eip = start as u32;
+
+        // the nop is fine
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::PageFault,
+                address: len as u32
+            })
+        );
+        assert_eq!(interp.context.eip, start as u32 + 1);
+
+        // a jump right past the end
+        interp.context.eip = len as u32;
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::PageFault,
+                address: len as u32
+            })
+        );
+    }
+
+    #[test_log::test]
+    fn get_eip() {
+        let code = assemble_x86!(
+            ; call ->next
+            ; ->next:
+            ; pop eax
+            ; mov ecx, eax
+            // ->target, 14 bytes after ->next
+            ; lea eax, [eax + 14]
+            ; jmp eax
+            ; mov ebx, 1
+            ; ret
+            ; ->target:
+            ; mov ebx, 2
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        // call, pop
+        assert_eq!(interp.run(2), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), CODE_ADDR + 5);
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP - 4);
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(ECX), CODE_ADDR + 5);
+        assert_eq!(interp.context.get_gp_reg(EAX), CODE_ADDR + 19);
+        assert_eq!(interp.context.get_gp_reg(EBX), 2);
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP);
+    }
+
+    #[test_log::test]
+    fn alignment_checks() {
+        let code = assemble_x86!(
+            ; mov eax, [0x3001]
+            ; mov [0x3002], ax
+            ; mov [0x3003], ax
+            ; mov al, [0x3001]
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.alignment_checks = true;
+
+        // EFLAGS.AC is clear
+        assert_eq!(interp.run(4), StepResult::Continue);
+
+        interp.context.alignment_check = 1;
+        interp.context.eip = CODE_ADDR;
+        interp.context.set_gp_reg(EAX, 0x1234);
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::AlignmentCheck,
+                address: 0x3001
+            })
+        );
+        assert_eq!(interp.context.eip, CODE_ADDR);
+
+        interp.context.eip = CODE_ADDR + 6;
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::AlignmentCheck,
+                address: 0x3003
+            })
+        );
+        assert_eq!(interp.context.eip, CODE_ADDR + 13);
+        // only the aligned store went through
+        assert_eq!(interp.memory[0x3000..0x3006], [0, 0, 0x34, 0x12, 0, 0]);
+        // bytes are always aligned
+        interp.context.eip = CODE_ADDR + 20;
+        assert_eq!(interp.step(), StepResult::Continue);
+
+        // the flag means nothing without the checks
+        interp.alignment_checks = false;
+        interp.context.eip = CODE_ADDR;
+        assert_eq!(interp.step(), StepResult::Continue);
+    }
+
+    fn data_segment_limited_to(limit: u32) -> SegmentationPolicy {
+        let mut policy = SegmentationPolicy::checked();
+        policy.set_segment(
+            SegmentRegister::DS,
+            SegmentDescriptor::new(0, limit, Protection::READ_WRITE),
+        );
+        policy
+    }
+
+    #[test_log::test]
+    fn segment_limits() {
+        let code = assemble_x86!(
+            ; mov eax, 1
+            ; mov [0x1ffc], eax
+            ; mov [0x1ffe], eax
+        );
+
+        let mut interp = interpreter(&code, NullHandler);
+        interp.segmentation = data_segment_limited_to(0x1fff);
+        assert_eq!(interp.run(2), StepResult::Continue);
+        assert_eq!(interp.memory[0x1ffc..0x2000], [1, 0, 0, 0]);
+
+        let before = interp.context.clone();
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::GeneralProtection,
+                address: 0x1ffe
+            })
+        );
+        assert_eq!(interp.context, before);
+        assert_eq!(interp.memory[0x1ffe..0x2002], [0, 0, 0, 0]);
+
+        // the same thing is fine without the checks
+        let mut interp = interpreter(&code, NullHandler);
+        assert_eq!(interp.run(3), StepResult::Continue);
+        assert_eq!(interp.memory[0x1ffe..0x2002], [1, 0, 0, 0]);
+    }
+
+    #[test_log::test]
+    fn segment_bases_and_rights() {
+        let code = assemble_x86!(
+            ; mov eax, [0x10]
+            ; mov [0x10], eax
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.memory[0x2010] = 0x42;
+
+        let mut policy = SegmentationPolicy::flat();
+        policy.set_segment(
+            SegmentRegister::DS,
+            SegmentDescriptor::new(0x2000, 0, Protection::READ),
+        );
+        interp.segmentation = policy;
+        assert_eq!(interp.run(2), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x42);
+
+        // read-only and the limit is 0 => both of the accesses fault when checked
+        interp.segmentation.mode = SegmentationMode::Checked;
+        interp.context.eip = CODE_ADDR;
+        assert!(matches!(
+            interp.step(),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::GeneralProtection,
+                ..
+            })
+        ));
+    }
+
+    #[test_log::test]
+    fn far_transfers() {
+        let far_jmp = |selector| {
+            Instr::new(
+                CODE_ADDR,
+                7,
+                Mnemonic::Jmp,
+                vec![Operand::FarBranch(selector, 0x100)],
+            )
+        };
+
+        // flat: only the offset matters
+        let mut interp = interpreter(&[], NullHandler);
+        assert_eq!(interp.execute(&far_jmp(0x23)), StepResult::Continue);
+        assert_eq!(interp.context.eip, 0x100);
+
+        let mut interp = interpreter(&[], NullHandler);
+        interp.segmentation = SegmentationPolicy::checked();
+        interp.segmentation.add_descriptor(
+            0x18,
+            SegmentDescriptor::new(0x3000, 0xfff, Protection::READ_EXECUTE),
+        );
+        assert_eq!(interp.execute(&far_jmp(0x1b)), StepResult::Continue);
+        assert_eq!(interp.context.eip, 0x3100);
+
+        interp.context.eip = CODE_ADDR;
+        assert_eq!(
+            interp.execute(&far_jmp(0x23)),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::GeneralProtection,
+                address: 0x100
+            })
+        );
+        assert_eq!(interp.context.eip, CODE_ADDR);
+
+        // the return address is not pushed if the call faults
+        let far_call = Instr::new(
+            CODE_ADDR,
+            7,
+            Mnemonic::Call,
+            vec![Operand::FarBranch(0x23, 0x100)],
+        );
+        let esp = interp.context.get_gp_reg(ESP);
+        assert!(matches!(
+            interp.execute(&far_call),
+            StepResult::Fault(InterpFault::Guest { .. })
+        ));
+        assert_eq!(interp.context.get_gp_reg(ESP), esp);
+    }
+
+    #[test_log::test]
+    fn salc() {
+        for cf in [false, true] {
+            // the assembler doesn't know salc (0xd6)
+            let code = assemble_x86!(
+                ; .byte -0x2a
+            );
+            let mut interp = interpreter(&code, NullHandler);
+            interp.context.set_gp_reg(EAX, 0x12345678);
+            interp.context.set_flag(Flag::Carry, cf);
+
+            assert_eq!(interp.step(), StepResult::Continue);
+            let expected = if cf { 0x123456ff } else { 0x12345600 };
+            assert_eq!(interp.context.get_gp_reg(EAX), expected);
+            assert_eq!(interp.context.get_flag(Flag::Carry), cf);
+        }
+    }
+
+    #[test_log::test]
+    fn bcd_adjusts() {
+        // (aam or aad, base, AX before, AX after)
+        let cases = [
+            (0xd4, 10, 0x1234_5663, 0x1234_0909),
+            (0xd4, 16, 0x0000_ff5c, 0x0000_050c),
+            (0xd4, 2, 0x0000_0003, 0x0000_0101),
+            (0xd4, 10, 0x0000_0700, 0x0000_0000),
+            (0xd5, 10, 0x1234_0907, 0x1234_0061),
+            (0xd5, 16, 0x0000_0a0b, 0x0000_00ab),
+            (0xd5, 2, 0x0000_ff01, 0x0000_00ff),
+        ];
+        for (opcode, base, before, after) in cases {
+            let code = [opcode, base];
+            let mut interp = interpreter(&code, NullHandler);
+            interp.context.set_gp_reg(EAX, before);
+            interp.context.set_flag(Flag::Carry, true);
+            assert_eq!(interp.step(), StepResult::Continue);
+            assert_eq!(interp.context.get_gp_reg(EAX), after, "{:x?}", code);
+            let al = after as u8;
+            assert_eq!(interp.context.get_flag(Flag::Zero), al == 0);
+            assert_eq!(interp.context.get_flag(Flag::Sign), al >= 0x80);
+            assert!(!interp.context.get_flag(Flag::Carry));
+        }
+
+        // aam 0
+        let mut interp = interpreter(&[0xd4, 0], NullHandler);
+        assert_eq!(interp.run(10), StepResult::Fault(InterpFault::DivideError));
+        assert_eq!(interp.context.eip, CODE_ADDR);
+    }
+
+    #[test_log::test]
+    fn narrow_shift_flags() {
+        // the operand is widened to 32 bits for the shift, the flags must still be taken at its own width
+        let code = assemble_x86!(
+            ; shr al, 1
+            ; shr ax, 1
+            ; shl al, 1
+            ; shl ax, 1
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        interp.context.set_gp_reg(EAX, 0x80);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x40);
+        assert!(interp.context.get_flag(Flag::Overflow));
+        assert!(!interp.context.get_flag(Flag::Carry));
+        assert!(!interp.context.get_flag(Flag::Sign));
+
+        interp.context.set_gp_reg(EAX, 0x8001);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x4000);
+        assert!(interp.context.get_flag(Flag::Overflow));
+        assert!(interp.context.get_flag(Flag::Carry));
+
+        interp.context.set_gp_reg(EAX, 0x40);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x80);
+        assert!(interp.context.get_flag(Flag::Overflow));
+        assert!(interp.context.get_flag(Flag::Sign));
+        assert!(!interp.context.get_flag(Flag::Carry));
+
+        interp.context.set_gp_reg(EAX, 0x8000);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0);
+        assert!(interp.context.get_flag(Flag::Overflow));
+        assert!(interp.context.get_flag(Flag::Carry));
+        assert!(interp.context.get_flag(Flag::Zero));
+    }
+
+    #[test_log::test]
+    fn shl_flags_match_reference() {
+        use crate::flags::mask;
+
+        // CF is the last bit shifted out, OF is whether the top bit changed on the last step (defined for 1-bit
+        // shifts only, but that's what Unicorn does for the others too). A masked count of zero touches nothing
+        fn reference(value: u32, count: u8, width: IntType) -> Option<(u64, bool, bool)> {
+            let count = (count & 0x1f) as u32;
+            if count == 0 {
+                return None;
+            }
+            let top = width.bit_width() as u32 - 1;
+            let value = value as u64 & mask(width);
+            let cf = (value << (count - 1)) >> top & 1 != 0;
+            let result = (value << count) & mask(width);
+            let of = (result >> top & 1 != 0) != cf;
+            Some((result, cf, of))
+        }
+
+        let counts = (0..=40).chain([63, 0xff]).collect::<Vec<u8>>();
+        for width in [IntType::I8, IntType::I16, IntType::I32] {
+            let code = match width {
+                IntType::I8 => assemble_x86!(; shl al, cl),
+                IntType::I16 => assemble_x86!(; shl ax, cl),
+                _ => assemble_x86!(; shl eax, cl),
+            };
+            let instr = Decoder::new(&code, CODE_ADDR).decode().unwrap();
+            let mut interp = interpreter(&code, NullHandler);
+
+            let values = (0..=0xff)
+                .chain([0x7fff, 0x8000, 0xc001, 0x4000_0000, 0x8000_0001])
+                .chain([0x7fff_ffff, 0xffff_ffff, 0x1234_5678]);
+            for value in values {
+                for &count in &counts {
+                    interp.context.set_gp_reg(EAX, value);
+                    interp.context.set_gp_reg(ECX, count as u32);
+                    for flag in [Flag::Carry, Flag::Overflow, Flag::Zero, Flag::Sign] {
+                        interp.context.set_flag(flag, true);
+                    }
+                    assert_eq!(interp.execute(&instr), StepResult::Continue);
+
+                    let (result, cf, of, zf, sf) = match reference(value, count, width) {
+                        Some((result, cf, of)) => {
+                            let sf = result >> (width.bit_width() - 1) != 0;
+                            (result, cf, of, result == 0, sf)
+                        }
+                        None => (value as u64 & mask(width), true, true, true, true),
+                    };
+                    let ctx = &interp.context;
+                    assert_eq!(
+                        (
+                            ctx.get_gp_reg(EAX) as u64 & mask(width),
+                            [Flag::Carry, Flag::Overflow, Flag::Zero, Flag::Sign]
+                                .map(|f| ctx.get_flag(f))
+                        ),
+                        (result, [cf, of, zf, sf]),
+                        "{} with {:#x}, cl={}",
+                        instr,
+                        value,
+                        count
+                    );
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn conditions_match_encoding() {
+        // the hardware evaluates the condition by the encoding: the pair number picks the test, the low bit negates it
+        fn encoded(encoding: usize, [cf, zf, sf, of, pf]: [bool; 5]) -> bool {
+            let test = match encoding >> 1 {
+                0 => of,
+                1 => cf,
+                2 => zf,
+                3 => cf || zf,
+                4 => sf,
+                5 => pf,
+                6 => sf != of,
+                _ => (sf != of) || zf,
+            };
+            test != (encoding & 1 != 0)
+        }
+
+        let mut interp = interpreter(&[], NullHandler);
+        for (encoding, condition) in Condition::iter().enumerate() {
+            let jcc = Instr::new(
+                CODE_ADDR,
+                2,
+                Mnemonic::Jcc(condition),
+                vec![Operand::Immediate32(0x1234)],
+            );
+            let cmovcc = Instr::new(
+                CODE_ADDR,
+                3,
+                Mnemonic::Cmovcc(condition),
+                vec![
+                    Operand::Register(Register::EAX),
+                    Operand::Register(Register::EBX),
+                ],
+            );
+            let setcc = Instr::new(
+                CODE_ADDR,
+                3,
+                Mnemonic::Setcc(condition),
+                vec![Operand::Register(Register::CL)],
+            );
+            for bits in 0..32 {
+                let flags = [0, 1, 2, 3, 4].map(|i| bits >> i & 1 != 0);
+                let expected = encoded(encoding, flags);
+                let set_flags = |ctx: &mut CpuContext| {
+                    for (flag, value) in [
+                        Flag::Carry,
+                        Flag::Zero,
+                        Flag::Sign,
+                        Flag::Overflow,
+                        Flag::Parity,
+                    ]
+                    .into_iter()
+                    .zip(flags)
+                    {
+                        ctx.set_flag(flag, value);
+                    }
+                };
+
+                set_flags(&mut interp.context);
+                assert_eq!(interp.execute(&jcc), StepResult::Continue);
+                let taken = interp.context.eip == 0x1234;
+                assert_eq!(taken, expected, "j{} with {:?}", condition.name(), flags);
+
+                set_flags(&mut interp.context);
+                interp.context.set_gp_reg(EAX, 1);
+                interp.context.set_gp_reg(EBX, 2);
+                assert_eq!(interp.execute(&cmovcc), StepResult::Continue);
+                let moved = interp.context.get_gp_reg(EAX) == 2;
+                assert_eq!(moved, expected, "cmov{} with {:?}", condition.name(), flags);
+
+                set_flags(&mut interp.context);
+                interp.context.set_gp_reg(ECX, 0xffff_ff55);
+                assert_eq!(interp.execute(&setcc), StepResult::Continue);
+                assert_eq!(
+                    interp.context.get_gp_reg(ECX),
+                    0xffff_ff00 | expected as u32,
+                    "set{} with {:?}",
+                    condition.name(),
+                    flags
+                );
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn arith_flags_match_reference() {
+        // the lowering builds the flags out of the overflow primitives, check it against the formulas directly
+        let code = assemble_x86!(
+            ; add al, bl
+            ; sub al, bl
+            ; sbb al, bl
+            ; adc al, bl
+            ; neg al
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        for lhs in 0..=0xffu32 {
+            for rhs in (0..=0xffu32).step_by(3) {
+                for carry in [false, true] {
+                    let expected = [
+                        crate::flags::add(lhs as u64, rhs as u64, false, IntType::I8),
+                        crate::flags::sub(lhs as u64, rhs as u64, false, IntType::I8),
+                        crate::flags::sub(lhs as u64, rhs as u64, carry, IntType::I8),
+                        crate::flags::add(lhs as u64, rhs as u64, carry, IntType::I8),
+                        // CF is set unless the operand was 0, OF only for 0x80
+                        crate::flags::sub(0, lhs as u64, false, IntType::I8),
+                    ];
+                    for (i, expected) in expected.iter().enumerate() {
+                        interp.context.eip = CODE_ADDR + 2 * i as u32;
+                        interp.context.set_gp_reg(EAX, lhs);
+                        interp.context.set_gp_reg(EBX, rhs);
+                        interp.context.set_flag(Flag::Carry, carry);
+                        assert_eq!(interp.step(), StepResult::Continue);
+
+                        let ctx = &interp.context;
+                        assert_eq!(ctx.get_gp_reg(EAX) as u64, expected.result);
+                        assert_eq!(
+                            [Flag::Carry, Flag::Overflow, Flag::Zero, Flag::Sign]
+                                .map(|f| ctx.get_flag(f)),
+                            [expected.cf, expected.of, expected.zf, expected.sf],
+                            "instruction {} with {:#x}, {:#x}, cf={}",
+                            i,
+                            lhs,
+                            rhs,
+                            carry
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn inc_dec_keep_carry() {
+        let code = assemble_x86!(
+            ; inc al
+            ; inc ax
+            ; inc eax
+            ; inc BYTE [0x100]
+            ; inc WORD [0x100]
+            ; inc DWORD [0x100]
+            ; dec al
+            ; dec ax
+            ; dec eax
+            ; dec BYTE [0x100]
+            ; dec WORD [0x100]
+            ; dec DWORD [0x100]
+        );
+        let mut decoder = Decoder::new(&code, CODE_ADDR);
+        let instrs: Vec<Instr> = (0..12).map(|_| decoder.decode().unwrap()).collect();
+        let mut interp = interpreter(&code, NullHandler);
+
+        for instr in &instrs {
+            let width = instr.operands[0].size();
+            let all_ones = crate::flags::mask(width) as u32;
+            let max = all_ones >> 1;
+            for value in [0, 1, 0xf, 0x10, max - 1, max, max + 1, max + 2, all_ones] {
+                for carry in [false, true] {
+                    interp.context.set_gp_reg(EAX, 0);
+                    interp.memory[0x100..0x104].copy_from_slice(&0u32.to_le_bytes());
+                    let register = matches!(instr.operands[0], Operand::Register(_));
+                    if register {
+                        interp.context.set_gp_reg(EAX, value);
+                    } else {
+                        interp.memory[0x100..0x104].copy_from_slice(&value.to_le_bytes());
+                    }
+                    interp.context.set_flag(Flag::Carry, carry);
+                    assert_eq!(interp.execute(instr), StepResult::Continue);
+
+                    let expected = match instr.mnemonic {
+                        Mnemonic::Inc => crate::flags::inc(value as u64, carry, width),
+                        _ => crate::flags::dec(value as u64, carry, width),
+                    };
+                    let result = if register {
+                        interp.context.get_gp_reg(EAX)
+                    } else {
+                        u32::from_le_bytes(interp.memory[0x100..0x104].try_into().unwrap())
+                    };
+                    let ctx = &interp.context;
+                    assert_eq!(
+                        result as u64, expected.result,
+                        "{} with {:#x}",
+                        instr, value
+                    );
+                    assert_eq!(
+                        [Flag::Carry, Flag::Overflow, Flag::Zero, Flag::Sign]
+                            .map(|f| ctx.get_flag(f)),
+                        [carry, expected.of, expected.zf, expected.sf],
+                        "{} with {:#x}, cf={}",
+                        instr,
+                        value,
+                        carry
+                    );
+                }
+            }
+        }
+    }
+
+    /// Checks `rcl`/`rcr` and `rol`/`ror` of AL/AX/EAX by CL against `flags::rotate_through_carry` &
+    /// `flags::rotate`. ZF & SF are set beforehand and have to survive
+    fn check_rotates(width: IntType, values: impl Iterator<Item = u32> + Clone, counts: &[u8]) {
+        use crate::flags::{rotate, rotate_through_carry};
+
+        let code = match width {
+            IntType::I8 => assemble_x86!(; rcl al, cl; rcr al, cl; rol al, cl; ror al, cl),
+            IntType::I16 => assemble_x86!(; rcl ax, cl; rcr ax, cl; rol ax, cl; ror ax, cl),
+            _ => assemble_x86!(; rcl eax, cl; rcr eax, cl; rol eax, cl; ror eax, cl),
+        };
+        let mut decoder = Decoder::new(&code, CODE_ADDR);
+        let rotates = [(); 4].map(|_| decoder.decode().unwrap());
+        let mut interp = interpreter(&code, NullHandler);
+
+        for (i, instr) in rotates.iter().enumerate() {
+            let (through_carry, left) = (i < 2, i % 2 == 0);
+            for value in values.clone() {
+                for &count in counts {
+                    for carry in [false, true] {
+                        interp.context.set_gp_reg(EAX, value);
+                        interp.context.set_gp_reg(ECX, count as u32);
+                        interp.context.set_flag(Flag::Carry, carry);
+                        interp.context.set_flag(Flag::Overflow, false);
+                        interp.context.set_flag(Flag::Zero, true);
+                        interp.context.set_flag(Flag::Sign, true);
+                        assert_eq!(interp.execute(instr), StepResult::Continue);
+
+                        let expected = if through_carry {
+                            rotate_through_carry(value as u64, count, carry, left, width)
+                        } else {
+                            rotate(value as u64, count, left, width)
+                        };
+                        let expected = expected.unwrap_or(crate::flags::RotateFlags {
+                            result: value as u64 & crate::flags::mask(width),
+                            cf: carry,
+                            of: false,
+                        });
+                        let ctx = &interp.context;
+                        assert_eq!(
+                            (
+                                ctx.get_gp_reg(EAX) as u64 & crate::flags::mask(width),
+                                [Flag::Carry, Flag::Overflow, Flag::Zero, Flag::Sign]
+                                    .map(|f| ctx.get_flag(f))
+                            ),
+                            (expected.result, [expected.cf, expected.of, true, true]),
+                            "{} with {:#x}, cl={}, cf={}",
+                            instr,
+                            value,
+                            count,
+                            carry
+                        );
+                        // the rest of the register stays
+                        let rest = !crate::flags::mask(width) as u32;
+                        assert_eq!(ctx.get_gp_reg(EAX) & rest, value & rest);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn rotate_through_carry_exhaustive_8bit() {
+        let counts = (0..=255).collect::<Vec<u8>>();
+        check_rotates(IntType::I8, 0..=0xff, &counts);
+    }
+
+    #[test_log::test]
+    fn rotate_through_carry_sampled() {
+        let counts = (0..=40).chain([63, 64, 0x7f, 0xff]).collect::<Vec<u8>>();
+        // the ring boundaries densely, the rest with a stride that hits every nibble
+        let values = (0..0x200)
+            .chain((0x7f00..0x8100).step_by(3))
+            .chain((0..=0xffff).step_by(0x101));
+        check_rotates(IntType::I16, values.map(|v| v | 0xabcd_0000), &counts);
+        let values = [
+            0,
+            1,
+            0x7fff_ffff,
+            0x8000_0000,
+            0x8000_0001,
+            0xffff_ffff,
+            0x1234_5678,
+        ];
+        check_rotates(IntType::I32, values.into_iter(), &counts);
+    }
+
+    #[test_log::test]
+    fn double_shifts_match_reference() {
+        use crate::flags::{double_shift, mask};
+
+        for width in [IntType::I16, IntType::I32] {
+            let code = match width {
+                IntType::I16 => assemble_x86!(; shld ax, bx, cl; shrd ax, bx, cl),
+                _ => assemble_x86!(; shld eax, ebx, cl; shrd eax, ebx, cl),
+            };
+            let mut decoder = Decoder::new(&code, CODE_ADDR);
+            let shifts = [decoder.decode().unwrap(), decoder.decode().unwrap()];
+            let mut interp = interpreter(&code, NullHandler);
+
+            for (instr, left) in shifts.iter().zip([true, false]) {
+                for (dst, src) in [
+                    (0x1234_5678u32, 0x9abc_def0u32),
+                    (0x8000_0001, 0x0000_8001),
+                    (0x0000_0001, 0xffff_ffff),
+                    (0xffff_ffff, 0),
+                    (0, 0),
+                ] {
+                    for count in (0..=40).chain([63, 64, 0xff]) {
+                        for flags in [false, true] {
+                            interp.context.set_gp_reg(EAX, dst);
+                            interp.context.set_gp_reg(EBX, src);
+                            interp.context.set_gp_reg(ECX, count as u32);
+                            for flag in [Flag::Carry, Flag::Zero, Flag::Sign, Flag::Overflow] {
+                                interp.context.set_flag(flag, flags);
+                            }
+                            assert_eq!(interp.execute(instr), StepResult::Continue);
+
+                            let ctx = &interp.context;
+                            let got = (
+                                ctx.get_gp_reg(EAX) as u64 & mask(width),
+                                [Flag::Carry, Flag::Zero, Flag::Sign, Flag::Overflow]
+                                    .map(|f| ctx.get_flag(f)),
+                            );
+                            let expected =
+                                match double_shift(dst as u64, src as u64, count, left, width) {
+                                    Some(f) => (f.result, [f.cf, f.zf, f.sf, f.of]),
+                                    None => (dst as u64 & mask(width), [flags; 4]),
+                                };
+                            assert_eq!(
+                                got, expected,
+                                "{} with {:#x}, {:#x}, cl={}",
+                                instr, dst, src, count
+                            );
+                            // the rest of the register stays
+                            let rest = !mask(width) as u32;
+                            assert_eq!(ctx.get_gp_reg(EAX) & rest, dst & rest);
+                            assert_eq!(ctx.get_gp_reg(EBX), src);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn bit_tests() {
+        let code = assemble_x86!(; bt eax, ecx; bts eax, ecx; btr eax, ecx; btc eax, ecx);
+        let mut decoder = Decoder::new(&code, CODE_ADDR);
+        let instrs: Vec<_> = (0..4).map(|_| decoder.decode().unwrap()).collect();
+        let mut interp = interpreter(&code, NullHandler);
+
+        for value in [0x8000_0001u32, 0x1234_5678, 0] {
+            for offset in [0, 3, 31, 32, 35, 0xffff_ffff] {
+                let bit = 1 << (offset % 32);
+                for (instr, expected) in
+                    instrs
+                        .iter()
+                        .zip([value, value | bit, value & !bit, value ^ bit])
+                {
+                    interp.context.set_gp_reg(EAX, value);
+                    interp.context.set_gp_reg(ECX, offset);
+                    interp.context.set_flag(Flag::Zero, true);
+                    assert_eq!(interp.execute(instr), StepResult::Continue);
+                    assert_eq!(interp.context.get_gp_reg(EAX), expected, "{}", instr);
+                    assert_eq!(interp.context.get_flag(Flag::Carry), value & bit != 0);
+                    assert!(interp.context.get_flag(Flag::Zero));
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn bit_scans() {
+        use crate::flags::mask;
+
+        for width in [IntType::I16, IntType::I32] {
+            let code = match width {
+                IntType::I16 => assemble_x86!(; bsf ax, bx; bsr ax, bx),
+                _ => assemble_x86!(; bsf eax, ebx; bsr eax, ebx),
+            };
+            let mut decoder = Decoder::new(&code, CODE_ADDR);
+            let scans = [decoder.decode().unwrap(), decoder.decode().unwrap()];
+            let mut interp = interpreter(&code, NullHandler);
+
+            for src in [
+                0u32,
+                1,
+                0x8000,
+                0x8000_0000,
+                0x0001_0000,
+                0x1234_5678,
+                0xffff_ffff,
+            ] {
+                let value = src & mask(width) as u32;
+                let expected = [
+                    (value != 0).then(|| value.trailing_zeros()),
+                    (value != 0).then(|| 31 - value.leading_zeros()),
+                ];
+                for (instr, expected) in scans.iter().zip(expected) {
+                    interp.context.set_gp_reg(EAX, 0xdead_beef);
+                    interp.context.set_gp_reg(EBX, src);
+                    assert_eq!(interp.execute(instr), StepResult::Continue);
+
+                    let ctx = &interp.context;
+                    let got = ctx.get_gp_reg(EAX);
+                    match expected {
+                        Some(index) => {
+                            assert_eq!(got & mask(width) as u32, index, "{} of {:#x}", instr, src)
+                        }
+                        // the destination is kept
+                        None => assert_eq!(got, 0xdead_beef, "{}", instr),
+                    }
+                    assert_eq!(got & !mask(width) as u32, 0xdead_beef & !mask(width) as u32);
+                    assert_eq!(ctx.get_flag(Flag::Zero), expected.is_none());
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn byte_swaps() {
+        // bswap eax; bswap ax
+        let code = [0x0f, 0xc8, 0x66, 0x0f, 0xc8];
+        let mut decoder = Decoder::new(&code, CODE_ADDR);
+        let bswap32 = decoder.decode().unwrap();
+        let bswap16 = decoder.decode().unwrap();
+        assert_eq!(bswap16.to_string(), "bswap ax");
+        let mut interp = interpreter(&code, NullHandler);
+
+        for flags in [false, true] {
+            for flag in Flag::iter() {
+                interp.context.set_flag(flag, flags);
+            }
+            interp.context.set_gp_reg(EAX, 0x1234_5678);
+            assert_eq!(interp.execute(&bswap32), StepResult::Continue);
+            assert_eq!(interp.context.get_gp_reg(EAX), 0x7856_3412);
+            assert_eq!(interp.execute(&bswap32), StepResult::Continue);
+            assert_eq!(interp.context.get_gp_reg(EAX), 0x1234_5678);
+            assert_eq!(interp.execute(&bswap16), StepResult::Continue);
+            assert_eq!(interp.context.get_gp_reg(EAX), 0x1234_0000);
+            for flag in Flag::iter() {
+                assert_eq!(interp.context.get_flag(flag), flags, "{:?}", flag);
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn sign_extensions() {
+        let code = assemble_x86!(
+            ; mov eax, 0x1234_5680
+            ; cbw
+            ; mov ebx, eax
+            ; cwde
+            ; mov ecx, eax
+            ; cwd
+            ; mov esi, edx
+            ; mov eax, 0x7fff_8000
+            ; cdq
+            ; ret
+        );
+
+        let mut interp = interpreter(&code, NullHandler);
+        interp.context.set_gp_reg(EDX, 0x5555_5555);
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(EBX), 0x1234_ff80);
+        assert_eq!(interp.context.get_gp_reg(ECX), 0xffff_ff80);
+        assert_eq!(interp.context.get_gp_reg(ESI), 0x5555_ffff);
+        assert_eq!(interp.context.get_gp_reg(EDX), 0);
+    }
+
+    #[test_log::test]
+    fn set_conditions() {
+        // all but setp & setnp, cmp leaves PF alone
+        for (lhs, rhs) in [
+            (1, 2),
+            (2, 1),
+            (5, 5),
+            (3, 0),
+            (-1, 1),
+            (0x7fff_ffff, -1),
+            (i32::MIN, 1),
+        ] {
+            let code = assemble_x86!(
+                ; mov ebx, 0x4000
+                ; mov DWORD [ebx], -1
+                ; mov eax, lhs
+                ; cmp eax, rhs
+                ; seto [ebx]
+                ; setno [ebx + 1]
+                ; setb [ebx + 2]
+                ; setae [ebx + 3]
+                ; sete [ebx + 4]
+                ; setne [ebx + 5]
+                ; setbe [ebx + 6]
+                ; seta [ebx + 7]
+                ; sets [ebx + 8]
+                ; setns [ebx + 9]
+                ; setl [ebx + 10]
+                ; setge [ebx + 11]
+                ; setle [ebx + 12]
+                ; setg [ebx + 13]
+                ; ret
+            );
+            let mut interp = interpreter(&code, NullHandler);
+            assert_eq!(interp.run(100), StepResult::Returned);
+
+            let (result, overflow) = lhs.overflowing_sub(rhs);
+            let below = (lhs as u32) < (rhs as u32);
+            let conditions = [
+                overflow,
+                below,
+                lhs == rhs,
+                below || lhs == rhs,
+                result < 0,
+                lhs < rhs,
+                lhs <= rhs,
+            ];
+            let expected: Vec<u8> = conditions
+                .iter()
+                .flat_map(|&c| [c as u8, !c as u8])
+                .collect();
+            assert_eq!(
+                interp.memory[0x4000..0x400e],
+                expected,
+                "cmp {}, {}",
+                lhs,
+                rhs
+            );
+            // the flags are still those of the cmp
+            assert_eq!(interp.context.get_flag(Flag::Carry), below);
+            assert_eq!(interp.context.get_flag(Flag::Overflow), overflow);
+        }
+    }
+
+    #[test_log::test]
+    fn exchanges() {
+        let code = assemble_x86!(
+            ; mov eax, 0x11223344
+            ; mov ebx, 0x55667788
+            ; xchg ah, bl
+            ; xchg ax, bx
+            ; xchg eax, eax
+            ; mov ecx, 0x4000
+            ; mov DWORD [ecx], 0x0a0b0c0d
+            ; xchg [ecx], eax
+            // lock xchg [ecx + 2], bx: the assembler won't put a LOCK on xchg, it is implied anyway
+            ; .bytes [0xf0_u8, 0x66, 0x87, 0x59, 0x02].iter().copied()
+            ; ret
+        );
+
+        let mut interp = interpreter(&code, NullHandler);
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x0a0b0c0d);
+        assert_eq!(interp.context.get_gp_reg(EBX), 0x55661122);
+        assert_eq!(interp.memory[0x4000..0x4004], [0x33, 0x77, 0x44, 0x88]);
+    }
+
+    #[test_log::test]
+    fn bit_strings() {
+        let code = assemble_x86!(
+            ; mov ebx, 0x4000
+            ; mov ecx, -1
+            ; bts DWORD [ebx], ecx
+            ; mov ecx, 70
+            ; bts DWORD [ebx], ecx
+            ; mov cx, -17
+            ; btc WORD [ebx], cx
+            // the immediate stays within the operand
+            ; bts DWORD [ebx], 35
+            ; bt WORD [ebx], cx
+            ; ret
+        );
+
+        let mut interp = interpreter(&code, NullHandler);
+        assert_eq!(interp.run(100), StepResult::Returned);
+        // bit 31 of the dword before & bit 15 of the word two words before
+        assert_eq!(interp.memory[0x3ffc..0x4000], [0, 0x80, 0, 0x80]);
+        assert_eq!(interp.memory[0x4000..0x4004], [0x08, 0, 0, 0]);
+        assert_eq!(interp.memory[0x4008..0x400c], [0x40, 0, 0, 0]);
+        assert!(interp.context.get_flag(Flag::Carry));
+    }
+
+    #[test_log::test]
+    fn narrow_mul_div() {
+        // (code, EAX, EDX, EBX before) -> (EAX, EDX after) or None for #DE
+        #[allow(clippy::type_complexity)]
+        let cases: &[(Vec<u8>, [u32; 3], Option<(u32, u32)>)] = &[
+            // mul writes AX for a byte, DX:AX for a word
+            (
+                assemble_x86!(; mul bl),
+                [0x12345680, 0, 2],
+                Some((0x12340100, 0)),
+            ),
+            (
+                assemble_x86!(; mul bx),
+                [0x12348000, 0x5555, 3],
+                Some((0x12348000, 1)),
+            ),
+            (assemble_x86!(; imul bl), [0x80, 0, 0xff], Some((0x0080, 0))),
+            // AX / BL: the quotient in AL, the remainder in AH
+            (
+                assemble_x86!(; div bl),
+                [0x0fff, 0, 0x10],
+                Some((0x0fff, 0)),
+            ),
+            (assemble_x86!(; div bl), [0x1000, 0, 0x10], None),
+            (assemble_x86!(; div bl), [0x1234, 0, 0], None),
+            // DX:AX / BX: the quotient in AX, the remainder in DX
+            (
+                assemble_x86!(; div bx),
+                [0xffff, 0xf, 0x10],
+                Some((0xffff, 0xf)),
+            ),
+            (assemble_x86!(; div bx), [0, 0x10, 0x10], None),
+            (assemble_x86!(; idiv bl), [0xff00, 0, 2], Some((0x0080, 0))),
+            (assemble_x86!(; idiv bl), [0x0100, 0, 2], None),
+            // the remainder has the sign of the dividend
+            (assemble_x86!(; idiv bl), [0xfff9, 0, 2], Some((0xfffd, 0))),
+            (
+                assemble_x86!(; idiv bl),
+                [0x0007, 0, 0xfe],
+                Some((0x01fd, 0)),
+            ),
+            (assemble_x86!(; idiv bx), [0, 0xffff, 2], Some((0x8000, 0))),
+            (assemble_x86!(; idiv bx), [0, 1, 2], None),
+            (
+                assemble_x86!(; idiv bx),
+                [0xfff9, 0xffff, 2],
+                Some((0xfffd, 0xffff)),
+            ),
+            (assemble_x86!(; idiv bx), [0x8000, 0xffff, 0xffff], None),
+            // the 32-bit forms divide EDX:EAX, with the same checks
+            (assemble_x86!(; div ebx), [0, 1, 2], Some((0x8000_0000, 0))),
+            (assemble_x86!(; div ebx), [0, 1, 1], None),
+            (assemble_x86!(; div ebx), [5, 0, 0], None),
+            (
+                assemble_x86!(; idiv ebx),
+                [0, 0xffff_ffff, 2],
+                Some((0x8000_0000, 0)),
+            ),
+            (assemble_x86!(; idiv ebx), [0, 1, 2], None),
+            (
+                assemble_x86!(; idiv ebx),
+                [0x8000_0000, 0xffff_ffff, 0xffff_ffff],
+                None,
+            ),
+            // the one that overflows the 64-bit division too
+            (
+                assemble_x86!(; idiv ebx),
+                [0, 0x8000_0000, 0xffff_ffff],
+                None,
+            ),
+        ];
+
+        for (code, [eax, edx, ebx], expected) in cases {
+            let mut interp = interpreter(code, NullHandler);
+            interp.context.set_gp_reg(EAX, *eax);
+            interp.context.set_gp_reg(EDX, *edx);
+            interp.context.set_gp_reg(EBX, *ebx);
+
+            match expected {
+                Some((eax, edx)) => {
+                    assert_eq!(interp.step(), StepResult::Continue, "{:x?}", code);
+                    assert_eq!(interp.context.get_gp_reg(EAX), *eax, "{:x?}", code);
+                    assert_eq!(interp.context.get_gp_reg(EDX), *edx, "{:x?}", code);
+                }
+                None => {
+                    assert_eq!(
+                        interp.step(),
+                        StepResult::Fault(InterpFault::DivideError),
+                        "{:x?}",
+                        code
+                    );
+                    assert_eq!(interp.context.eip, CODE_ADDR);
+                }
+            }
+        }
+
+        // CF & OF tell whether the upper half is used
+        let mut interp = interpreter(&assemble_x86!(; mul bl; mul bl), NullHandler);
+        interp.context.set_gp_reg(EAX, 0x7f);
+        interp.context.set_gp_reg(EBX, 2);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert!(!interp.context.get_flag(Flag::Carry));
+        assert!(!interp.context.get_flag(Flag::Overflow));
+        interp.context.set_gp_reg(EAX, 0x80);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert!(interp.context.get_flag(Flag::Carry));
+        assert!(interp.context.get_flag(Flag::Overflow));
+    }
+
+    /// Rust's `/` & `%` round toward zero with the remainder taking the sign of the dividend, just like idiv
+    #[test_log::test]
+    fn idiv_matches_reference() {
+        let code = assemble_x86!(; idiv ebx);
+        let instr = Decoder::new(&code, CODE_ADDR).decode().unwrap();
+        let mut interp = interpreter(&code, NullHandler);
+
+        let dividends = [
+            0i64,
+            7,
+            -7,
+            0x7fff_ffff,
+            -0x8000_0000,
+            0x1_2345_6789,
+            -0x1_2345_6789,
+            0x3c5a_1d07_9e42_b8f1,
+            -0x2b7c_4d1e_0f93_6a55,
+            i64::MAX,
+            i64::MIN,
+        ];
+        let divisors = [
+            1i32,
+            -1,
+            2,
+            -2,
+            7,
+            -7,
+            0x1234_5678,
+            -0x06d6_1d34,
+            i32::MAX,
+            i32::MIN,
+        ];
+        let (mut checked, mut faults) = (0, 0);
+        for &dividend in &dividends {
+            for &divisor in &divisors {
+                interp.context.set_gp_reg(EAX, dividend as u32);
+                interp.context.set_gp_reg(EDX, (dividend >> 32) as u32);
+                interp.context.set_gp_reg(EBX, divisor as u32);
+
+                // the ones that don't fit raise #DE (i64::MIN / -1 doesn't even fit 64 bits)
+                let quotient = match dividend.checked_div(divisor as i64) {
+                    Some(quotient) if quotient == quotient as i32 as i64 => quotient,
+                    _ => {
+                        assert_eq!(
+                            interp.execute(&instr),
+                            StepResult::Fault(InterpFault::DivideError),
+                            "{:#x} / {:#x}",
+                            dividend,
+                            divisor
+                        );
+                        faults += 1;
+                        continue;
+                    }
+                };
+                let remainder = dividend % divisor as i64;
+
+                assert_eq!(interp.execute(&instr), StepResult::Continue);
+                assert_eq!(
+                    (
+                        interp.context.get_gp_reg(EAX) as i32,
+                        interp.context.get_gp_reg(EDX) as i32
+                    ),
+                    (quotient as i32, remainder as i32),
+                    "{:#x} / {:#x}",
+                    dividend,
+                    divisor
+                );
+                checked += 1;
+            }
+        }
+        // most of the 64-bit dividends are too big for most of the divisors
+        assert!(checked > 50, "{}", checked);
+        assert!(faults > 0);
+    }
+
+    /// One-operand mul/imul of every width against the product computed in 64 bits
+    fn check_widening_mul(signed: bool) {
+        use crate::flags::mask;
+
+        let values = [
+            0,
+            1,
+            2,
+            0x7f,
+            0x80,
+            0xff,
+            0x7fff,
+            0x8000,
+            0xffff,
+            0x1234_5678,
+        ]
+        .into_iter()
+        .chain([0x7fff_ffff, 0x8000_0000, 0xffff_ffff]);
+        for width in [IntType::I8, IntType::I16, IntType::I32] {
+            let code = match (signed, width) {
+                (false, IntType::I8) => assemble_x86!(; mul bl),
+                (false, IntType::I16) => assemble_x86!(; mul bx),
+                (false, _) => assemble_x86!(; mul ebx),
+                (true, IntType::I8) => assemble_x86!(; imul bl),
+                (true, IntType::I16) => assemble_x86!(; imul bx),
+                (true, _) => assemble_x86!(; imul ebx),
+            };
+            let instr = Decoder::new(&code, CODE_ADDR).decode().unwrap();
+            let mut interp = interpreter(&code, NullHandler);
+
+            let bits = width.bit_width();
+            let extend = |value: u32| {
+                let value = value as u64 & mask(width);
+                if signed {
+                    ((value << (64 - bits)) as i64 >> (64 - bits)) as i128
+                } else {
+                    value as i128
+                }
+            };
+            // whatever is in EAX above the operand
+            let filler = 0xdead_beef & !mask(width) as u32;
+            for lhs in values.clone() {
+                for rhs in values.clone() {
+                    interp
+                        .context
+                        .set_gp_reg(EAX, filler | lhs & mask(width) as u32);
+                    interp.context.set_gp_reg(EDX, 0xbeef_beef);
+                    interp.context.set_gp_reg(EBX, rhs);
+                    assert_eq!(interp.execute(&instr), StepResult::Continue);
+
+                    let product = extend(lhs) * extend(rhs);
+                    let lo = product as u64 & mask(width);
+                    let hi = (product >> bits) as u64 & mask(width);
+                    // set when the lower half alone doesn't make the product
+                    let overflow = extend(lo as u32) != product;
+                    // the high half goes to AH for a byte, the rest of EAX & EDX stay
+                    let (eax, edx) = match width {
+                        IntType::I8 => (0xdead_0000 | (hi << 8 | lo) as u32, 0xbeef_beef),
+                        IntType::I16 => (filler | lo as u32, 0xbeef_0000 | hi as u32),
+                        _ => (lo as u32, hi as u32),
+                    };
+                    let ctx = &interp.context;
+                    assert_eq!(
+                        (
+                            ctx.get_gp_reg(EAX),
+                            ctx.get_gp_reg(EDX),
+                            ctx.get_flag(Flag::Carry),
+                            ctx.get_flag(Flag::Overflow)
+                        ),
+                        (eax, edx, overflow, overflow),
+                        "{} with {:#x} * {:#x}",
+                        instr,
+                        lhs,
+                        rhs
+                    );
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn mul_matches_reference() {
+        check_widening_mul(false);
+    }
+
+    #[test_log::test]
+    fn imul_one_operand_matches_reference() {
+        check_widening_mul(true);
+    }
+
+    #[test_log::test]
+    fn imul_three_operand_immediates() {
+        use crate::flags::mask;
+
+        let sources = [
+            0,
+            1,
+            -1,
+            2,
+            0x7f,
+            -0x80,
+            0x4000,
+            -0x8000,
+            0x1234_5678,
+            i32::MIN,
+        ];
+        for width in [IntType::I16, IntType::I32] {
+            let prefix: &[u8] = if width == IntType::I16 { &[0x66] } else { &[] };
+            let bits = width.bit_width();
+            let extend = |value: i64| (value << (64 - bits)) >> (64 - bits);
+
+            // imul eax, ebx, imm8 (sign-extended) & imul eax, ebx, imm16/imm32
+            let mut encodings = Vec::new();
+            for imm8 in [3i8, -1, -0x80, 0x7f] {
+                encodings.push(([prefix, &[0x6b, 0xc3, imm8 as u8]].concat(), imm8 as i64));
+            }
+            for imm in [0x1234i64, -0x7654, 0x7fff_1234, -0x1_0001] {
+                let imm = extend(imm);
+                let bytes = &(imm as u32).to_le_bytes()[..width.byte_width() as usize];
+                encodings.push(([prefix, &[0x69, 0xc3], bytes].concat(), imm));
+            }
+
+            for (code, imm) in encodings {
+                let instr = Decoder::new(&code, CODE_ADDR).decode().unwrap();
+                // sign-extended to the operand size by the decoder
+                let decoded = match instr.operands[2] {
+                    Operand::Immediate16(imm) => imm as u64,
+                    Operand::Immediate32(imm) => imm as u64,
+                    other => panic!("unexpected immediate {:?}", other),
+                };
+                assert_eq!(decoded, imm as u64 & mask(width), "{}", instr);
+                let mut interp = interpreter(&code, NullHandler);
+                for &src in &sources {
+                    interp.context.set_gp_reg(EAX, 0xdead_beef);
+                    interp.context.set_gp_reg(EBX, src as u32);
+                    assert_eq!(interp.execute(&instr), StepResult::Continue);
+
+                    let product = extend(src as i64) * imm;
+                    let truncated = extend(product);
+                    let expected = (0xdead_beef & !mask(width) as u32)
+                        | (truncated as u32 & mask(width) as u32);
+                    let overflow = interp.context.get_flag(Flag::Overflow);
+                    assert_eq!(
+                        (interp.context.get_gp_reg(EAX), overflow),
+                        (expected, truncated != product),
+                        "{} with ebx = {:#x}",
+                        instr,
+                        src
+                    );
+                    assert_eq!(interp.context.get_flag(Flag::Carry), overflow);
+                }
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn sib_irregularities() {
+        let code = [
+            // mov eax, [0x2000] with a SIB byte (no base, no index)
+            0x8b, 0x04, 0x25, 0x00, 0x20, 0x00, 0x00, //
+            // mov ebx, [ebp + 0]
+            0x8b, 0x5d, 0x00, //
+            // mov ecx, [esp*2 + esp + 4], the index is not there
+            0x8b, 0x4c, 0x64, 0x04,
+        ];
+        let mut interp = interpreter(&code, NullHandler);
+        interp.memory[0x2000..0x2004].copy_from_slice(&0x11223344u32.to_le_bytes());
+        interp.memory[0x3000..0x3004].copy_from_slice(&0x55667788u32.to_le_bytes());
+        interp.memory[0x4004..0x4008].copy_from_slice(&0x99aabbccu32.to_le_bytes());
+        interp.context.set_gp_reg(EBP, 0x3000);
+        interp.context.set_gp_reg(ESP, 0x4000);
+
+        assert_eq!(interp.run(3), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x11223344);
+        assert_eq!(interp.context.get_gp_reg(EBX), 0x55667788);
+        assert_eq!(interp.context.get_gp_reg(ECX), 0x99aabbcc);
+    }
+
+    #[test_log::test]
+    fn negative_displacements() {
+        let code = assemble_x86!(
+            ; mov eax, 0x2008
+            ; mov DWORD [eax - 8], 0x1337
+            ; mov ebx, 0x2100 - 12
+            ; mov ecx, 3
+            ; add DWORD [ebx + ecx*4 - 0x100], 1
+            ; push ebp
+            ; mov ebp, esp
+            ; sub esp, 8
+            ; mov DWORD [ebp - 4], 5
+            ; add DWORD [ebp - 4], 3
+            ; mov edx, [ebp - 4]
+            ; lea esi, [ebp - 8]
+            ; leave
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.memory[0x2000..0x2004], 0x1338u32.to_le_bytes());
+        assert_eq!(interp.context.get_gp_reg(EDX), 8);
+        // the frame is under the pushed ebp, under the return address
+        assert_eq!(interp.context.get_gp_reg(ESI), STACK_TOP - 16);
+        assert_eq!(
+            interp.memory[STACK_TOP as usize - 12..][..4],
+            8u32.to_le_bytes()
+        );
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP);
+    }
+
+    #[test_log::test]
+    fn control_registers() {
+        #[derive(Default)]
+        struct Recorder(Vec<(u8, u32)>);
+        impl RuntimeHandler for Recorder {
+            fn control_register_write(&mut self, _: &mut CpuContext, register: u8, value: u32) {
+                self.0.push((register, value));
+            }
+        }
+
+        let code = assemble_x86!(
+            ; mov eax, cr0
+            ; mov ebx, cr3
+            ; smsw cx
+            ; mov cr3, edx
+            ; lmsw dx
+        );
+        let profile = SystemRegisterProfile {
+            cr3: 0x1234000,
+            writes: ControlRegisterWrites::Record,
+            ..SystemRegisterProfile::default()
+        };
+        let mut interp = interpreter(&code, Recorder::default());
+        interp.system_registers = profile.clone();
+        interp.context.set_gp_reg(EDX, 0x5678000e);
+
+        assert_eq!(interp.run(5), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), profile.cr0);
+        assert_eq!(interp.context.get_gp_reg(EBX), 0x1234000);
+        assert_eq!(interp.context.get_gp_reg(ECX), profile.cr0 & 0xffff);
+        // lmsw loads TS, EM & MP, but can't clear PE
+        assert_eq!(
+            interp.handler.0,
+            vec![(3, 0x5678000e), (0, profile.cr0 & !0xf | 0xf)]
+        );
+
+        // the writes fault with the policy, the reads still work
+        let mut interp = interpreter(&code, NullHandler);
+        interp.system_registers.writes = ControlRegisterWrites::Fault;
+        assert_eq!(
+            interp.run(5),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::GeneralProtection,
+                address: 0
+            })
+        );
+        assert_eq!(interp.context.eip, CODE_ADDR + 10);
+        assert_eq!(interp.context.get_gp_reg(EAX), profile.cr0);
+
+        // in user mode only smsw is allowed
+        let mut interp = interpreter(&code[6..], NullHandler);
+        interp.system_registers.user_mode = true;
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(ECX), profile.cr0 & 0xffff);
+        let mut interp = interpreter(&code, NullHandler);
+        interp.system_registers.user_mode = true;
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::GeneralProtection,
+                address: 0
+            })
+        );
+        assert_eq!(interp.context.eip, CODE_ADDR);
+    }
+
+    #[test_log::test]
+    fn msrs() {
+        #[derive(Default)]
+        struct Recorder(Vec<(u32, u64)>);
+        impl RuntimeHandler for Recorder {
+            fn msr_write(&mut self, _: &mut CpuContext, index: u32, value: u64) {
+                self.0.push((index, value));
+            }
+        }
+
+        let code = assemble_x86!(
+            ; mov ecx, 0xce
+            ; rdmsr
+            ; mov ebx, eax
+            ; mov ecx, 0x10
+            ; rdmsr
+            ; mov edx, 0x11223344
+            ; wrmsr
+            ; rdpmc
+        );
+        let mut profile = SystemRegisterProfile {
+            performance_counter: 0x1234,
+            writes: ControlRegisterWrites::Record,
+            ..SystemRegisterProfile::default()
+        };
+        profile.set_msr(MSR_PLATFORM_INFO, 0x0000_0001_0000_2000);
+        profile.set_msr(0x1b, 0xfee00900);
+
+        let mut interp = interpreter(&code, Recorder::default());
+        interp.system_registers = profile.clone();
+        assert_eq!(interp.run(8), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EBX), 0x2000);
+        // the unknown one reads as zero
+        assert_eq!(interp.handler.0, vec![(0x10, 0x11223344_00000000)]);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x1234);
+        assert_eq!(interp.context.get_gp_reg(EDX), 0);
+
+        let gp = StepResult::Fault(InterpFault::Guest {
+            fault: GuestFault::GeneralProtection,
+            address: 0,
+        });
+
+        // the unknown one faults with the strict policy
+        let mut interp = interpreter(&code, NullHandler);
+        interp.system_registers = SystemRegisterProfile {
+            unknown_msrs: UnknownMsrReads::Fault,
+            ..profile.clone()
+        };
+        assert_eq!(interp.run(8), gp);
+        assert_eq!(interp.context.eip, CODE_ADDR + 14);
+        assert_eq!(interp.context.get_gp_reg(EBX), 0x2000);
+
+        // nothing but rdpmc with CR4.PCE in user mode
+        let mut interp = interpreter(&code, NullHandler);
+        interp.system_registers = SystemRegisterProfile {
+            user_mode: true,
+            ..profile.clone()
+        };
+        assert_eq!(interp.run(8), gp);
+        assert_eq!(interp.context.eip, CODE_ADDR + 5);
+        let rdpmc = &code[code.len() - 2..];
+        let mut interp = interpreter(rdpmc, NullHandler);
+        interp.system_registers.user_mode = true;
+        assert_eq!(interp.step(), gp);
+        interp.system_registers.cr4 = CR4_PCE;
+        assert_eq!(interp.step(), StepResult::Continue);
+    }
+
+    #[test_log::test]
+    fn cache_maintenance() {
+        #[derive(Default)]
+        struct Recorder(Vec<u32>);
+        impl RuntimeHandler for Recorder {
+            fn cache_flush(&mut self, _: &mut CpuContext, address: u32) {
+                self.0.push(address);
+            }
+        }
+
+        let code = assemble_x86!(
+            ; clflush [eax + 0x10]
+            ; invd
+            ; wbinvd
+            ; invlpg [ebx]
+        );
+
+        let mut interp = interpreter(&code, Recorder::default());
+        interp.context.set_gp_reg(EAX, 0x2000);
+        // ebx points nowhere, invlpg doesn't care
+        interp.context.set_gp_reg(EBX, 0x1234_5678);
+        assert_eq!(interp.run(4), StepResult::Continue);
+        assert_eq!(interp.handler.0, vec![0x2010]);
+
+        // faults like a read would, before telling the handler
+        let mut interp = interpreter(&code, Recorder::default());
+        interp.context.set_gp_reg(EAX, 0x9000);
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::MemoryOutOfBounds {
+                address: 0x9010,
+                size: 1
+            })
+        );
+        assert!(interp.handler.0.is_empty());
+
+        // clflush is not privileged, the rest is
+        let mut interp = interpreter(&code, NullHandler);
+        interp.system_registers.user_mode = true;
+        interp.context.set_gp_reg(EAX, 0x2000);
+        assert_eq!(interp.step(), StepResult::Continue);
+        for _ in 0..3 {
+            let eip = interp.context.eip;
+            assert_eq!(
+                interp.step(),
+                StepResult::Fault(InterpFault::Guest {
+                    fault: GuestFault::GeneralProtection,
+                    address: 0,
+                })
+            );
+            // skip over it
+            let len = Decoder::new(&code[(eip - CODE_ADDR) as usize..], eip)
+                .decode()
+                .unwrap()
+                .len;
+            interp.context.eip += len as u32;
+        }
+    }
+
+    #[test_log::test]
+    fn bound() {
+        let code = assemble_x86!(
+            ; bound eax, [0x2000]
+            ; bound ax, [0x2008]
+        );
+
+        let check = |value: u32, in_range: bool| {
+            let mut interp = interpreter(&code, NullHandler);
+            interp.memory[0x2000..0x2008]
+                .copy_from_slice(&[-5i32, 10].map(i32::to_le_bytes).concat());
+            interp.memory[0x2008..0x200c]
+                .copy_from_slice(&[-5i16, 10].map(i16::to_le_bytes).concat());
+            interp.context.set_gp_reg(EAX, value);
+
+            for ip in [CODE_ADDR, CODE_ADDR + 6] {
+                let result = interp.step();
+                if in_range {
+                    assert_eq!(result, StepResult::Continue, "0x{:x} at 0x{:x}", value, ip);
+                } else {
+                    assert_eq!(
+                        result,
+                        StepResult::Fault(InterpFault::Guest {
+                            fault: GuestFault::BoundRange,
+                            address: 0
+                        }),
+                        "0x{:x} at 0x{:x}",
+                        value,
+                        ip
+                    );
+                    assert_eq!(interp.context.eip, ip);
+                    // skip it & check the 16-bit one
+                    interp.context.eip = CODE_ADDR + 6;
+                }
+            }
+        };
+
+        check(-6i32 as u32, false);
+        check(-5i32 as u32, true);
+        check(0, true);
+        check(10, true);
+        check(11, false);
+    }
+
+    #[test_log::test]
+    fn invalid_opcode() {
+        // nop; (ff /7)
+        let mut interp = interpreter(&[0x90, 0xff, 0xf8], NullHandler);
+        assert_eq!(
+            interp.run(10),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::InvalidOpcode,
+                address: CODE_ADDR + 1
+            })
+        );
+        assert_eq!(interp.context.eip, CODE_ADDR + 1);
+    }
+
+    #[test_log::test]
+    fn unsupported_instruction_trap() {
+        let code = assemble_x86!(
+            ; test eax, eax
+            ; jz >skip
+            ; cpuid
+            ; skip:
+            ; mov ebx, 1
+            ; ret
+        );
+
+        let run = |eax| {
+            let mut interp = interpreter(&code, NullHandler);
+            interp.unsupported_instructions = UnsupportedInstructionPolicy::Trap;
+            interp.context.set_gp_reg(EAX, eax);
+            (
+                interp.run(10),
+                interp.context.get_gp_reg(EBX),
+                interp.context.eip,
+            )
+        };
+
+        // jumped over
+        assert_eq!(run(0).0, StepResult::Returned);
+        assert_eq!(run(0).1, 1);
+        // got there
+        assert_eq!(
+            run(1),
+            (
+                StepResult::Fault(InterpFault::Guest {
+                    fault: GuestFault::Unimplemented {
+                        eip: CODE_ADDR + 8,
+                        bytes: InstructionBytes::new(&[0x0f, 0xa2]),
+                    },
+                    address: CODE_ADDR + 8
+                }),
+                0,
+                CODE_ADDR + 8
+            )
+        );
+    }
+
+    /// Runs `code` with `value` at 0x2000 & `control` at 0x2010, returns the double stored at 0x2008
+    fn run_x87(code: &[u8], value: f64, control: u16) -> u64 {
+        let mut interp = interpreter(code, NullHandler);
+        interp.memory[0x2000..0x2008].copy_from_slice(&value.to_le_bytes());
+        interp.memory[0x2010..0x2012].copy_from_slice(&control.to_le_bytes());
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        // everything pushed was popped
+        assert_eq!(interp.context.fpu_status, 0);
+        assert_eq!(interp.context.fpu_tag, 0xffff);
+        u64::from_le_bytes(interp.memory[0x2008..0x2010].try_into().unwrap())
+    }
+
+    const VALUES: [f64; 8] = [1.5, -2.25, 0.0, -0.0, 0.1, 1e-310, f64::INFINITY, -1e300];
+
+    #[test_log::test]
+    fn x87_sign_ops() {
+        let fchs = assemble_x86!(
+            ; fld QWORD [0x2000]
+            ; fchs
+            ; fstp QWORD [0x2008]
+            ; ret
+        );
+        let fabs = assemble_x86!(
+            ; fld QWORD [0x2000]
+            ; fabs
+            ; fstp QWORD [0x2008]
+            ; ret
+        );
+
+        for value in VALUES {
+            assert_eq!(
+                run_x87(&fchs, value, 0x37f),
+                (-value).to_bits(),
+                "{}",
+                value
+            );
+            assert_eq!(
+                run_x87(&fabs, value, 0x37f),
+                value.abs().to_bits(),
+                "{}",
+                value
+            );
+        }
+        assert_eq!(run_x87(&fabs, -0.0, 0x37f), 0.0f64.to_bits());
+    }
+
+    #[test_log::test]
+    fn x87_sqrt() {
+        let code = assemble_x86!(
+            ; fld QWORD [0x2000]
+            ; fsqrt
+            ; fstp QWORD [0x2008]
+            ; ret
+        );
+
+        for value in [2.0f64, 6.25, 0.1, 1e-310, -0.0, f64::INFINITY] {
+            assert_eq!(
+                run_x87(&code, value, 0x37f),
+                value.sqrt().to_bits(),
+                "{}",
+                value
+            );
+        }
+        assert!(f64::from_bits(run_x87(&code, -1.0, 0x37f)).is_nan());
+    }
+
+    #[test_log::test]
+    fn x87_frndint() {
+        let code = assemble_x86!(
+            ; fldcw WORD [0x2010]
+            ; fld QWORD [0x2000]
+            ; frndint
+            ; fstp QWORD [0x2008]
+            ; ret
+        );
+
+        let nearest = 0x037f;
+        let down = 0x077f;
+        let up = 0x0b7f;
+        let zero = 0x0f7f;
+
+        assert_eq!(run_x87(&code, 2.5, nearest), 2.0f64.to_bits());
+        assert_eq!(run_x87(&code, 3.5, nearest), 4.0f64.to_bits());
+        assert_eq!(run_x87(&code, 2.5, zero), 2.0f64.to_bits());
+        assert_eq!(run_x87(&code, -2.5, zero), (-2.0f64).to_bits());
+
+        for value in [2.5f64, -2.5, 3.5, 1.7, -1.2, -0.3, 1e300, 0.0] {
+            for (control, expected) in [
+                (nearest, value.round_ties_even()),
+                (down, value.floor()),
+                (up, value.ceil()),
+                (zero, value.trunc()),
+            ] {
+                assert_eq!(
+                    run_x87(&code, value, control),
+                    expected.to_bits(),
+                    "{} with 0x{:x}",
+                    value,
+                    control
+                );
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn x87_stack() {
+        let code = assemble_x86!(
+            ; fldz
+            ; fld1
+            ; fldpi
+            ; fxch st2
+            ; fstp QWORD [0x2000]
+            ; fstp QWORD [0x2008]
+            ; fstp QWORD [0x2010]
+            // float <-> double
+            ; fld DWORD [0x2020]
+            ; fld st0
+            ; fstp DWORD [0x2024]
+            ; fstp QWORD [0x2028]
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.memory[0x2020..0x2024].copy_from_slice(&0.1f32.to_le_bytes());
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        let double_at =
+            |addr: usize| u64::from_le_bytes(interp.memory[addr..addr + 8].try_into().unwrap());
+        assert_eq!(double_at(0x2000), 0.0f64.to_bits());
+        assert_eq!(double_at(0x2008), 1.0f64.to_bits());
+        assert_eq!(double_at(0x2010), std::f64::consts::PI.to_bits());
+        assert_eq!(interp.memory[0x2024..0x2028], 0.1f32.to_le_bytes());
+        assert_eq!(double_at(0x2028), (0.1f32 as f64).to_bits());
+        assert_eq!(interp.context.fpu_tag, 0xffff);
+    }
+
+    #[test_log::test]
+    fn non_temporal_stores() {
+        let code = assemble_x86!(
+            ; mov eax, 0x12345678
+            ; movnti [0x2000], eax
+            ; movntps [0x2010], xmm1
+            ; movntdq [0x2021], xmm1
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.context.xmm_regs[2] = 0x0706050403020100;
+        interp.context.xmm_regs[3] = 0x0f0e0d0c0b0a0908;
+
+        // unaligned is fine unless asked otherwise
+        assert_eq!(interp.run(100), StepResult::Returned);
+        let expected: Vec<u8> = (0..16).collect();
+        assert_eq!(interp.memory[0x2000..0x2004], 0x12345678u32.to_le_bytes());
+        assert_eq!(interp.memory[0x2010..0x2020], expected[..]);
+        assert_eq!(interp.memory[0x2021..0x2031], expected[..]);
+
+        let code = assemble_x86!(
+            ; movntps [0x2010], xmm1
+            ; movntps [0x2001], xmm1
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.strict_alignment = true;
+        interp.context.xmm_regs[2] = 0x0706050403020100;
+        interp.context.xmm_regs[3] = 0x0f0e0d0c0b0a0908;
+
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.memory[0x2010..0x2020], expected[..]);
+
+        let before = interp.context.clone();
+        assert_eq!(
+            interp.step(),
+            StepResult::Fault(InterpFault::Guest {
+                fault: GuestFault::GeneralProtection,
+                address: 0x2001
+            })
+        );
+        assert_eq!(interp.context, before);
+        assert_eq!(interp.memory[0x2001..0x2010], [0; 15]);
+    }
+
+    fn get_xmm<H: RuntimeHandler>(interp: &Interpreter<H>, i: usize) -> u128 {
+        let regs = &interp.context.xmm_regs;
+        regs[2 * i] as u128 | (regs[2 * i + 1] as u128) << 64
+    }
+
+    fn set_xmm<H: RuntimeHandler>(interp: &mut Interpreter<H>, i: usize, value: u128) {
+        interp.context.xmm_regs[2 * i] = value as u64;
+        interp.context.xmm_regs[2 * i + 1] = (value >> 64) as u64;
+    }
+
+    fn words_to_xmm(words: [u16; 8]) -> u128 {
+        words
+            .iter()
+            .rev()
+            .fold(0, |acc, &word| acc << 16 | word as u128)
+    }
+
+    #[test_log::test]
+    fn word_lanes() {
+        // 0xf000, 0xf111, ..., 0xf777: the top bit checks the zero extension
+        let lanes: [u16; 8] = std::array::from_fn(|i| 0xf000 + 0x111 * i as u16);
+        let pattern = words_to_xmm(lanes);
+
+        let mut interp = interpreter(&[], NullHandler);
+        set_xmm(&mut interp, 1, pattern);
+        // only the low 3 bits of the immediate count
+        for imm in [0, 1, 2, 3, 4, 5, 6, 7, 8, 13, 0xff] {
+            execute(
+                &mut interp,
+                Mnemonic::Pextrw,
+                vec![
+                    Operand::Register(Register::EAX),
+                    Operand::Xmm(1),
+                    Operand::Immediate8(imm),
+                ],
+            );
+            assert_eq!(
+                interp.context.get_gp_reg(EAX),
+                lanes[imm as usize & 7] as u32
+            );
+            assert_eq!(get_xmm(&interp, 1), pattern);
+        }
+
+        let code = assemble_x86!(
+            ; mov eax, 0x12345678
+            ; pinsrw xmm1, eax, 0
+            ; mov WORD [0x2000], 0x4321
+            ; pinsrw xmm1, WORD [0x2000], 15
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        set_xmm(&mut interp, 1, pattern);
+        assert_eq!(interp.run(2), StepResult::Continue);
+        let mut expected = lanes;
+        expected[0] = 0x5678;
+        assert_eq!(get_xmm(&interp, 1), words_to_xmm(expected));
+        assert_eq!(interp.run(2), StepResult::Continue);
+        expected[7] = 0x4321;
+        assert_eq!(get_xmm(&interp, 1), words_to_xmm(expected));
+
+        let code = assemble_x86!(
+            // reversed low half, the high one is copied
+            ; pshuflw xmm2, xmm1, 0x1b
+            // broadcast lane 5 in the high half, the low one is copied
+            ; pshufhw xmm3, xmm1, 0x55
+            // swap the pairs in the high half, from memory
+            ; pshufhw xmm1, OWORD [0x2000], 0x4e
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        set_xmm(&mut interp, 1, pattern);
+        interp.memory[0x2000..0x2010].copy_from_slice(&(!pattern).to_le_bytes());
+        assert_eq!(interp.run(3), StepResult::Continue);
+        let [l0, l1, l2, l3, l4, l5, l6, l7] = lanes;
+        assert_eq!(
+            get_xmm(&interp, 2),
+            words_to_xmm([l3, l2, l1, l0, l4, l5, l6, l7])
+        );
+        assert_eq!(
+            get_xmm(&interp, 3),
+            words_to_xmm([l0, l1, l2, l3, l5, l5, l5, l5])
+        );
+        let [n0, n1, n2, n3, n4, n5, n6, n7] = lanes.map(|lane| !lane);
+        assert_eq!(
+            get_xmm(&interp, 1),
+            words_to_xmm([n0, n1, n2, n3, n6, n7, n4, n5])
+        );
+    }
+
+    #[test_log::test]
+    fn min_max() {
+        // the first operand, the second one, max, min
+        // the result is the second operand unless the comparison is ordered & strict
+        let nan_a = f32::from_bits(0x7fc0_0001);
+        let nan_b = f32::from_bits(0xffc0_0002);
+        let cases: [(f32, f32, f32, f32); 8] = [
+            (1.0, 2.0, 2.0, 1.0),
+            (-1.0, f32::NEG_INFINITY, -1.0, f32::NEG_INFINITY),
+            (nan_a, 1.0, 1.0, 1.0),
+            (1.0, nan_a, nan_a, nan_a),
+            (nan_a, nan_b, nan_b, nan_b),
+            (0.0, -0.0, -0.0, -0.0),
+            (-0.0, 0.0, 0.0, 0.0),
+            (3.0, 3.0, 3.0, 3.0),
+        ];
+        let single = |lanes: &[f32]| {
+            lanes
+                .iter()
+                .rev()
+                .fold(0, |acc, v| acc << 32 | v.to_bits() as u128)
+        };
+        let double = |lanes: &[f32]| {
+            lanes
+                .iter()
+                .rev()
+                .fold(0, |acc, &v| acc << 64 | (v as f64).to_bits() as u128)
+        };
+        let column =
+            |i: usize| -> Vec<f32> { cases.iter().map(|c| [c.0, c.1, c.2, c.3][i]).collect() };
+        let (lhs, rhs, max, min) = (column(0), column(1), column(2), column(3));
+
+        let code = assemble_x86!(
+            ; maxps xmm0, xmm4
+            ; minps xmm1, OWORD [0x2000]
+            ; maxpd xmm2, xmm5
+            ; minpd xmm3, OWORD [0x2010]
+        );
+        // 4 singles or 2 doubles at a time
+        for chunk in 0..4 {
+            let singles = chunk % 2 * 4..chunk % 2 * 4 + 4;
+            let doubles = chunk * 2..chunk * 2 + 2;
+
+            let mut interp = interpreter(&code, NullHandler);
+            set_xmm(&mut interp, 0, single(&lhs[singles.clone()]));
+            set_xmm(&mut interp, 4, single(&rhs[singles.clone()]));
+            set_xmm(&mut interp, 1, single(&lhs[singles.clone()]));
+            interp.memory[0x2000..0x2010]
+                .copy_from_slice(&single(&rhs[singles.clone()]).to_le_bytes());
+            set_xmm(&mut interp, 2, double(&lhs[doubles.clone()]));
+            set_xmm(&mut interp, 5, double(&rhs[doubles.clone()]));
+            set_xmm(&mut interp, 3, double(&lhs[doubles.clone()]));
+            interp.memory[0x2010..0x2020]
+                .copy_from_slice(&double(&rhs[doubles.clone()]).to_le_bytes());
+
+            assert_eq!(interp.run(4), StepResult::Continue);
+            assert_eq!(get_xmm(&interp, 0), single(&max[singles.clone()]));
+            assert_eq!(get_xmm(&interp, 1), single(&min[singles]));
+            assert_eq!(get_xmm(&interp, 2), double(&max[doubles.clone()]));
+            assert_eq!(get_xmm(&interp, 3), double(&min[doubles]));
+        }
+
+        // the scalar ones keep the rest of the destination
+        let code = assemble_x86!(
+            ; maxss xmm0, xmm4
+            ; minss xmm1, DWORD [0x2000]
+            ; maxsd xmm2, xmm5
+            ; minsd xmm3, QWORD [0x2010]
+        );
+        let upper = 0xdead_beef_0123_4567_89ab_cdef_0000_0000u128;
+        for (lhs, rhs, max, min) in cases {
+            let mut interp = interpreter(&code, NullHandler);
+            set_xmm(&mut interp, 0, upper | single(&[lhs]));
+            set_xmm(&mut interp, 4, !0 << 32 | single(&[rhs]));
+            set_xmm(&mut interp, 1, upper | single(&[lhs]));
+            interp.memory[0x2000..0x2004].copy_from_slice(&rhs.to_bits().to_le_bytes());
+            set_xmm(&mut interp, 2, upper << 32 | double(&[lhs]));
+            set_xmm(&mut interp, 5, !0 << 64 | double(&[rhs]));
+            set_xmm(&mut interp, 3, upper << 32 | double(&[lhs]));
+            interp.memory[0x2010..0x2018].copy_from_slice(&(rhs as f64).to_bits().to_le_bytes());
+
+            assert_eq!(interp.run(4), StepResult::Continue);
+            assert_eq!(get_xmm(&interp, 0), upper | single(&[max]));
+            assert_eq!(get_xmm(&interp, 1), upper | single(&[min]));
+            assert_eq!(get_xmm(&interp, 2), upper << 32 | double(&[max]));
+            assert_eq!(get_xmm(&interp, 3), upper << 32 | double(&[min]));
+        }
+    }
+
+    #[test_log::test]
+    fn float_compares() {
+        // the usual compiler output: eax = 0 unordered, 1 above, 2 below, 3 equal
+        // & ebx = 1 when ordered (through cmov)
+        macro_rules! classify {
+            ($($compare:tt)*) => {
+                assemble_x86!(
+                    ; xor ebx, ebx
+                    ; mov ecx, 1
+                    ; $($compare)*
+                    ; cmovnp ebx, ecx
+                    ; jp >unordered
+                    ; ja >above
+                    ; jb >below
+                    ; mov eax, 3
+                    ; ret
+                    ; unordered:
+                    ; mov eax, 0
+                    ; ret
+                    ; above:
+                    ; mov eax, 1
+                    ; ret
+                    ; below:
+                    ; mov eax, 2
+                    ; ret
+                )
+            };
+        }
+        let codes = [
+            classify!(ucomiss xmm0, xmm1),
+            classify!(comiss xmm0, DWORD [0x2000]),
+            classify!(ucomisd xmm0, QWORD [0x2000]),
+            classify!(comisd xmm0, xmm1),
+        ];
+
+        let cases = [
+            (1.0, 2.0, 2),
+            (2.0, 1.0, 1),
+            (-0.0, 0.0, 3),
+            (f64::INFINITY, f64::INFINITY, 3),
+            (f64::NAN, 1.0, 0),
+            (1.0, f64::NAN, 0),
+        ];
+        for (i, code) in codes.iter().enumerate() {
+            let double = i >= 2;
+            // garbage in the rest of the register doesn't matter
+            let bits = |v: f64| {
+                if double {
+                    !0 << 64 | v.to_bits() as u128
+                } else {
+                    !0 << 32 | (v as f32).to_bits() as u128
+                }
+            };
+            for (lhs, rhs, expected) in cases {
+                let mut interp = interpreter(code, NullHandler);
+                set_xmm(&mut interp, 0, bits(lhs));
+                set_xmm(&mut interp, 1, bits(rhs));
+                interp.memory[0x2000..0x2008].copy_from_slice(&(bits(rhs) as u64).to_le_bytes());
+                for flag in [Flag::Overflow, Flag::AuxiliaryCarry, Flag::Sign] {
+                    interp.context.set_flag(flag, true);
+                }
+
+                assert_eq!(interp.run(100), StepResult::Returned);
+                assert_eq!(interp.context.get_gp_reg(EAX), expected, "{} {}", lhs, rhs);
+                assert_eq!(interp.context.get_gp_reg(EBX), (expected != 0) as u32);
+                for flag in [Flag::Overflow, Flag::AuxiliaryCarry, Flag::Sign] {
+                    assert!(!interp.context.get_flag(flag));
+                }
+            }
+        }
+    }
+
+    fn lanes_to_xmm(lanes: [u32; 4]) -> u128 {
+        lanes
+            .iter()
+            .rev()
+            .fold(0, |acc, &lane| acc << 32 | lane as u128)
+    }
+
+    #[test_log::test]
+    fn packed_conversions() {
+        const INDEFINITE: u32 = 0x80000000;
+        let floats = lanes_to_xmm([1.75f32, -2.5, f32::NAN, 3e9].map(f32::to_bits));
+        let doubles = 2.5f64.to_bits() as u128 | ((-1e10f64).to_bits() as u128) << 64;
+
+        let code = assemble_x86!(
+            ; cvttps2dq xmm2, xmm0
+            ; cvtps2dq xmm3, xmm0
+            ; cvtpd2dq xmm4, xmm1
+            ; cvttpd2dq xmm5, OWORD [0x2000]
+            // round up
+            ; ldmxcsr [0x2010]
+            ; cvtps2dq xmm6, OWORD [0x2020]
+            ; cvtpd2dq xmm7, xmm1
+            ; stmxcsr [0x2014]
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        set_xmm(&mut interp, 0, floats);
+        set_xmm(&mut interp, 1, doubles);
+        set_xmm(&mut interp, 7, !0);
+        interp.memory[0x2000..0x2010].copy_from_slice(&doubles.to_le_bytes());
+        interp.memory[0x2010..0x2014].copy_from_slice(&0x5f80u32.to_le_bytes());
+        interp.memory[0x2020..0x2030].copy_from_slice(&floats.to_le_bytes());
+
+        assert_eq!(interp.run(8), StepResult::Continue);
+        let ints = |lanes: [i32; 2]| lanes_to_xmm([lanes[0] as u32, lanes[1] as u32, 0, 0]);
+        // the NaN & the one out of the range
+        let with_tail = |lanes: [i32; 2]| {
+            lanes_to_xmm([lanes[0] as u32, lanes[1] as u32, INDEFINITE, INDEFINITE])
+        };
+        assert_eq!(get_xmm(&interp, 2), with_tail([1, -2]));
+        // to nearest even
+        assert_eq!(get_xmm(&interp, 3), with_tail([2, -2]));
+        assert_eq!(get_xmm(&interp, 4), ints([2, INDEFINITE as i32]));
+        assert_eq!(get_xmm(&interp, 5), ints([2, INDEFINITE as i32]));
+        assert_eq!(get_xmm(&interp, 6), with_tail([2, -2]));
+        assert_eq!(get_xmm(&interp, 7), ints([3, INDEFINITE as i32]));
+        assert_eq!(interp.context.mxcsr, 0x5f80);
+        assert_eq!(interp.memory[0x2014..0x2018], 0x5f80u32.to_le_bytes());
+
+        let code = assemble_x86!(
+            ; cvtdq2ps xmm1, xmm0
+            ; cvtdq2pd xmm2, xmm0
+            ; cvtdq2pd xmm3, QWORD [0x2000]
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        let ints = lanes_to_xmm([-7i32 as u32, 0x7fffffff, 16777217, 1]);
+        set_xmm(&mut interp, 0, ints);
+        interp.memory[0x2000..0x2010].copy_from_slice(&ints.rotate_right(64).to_le_bytes());
+
+        assert_eq!(interp.run(3), StepResult::Continue);
+        // rounded to nearest
+        let floats = [-7.0f32, 2147483648.0, 16777216.0, 1.0];
+        assert_eq!(get_xmm(&interp, 1), lanes_to_xmm(floats.map(f32::to_bits)));
+        let doubles = |a: f64, b: f64| a.to_bits() as u128 | (b.to_bits() as u128) << 64;
+        assert_eq!(get_xmm(&interp, 2), doubles(-7.0, 2147483647.0));
+        assert_eq!(get_xmm(&interp, 3), doubles(16777217.0, 1.0));
+    }
+
+    #[test_log::test]
+    fn packed_shifts() {
+        // (lane, count, lane width) -> the shifted lane
+        type Model = fn(u64, u64, u32) -> u64;
+
+        let pattern = 0x8000_7fff_f00d_0001_c3a5_5a3c_0102_fe80u128;
+        // each lane of the pattern shifted by the model
+        let expected = |width: u32, count: u64, shift: Model| {
+            let mask = u64::MAX >> (64 - width);
+            (0..128 / width).fold(0u128, |acc, i| {
+                let lane = (pattern >> (i * width)) as u64 & mask;
+                acc | ((shift(lane, count, width) & mask) as u128) << (i * width)
+            })
+        };
+        fn left(lane: u64, count: u64, width: u32) -> u64 {
+            if count >= width as u64 {
+                0
+            } else {
+                lane << count
+            }
+        }
+        fn right(lane: u64, count: u64, width: u32) -> u64 {
+            if count >= width as u64 {
+                0
+            } else {
+                lane >> count
+            }
+        }
+        fn arithmetic(lane: u64, count: u64, width: u32) -> u64 {
+            let signed = (lane << (64 - width)) as i64 >> (64 - width);
+            (signed >> count.min(width as u64 - 1)) as u64
+        }
+
+        let shifts: [(Mnemonic, u32, Model); 8] = [
+            (Mnemonic::Psllw, 16, left),
+            (Mnemonic::Pslld, 32, left),
+            (Mnemonic::Psllq, 64, left),
+            (Mnemonic::Psrlw, 16, right),
+            (Mnemonic::Psrld, 32, right),
+            (Mnemonic::Psrlq, 64, right),
+            (Mnemonic::Psraw, 16, arithmetic),
+            (Mnemonic::Psrad, 32, arithmetic),
+        ];
+        for (mnemonic, width, shift) in shifts {
+            for count in [0, width as u8 - 1, width as u8, 200] {
+                let expected = expected(width, count as u64, shift);
+
+                // the immediate
+                let mut interp = interpreter(&[], NullHandler);
+                set_xmm(&mut interp, 0, pattern);
+                let operands = vec![Operand::Xmm(0), Operand::Immediate8(count)];
+                execute(&mut interp, mnemonic, operands);
+                assert_eq!(get_xmm(&interp, 0), expected, "{:?} {}", mnemonic, count);
+
+                // all the low 64 bits of a register count
+                set_xmm(&mut interp, 0, pattern);
+                set_xmm(&mut interp, 1, !0 << 64 | count as u128);
+                execute(
+                    &mut interp,
+                    mnemonic,
+                    vec![Operand::Xmm(0), Operand::Xmm(1)],
+                );
+                assert_eq!(get_xmm(&interp, 0), expected, "{:?} {}", mnemonic, count);
+            }
+            let mut interp = interpreter(&[], NullHandler);
+            set_xmm(&mut interp, 0, pattern);
+            set_xmm(&mut interp, 1, 1 << 32);
+            execute(
+                &mut interp,
+                mnemonic,
+                vec![Operand::Xmm(0), Operand::Xmm(1)],
+            );
+            assert_eq!(get_xmm(&interp, 0), expected(width, 1 << 32, shift));
+        }
+
+        for count in [0, 1, 7, 8, 9, 15, 16, 200] {
+            let bits = count as u32 * 8;
+            let mut interp = interpreter(&[], NullHandler);
+            set_xmm(&mut interp, 0, pattern);
+            set_xmm(&mut interp, 1, pattern);
+            execute(
+                &mut interp,
+                Mnemonic::Pslldq,
+                vec![Operand::Xmm(0), Operand::Immediate8(count)],
+            );
+            execute(
+                &mut interp,
+                Mnemonic::Psrldq,
+                vec![Operand::Xmm(1), Operand::Immediate8(count)],
+            );
+            assert_eq!(get_xmm(&interp, 0), pattern.checked_shl(bits).unwrap_or(0));
+            assert_eq!(get_xmm(&interp, 1), pattern.checked_shr(bits).unwrap_or(0));
+        }
+
+        // the decoded forms
+        let code = assemble_x86!(
+            ; psrad xmm0, 31
+            ; psllq xmm1, OWORD [0x2000]
+            ; psrldq xmm2, 9
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        for i in 0..3 {
+            set_xmm(&mut interp, i, pattern);
+        }
+        interp.memory[0x2000..0x2010].copy_from_slice(&4u128.to_le_bytes());
+        assert_eq!(interp.run(3), StepResult::Continue);
+        assert_eq!(get_xmm(&interp, 0), expected(32, 31, arithmetic));
+        assert_eq!(get_xmm(&interp, 1), expected(64, 4, left));
+        assert_eq!(get_xmm(&interp, 2), pattern >> 72);
+    }
+
+    #[test_log::test]
+    fn saturating_packs() {
+        let words = |lanes: [i16; 8]| words_to_xmm(lanes.map(|v| v as u16));
+        let bytes = |lanes: [i16; 16]| {
+            u128::from_le_bytes(lanes.map(|v| {
+                assert!((-128..=255).contains(&v));
+                v as u8
+            }))
+        };
+
+        // in range, the boundaries & around them, far out of the range
+        let dst = words([0, 127, 128, -128, -129, 0x7fff, -0x8000, -1]);
+        let src = words([5, -5, 300, -300, 126, -127, 255, 1]);
+        let code = assemble_x86!(
+            ; packsswb xmm0, xmm1
+            ; packuswb xmm2, OWORD [0x2000]
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        set_xmm(&mut interp, 0, dst);
+        set_xmm(&mut interp, 1, src);
+        set_xmm(&mut interp, 2, dst);
+        interp.memory[0x2000..0x2010].copy_from_slice(&src.to_le_bytes());
+
+        assert_eq!(interp.run(2), StepResult::Continue);
+        assert_eq!(
+            get_xmm(&interp, 0),
+            bytes([0, 127, 127, -128, -128, 127, -128, -1, 5, -5, 127, -128, 126, -127, 127, 1])
+        );
+        assert_eq!(
+            get_xmm(&interp, 2),
+            bytes([0, 127, 128, 0, 0, 255, 0, 0, 5, 0, 255, 0, 126, 0, 255, 1])
+        );
+
+        let dwords = |lanes: [i32; 4]| lanes_to_xmm(lanes.map(|v| v as u32));
+        let code = assemble_x86!(
+            ; packssdw xmm0, xmm1
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        set_xmm(&mut interp, 0, dwords([0, 32767, 32768, -32769]));
+        set_xmm(&mut interp, 1, dwords([-32768, 70000, -1, 12345]));
+
+        assert_eq!(interp.run(1), StepResult::Continue);
+        assert_eq!(
+            get_xmm(&interp, 0),
+            words([0, 32767, 32767, -32768, -32768, 32767, -1, 12345])
+        );
+    }
+
+    #[test_log::test]
+    fn mmx() {
+        let a = 0x80ff_7f01_1234_fffeu64;
+        let b = 0x8001_0101_edcc_0003u64;
+        let code = assemble_x86!(
+            ; movq mm0, [0x2000]
+            ; movq mm1, [0x2008]
+            ; movq mm2, mm0
+            ; paddb mm0, mm1
+            ; paddw mm2, [0x2008]
+            ; movq mm3, mm1
+            ; pand mm3, mm2
+            ; movq mm4, mm1
+            ; por mm4, mm2
+            ; pxor mm1, mm2
+            ; movd eax, mm0
+            ; mov ebx, -1
+            ; movd mm5, ebx
+            ; movq [0x2010], mm2
+            // the same thing on xmm registers
+            ; movq xmm0, [0x2000]
+            ; movd xmm1, [0x2008]
+            ; paddw xmm0, xmm1
+            ; pxor xmm1, xmm1
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.memory[0x2000..0x2008].copy_from_slice(&a.to_le_bytes());
+        interp.memory[0x2008..0x2010].copy_from_slice(&b.to_le_bytes());
+        set_xmm(&mut interp, 0, !0);
+        set_xmm(&mut interp, 1, !0);
+
+        assert_eq!(interp.run(18), StepResult::Continue);
+        let bytes_sum = u64::from_le_bytes(std::array::from_fn(|i| {
+            a.to_le_bytes()[i].wrapping_add(b.to_le_bytes()[i])
+        }));
+        let words_sum = 0x0100_8002_0000_0001u64;
+        let fpu_regs = interp.context.fpu_regs;
+        assert_eq!(fpu_regs[0], bytes_sum);
+        assert_eq!(fpu_regs[2], words_sum);
+        assert_eq!(fpu_regs[3], b & words_sum);
+        assert_eq!(fpu_regs[4], b | words_sum);
+        assert_eq!(fpu_regs[1], b ^ words_sum);
+        assert_eq!(interp.context.get_gp_reg(EAX), bytes_sum as u32);
+        assert_eq!(fpu_regs[5], 0xffff_ffff);
+        assert_eq!(interp.memory[0x2010..0x2018], words_sum.to_le_bytes());
+        // the upper halves are zeroed by the loads
+        assert_eq!(get_xmm(&interp, 0), 0x80ff_7f01_0000_0001);
+        assert_eq!(get_xmm(&interp, 1), 0);
+        // all the registers are valid & TOP is 0
+        assert_eq!(interp.context.fpu_tag, 0);
+        assert_eq!(interp.context.fpu_status, 0);
+    }
+
+    #[test_log::test]
+    fn mmx_then_x87() {
+        let mmx_then_sqrt = |emms: bool| {
+            let mut code = assemble_x86!(
+                ; movq mm0, [0x2000]
+                ; paddw mm0, mm0
+            );
+            if emms {
+                code.extend(assemble_x86!(
+                    ; emms
+                ));
+            }
+            code.extend(assemble_x86!(
+                ; fld QWORD [0x2008]
+                ; fsqrt
+                ; fstp QWORD [0x2010]
+                ; ret
+            ));
+
+            let mut interp = interpreter(&code, NullHandler);
+            interp.memory[0x2008..0x2010].copy_from_slice(&16.0f64.to_le_bytes());
+            assert_eq!(interp.run(100), StepResult::Returned);
+            interp
+        };
+
+        let interp = mmx_then_sqrt(true);
+        assert_eq!(interp.memory[0x2010..0x2018], 4.0f64.to_le_bytes());
+        assert_eq!(interp.context.fpu_tag, 0xffff);
+
+        // the stack is full of the mmx values, so the load overflows
+        let interp = mmx_then_sqrt(false);
+        let result = f64::from_le_bytes(interp.memory[0x2010..0x2018].try_into().unwrap());
+        assert!(result.is_nan());
+        // invalid operation, stack fault & C1
+        assert_eq!(interp.context.fpu_status & 0x241, 0x241);
+        // only the popped register is empty
+        assert_eq!(interp.context.fpu_tag, 0b11 << 14);
+    }
+
+    #[test_log::test]
+    fn fpu_status_word() {
+        let code = [
+            0x9b, 0xdf, 0xe0, // fstsw ax
+            0xd9, 0xe8, // fld1
+            0x9b, // wait
+            0xdf, 0xe0, // fnstsw ax
+            0xdd, 0x3d, 0x00, 0x20, 0x00, 0x00, // fnstsw [0x2000]
+            0xc3, // ret
+        ];
+        let mut interp = interpreter(&code, NullHandler);
+        interp.context.set_gp_reg(EAX, 0xdead0000);
+        interp.context.fpu_status = 0x4100;
+
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0xdead4100);
+        assert_eq!(interp.context.eip, CODE_ADDR + 3);
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        // TOP is 7 after the push
+        assert_eq!(interp.context.get_gp_reg(EAX), 0xdead7900);
+        assert_eq!(interp.memory[0x2000..0x2002], 0x7900u16.to_le_bytes());
+    }
+
+    #[derive(Default)]
+    struct Ports {
+        written: Vec<(u16, IntType, u32)>,
+    }
+
+    impl RuntimeHandler for Ports {
+        fn port_in(&mut self, _: &mut CpuContext, port: u16, _: IntType) -> u32 {
+            port as u32 + 1
+        }
+
+        fn port_out(&mut self, _: &mut CpuContext, port: u16, size: IntType, value: u32) {
+            self.written.push((port, size, value))
+        }
+    }
+
+    #[test_log::test]
+    fn handlers() {
+        let code = assemble_x86!(
+            ; in al, 0x10
+            ; mov dx, 0x20
+            ; out dx, al
+            ; int 0x21
+            ; mov eax, 1
+        );
+        let mut interp = interpreter(&code, Ports::default());
+
+        assert_eq!(interp.run(100), StepResult::HostRequest);
+        assert_eq!(interp.handler.written, vec![(0x20, IntType::I8, 0x11)]);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x11);
+        // right after the int
+        assert_eq!(interp.context.eip, CODE_ADDR + 2 + 4 + 1 + 2);
+    }
+
+    #[test_log::test]
+    fn string_ports() {
+        let code = assemble_x86!(
+            ; mov esi, 0x4000
+            ; mov DWORD [esi], 0x0403_0201
+            ; mov dx, 0x20
+            ; mov ecx, 3
+            ; rep outsb
+            ; mov edi, 0x4010
+            ; mov dx, 0x30
+            ; insw
+            ; outsd
+            ; ret
+        );
+        let mut interp = interpreter(&code, Ports::default());
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(
+            interp.handler.written,
+            vec![
+                (0x20, IntType::I8, 1),
+                (0x20, IntType::I8, 2),
+                (0x20, IntType::I8, 3),
+                (0x30, IntType::I32, 0x04),
+            ]
+        );
+        assert_eq!(interp.memory[0x4010..0x4012], 0x31u16.to_le_bytes());
+        assert_eq!(interp.context.get_gp_reg(ECX), 0);
+        assert_eq!(interp.context.get_gp_reg(EDI), 0x4012);
+        assert_eq!(interp.context.get_gp_reg(ESI), 0x4007);
+    }
+
+    #[test_log::test]
+    fn edx_eax_pair() {
+        let mut interp = interpreter(&[], NullHandler);
+
+        for value in [
+            0,
+            1,
+            0xffff_ffff,
+            0x1_0000_0000,
+            0x8000_0000_0000_0001,
+            0x7fff_ffff_8000_0000,
+            u64::MAX,
+        ] {
+            interp.context.set_gp_reg(EDX, (value >> 32) as u32);
+            interp.context.set_gp_reg(EAX, value as u32);
+            let read = interp.read_edx_eax();
+            assert_eq!(read.ty, IntType::I64);
+            assert_eq!(read.bits, value);
+
+            interp.context.set_gp_reg(EDX, 0xdead);
+            interp.context.set_gp_reg(EAX, 0xbeef);
+            let value = interp.make_u64(value);
+            interp.write_edx_eax(value);
+            assert_eq!(interp.context.get_gp_reg(EDX), (value.bits >> 32) as u32);
+            assert_eq!(interp.context.get_gp_reg(EAX), value.bits as u32);
+        }
+    }
+
+    #[test_log::test]
+    fn esp_operands() {
+        let code = assemble_x86!(
+            ; push esp
+            ; pop eax
+            ; push DWORD 0x1234
+            ; push DWORD 0x5678
+            ; pop DWORD [esp]
+            ; pop ebx
+            ; lea ecx, [esp - 0x10]
+            ; push ecx
+            ; pop esp
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        assert_eq!(interp.run(100), StepResult::Continue);
+        // the value before the push
+        assert_eq!(interp.context.get_gp_reg(EAX), STACK_TOP - 4);
+        // pop [esp] addresses the memory after the increment
+        assert_eq!(interp.context.get_gp_reg(EBX), 0x5678);
+        // pop esp keeps the popped value, not the incremented one
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP - 4 - 0x10);
+    }
+
+    #[test_log::test]
+    fn call_through_stack() {
+        let code = assemble_x86!(
+            ; mov DWORD [esp - 4], CODE_ADDR as i32 + 13
+            ; call DWORD [esp - 4]
+            ; ret
+            ; mov eax, [esp]
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        // the pointer got overwritten by the return address only after being read
+        assert_eq!(interp.context.get_gp_reg(EAX), CODE_ADDR + 12);
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP);
+    }
+
+    #[test_log::test]
+    fn esp_operands_stack_contents() {
+        let code = assemble_x86!(
+            ; push DWORD CODE_ADDR as i32 + 16
+            ; push DWORD 0x1111
+            // reads the pointer before pushing the return address over the 0x1111
+            ; call DWORD [esp + 4]
+            ; jmp BYTE ->end
+            // CODE_ADDR + 16
+            ; mov ebx, [esp]
+            ; ret
+            ; ->end:
+            // duplicates the top of the stack
+            ; push DWORD [esp]
+            // the address is computed with ESP already incremented, so it replaces the pointer
+            ; pop DWORD [esp + 4]
+            ; add esp, 8
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        // the address after `call [esp + 4]`
+        assert_eq!(interp.context.get_gp_reg(EBX), CODE_ADDR + 14);
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP);
+
+        let stack = &interp.memory[(STACK_TOP - 16) as usize..STACK_TOP as usize];
+        let words: Vec<u32> = stack
+            .chunks(4)
+            .map(|w| u32::from_le_bytes(w.try_into().unwrap()))
+            .collect();
+        // the return address got overwritten by `push [esp]`, the pointer by `pop [esp + 4]`
+        assert_eq!(words, vec![0x1111, 0x1111, 0x1111, 0]);
+    }
+
+    #[derive(Default)]
+    struct Syscalls {
+        // (eax, ecx, edx, esp, eip) as seen by the handler
+        seen: Vec<(u32, u32, u32, u32, u32)>,
+        // emulate the kernel's sysexit to here
+        sysexit_to: Option<u32>,
+    }
+
+    impl RuntimeHandler for Syscalls {
+        fn fast_syscall(&mut self, ctx: &mut CpuContext) {
+            self.seen.push((
+                ctx.get_gp_reg(EAX),
+                ctx.get_gp_reg(ECX),
+                ctx.get_gp_reg(EDX),
+                ctx.get_gp_reg(ESP),
+                ctx.eip,
+            ));
+            ctx.set_gp_reg(EAX, 0x1000);
+            if let Some(target) = self.sysexit_to {
+                ctx.eip = target;
+                ctx.set_gp_reg(ESP, ctx.get_gp_reg(ECX));
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn fast_syscalls() {
+        let code = assemble_x86!(
+            ; mov eax, 0x25
+            ; mov edx, esp
+            ; sysenter
+            ; add eax, 1
+            ; ret
+            ; add eax, 2
+            ; ret
+        );
+        let after_sysenter = CODE_ADDR + 5 + 2 + 2;
+
+        let mut interp = interpreter(&code, Syscalls::default());
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(
+            interp.handler.seen,
+            vec![(0x25, 0, STACK_TOP - 4, STACK_TOP - 4, after_sysenter)]
+        );
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x1001);
+
+        // the handler returns somewhere else, with the stack from ECX
+        let mut interp = interpreter(&code, Syscalls::default());
+        interp.handler.sysexit_to = Some(after_sysenter + 4);
+        interp.context.set_gp_reg(ECX, STACK_TOP - 0x100);
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x1002);
+        // popped the return address from the new stack
+        assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP - 0x100 + 4);
+
+        // nobody to handle it
+        let mut interp = interpreter(&code, NullHandler);
+        assert_eq!(interp.run(100), StepResult::HostRequest);
+        assert_eq!(interp.context.eip, after_sysenter);
+    }
+
+    struct LinuxExit;
+
+    impl RuntimeHandler for LinuxExit {
+        fn interrupt(&mut self, ctx: &mut CpuContext, vector: u8) {
+            if !crate::handler::linux_exit_syscall(ctx, vector) {
+                ctx.exit = EXIT_HOST_REQUEST;
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn guest_exit() {
+        // exits from `outer` with ECX = 1, from `inner` it calls otherwise
+        let code = assemble_x86!(
+            ; call ->outer
+            ; ret
+            ; ->outer:
+            ; cmp ecx, 1
+            ; je ->exit
+            ; call ->inner
+            ; ret
+            ; ->inner:
+            ; ->exit:
+            ; mov ebx, 42
+            ; mov eax, 1
+            // int 0x80, dynasm takes the vector as a signed byte
+            ; .bytes [0xcd_u8, 0x80].iter().copied()
+            ; ud2
+        );
+
+        let mut interp = interpreter(&code, LinuxExit);
+        for depth in [1, 2, 1] {
+            interp.context.eip = CODE_ADDR;
+            interp.context.set_gp_reg(ESP, STACK_TOP - 4);
+            interp.context.set_gp_reg(ECX, depth);
+            assert_eq!(interp.run(100), StepResult::GuestExit { code: 42 });
+            // the calls in progress are just left there
+            assert_eq!(interp.context.get_gp_reg(ESP), STACK_TOP - 4 - 4 * depth);
+            assert_eq!(interp.context.exit_code, 42);
+        }
+
+        // any other syscall still goes to the host
+        let mut interp = interpreter(&code, LinuxExit);
+        interp.context.eip = CODE_ADDR + code.len() as u32 - 4;
+        interp.context.set_gp_reg(EAX, 4);
+        assert_eq!(interp.run(100), StepResult::HostRequest);
+    }
+
+    #[derive(Default)]
+    struct Checkpoints {
+        seen: Vec<CpuContext>,
+    }
+
+    impl RuntimeHandler for Checkpoints {
+        fn interrupt(&mut self, ctx: &mut CpuContext, vector: u8) {
+            if !crate::handler::record_checkpoint(ctx, vector, &mut self.seen) {
+                ctx.exit = EXIT_HOST_REQUEST;
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn checkpoints() {
+        let code = assemble_x86!(
+            ; mov ecx, 2
+            ; ->top:
+            ; .bytes crate::handler::CHECKPOINT_INSTRUCTION.iter().copied()
+            ; dec ecx
+            ; jnz ->top
+            ; int3
+        );
+
+        let mut interp = interpreter(&code, Checkpoints::default());
+        assert_eq!(interp.run(100), StepResult::HostRequest);
+        let seen: Vec<_> = interp
+            .handler
+            .seen
+            .iter()
+            .map(|ctx| (ctx.get_gp_reg(ECX), ctx.get_flag(Flag::Zero), ctx.eip))
+            .collect();
+        let after = CODE_ADDR + 5 + 2;
+        assert_eq!(seen, vec![(2, false, after), (1, false, after)]);
+        // the rest of the interrupts are not
+        assert_eq!(interp.context.eip, CODE_ADDR + code.len() as u32);
+    }
+
+    #[test_log::test]
+    fn sysexit() {
+        let mut interp = interpreter(&[], NullHandler);
+        interp.context.set_gp_reg(ECX, 0x4000);
+        interp.context.set_gp_reg(EDX, 0x2345);
+
+        execute(&mut interp, Mnemonic::Sysexit, vec![]);
+        assert_eq!(interp.context.get_gp_reg(ESP), 0x4000);
+        assert_eq!(interp.context.eip, 0x2345);
+    }
+
+    #[test_log::test]
+    fn accumulator_forms() {
+        // dynasm picks the short accumulator-immediate encodings, so the ModRM ones are spelled out
+        let pairs: Vec<(Vec<u8>, &[u8])> = vec![
+            (assemble_x86!(; add al, 0x7f), &[0x80, 0xc0, 0x7f]),
+            (
+                assemble_x86!(; add ax, 0x7ff0),
+                &[0x66, 0x81, 0xc0, 0xf0, 0x7f],
+            ),
+            (
+                assemble_x86!(; add eax, 0x7ffffff0),
+                &[0x81, 0xc0, 0xf0, 0xff, 0xff, 0x7f],
+            ),
+            (assemble_x86!(; and al, -0x7f), &[0x80, 0xe0, 0x81]),
+            (
+                assemble_x86!(; and ax, -0x7fff),
+                &[0x66, 0x81, 0xe0, 0x01, 0x80],
+            ),
+            (
+                assemble_x86!(; and eax, -0x7fffffff),
+                &[0x81, 0xe0, 0x01, 0x00, 0x00, 0x80],
+            ),
+            (assemble_x86!(; cmp al, 0x10), &[0x80, 0xf8, 0x10]),
+            (
+                assemble_x86!(; cmp ax, 0x1000),
+                &[0x66, 0x81, 0xf8, 0x00, 0x10],
+            ),
+            (
+                assemble_x86!(; cmp eax, 0x10000000),
+                &[0x81, 0xf8, 0x00, 0x00, 0x00, 0x10],
+            ),
+            (assemble_x86!(; test al, -0x80), &[0xf6, 0xc0, 0x80]),
+            (
+                assemble_x86!(; test ax, -0x8000),
+                &[0x66, 0xf7, 0xc0, 0x00, 0x80],
+            ),
+            (
+                assemble_x86!(; test eax, -0x80000000),
+                &[0xf7, 0xc0, 0x00, 0x00, 0x00, 0x80],
+            ),
+            (
+                assemble_x86!(; or eax, 0x100),
+                &[0x81, 0xc8, 0x00, 0x01, 0x00, 0x00],
+            ),
+            (
+                assemble_x86!(; sub eax, 0x100),
+                &[0x81, 0xe8, 0x00, 0x01, 0x00, 0x00],
+            ),
+            (
+                assemble_x86!(; xor eax, 0x100),
+                &[0x81, 0xf0, 0x00, 0x01, 0x00, 0x00],
+            ),
+            (
+                assemble_x86!(; sbb eax, 0x100),
+                &[0x81, 0xd8, 0x00, 0x01, 0x00, 0x00],
+            ),
+        ];
+
+        let decode = |code: &[u8]| Decoder::new(code, CODE_ADDR).decode().unwrap();
+
+        for (short, general) in pairs {
+            let short_instr = decode(&short);
+            let general_instr = decode(general);
+            assert!(
+                short.len() < general.len(),
+                "{:02x?} is not the short form",
+                short
+            );
+            assert_eq!(short_instr.mnemonic, general_instr.mnemonic);
+            assert_eq!(short_instr.operands, general_instr.operands);
+
+            for eax in [0, 0x7f, 0x8000_7ff0, 0xffff_ffff] {
+                let run = |code: &[u8]| {
+                    let mut interp = interpreter(code, NullHandler);
+                    interp.context.set_gp_reg(EAX, eax);
+                    interp.context.set_flag(Flag::Carry, true);
+                    assert_eq!(interp.step(), StepResult::Continue);
+                    interp.context.eip = 0;
+                    interp.context
+                };
+                assert_eq!(
+                    run(&short),
+                    run(general),
+                    "{} with eax = 0x{:x}",
+                    short_instr,
+                    eax
+                );
+            }
+        }
+    }
+
+    #[test_log::test]
+    fn lea_forms() {
+        let code = assemble_x86!(
+            ; lea eax, [ebx*8 + 4]
+            ; lea ecx, [esp + 8]
+            ; lea edx, [ebp]
+            ; lea si, [ebx + edi*4 + 0x20]
+            ; lea edi, [ebx + edi*4 - 0x10]
+            ; ret
+        );
+
+        let mut interp = interpreter(&code, NullHandler);
+        interp.context.set_gp_reg(EBX, 0xffff_fff0);
+        interp.context.set_gp_reg(EBP, 0x1234);
+        interp.context.set_gp_reg(ESI, 0xaaaa_aaaa);
+        interp.context.set_gp_reg(EDI, 0x4000_0005);
+        for flag in [Flag::Carry, Flag::Zero, Flag::Sign, Flag::Overflow] {
+            interp.context.set_flag(flag, true);
+        }
+
+        assert_eq!(interp.run(100), StepResult::Returned);
+        // wraps around
+        assert_eq!(interp.context.get_gp_reg(EAX), 0xffff_ff84);
+        assert_eq!(interp.context.get_gp_reg(ECX), STACK_TOP - 4 + 8);
+        assert_eq!(interp.context.get_gp_reg(EDX), 0x1234);
+        // 0xffff_fff0 + 0x1_0000_0014 + 0x20, the upper half of ESI is kept
+        assert_eq!(interp.context.get_gp_reg(ESI), 0xaaaa_0024);
+        assert_eq!(interp.context.get_gp_reg(EDI), 0xffff_fff4);
+        for flag in [Flag::Carry, Flag::Zero, Flag::Sign, Flag::Overflow] {
+            assert!(interp.context.get_flag(flag), "{:?}", flag);
+        }
+    }
+
+    #[derive(Default)]
+    struct UndefinedReads {
+        // (flag, source, eip of the reader)
+        seen: Vec<(Flag, u32, u32)>,
+    }
+
+    impl RuntimeHandler for UndefinedReads {
+        fn undefined_flag(&mut self, ctx: &mut CpuContext, flag: Flag, source: u32) {
+            self.seen.push((flag, source, ctx.eip));
+        }
+    }
+
+    #[test_log::test]
+    fn undefined_flags() {
+        // ZF is undefined after imul, so the jz depends on it
+        let code = assemble_x86!(
+            ; mov eax, 3
+            ; mov ecx, 0
+            ; imul ecx
+            ; jz ->zero
+            ; mov ebx, 1
+            ; ret
+            ; ->zero:
+            ; mov ebx, 2
+            ; ret
+        );
+        let imul_addr = CODE_ADDR + 5 + 5;
+        let jz_addr = imul_addr + 2;
+
+        // relaxed: ZF is cleared, even though the result is zero
+        let mut interp = interpreter(&code, NullHandler);
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(EBX), 1);
+
+        // strict: the read is reported and gives false
+        let mut interp = interpreter(&code, UndefinedReads::default());
+        interp.undefined_flags = UndefinedFlagsPolicy::Strict;
+        // the poison needs a byte per flag
+        interp.context.set_flag_storage(FlagStorage::Bytes);
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.handler.seen, vec![(Flag::Zero, imul_addr, jz_addr)]);
+        assert_eq!(interp.context.get_gp_reg(EBX), 1);
+
+        // nobody expects it
+        let mut interp = interpreter(&code, NullHandler);
+        interp.undefined_flags = UndefinedFlagsPolicy::Strict;
+        interp.context.set_flag_storage(FlagStorage::Bytes);
+        assert_eq!(interp.run(100), StepResult::HostRequest);
+
+        // the defined flags are still fine to read
+        let mut interp = interpreter(&code, UndefinedReads::default());
+        interp.undefined_flags = UndefinedFlagsPolicy::Strict;
+        interp.context.set_flag_storage(FlagStorage::Bytes);
+        let imul = Instr::new(
+            CODE_ADDR,
+            2,
+            Mnemonic::Imul,
+            vec![Operand::Register(Register::ECX)],
+        );
+        assert_eq!(interp.execute(&imul), StepResult::Continue);
+        let jo = Instr::new(
+            CODE_ADDR + 2,
+            2,
+            Mnemonic::Jcc(Condition::O),
+            vec![Operand::Immediate32(0x1234)],
+        );
+        assert_eq!(interp.execute(&jo), StepResult::Continue);
+        assert_eq!(interp.context.eip, CODE_ADDR + 4);
+        assert!(interp.handler.seen.is_empty());
+    }
+
+    #[test_log::test]
+    fn imul_memory_source() {
+        let code16 = assemble_x86!(
+            ; imul ax, WORD [0x100]
+            ; ret
+        );
+        let code32 = assemble_x86!(
+            ; imul eax, DWORD [0x100]
+            ; ret
+        );
+        let run = |code: &[u8], lhs: u32, rhs: u32| {
+            let mut interp = interpreter(code, NullHandler);
+            interp.context.set_gp_reg(EAX, lhs);
+            interp.memory[0x100..0x104].copy_from_slice(&rhs.to_le_bytes());
+            assert_eq!(interp.run(100), StepResult::Returned);
+            let overflow = interp.context.get_flag(Flag::Overflow);
+            assert_eq!(interp.context.get_flag(Flag::Carry), overflow);
+            (interp.context.get_gp_reg(EAX), overflow)
+        };
+
+        // around the products that just fit & just don't: 0x7fff, 0x8000 and their negations
+        let values16 = [
+            1i16, -1, 2, -2, 0xb5, -0xb5, 0xb6, -0xb6, 0x100, 0x80, -0x80, 0x4000, -0x4000, 0x7fff,
+            -0x8000,
+        ];
+        for &lhs in &values16 {
+            for &rhs in &values16 {
+                let product = lhs as i32 * rhs as i32;
+                // the upper half of EAX stays
+                let expected = 0xabcd_0000 | (product as u16 as u32);
+                assert_eq!(
+                    run(&code16, 0xabcd_0000 | lhs as u16 as u32, rhs as u16 as u32),
+                    (expected, product != product as i16 as i32),
+                    "imul {:#x}, {:#x}",
+                    lhs,
+                    rhs
+                );
+            }
+        }
+
+        // the three-operand form, with the immediate sign-extended to 16 bits
+        let code = assemble_x86!(
+            ; imul cx, WORD [0x100], -2
+            ; ret
+        );
+        let mut interp = interpreter(&code, NullHandler);
+        interp.memory[0x100..0x102].copy_from_slice(&0x4000u16.to_le_bytes());
+        assert_eq!(interp.run(100), StepResult::Returned);
+        assert_eq!(interp.context.get_gp_reg(ECX) as u16, 0x8000);
+        assert!(!interp.context.get_flag(Flag::Overflow));
+
+        for (lhs, rhs) in [
+            (0x10000i32, 0x8000i32),
+            (0x10000, -0x8000),
+            (-0x10000, 0x8000),
+            (0xb505, 0xb504),
+            (0xb505, 0xb505),
+            (-1, i32::MIN),
+        ] {
+            let product = lhs as i64 * rhs as i64;
+            assert_eq!(
+                run(&code32, lhs as u32, rhs as u32),
+                (product as u32, product != product as i32 as i64),
+                "imul {:#x}, {:#x}",
+                lhs,
+                rhs
+            );
+        }
+    }
+
+    fn execute(interp: &mut Interpreter<NullHandler>, mnemonic: Mnemonic, operands: Vec<Operand>) {
+        // the length doesn't matter much, as long as it's consistent
+        let instr = Instr::new(interp.context.eip, 2, mnemonic, operands);
+        assert_eq!(interp.execute(&instr), StepResult::Continue);
+    }
+
+    #[test_log::test]
+    fn constructed_instructions() {
+        let mut interp = interpreter(&[], NullHandler);
+
+        execute(
+            &mut interp,
+            Mnemonic::Mov,
+            vec![
+                Operand::Register(Register::EAX),
+                Operand::Immediate32(0x7fffffff),
+            ],
+        );
+        execute(
+            &mut interp,
+            Mnemonic::Add,
+            vec![Operand::Register(Register::EAX), Operand::Immediate32(1)],
+        );
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x80000000);
+        assert!(interp.context.get_flag(Flag::Overflow));
+        assert!(interp.context.get_flag(Flag::Sign));
+        assert!(!interp.context.get_flag(Flag::Carry));
+        assert_eq!(interp.context.eip, CODE_ADDR + 4);
+
+        let mem = MemoryOperand {
+            base: Some(Register::EBX),
+            index: None,
+            scale: 1,
+            displacement: 0x10,
+            size: Some(IntType::I16),
+            segment: None,
+        };
+        execute(
+            &mut interp,
+            Mnemonic::Mov,
+            vec![
+                Operand::Register(Register::EBX),
+                Operand::Immediate32(0x200),
+            ],
+        );
+        execute(
+            &mut interp,
+            Mnemonic::Mov,
+            vec![Operand::Memory(mem), Operand::Immediate16(0xbeef)],
+        );
+        assert_eq!(interp.memory[0x210..0x212], [0xef, 0xbe]);
+
+        // ZF is clear after the add
+        execute(
+            &mut interp,
+            Mnemonic::Cmovcc(Condition::NE),
+            vec![
+                Operand::Register(Register::ECX),
+                Operand::Register(Register::EAX),
+            ],
+        );
+        assert_eq!(interp.context.get_gp_reg(ECX), 0x80000000);
+
+        let jump = Instr::new(
+            interp.context.eip,
+            2,
+            Mnemonic::Jcc(Condition::S),
+            vec![Operand::Immediate32(0x1234)],
+        );
+        assert_eq!(interp.execute(&jump), StepResult::Continue);
+        assert_eq!(interp.context.eip, 0x1234);
+    }
+
+    #[test_log::test]
+    fn constructed_rep_stos() {
+        let mut interp = interpreter(&[], NullHandler);
+        interp.context.set_gp_reg(EAX, 0xaa);
+        interp.context.set_gp_reg(ECX, 3);
+        interp.context.set_gp_reg(EDI, 0x300);
+
+        let stos = Instr::new(
+            CODE_ADDR,
+            2,
+            Mnemonic::Stos,
+            vec![
+                Operand::Memory(MemoryOperand {
+                    base: Some(Register::EDI),
+                    index: None,
+                    scale: 0,
+                    displacement: 0,
+                    size: Some(IntType::I8),
+                    segment: Some(SegmentRegister::ES),
+                }),
+                Operand::Register(Register::AL),
+            ],
+        )
+        .with_prefixes(Prefixes::REP);
+
+        assert_eq!(interp.execute(&stos), StepResult::Continue);
+        assert_eq!(interp.memory[0x2ff..0x304], [0, 0xaa, 0xaa, 0xaa, 0]);
+        assert_eq!(interp.context.get_gp_reg(ECX), 0);
+        assert_eq!(interp.context.get_gp_reg(EDI), 0x303);
+    }
+
+    #[test_log::test]
+    fn string_ops_16bit_addresses() {
+        // with the 0x67 prefix the string instructions use SI, DI & CX: the upper halves stay as they are
+        #[rustfmt::skip]
+        let code = [
+            0x67, 0xf3, 0xa4, // rep movsb
+            0x67, 0xaa,       // stosb
+            0x67, 0x66, 0xad, // lodsw
+            0x67, 0xf2, 0xae, // repne scasb
+        ];
+        let mut memory = vec![0; 0x10000];
+        memory[CODE_ADDR as usize..][..code.len()].copy_from_slice(&code);
+        memory[0x2000..0x2003].copy_from_slice(&[1, 2, 3]);
+        memory[0xfffe] = 0x41;
+        let mut interp = Interpreter::new(memory, NullHandler);
+        interp.context.eip = CODE_ADDR;
+
+        // the count is in CX, ECX would take forever
+        interp.context.set_gp_reg(ESI, 0x1234_2000);
+        interp.context.set_gp_reg(EDI, 0x5678_3000);
+        interp.context.set_gp_reg(ECX, 0xabcd_0003);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.memory[0x3000..0x3004], [1, 2, 3, 0]);
+        assert_eq!(interp.context.get_gp_reg(ESI), 0x1234_2003);
+        assert_eq!(interp.context.get_gp_reg(EDI), 0x5678_3003);
+        assert_eq!(interp.context.get_gp_reg(ECX), 0xabcd_0000);
+
+        // DI wraps around from 0xffff
+        interp.context.set_gp_reg(EDI, 0x5678_ffff);
+        interp.context.set_gp_reg(EAX, 0x42);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.memory[0xffff], 0x42);
+        assert_eq!(interp.context.get_gp_reg(EDI), 0x5678_0000);
+
+        interp.context.set_gp_reg(ESI, 0x1234_fffe);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert_eq!(interp.context.get_gp_reg(EAX), 0x4241);
+        assert_eq!(interp.context.get_gp_reg(ESI), 0x1234_0000);
+
+        // stops on the second byte, having gone past the end of the 64 KiB
+        interp.context.set_gp_reg(EAX, 0x42);
+        interp.context.set_gp_reg(EDI, 0x5678_fffe);
+        interp.context.set_gp_reg(ECX, 0x7777_0010);
+        assert_eq!(interp.step(), StepResult::Continue);
+        assert!(interp.context.get_flag(Flag::Zero));
+        assert_eq!(interp.context.get_gp_reg(EDI), 0x5678_0000);
+        assert_eq!(interp.context.get_gp_reg(ECX), 0x7777_000e);
+    }
+
+    #[test_log::test]
+    fn diff_json() {
+        let before = CpuContext::default();
+        assert_eq!(context_diff_json(&before, &before), "{}");
+
+        let mut after = before.clone();
+        after.set_gp_reg(EAX, 42);
+        after.set_gp_reg(EDI, 1);
+        after.set_flag(Flag::Zero, true);
+        after.set_flag(Flag::Carry, true);
+        after.eip = 0x1005;
+
+        assert_eq!(
+            context_diff_json(&before, &after),
+            r#"{"eax":[0,42],"edi":[0,1],"flags":{"CF":[false,true],"ZF":[false,true]},"eip":[0,4101]}"#
+        );
+
+        let mut after = before.clone();
+        after.set_flag(Flag::Sign, true);
+        assert_eq!(
+            context_diff_json(&before, &after),
+            r#"{"flags":{"SF":[false,true]}}"#
+        );
+    }
+}
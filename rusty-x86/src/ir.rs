@@ -0,0 +1,1864 @@
+//! Our own representation of a decoded instruction
+//!
+//! The codegen consumes `Instr` instead of `iced_x86::Instruction`, so instructions can be constructed by hand
+//! (in tests, for example) and iced is only needed to decode the bytes
+
+use std::fmt::{Display, Formatter};
+
+use bitflags::bitflags;
+use iced_x86::{ConditionCode, DecoderError, Instruction, Mnemonic as IcedMnemonic};
+use log::warn;
+use strum_macros::EnumIter;
+
+use crate::disasm::Operands;
+use crate::types::{IntType, Operand};
+
+/// Condition tested by `jcc` & `cmovcc`. Names are the canonical ones used by the Intel manual, the order is the one of
+/// the encodings (the low nibble of the `jcc` opcode)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, EnumIter)]
+pub enum Condition {
+    O,
+    NO,
+    B,
+    AE,
+    E,
+    NE,
+    BE,
+    A,
+    S,
+    NS,
+    P,
+    NP,
+    L,
+    GE,
+    LE,
+    G,
+}
+
+impl Condition {
+    pub fn name(self) -> &'static str {
+        use Condition::*;
+        match self {
+            O => "o",
+            NO => "no",
+            B => "b",
+            AE => "ae",
+            E => "e",
+            NE => "ne",
+            BE => "be",
+            A => "a",
+            S => "s",
+            NS => "ns",
+            P => "p",
+            NP => "np",
+            L => "l",
+            GE => "ge",
+            LE => "le",
+            G => "g",
+        }
+    }
+
+    /// The outcome of the condition for known CF, ZF, SF, OF & PF
+    pub fn evaluate(
+        self,
+        carry: bool,
+        zero: bool,
+        sign: bool,
+        overflow: bool,
+        parity: bool,
+    ) -> bool {
+        use Condition::*;
+        match self {
+            O => overflow,
+            NO => !overflow,
+            B => carry,
+            AE => !carry,
+            E => zero,
+            NE => !zero,
+            BE => carry || zero,
+            A => !carry && !zero,
+            S => sign,
+            NS => !sign,
+            P => parity,
+            NP => !parity,
+            L => sign != overflow,
+            GE => sign == overflow,
+            LE => zero || sign != overflow,
+            G => !zero && sign == overflow,
+        }
+    }
+
+    fn from_iced(cc: ConditionCode) -> Option<Self> {
+        use Condition::*;
+        Some(match cc {
+            ConditionCode::None => return None,
+            ConditionCode::o => O,
+            ConditionCode::no => NO,
+            ConditionCode::b => B,
+            ConditionCode::ae => AE,
+            ConditionCode::e => E,
+            ConditionCode::ne => NE,
+            ConditionCode::be => BE,
+            ConditionCode::a => A,
+            ConditionCode::s => S,
+            ConditionCode::ns => NS,
+            ConditionCode::p => P,
+            ConditionCode::np => NP,
+            ConditionCode::l => L,
+            ConditionCode::ge => GE,
+            ConditionCode::le => LE,
+            ConditionCode::g => G,
+        })
+    }
+}
+
+bitflags! {
+    pub struct Prefixes: u8 {
+        /// `rep`/`repe` (they are encoded the same way, the meaning depends on the instruction)
+        const REP = 0b0001;
+        const REPNE = 0b0010;
+        const LOCK = 0b0100;
+    }
+}
+
+impl Prefixes {
+    /// The prefixes that actually change what `instr` does, in a form that doesn't depend on how they were encoded.
+    ///
+    /// iced already resolves the ordering: of repeated or conflicting prefixes (`66 66`, two segment overrides,
+    /// `f2 f3`) the last one wins, like on hardware, and LOCK where it raises #UD makes the whole instruction invalid.
+    /// What's left is dropping what the CPU ignores
+    fn canonical(instr: &Instruction, mnemonic: Mnemonic) -> Self {
+        let mut prefixes = Prefixes::empty();
+        // only lockable instructions with a memory destination get here
+        prefixes.set(Prefixes::LOCK, instr.has_lock_prefix());
+
+        // everywhere else f2/f3 are either part of the opcode (lzcnt, pause, movss) and iced doesn't report them,
+        // or ignored (`rep ret`, xacquire/xrelease on CPUs without TSX)
+        if mnemonic.is_string() {
+            use Mnemonic::*;
+            match mnemonic {
+                Scas | Cmps => {
+                    prefixes.set(Prefixes::REP, instr.has_rep_prefix());
+                    prefixes.set(Prefixes::REPNE, instr.has_repne_prefix());
+                }
+                // there's no "while not equal" for the ones that don't compare, f2 repeats them just like f3
+                _ => prefixes.set(
+                    Prefixes::REP,
+                    instr.has_rep_prefix() || instr.has_repne_prefix(),
+                ),
+            }
+        }
+        prefixes
+    }
+}
+
+/// The instructions the codegen knows about
+///
+/// String instructions don't have the size in the name: it's the size of their memory operands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Mnemonic {
+    Nop,
+    Mov,
+    Movzx,
+    Movsx,
+    Add,
+    Adc,
+    Sub,
+    Cmp,
+    Sbb,
+    Lea,
+    Dec,
+    Inc,
+    Neg,
+    Cbw,
+    Cwde,
+    Cwd,
+    Cdq,
+    Mul,
+    Imul,
+    Xor,
+    Not,
+    And,
+    Test,
+    Or,
+    Shr,
+    Sar,
+    Shl,
+    Shld,
+    Shrd,
+    Rol,
+    Ror,
+    Rcl,
+    Rcr,
+    Bt,
+    Bts,
+    Btr,
+    Btc,
+    Bsf,
+    Bsr,
+    Bswap,
+    Xchg,
+    Div,
+    Idiv,
+    Push,
+    Pop,
+    Pushfd,
+    Popfd,
+    /// The 16-bit ones, with the lower half of EFLAGS
+    Pushf,
+    Popf,
+    Pushad,
+    Popad,
+    /// The 16-bit ones
+    Pusha,
+    Popa,
+    Leave,
+    Ret,
+    Jmp,
+    Call,
+    Stc,
+    Clc,
+    Int,
+    Int3,
+    Sysenter,
+    Sysexit,
+    Iretd,
+    /// The 16-bit one: pops IP, CS & FLAGS
+    Iret,
+    In,
+    Out,
+    Jcc(Condition),
+    Cmovcc(Condition),
+    Setcc(Condition),
+    Movs,
+    Stos,
+    Scas,
+    Lods,
+    Cmps,
+    Ins,
+    Outs,
+    /// AL = CF ? 0xff : 0 (undocumented, but consistently implemented)
+    Salc,
+    /// The BCD adjusts with any base: AH, AL = AL / imm, AL % imm
+    Aam,
+    /// AL = AL + AH * imm, AH = 0
+    Aad,
+    Bound,
+    /// Raises #UD: `ud2` & friends, or the bytes that are not an instruction at all
+    Invalid,
+    /// `mov` to or from a control register (see system_registers.rs)
+    MovCr,
+    Smsw,
+    Lmsw,
+    Rdmsr,
+    Wrmsr,
+    Rdpmc,
+    /// Touches the byte (so it faults like a read would) & tells `RuntimeHandler::cache_flush`. There is no cache
+    Clflush,
+    /// The cache & TLB maintenance: nothing to do, but privileged
+    Invd,
+    Wbinvd,
+    Invlpg,
+    /// Raises #PF: the instruction runs into the bytes that can't be fetched, starting at the address in the operand.
+    /// Made by the decoder where the code ends (see `Instr::fetch_fault`)
+    FetchFault,
+    /// Raises `GuestFault::Unimplemented`: a valid instruction we can't translate, the operands are its bytes.
+    /// Made by the decoder with `UnsupportedInstructionPolicy::Trap` (see `Instr::unimplemented`)
+    Unimplemented,
+
+    // x87 (see fpu.rs)
+    Fld,
+    Fst,
+    Fstp,
+    Fldz,
+    Fld1,
+    Fldpi,
+    Fxch,
+    Fchs,
+    Fabs,
+    Fsqrt,
+    Frndint,
+    Fldcw,
+    Fnstcw,
+    Fninit,
+    Fnstsw,
+    /// `fwait` + `fnstcw`
+    Fstcw,
+    /// `fwait` + `fninit`
+    Finit,
+    /// `fwait` + `fnstsw`
+    Fstsw,
+    /// `fwait` on its own
+    Wait,
+
+    // SSE (see sse.rs)
+    Movnti,
+    Movntps,
+    Movntpd,
+    Movntdq,
+    Pextrw,
+    Pinsrw,
+    Pshuflw,
+    Pshufhw,
+    Maxss,
+    Maxsd,
+    Maxps,
+    Maxpd,
+    Minss,
+    Minsd,
+    Minps,
+    Minpd,
+    Ucomiss,
+    Ucomisd,
+    Comiss,
+    Comisd,
+    Cvtdq2ps,
+    Cvtps2dq,
+    Cvttps2dq,
+    Cvtdq2pd,
+    Cvtpd2dq,
+    Cvttpd2dq,
+    Ldmxcsr,
+    Stmxcsr,
+    Psllw,
+    Pslld,
+    Psllq,
+    Psrlw,
+    Psrld,
+    Psrlq,
+    Psraw,
+    Psrad,
+    Pslldq,
+    Psrldq,
+    Packsswb,
+    Packuswb,
+    Packssdw,
+    Emms,
+    Movd,
+    Movq,
+    Paddb,
+    Paddw,
+    Pand,
+    Por,
+    Pxor,
+
+    // the rest are produced by the peephole pass (see peephole.rs), never by the decoder
+    /// `xor r, r` / `sub r, r`: r = 0 with the flags known in advance
+    ZeroReg,
+    /// `test r, r; je/jne target` fused into a single compare against zero
+    TestJcc(Condition),
+    /// `cmp a, b; jcc target`: the condition is a compare of `a` & `b`, the flags are only stored for the later readers
+    CmpJcc(Condition),
+    /// `cmp a, b; cmovcc dst, src` (with a register `src`), the same way as `CmpJcc`
+    CmpCmovcc(Condition),
+    /// `setcc r8; movzx r32, r8` with `r8` a part of `r32`: the condition zero-extended straight into `r32`
+    SetccZx(Condition),
+    /// `push ebp; mov ebp, esp`
+    Prologue,
+    /// `lea r, [r + disp]`: an add that doesn't touch the flags
+    AddNoFlags,
+    /// `jmp [table + index * 4]` with the table read at the translation time (see switch_table.rs).
+    /// The operands are the original memory operand & the targets, in the order of the table
+    JmpTable,
+}
+
+impl Mnemonic {
+    pub fn is_string(self) -> bool {
+        use Mnemonic::*;
+        matches!(self, Movs | Stos | Scas | Lods | Cmps | Ins | Outs)
+    }
+
+    pub fn is_fpu(self) -> bool {
+        use Mnemonic::*;
+        matches!(
+            self,
+            Fld | Fst
+                | Fstp
+                | Fldz
+                | Fld1
+                | Fldpi
+                | Fxch
+                | Fchs
+                | Fabs
+                | Fsqrt
+                | Frndint
+                | Fldcw
+                | Fnstcw
+                | Fninit
+                | Fnstsw
+                | Fstcw
+                | Finit
+                | Fstsw
+                | Wait
+        )
+    }
+
+    pub fn is_sse(self) -> bool {
+        use Mnemonic::*;
+        matches!(
+            self,
+            Movnti
+                | Movntps
+                | Movntpd
+                | Movntdq
+                | Pextrw
+                | Pinsrw
+                | Pshuflw
+                | Pshufhw
+                | Maxss
+                | Maxsd
+                | Maxps
+                | Maxpd
+                | Minss
+                | Minsd
+                | Minps
+                | Minpd
+                | Ucomiss
+                | Ucomisd
+                | Comiss
+                | Comisd
+                | Cvtdq2ps
+                | Cvtps2dq
+                | Cvttps2dq
+                | Cvtdq2pd
+                | Cvtpd2dq
+                | Cvttpd2dq
+                | Ldmxcsr
+                | Stmxcsr
+                | Psllw
+                | Pslld
+                | Psllq
+                | Psrlw
+                | Psrld
+                | Psrlq
+                | Psraw
+                | Psrad
+                | Pslldq
+                | Psrldq
+                | Packsswb
+                | Packuswb
+                | Packssdw
+                | Emms
+                | Movd
+                | Movq
+                | Paddb
+                | Paddw
+                | Pand
+                | Por
+                | Pxor
+        )
+    }
+
+    pub fn is_branch(self) -> bool {
+        use Mnemonic::*;
+        matches!(
+            self,
+            Jmp | JmpTable | Call | Jcc(_) | TestJcc(_) | CmpJcc(_)
+        )
+    }
+
+    /// Execution never continues to the next instruction
+    pub fn ends_block(self) -> bool {
+        use Mnemonic::*;
+        matches!(
+            self,
+            Jmp | JmpTable | Ret | Sysexit | Iretd | Iret | Invalid | FetchFault | Unimplemented
+        )
+    }
+
+    /// Reads or writes the stack without naming ESP in the operands
+    pub fn uses_stack(self) -> bool {
+        use Mnemonic::*;
+        matches!(
+            self,
+            Push | Pop
+                | Pushfd
+                | Popfd
+                | Pushf
+                | Popf
+                | Pushad
+                | Popad
+                | Pusha
+                | Popa
+                | Leave
+                | Prologue
+                | Call
+                | Ret
+                | Int
+                | Int3
+                | Iretd
+                | Iret
+        )
+    }
+
+    fn from_iced(instr: &Instruction) -> Option<Self> {
+        use IcedMnemonic as I;
+        use Mnemonic::*;
+
+        if instr.is_jcc_short_or_near() {
+            return Some(Jcc(Condition::from_iced(instr.condition_code())?));
+        }
+        Some(match instr.mnemonic() {
+            I::Cmova
+            | I::Cmovae
+            | I::Cmovb
+            | I::Cmovbe
+            | I::Cmove
+            | I::Cmovg
+            | I::Cmovge
+            | I::Cmovl
+            | I::Cmovle
+            | I::Cmovne
+            | I::Cmovno
+            | I::Cmovnp
+            | I::Cmovns
+            | I::Cmovo
+            | I::Cmovp
+            | I::Cmovs => Cmovcc(Condition::from_iced(instr.condition_code())?),
+            I::Seta
+            | I::Setae
+            | I::Setb
+            | I::Setbe
+            | I::Sete
+            | I::Setg
+            | I::Setge
+            | I::Setl
+            | I::Setle
+            | I::Setne
+            | I::Setno
+            | I::Setnp
+            | I::Setns
+            | I::Seto
+            | I::Setp
+            | I::Sets => Setcc(Condition::from_iced(instr.condition_code())?),
+            I::Nop => Nop,
+            I::Mov if (0..instr.op_count()).any(|i| instr.op_register(i).is_cr()) => MovCr,
+            I::Mov => Mov,
+            I::Movzx => Movzx,
+            I::Movsx => Movsx,
+            I::Add => Add,
+            I::Adc => Adc,
+            I::Sub => Sub,
+            I::Cmp => Cmp,
+            I::Sbb => Sbb,
+            I::Lea => Lea,
+            I::Dec => Dec,
+            I::Inc => Inc,
+            I::Neg => Neg,
+            I::Cbw => Cbw,
+            I::Cwde => Cwde,
+            I::Cwd => Cwd,
+            I::Cdq => Cdq,
+            I::Mul => Mul,
+            I::Imul => Imul,
+            I::Xor => Xor,
+            I::Not => Not,
+            I::And => And,
+            I::Test => Test,
+            I::Or => Or,
+            I::Shr => Shr,
+            I::Sar => Sar,
+            I::Shl => Shl,
+            I::Shld => Shld,
+            I::Shrd => Shrd,
+            I::Rol => Rol,
+            I::Ror => Ror,
+            I::Rcl => Rcl,
+            I::Rcr => Rcr,
+            I::Bt => Bt,
+            I::Bts => Bts,
+            I::Btr => Btr,
+            I::Btc => Btc,
+            I::Bsf => Bsf,
+            I::Bsr => Bsr,
+            I::Bswap => Bswap,
+            I::Xchg => Xchg,
+            I::Div => Div,
+            I::Idiv => Idiv,
+            I::Push => Push,
+            I::Pop => Pop,
+            I::Pushfd => Pushfd,
+            I::Popfd => Popfd,
+            I::Pushf => Pushf,
+            I::Popf => Popf,
+            I::Pushad => Pushad,
+            I::Popad => Popad,
+            I::Pusha => Pusha,
+            I::Popa => Popa,
+            I::Leave => Leave,
+            I::Ret => Ret,
+            I::Jmp => Jmp,
+            I::Call => Call,
+            I::Stc => Stc,
+            I::Clc => Clc,
+            I::Int => Int,
+            I::Int3 => Int3,
+            I::Sysenter => Sysenter,
+            I::Sysexit => Sysexit,
+            I::Iretd => Iretd,
+            I::Iret => Iret,
+            I::In => In,
+            I::Out => Out,
+            I::Movsb | I::Movsw | I::Movsd => Movs,
+            I::Stosb | I::Stosw | I::Stosd => Stos,
+            I::Scasb | I::Scasw | I::Scasd => Scas,
+            I::Lodsb | I::Lodsw | I::Lodsd => Lods,
+            I::Cmpsb | I::Cmpsw | I::Cmpsd => Cmps,
+            I::Insb | I::Insw | I::Insd => Ins,
+            I::Outsb | I::Outsw | I::Outsd => Outs,
+            I::Salc => Salc,
+            I::Aam => Aam,
+            I::Aad => Aad,
+            I::Bound => Bound,
+            I::Smsw => Smsw,
+            I::Lmsw => Lmsw,
+            I::Rdmsr => Rdmsr,
+            I::Wrmsr => Wrmsr,
+            I::Rdpmc => Rdpmc,
+            I::Clflush | I::Clflushopt => Clflush,
+            I::Invd => Invd,
+            I::Wbinvd => Wbinvd,
+            I::Invlpg => Invlpg,
+            I::Ud0 | I::Ud1 | I::Ud2 => Invalid,
+            I::Fld => Fld,
+            I::Fst => Fst,
+            I::Fstp => Fstp,
+            I::Fldz => Fldz,
+            I::Fld1 => Fld1,
+            I::Fldpi => Fldpi,
+            I::Fxch => Fxch,
+            I::Fchs => Fchs,
+            I::Fabs => Fabs,
+            I::Fsqrt => Fsqrt,
+            I::Frndint => Frndint,
+            I::Fldcw => Fldcw,
+            I::Fnstcw => Fnstcw,
+            I::Fninit => Fninit,
+            I::Fnstsw => Fnstsw,
+            I::Wait => Wait,
+            I::Movnti => Movnti,
+            I::Movntps => Movntps,
+            I::Movntpd => Movntpd,
+            I::Movntdq => Movntdq,
+            I::Pextrw => Pextrw,
+            I::Pinsrw => Pinsrw,
+            I::Pshuflw => Pshuflw,
+            I::Pshufhw => Pshufhw,
+            I::Maxss => Maxss,
+            I::Maxsd => Maxsd,
+            I::Maxps => Maxps,
+            I::Maxpd => Maxpd,
+            I::Minss => Minss,
+            I::Minsd => Minsd,
+            I::Minps => Minps,
+            I::Minpd => Minpd,
+            I::Ucomiss => Ucomiss,
+            I::Ucomisd => Ucomisd,
+            I::Comiss => Comiss,
+            I::Comisd => Comisd,
+            I::Cvtdq2ps => Cvtdq2ps,
+            I::Cvtps2dq => Cvtps2dq,
+            I::Cvttps2dq => Cvttps2dq,
+            I::Cvtdq2pd => Cvtdq2pd,
+            I::Cvtpd2dq => Cvtpd2dq,
+            I::Cvttpd2dq => Cvttpd2dq,
+            I::Ldmxcsr => Ldmxcsr,
+            I::Stmxcsr => Stmxcsr,
+            I::Psllw => Psllw,
+            I::Pslld => Pslld,
+            I::Psllq => Psllq,
+            I::Psrlw => Psrlw,
+            I::Psrld => Psrld,
+            I::Psrlq => Psrlq,
+            I::Psraw => Psraw,
+            I::Psrad => Psrad,
+            I::Pslldq => Pslldq,
+            I::Psrldq => Psrldq,
+            I::Packsswb => Packsswb,
+            I::Packuswb => Packuswb,
+            I::Packssdw => Packssdw,
+            I::Emms => Emms,
+            I::Movd => Movd,
+            I::Movq => Movq,
+            I::Paddb => Paddb,
+            I::Paddw => Paddw,
+            I::Pand => Pand,
+            I::Por => Por,
+            I::Pxor => Pxor,
+            _ => return None,
+        })
+    }
+}
+
+impl Display for Mnemonic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Mnemonic::Jcc(cond) => write!(f, "j{}", cond.name()),
+            Mnemonic::Cmovcc(cond) => write!(f, "cmov{}", cond.name()),
+            Mnemonic::TestJcc(cond) => write!(f, "testj{}", cond.name()),
+            Mnemonic::CmpJcc(cond) => write!(f, "cmpj{}", cond.name()),
+            Mnemonic::CmpCmovcc(cond) => write!(f, "cmpcmov{}", cond.name()),
+            Mnemonic::Setcc(cond) => write!(f, "set{}", cond.name()),
+            Mnemonic::SetccZx(cond) => write!(f, "set{}zx", cond.name()),
+            Mnemonic::MovCr => f.write_str("mov"),
+            m => write!(f, "{}", format!("{:?}", m).to_ascii_lowercase()),
+        }
+    }
+}
+
+/// A single decoded instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instr {
+    /// Address of the instruction
+    pub ip: u32,
+    /// Length of the encoding, in bytes
+    pub len: u8,
+    pub mnemonic: Mnemonic,
+    pub prefixes: Prefixes,
+    pub operands: Vec<Operand>,
+}
+
+impl Instr {
+    pub fn new(ip: u32, len: u8, mnemonic: Mnemonic, operands: Vec<Operand>) -> Self {
+        Self {
+            ip,
+            len,
+            mnemonic,
+            prefixes: Prefixes::empty(),
+            operands,
+        }
+    }
+
+    pub fn with_prefixes(mut self, prefixes: Prefixes) -> Self {
+        self.prefixes = prefixes;
+        self
+    }
+
+    pub fn next_ip(&self) -> u32 {
+        self.ip.wrapping_add(self.len as u32)
+    }
+
+    /// Whether `address` is one of the bytes of the instruction (a jump into the middle of it, a breakpoint on it).
+    /// The address space wraps around, so does the instruction
+    pub fn contains(&self, address: u32) -> bool {
+        address.wrapping_sub(self.ip) < self.len as u32
+    }
+
+    /// Size of the data the instruction works on: the size of the first operand, if there is one
+    pub fn width(&self) -> Option<IntType> {
+        match self.operands.first() {
+            Some(Operand::FarBranch(..)) | None => None,
+            Some(op) => Some(op.size()),
+        }
+    }
+
+    /// The instruction at `ip` that can't be fetched because the code ends at `end` (which might be `ip` itself)
+    pub fn fetch_fault(ip: u32, end: u32) -> Self {
+        Self::new(
+            ip,
+            end.wrapping_sub(ip) as u8,
+            Mnemonic::FetchFault,
+            vec![Operand::Immediate32(end)],
+        )
+    }
+
+    /// A jump from `ip` to `ip` itself, taking no bytes: continues a block that was cut short at `ip` in another one
+    pub fn fallthrough(ip: u32) -> Self {
+        Self::new(ip, 0, Mnemonic::Jmp, vec![Operand::Immediate32(ip)])
+    }
+
+    /// The instruction encoded by `bytes` at `ip`, which we can't translate
+    pub fn unimplemented(ip: u32, bytes: &[u8]) -> Self {
+        Self::new(
+            ip,
+            bytes.len() as u8,
+            Mnemonic::Unimplemented,
+            bytes.iter().map(|&b| Operand::Immediate8(b)).collect(),
+        )
+    }
+
+    /// Target of a `call rel32`. Not for `call $+5` (see `calls_next`)
+    pub fn direct_call_target(&self) -> Option<u32> {
+        match (self.mnemonic, self.operands.as_slice()) {
+            (Mnemonic::Call, [Operand::Immediate32(target)]) if !self.calls_next() => Some(*target),
+            _ => None,
+        }
+    }
+
+    /// `call $+5`, the first half of the `call next; next: pop eax` get-EIP idiom. It's not really a call: the return
+    /// address is only there to be popped, the execution just continues
+    pub fn calls_next(&self) -> bool {
+        matches!(
+            (self.mnemonic, self.operands.as_slice()),
+            (Mnemonic::Call, [Operand::Immediate32(target)]) if *target == self.next_ip()
+        )
+    }
+}
+
+impl Operands for Instr {
+    fn get_operands(&self) -> Vec<Operand> {
+        self.operands.clone()
+    }
+}
+
+impl Display for Instr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.prefixes.contains(Prefixes::LOCK) {
+            write!(f, "lock ")?;
+        }
+        if self.prefixes.contains(Prefixes::REP) {
+            match self.mnemonic {
+                Mnemonic::Scas | Mnemonic::Cmps => write!(f, "repe ")?,
+                _ => write!(f, "rep ")?,
+            }
+        }
+        if self.prefixes.contains(Prefixes::REPNE) {
+            write!(f, "repne ")?;
+        }
+
+        if self.mnemonic.is_string() {
+            // the operands are implicit, the size is in the suffix
+            let suffix = match self.width() {
+                Some(IntType::I8) => "b",
+                Some(IntType::I16) => "w",
+                Some(IntType::I32) => "d",
+                _ => "",
+            };
+            return write!(f, "{}{}", self.mnemonic, suffix);
+        }
+
+        write!(f, "{}", self.mnemonic)?;
+        for (i, op) in self.operands.iter().enumerate() {
+            write!(f, "{}", if i == 0 { " " } else { ", " })?;
+            match op {
+                // branch targets are addresses, not sized immediates
+                Operand::Immediate32(target) if self.mnemonic.is_branch() => {
+                    write!(f, "0x{:x}", target)?
+                }
+                op => write!(f, "{}", op)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What to do with the bytes that don't encode any instruction
+#[derive(Debug, Clone, Copy, Eq, Default)]
+pub enum InvalidOpcodePolicy {
+    /// Decode them as `Invalid`, which raises #UD when executed
+    #[default]
+    Fault,
+    /// Let the function make sense of the bytes at `ip` (to emulate vendor-specific behaviour, for example).
+    /// If it returns `None` we fault anyway
+    Fallback(fn(bytes: &[u8], ip: u32) -> Option<Instr>),
+}
+
+impl PartialEq for InvalidOpcodePolicy {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (InvalidOpcodePolicy::Fault, InvalidOpcodePolicy::Fault) => true,
+            (InvalidOpcodePolicy::Fallback(a), InvalidOpcodePolicy::Fallback(b)) => {
+                std::ptr::fn_addr_eq(*a, *b)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// What to do with the valid instructions we don't know how to translate
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnsupportedInstructionPolicy {
+    /// Fail the decoding with `DecodeError::Unsupported`, so nothing gets translated
+    #[default]
+    Fail,
+    /// Decode them as `Unimplemented`, which raises `GuestFault::Unimplemented` if the execution gets there
+    Trap,
+    /// The listed ones are decoded as `nop` (with a warning), for the ones that can be safely skipped.
+    /// The rest are trapped
+    Ignore(&'static [IcedMnemonic]),
+}
+
+/// The default operand & address size of the code
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CodeMode {
+    #[default]
+    Bits32,
+    /// Real-mode-style code, like boot sectors & DOS programs: 16-bit operands & addresses unless the 0x66 & 0x67
+    /// prefixes say otherwise. Return addresses & interrupt frames are 16-bit too.
+    ///
+    /// EIP is still the linear address (CS is taken to be 0), so the code has to be in the first 64 KiB.
+    /// The data segments get their bases from the segmentation policy (see `SegmentDescriptor::real_mode`), and so does
+    /// the stack: push & pop go to SS:SP, with SP wrapping around at 64 KiB
+    Bits16,
+}
+
+impl CodeMode {
+    pub fn bitness(self) -> u32 {
+        match self {
+            CodeMode::Bits32 => 32,
+            CodeMode::Bits16 => 16,
+        }
+    }
+
+    /// The size of what `call` pushes & `ret` pops, and of the interrupt frame entries
+    pub fn stack_word(self) -> IntType {
+        match self {
+            CodeMode::Bits32 => IntType::I32,
+            CodeMode::Bits16 => IntType::I16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Not an instruction. Only for the `iced_x86::Instruction`s made elsewhere: `Decoder` makes those `Mnemonic::Invalid`
+    Invalid { ip: u32 },
+    /// The code ends at `end`, in the middle of the instruction at `ip` (or right at it)
+    Truncated { ip: u32, end: u32 },
+    /// A valid instruction we don't know how to translate
+    Unsupported { ip: u32, mnemonic: IcedMnemonic },
+}
+
+impl Display for DecodeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Invalid { ip } => write!(f, "invalid instruction at 0x{:08x}", ip),
+            DecodeError::Truncated { ip, end } => write!(
+                f,
+                "the instruction at 0x{:08x} doesn't fit in the code ending at 0x{:08x}",
+                ip, end
+            ),
+            DecodeError::Unsupported { ip, mnemonic } => {
+                write!(f, "unsupported instruction {:?} at 0x{:08x}", mnemonic, ip)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+impl TryFrom<&Instruction> for Instr {
+    type Error = DecodeError;
+
+    fn try_from(instr: &Instruction) -> Result<Self, Self::Error> {
+        let ip = instr.ip32();
+        if instr.is_invalid() {
+            return Err(DecodeError::Invalid { ip });
+        }
+        let mnemonic = Mnemonic::from_iced(instr).ok_or(DecodeError::Unsupported {
+            ip,
+            mnemonic: instr.mnemonic(),
+        })?;
+
+        Ok(Instr {
+            ip,
+            len: instr.len() as u8,
+            mnemonic,
+            prefixes: Prefixes::canonical(instr, mnemonic),
+            operands: instr.get_operands(),
+        })
+    }
+}
+
+/// Decodes 32-bit (or 16-bit, see `CodeMode`) code into `Instr`s
+pub struct Decoder<'a> {
+    inner: iced_x86::Decoder<'a>,
+    code: &'a [u8],
+    start_ip: u32,
+    invalid_opcodes: InvalidOpcodePolicy,
+    unsupported_instructions: UnsupportedInstructionPolicy,
+}
+
+impl<'a> Decoder<'a> {
+    /// `code` is located at `ip` in the guest
+    pub fn new(code: &'a [u8], ip: u32) -> Self {
+        Self {
+            inner: iced_x86::Decoder::with_ip(32, code, ip as u64, iced_x86::DecoderOptions::NONE),
+            code,
+            start_ip: ip,
+            invalid_opcodes: InvalidOpcodePolicy::default(),
+            unsupported_instructions: UnsupportedInstructionPolicy::default(),
+        }
+    }
+
+    pub fn invalid_opcodes(mut self, policy: InvalidOpcodePolicy) -> Self {
+        self.invalid_opcodes = policy;
+        self
+    }
+
+    pub fn unsupported_instructions(mut self, policy: UnsupportedInstructionPolicy) -> Self {
+        self.unsupported_instructions = policy;
+        self
+    }
+
+    pub fn code_mode(mut self, mode: CodeMode) -> Self {
+        self.inner = iced_x86::Decoder::with_ip(
+            mode.bitness(),
+            self.code,
+            self.start_ip as u64,
+            iced_x86::DecoderOptions::NONE,
+        );
+        self
+    }
+
+    pub fn can_decode(&self) -> bool {
+        self.inner.can_decode()
+    }
+
+    pub fn ip(&self) -> u32 {
+        self.inner.ip() as u32
+    }
+
+    pub fn decode(&mut self) -> Result<Instr, DecodeError> {
+        let instr = self.inner.decode();
+        if instr.is_invalid() && self.inner.last_error() == DecoderError::NoMoreBytes {
+            return Err(DecodeError::Truncated {
+                ip: instr.ip32(),
+                end: self.start_ip.wrapping_add(self.code.len() as u32),
+            });
+        }
+        if instr.is_invalid() && self.inner.last_error() == DecoderError::InvalidInstruction {
+            let ip = instr.ip32();
+            if let InvalidOpcodePolicy::Fallback(fallback) = self.invalid_opcodes {
+                let position = ip.wrapping_sub(self.start_ip) as usize;
+                if let Some(instr) = fallback(&self.code[position..], ip) {
+                    // continue right after whatever the fallback decided the instruction is
+                    self.inner
+                        .set_position(position + instr.len as usize)
+                        .expect("the fallback instruction doesn't fit in the code");
+                    self.inner.set_ip(instr.next_ip() as u64);
+                    return Ok(instr);
+                }
+            }
+            return Ok(Instr::new(ip, instr.len() as u8, Mnemonic::Invalid, vec![]));
+        }
+        let instr = match Instr::try_from(&instr) {
+            Err(DecodeError::Unsupported { ip, mnemonic }) => {
+                return self.unsupported(ip, instr.len(), mnemonic)
+            }
+            instr => instr?,
+        };
+        if instr.mnemonic == Mnemonic::Wait {
+            return Ok(self.merge_wait(instr));
+        }
+        Ok(instr)
+    }
+
+    /// `DecodeError::Unsupported` or whatever the policy makes of it
+    fn unsupported(
+        &self,
+        ip: u32,
+        len: usize,
+        mnemonic: IcedMnemonic,
+    ) -> Result<Instr, DecodeError> {
+        let position = ip.wrapping_sub(self.start_ip) as usize;
+        let bytes = &self.code[position..position + len];
+        match self.unsupported_instructions {
+            UnsupportedInstructionPolicy::Fail => Err(DecodeError::Unsupported { ip, mnemonic }),
+            UnsupportedInstructionPolicy::Ignore(ignored) if ignored.contains(&mnemonic) => {
+                warn!("ignoring the unsupported {:?} at 0x{:08x}", mnemonic, ip);
+                Ok(Instr::new(ip, len as u8, Mnemonic::Nop, vec![]))
+            }
+            UnsupportedInstructionPolicy::Trap | UnsupportedInstructionPolicy::Ignore(_) => {
+                Ok(Instr::unimplemented(ip, bytes))
+            }
+        }
+    }
+
+    /// `fwait` is a separate instruction, but `fwait; fnstsw` & co are the well-known `fstsw` & co.
+    /// Folds them into one, leaving the other `fwait`s alone
+    fn merge_wait(&mut self, wait: Instr) -> Instr {
+        if !self.inner.can_decode() {
+            return wait;
+        }
+        let position = self.inner.position();
+        let next = self.inner.decode();
+
+        let mnemonic = match next.mnemonic() {
+            IcedMnemonic::Fnstsw => Mnemonic::Fstsw,
+            IcedMnemonic::Fnstcw => Mnemonic::Fstcw,
+            IcedMnemonic::Fninit => Mnemonic::Finit,
+            _ => {
+                self.inner.set_position(position).unwrap();
+                self.inner.set_ip(wait.next_ip() as u64);
+                return wait;
+            }
+        };
+        Instr {
+            len: wait.len + next.len() as u8,
+            mnemonic,
+            operands: next.get_operands(),
+            ..wait
+        }
+    }
+}
+
+/// Decodes instructions starting at `ip` up to (and including) the one that ends the basic block
+///
+/// Stops early after `max_len` instructions or if the code ends. An instruction cut short by the end of the code
+/// becomes `Mnemonic::FetchFault`, so it only faults if the execution gets that far
+pub fn decode_block(
+    code: &[u8],
+    ip: u32,
+    max_len: usize,
+    invalid_opcodes: InvalidOpcodePolicy,
+    unsupported_instructions: UnsupportedInstructionPolicy,
+    code_mode: CodeMode,
+) -> Result<Vec<Instr>, DecodeError> {
+    let mut decoder = Decoder::new(code, ip)
+        .code_mode(code_mode)
+        .invalid_opcodes(invalid_opcodes)
+        .unsupported_instructions(unsupported_instructions);
+    let mut res = Vec::new();
+    while decoder.can_decode() && res.len() < max_len {
+        let instr = match decoder.decode() {
+            Err(DecodeError::Truncated { ip, end }) => Instr::fetch_fault(ip, end),
+            instr => instr?,
+        };
+        let ends_block = instr.mnemonic.ends_block();
+        res.push(instr);
+        if ends_block {
+            break;
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        decode_block, CodeMode, Condition, DecodeError, Decoder, Instr, InvalidOpcodePolicy,
+        Mnemonic, Prefixes, UnsupportedInstructionPolicy,
+    };
+    use crate::assemble_x86;
+    use crate::types::{IntType, MemoryOperand, Operand, Register};
+
+    fn decode_all(code: &[u8]) -> Vec<Instr> {
+        let mut decoder = Decoder::new(code, 0x1000);
+        let mut res = Vec::new();
+        while decoder.can_decode() {
+            res.push(decoder.decode().unwrap());
+        }
+        res
+    }
+
+    #[test_log::test]
+    fn decode() {
+        let code = assemble_x86!(
+            ; mov eax, [ebx + ecx*4 + 0x10]
+            ; add al, 1
+            ; jne ->end
+            ; rep stosd
+            ; repne scasb
+            ; cmovl edx, eax
+            ; ->end:
+            ; ret
+        );
+        let instrs = decode_all(&code);
+
+        assert_eq!(
+            instrs[0],
+            Instr::new(
+                0x1000,
+                4,
+                Mnemonic::Mov,
+                vec![
+                    Operand::Register(Register::EAX),
+                    Operand::Memory(MemoryOperand {
+                        base: Some(Register::EBX),
+                        index: Some(Register::ECX),
+                        scale: 4,
+                        displacement: 0x10,
+                        size: Some(IntType::I32),
+                        segment: None,
+                    })
+                ]
+            )
+        );
+        assert_eq!(instrs[0].next_ip(), 0x1004);
+
+        assert_eq!(instrs[1].mnemonic, Mnemonic::Add);
+        assert_eq!(instrs[1].width(), Some(IntType::I8));
+
+        assert_eq!(instrs[2].mnemonic, Mnemonic::Jcc(Condition::NE));
+        assert_eq!(instrs[2].operands, vec![Operand::Immediate32(instrs[6].ip)]);
+
+        assert_eq!(instrs[3].mnemonic, Mnemonic::Stos);
+        assert_eq!(instrs[3].prefixes, Prefixes::REP);
+        assert_eq!(instrs[3].width(), Some(IntType::I32));
+
+        assert_eq!(instrs[4].mnemonic, Mnemonic::Scas);
+        assert_eq!(instrs[4].prefixes, Prefixes::REPNE);
+
+        assert_eq!(instrs[5].mnemonic, Mnemonic::Cmovcc(Condition::L));
+        assert_eq!(instrs[6].mnemonic, Mnemonic::Ret);
+        assert_eq!(instrs[6].next_ip() as usize, 0x1000 + code.len());
+    }
+
+    #[test_log::test]
+    fn lea_addressing() {
+        let code = assemble_x86!(
+            ; lea eax, [ebx*8 + 4]
+            ; lea eax, [esp + 8]
+            ; lea eax, [ebp]
+            ; lea ax, [ebx + ecx*2 - 1]
+        );
+        let instrs = decode_all(&code);
+
+        let memory = |instr: &Instr| match instr.operands[1] {
+            Operand::Memory(m) => m,
+            _ => panic!("not a memory operand"),
+        };
+        let operand = |base, index, scale, displacement| MemoryOperand {
+            base,
+            index,
+            scale,
+            displacement,
+            size: None,
+            segment: None,
+        };
+
+        // no base: the displacement is always 32-bit
+        assert_eq!(instrs[0].len, 7);
+        assert_eq!(memory(&instrs[0]), operand(None, Some(Register::EBX), 8, 4));
+        // needs a SIB byte
+        assert_eq!(instrs[1].len, 4);
+        assert_eq!(memory(&instrs[1]), operand(Some(Register::ESP), None, 1, 8));
+        // needs a zero disp8
+        assert_eq!(instrs[2].len, 3);
+        assert_eq!(memory(&instrs[2]), operand(Some(Register::EBP), None, 1, 0));
+        assert_eq!(instrs[3].operands[0], Operand::Register(Register::AX));
+        assert_eq!(
+            memory(&instrs[3]),
+            operand(Some(Register::EBX), Some(Register::ECX), 2, -1)
+        );
+    }
+
+    #[test_log::test]
+    fn negative_displacements() {
+        let code = [
+            0x8d, 0x40, 0xf8, // lea eax, [eax - 8]: disp8
+            0x8d, 0x84, 0x8b, 0x00, 0xff, 0xff,
+            0xff, // lea eax, [ebx + ecx*4 - 0x100]: disp32
+            0x8d, 0x80, 0x00, 0x00, 0x00, 0x80, // lea eax, [eax - 0x80000000]
+            0x8d, 0x80, 0xff, 0xff, 0xff, 0x7f, // lea eax, [eax + 0x7fffffff]
+            0x67, 0x8d, 0x47, 0xf8, // lea eax, [bx - 8]: disp8, 16-bit address
+            0x67, 0x8d, 0x87, 0x00, 0x80, // lea eax, [bx - 0x8000]: disp16
+            0x67, 0x8d, 0x06, 0x00,
+            0x80, // lea eax, [0x8000]: an absolute 16-bit address stays positive
+        ];
+        let displacements = decode_all(&code)
+            .iter()
+            .map(|instr| match instr.operands[1] {
+                Operand::Memory(m) => m.displacement,
+                _ => panic!("not a memory operand"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            displacements,
+            [
+                -8,
+                -0x100,
+                i32::MIN as i64,
+                i32::MAX as i64,
+                -8,
+                -0x8000,
+                0x8000
+            ]
+        );
+    }
+
+    #[test_log::test]
+    fn sib_irregularities() {
+        let decode_memory = |code: &[u8]| {
+            let instrs = decode_all(code);
+            assert_eq!(instrs.len(), 1);
+            assert_eq!(instrs[0].len as usize, code.len());
+            match instrs[0].operands[1] {
+                Operand::Memory(m) => m,
+                _ => panic!("not a memory operand"),
+            }
+        };
+        let dword = Some(IntType::I32);
+
+        // mov eax, [0x12345678] without a SIB byte, with one (base=101b, mod=00, index=100b) & with the scale bits
+        // set on the missing index
+        let absolute = MemoryOperand::absolute(0x12345678, dword);
+        assert_eq!(
+            decode_memory(&[0x8b, 0x05, 0x78, 0x56, 0x34, 0x12]),
+            absolute
+        );
+        assert_eq!(
+            decode_memory(&[0x8b, 0x04, 0x25, 0x78, 0x56, 0x34, 0x12]),
+            absolute
+        );
+        assert_eq!(
+            decode_memory(&[0x8b, 0x04, 0x65, 0x78, 0x56, 0x34, 0x12]),
+            absolute
+        );
+
+        // mov eax, [ecx*4 + 0x12345678]: no base, but an index
+        assert_eq!(
+            decode_memory(&[0x8b, 0x04, 0x8d, 0x78, 0x56, 0x34, 0x12]),
+            MemoryOperand {
+                index: Some(Register::ECX),
+                scale: 4,
+                ..absolute
+            }
+        );
+
+        // mov eax, [esp] & mov eax, [esp*2 + esp]: index=100b is ESP as a base only
+        let esp = MemoryOperand {
+            base: Some(Register::ESP),
+            displacement: 0,
+            ..absolute
+        };
+        assert_eq!(decode_memory(&[0x8b, 0x04, 0x24]), esp);
+        assert_eq!(decode_memory(&[0x8b, 0x04, 0x64]), esp);
+
+        // mov eax, [ebp + 0]: mod=00 would mean disp32, so it's a zero disp8
+        assert_eq!(
+            decode_memory(&[0x8b, 0x45, 0x00]),
+            MemoryOperand {
+                base: Some(Register::EBP),
+                displacement: 0,
+                ..absolute
+            }
+        );
+    }
+
+    #[test_log::test]
+    fn block() {
+        let code = assemble_x86!(
+            ; xor eax, eax
+            ; je ->end
+            ; jmp ->end
+            ; ->end:
+            ; ret
+        );
+
+        let block = decode_block(
+            &code,
+            0x1000,
+            usize::MAX,
+            InvalidOpcodePolicy::Fault,
+            UnsupportedInstructionPolicy::Fail,
+            CodeMode::Bits32,
+        )
+        .unwrap();
+        let mnemonics: Vec<_> = block.iter().map(|i| i.mnemonic).collect();
+        assert_eq!(
+            mnemonics,
+            vec![Mnemonic::Xor, Mnemonic::Jcc(Condition::E), Mnemonic::Jmp]
+        );
+
+        assert_eq!(
+            decode_block(
+                &code,
+                0x1000,
+                1,
+                InvalidOpcodePolicy::Fault,
+                UnsupportedInstructionPolicy::Fail,
+                CodeMode::Bits32,
+            )
+            .unwrap()
+            .len(),
+            1
+        );
+    }
+
+    #[test_log::test]
+    fn truncated_block() {
+        // nop; mov eax, imm32 missing the last two bytes
+        let code = [0x90, 0xb8, 0x01, 0x02];
+        let block = decode_block(
+            &code,
+            0x1000,
+            usize::MAX,
+            InvalidOpcodePolicy::Fault,
+            UnsupportedInstructionPolicy::Fail,
+            CodeMode::Bits32,
+        )
+        .unwrap();
+        assert_eq!(
+            block,
+            vec![
+                Instr::new(0x1000, 1, Mnemonic::Nop, vec![]),
+                Instr::new(
+                    0x1001,
+                    3,
+                    Mnemonic::FetchFault,
+                    vec![Operand::Immediate32(0x1004)]
+                ),
+            ]
+        );
+
+        // ending right between the instructions is not a fault by itself
+        let block = decode_block(
+            &code[..1],
+            0x1000,
+            usize::MAX,
+            InvalidOpcodePolicy::Fault,
+            UnsupportedInstructionPolicy::Fail,
+            CodeMode::Bits32,
+        )
+        .unwrap();
+        assert_eq!(block.len(), 1);
+    }
+
+    #[test_log::test]
+    fn decode_errors() {
+        // truncated mov eax, imm32
+        let mut decoder = Decoder::new(&[0xb8, 0x01], 0x1000);
+        assert_eq!(
+            decoder.decode(),
+            Err(DecodeError::Truncated {
+                ip: 0x1000,
+                end: 0x1002
+            })
+        );
+        // nothing to decode at all
+        let mut decoder = Decoder::new(&[], 0x1000);
+        assert_eq!(
+            decoder.decode(),
+            Err(DecodeError::Truncated {
+                ip: 0x1000,
+                end: 0x1000
+            })
+        );
+
+        let mut decoder = Decoder::new(&[0x90, 0x0f, 0xa2], 0x1000);
+        assert_eq!(decoder.decode().unwrap().mnemonic, Mnemonic::Nop);
+        let err = decoder.decode().unwrap_err();
+        assert_eq!(
+            err,
+            DecodeError::Unsupported {
+                ip: 0x1001,
+                mnemonic: iced_x86::Mnemonic::Cpuid
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "unsupported instruction Cpuid at 0x00001001"
+        );
+    }
+
+    #[test_log::test]
+    fn unsupported_instructions() {
+        use UnsupportedInstructionPolicy::*;
+
+        // nop; cpuid; daa; ret
+        let code = [0x90, 0x0f, 0xa2, 0x27, 0xc3];
+        let decode = |policy| {
+            decode_block(
+                &code,
+                0x1000,
+                usize::MAX,
+                InvalidOpcodePolicy::Fault,
+                policy,
+                CodeMode::Bits32,
+            )
+        };
+
+        assert_eq!(
+            decode(Fail),
+            Err(DecodeError::Unsupported {
+                ip: 0x1001,
+                mnemonic: iced_x86::Mnemonic::Cpuid
+            })
+        );
+        // the block ends there, the execution can't get past it
+        assert_eq!(
+            decode(Trap).unwrap(),
+            vec![
+                Instr::new(0x1000, 1, Mnemonic::Nop, vec![]),
+                Instr::unimplemented(0x1001, &[0x0f, 0xa2]),
+            ]
+        );
+        assert_eq!(
+            decode(Ignore(&[iced_x86::Mnemonic::Cpuid])).unwrap(),
+            vec![
+                Instr::new(0x1000, 1, Mnemonic::Nop, vec![]),
+                Instr::new(0x1001, 2, Mnemonic::Nop, vec![]),
+                Instr::unimplemented(0x1003, &[0x27]),
+            ]
+        );
+    }
+
+    #[test_log::test]
+    fn prefix_combinations() {
+        use Mnemonic::*;
+        let empty = Prefixes::empty();
+        #[rustfmt::skip]
+        let cases: &[(&[u8], Mnemonic, Prefixes, &str)] = &[
+            // the last operand size prefix counts, repeating it changes nothing
+            (&[0x66, 0x66, 0x01, 0xd8], Add, empty, "add ax, bx"),
+            (&[0x66, 0xf3, 0xa5], Movs, Prefixes::REP, "rep movsw"),
+            (&[0xf3, 0x66, 0xa5], Movs, Prefixes::REP, "rep movsw"),
+            (&[0xf3, 0xf3, 0xa4], Movs, Prefixes::REP, "rep movsb"),
+            // the address size is in the operands (SI & DI instead of ESI & EDI)
+            (&[0x67, 0xf3, 0xa4], Movs, Prefixes::REP, "rep movsb"),
+            // so does the last segment override
+            (&[0x64, 0x65, 0x8b, 0x03], Mov, empty, "mov eax, dword [gs:ebx]"),
+            (&[0x65, 0x64, 0x8b, 0x03], Mov, empty, "mov eax, dword [fs:ebx]"),
+            // and the last of f2/f3
+            (&[0xf2, 0xf3, 0xa6], Cmps, Prefixes::REP, "repe cmpsb"),
+            (&[0xf3, 0xf2, 0xa6], Cmps, Prefixes::REPNE, "repne cmpsb"),
+            // which means just "rep" for the string instructions that don't compare
+            (&[0xf3, 0xf2, 0xa4], Movs, Prefixes::REP, "rep movsb"),
+            (&[0xf2, 0xab], Stos, Prefixes::REP, "rep stosd"),
+            // rep is ignored on everything else
+            (&[0xf3, 0xc3], Ret, empty, "ret"),
+            (&[0xf3, 0x01, 0xd8], Add, empty, "add eax, ebx"),
+            (&[0xf2, 0x01, 0xd8], Add, empty, "add eax, ebx"),
+            // lock on a memory destination is fine, xrelease is a hint
+            (&[0xf0, 0x01, 0x18], Add, Prefixes::LOCK, "lock add dword [eax], ebx"),
+            (&[0xf0, 0xf0, 0x01, 0x18], Add, Prefixes::LOCK, "lock add dword [eax], ebx"),
+            (&[0xf0, 0xf3, 0x01, 0x18], Add, Prefixes::LOCK, "lock add dword [eax], ebx"),
+            // but #UD on a register destination or on an instruction that can't be locked
+            (&[0xf0, 0x01, 0xd8], Invalid, empty, "invalid"),
+            (&[0xf0, 0x8b, 0x03], Invalid, empty, "invalid"),
+            (&[0xf0, 0xa4], Invalid, empty, "invalid"),
+        ];
+
+        for &(code, mnemonic, prefixes, text) in cases {
+            let instr = Decoder::new(code, 0x1000).decode().unwrap();
+            assert_eq!(
+                (instr.mnemonic, instr.prefixes, instr.to_string().as_str()),
+                (mnemonic, prefixes, text),
+                "{:02x?}",
+                code
+            );
+            // the prefixes are always part of the instruction
+            assert_eq!(instr.len as usize, code.len(), "{:02x?}", code);
+        }
+    }
+
+    #[test_log::test]
+    fn lengths_match_dynasm() {
+        macro_rules! case {
+            ($($asm:tt)*) => {
+                (stringify!($($asm)*), assemble_x86!(; $($asm)*))
+            };
+        }
+        let cases = [
+            // the ModRM & SIB forms, with the displacements of every size
+            case!(mov eax, ebx),
+            case!(mov eax, [ebx]),
+            case!(mov eax, [ebx + 0x10]),
+            case!(mov eax, [ebx + 0x1000]),
+            case!(mov eax, [ebx - 0x80]),
+            case!(mov eax, [ebx + ecx * 4 + 0x10]),
+            case!(mov eax, [ebx + ecx * 8 + 0x12345]),
+            case!(mov eax, [ecx * 2]),
+            case!(mov eax, [ecx * 4 + 0x10]),
+            case!(mov eax, [esp]),
+            case!(mov eax, [esp + 4]),
+            case!(mov eax, [ebp]),
+            case!(mov eax, [ebp + ecx]),
+            case!(mov eax, [0x100000]),
+            case!(mov[0x100000], eax),
+            case!(mov DWORD [ebx + 4], 0x12345678),
+            case!(mov BYTE [ebx + ecx], 0x12),
+            case!(lea eax, [ebx + ecx * 2 - 0x20]),
+            // the immediates: imm8 sign-extended, imm16, imm32
+            case!(add eax, 1),
+            case!(add eax, 0x1000),
+            case!(add ebx, 0x1000),
+            case!(add al, 1),
+            case!(add bl, 1),
+            case!(add DWORD [eax], 1),
+            case!(add DWORD [eax + 8], 0x1000),
+            case!(mov eax, 0x12345678),
+            case!(mov al, 0x12),
+            case!(push 1),
+            case!(push 0x1000),
+            case!(ret 8),
+            case!(enter 0x10, 0),
+            case!(imul eax, ebx, 3),
+            case!(imul eax, [ebx], 0x1000),
+            case!(shl eax, 3),
+            case!(shl eax, 1),
+            case!(shl eax, cl),
+            case!(shld eax, ebx, 4),
+            case!(shrd eax, ebx, cl),
+            // the operand size prefix
+            case!(mov ax, bx),
+            case!(mov ax, 0x1234),
+            case!(add ax, 1),
+            case!(add ax, 0x1234),
+            case!(add WORD [eax], 0x1234),
+            case!(movzx eax, WORD [ebx]),
+            case!(movsx ax, bl),
+            case!(push ax),
+            case!(inc ax),
+            // two-byte opcodes
+            case!(movzx eax, bl),
+            case!(cmovl edx, eax),
+            case!(sete al),
+            case!(bt eax, 5),
+            case!(bsf eax, [ebx]),
+            case!(bswap eax),
+            case!(xadd[eax], ebx),
+            case!(cmpxchg[eax], ebx),
+            case!(cdq),
+            case!(cpuid),
+            case!(rdtsc),
+            // the string instructions & rep
+            case!(movsb),
+            case!(movsw),
+            case!(rep movsd),
+            case!(rep stosw),
+            case!(repe cmpsb),
+            case!(repne scasw),
+            case!(lodsd),
+            // lock
+            case!(lock add [eax], ebx),
+            case!(lock inc DWORD [eax + 4]),
+            case!(lock xadd [eax], ecx),
+            case!(lock cmpxchg [eax + ecx * 4], edx),
+            case!(lock add WORD [eax], 0x1234),
+            case!(xchg[eax], ebx),
+            // segment overrides
+            case!(fs mov eax, [0x18]),
+            case!(fs mov eax, [ebx + 4]),
+            case!(gs mov WORD [ebx], 0x1234),
+            case!(rep movsb),
+            // the branches: short & near
+            case!(jmp BYTE >next; next:),
+            case!(jmp >next; next:),
+            case!(jne BYTE >next; next:),
+            case!(jne >next; next:),
+            case!(call >next; next:),
+            case!(call eax),
+            case!(call DWORD [eax + 8]),
+            case!(jmp DWORD [ecx * 4 + 0x1000]),
+            case!(loop >next; next:),
+            case!(jecxz >next; next:),
+            // x87
+            case!(fld DWORD [eax]),
+            case!(fld QWORD [eax + 8]),
+            case!(fstp QWORD [esp]),
+            case!(faddp st1, st0),
+            case!(fild DWORD [ebx]),
+            case!(fistp WORD [ebx]),
+            case!(fnstsw ax),
+            case!(fstsw ax),
+            case!(fnstcw WORD [esp]),
+            case!(fldcw WORD [esp]),
+            // SSE, with the mandatory prefixes
+            case!(movaps xmm0, [eax]),
+            case!(movups[eax + 0x10], xmm1),
+            case!(movss xmm0, [eax]),
+            case!(movsd xmm1, QWORD [eax]),
+            case!(addps xmm0, xmm1),
+            case!(addsd xmm0, xmm1),
+            case!(pxor xmm2, xmm3),
+            case!(movd xmm0, eax),
+            case!(movq xmm0, QWORD [eax]),
+            case!(cvttss2si eax, xmm0),
+            case!(cvtsi2sd xmm0, eax),
+            case!(ucomiss xmm0, xmm1),
+            case!(shufps xmm0, xmm1, 0x1b),
+            case!(pshufd xmm0, xmm1, 0x1b),
+            // other odd ones
+            case!(int 0x2e),
+            case!(int3),
+            case!(nop),
+            case!(hlt),
+            case!(in al, 0x60),
+            case!(out dx, eax),
+            case!(aam),
+        ];
+
+        let decoder = |code| {
+            Decoder::new(code, 0x1000).unsupported_instructions(UnsupportedInstructionPolicy::Trap)
+        };
+        for (text, code) in &cases {
+            let mut decoder = decoder(code);
+            let instr = decoder.decode().unwrap();
+            assert_eq!(instr.len as usize, code.len(), "{}: {:02x?}", text, code);
+            assert_eq!(decoder.ip(), instr.next_ip(), "{}", text);
+            assert!(!decoder.can_decode(), "{}", text);
+        }
+
+        // all in a row: one desync and the rest decodes as garbage
+        let code = cases
+            .iter()
+            .flat_map(|(_, code)| code)
+            .copied()
+            .collect::<Vec<_>>();
+        let mut decoder = decoder(&code);
+        let mut ip = 0x1000;
+        for (text, code) in &cases {
+            let instr = decoder.decode().unwrap();
+            assert_eq!((instr.ip, instr.len as usize), (ip, code.len()), "{}", text);
+            assert!(
+                instr.contains(ip) && instr.contains(instr.next_ip() - 1),
+                "{}",
+                text
+            );
+            assert!(!instr.contains(instr.next_ip()), "{}", text);
+            ip = instr.next_ip();
+        }
+        assert!(!decoder.can_decode());
+    }
+
+    #[test_log::test]
+    fn wait_forms() {
+        let code = [
+            0x9b, 0xdf, 0xe0, // fstsw ax
+            0xdf, 0xe0, // fnstsw ax
+            0x9b, 0xd9, 0x7d, 0x00, // fstcw [ebp]
+            0x9b, 0xdb, 0xe3, // finit
+            0xdb, 0xe3, // fninit
+            0x9b, 0x90, // wait; nop
+            0x9b, // wait at the very end
+        ];
+        let instrs = decode_all(&code);
+        assert_eq!(
+            instrs
+                .iter()
+                .map(|i| (i.ip - 0x1000, i.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, "fstsw ax".to_string()),
+                (3, "fnstsw ax".to_string()),
+                (5, "fstcw word [ebp]".to_string()),
+                (9, "finit".to_string()),
+                (12, "fninit".to_string()),
+                (14, "wait".to_string()),
+                (15, "nop".to_string()),
+                (16, "wait".to_string()),
+            ]
+        );
+        assert_eq!(instrs[0].len, 3);
+    }
+
+    #[test_log::test]
+    fn control_registers() {
+        let code = assemble_x86!(
+            ; mov eax, cr0
+            ; mov cr3, ebx
+            ; smsw [eax]
+            ; lmsw cx
+        );
+        let instrs = decode_all(&code);
+        assert_eq!(
+            instrs
+                .iter()
+                .map(|i| (i.mnemonic, i.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Mnemonic::MovCr, "mov eax, cr0".to_string()),
+                (Mnemonic::MovCr, "mov cr3, ebx".to_string()),
+                (Mnemonic::Smsw, "smsw word [eax]".to_string()),
+                // the register form takes a 32-bit register, using only the low word of it
+                (Mnemonic::Lmsw, "lmsw ecx".to_string()),
+            ]
+        );
+        assert_eq!(instrs[1].operands[0], Operand::ControlRegister(3));
+    }
+
+    #[test_log::test]
+    fn cache_maintenance() {
+        // clflush [eax]; clflushopt [ebx+4]; invd; wbinvd; invlpg [ecx]
+        let code = [
+            0x0f, 0xae, 0x38, 0x66, 0x0f, 0xae, 0x7b, 0x04, 0x0f, 0x08, 0x0f, 0x09, 0x0f, 0x01,
+            0x39,
+        ];
+        let instrs = decode_all(&code);
+        assert_eq!(
+            instrs
+                .iter()
+                .map(|i| (i.mnemonic, i.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                (Mnemonic::Clflush, "clflush byte [eax]".to_string()),
+                (Mnemonic::Clflush, "clflush byte [ebx+0x4]".to_string()),
+                (Mnemonic::Invd, "invd".to_string()),
+                (Mnemonic::Wbinvd, "wbinvd".to_string()),
+                (Mnemonic::Invlpg, "invlpg [ecx]".to_string()),
+            ]
+        );
+    }
+
+    #[test_log::test]
+    fn invalid_opcodes() {
+        // salc; ud2; (ff /7 is not an instruction)
+        let code = [0xd6, 0x0f, 0x0b, 0xff, 0xf8, 0x90];
+        let instrs = decode_all(&code);
+        assert_eq!(
+            instrs.iter().map(|i| i.mnemonic).collect::<Vec<_>>(),
+            vec![
+                Mnemonic::Salc,
+                Mnemonic::Invalid,
+                Mnemonic::Invalid,
+                Mnemonic::Nop
+            ]
+        );
+        assert_eq!(instrs[2].ip, 0x1003);
+
+        // the block ends at the first invalid instruction
+        let block = decode_block(
+            &code,
+            0x1000,
+            usize::MAX,
+            InvalidOpcodePolicy::Fault,
+            UnsupportedInstructionPolicy::Fail,
+            CodeMode::Bits32,
+        )
+        .unwrap();
+        assert_eq!(block.len(), 2);
+
+        // pretend that ff f8 is a two-byte nop on some exotic CPU
+        fn fallback(bytes: &[u8], ip: u32) -> Option<Instr> {
+            match bytes {
+                [0xff, 0xf8, ..] => Some(Instr::new(ip, 2, Mnemonic::Nop, vec![])),
+                _ => None,
+            }
+        }
+        let mut decoder = Decoder::new(&code[3..], 0x1003)
+            .invalid_opcodes(InvalidOpcodePolicy::Fallback(fallback));
+        assert_eq!(
+            decoder.decode().unwrap(),
+            Instr::new(0x1003, 2, Mnemonic::Nop, vec![])
+        );
+        // and the decoding continues after it
+        assert_eq!(decoder.ip(), 0x1005);
+        assert_eq!(decoder.decode().unwrap().ip, 0x1005);
+
+        // the fallback doesn't know this one
+        let mut decoder = Decoder::new(&[0xfe, 0xf8], 0x1000)
+            .invalid_opcodes(InvalidOpcodePolicy::Fallback(fallback));
+        assert_eq!(decoder.decode().unwrap().mnemonic, Mnemonic::Invalid);
+    }
+
+    #[test_log::test]
+    #[rustfmt::skip]
+    fn display() {
+        let code = assemble_x86!(
+            ; mov eax, [ebx + ecx*4 + 0x10]
+            ; add al, 1
+            ; jne ->end
+            ; rep stosd
+            ; repe scasw
+            ; cmovl edx, eax
+            ; call ->end
+            ; ->end:
+            ; ret
+        );
+        let text: Vec<String> = decode_all(&code).iter().map(|i| i.to_string()).collect();
+
+        assert_eq!(text, vec![
+            "mov eax, dword [ebx+ecx*4+0x10]",
+            "add al, byte 0x1",
+            "jne 0x1019",
+            "rep stosd",
+            "repe scasw",
+            "cmovl edx, eax",
+            "call 0x1019",
+            "ret",
+        ]);
+    }
+}
@@ -1,89 +1,183 @@
 extern crate core;
 
+pub mod allocator;
 pub mod backend;
+#[cfg(feature = "capi")]
+pub mod capi;
+pub mod config;
+pub mod constprop;
+pub mod coverage;
 pub mod disasm;
+#[cfg(feature = "llvm")]
+pub mod dos;
+pub mod flags;
+pub mod fpu;
+pub mod handler;
+pub mod inlining;
+pub mod intel_syntax;
+#[cfg(feature = "interp")]
+pub mod interp;
+pub mod ir;
+pub mod liveness;
+#[cfg(feature = "llvm")]
 pub mod llvm;
 pub mod memory_image;
+pub mod peephole;
+#[cfg(feature = "llvm")]
+pub mod runtime;
+pub mod segmentation;
+pub mod sse;
+pub mod switch_table;
+pub mod system_registers;
 pub mod types;
+#[cfg(feature = "llvm")]
+pub mod win32;
 
 use crate::backend::{Builder, ComparisonType, IntValue};
 use crate::disasm::Operands;
+use crate::handler::{GuestFault, InstructionBytes};
+use crate::ir::{Condition, Instr, Mnemonic, Prefixes};
+use crate::memory_image::Protection;
+use crate::system_registers::{
+    ControlRegisterWrites, SystemRegisterProfile, UnknownMsrReads, CR4_PCE, MSW_WRITABLE,
+};
 use crate::types::Register::*;
-use crate::types::{ControlFlow, Flag, IntType, Operand, Register};
-use iced_x86::{ConditionCode, Instruction, Mnemonic};
-
-#[allow(clippy::let_and_return)]
-fn compute_condition_code<B: Builder>(
+use crate::types::{
+    ConditionCode, ControlFlow, Flag, FlagStorage, IntType, Operand, Register, EFLAGS_AC_BIT,
+    EFLAGS_FIXED, EFLAGS_IF_BIT, PF_INSTRUCTION_FETCH,
+};
+
+/// `cmp lhs, rhs` fused with the instruction reading its flags (see peephole.rs): the flags are stored the same way
+/// as for a lone `cmp` (and dropped if dead), while `condition_code` is evaluated on the operands themselves, so the
+/// consumer doesn't have to read the flags back
+fn codegen_fused_cmp<B: Builder>(
     builder: &mut B,
-    condition_code: ConditionCode,
+    lhs: Operand,
+    rhs: Operand,
+    condition_code: Condition,
 ) -> B::BoolValue {
-    let mut comp = |cc| compute_condition_code(builder, cc);
+    store_zero_idiom_pf(builder, lhs, rhs);
+
+    let lhs = builder.load_operand(lhs);
+    let rhs = builder.load_operand(rhs);
+    let res = builder.sub(lhs, rhs);
+
+    let of = builder.ssub_overflow(lhs, rhs);
+    let cf = builder.usub_overflow(lhs, rhs);
+    let sf = builder.extract_msb(res);
+
+    builder.compute_and_store_zf(res);
+    builder.store_flag(Flag::Sign, sf);
+    builder.store_flag(Flag::Overflow, of);
+    builder.store_flag(Flag::Carry, cf);
+
+    use ComparisonType::*;
+    use Condition::*;
+    let comparison = match condition_code {
+        O => return of,
+        NO => return builder.bool_not(of),
+        S => return sf,
+        NS => return builder.bool_not(sf),
+        P | NP => unreachable!("cmp doesn't compute PF, so jp & co are not fused"),
+        B => UnsignedLess,
+        AE => UnsignedGreaterOrEqual,
+        E => Equal,
+        NE => NotEqual,
+        BE => UnsignedLessOrEqual,
+        A => UnsignedGreater,
+        L => SignedLess,
+        GE => SignedGreaterOrEqual,
+        LE => SignedLessOrEqual,
+        G => SignedGreater,
+    };
+    builder.icmp(comparison, lhs, rhs)
+}
 
-    use ConditionCode::*;
-    match condition_code {
-        None => panic!("Can't compute None condition"),
+/// PF isn't computed by the integer ops, except for the zero idioms (`xor r, r`, `sub r, r` & `cmp r, r`): the result is
+/// always 0, so PF is always set. Stores it for those, to match what `ZeroReg` does
+fn store_zero_idiom_pf<B: Builder>(builder: &mut B, dst: Operand, src: Operand) {
+    if matches!(dst, Operand::Register(_)) && dst == src {
+        builder.store_flag(Flag::Parity, builder.make_true());
+    }
+}
+
+/// Whether the flags satisfy the condition. The only place the conditions are turned into the flags: `jcc`, `setcc`,
+/// `cmovcc` & the fast `jcc` of the interpreter all go through it (the fused forms compare the operands instead)
+#[allow(clippy::let_and_return)]
+pub fn evaluate_cc<B: Builder>(builder: &mut B, condition_code: ConditionCode) -> B::BoolValue {
+    let mut comp = |cc| evaluate_cc(builder, cc);
 
-        o => {
+    use Condition::*;
+    match condition_code {
+        O => {
             let of = builder.load_flag(Flag::Overflow);
             of
         }
-        no => {
+        NO => {
             let of = builder.load_flag(Flag::Overflow);
             builder.bool_not(of)
         }
 
-        b => {
+        B => {
             let cf = builder.load_flag(Flag::Carry);
             cf
         }
-        ae => {
+        AE => {
             let cf = builder.load_flag(Flag::Carry);
             builder.bool_not(cf)
         }
 
-        e => {
+        E => {
             let zf = builder.load_flag(Flag::Zero);
             zf
         }
-        ne => {
+        NE => {
             let zf = builder.load_flag(Flag::Zero);
             builder.bool_not(zf)
         }
 
-        be => {
+        BE => {
             let cf = builder.load_flag(Flag::Carry);
             let zf = builder.load_flag(Flag::Zero);
             let r = builder.bool_or(cf, zf);
             r
         }
-        a => {
-            let r = comp(be);
+        A => {
+            let r = comp(BE);
             let r = builder.bool_not(r);
             r
         }
 
-        s => {
+        S => {
             let sf = builder.load_flag(Flag::Sign);
             sf
         }
-        ns => {
+        NS => {
             let sf = builder.load_flag(Flag::Sign);
             builder.bool_not(sf)
         }
 
-        p | np => unimplemented!("condition code {:?}", condition_code),
+        // PF is only meaningful after a float comparison (ucomiss & co), which is where jp is used anyway
+        P => {
+            let pf = builder.load_flag(Flag::Parity);
+            pf
+        }
+        NP => {
+            let pf = builder.load_flag(Flag::Parity);
+            builder.bool_not(pf)
+        }
 
-        l => {
+        L => {
             let sf = builder.load_flag(Flag::Sign);
             let of = builder.load_flag(Flag::Overflow);
             builder.bool_xor(sf, of)
         }
-        ge => {
-            let r = comp(l);
+        GE => {
+            let r = comp(L);
             builder.bool_not(r)
         }
 
-        le => {
+        LE => {
             let sf = builder.load_flag(Flag::Sign);
             let of = builder.load_flag(Flag::Overflow);
             let zf = builder.load_flag(Flag::Zero);
@@ -91,75 +185,325 @@ fn compute_condition_code<B: Builder>(
             let r = builder.bool_or(is_l, zf);
             r
         }
-        g => {
-            let r = comp(le);
+        G => {
+            let r = comp(LE);
             builder.bool_not(r)
         }
     }
 }
 
-#[rustfmt::skip]
-fn is_cmovcc(mnemonic: Mnemonic) -> bool {
-    use Mnemonic::*;
-    match mnemonic {
-        Cmova |
-        Cmovae |
-        Cmovb |
-        Cmovbe |
-        Cmove |
-        Cmovg |
-        Cmovge |
-        Cmovl |
-        Cmovle |
-        Cmovne |
-        Cmovno |
-        Cmovnp |
-        Cmovns |
-        Cmovo |
-        Cmovp |
-        Cmovs  => {
-            true
-        },
-        _ => false,
+/// Resolves the target of a far jmp/call through the segmentation policy, raising the fault if it's not allowed
+fn far_transfer_target<B: Builder>(builder: &mut B, selector: u16, offset: u32) -> Option<u32> {
+    match builder.segmentation().far_target(selector, offset) {
+        Ok(target) => Some(target),
+        Err(fault) => {
+            builder.raise_fault(fault, builder.make_u32(offset));
+            None
+        }
+    }
+}
+
+/// The flags that go into the EFLAGS image. AF is not maintained (and can't be loaded), so it's always pushed as 0
+const EFLAGS_IMAGE_FLAGS: [Flag; 7] = [
+    Flag::Carry,
+    Flag::Parity,
+    Flag::Zero,
+    Flag::Sign,
+    Flag::Direction,
+    Flag::Overflow,
+    Flag::Id,
+];
+
+/// The bits of `EFLAGS_IMAGE_FLAGS` in EFLAGS
+fn eflags_image_mask() -> u32 {
+    EFLAGS_IMAGE_FLAGS
+        .iter()
+        .fold(0, |mask, flag| mask | 1 << flag.eflags_bit())
+}
+
+/// Packs the flags into an EFLAGS value, as pushed by `int n` (and `pushfd`)
+fn pack_eflags<B: Builder>(builder: &mut B) -> B::IntValue {
+    let mut eflags = builder.make_u32(EFLAGS_FIXED);
+    let mut bits = Vec::new();
+    match builder.flag_storage() {
+        // already in their places, only AF has to go
+        FlagStorage::Packed => {
+            let flags = builder.load_packed_flags();
+            let flags = builder.int_and(flags, builder.make_u32(eflags_image_mask()));
+            eflags = builder.int_or(eflags, flags);
+        }
+        FlagStorage::Bytes => bits.extend(
+            EFLAGS_IMAGE_FLAGS
+                .iter()
+                .map(|&flag| (builder.load_flag(flag), flag.eflags_bit())),
+        ),
+    }
+    let interrupt_flag = builder.load_interrupt_flag();
+    let alignment_check = builder.load_alignment_check_flag();
+    let system_bits = [
+        (interrupt_flag, EFLAGS_IF_BIT),
+        (alignment_check, EFLAGS_AC_BIT),
+    ];
+    for (value, bit) in bits.into_iter().chain(system_bits) {
+        let value = builder.bool_to_int(value, IntType::I32);
+        let value = builder.shl(value, builder.make_u32(bit));
+        eflags = builder.int_or(eflags, value);
+    }
+    eflags
+}
+
+/// The reverse of `pack_eflags`: takes the flags (and IF & AC) from an EFLAGS value, as `iretd` (and `popfd`) do
+///
+/// The guest is treated as if it had IOPL 3, so IF is writable. Everything we don't keep is dropped: the reserved bits
+/// (bit 1 reads as 1 no matter what was popped), AF, TF, IOPL, NT, RF, VM & the virtual interrupt flags
+fn unpack_eflags<B: Builder>(builder: &mut B, eflags: B::IntValue) {
+    match builder.flag_storage() {
+        // AF is the only one kept that doesn't come from the value
+        FlagStorage::Packed => {
+            let mask = eflags_image_mask();
+            let old = builder.load_packed_flags();
+            let kept = builder.int_and(old, builder.make_u32(!mask));
+            let new = builder.int_and(eflags, builder.make_u32(mask));
+            let flags = builder.int_or(kept, new);
+            builder.store_packed_flags(flags);
+        }
+        FlagStorage::Bytes => {
+            for flag in EFLAGS_IMAGE_FLAGS {
+                let value = builder.extract_bit(eflags, builder.make_u32(flag.eflags_bit()));
+                builder.store_flag(flag, value);
+            }
+        }
+    }
+    let interrupt_flag = builder.extract_bit(eflags, builder.make_u32(EFLAGS_IF_BIT));
+    builder.store_interrupt_flag(interrupt_flag);
+    let alignment_check = builder.extract_bit(eflags, builder.make_u32(EFLAGS_AC_BIT));
+    builder.store_alignment_check_flag(alignment_check);
+}
+
+/// `unpack_eflags` for the lower half of EFLAGS (`iret` & `popf`), AC & the rest of the upper half stay
+fn unpack_flags16<B: Builder>(builder: &mut B, flags: B::IntValue) {
+    let flags = builder.zext(flags, IntType::I32);
+    let eflags = pack_eflags(builder);
+    let upper = builder.int_and(eflags, builder.make_u32(0xffff_0000));
+    let eflags = builder.int_or(upper, flags);
+    unpack_eflags(builder, eflags);
+}
+
+/// `int n`: to the guest handler if there is one in the `InterruptVectorTable`, to the host otherwise
+fn software_interrupt<B: Builder>(builder: &mut B, vector: u8, next_eip: u32) -> ControlFlow<B> {
+    let table = builder.interrupt_vectors();
+    let (handler, clear_if) = match table.handler(vector) {
+        Some(handler) => (handler, table.clear_if),
+        None => {
+            builder.interrupt(vector, next_eip);
+            return ControlFlow::NextInstruction;
+        }
+    };
+
+    // FLAGS, CS & IP in the 16-bit code
+    let word = builder.code_mode().stack_word();
+    let mut eflags = pack_eflags(builder);
+    if word == IntType::I16 {
+        eflags = builder.trunc(eflags, word);
+    }
+    builder.push(eflags);
+    // the CS placeholder
+    builder.push(builder.make_int_value(word, 0, false));
+    builder.push(builder.make_int_value(word, next_eip as u64, false));
+    if clear_if {
+        builder.store_interrupt_flag(builder.make_false());
+    }
+
+    // the iretd comes back to next_eip through the dispatcher (or goes wherever the handler pointed the frame to)
+    ControlFlow::DirectJump(handler)
+}
+
+/// What `call` pushes: the address after it, 16 bits of it in the 16-bit code
+fn push_return_address<B: Builder>(builder: &mut B, ret: u32) {
+    let word = builder.code_mode().stack_word();
+    builder.push(builder.make_int_value(word, ret as u64, false));
+}
+
+/// The repetition prefix of a string instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RepPrefix {
+    Rep,
+    Repe,
+    Repne,
+}
+
+impl RepPrefix {
+    fn of(instr: &Instr) -> Option<Self> {
+        // REP and REPE are actually encoded the same way
+        // Semantics depend on the instruction encoded
+        if instr.prefixes.contains(Prefixes::REP) {
+            match instr.mnemonic {
+                Mnemonic::Scas | Mnemonic::Cmps => Some(RepPrefix::Repe),
+                _ => Some(RepPrefix::Rep),
+            }
+        } else if instr.prefixes.contains(Prefixes::REPNE) {
+            Some(RepPrefix::Repne)
+        } else {
+            None
+        }
+    }
+}
+
+/// The pointers & the counter of a string instruction: ESI, EDI & ECX, or SI, DI & CX with the 16-bit address
+/// size (the 0x67 prefix). The 16-bit ones wrap around at 64 KiB, leaving the upper halves of the registers alone
+#[derive(Debug, Clone, Copy)]
+struct StringRegisters {
+    source: Register,
+    destination: Register,
+    count: Register,
+}
+
+impl StringRegisters {
+    fn of(instr: &Instr) -> Self {
+        let address16 = instr.operands.iter().any(|op| match op {
+            Operand::Memory(m) => m.base.is_some_and(|base| base.size() == IntType::I16),
+            _ => false,
+        });
+        if address16 {
+            Self {
+                source: Register::SI,
+                destination: Register::DI,
+                count: Register::CX,
+            }
+        } else {
+            Self {
+                source: Register::ESI,
+                destination: Register::EDI,
+                count: Register::ECX,
+            }
+        }
     }
 }
 
-fn codegen_string_instr<B: Builder>(builder: &mut B, instr: Instruction) {
+/// Repeats `body` (a single iteration of the string instruction) the way a rep prefix does it:
+///
+/// 1. ECX (`count_reg`) is tested *before* each iteration. If it's zero on entry the body is not executed at all,
+///    so nothing (including the flags) is touched
+/// 2. the body is executed
+/// 3. ECX is decremented
+/// 4. the loop ends if ECX became zero *or* (for repe/repne) the ZF produced by this iteration says so.
+///    Both are checked after the same iteration, so when ECX hits zero on the element that would
+///    also terminate the loop, ECX is zero and the flags are those of the last comparison
+///
+/// As the pointers are advanced by the body, they end up pointing one element past the last processed one
+fn codegen_rep<B: Builder, F: Fn(&mut B)>(
+    builder: &mut B,
+    prefix: RepPrefix,
+    count_reg: Register,
+    body: F,
+) {
+    let zero = builder.make_int_value(count_reg.size(), 0, false);
+    let one = builder.make_int_value(count_reg.size(), 1, false);
+
+    let start_count = builder.load_register(count_reg);
+    let should_enter = builder.icmp(ComparisonType::NotEqual, start_count, zero);
+    builder.ifelse(
+        should_enter,
+        |builder| {
+            builder.repeat_until(|builder| {
+                body(builder);
+
+                let counter = builder.load_register(count_reg);
+                let counter = builder.sub(counter, one);
+
+                builder.store_register(count_reg, counter);
+
+                let counter_continue = builder.icmp(ComparisonType::NotEqual, counter, zero);
+
+                let additional_continue = match prefix {
+                    RepPrefix::Rep => builder.make_true(),
+                    RepPrefix::Repe => builder.load_flag(Flag::Zero),
+                    RepPrefix::Repne => {
+                        let zf = builder.load_flag(Flag::Zero);
+                        builder.bool_not(zf)
+                    }
+                };
+
+                builder.bool_and(counter_continue, additional_continue)
+            });
+        },
+        |_| {},
+    );
+}
+
+fn codegen_string_instr<B: Builder>(builder: &mut B, instr: &Instr) {
+    let registers = StringRegisters::of(instr);
+
     let advance_reg = |builder: &mut B, size: IntType, reg: Register| {
-        let size = builder.make_u32(size.byte_width() as u32);
-        let edi = builder.load_register(reg);
+        let size = builder.make_int_value(reg.size(), size.byte_width() as u64, false);
+        let ptr = builder.load_register(reg);
 
-        // if DF = 1 => EDI += size, else => EDI -= size
+        // if DF = 1 => ptr -= size, else => ptr += size
         let df = builder.load_flag(Flag::Direction);
         builder.ifelse(
             df,
             |builder| {
-                let edi = builder.sub(edi, size);
-                builder.store_register(reg, edi);
+                let ptr = builder.sub(ptr, size);
+                builder.store_register(reg, ptr);
             },
             |builder| {
-                let edi = builder.add(edi, size);
-                builder.store_register(reg, edi);
+                let ptr = builder.add(ptr, size);
+                builder.store_register(reg, ptr);
             },
         );
     };
 
-    let advance_edi = |builder: &mut B, size: IntType| advance_reg(builder, size, Register::EDI);
-    let advance_esi = |builder: &mut B, size: IntType| advance_reg(builder, size, Register::ESI);
+    let advance_edi =
+        |builder: &mut B, size: IntType| advance_reg(builder, size, registers.destination);
+    let advance_esi = |builder: &mut B, size: IntType| advance_reg(builder, size, registers.source);
+
+    // sets the flags like `cmp lhs, rhs` does
+    let compare = |builder: &mut B, lhs: Operand, rhs: Operand| {
+        // this code duplicates Sub & Cmp...
+        let lhs = builder.load_operand(lhs);
+        let rhs = builder.load_operand(rhs);
+        let res = builder.sub(lhs, rhs);
+
+        let of = builder.ssub_overflow(lhs, rhs);
+        let cf = builder.usub_overflow(lhs, rhs);
+
+        // The OF, SF, ZF, AF, PF, and CF flags are set according
+        //   to the temporary result of the comparison.
+        // AF and PF are not implemented rn
+        // not that they are actually useful...
+        builder.compute_and_store_zf(res);
+        builder.compute_and_store_sf(res);
+        builder.store_flag(Flag::Overflow, of);
+        builder.store_flag(Flag::Carry, cf);
+    };
 
     let execute_instr = |builder: &mut B| {
         use Mnemonic::*;
         // this handles the core instruction
-        match instr.mnemonic() {
-            // no port IO for you
-            Insb | Insw | Insd | Outsb | Outsw | Outsd => unimplemented!(),
+        match instr.mnemonic {
+            // through the handler's ports, an element at a time
+            Ins => {
+                operands!([dst, port], instr);
+
+                let port = builder.load_operand(port);
+                let val = builder.port_in(port, dst.size());
+                builder.store_operand(dst, val);
+
+                advance_edi(builder, dst.size());
+            }
 
-            Lodsb | Lodsw | Lodsd | Cmpsb | Cmpsw | Cmpsd => {
-                todo!("{:?}", instr.mnemonic())
+            Outs => {
+                operands!([port, src], instr);
+
+                let port = builder.load_operand(port);
+                let val = builder.load_operand(src);
+                builder.port_out(port, val);
+
+                advance_esi(builder, src.size());
             }
 
-            Movsb | Movsw | Movsd => {
-                operands!([dst, src], &instr);
+            Movs => {
+                operands!([dst, src], instr);
 
                 let val = builder.load_operand(src);
                 builder.store_operand(dst, val);
@@ -168,8 +512,8 @@ fn codegen_string_instr<B: Builder>(builder: &mut B, instr: Instruction) {
                 advance_edi(builder, dst.size());
             }
 
-            Stosb | Stosw | Stosd => {
-                operands!([dst, val], &instr);
+            Stos => {
+                operands!([dst, val], instr);
 
                 let val = builder.load_operand(val);
                 builder.store_operand(dst, val);
@@ -177,123 +521,123 @@ fn codegen_string_instr<B: Builder>(builder: &mut B, instr: Instruction) {
                 advance_edi(builder, dst.size());
             }
 
-            Scasb | Scasw | Scasd => {
-                operands!([cmp, src], &instr);
+            Lods => {
+                operands!([dst, src], instr);
 
-                // this code duplicates Sub & Cmp...
-                let lhs = builder.load_operand(cmp);
-                let rhs = builder.load_operand(src);
-                let res = builder.sub(lhs, rhs);
+                let val = builder.load_operand(src);
+                builder.store_operand(dst, val);
 
-                let of = builder.ssub_overflow(lhs, rhs);
-                let cf = builder.usub_overflow(lhs, rhs);
+                advance_esi(builder, dst.size());
+            }
 
-                // The OF, SF, ZF, AF, PF, and CF flags are set according
-                //   to the temporary result of the comparison.
-                // AF and PF are not implemented rn
-                // not that they are actually useful...
-                builder.compute_and_store_zf(res);
-                builder.compute_and_store_sf(res);
-                builder.store_flag(Flag::Overflow, of);
-                builder.store_flag(Flag::Carry, cf);
+            Scas => {
+                operands!([cmp, src], instr);
+
+                compare(builder, cmp, src);
 
                 advance_edi(builder, src.size());
             }
-            _ => unreachable!(),
-        }
-    };
 
-    // those handle the repetition
-    enum Prefix {
-        Rep,
-        Repe,
-        Repne,
-    }
+            Cmps => {
+                // [esi] is compared to es:[edi]
+                operands!([src1, src2], instr);
 
-    // REP and REPE are actually encoded the same way
-    // Semantics depend on the instruction encoded
-    let prefix = if instr.has_rep_prefix() {
-        use Mnemonic::*;
-        match instr.mnemonic() {
-            Scasb | Scasw | Scasd | Cmpsb | Cmpsw | Cmpsd => Some(Prefix::Repe),
-            _ => Some(Prefix::Rep),
+                compare(builder, src1, src2);
+
+                advance_esi(builder, src1.size());
+                advance_edi(builder, src2.size());
+            }
+            _ => unreachable!(),
         }
-    } else if instr.has_repne_prefix() {
-        Some(Prefix::Repne)
-    } else {
-        None
     };
 
-    if let Some(prefix) = prefix {
-        let count_reg = Register::ECX;
-
-        let start_count = builder.load_register(count_reg);
-        let should_enter = builder.icmp(ComparisonType::NotEqual, start_count, builder.make_u32(0));
-        builder.ifelse(
-            should_enter,
-            |builder| {
-                builder.repeat_until(|builder| {
-                    execute_instr(builder);
-
-                    let counter = builder.load_register(count_reg);
-                    let counter = builder.sub(counter, builder.make_u32(1));
-
-                    builder.store_register(count_reg, counter);
-
-                    let counter_continue =
-                        builder.icmp(ComparisonType::NotEqual, counter, builder.make_u32(0));
-
-                    let additional_continue = match prefix {
-                        Prefix::Rep => builder.make_true(),
-                        Prefix::Repe => builder.load_flag(Flag::Zero),
-                        Prefix::Repne => {
-                            let zf = builder.load_flag(Flag::Zero);
-                            builder.bool_not(zf)
-                        }
-                    };
-
-                    builder.bool_and(counter_continue, additional_continue)
-                });
-            },
-            |_| {},
-        );
-    } else {
-        execute_instr(builder)
+    match RepPrefix::of(instr) {
+        Some(prefix) => codegen_rep(builder, prefix, registers.count, execute_instr),
+        None => execute_instr(builder),
     }
 }
 
+/// The arithmetic flags are computed the way `flags::add` & `flags::sub` spell them out
 // TODO: handle control flow
-pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> ControlFlow<B> {
+pub fn codegen_instr<B: Builder>(builder: &mut B, instr: &Instr) -> ControlFlow<B> {
+    use crate::ir::Mnemonic::*;
     use crate::Flag::*;
-    use iced_x86::Mnemonic::*;
 
-    assert!(!instr.has_lock_prefix());
-    assert!(!instr.has_xacquire_prefix());
-    assert!(!instr.has_xrelease_prefix());
+    // the guest has a single thread, so a locked instruction is atomic without any help (the decoder has already
+    // rejected LOCK where it's not allowed). Except for `xchg` with memory: that one is the way to talk to whatever
+    // else shares the memory, see `Builder::exchange_memory`
 
-    if instr.is_string_instruction() {
+    builder.begin_instruction(instr);
+
+    if instr.mnemonic.is_string() {
         codegen_string_instr(builder, instr);
         return ControlFlow::NextInstruction;
     }
 
-    assert!(!instr.has_rep_prefix());
-    assert!(!instr.has_repe_prefix());
-    assert!(!instr.has_repne_prefix());
+    if instr.mnemonic.is_fpu() {
+        fpu::codegen_fpu_instr(builder, instr);
+        return ControlFlow::NextInstruction;
+    }
+
+    if instr.mnemonic.is_sse() {
+        sse::codegen_sse_instr(builder, instr);
+        return ControlFlow::NextInstruction;
+    }
+
+    assert!(!instr.prefixes.contains(Prefixes::REP));
+    assert!(!instr.prefixes.contains(Prefixes::REPNE));
+
+    let mnemonic = instr.mnemonic;
 
-    let mnemonic = instr.mnemonic();
+    if let Jcc(code) = mnemonic {
+        operands!([target], instr);
+
+        let cond = evaluate_cc(builder, code);
+
+        ControlFlow::Conditional(cond, target.as_imm32())
+    } else if let TestJcc(code) = mnemonic {
+        operands!([reg, target], instr);
+
+        let val = builder.load_operand(reg);
+        let zero = builder.make_int_value(val.size(), 0, false);
+        let is_zero = builder.icmp(ComparisonType::Equal, val, zero);
+
+        // same flags as the `test` would set
+        builder.store_flag(Flag::Zero, is_zero);
+        builder.compute_and_store_sf(val);
+        builder.store_flag(Flag::Carry, builder.make_false());
+        builder.store_flag(Flag::Overflow, builder.make_false());
+
+        let cond = match code {
+            Condition::E => is_zero,
+            Condition::NE => builder.bool_not(is_zero),
+            _ => unreachable!(),
+        };
 
-    if instr.is_jcc_short_or_near() {
-        operands!([target], &instr);
+        ControlFlow::Conditional(cond, target.as_imm32())
+    } else if let CmpJcc(code) = mnemonic {
+        operands!([lhs, rhs, target], instr);
 
-        let code = instr.condition_code();
-        let cond = compute_condition_code(builder, code);
+        let cond = codegen_fused_cmp(builder, lhs, rhs, code);
 
         ControlFlow::Conditional(cond, target.as_imm32())
-    } else if is_cmovcc(instr.mnemonic()) {
-        operands!([dst, src], &instr);
+    } else if let Setcc(code) | SetccZx(code) = mnemonic {
+        operands!([dst], instr);
 
-        let code = instr.condition_code();
-        let cond = compute_condition_code(builder, code);
+        // the fused form writes the whole register at once, no merging with the bits above the byte
+        let cond = evaluate_cc(builder, code);
+        let val = builder.bool_to_int(cond, dst.size());
+        builder.store_operand(dst, val);
+
+        ControlFlow::NextInstruction
+    } else if let Cmovcc(code) | CmpCmovcc(code) = mnemonic {
+        let (dst, src, cond) = if mnemonic == CmpCmovcc(code) {
+            operands!([lhs, rhs, dst, src], instr);
+            (dst, src, codegen_fused_cmp(builder, lhs, rhs, code))
+        } else {
+            operands!([dst, src], instr);
+            (dst, src, evaluate_cc(builder, code))
+        };
 
         builder.ifelse(
             cond,
@@ -313,27 +657,27 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 // fuf, this was easy
             }
             Mov => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let val = builder.load_operand(src);
                 builder.store_operand(dst, val);
             }
             Movzx => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let val = builder.load_operand(src);
                 let val = builder.zext(val, dst.size());
                 builder.store_operand(dst, val);
             }
             Movsx => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let val = builder.load_operand(src);
                 let val = builder.sext(val, dst.size());
                 builder.store_operand(dst, val);
             }
             Add => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let lhs = builder.load_operand(dst);
                 let rhs = builder.load_operand(src);
@@ -352,8 +696,36 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 builder.store_flag(Flag::Overflow, of);
                 builder.store_flag(Flag::Carry, cf);
             }
+            Adc => {
+                operands!([dst, src], instr);
+
+                let lhs = builder.load_operand(dst);
+                let rhs = builder.load_operand(src);
+                let carry = builder.load_flag(Carry);
+                let carry = builder.bool_to_int(carry, lhs.size());
+
+                let res = builder.add(lhs, rhs);
+
+                // like in sbb, the two steps can both overflow & cancel out (-128 + -1 + 1), see flags::add.
+                // Only one of them can carry: the first one tops out at 0xff..fe
+                let of_base = builder.sadd_overflow(lhs, rhs);
+                let of_carry = builder.sadd_overflow(res, carry);
+                let of = builder.bool_xor(of_base, of_carry);
+
+                let cf_base = builder.uadd_overflow(lhs, rhs);
+                let cf_carry = builder.uadd_overflow(res, carry);
+                let cf = builder.bool_or(cf_base, cf_carry);
+
+                let res = builder.add(res, carry);
+                builder.store_operand(dst, res);
+
+                builder.compute_and_store_zf(res);
+                builder.compute_and_store_sf(res);
+                builder.store_flag(Flag::Overflow, of);
+                builder.store_flag(Flag::Carry, cf);
+            }
             Sub | Cmp => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let lhs = builder.load_operand(dst);
                 let rhs = builder.load_operand(src);
@@ -373,9 +745,10 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 builder.compute_and_store_sf(res);
                 builder.store_flag(Flag::Overflow, of);
                 builder.store_flag(Flag::Carry, cf);
+                store_zero_idiom_pf(builder, dst, src);
             }
             Sbb => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let lhs = builder.load_operand(dst);
                 let rhs = builder.load_operand(src);
@@ -384,9 +757,10 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
 
                 let res = builder.sub(lhs, rhs);
 
+                // the two steps can both overflow and cancel each other out (127 - -1 - 1), see flags::sub
                 let of_base = builder.ssub_overflow(lhs, rhs);
                 let of_borrow = builder.ssub_overflow(res, borrow);
-                let of = builder.bool_or(of_base, of_borrow);
+                let of = builder.bool_xor(of_base, of_borrow);
 
                 let cf_base = builder.usub_overflow(lhs, rhs);
                 let cf_borrow = builder.usub_overflow(res, borrow);
@@ -404,55 +778,50 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 builder.store_flag(Flag::Carry, cf);
             }
             Lea => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let addr = match src {
                     Operand::Memory(m) => builder.compute_memory_operand_address(m),
                     _ => panic!("Expected 2nd lea operand to be memory reference"),
                 };
 
-                // TODO: size conversion (store 32-bit address as 16-bit value for example)
-                assert_eq!(dst.size(), addr.size());
+                // the address is computed in 32 bits (wrapping around), a 16-bit destination gets the low half
+                // no flags are affected
+                let addr = match dst.size() {
+                    IntType::I32 => addr,
+                    IntType::I16 => builder.trunc(addr, IntType::I16),
+                    size => panic!("Unexpected lea destination size: {:?}", size),
+                };
                 builder.store_operand(dst, addr);
             }
-            Dec => {
-                operands!([dst], &instr);
-
-                let val = builder.load_operand(dst);
-
-                let one = builder.make_int_value(val.size(), 1, false);
-
-                let res = builder.sub(val, one);
-
-                builder.store_operand(dst, res);
-
-                let of = builder.ssub_overflow(val, one);
-
-                // The CF flag is not affected. The OF, SF, ZF, AF, and PF flags are set according to the result.
-                builder.compute_and_store_zf(res);
-                builder.compute_and_store_sf(res);
-                builder.store_flag(Flag::Overflow, of);
-            }
-            Inc => {
-                operands!([dst], &instr);
+            Inc | Dec => {
+                operands!([dst], instr);
 
                 let val = builder.load_operand(dst);
 
                 let one = builder.make_int_value(val.size(), 1, false);
 
-                let res = builder.add(val, one);
+                let res = match mnemonic {
+                    Inc => builder.add(val, one),
+                    _ => builder.sub(val, one),
+                };
 
                 builder.store_operand(dst, res);
 
-                let of = builder.sadd_overflow(val, one);
+                // overflows only going past 0x7f..f up & 0x80..0 down, see flags::inc
+                let of = match mnemonic {
+                    Inc => builder.sadd_overflow(val, one),
+                    _ => builder.ssub_overflow(val, one),
+                };
 
                 // The CF flag is not affected. The OF, SF, ZF, AF, and PF flags are set according to the result.
+                // So unlike add & sub, nothing is stored to it here
                 builder.compute_and_store_zf(res);
                 builder.compute_and_store_sf(res);
                 builder.store_flag(Flag::Overflow, of);
             }
             Neg => {
-                operands!([dst], &instr);
+                operands!([dst], instr);
 
                 let val = builder.load_operand(dst);
 
@@ -470,6 +839,16 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 builder.store_flag(Flag::Overflow, of);
                 builder.store_flag(Flag::Carry, cf);
             }
+            Cbw | Cwde => {
+                let (dst, src) = match mnemonic {
+                    Cbw => (AX, AL),
+                    Cwde => (EAX, AX),
+                    _ => unreachable!(),
+                };
+                let val = builder.load_register(src);
+                let val = builder.sext(val, dst.size());
+                builder.store_register(dst, val);
+            }
             Cwd | Cdq => {
                 let (hi, lo) = match mnemonic {
                     Cwd => (DX, AX),
@@ -490,7 +869,7 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                         IntType::I32 => {
                             (Operand::RegisterPair(EDX, EAX), Operand::Register(EAX), src)
                         }
-                        IntType::I64 => unimplemented!(),
+                        IntType::I64 | IntType::I128 => unimplemented!(),
                     },
                     [dst, src] => {
                         assert_eq!(dst.size(), src.size());
@@ -536,16 +915,15 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 // in the destination operand size.
 
                 // The SF, ZF, AF, and PF flags are undefined.
-                // TODO: do we want to represent ub here? leaving as zero for now
-                builder.store_flag(Flag::Zero, builder.make_false());
-                builder.store_flag(Flag::Sign, builder.make_false());
+                builder.store_undefined_flag(Flag::Zero, Some(builder.make_false()));
+                builder.store_undefined_flag(Flag::Sign, Some(builder.make_false()));
                 builder.store_flag(Flag::Overflow, overflow);
                 builder.store_flag(Flag::Carry, overflow);
 
                 builder.store_operand(dst, res_stored)
             }
             Xor => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let lhs = builder.load_operand(dst);
                 let rhs = builder.load_operand(src);
@@ -561,9 +939,10 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 builder.compute_and_store_sf(res);
                 builder.store_flag(Flag::Carry, builder.make_false());
                 builder.store_flag(Flag::Overflow, builder.make_false());
+                store_zero_idiom_pf(builder, dst, src);
             }
             Not => {
-                operands!([dst], &instr);
+                operands!([dst], instr);
 
                 let val = builder.load_operand(dst);
                 let val = builder.int_not(val);
@@ -571,7 +950,7 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 builder.store_operand(dst, val);
             }
             And | Test => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let lhs = builder.load_operand(dst);
                 let rhs = builder.load_operand(src);
@@ -589,7 +968,7 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 builder.store_flag(Flag::Overflow, builder.make_false());
             }
             Or => {
-                operands!([dst, src], &instr);
+                operands!([dst, src], instr);
 
                 let lhs = builder.load_operand(dst);
                 let rhs = builder.load_operand(src);
@@ -606,7 +985,7 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                 builder.store_flag(Flag::Overflow, builder.make_false());
             }
             Shr | Sar | Shl => {
-                operands!([dst, count], &instr);
+                operands!([dst, count], instr);
 
                 let count = builder.load_operand(count);
                 let count = builder.zext(count, IntType::I32);
@@ -658,7 +1037,8 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                         // OF is defined only for 1-bit shifts, but we'll compute it anyways
                         // maybe we can get better by telling LLVM it's undef?
                         let of = match mnemonic {
-                            Shr => builder.extract_msb(val),
+                            // the original sign bit, taken at the operand width: `val` is zero-extended to 32 bits
+                            Shr => builder.extract_bit(val, res_msb_bit_number),
                             Sar => builder.make_false(),
                             Shl => {
                                 let msb = builder.extract_bit(res, res_msb_bit_number);
@@ -688,123 +1068,961 @@ pub fn codegen_instr<B: Builder>(builder: &mut B, instr: Instruction) -> Control
                     },
                 );
             }
-            Div | Idiv => {
-                operands!([src], &instr);
+            Shld | Shrd => {
+                operands!([dst, src, count], instr);
 
-                let double_size = src.size().double_sized();
+                let count = builder.load_operand(count);
+                let count = builder.zext(count, IntType::I32);
+                let count = builder.int_and(count, builder.make_u32(0x1f));
 
-                let (lo, hi, quo_dst, rem_dst) = match src.size() {
-                    IntType::I8 => todo!(),
-                    IntType::I16 => todo!(),
-                    IntType::I32 => (EAX, EDX, EAX, EDX),
-                    _ => unreachable!(),
-                };
+                let not_zero = builder.icmp(ComparisonType::NotEqual, count, builder.make_u32(0));
 
-                assert_eq!(lo.size(), hi.size());
+                // see `flags::double_shift`
+                builder.ifelse(
+                    not_zero,
+                    |builder| {
+                        let size = dst.size();
+                        let val = builder.load_operand(dst);
+                        let val = builder.zext(val, IntType::I64);
+                        let src = builder.load_operand(src);
+                        let src = builder.zext(src, IntType::I64);
+                        let half = builder.make_u64(32);
+
+                        // the bits shifted through, 64 of them hold either operand size. A 16-bit count above 16
+                        // is undefined, Intel shifts `dst:src:dst` then
+                        let ring = match (size, mnemonic) {
+                            (IntType::I16, _) => {
+                                let high = builder.shl(val, half);
+                                let middle = builder.shl(src, builder.make_u64(16));
+                                let high = builder.int_or(high, middle);
+                                builder.int_or(high, val)
+                            }
+                            (_, Shld) => {
+                                let high = builder.shl(val, half);
+                                builder.int_or(high, src)
+                            }
+                            _ => {
+                                let high = builder.shl(src, half);
+                                builder.int_or(high, val)
+                            }
+                        };
 
-                let dividend = builder.load_operand(Operand::RegisterPair(hi, lo));
+                        let count = builder.zext(count, IntType::I64);
+                        let count_sub_1 = builder.sub(count, builder.make_u64(1));
+                        // the value one step before the end gives the last bit shifted out
+                        let (res, before) = match mnemonic {
+                            Shld => {
+                                let res = builder.shl(ring, count);
+                                let before = builder.shl(ring, count_sub_1);
+                                (builder.lshr(res, half), builder.lshr(before, half))
+                            }
+                            Shrd => (builder.lshr(ring, count), builder.lshr(ring, count_sub_1)),
+                            _ => unreachable!(),
+                        };
+                        let res = builder.trunc(res, size);
+                        let before = builder.trunc(before, size);
+
+                        let msb_bit_number =
+                            builder.make_int_value(size, size.bit_width() as u64 - 1, false);
+                        let cf = match mnemonic {
+                            Shld => builder.extract_bit(before, msb_bit_number),
+                            _ => {
+                                builder.extract_bit(before, builder.make_int_value(size, 0, false))
+                            }
+                        };
+                        // OF is defined only for 1-bit shifts (whether the sign has changed), the others get
+                        // whether the last step changed it
+                        let changed = builder.int_xor(before, res);
+                        let of = builder.extract_bit(changed, msb_bit_number);
 
-                let divisor = builder.load_operand(src);
-                let divisor = if mnemonic == Div {
-                    builder.zext(divisor, double_size)
-                } else {
-                    builder.sext(divisor, double_size)
+                        builder.store_operand(dst, res);
+                        builder.compute_and_store_zf(res);
+                        builder.compute_and_store_sf(res);
+                        builder.store_flag(Flag::Carry, cf);
+                        builder.store_flag(Flag::Overflow, of);
+                    },
+                    |_| {},
+                );
+            }
+            Bt | Bts | Btr | Btc => {
+                operands!([dst, offset], instr);
+
+                let size = dst.size();
+                let width = size.bit_width() as u64;
+                let bit_offset = builder.load_operand(offset);
+                let bit_offset = builder.zext(bit_offset, size);
+                let bit =
+                    builder.int_and(bit_offset, builder.make_int_value(size, width - 1, false));
+
+                // with a register offset the memory operand is the start of a bit string: the offset is signed and
+                // picks an operand-sized unit anywhere around it. Only the operand itself is checked against the
+                // segment
+                let unit = match (dst, offset) {
+                    (Operand::Memory(op), Operand::Register(_)) => {
+                        let access = match mnemonic {
+                            Bt => Protection::READ,
+                            _ => Protection::READ_WRITE,
+                        };
+                        let address = builder.compute_linear_address(op, access);
+                        let units = builder.ashr(
+                            bit_offset,
+                            builder.make_int_value(size, width.trailing_zeros() as u64, false),
+                        );
+                        let units = builder.sext(units, IntType::I32);
+                        let displacement =
+                            builder.mul(units, builder.make_u32(size.byte_width() as u32));
+                        Some(builder.add(address, displacement))
+                    }
+                    _ => None,
                 };
 
-                let quotient = if mnemonic == Div {
-                    builder.udiv(dividend, divisor)
-                } else {
-                    builder.sdiv(dividend, divisor)
+                let val = match unit {
+                    Some(address) => builder.load_memory(size, address),
+                    None => builder.load_operand(dst),
+                };
+                let cf = builder.extract_bit(val, bit);
+                let mask = builder.shl(builder.make_int_value(size, 1, false), bit);
+                let res = match mnemonic {
+                    Bt => None,
+                    Bts => Some(builder.int_or(val, mask)),
+                    Btr => {
+                        let mask = builder.int_not(mask);
+                        Some(builder.int_and(val, mask))
+                    }
+                    Btc => Some(builder.int_xor(val, mask)),
+                    _ => unreachable!(),
                 };
 
-                // TODO: test overflow and trap if out of bounds
-
-                // calculate the remainder
-                let whole = builder.mul(quotient, divisor);
-                let remainder = builder.sub(dividend, whole);
-
-                let quotient = builder.trunc(quotient, src.size());
-                let remainder = builder.trunc(remainder, src.size());
-
-                builder.store_register(quo_dst, quotient);
-                builder.store_register(rem_dst, remainder);
-
-                // all flags are undefined
+                match (res, unit) {
+                    (Some(res), Some(address)) => builder.store_memory(address, res),
+                    (Some(res), None) => builder.store_operand(dst, res),
+                    (None, _) => {}
+                }
+                // ZF is left alone, OF, SF, AF & PF are undefined
+                builder.store_flag(Flag::Carry, cf);
+                for flag in [Flag::Overflow, Flag::Sign] {
+                    builder.store_undefined_flag(flag, None);
+                }
             }
-            Push => {
-                operands!([src], &instr);
+            Bsf | Bsr => {
+                operands!([dst, src], instr);
 
                 let val = builder.load_operand(src);
+                let size = val.size();
+                let is_zero = builder.icmp(
+                    ComparisonType::Equal,
+                    val,
+                    builder.make_int_value(size, 0, false),
+                );
+                let not_zero = builder.bool_not(is_zero);
 
-                builder.push(val);
-            }
-            Pop => {
-                operands!([dst], &instr);
-
-                let val = builder.pop(dst.size());
+                // the manual leaves the destination undefined for zero, but the hardware keeps it & the code out
+                // there relies on that
+                builder.ifelse(
+                    not_zero,
+                    |builder| {
+                        let index = match mnemonic {
+                            Bsf => builder.cttz(val),
+                            Bsr => {
+                                let leading = builder.ctlz(val);
+                                let msb_bit_number = builder.make_int_value(
+                                    size,
+                                    size.bit_width() as u64 - 1,
+                                    false,
+                                );
+                                builder.sub(msb_bit_number, leading)
+                            }
+                            _ => unreachable!(),
+                        };
+                        builder.store_operand(dst, index);
+                    },
+                    |_| {},
+                );
 
-                builder.store_operand(dst, val);
+                builder.store_flag(Flag::Zero, is_zero);
+                // CF, OF, SF, AF & PF are undefined
+                for flag in [Flag::Carry, Flag::Overflow, Flag::Sign] {
+                    builder.store_undefined_flag(flag, None);
+                }
             }
-            Leave => {
-                operands!([], &instr);
-
-                let old_ebp = builder.load_register(EBP);
-                builder.store_register(ESP, old_ebp);
+            Bswap => {
+                operands!([dst], instr);
 
-                let new_ebp = builder.pop(IntType::I32);
-
-                builder.store_register(EBP, new_ebp);
+                let val = builder.load_operand(dst);
+                let swapped = match val.size() {
+                    IntType::I32 => builder.bswap(val),
+                    // undefined for a 16-bit register, the CPUs out there zero it
+                    size => builder.make_int_value(size, 0, false),
+                };
+                builder.store_operand(dst, swapped);
             }
-            Ret => {
-                // TODO: control flow, no-op for now
-                // Pop the return address (TODO: where to store it? we don't have EIP yet)
+            Xchg => {
+                operands!([dst, src], instr);
+
+                match (dst, src) {
+                    // locked with or without the prefix
+                    (Operand::Memory(op), Operand::Register(reg))
+                    | (Operand::Register(reg), Operand::Memory(op)) => {
+                        let address = builder.compute_linear_address(op, Protection::READ_WRITE);
+                        let value = builder.load_register(reg);
+                        let old = builder.exchange_memory(address, value);
+                        builder.store_register(reg, old);
+                    }
+                    // both are loaded before the stores, so `xchg eax, eax` changes nothing
+                    _ => {
+                        let a = builder.load_operand(dst);
+                        let b = builder.load_operand(src);
+                        builder.store_operand(dst, b);
+                        builder.store_operand(src, a);
+                    }
+                }
+            }
+            Rol | Ror => {
+                operands!([dst, count], instr);
 
-                let _raddr = builder.pop(IntType::I32);
+                let count = builder.load_operand(count);
+                let count = builder.zext(count, IntType::I32);
+                let count = builder.int_and(count, builder.make_u32(0x1f));
 
-                return ControlFlow::Return;
-            }
-            Jmp => {
-                operands!([target], &instr);
+                let not_zero = builder.icmp(ComparisonType::NotEqual, count, builder.make_u32(0));
 
-                return match target {
-                    Operand::Immediate8(_) | Operand::Immediate16(_) | Operand::Immediate64(_) => {
-                        panic!("Jump to unsupported immediate size")
-                    }
-                    Operand::Immediate32(target) => ControlFlow::DirectJump(target),
+                // see `flags::rotate`
+                builder.ifelse(
+                    not_zero,
+                    |builder| {
+                        // the rotates take the count modulo the width. For 8 & 16 bits that can make it zero,
+                        // which still sets the flags
+                        let val = builder.load_operand(dst);
+                        let count = builder.trunc(count, dst.size());
+                        let res = match mnemonic {
+                            Rol => builder.rotl(val, count),
+                            Ror => builder.rotr(val, count),
+                            _ => unreachable!(),
+                        };
+
+                        let msb_bit_number = builder.make_int_value(
+                            dst.size(),
+                            dst.size().bit_width() as u64 - 1,
+                            false,
+                        );
+                        let msb = builder.extract_bit(res, msb_bit_number);
+                        // OF is defined only for 1-bit rotates, the others get the same thing
+                        let (cf, of) = match mnemonic {
+                            Rol => {
+                                let lsb = builder
+                                    .extract_bit(res, builder.make_int_value(dst.size(), 0, false));
+                                (lsb, builder.bool_xor(msb, lsb))
+                            }
+                            Ror => {
+                                let next = builder.make_int_value(
+                                    dst.size(),
+                                    dst.size().bit_width() as u64 - 2,
+                                    false,
+                                );
+                                let next = builder.extract_bit(res, next);
+                                (msb, builder.bool_xor(msb, next))
+                            }
+                            _ => unreachable!(),
+                        };
+
+                        builder.store_operand(dst, res);
+                        builder.store_flag(Flag::Carry, cf);
+                        builder.store_flag(Flag::Overflow, of);
+                    },
+                    |_| {},
+                );
+            }
+            Rcl | Rcr => {
+                operands!([dst, count], instr);
+
+                let count = builder.load_operand(count);
+                let count = builder.zext(count, IntType::I32);
+                let count = builder.int_and(count, builder.make_u32(0x1f));
+
+                let not_zero = builder.icmp(ComparisonType::NotEqual, count, builder.make_u32(0));
+
+                // see `flags::rotate_through_carry`
+                builder.ifelse(
+                    not_zero,
+                    |builder| {
+                        // the operand with CF on top of it is a ring of width + 1 bits, rotated as a whole.
+                        // The count is taken modulo the ring size: 9 & 17 are below 32, so rcl al, 9 is a no-op
+                        let width = dst.size().bit_width() as u64;
+                        let ring_bits = builder.make_u64(width + 1);
+                        let count = builder.zext(count, IntType::I64);
+                        let turns = builder.udiv(count, ring_bits);
+                        let turns = builder.mul(turns, ring_bits);
+                        let count = builder.sub(count, turns);
+                        let back = builder.sub(ring_bits, count);
+
+                        let val = builder.load_operand(dst);
+                        let val = builder.zext(val, IntType::I64);
+                        let cf = builder.load_flag(Flag::Carry);
+                        let cf = builder.bool_to_int(cf, IntType::I64);
+                        let cf = builder.shl(cf, builder.make_u64(width));
+                        let ring = builder.int_or(val, cf);
+
+                        // shifting by the whole ring (zero turns) gives a zero, which is what we need
+                        let (there, around) = match mnemonic {
+                            Rcl => (builder.shl(ring, count), builder.lshr(ring, back)),
+                            Rcr => (builder.lshr(ring, count), builder.shl(ring, back)),
+                            _ => unreachable!(),
+                        };
+                        let rotated = builder.int_or(there, around);
+
+                        let cf = builder.extract_bit(rotated, builder.make_u64(width));
+                        // OF is defined only for 1-bit rotates (whether the sign has changed),
+                        // the others get the same thing
+                        let changed = builder.int_xor(val, rotated);
+                        let of = builder.extract_bit(changed, builder.make_u64(width - 1));
+
+                        let res = builder.trunc(rotated, dst.size());
+                        builder.store_operand(dst, res);
+                        builder.store_flag(Flag::Carry, cf);
+                        builder.store_flag(Flag::Overflow, of);
+                    },
+                    |_| {},
+                );
+            }
+            Mul => {
+                operands!([src], instr);
+
+                let (dst, lhs) = match src.size() {
+                    IntType::I8 => (Operand::Register(AX), AL),
+                    IntType::I16 => (Operand::RegisterPair(DX, AX), AX),
+                    IntType::I32 => (Operand::RegisterPair(EDX, EAX), EAX),
+                    IntType::I64 | IntType::I128 => unimplemented!(),
+                };
+
+                let double_size = src.size().double_sized();
+
+                let lhs = builder.load_register(lhs);
+                let lhs = builder.zext(lhs, double_size);
+                let rhs = builder.load_operand(src);
+                let rhs = builder.zext(rhs, double_size);
+
+                let res = builder.mul(lhs, rhs);
+
+                // CF & OF are set when the upper half of the result is not zero
+                let hi = builder.lshr(
+                    res,
+                    builder.make_int_value(double_size, src.size().bit_width() as u64, false),
+                );
+                let overflow = builder.icmp(
+                    ComparisonType::NotEqual,
+                    hi,
+                    builder.make_int_value(double_size, 0, false),
+                );
+
+                // The SF, ZF, AF, and PF flags are undefined.
+                builder.store_undefined_flag(Flag::Zero, Some(builder.make_false()));
+                builder.store_undefined_flag(Flag::Sign, Some(builder.make_false()));
+                builder.store_flag(Flag::Overflow, overflow);
+                builder.store_flag(Flag::Carry, overflow);
+
+                builder.store_operand(dst, res)
+            }
+            Div | Idiv => {
+                operands!([src], instr);
+
+                // the byte form divides AX, putting the quotient into AL & the remainder into AH
+                let (dividend, quo_dst, rem_dst) = match src.size() {
+                    IntType::I8 => (builder.load_register(AX), AL, AH),
+                    IntType::I16 => (builder.load_operand(Operand::RegisterPair(DX, AX)), AX, DX),
+                    IntType::I32 => (builder.read_edx_eax(), EAX, EDX),
+                    _ => unreachable!(),
+                };
+
+                // The narrow forms divide at twice the width of the dividend, where the quotient can't overflow
+                // (no INT_MIN / -1). The 32-bit one divides EDX:EAX at 64 bits, as wide as there is.
+                // Either way, the quotient is checked to fit the destination afterwards
+                let narrow = src.size() != IntType::I32;
+                let (dividend, division_size) = if narrow {
+                    let wide = dividend.size().double_sized();
+                    let dividend = if mnemonic == Div {
+                        builder.zext(dividend, wide)
+                    } else {
+                        builder.sext(dividend, wide)
+                    };
+                    (dividend, wide)
+                } else {
+                    (dividend, dividend.size())
+                };
+
+                let divisor = builder.load_operand(src);
+                let divisor = if mnemonic == Div {
+                    builder.zext(divisor, division_size)
+                } else {
+                    builder.sext(divisor, division_size)
+                };
+
+                // both before the division: they would make it undefined
+                let zero = builder.make_int_value(division_size, 0, false);
+                let by_zero = builder.icmp(ComparisonType::Equal, divisor, zero);
+                builder.ifelse(
+                    by_zero,
+                    |builder| builder.raise_fault(GuestFault::DivideError, builder.make_u32(0)),
+                    |_| {},
+                );
+                if mnemonic == Idiv && !narrow {
+                    let min = builder.make_int_value(division_size, 1 << 63, false);
+                    let minus_one = builder.make_int_value(division_size, u64::MAX, false);
+                    let is_min = builder.icmp(ComparisonType::Equal, dividend, min);
+                    let is_minus_one = builder.icmp(ComparisonType::Equal, divisor, minus_one);
+                    let overflow = builder.bool_and(is_min, is_minus_one);
+                    builder.ifelse(
+                        overflow,
+                        |builder| builder.raise_fault(GuestFault::DivideError, builder.make_u32(0)),
+                        |_| {},
+                    );
+                }
+
+                let quotient = if mnemonic == Div {
+                    builder.udiv(dividend, divisor)
+                } else {
+                    builder.sdiv(dividend, divisor)
+                };
+
+                let truncated = builder.trunc(quotient, src.size());
+                let extended = if mnemonic == Div {
+                    builder.zext(truncated, division_size)
+                } else {
+                    builder.sext(truncated, division_size)
+                };
+                let overflow = builder.icmp(ComparisonType::NotEqual, quotient, extended);
+                builder.ifelse(
+                    overflow,
+                    |builder| builder.raise_fault(GuestFault::DivideError, builder.make_u32(0)),
+                    |_| {},
+                );
+
+                // calculate the remainder
+                let whole = builder.mul(quotient, divisor);
+                let remainder = builder.sub(dividend, whole);
+
+                let quotient = builder.trunc(quotient, src.size());
+                let remainder = builder.trunc(remainder, src.size());
+
+                builder.store_register(quo_dst, quotient);
+                builder.store_register(rem_dst, remainder);
+
+                // all flags are undefined
+                for flag in [Flag::Carry, Flag::Overflow, Flag::Sign, Flag::Zero] {
+                    builder.store_undefined_flag(flag, None);
+                }
+            }
+            Push => {
+                operands!([src], instr);
+
+                // before ESP moves: `push esp` pushes the old value, `push [esp]` duplicates the top of the stack
+                let val = builder.load_operand(src);
+
+                builder.push(val);
+            }
+            Pop => {
+                operands!([dst], instr);
+
+                let val = builder.pop(dst.size());
+
+                // after ESP moves: `pop [esp + 4]` addresses with the incremented ESP, `pop esp` keeps the value
+                builder.store_operand(dst, val);
+            }
+            Pushfd => {
+                operands!([], instr);
+
+                let eflags = pack_eflags(builder);
+                builder.push(eflags);
+            }
+            Pushf => {
+                operands!([], instr);
+
+                let eflags = pack_eflags(builder);
+                let flags = builder.trunc(eflags, IntType::I16);
+                builder.push(flags);
+            }
+            Popfd => {
+                operands!([], instr);
+
+                let eflags = builder.pop(IntType::I32);
+                unpack_eflags(builder, eflags);
+            }
+            Popf => {
+                operands!([], instr);
+
+                let flags = builder.pop(IntType::I16);
+                unpack_flags16(builder, flags);
+            }
+            Pushad | Pusha | Popad | Popa => {
+                operands!([], instr);
+
+                let registers = match mnemonic {
+                    Pushad | Popad => [EAX, ECX, EDX, EBX, ESP, EBP, ESI, EDI],
+                    _ => [AX, CX, DX, BX, SP, BP, SI, DI],
+                };
+                if let Pushad | Pusha = mnemonic {
+                    // ESP goes in as it was before the first push
+                    let values = registers.map(|register| builder.load_register(register));
+                    for value in values {
+                        builder.push(value);
+                    }
+                } else {
+                    for register in registers.into_iter().rev() {
+                        let value = builder.pop(register.size());
+                        // the saved ESP is skipped, ESP is where the pops leave it
+                        if !matches!(register, ESP | SP) {
+                            builder.store_register(register, value);
+                        }
+                    }
+                }
+            }
+            Leave => {
+                operands!([], instr);
+
+                let (bp, sp) = match builder.code_mode().stack_word() {
+                    IntType::I16 => (BP, SP),
+                    _ => (EBP, ESP),
+                };
+                let old_ebp = builder.load_register(bp);
+                builder.store_register(sp, old_ebp);
+
+                let new_ebp = builder.pop(bp.size());
+
+                builder.store_register(bp, new_ebp);
+            }
+            Ret => {
+                // TODO: control flow, no-op for now
+                // Pop the return address (TODO: where to store it? we don't have EIP yet)
+
+                let _raddr = builder.pop(builder.code_mode().stack_word());
+
+                return ControlFlow::Return;
+            }
+            Jmp => {
+                operands!([target], instr);
+
+                return match target {
+                    Operand::Immediate8(_) | Operand::Immediate16(_) | Operand::Immediate64(_) => {
+                        panic!("Jump to unsupported immediate size")
+                    }
+                    Operand::Immediate32(target) => ControlFlow::DirectJump(target),
+                    Operand::FarBranch(selector, offset) => {
+                        match far_transfer_target(builder, selector, offset) {
+                            Some(target) => ControlFlow::DirectJump(target),
+                            None => ControlFlow::Return,
+                        }
+                    }
                     target => {
                         let target = builder.load_operand(target);
                         ControlFlow::IndirectJump(target)
                     }
                 };
             }
+            JmpTable => {
+                let (table, targets) = match instr.operands.split_first() {
+                    Some((Operand::Memory(table), targets)) => (*table, targets),
+                    _ => panic!("Malformed jump table: {}", instr),
+                };
+                let index = builder.load_register(table.index.unwrap());
+                // the index is past the end of the table only if the bounds check doesn't match the table,
+                // then the jmp just does what it did before
+                let fallback = builder.load_operand(Operand::Memory(table));
+                let targets = targets.iter().map(|target| target.as_imm32()).collect();
+                return ControlFlow::Switch(index, targets, fallback);
+            }
             Call => {
-                operands!([target], &instr);
+                operands!([target], instr);
 
-                let ret = instr.next_ip32();
-                builder.push(builder.make_u32(ret));
-
-                match target {
+                let target = match target {
                     Operand::Immediate8(_) | Operand::Immediate16(_) | Operand::Immediate64(_) => {
                         panic!("Call to unsupported immediate size")
                     }
-                    Operand::Immediate32(target) => {
-                        builder.direct_call(target, instr.next_ip32());
+                    Operand::Immediate32(target) => target,
+                    // there is no CS to push, so this is a near call to wherever the descriptor points
+                    Operand::FarBranch(selector, offset) => {
+                        match far_transfer_target(builder, selector, offset) {
+                            Some(target) => target,
+                            None => return ControlFlow::Return,
+                        }
                     }
-                    _ => todo!(),
+                    target => {
+                        // read the target before pushing: it might be stored right below ESP
+                        let target = builder.load_operand(target);
+
+                        let ret = builder.instruction_end();
+                        push_return_address(builder, ret);
+
+                        builder.indirect_call(target, ret);
+                        return ControlFlow::NextInstruction;
+                    }
+                };
+
+                let ret = builder.instruction_end();
+                push_return_address(builder, ret);
+
+                // get-EIP: the "callee" is the rest of this block, there's nothing to return to
+                if instr.calls_next() {
+                    return ControlFlow::NextInstruction;
                 }
+                builder.direct_call(target, ret);
+            }
+            ZeroReg => {
+                operands!([dst], instr);
+
+                builder.store_operand(dst, builder.make_int_value(dst.size(), 0, false));
+
+                // same flags as the xor would set
+                builder.store_flag(Flag::Zero, builder.make_true());
+                builder.store_flag(Flag::Parity, builder.make_true());
+                builder.store_flag(Flag::Sign, builder.make_false());
+                builder.store_flag(Flag::Carry, builder.make_false());
+                builder.store_flag(Flag::Overflow, builder.make_false());
+            }
+            Prologue => {
+                operands!([], instr);
+
+                let ebp = builder.load_register(EBP);
+                builder.push(ebp);
+
+                let esp = builder.load_register(ESP);
+                builder.store_register(EBP, esp);
+            }
+            AddNoFlags => {
+                operands!([dst, src], instr);
+
+                let lhs = builder.load_operand(dst);
+                let rhs = builder.load_operand(src);
+                let res = builder.add(lhs, rhs);
+
+                builder.store_operand(dst, res);
             }
             Stc => builder.store_flag(Carry, builder.make_true()),
             Clc => builder.store_flag(Carry, builder.make_false()),
+            Salc => {
+                operands!([], instr);
+
+                let cf = builder.load_flag(Carry);
+                let val = builder.select(cf, builder.make_u8(0xff), builder.make_u8(0));
+                builder.store_register(AL, val);
+            }
+            Aam | Aad => {
+                operands!([base], instr);
+
+                let base = builder.load_operand(base);
+                let al = builder.load_register(AL);
+                let (ah, al) = if mnemonic == Aam {
+                    let zero = builder.make_u8(0);
+                    let by_zero = builder.icmp(ComparisonType::Equal, base, zero);
+                    builder.ifelse(
+                        by_zero,
+                        |builder| builder.raise_fault(GuestFault::DivideError, builder.make_u32(0)),
+                        |_| {},
+                    );
+
+                    let quotient = builder.udiv(al, base);
+                    let whole = builder.mul(quotient, base);
+                    (quotient, builder.sub(al, whole))
+                } else {
+                    let ah = builder.load_register(AH);
+                    let high = builder.mul(ah, base);
+                    (builder.make_u8(0), builder.add(al, high))
+                };
+                builder.store_register(AH, ah);
+                builder.store_register(AL, al);
+
+                // ZF & SF from AL (PF isn't kept). CF & OF are undefined, left clear as after a logic instruction
+                builder.compute_and_store_zf(al);
+                builder.compute_and_store_sf(al);
+                for flag in [Flag::Carry, Flag::Overflow] {
+                    builder.store_undefined_flag(flag, Some(builder.make_false()));
+                }
+            }
+            Bound => {
+                operands!([index, bounds], instr);
+
+                let index = builder.load_operand(index);
+                let size = index.size();
+
+                // the lower bound is at the lower address, the upper one follows it
+                let bounds = builder.load_operand(bounds);
+                let lower = builder.trunc(bounds, size);
+                let upper = builder.lshr(
+                    bounds,
+                    builder.make_int_value(bounds.size(), size.bit_width() as u64, false),
+                );
+                let upper = builder.trunc(upper, size);
+
+                let below = builder.icmp(ComparisonType::SignedLess, index, lower);
+                let above = builder.icmp(ComparisonType::SignedGreater, index, upper);
+                let out_of_range = builder.bool_or(below, above);
+                builder.ifelse(
+                    out_of_range,
+                    |builder| builder.raise_fault(GuestFault::BoundRange, builder.make_u32(0)),
+                    |_| {},
+                );
+            }
+            MovCr | Lmsw => {
+                let profile = builder.system_registers().clone();
+                let cr0 = profile.cr0;
+
+                if profile.user_mode {
+                    builder.raise_fault(GuestFault::GeneralProtection, builder.make_u32(0));
+                    return ControlFlow::Return;
+                }
+
+                // (register, the new value) for a write
+                let write = match (mnemonic, instr.get_operands().as_slice()) {
+                    (MovCr, &[dst, Operand::ControlRegister(cr)]) => {
+                        let value = match profile.read(cr) {
+                            Some(value) => value,
+                            None => {
+                                builder.raise_fault(
+                                    GuestFault::InvalidOpcode,
+                                    builder.make_u32(instr.ip),
+                                );
+                                return ControlFlow::Return;
+                            }
+                        };
+                        builder.store_operand(dst, builder.make_u32(value));
+                        None
+                    }
+                    (MovCr, &[Operand::ControlRegister(cr), src]) => {
+                        if profile.read(cr).is_none() {
+                            builder
+                                .raise_fault(GuestFault::InvalidOpcode, builder.make_u32(instr.ip));
+                            return ControlFlow::Return;
+                        }
+                        Some((cr, builder.load_operand(src)))
+                    }
+                    (Lmsw, &[src]) => {
+                        let msw = builder.load_operand(src);
+                        let msw = builder.zext(msw, IntType::I32);
+                        let msw = builder.int_and(msw, builder.make_u32(MSW_WRITABLE));
+                        let kept = SystemRegisterProfile::cr0_after_lmsw(cr0, 0);
+                        Some((0, builder.int_or(msw, builder.make_u32(kept))))
+                    }
+                    _ => unreachable!(),
+                };
+
+                if let Some((cr, value)) = write {
+                    match profile.writes {
+                        ControlRegisterWrites::Ignore => {}
+                        ControlRegisterWrites::Record => builder.control_register_write(cr, value),
+                        ControlRegisterWrites::Fault => {
+                            builder.raise_fault(GuestFault::GeneralProtection, builder.make_u32(0));
+                            return ControlFlow::Return;
+                        }
+                    }
+                }
+
+                if mnemonic == MovCr {
+                    // The OF, SF, ZF, AF, PF, and CF flags are undefined.
+                    for flag in [Flag::Carry, Flag::Overflow, Flag::Sign, Flag::Zero] {
+                        builder.store_undefined_flag(flag, None);
+                    }
+                }
+            }
+            Smsw => {
+                operands!([dst], instr);
+
+                // not privileged: user mode code can read the MSW (a 32-bit register gets the whole CR0)
+                let profile = builder.system_registers();
+                let value = match dst.size() {
+                    IntType::I16 => profile.msw() as u64,
+                    _ => profile.cr0 as u64,
+                };
+                builder.store_operand(dst, builder.make_int_value(dst.size(), value, false));
+            }
+            Rdmsr | Wrmsr => {
+                operands!([], instr);
+                let profile = builder.system_registers().clone();
+
+                if profile.user_mode {
+                    builder.raise_fault(GuestFault::GeneralProtection, builder.make_u32(0));
+                    return ControlFlow::Return;
+                }
+
+                let index = builder.load_register(ECX);
+
+                if mnemonic == Rdmsr {
+                    // a chain of selects over the known ones, the index is only known at run time
+                    let mut value = builder.make_int_value(IntType::I64, 0, false);
+                    let mut known = builder.make_false();
+                    for (&msr, &msr_value) in &profile.msrs {
+                        let is_this =
+                            builder.icmp(ComparisonType::Equal, index, builder.make_u32(msr));
+                        value = builder.select(
+                            is_this,
+                            builder.make_int_value(IntType::I64, msr_value, false),
+                            value,
+                        );
+                        known = builder.bool_or(known, is_this);
+                    }
+
+                    if profile.unknown_msrs == UnknownMsrReads::Fault {
+                        let unknown = builder.bool_not(known);
+                        builder.ifelse(
+                            unknown,
+                            |builder| {
+                                builder
+                                    .raise_fault(GuestFault::GeneralProtection, builder.make_u32(0))
+                            },
+                            |_| {},
+                        );
+                    }
+
+                    builder.write_edx_eax(value);
+                } else {
+                    match profile.writes {
+                        ControlRegisterWrites::Ignore => {}
+                        ControlRegisterWrites::Record => {
+                            let value = builder.read_edx_eax();
+                            builder.msr_write(index, value);
+                        }
+                        ControlRegisterWrites::Fault => {
+                            builder.raise_fault(GuestFault::GeneralProtection, builder.make_u32(0));
+                            return ControlFlow::Return;
+                        }
+                    }
+                }
+            }
+            Rdpmc => {
+                operands!([], instr);
+                let profile = builder.system_registers();
+
+                if profile.user_mode && profile.cr4 & CR4_PCE == 0 {
+                    builder.raise_fault(GuestFault::GeneralProtection, builder.make_u32(0));
+                    return ControlFlow::Return;
+                }
+
+                let value = profile.performance_counter;
+                builder.write_edx_eax(builder.make_int_value(IntType::I64, value, false));
+            }
+            Clflush => {
+                operands!([line], instr);
+
+                let line = match line {
+                    Operand::Memory(m) => m,
+                    _ => panic!("Expected clflush to have a memory operand"),
+                };
+                let address = builder.compute_linear_address(line, Protection::READ);
+                builder.probe_memory(address);
+                builder.cache_flush(address);
+            }
+            Invd | Wbinvd | Invlpg => {
+                // invlpg doesn't access the memory, so its operand doesn't matter
+                if builder.system_registers().user_mode {
+                    builder.raise_fault(GuestFault::GeneralProtection, builder.make_u32(0));
+                    return ControlFlow::Return;
+                }
+            }
+            Invalid => {
+                operands!([], instr);
+
+                builder.raise_fault(GuestFault::InvalidOpcode, builder.make_u32(instr.ip));
+                return ControlFlow::Return;
+            }
+            FetchFault => {
+                operands!([end], instr);
+
+                let end = match end {
+                    Operand::Immediate32(end) => end,
+                    _ => panic!("Expected the end of the code to be imm32"),
+                };
+
+                builder.raise_page_fault(builder.make_u32(end), PF_INSTRUCTION_FETCH);
+                return ControlFlow::Return;
+            }
+            Unimplemented => {
+                let bytes: Vec<u8> = instr
+                    .operands
+                    .iter()
+                    .map(|op| match *op {
+                        Operand::Immediate8(b) => b,
+                        op => panic!("Expected the instruction bytes to be imm8, got {:?}", op),
+                    })
+                    .collect();
+
+                let fault = GuestFault::Unimplemented {
+                    eip: instr.ip,
+                    bytes: InstructionBytes::new(&bytes),
+                };
+                builder.raise_fault(fault, builder.make_u32(instr.ip));
+                return ControlFlow::Return;
+            }
             Int => {
-                // TODO: maybe try to handle int 3 and other stuff differently?
-                // Also wanna have runtime info on WTF has happened
-                builder.trap();
+                operands!([vector], instr);
+
+                let vector = match vector {
+                    Operand::Immediate8(vector) => vector,
+                    _ => panic!("Expected int vector to be imm8"),
+                };
+
+                return software_interrupt(builder, vector, instr.next_ip());
+            }
+            Int3 => {
+                operands!([], instr);
+
+                return software_interrupt(builder, 3, instr.next_ip());
+            }
+            Iretd => {
+                operands!([], instr);
+
+                let eip = builder.pop(IntType::I32);
+                // no segments, nothing to do with CS
+                let _cs = builder.pop(IntType::I32);
+                let eflags = builder.pop(IntType::I32);
+                unpack_eflags(builder, eflags);
+
+                return ControlFlow::IndirectJump(eip);
+            }
+            Iret => {
+                operands!([], instr);
+
+                let ip = builder.pop(IntType::I16);
+                let _cs = builder.pop(IntType::I16);
+                let flags = builder.pop(IntType::I16);
+                unpack_flags16(builder, flags);
+
+                return ControlFlow::IndirectJump(builder.zext(ip, IntType::I32));
+            }
+            Sysenter => {
+                operands!([], instr);
+
+                builder.fast_syscall(instr.next_ip());
+            }
+            Sysexit => {
+                operands!([], instr);
+
+                // back to the user code: EIP = EDX, ESP = ECX
+                let eip = builder.load_register(Register::EDX);
+                let esp = builder.load_register(Register::ECX);
+                builder.store_register(Register::ESP, esp);
+                return ControlFlow::IndirectJump(eip);
+            }
+            In => {
+                operands!([dst, port], instr);
+
+                // port is either DX or imm8 (which is zero-extended)
+                let port = builder.load_operand(port);
+                let port = builder.zext(port, IntType::I16);
+
+                let val = builder.port_in(port, dst.size());
+                builder.store_operand(dst, val);
+            }
+            Out => {
+                operands!([port, src], instr);
+
+                let port = builder.load_operand(port);
+                let port = builder.zext(port, IntType::I16);
+
+                let val = builder.load_operand(src);
+                builder.port_out(port, val);
             }
 
             // TODO: uncomment when unit tests for different direction of string operations will be in place
             //Std => builder.store_flag(Direction, builder.make_true()),
             //Cld => builder.store_flag(Direction, builder.make_false()),
-            m => panic!("Unknown instruction mnemonic: {:?}", m),
+            m => panic!("Unsupported instruction mnemonic: {:?}", m),
         };
 
         ControlFlow::NextInstruction
@@ -877,6 +2095,7 @@ mod tests {
         }
     }
 
+    #[cfg(feature = "llvm")]
     mod llvm {
         use crate::llvm;
         use crate::memory_image::MemoryImage;
@@ -926,7 +2145,12 @@ mod tests {
         fn test_recomp(x86_code: Vec<u8>, expected_aarch64_code: Vec<u8>) {
             debug!(
                 "CODE:\n{}",
-                crate::disasm::disassemble(x86_code.as_slice(), 0x1000)
+                crate::disasm::listing(
+                    &MemoryImage::from_code_region(0x1000, &x86_code),
+                    0x1000,
+                    x86_code.len() as u32,
+                    &crate::disasm::SymbolTable::new()
+                )
             );
 
             let result = recompile(x86_code.as_slice());
@@ -949,6 +2173,24 @@ mod tests {
             assert_eq!(result, expected);
         }
 
+        /// The IR of the block at 0x1000, translated from `code` with `options`
+        fn function_ir(code: &MemoryImage, options: &crate::config::TranslationOptions) -> String {
+            let context = &Context::create();
+            let types = &llvm::backend::Types::new(context);
+            let rt_funs = &llvm::backend::RuntimeHelpers::dummy(types);
+            let module =
+                llvm::recompile_with_options(context, types, rt_funs, options, code, &[0x1000]);
+            module.verify().unwrap();
+
+            let ir = module
+                .get_function("sub_00001000")
+                .unwrap()
+                .print_to_string()
+                .to_string();
+            trace!("llvm ir:\n{}", ir);
+            ir
+        }
+
         #[test]
         fn simple_llvm() {
             // we get this
@@ -1264,5 +2506,560 @@ mod tests {
 
             test_recomp(code, expected_result);
         }
+
+        #[test]
+        fn nontemporal_llvm() {
+            let code = assemble_x86!(
+                ; movnti [0x2000], eax
+                ; movntps [0x2010], xmm1
+                ; ret
+            );
+
+            let context = &Context::create();
+            let types = &llvm::backend::Types::new(context);
+            let rt_funs = &llvm::backend::RuntimeHelpers::dummy(types);
+            let code = MemoryImage::from_code_region(0x1000, &code);
+
+            let module = llvm::recompile(context, types, rt_funs, &code, &[0x1000]);
+            module.verify().unwrap();
+
+            let ir = module.print_to_string().to_string();
+            trace!("llvm ir:\n{}", ir);
+
+            assert!(ir.contains("!nontemporal"));
+        }
+
+        #[test]
+        fn fused_compare_llvm() {
+            use crate::config::TranslationOptions;
+
+            // both successors overwrite the flags, so the `cmp` doesn't need to store any
+            let code = assemble_x86!(
+                ; cmp eax, ebx
+                ; jb ->below
+                ; xor eax, eax
+                ; ret
+                ; ->below:
+                ; sub eax, 1
+                ; ret
+            );
+            let code = MemoryImage::from_code_region(0x1000, &code);
+
+            let block_ir = |peephole| {
+                let options = TranslationOptions {
+                    peephole,
+                    flag_liveness: true,
+                    ..TranslationOptions::default()
+                };
+                function_ir(&code, &options)
+            };
+
+            // the flags array is the field 1 of the context
+            let flags_access = "i32 0, i32 1, i32";
+            assert!(block_ir(false).contains(flags_access));
+            let fused = block_ir(true);
+            assert!(!fused.contains(flags_access));
+            assert!(fused.contains("icmp ult"));
+        }
+
+        #[test]
+        fn fused_setcc_llvm() {
+            use crate::config::TranslationOptions;
+
+            let code = assemble_x86!(
+                ; cmp eax, ebx
+                ; setl al
+                ; movzx eax, al
+                ; ret
+            );
+            let code = MemoryImage::from_code_region(0x1000, &code);
+
+            let block_ir = |peephole| {
+                let options = TranslationOptions {
+                    peephole,
+                    ..TranslationOptions::default()
+                };
+                function_ir(&code, &options)
+            };
+
+            // a write to AL keeps the rest of EAX: masked with 0xffffff00
+            let partial_write = "-256";
+            assert!(block_ir(false).contains(partial_write));
+            let fused = block_ir(true);
+            assert!(!fused.contains(partial_write));
+            // the condition selected at 32 bits right away
+            assert!(fused.contains("i32 1, i32 0"));
+        }
+
+        #[test]
+        fn known_branch_llvm() {
+            use crate::config::TranslationOptions;
+
+            let code = assemble_x86!(
+                ; xor eax, eax
+                ; jz ->taken
+                ; mov eax, 1
+                ; ret
+                ; ->taken:
+                ; mov eax, 2
+                ; ret
+            );
+            let code = MemoryImage::from_code_region(0x1000, &code);
+
+            let block_ir = |peephole| {
+                let options = TranslationOptions {
+                    peephole,
+                    ..TranslationOptions::default()
+                };
+                function_ir(&code, &options)
+            };
+
+            let conditional = block_ir(false);
+            assert!(conditional.contains("br_to_0000100a"));
+            assert!(conditional.contains("bb_00001004"));
+            // straight to the taken block, the fallthrough isn't even there
+            let folded = block_ir(true);
+            assert!(!folded.contains("br_to_0000100a"));
+            assert!(!folded.contains("bb_00001004"));
+            assert!(folded.contains("sub_0000100a"));
+        }
+
+        #[test]
+        fn split_blocks_llvm() {
+            use crate::config::{TranslationOptions, DEFAULT_MAX_BLOCK_INSTRUCTIONS};
+
+            // xor eax, eax; 5000 x inc eax; ret
+            let mut code = vec![0x31, 0xc0];
+            code.extend(std::iter::repeat(0x40).take(5000));
+            code.push(0xc3);
+            let code = MemoryImage::from_code_region(0x1000, &code);
+
+            let context = &Context::create();
+            let types = &llvm::backend::Types::new(context);
+            let rt_funs = &llvm::backend::RuntimeHelpers::dummy(types);
+            let options = TranslationOptions::default();
+            let translation =
+                llvm::try_translate(context, types, rt_funs, &options, &code, &[0x1000]).unwrap();
+            translation.module.verify().unwrap();
+
+            assert_eq!(translation.blocks, 3);
+            assert_eq!(translation.stats.split_blocks, 2);
+            // the jump to the next piece on top
+            assert_eq!(
+                translation.stats.longest_block,
+                DEFAULT_MAX_BLOCK_INSTRUCTIONS + 1
+            );
+            assert_eq!(translation.guest_bytes, 5003);
+            // chained directly
+            let first = translation
+                .module
+                .get_function("sub_00001000")
+                .unwrap()
+                .print_to_string()
+                .to_string();
+            assert!(first.contains(&format!("sub_{:08x}", 0x1002 + 2047)));
+        }
+
+        #[test]
+        fn constant_address_llvm() {
+            use crate::config::TranslationOptions;
+
+            let code = assemble_x86!(
+                ; mov eax, 0x100000
+                ; mov ebx, [eax + 8]
+                ; mov ecx, [eax + 0x100000]
+                ; ret
+            );
+            let code = MemoryImage::from_code_region(0x1000, &code);
+
+            let block_ir = |constant_addresses| {
+                let options = TranslationOptions {
+                    constant_addresses,
+                    memory_limit: Some(0x200000),
+                    ..TranslationOptions::default()
+                };
+                function_ir(&code, &options)
+            };
+
+            // both accesses are checked at run time without the folding
+            assert_eq!(block_ir(false).matches("\nout_of_bounds").count(), 2);
+            // the first one is in bounds, and the load goes straight to the host memory.
+            // The second one is past the limit, so it still gets the check (which always traps)
+            let folded = block_ir(true);
+            assert_eq!(folded.matches("\nout_of_bounds").count(), 1);
+            assert!(folded.contains("i64 1048584"));
+        }
+
+        #[test]
+        fn switch_table_llvm() {
+            use crate::config::TranslationOptions;
+            use crate::ir::{
+                decode_block, CodeMode, InvalidOpcodePolicy, UnsupportedInstructionPolicy,
+            };
+            use crate::types::Operand;
+
+            // `dispatch` from c_functions.c: `cmp eax, 4; ja default; jmp [table + eax * 4]`
+            const BASE: u32 = 0x10000;
+            let code = include_bytes!("../tests/fixtures/c_functions.bin");
+            let dispatch = u32::from_le_bytes(code[8..12].try_into().unwrap());
+            let image = MemoryImage::from_code_region(BASE, code);
+            let jmp = decode_block(
+                image.execute_all_at(dispatch),
+                dispatch,
+                usize::MAX,
+                InvalidOpcodePolicy::Fault,
+                UnsupportedInstructionPolicy::Fail,
+                CodeMode::Bits32,
+            )
+            .unwrap()
+            .pop()
+            .unwrap();
+            let table = match jmp.operands.as_slice() {
+                [Operand::Memory(table)] => table.displacement as u32,
+                _ => panic!("not a table jump: {}", jmp),
+            };
+            let cases = (0..5)
+                .map(|i| {
+                    let entry = (table - BASE + i * 4) as usize;
+                    u32::from_le_bytes(code[entry..entry + 4].try_into().unwrap())
+                })
+                .collect::<Vec<_>>();
+
+            let context = &Context::create();
+            let types = &llvm::backend::Types::new(context);
+            let rt_funs = &llvm::backend::RuntimeHelpers::dummy(types);
+            let options = TranslationOptions {
+                switch_tables: true,
+                ..TranslationOptions::default()
+            };
+            let module = llvm::recompile_with_options(
+                context,
+                types,
+                rt_funs,
+                &options,
+                &image,
+                &[dispatch],
+            );
+            module.verify().unwrap();
+
+            let ir = module
+                .get_function(&format!("sub_{:08x}", dispatch))
+                .unwrap()
+                .print_to_string()
+                .to_string();
+            trace!("llvm ir:\n{}", ir);
+            assert_eq!(ir.matches("switch i32").count(), 1);
+            assert!(ir.contains("label %switch_default"));
+            for (i, &case) in cases.iter().enumerate() {
+                assert!(ir.contains(&format!("i32 {}, label %case_{}_{:08x}", i, i, case)));
+                // translated along with the switch & called directly
+                assert!(module.get_function(&format!("sub_{:08x}", case)).is_some());
+                assert!(ir.contains(&format!("@sub_{:08x}(", case)));
+            }
+            // past the end of the table it goes through the dispatcher
+            assert!(ir.contains("@indirect_bb_call("));
+        }
+
+        #[test]
+        fn inline_leaves_llvm() {
+            use std::collections::BTreeSet;
+
+            use crate::config::TranslationOptions;
+            use crate::ir::{
+                decode_block, CodeMode, InvalidOpcodePolicy, UnsupportedInstructionPolicy,
+            };
+
+            let code = assemble_x86!(
+                ; mov ecx, 0x100
+                ; call ->getter
+                ; add eax, 1
+                ; call ->with_argument
+                ; ret
+                ; ->getter:
+                ; mov eax, [ecx + 4]
+                ; ret
+                ; ->with_argument:
+                ; mov eax, [esp + 4]
+                ; ret
+            );
+            let image = MemoryImage::from_code_region(0x1000, &code);
+            let calls = decode_block(
+                &code,
+                0x1000,
+                usize::MAX,
+                InvalidOpcodePolicy::Fault,
+                UnsupportedInstructionPolicy::Fail,
+                CodeMode::Bits32,
+            )
+            .unwrap()
+            .iter()
+            .filter_map(|instr| instr.direct_call_target())
+            .collect::<Vec<_>>();
+            let (getter, with_argument) = (calls[0], calls[1]);
+
+            let context = &Context::create();
+            let types = &llvm::backend::Types::new(context);
+            let rt_funs = &llvm::backend::RuntimeHelpers::dummy(types);
+            let translate = |inline_leaves| {
+                let options = TranslationOptions {
+                    inline_leaves,
+                    ..TranslationOptions::default()
+                };
+                let translation =
+                    llvm::translate(context, types, rt_funs, &options, &image, &[0x1000]);
+                translation.module.verify().unwrap();
+                let ir = translation
+                    .module
+                    .get_function("sub_00001000")
+                    .unwrap()
+                    .print_to_string()
+                    .to_string();
+                trace!("llvm ir:\n{}", ir);
+                // the leaf is there for the other callers all the same
+                assert!(translation
+                    .module
+                    .get_function(&format!("sub_{:08x}", getter))
+                    .is_some());
+                (translation.stats, translation.inlined_leaves, ir)
+            };
+
+            let (stats, inlined, ir) = translate(false);
+            assert_eq!(stats.inlined_calls, 0);
+            assert!(inlined.is_empty());
+            assert!(ir.contains(&format!("@sub_{:08x}(", getter)));
+
+            // the one reading its argument from the stack is called for real
+            let (stats, inlined, ir) = translate(true);
+            assert_eq!(stats.inlined_calls, 1);
+            assert_eq!(
+                inlined.into_iter().collect::<Vec<_>>(),
+                [(getter, BTreeSet::from([0x1000]))]
+            );
+            assert!(!ir.contains(&format!("@sub_{:08x}(", getter)));
+            assert!(ir.contains(&format!("@sub_{:08x}(", with_argument)));
+        }
+
+        #[test]
+        fn shadow_flags_llvm() {
+            use std::collections::HashMap;
+
+            use inkwell::execution_engine::JitFunction;
+            use inkwell::OptimizationLevel;
+
+            use crate::codegen_instr;
+            use crate::config::TranslationOptions;
+            use crate::handler::VECTOR_FLAG_MISMATCH;
+            use crate::ir::Decoder;
+            use crate::liveness::FlagSet;
+            use crate::llvm::backend::{EntryFunc, LlvmBuilder};
+            use crate::types::{
+                CpuContext, Flag, FlagStorage, FullSizeGeneralPurposeRegister::*, EXIT_FAULT,
+                EXIT_NONE,
+            };
+
+            let code = assemble_x86!(
+                ; cmp eax, ebx
+                ; adc ecx, 0
+            );
+            let mut decoder = Decoder::new(&code, 0x1000);
+            let cmp = decoder.decode().unwrap();
+            let adc = decoder.decode().unwrap();
+
+            // `broken` is the flag liveness gone wrong: the carry `adc` reads is not stored by the `cmp`
+            let run = |broken: bool, shadow_flags: bool| {
+                let context = &Context::create();
+                let types = &llvm::backend::Types::new(context);
+                let rt_funs = &llvm::backend::RuntimeHelpers::dummy(types);
+                let options = TranslationOptions {
+                    shadow_flags,
+                    flag_storage: FlagStorage::from_env(),
+                    ..TranslationOptions::default()
+                };
+                let module = context.create_module("test");
+                let dispatcher =
+                    module.add_function(llvm::DISPATCHER_NAME, types.indirect_bb_call, None);
+
+                let mut builder = LlvmBuilder::new(
+                    context, &module, types, rt_funs, &options, dispatcher, 0x1000,
+                );
+                for (index, instr) in [&cmp, &adc].into_iter().enumerate() {
+                    builder.set_dead_flags(if broken && index == 0 {
+                        FlagSet::all()
+                    } else {
+                        FlagSet::empty()
+                    });
+                    let flow = codegen_instr(&mut builder, instr);
+                    builder.handle_flow(instr.next_ip(), flow);
+                }
+                builder.get_raw_builder().build_return(None);
+
+                let blocks = HashMap::from([(0x1000, builder.get_function())]);
+                llvm::codegen_dynamic_dispatcher(context, &module, types, &blocks, dispatcher);
+                llvm::add_entry_trampoline(context, &module, types);
+                trace!("llvm ir:\n{}", module.print_to_string().to_string());
+                module.verify().unwrap();
+
+                let engine = module
+                    .create_jit_execution_engine(OptimizationLevel::None)
+                    .unwrap();
+                let entry: JitFunction<EntryFunc> =
+                    unsafe { engine.get_function(llvm::ENTRY_TRAMPOLINE).unwrap() };
+                let mut ctx = CpuContext::with_flag_storage(options.flag_storage);
+                ctx.set_gp_reg(EAX, 1);
+                ctx.set_gp_reg(EBX, 2);
+                // neither of them touches the memory
+                let exit = unsafe { entry.call(&mut ctx, std::ptr::null_mut(), 0x1000) };
+                (exit, ctx)
+            };
+
+            let (exit, ctx) = run(false, true);
+            assert_eq!(exit, EXIT_NONE);
+            assert_eq!(ctx.get_gp_reg(ECX), 1);
+
+            // unnoticed without the shadow
+            let (exit, ctx) = run(true, false);
+            assert_eq!(exit, EXIT_NONE);
+            assert_eq!(ctx.get_gp_reg(ECX), 0);
+
+            let (exit, ctx) = run(true, true);
+            assert_eq!(exit, EXIT_FAULT);
+            assert_eq!(ctx.fault_vector, VECTOR_FLAG_MISMATCH as u32);
+            assert_eq!(ctx.fault_address, Flag::Carry as u32);
+            assert_eq!(ctx.eip, adc.ip);
+            assert_eq!(ctx.get_gp_reg(ECX), 0);
+        }
+
+        #[test]
+        fn register_store_order_llvm() {
+            let code = assemble_x86!(
+                ; mov esi, 1
+                ; mov ebx, 2
+                ; mov edx, 3
+                ; mov eax, 4
+                ; mov ecx, 5
+                ; ret
+            );
+            let image = MemoryImage::from_code_region(0x1000, &code);
+
+            let context = &Context::create();
+            let types = &llvm::backend::Types::new(context);
+            let rt_funs = &llvm::backend::RuntimeHelpers::dummy(types);
+            let module = llvm::recompile(context, types, rt_funs, &image, &[0x1000]);
+            module.verify().unwrap();
+
+            let ir = module
+                .get_function("sub_00001000")
+                .unwrap()
+                .print_to_string()
+                .to_string();
+            trace!("llvm ir:\n{}", ir);
+
+            // the registers are stored to the context by the instructions writing them, in the program order
+            let stores = ir
+                .lines()
+                .filter(|line| line.trim_start().starts_with("store i32"))
+                // `store i32 %value, i32* %ESP_ptr1, align 4`
+                .filter_map(|line| line.split("* %").nth(1)?.split(',').next())
+                .map(|pointer| pointer.trim_end_matches(|c: char| c.is_ascii_digit()))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                stores,
+                ["ESI_ptr", "EBX_ptr", "EDX_ptr", "EAX_ptr", "ECX_ptr", "ESP_ptr"]
+            );
+        }
+
+        #[test]
+        fn malformed_lowering_llvm() {
+            use crate::backend::Builder;
+            use crate::config::TranslationOptions;
+            use crate::handler::GuestFault;
+            use crate::ir::{Decoder, Instr};
+            use crate::llvm::backend::LlvmBuilder;
+            use crate::llvm::{MalformedBlock, TranslateError};
+            use crate::types::Flag;
+
+            // a fault in a conditional nested in another one, like the #DE of a div. A misbehaving fault ends the
+            // block with a `ret` without starting a new one, so the `br` closing the inner conditional goes after it
+            fn lower(
+                builder: &mut LlvmBuilder,
+                instr: &Instr,
+                misbehave: bool,
+            ) -> Result<(), MalformedBlock> {
+                let checkpoint = builder.lowering_checkpoint();
+                builder.begin_instruction(instr);
+                let outer = builder.load_flag(Flag::Carry);
+                builder.ifelse(
+                    outer,
+                    |builder| {
+                        let inner = builder.load_flag(Flag::Zero);
+                        builder.ifelse(
+                            inner,
+                            |builder| {
+                                if misbehave {
+                                    builder.get_raw_builder().build_return(None);
+                                } else {
+                                    builder
+                                        .raise_fault(GuestFault::DivideError, builder.make_u32(0))
+                                }
+                            },
+                            |_| {},
+                        );
+                    },
+                    |_| {},
+                );
+                builder.check_lowering(checkpoint, false)
+            }
+
+            let code = assemble_x86!(
+                ; div ecx
+                ; ret
+            );
+            let instr = Decoder::new(&code, 0x1000).decode().unwrap();
+
+            let context = &Context::create();
+            let types = &llvm::backend::Types::new(context);
+            let rt_funs = &llvm::backend::RuntimeHelpers::dummy(types);
+            let options = TranslationOptions::default();
+            let module = context.create_module("test");
+            let dispatcher = module.add_function("dispatcher", types.indirect_bb_call, None);
+            let builder = |address| {
+                LlvmBuilder::new(
+                    context, &module, types, rt_funs, &options, dispatcher, address,
+                )
+            };
+
+            assert_eq!(lower(&mut builder(0x1000), &instr, false), Ok(()));
+
+            // entry, the outer then/else/continuation, then the inner ones
+            let problem = lower(&mut builder(0x2000), &instr, true).unwrap_err();
+            assert_eq!(
+                problem,
+                MalformedBlock::AfterTerminator {
+                    index: 4,
+                    name: String::new()
+                }
+            );
+            let error = TranslateError {
+                ip: instr.ip,
+                instr: instr.to_string(),
+                problem,
+            };
+            assert_eq!(
+                error.to_string(),
+                format!(
+                    "lowering `{}` at 0x00001000 left an instruction after the terminator of block 4",
+                    instr
+                )
+            );
+
+            // and the real lowering passes the checks
+            let context = &Context::create();
+            let types = &llvm::backend::Types::new(context);
+            let rt_funs = &llvm::backend::RuntimeHelpers::dummy(types);
+            let code = MemoryImage::from_code_region(0x1000, &code);
+            let translation =
+                llvm::try_translate(context, types, rt_funs, &options, &code, &[0x1000]).unwrap();
+            translation.module.verify().unwrap();
+        }
     }
 }
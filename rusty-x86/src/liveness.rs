@@ -0,0 +1,427 @@
+//! Flag liveness across the basic blocks, to skip computing the flags nobody looks at
+//!
+//! Almost every block starts with something like `cmp` or `add`, so the flags left by its predecessor
+//! are dead. Which flags are live at the start of each block is computed with the usual backwards
+//! fixpoint over the statically discovered CFG, then every instruction gets a set of flags it doesn't
+//! need to store.
+//!
+//! Whatever we can't see through (indirect jumps, calls, returns, the handlers and all the instructions
+//! not described in `effects`) is treated as reading all the flags. The one place where the difference is
+//! observable are the faults: the context at a fault might have stale values of the dead flags.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use bitflags::bitflags;
+
+use crate::ir::{Condition, Instr, Mnemonic};
+use crate::peephole::CompilationStats;
+use crate::switch_table;
+use crate::types::{Flag, Operand};
+
+bitflags! {
+    /// One bit per `Flag`
+    #[derive(Default)]
+    pub struct FlagSet: u8 {
+        const CARRY = 1 << Flag::Carry as u8;
+        const PARITY = 1 << Flag::Parity as u8;
+        const AUXILIARY_CARRY = 1 << Flag::AuxiliaryCarry as u8;
+        const ZERO = 1 << Flag::Zero as u8;
+        const SIGN = 1 << Flag::Sign as u8;
+        const OVERFLOW = 1 << Flag::Overflow as u8;
+        const DIRECTION = 1 << Flag::Direction as u8;
+        const ID = 1 << Flag::Id as u8;
+        /// What the integer arithmetic sets (PF & AF are not maintained by it, bar PF of the zero idioms)
+        const ARITHMETIC = Self::CARRY.bits | Self::ZERO.bits | Self::SIGN.bits | Self::OVERFLOW.bits;
+    }
+}
+
+impl From<Flag> for FlagSet {
+    fn from(flag: Flag) -> Self {
+        Self::from_bits_truncate(1 << flag as u8)
+    }
+}
+
+fn condition_flags(condition: Condition) -> FlagSet {
+    use Condition::*;
+    match condition {
+        O | NO => FlagSet::OVERFLOW,
+        B | AE => FlagSet::CARRY,
+        E | NE => FlagSet::ZERO,
+        BE | A => FlagSet::CARRY | FlagSet::ZERO,
+        S | NS => FlagSet::SIGN,
+        P | NP => FlagSet::PARITY,
+        L | GE => FlagSet::SIGN | FlagSet::OVERFLOW,
+        LE | G => FlagSet::SIGN | FlagSet::OVERFLOW | FlagSet::ZERO,
+    }
+}
+
+/// (read, always overwritten) flags of an instruction, as lowered by `codegen_instr`
+fn effects(instr: &Instr) -> (FlagSet, FlagSet) {
+    use Mnemonic::*;
+
+    let none = FlagSet::empty();
+    if !instr.prefixes.is_empty() {
+        return (FlagSet::all(), none);
+    }
+
+    match (instr.mnemonic, instr.operands.as_slice()) {
+        (
+            Nop | Mov | Movzx | Movsx | Lea | Push | Pop | Pushad | Popad | Pusha | Popa | Leave
+            | Not | Bswap | Xchg | Cbw | Cwde | Cwd | Cdq | Prologue | AddNoFlags,
+            _,
+        ) => (none, none),
+        // the zero idioms set PF too
+        (ZeroReg, _) => (none, FlagSet::ARITHMETIC | FlagSet::PARITY),
+        (Xor | Sub | Cmp | CmpJcc(_) | CmpCmovcc(_), [dst @ Operand::Register(_), src, ..])
+            if dst == src =>
+        {
+            (none, FlagSet::ARITHMETIC | FlagSet::PARITY)
+        }
+        (
+            Add | Sub | Cmp | Neg | Xor | And | Or | Test | Mul | Imul | TestJcc(_) | CmpJcc(_)
+            | CmpCmovcc(_),
+            _,
+        ) => (none, FlagSet::ARITHMETIC),
+        (Adc | Sbb, _) => (FlagSet::CARRY, FlagSet::ARITHMETIC),
+        (Inc | Dec, _) => (none, FlagSet::ARITHMETIC - FlagSet::CARRY),
+        // a zero count leaves the flags alone
+        (Shl | Shr | Sar, [_, Operand::Immediate8(count)]) if count & 0x1f != 0 => {
+            (none, FlagSet::ARITHMETIC)
+        }
+        (Shl | Shr | Sar, _) => (none, none),
+        (Shld | Shrd, [_, _, Operand::Immediate8(count)]) if count & 0x1f != 0 => {
+            (none, FlagSet::ARITHMETIC)
+        }
+        (Shld | Shrd, _) => (none, none),
+        (Rol | Ror, [_, Operand::Immediate8(count)]) if count & 0x1f != 0 => {
+            (none, FlagSet::CARRY | FlagSet::OVERFLOW)
+        }
+        (Rol | Ror, _) => (none, none),
+        (Stc | Clc | Bt | Bts | Btr | Btc, _) => (none, FlagSet::CARRY),
+        (Bsf | Bsr, _) => (none, FlagSet::ZERO),
+        (Aam | Aad, _) => (none, FlagSet::ARITHMETIC),
+        // all of them come from the popped image (`unpack_eflags`), AF isn't kept at all
+        (Popfd | Popf, _) => (none, FlagSet::all() - FlagSet::AUXILIARY_CARRY),
+        (Jcc(condition) | Cmovcc(condition) | Setcc(condition) | SetccZx(condition), _) => {
+            (condition_flags(condition), none)
+        }
+        (Jmp, [Operand::Immediate32(_)]) => (none, none),
+        _ => (FlagSet::all(), none),
+    }
+}
+
+/// The instructions treated as reading all the flags: after them nothing can tell a skipped store happened
+pub fn reads_all_flags(instr: &Instr) -> bool {
+    effects(instr).0 == FlagSet::all()
+}
+
+/// Target of a `jcc` (or its fused form)
+fn branch_target(instr: &Instr) -> Option<u32> {
+    match (instr.mnemonic, instr.operands.as_slice()) {
+        (
+            Mnemonic::Jcc(_) | Mnemonic::TestJcc(_) | Mnemonic::CmpJcc(_),
+            [.., Operand::Immediate32(target)],
+        ) => Some(*target),
+        _ => None,
+    }
+}
+
+/// Where the execution goes after the last instruction of the block (if we know that)
+fn fallthrough(block: &[Instr]) -> Option<u32> {
+    let last = block.last()?;
+    match (last.mnemonic, last.operands.as_slice()) {
+        (Mnemonic::Jmp, [Operand::Immediate32(target)]) => Some(*target),
+        (mnemonic, _) if mnemonic.ends_block() => None,
+        // the block was cut short (per-instruction mode, the length limit)
+        _ => Some(last.next_ip()),
+    }
+}
+
+/// Decodes everything statically reachable from `entries` with `decode`.
+/// The blocks that don't decode are left out (and are treated as reading everything)
+pub fn discover_blocks(
+    entries: &[u32],
+    mut decode: impl FnMut(u32) -> Option<Vec<Instr>>,
+) -> BTreeMap<u32, Vec<Instr>> {
+    let mut blocks = BTreeMap::new();
+    let mut queue: VecDeque<u32> = entries.iter().copied().collect();
+
+    while let Some(address) = queue.pop_front() {
+        if blocks.contains_key(&address) {
+            continue;
+        }
+        let block = match decode(address) {
+            Some(block) if !block.is_empty() => block,
+            _ => continue,
+        };
+
+        for instr in &block {
+            queue.extend(branch_target(instr));
+            queue.extend(instr.direct_call_target());
+            queue.extend(switch_table::targets(instr));
+        }
+        queue.extend(fallthrough(&block));
+
+        blocks.insert(address, block);
+    }
+    blocks
+}
+
+/// For every analyzed block: the flags that are dead after each of its instructions
+#[derive(Debug, Default)]
+pub struct FlagLiveness {
+    dead: HashMap<u32, Vec<FlagSet>>,
+}
+
+impl FlagLiveness {
+    pub fn analyze(blocks: &BTreeMap<u32, Vec<Instr>>, stats: &mut CompilationStats) -> Self {
+        let mut live_in: HashMap<u32, FlagSet> =
+            blocks.keys().map(|&a| (a, FlagSet::empty())).collect();
+
+        // the sets only grow, so this terminates
+        loop {
+            let mut changed = false;
+            for (&address, block) in blocks.iter().rev() {
+                let (live, _) = walk(block, &live_in);
+                if live != live_in[&address] {
+                    live_in.insert(address, live);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+
+        let dead = blocks
+            .iter()
+            .map(|(&address, block)| {
+                let (_, dead) = walk(block, &live_in);
+                for (instr, dead) in block.iter().zip(&dead) {
+                    let (_, written) = effects(instr);
+                    stats.dead_flag_stores += (written & *dead).bits().count_ones() as usize;
+                }
+                (address, dead)
+            })
+            .collect();
+
+        Self { dead }
+    }
+
+    /// The flags whose stores can be skipped in the `index`th instruction of the block at `block`
+    pub fn dead_flags(&self, block: u32, index: usize) -> FlagSet {
+        self.dead
+            .get(&block)
+            .and_then(|dead| dead.get(index))
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// Goes backwards through the block, returns what's live at its start & what's dead after each instruction
+fn walk(block: &[Instr], live_in: &HashMap<u32, FlagSet>) -> (FlagSet, Vec<FlagSet>) {
+    // the blocks we know nothing about might read anything
+    let live_at = |address| live_in.get(&address).copied().unwrap_or(FlagSet::all());
+
+    let mut live = fallthrough(block).map_or(FlagSet::all(), live_at);
+    let mut dead = vec![FlagSet::empty(); block.len()];
+    for (i, instr) in block.iter().enumerate().rev() {
+        if let Some(target) = branch_target(instr) {
+            live |= live_at(target);
+        }
+
+        let (read, written) = effects(instr);
+        // the instruction might look at what it has just stored
+        dead[i] = !live - read;
+        live = (live - written) | read;
+    }
+    (live, dead)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{discover_blocks, FlagLiveness, FlagSet};
+    use crate::assemble_x86;
+    use crate::ir::{
+        decode_block, CodeMode, Instr, InvalidOpcodePolicy, UnsupportedInstructionPolicy,
+    };
+    use crate::peephole::CompilationStats;
+
+    const CODE_ADDR: u32 = 0x1000;
+
+    fn blocks(code: &[u8]) -> BTreeMap<u32, Vec<Instr>> {
+        discover_blocks(&[CODE_ADDR], |address| {
+            let offset = address.checked_sub(CODE_ADDR)? as usize;
+            decode_block(
+                code.get(offset..)?,
+                address,
+                usize::MAX,
+                InvalidOpcodePolicy::Fault,
+                UnsupportedInstructionPolicy::Fail,
+                CodeMode::Bits32,
+            )
+            .ok()
+        })
+    }
+
+    #[test_log::test]
+    fn across_blocks() {
+        let code = assemble_x86!(
+            ; ->start:
+            ; add eax, ebx  // all dead: the cmp overwrites them
+            ; jmp ->next
+            ; ->next:
+            ; inc ecx       // CF is never written, ZF/SF/OF are dead
+            ; cmp ecx, 10   // only CF is read, by the jb
+            ; jb ->start
+            ; sub eax, 1    // the caller might look at these
+            ; ret
+        );
+        let blocks = blocks(&code);
+        // add & a near jmp
+        let next = CODE_ADDR + 2 + 5;
+        assert_eq!(
+            blocks.keys().copied().collect::<Vec<_>>(),
+            vec![CODE_ADDR, next]
+        );
+
+        let mut stats = CompilationStats::default();
+        let liveness = FlagLiveness::analyze(&blocks, &mut stats);
+        assert_eq!(stats.dead_flag_stores, 4 + 3 + 3);
+
+        assert!(liveness
+            .dead_flags(CODE_ADDR, 0)
+            .contains(FlagSet::ARITHMETIC));
+        assert!(liveness.dead_flags(next, 0).contains(FlagSet::ARITHMETIC));
+        assert_eq!(
+            liveness.dead_flags(next, 1) & FlagSet::ARITHMETIC,
+            FlagSet::ZERO | FlagSet::SIGN | FlagSet::OVERFLOW
+        );
+        assert_eq!(liveness.dead_flags(next, 3), FlagSet::empty());
+        // not analyzed
+        assert_eq!(liveness.dead_flags(0x2000, 0), FlagSet::empty());
+    }
+
+    #[test_log::test]
+    fn unknown_successors() {
+        let code = assemble_x86!(
+            ; add eax, ebx
+            ; call eax
+            ; add eax, ebx
+            ; jmp eax
+        );
+        let mut stats = CompilationStats::default();
+        FlagLiveness::analyze(&blocks(&code), &mut stats);
+        assert_eq!(stats.dead_flag_stores, 0);
+    }
+
+    #[cfg(feature = "interp")]
+    mod differential {
+        use std::collections::HashMap;
+
+        use super::{blocks, CODE_ADDR};
+        use crate::assemble_x86;
+        use crate::handler::NullHandler;
+        use crate::interp::{Interpreter, StepResult};
+        use crate::liveness::{FlagLiveness, FlagSet};
+        use crate::peephole::CompilationStats;
+        use crate::types::{CpuContext, Flag, FullSizeGeneralPurposeRegister::*};
+        use strum::IntoEnumIterator;
+
+        fn run(code: &[u8], initial: &CpuContext, dead: &HashMap<u32, FlagSet>) -> CpuContext {
+            let mut memory = vec![0; 0x8000];
+            memory[CODE_ADDR as usize..][..code.len()].copy_from_slice(code);
+            let mut interp = Interpreter::new(memory, NullHandler);
+            interp.context = initial.clone();
+            interp.context.eip = CODE_ADDR;
+            loop {
+                interp.dead_flags = dead.get(&interp.context.eip).copied().unwrap_or_default();
+                match interp.step() {
+                    StepResult::Continue => {}
+                    StepResult::Returned => return interp.context,
+                    r => panic!("unexpected {:?}", r),
+                }
+            }
+        }
+
+        /// Runs the function with and without the dead flag stores from a bunch of different states
+        fn check(code: &[u8]) {
+            let blocks = blocks(code);
+            let mut stats = CompilationStats::default();
+            let liveness = FlagLiveness::analyze(&blocks, &mut stats);
+            assert_ne!(stats.dead_flag_stores, 0, "nothing is dead");
+
+            // what's dead after an instruction doesn't depend on where its block started
+            let dead = blocks
+                .iter()
+                .flat_map(|(&address, block)| {
+                    let liveness = &liveness;
+                    block
+                        .iter()
+                        .enumerate()
+                        .map(move |(i, instr)| (instr.ip, liveness.dead_flags(address, i)))
+                })
+                .collect();
+
+            for value in [0, 1, 0x7f, 0x80000000, 0xffffffff] {
+                for flags in [false, true] {
+                    let mut initial = CpuContext::default();
+                    for reg in [EAX, EBX, EDX, ESI, EDI] {
+                        initial.set_gp_reg(reg, value);
+                    }
+                    initial.set_gp_reg(ESP, 0x7000);
+                    for flag in Flag::iter() {
+                        initial.set_flag(flag, flags);
+                    }
+
+                    assert_eq!(
+                        run(code, &initial, &HashMap::new()),
+                        run(code, &initial, &dead),
+                        "mismatch with value = 0x{:x}, flags = {}",
+                        value,
+                        flags
+                    );
+                }
+            }
+        }
+
+        #[test_log::test]
+        fn loops() {
+            check(&assemble_x86!(
+                ; mov ecx, 0
+                ; ->top:
+                ; add eax, ebx
+                ; jmp ->next
+                ; ->next:
+                ; inc ecx
+                ; sub edx, ecx
+                ; cmp ecx, 5
+                ; jb ->top
+                ; shl eax, 3
+                ; test eax, eax
+                ; jz ->zero
+                ; sbb ebx, ecx
+                ; ->zero:
+                ; dec esi
+                ; ret
+            ));
+        }
+
+        #[test_log::test]
+        fn conditional_shifts() {
+            // the shift by cl might leave the flags of the add in place
+            check(&assemble_x86!(
+                ; add eax, ebx
+                ; mov ecx, esi
+                ; shl edx, cl
+                ; jc ->out
+                ; add edi, 1
+                ; ->out:
+                ; cmp eax, edi
+                ; ret
+            ));
+        }
+    }
+}
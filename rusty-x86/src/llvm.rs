@@ -1,7 +1,6 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
 
-use iced_x86::Code::Call_rel32_32;
-use iced_x86::{Decoder, DecoderOptions};
 use inkwell::basic_block::BasicBlock;
 use inkwell::context::Context;
 use inkwell::module::{Linkage, Module};
@@ -13,12 +12,22 @@ use inkwell::OptimizationLevel;
 use log::debug;
 
 use crate::codegen_instr;
+use crate::config::TranslationOptions;
+use crate::constprop;
+use crate::coverage;
+use crate::inlining;
+use crate::ir::{decode_block, DecodeError, Instr, Mnemonic};
+use crate::liveness::{self, FlagLiveness};
 use crate::llvm::backend::{
     Intrinsics, LlvmBuilder, RuntimeHelpers, Types, FASTCC_CALLING_CONVENTION,
 };
 use crate::memory_image::MemoryImage;
+use crate::peephole::{self, CompilationStats};
+use crate::switch_table;
+use crate::types::{ControlFlow, Operand, EXIT_NONE};
 
 pub mod backend;
+pub mod cache;
 
 pub fn get_aarch64_target_machine() -> TargetMachine {
     Target::initialize_aarch64(&InitializationConfig::default());
@@ -37,7 +46,7 @@ pub fn get_aarch64_target_machine() -> TargetMachine {
         .unwrap()
 }
 
-fn codegen_dynamic_dispatcher<'ctx>(
+pub(crate) fn codegen_dynamic_dispatcher<'ctx>(
     context: &'ctx Context,
     module: &'ctx Module,
     types: &'ctx Types,
@@ -90,6 +99,185 @@ fn codegen_dynamic_dispatcher<'ctx>(
     builder.build_switch(eip, else_bb, &cases);
 }
 
+pub(crate) const DISPATCHER_NAME: &str = "indirect_bb_call";
+
+/// The guest eip of every memory access in the translated code, indexed by the number the code stores
+/// to `CpuContext::fault_site` right before the access (only with `TranslationOptions::fault_sites`)
+///
+/// With the blocks chained & optimized there is no telling which guest instruction a faulting host one
+/// belongs to otherwise
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FaultSites(Vec<u32>);
+
+impl FaultSites {
+    /// Number of the new site
+    pub fn add(&mut self, eip: u32) -> u32 {
+        self.0.push(eip);
+        (self.0.len() - 1) as u32
+    }
+
+    pub fn eip(&self, site: u32) -> Option<u32> {
+        self.0.get(site as usize).copied()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+/// How a lowering broke the structure of the function it was emitted into, see `LlvmBuilder::check_lowering`
+///
+/// The blocks are named by their position in the function (the entry one is 0) & their name, which is often empty
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MalformedBlock {
+    /// Falls off the end
+    Unterminated { index: usize, name: String },
+    /// Has something after its terminator (possibly another terminator)
+    AfterTerminator { index: usize, name: String },
+    /// The builder was left in a terminated block, so the control flow of the instruction had nowhere to go
+    LeftTerminated { index: usize, name: String },
+}
+
+impl Display for MalformedBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let (what, index, name) = match self {
+            MalformedBlock::Unterminated { index, name } => ("no terminator in", index, name),
+            MalformedBlock::AfterTerminator { index, name } => {
+                ("an instruction after the terminator of", index, name)
+            }
+            MalformedBlock::LeftTerminated { index, name } => {
+                ("the builder left at the terminator of", index, name)
+            }
+        };
+        write!(f, "{} block {}", what, index)?;
+        if !name.is_empty() {
+            write!(f, " ({})", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// The lowering of a guest instruction generated malformed LLVM IR
+///
+/// Only looked for in the debug builds, `translate` panics with it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranslateError {
+    pub ip: u32,
+    /// As disassembled
+    pub instr: String,
+    pub problem: MalformedBlock,
+}
+
+impl TranslateError {
+    fn new(instr: &Instr, problem: MalformedBlock) -> Self {
+        Self {
+            ip: instr.ip,
+            instr: instr.to_string(),
+            problem,
+        }
+    }
+}
+
+impl Display for TranslateError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "lowering `{}` at 0x{:08x} left {}",
+            self.instr, self.ip, self.problem
+        )
+    }
+}
+
+impl std::error::Error for TranslateError {}
+
+/// Why `try_translate` gave up on the code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TranslateFailure {
+    /// Some of the reachable code doesn't decode (an unsupported instruction with `UnsupportedInstructionPolicy::Fail`,
+    /// an instruction cut off by the end of the code)
+    Decode(DecodeError),
+    Lowering(TranslateError),
+}
+
+impl Display for TranslateFailure {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TranslateFailure::Decode(e) => e.fmt(f),
+            TranslateFailure::Lowering(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for TranslateFailure {}
+
+/// The checks are not free, and a release build is not where the lowerings are debugged
+const VALIDATE_LOWERING: bool = cfg!(debug_assertions);
+
+/// A recompiled module with what the runtime needs to know about it
+pub struct Translation<'ctx> {
+    pub module: Module<'ctx>,
+    pub fault_sites: FaultSites,
+    /// The guest basic blocks in the module
+    pub blocks: usize,
+    /// The guest code they span, in bytes
+    pub guest_bytes: u64,
+    pub stats: CompilationStats,
+    /// The blocks with a copy of a leaf function in them, by the address of the leaf (see inlining.rs)
+    pub inlined_leaves: BTreeMap<u32, BTreeSet<u32>>,
+}
+
+/// The C ABI function all the entries from the host go through (see `add_entry_trampoline`)
+pub const ENTRY_TRAMPOLINE: &str = "rusty_x86_enter";
+
+/// Adds `u32 rusty_x86_enter(CpuContext *ctx, u8 *mem, u32 eip)` to a recompiled module
+///
+/// It clears `CpuContext::exit`, runs the translated block at `eip` (through the dispatcher, so any of them)
+/// & returns the `exit` it ended with. The blocks are fastcc and are free to use any host register,
+/// having a C function in between is what keeps the callee-saved ones of the host intact
+pub fn add_entry_trampoline<'ctx>(
+    context: &'ctx Context,
+    module: &Module<'ctx>,
+    types: &Types<'ctx>,
+) -> FunctionValue<'ctx> {
+    let trampoline = module.add_function(ENTRY_TRAMPOLINE, types.entry_fn, Some(Linkage::External));
+    let dispatcher = module
+        .get_function(DISPATCHER_NAME)
+        .expect("not a recompiled module");
+
+    let builder = context.create_builder();
+    builder.position_at_end(context.append_basic_block(trampoline, "entry"));
+
+    let ctx_ptr = trampoline.get_nth_param(0).unwrap().into_pointer_value();
+    let mem_ptr = trampoline.get_nth_param(1).unwrap().into_pointer_value();
+    let eip = trampoline.get_nth_param(2).unwrap().into_int_value();
+
+    // SAFETY: exit is the 4th field of the context
+    let exit_ptr = unsafe {
+        builder.build_gep(
+            ctx_ptr,
+            &[types.i32.const_zero(), types.i32.const_int(3, false)],
+            "exit_ptr",
+        )
+    };
+    builder.build_store(exit_ptr, types.i32.const_int(EXIT_NONE as u64, false));
+
+    let call = builder.build_call(
+        dispatcher,
+        &[ctx_ptr.into(), mem_ptr.into(), eip.into()],
+        "",
+    );
+    call.set_call_convention(FASTCC_CALLING_CONVENTION);
+
+    let exit = builder.build_load(exit_ptr, "exit");
+    builder.build_return(Some(&exit));
+
+    trampoline
+}
+
 pub fn recompile<'ctx>(
     context: &'ctx Context,
     types: &'ctx Types,
@@ -97,11 +285,54 @@ pub fn recompile<'ctx>(
     image: &MemoryImage,
     basic_blocks: &[u32],
 ) -> Module<'ctx> {
+    recompile_with_options(
+        context,
+        types,
+        rt_funs,
+        &TranslationOptions::default(),
+        image,
+        basic_blocks,
+    )
+}
+
+pub fn recompile_with_options<'ctx>(
+    context: &'ctx Context,
+    types: &'ctx Types,
+    rt_funs: &'ctx RuntimeHelpers<'ctx>,
+    options: &TranslationOptions,
+    image: &MemoryImage,
+    basic_blocks: &[u32],
+) -> Module<'ctx> {
+    translate(context, types, rt_funs, options, image, basic_blocks).module
+}
+
+pub fn translate<'ctx>(
+    context: &'ctx Context,
+    types: &'ctx Types,
+    rt_funs: &'ctx RuntimeHelpers<'ctx>,
+    options: &TranslationOptions,
+    image: &MemoryImage,
+    basic_blocks: &[u32],
+) -> Translation<'ctx> {
+    try_translate(context, types, rt_funs, options, image, basic_blocks)
+        .unwrap_or_else(|e| panic!("{}", e))
+}
+
+/// `translate`, with the code that doesn't decode reported instead of panicking on, and the structure of the
+/// generated functions checked after every instruction in the debug builds
+pub fn try_translate<'ctx>(
+    context: &'ctx Context,
+    types: &'ctx Types,
+    rt_funs: &'ctx RuntimeHelpers<'ctx>,
+    options: &TranslationOptions,
+    image: &MemoryImage,
+    basic_blocks: &[u32],
+) -> Result<Translation<'ctx>, TranslateFailure> {
     let module_obj = context.create_module("test");
     let module = &module_obj;
 
     let indirect_bb_call = module.add_function(
-        "indirect_bb_call",
+        DISPATCHER_NAME,
         types.indirect_bb_call,
         Some(Linkage::Internal),
     );
@@ -109,61 +340,240 @@ pub fn recompile<'ctx>(
 
     let mut queue = VecDeque::new();
     let mut lifted_functions = HashMap::new();
+    let mut stats = CompilationStats::default();
+    let mut fault_sites = FaultSites::default();
+    let mut guest_bytes = 0;
+    let mut leaves = HashMap::new();
+    let mut inlined_leaves: BTreeMap<u32, BTreeSet<u32>> = BTreeMap::new();
     queue.extend(basic_blocks);
 
+    // kinda want to assert that the block ends with a ret or a jmp, but some tests without ret's don't work then
+    // (that's what `fetch_faults` is for)
+    let max_len = if options.per_instruction {
+        1
+    } else {
+        options.max_block_instructions
+    };
+    let inline_leaves = options.inline_leaves && !options.per_instruction && !options.coverage;
+    let decode = |address: u32, stats: &mut CompilationStats| {
+        let mut block = decode_block(
+            image.execute_all_at(address),
+            address,
+            max_len,
+            options.invalid_opcodes,
+            options.unsupported_instructions,
+            options.code_mode,
+        )?;
+        // the code ran out between two instructions (or before the first one)
+        let falls_off = block.len() < max_len
+            && !block
+                .last()
+                .is_some_and(|instr| instr.mnemonic.ends_block());
+        if options.fetch_faults && falls_off {
+            let end = block.last().map_or(address, Instr::next_ip);
+            block.push(Instr::fetch_fault(end, end));
+        }
+        // the per-instruction mode chains every block anyway
+        if !options.per_instruction && block.len() == max_len {
+            let cut = block.last().filter(|instr| !instr.mnemonic.ends_block());
+            if let Some(end) = cut.map(Instr::next_ip) {
+                block.push(Instr::fallthrough(end));
+                stats.split_blocks += 1;
+            }
+        }
+        if options.peephole {
+            block = peephole::optimize(block, stats);
+        }
+        if options.constant_addresses {
+            block = constprop::fold_addresses(block, stats);
+        }
+        if options.switch_tables {
+            block = switch_table::resolve_tables(block, image, &options.segmentation, stats);
+        }
+        Ok::<_, DecodeError>(block)
+    };
+
+    // the liveness needs the whole CFG, so everything reachable is decoded in advance
+    let (mut decoded, liveness) = if options.flag_liveness {
+        let decoded =
+            liveness::discover_blocks(basic_blocks, |address| decode(address, &mut stats).ok());
+        let liveness = FlagLiveness::analyze(&decoded, &mut stats);
+        (decoded, liveness)
+    } else {
+        (BTreeMap::new(), FlagLiveness::default())
+    };
+
     while !queue.is_empty() {
         let address = queue.pop_front().unwrap();
+        // might have been queued more than once
+        if lifted_functions.contains_key(&address) {
+            continue;
+        }
 
         debug!("processing bb at 0x{:08x}", address);
 
-        let mut builder =
-            LlvmBuilder::new(context, module, types, rt_funs, indirect_bb_call, address);
+        let mut builder = LlvmBuilder::new(
+            context,
+            module,
+            types,
+            rt_funs,
+            options,
+            indirect_bb_call,
+            address,
+        );
+        builder.set_fault_sites(std::mem::take(&mut fault_sites));
 
         lifted_functions.insert(address, builder.get_function());
 
-        // this might be kinda expensive. TODO: how can we recycle decoders? Maybe create one for each region?
-        let mut decoder = Decoder::new(32, image.execute_all_at(address), DecoderOptions::NONE);
-        decoder.set_ip(address as u64);
+        let block = match decoded.remove(&address) {
+            Some(block) => block,
+            None => decode(address, &mut stats).map_err(TranslateFailure::Decode)?,
+        };
+        guest_bytes += block.iter().map(|instr| instr.len as u64).sum::<u64>();
+        stats.longest_block = stats.longest_block.max(block.len());
 
-        loop {
-            // kinda want to assert that we should be able to decode, but some tests without ret's don't work then
-            // TODO: ???
-            // also probably want to raise an error if we jumped to smth undecodable
-            if !decoder.can_decode() {
-                break;
+        let mut coverage_blocks = if options.coverage {
+            coverage::sub_blocks(&block)
+        } else {
+            Vec::new()
+        }
+        .into_iter()
+        .peekable();
+
+        let mut last_lowering = None;
+        for (index, instr) in block.into_iter().enumerate() {
+            let checkpoint = builder.lowering_checkpoint();
+            if let Some(extent) = coverage_blocks.next_if(|extent| extent.index == index) {
+                builder.block_hit(extent.address, extent.size);
+            }
+            if options.instruction_hook {
+                builder.instruction_hook(instr.ip);
+            }
+            builder.set_dead_flags(liveness.dead_flags(address, index));
+            if liveness::reads_all_flags(&instr) {
+                builder.check_shadow_flags();
             }
-            //assert!(decoder.can_decode());
 
-            let instr = decoder.decode();
+            let leaf = inline_leaves
+                .then(|| instr.direct_call_target())
+                .flatten()
+                .and_then(|target| {
+                    let body = leaves.entry(target).or_insert_with(|| {
+                        let block = match decoded.get(&target) {
+                            Some(block) => Some(block.clone()),
+                            // the stats are for what gets translated on its own
+                            None => decode(target, &mut CompilationStats::default()).ok(),
+                        };
+                        block.and_then(|block| inlining::leaf_body(&block).map(<[Instr]>::to_vec))
+                    });
+                    Some((target, body.clone()?))
+                });
 
-            let flow = codegen_instr(&mut builder, instr);
+            let flow = match leaf {
+                Some((target, body)) => {
+                    for (leaf_index, leaf_instr) in body.iter().enumerate() {
+                        let leaf_checkpoint = builder.lowering_checkpoint();
+                        builder.set_dead_flags(liveness.dead_flags(target, leaf_index));
+                        if liveness::reads_all_flags(leaf_instr) {
+                            builder.check_shadow_flags();
+                        }
 
-            builder.handle_flow(instr.next_ip32(), flow.clone());
+                        let flow = codegen_instr(&mut builder, leaf_instr);
 
-            if let Some(addr) = flow.outer_jump_ref() {
+                        builder.handle_flow(leaf_instr.next_ip(), flow);
+                        if VALIDATE_LOWERING {
+                            builder
+                                .check_lowering(leaf_checkpoint, false)
+                                .map_err(|problem| {
+                                    TranslateFailure::Lowering(TranslateError::new(
+                                        leaf_instr, problem,
+                                    ))
+                                })?;
+                        }
+                    }
+                    stats.inlined_calls += 1;
+                    inlined_leaves.entry(target).or_default().insert(address);
+                    // neither the return address nor the `ret`: straight to the instruction after the `call`
+                    ControlFlow::NextInstruction
+                }
+                None => codegen_instr(&mut builder, &instr),
+            };
+
+            builder.handle_flow(instr.next_ip(), flow.clone());
+            if VALIDATE_LOWERING {
+                builder
+                    .check_lowering(checkpoint, false)
+                    .map_err(|problem| {
+                        TranslateFailure::Lowering(TranslateError::new(&instr, problem))
+                    })?;
+            }
+
+            for &addr in flow.outer_jump_ref().iter().chain(flow.switch_targets()) {
                 if !lifted_functions.contains_key(&addr) {
                     queue.push_back(addr);
                 }
             }
-            // kinda meh
-            if instr.op_code().code() == Call_rel32_32 {
-                let target = instr.near_branch32();
+            let call_target = match (instr.mnemonic, instr.operands.as_slice()) {
+                (Mnemonic::Call, [Operand::FarBranch(selector, offset)]) => {
+                    options.segmentation.far_target(*selector, *offset).ok()
+                }
+                _ => instr.direct_call_target(),
+            };
+            // the iretd of a guest interrupt handler resumes after the int, through the dispatcher
+            let interrupt_return = match (instr.mnemonic, instr.operands.as_slice()) {
+                (Mnemonic::Int, [Operand::Immediate8(vector)]) => {
+                    options.interrupt_vectors.handler(*vector)
+                }
+                (Mnemonic::Int3, []) => options.interrupt_vectors.handler(3),
+                _ => None,
+            }
+            .map(|_| instr.next_ip());
+            for target in call_target.into_iter().chain(interrupt_return) {
                 if !lifted_functions.contains_key(&target) {
                     queue.push_back(target);
                 }
             }
 
+            // the `ret` below ends up in the blocks of the last instruction
+            last_lowering = VALIDATE_LOWERING.then(|| (instr.clone(), checkpoint));
             if !flow.can_reach_next_instruction() {
                 break;
             }
+
+            if options.per_instruction {
+                // every instruction gets its own basic block
+                let next = instr.next_ip();
+                builder.jump_to_basic_block(next);
+                if !lifted_functions.contains_key(&next) {
+                    queue.push_back(next);
+                }
+                break;
+            }
         }
 
         let llvm_builder = builder.get_raw_builder();
         llvm_builder.build_return(None);
+        if let Some((instr, checkpoint)) = last_lowering {
+            builder
+                .check_lowering(checkpoint, true)
+                .map_err(|problem| {
+                    TranslateFailure::Lowering(TranslateError::new(&instr, problem))
+                })?;
+        }
+        fault_sites = builder.take_fault_sites();
     }
 
+    debug!("{:?}", stats);
+
     // codegen for indirect_bb_call
     codegen_dynamic_dispatcher(context, module, types, &lifted_functions, indirect_bb_call);
 
-    module_obj
+    Ok(Translation {
+        module: module_obj,
+        fault_sites,
+        blocks: lifted_functions.len(),
+        guest_bytes,
+        stats,
+        inlined_leaves,
+    })
 }
@@ -1,16 +1,35 @@
 use std::ffi::c_void;
 use std::marker::PhantomData;
 
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::intrinsics::Intrinsic;
 use inkwell::module::{Linkage, Module};
-use inkwell::types::{FunctionType, IntType as LlvmIntType, PointerType, StructType, VoidType};
-use inkwell::values::{BasicValue, FunctionValue, IntValue as LlvmIntValue, PointerValue};
-use inkwell::{AddressSpace, IntPredicate};
-
-use crate::backend::{BoolValue, ComparisonType, IntValue};
-use crate::types::{CpuContext, Flag, FullSizeGeneralPurposeRegister, IntType, Register};
+use inkwell::types::{
+    FloatType, FunctionType, IntType as LlvmIntType, PointerType, StructType, VoidType,
+};
+use inkwell::values::{
+    BasicValue, FloatValue, FunctionValue, InstructionOpcode, IntValue as LlvmIntValue,
+    PointerValue,
+};
+use inkwell::{AddressSpace, AtomicOrdering, AtomicRMWBinOp, FloatPredicate, IntPredicate};
+use strum::IntoEnumIterator;
+
+use crate::backend::{
+    BoolValue, Builder as _, ComparisonType, FloatComparisonType, IntValue, RoundingMode,
+};
+use crate::config::TranslationOptions;
+use crate::handler::{GuestFault, InterruptVectorTable};
+use crate::ir::{CodeMode, Instr};
+use crate::liveness::FlagSet;
+use crate::llvm::{FaultSites, MalformedBlock};
+use crate::segmentation::SegmentationPolicy;
+use crate::system_registers::SystemRegisterProfile;
+use crate::types::{
+    CpuContext, Flag, FlagStorage, FpuWord, FullSizeGeneralPurposeRegister, IntType, Register,
+    UndefinedFlagsPolicy, EXIT_FAULT, FLAG_UNDEFINED,
+};
 use crate::ControlFlow;
 
 pub struct LlvmBuilder<'ctx, 'a> {
@@ -28,6 +47,38 @@ pub struct LlvmBuilder<'ctx, 'a> {
     // this is for functions to be implemented by a runtime
     #[allow(unused)]
     rt_funs: &'a RuntimeHelpers<'ctx>,
+    options: &'a TranslationOptions,
+    // address of the instruction being lowered, for the faults
+    current_eip: u32,
+    // & of the one after it
+    next_eip: u32,
+    // the stores to those are skipped (see liveness.rs)
+    dead_flags: FlagSet,
+    fault_sites: FaultSites,
+}
+
+/// See `LlvmBuilder::lowering_checkpoint`
+#[derive(Debug, Clone, Copy)]
+pub struct LoweringCheckpoint<'ctx> {
+    block: BasicBlock<'ctx>,
+    block_count: usize,
+}
+
+fn is_terminator(opcode: InstructionOpcode) -> bool {
+    use InstructionOpcode::*;
+    matches!(
+        opcode,
+        Return
+            | Br
+            | Switch
+            | IndirectBr
+            | Invoke
+            | Unreachable
+            | Resume
+            | CleanupRet
+            | CatchRet
+            | CatchSwitch
+    )
 }
 
 #[derive(Clone, Copy)]
@@ -39,6 +90,8 @@ pub struct Types<'ctx> {
     pub i16: LlvmIntType<'ctx>,
     pub i32: LlvmIntType<'ctx>,
     pub i64: LlvmIntType<'ctx>,
+    pub f32: FloatType<'ctx>,
+    pub f64: FloatType<'ctx>,
     #[allow(unused)]
     pub ctx: StructType<'ctx>,
     #[allow(unused)]
@@ -46,6 +99,20 @@ pub struct Types<'ctx> {
 
     pub bb_fn: FunctionType<'ctx>,            // ctx: Context*, mem: u8*
     pub indirect_bb_call: FunctionType<'ctx>, // ctx: Context*, mem: u8*, eip: u32
+    pub entry_fn: FunctionType<'ctx>,         // ctx: Context*, mem: u8*, eip: u32 -> exit: u32
+
+    // runtime helpers (see RuntimeHelpers)
+    pub interrupt_fn: FunctionType<'ctx>, // ctx: Context*, vector: u8
+    pub port_in_fn: FunctionType<'ctx>,   // ctx: Context*, port: u16, size: u8 -> u32
+    pub port_out_fn: FunctionType<'ctx>,  // ctx: Context*, port: u16, size: u8, value: u32
+    pub instruction_hook_fn: FunctionType<'ctx>, // ctx: Context*
+    pub fast_syscall_fn: FunctionType<'ctx>, // ctx: Context*
+    pub undefined_flag_fn: FunctionType<'ctx>, // ctx: Context*, flag: u8, source: u32
+    pub control_register_write_fn: FunctionType<'ctx>, // ctx: Context*, register: u8, value: u32
+    pub msr_write_fn: FunctionType<'ctx>, // ctx: Context*, index: u32, value: u64
+    pub cache_flush_fn: FunctionType<'ctx>, // ctx: Context*, address: u32
+    pub block_hit_fn: FunctionType<'ctx>, // ctx: Context*, address: u32, size: u32
+    pub stack_soft_limit_fn: FunctionType<'ctx>, // ctx: Context*, esp: u32
 }
 
 impl<'ctx> Types<'ctx> {
@@ -57,12 +124,33 @@ impl<'ctx> Types<'ctx> {
         let i16 = context.i16_type();
         let i32 = context.i32_type();
         let i64 = context.i64_type();
+        let f32 = context.f32_type();
+        let f64 = context.f64_type();
 
         let ctx = context.opaque_struct_type("context");
         ctx.set_body(
             &[
-                i32.array_type(8).into(), // general-purpose registers
-                i8.array_type(8).into(),  // general-purpose registers
+                i32.array_type(8).into(),  // general-purpose registers
+                i8.array_type(8).into(),   // flags
+                i32.into(),                // eip
+                i32.into(),                // exit
+                i32.into(),                // fault_vector
+                i32.into(),                // fault_address
+                i64.array_type(8).into(),  // fpu_regs
+                i16.into(),                // fpu_control
+                i16.into(),                // fpu_status
+                i16.into(),                // fpu_tag
+                i64.array_type(16).into(), // xmm_regs
+                i32.into(),                // mxcsr
+                i32.array_type(8).into(),  // flag_sources
+                i32.into(),                // fault_site
+                i32.into(),                // interrupt_flag
+                i32.into(),                // fault_error_code
+                i32.into(),                // alignment_check
+                i32.into(),                // eflags
+                i32.into(),                // flag_storage
+                i32.into(),                // shadow_flags
+                i32.into(),                // exit_code
             ],
             false,
         );
@@ -86,6 +174,21 @@ impl<'ctx> Types<'ctx> {
             false,
         );
 
+        let entry_fn = i32.fn_type(&[ctx_ptr.into(), mem_ptr.into(), i32.into()], false);
+
+        let interrupt_fn = void.fn_type(&[ctx_ptr.into(), i8.into()], false);
+        let port_in_fn = i32.fn_type(&[ctx_ptr.into(), i16.into(), i8.into()], false);
+        let instruction_hook_fn = void.fn_type(&[ctx_ptr.into()], false);
+        let port_out_fn = void.fn_type(&[ctx_ptr.into(), i16.into(), i8.into(), i32.into()], false);
+        let fast_syscall_fn = void.fn_type(&[ctx_ptr.into()], false);
+        let undefined_flag_fn = void.fn_type(&[ctx_ptr.into(), i8.into(), i32.into()], false);
+        let control_register_write_fn =
+            void.fn_type(&[ctx_ptr.into(), i8.into(), i32.into()], false);
+        let msr_write_fn = void.fn_type(&[ctx_ptr.into(), i32.into(), i64.into()], false);
+        let cache_flush_fn = void.fn_type(&[ctx_ptr.into(), i32.into()], false);
+        let block_hit_fn = void.fn_type(&[ctx_ptr.into(), i32.into(), i32.into()], false);
+        let stack_soft_limit_fn = void.fn_type(&[ctx_ptr.into(), i32.into()], false);
+
         Self {
             void,
             i1,
@@ -93,11 +196,26 @@ impl<'ctx> Types<'ctx> {
             i16,
             i32,
             i64,
+            f32,
+            f64,
             ctx,
             ctx_ptr,
 
             bb_fn,
             indirect_bb_call: rt_indirect_bb_call,
+            entry_fn,
+
+            interrupt_fn,
+            port_in_fn,
+            port_out_fn,
+            instruction_hook_fn,
+            fast_syscall_fn,
+            undefined_flag_fn,
+            control_register_write_fn,
+            msr_write_fn,
+            cache_flush_fn,
+            block_hit_fn,
+            stack_soft_limit_fn,
         }
     }
 }
@@ -107,7 +225,17 @@ pub struct Intrinsics {
     pub uadd_with_overflow: Intrinsic,
     pub ssub_with_overflow: Intrinsic,
     pub usub_with_overflow: Intrinsic,
+    pub fshl: Intrinsic,
+    pub fshr: Intrinsic,
+    pub cttz: Intrinsic,
+    pub ctlz: Intrinsic,
+    pub bswap: Intrinsic,
     pub trap: Intrinsic,
+    pub sqrt: Intrinsic,
+    pub roundeven: Intrinsic,
+    pub floor: Intrinsic,
+    pub ceil: Intrinsic,
+    pub trunc: Intrinsic,
 }
 
 impl Intrinsics {
@@ -117,7 +245,17 @@ impl Intrinsics {
             uadd_with_overflow: Intrinsic::find("llvm.uadd.with.overflow").unwrap(),
             ssub_with_overflow: Intrinsic::find("llvm.ssub.with.overflow").unwrap(),
             usub_with_overflow: Intrinsic::find("llvm.usub.with.overflow").unwrap(),
+            fshl: Intrinsic::find("llvm.fshl").unwrap(),
+            fshr: Intrinsic::find("llvm.fshr").unwrap(),
+            cttz: Intrinsic::find("llvm.cttz").unwrap(),
+            ctlz: Intrinsic::find("llvm.ctlz").unwrap(),
+            bswap: Intrinsic::find("llvm.bswap").unwrap(),
             trap: Intrinsic::find("llvm.trap").unwrap(),
+            sqrt: Intrinsic::find("llvm.sqrt").unwrap(),
+            roundeven: Intrinsic::find("llvm.roundeven").unwrap(),
+            floor: Intrinsic::find("llvm.floor").unwrap(),
+            ceil: Intrinsic::find("llvm.ceil").unwrap(),
+            trunc: Intrinsic::find("llvm.trunc").unwrap(),
         }
     }
 }
@@ -142,9 +280,24 @@ impl<'ctx> RuntimeHelpers<'ctx> {
     }
 }
 
+// names of the functions the runtime should provide (they use the C calling convention)
+pub const INTERRUPT_HELPER: &str = "rusty_x86_interrupt";
+pub const PORT_IN_HELPER: &str = "rusty_x86_port_in";
+pub const PORT_OUT_HELPER: &str = "rusty_x86_port_out";
+pub const INSTRUCTION_HOOK_HELPER: &str = "rusty_x86_instruction_hook";
+pub const FAST_SYSCALL_HELPER: &str = "rusty_x86_fast_syscall";
+pub const UNDEFINED_FLAG_HELPER: &str = "rusty_x86_undefined_flag";
+pub const CONTROL_REGISTER_WRITE_HELPER: &str = "rusty_x86_control_register_write";
+pub const MSR_WRITE_HELPER: &str = "rusty_x86_msr_write";
+pub const CACHE_FLUSH_HELPER: &str = "rusty_x86_cache_flush";
+pub const BLOCK_HIT_HELPER: &str = "rusty_x86_block_hit";
+pub const STACK_SOFT_LIMIT_HELPER: &str = "rusty_x86_stack_soft_limit";
+
 pub const FASTCC_CALLING_CONVENTION: u32 = 8;
 
 pub type BbFunc = unsafe extern "C" fn(*mut CpuContext, *mut u8) -> c_void;
+/// See `crate::llvm::add_entry_trampoline`
+pub type EntryFunc = unsafe extern "C" fn(*mut CpuContext, *mut u8, u32) -> u32;
 
 impl<'ctx, 'a> LlvmBuilder<'ctx, 'a> {
     pub fn new(
@@ -152,6 +305,7 @@ impl<'ctx, 'a> LlvmBuilder<'ctx, 'a> {
         module: &'a Module<'ctx>,
         types: &'a Types<'ctx>,
         rt_funs: &'a RuntimeHelpers<'ctx>,
+        options: &'a TranslationOptions,
         indirect_bb_call: FunctionValue<'ctx>,
         basic_block_addr: u32,
     ) -> Self {
@@ -178,9 +332,82 @@ impl<'ctx, 'a> LlvmBuilder<'ctx, 'a> {
 
             indirect_bb_call,
             rt_funs,
+            options,
+            current_eip: basic_block_addr,
+            next_eip: basic_block_addr,
+            dead_flags: FlagSet::empty(),
+            fault_sites: FaultSites::default(),
+        }
+    }
+
+    /// Where the blocks the next lowering touches start, for `check_lowering`
+    pub fn lowering_checkpoint(&self) -> LoweringCheckpoint<'ctx> {
+        LoweringCheckpoint {
+            block: self.builder.get_insert_block().unwrap(),
+            block_count: self.function.count_basic_blocks() as usize,
         }
     }
 
+    /// Checks the blocks touched since the checkpoint: the one the lowering started in & all the ones it appended
+    ///
+    /// Every one of them has to end with exactly one terminator, except for the one the builder is left in, which has
+    /// none yet: the next instruction goes there (or the `ret` that ends the function, after which `finished` is set
+    /// and it's checked like the others). A lowering that terminates a block on its own without moving to a new
+    /// one makes the next terminator land after it, which LLVM only reports as "Terminator found in the middle of a
+    /// basic block" somewhere in the module
+    pub fn check_lowering(
+        &self,
+        checkpoint: LoweringCheckpoint<'ctx>,
+        finished: bool,
+    ) -> Result<(), MalformedBlock> {
+        let blocks = self.function.get_basic_blocks();
+        let current = self.builder.get_insert_block().unwrap();
+
+        let touched = std::iter::once(checkpoint.block).chain(
+            blocks[checkpoint.block_count..]
+                .iter()
+                .copied()
+                .filter(|&block| block != checkpoint.block),
+        );
+        for block in touched {
+            let index = blocks.iter().position(|&b| b == block).unwrap();
+            let name = block.get_name().to_string_lossy().into_owned();
+
+            let mut terminated = false;
+            let mut instruction = block.get_first_instruction();
+            while let Some(value) = instruction {
+                if terminated {
+                    return Err(MalformedBlock::AfterTerminator { index, name });
+                }
+                terminated = is_terminator(value.get_opcode());
+                instruction = value.get_next_instruction();
+            }
+
+            let open = block == current && !finished;
+            if !terminated && !open {
+                return Err(MalformedBlock::Unterminated { index, name });
+            }
+            if terminated && open {
+                return Err(MalformedBlock::LeftTerminated { index, name });
+            }
+        }
+        Ok(())
+    }
+
+    /// Flags nobody reads after the next instruction, so it doesn't need to store them
+    pub fn set_dead_flags(&mut self, flags: FlagSet) {
+        self.dead_flags = flags;
+    }
+
+    /// The sites are numbered across the whole module, so the table is passed from one block to the next
+    pub fn set_fault_sites(&mut self, sites: FaultSites) {
+        self.fault_sites = sites;
+    }
+
+    pub fn take_fault_sites(&mut self) -> FaultSites {
+        std::mem::take(&mut self.fault_sites)
+    }
+
     pub fn get_raw_builder(&self) -> &Builder<'ctx> {
         &self.builder
     }
@@ -236,26 +463,533 @@ impl<'ctx, 'a> LlvmBuilder<'ctx, 'a> {
         r
     }
 
+    fn build_ctx_field_gep(&mut self, field: u64, name: &str) -> PointerValue<'ctx> {
+        let i32_type = self.context.i32_type();
+        // SAFETY: ¯\_(ツ)_/¯
+        unsafe {
+            self.builder.build_gep(
+                self.ctx_ptr,
+                &[
+                    i32_type.const_zero(),            // deref the pointer itself
+                    i32_type.const_int(field, false), // then select the field
+                ],
+                name,
+            )
+        }
+    }
+
+    fn build_ctx_eip_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(2, "eip_ptr")
+    }
+
+    fn build_ctx_exit_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(3, "exit_ptr")
+    }
+
+    fn build_ctx_fault_vector_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(4, "fault_vector_ptr")
+    }
+
+    fn build_ctx_fault_address_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(5, "fault_address_ptr")
+    }
+
+    fn build_ctx_fpu_reg_gep(&mut self, index: LlvmIntValue<'ctx>) -> PointerValue<'ctx> {
+        let i32_type = self.context.i32_type();
+        // SAFETY: ¯\_(ツ)_/¯
+        let r = unsafe {
+            self.builder.build_gep(
+                self.ctx_ptr,
+                &[
+                    i32_type.const_zero(),        // deref the pointer itself
+                    i32_type.const_int(6, false), // select the fpu_regs array
+                    index, // then select the register (known only at runtime)
+                ],
+                "fpu_reg_ptr",
+            )
+        };
+        debug_assert_eq!(
+            r.get_type().get_element_type().into_int_type(),
+            self.types.i64
+        );
+        r
+    }
+
+    fn build_ctx_fpu_word_gep(&mut self, word: FpuWord) -> PointerValue<'ctx> {
+        let (field, name) = match word {
+            FpuWord::Control => (7, "fpu_control_ptr"),
+            FpuWord::Status => (8, "fpu_status_ptr"),
+            FpuWord::Tag => (9, "fpu_tag_ptr"),
+        };
+        self.build_ctx_field_gep(field, name)
+    }
+
+    fn build_ctx_xmm_gep(&mut self, index: u8, high: bool) -> PointerValue<'ctx> {
+        let i32_type = self.context.i32_type();
+        let element = 2 * index as u64 + high as u64;
+        // SAFETY: ¯\_(ツ)_/¯
+        unsafe {
+            self.builder.build_gep(
+                self.ctx_ptr,
+                &[
+                    i32_type.const_zero(),              // deref the pointer itself
+                    i32_type.const_int(10, false),      // select the xmm_regs array
+                    i32_type.const_int(element, false), // then select the half
+                ],
+                &*format!("xmm{}_{}_ptr", index, if high { "hi" } else { "lo" }),
+            )
+        }
+    }
+
+    fn build_ctx_mxcsr_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(11, "mxcsr_ptr")
+    }
+
+    fn build_ctx_fault_site_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(13, "fault_site_ptr")
+    }
+
+    fn build_ctx_interrupt_flag_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(14, "interrupt_flag_ptr")
+    }
+
+    fn build_ctx_fault_error_code_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(15, "fault_error_code_ptr")
+    }
+
+    fn build_ctx_alignment_check_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(16, "alignment_check_ptr")
+    }
+
+    fn build_ctx_eflags_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(17, "eflags_ptr")
+    }
+
+    fn build_ctx_shadow_flags_gep(&mut self) -> PointerValue<'ctx> {
+        self.build_ctx_field_gep(19, "shadow_flags_ptr")
+    }
+
+    fn build_ctx_flag_source_gep(&mut self, flag: Flag) -> PointerValue<'ctx> {
+        let i32_type = self.context.i32_type();
+        // SAFETY: ¯\_(ツ)_/¯
+        unsafe {
+            self.builder.build_gep(
+                self.ctx_ptr,
+                &[
+                    i32_type.const_zero(),                  // deref the pointer itself
+                    i32_type.const_int(12, false),          // select the flag_sources array
+                    i32_type.const_int(flag as u64, false), // then select the flag
+                ],
+                &*format!("{:?}_source_ptr", flag),
+            )
+        }
+    }
+
+    fn float_type(&self, ty: IntType) -> FloatType<'ctx> {
+        match ty {
+            IntType::I32 => self.types.f32,
+            IntType::I64 => self.types.f64,
+            ty => panic!("No floating point type of size {:?}", ty),
+        }
+    }
+
+    fn bits_to_float(&mut self, val: LlvmIntValue<'ctx>) -> FloatValue<'ctx> {
+        let ty = self.float_type(val.size());
+        self.builder.build_bitcast(val, ty, "").into_float_value()
+    }
+
+    fn float_to_bits(&mut self, val: FloatValue<'ctx>) -> LlvmIntValue<'ctx> {
+        let ty = if val.get_type() == self.types.f32 {
+            self.types.i32
+        } else {
+            self.types.i64
+        };
+        self.builder.build_bitcast(val, ty, "").into_int_value()
+    }
+
+    fn call_unary_float_intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        val: LlvmIntValue<'ctx>,
+    ) -> LlvmIntValue<'ctx> {
+        let val = self.bits_to_float(val);
+        let fun = intrinsic
+            .get_declaration(self.module, &[val.get_type().into()])
+            .unwrap();
+        let res = self
+            .builder
+            .build_call(fun, &[val.into()], "")
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_float_value();
+        self.float_to_bits(res)
+    }
+
+    /// If someone asked us to exit (by setting CpuContext::exit) - return to the caller
+    /// Used after anything that can give control to the host
+    fn build_exit_check(&mut self) {
+        let exit_ptr = self.build_ctx_exit_gep();
+        let exit = self.builder.build_load(exit_ptr, "exit").into_int_value();
+        let should_exit = self.builder.build_int_compare(
+            IntPredicate::NE,
+            exit,
+            self.types.i32.const_zero(),
+            "should_exit",
+        );
+
+        let exit_bb = self.context.append_basic_block(self.function, "exit");
+        let cont_bb = self.context.append_basic_block(self.function, "");
+
+        self.builder
+            .build_conditional_branch(should_exit, exit_bb, cont_bb);
+
+        self.builder.position_at_end(exit_bb);
+        self.builder.build_return(None);
+
+        self.builder.position_at_end(cont_bb);
+    }
+
+    fn get_runtime_helper(&mut self, name: &str, ty: FunctionType<'ctx>) -> FunctionValue<'ctx> {
+        if let Some(fun) = self.module.get_function(name) {
+            fun
+        } else {
+            self.module.add_function(name, ty, Some(Linkage::External))
+        }
+    }
+
     fn int_type(&self, ty: IntType) -> LlvmIntType<'ctx> {
         match ty {
             IntType::I8 => self.types.i8,
             IntType::I16 => self.types.i16,
             IntType::I32 => self.types.i32,
             IntType::I64 => self.types.i64,
+            IntType::I128 => self.context.i128_type(),
         }
     }
 
-    fn get_host_pointer(&mut self, target_ptr: LlvmIntValue<'ctx>) -> PointerValue<'ctx> {
+    fn get_host_pointer(
+        &mut self,
+        target_ptr: LlvmIntValue<'ctx>,
+        size: IntType,
+    ) -> PointerValue<'ctx> {
+        if self.options.fault_sites {
+            let site = self.fault_sites.add(self.current_eip);
+            let site_ptr = self.build_ctx_fault_site_gep();
+            self.builder.build_store(site_ptr, self.make_u32(site));
+        }
+
         let target_ptr_ext = self
             .builder
             .build_int_z_extend(target_ptr, self.types.i64, "");
 
+        let in_bounds = |limit: u64| {
+            self.constant_address(target_ptr)
+                .is_some_and(|address| address as u64 + size.byte_width() as u64 <= limit)
+        };
+        if let Some(limit) = self.options.memory_limit.filter(|&limit| !in_bounds(limit)) {
+            // the end of the access should be within the limit (computing in 64 bits, so no overflow)
+            let end = self.builder.build_int_add(
+                target_ptr_ext,
+                self.types.i64.const_int(size.byte_width() as u64, false),
+                "access_end",
+            );
+            let out_of_bounds = self.builder.build_int_compare(
+                IntPredicate::UGT,
+                end,
+                self.types.i64.const_int(limit, false),
+                "out_of_bounds",
+            );
+
+            let trap_bb = self
+                .context
+                .append_basic_block(self.function, "out_of_bounds");
+            let cont_bb = self.context.append_basic_block(self.function, "");
+            self.builder
+                .build_conditional_branch(out_of_bounds, trap_bb, cont_bb);
+
+            self.builder.position_at_end(trap_bb);
+            crate::backend::Builder::trap(self);
+            self.builder.build_unreachable();
+
+            self.builder.position_at_end(cont_bb);
+        }
+
         unsafe {
             self.builder
                 .build_gep(self.mem_ptr, &[target_ptr_ext], "hptr")
         }
     }
 
+    /// With `constant_addresses`, the value of an address known at the translation time (see constprop.rs), so that
+    /// the checks it's known to pass can be left out
+    fn constant_address(&self, address: LlvmIntValue<'ctx>) -> Option<u32> {
+        if !self.options.constant_addresses {
+            return None;
+        }
+        address
+            .get_zero_extended_constant()
+            .map(|address| address as u32)
+    }
+
+    /// With `alignment_checks`: raises #AC if EFLAGS.AC is set & the address is not a multiple of the access size
+    fn build_alignment_check(&mut self, address: LlvmIntValue<'ctx>, size: IntType) {
+        if !self.options.alignment_checks
+            || !matches!(size, IntType::I16 | IntType::I32 | IntType::I64)
+        {
+            return;
+        }
+        if self
+            .constant_address(address)
+            .is_some_and(|address| address.is_multiple_of(size.byte_width() as u32))
+        {
+            return;
+        }
+
+        let enabled = self.load_alignment_check_flag();
+        let low_bits = self.int_and(address, self.make_u32(size.byte_width() as u32 - 1));
+        let misaligned = self.icmp(ComparisonType::NotEqual, low_bits, self.make_u32(0));
+        let fault = self.bool_and(enabled, misaligned);
+        self.ifelse(
+            fault,
+            |builder| builder.raise_fault(GuestFault::AlignmentCheck, address),
+            |_| {},
+        );
+    }
+
+    /// What `load_flag` reads, without the shadow check
+    fn load_flag_value(&mut self, flag: Flag) -> LlvmIntValue<'ctx> {
+        match flag {
+            Flag::Carry => {}
+            Flag::Parity => {}
+            Flag::AuxiliaryCarry => unimplemented!(),
+            Flag::Zero => {}
+            Flag::Sign => {}
+            Flag::Overflow => {}
+            Flag::Direction => {}
+            Flag::Id => {}
+        };
+
+        if self.options.flag_storage == FlagStorage::Packed {
+            let eflags = self.load_packed_flags();
+            return self.extract_bit(eflags, self.make_u32(flag.eflags_bit()));
+        }
+
+        let ptr = self.build_ctx_flag_gep(self.ctx_ptr, flag);
+        let i8_val = self.builder.build_load(ptr, "").into_int_value();
+
+        if self.options.undefined_flags == UndefinedFlagsPolicy::Strict {
+            let poisoned = self.builder.build_int_compare(
+                IntPredicate::EQ,
+                i8_val,
+                self.make_u8(FLAG_UNDEFINED),
+                "poisoned",
+            );
+
+            let report_bb = self
+                .context
+                .append_basic_block(self.function, "undefined_flag");
+            let cont_bb = self.context.append_basic_block(self.function, "");
+
+            self.builder
+                .build_conditional_branch(poisoned, report_bb, cont_bb);
+
+            self.builder.position_at_end(report_bb);
+            let eip_ptr = self.build_ctx_eip_gep();
+            self.builder
+                .build_store(eip_ptr, self.make_u32(self.current_eip));
+            let source_ptr = self.build_ctx_flag_source_gep(flag);
+            let source = self.builder.build_load(source_ptr, "source");
+            let helper =
+                self.get_runtime_helper(UNDEFINED_FLAG_HELPER, self.types.undefined_flag_fn);
+            let args = &[
+                self.ctx_ptr.into(),
+                self.make_u8(flag as u8).into(),
+                source.into(),
+            ];
+            self.builder.build_call(helper, args, "");
+            self.build_exit_check();
+            self.builder.build_unconditional_branch(cont_bb);
+
+            self.builder.position_at_end(cont_bb);
+
+            // a poisoned flag reads as false, like the interpreter does
+            let one = self.make_u8(1);
+            return self.builder.build_int_compare(
+                IntPredicate::EQ,
+                i8_val,
+                one,
+                &*format!("{:?}", flag),
+            );
+        }
+
+        let zero = self.make_u8(0);
+
+        self.builder
+            .build_int_compare(IntPredicate::NE, i8_val, zero, &*format!("{:?}", flag))
+    }
+
+    /// With `shadow_flags`: the copy of the flag in `CpuContext::shadow_flags` gets the value, even if it's dead
+    fn store_shadow_flag(&mut self, flag: Flag, value: LlvmIntValue<'ctx>) {
+        if !self.options.shadow_flags {
+            return;
+        }
+        let bit = flag.eflags_bit();
+        let ptr = self.build_ctx_shadow_flags_gep();
+        let shadow = self.builder.build_load(ptr, "shadow").into_int_value();
+        let others = self.int_and(shadow, self.make_u32(!(1 << bit)));
+        let value = self.zext(value, IntType::I32);
+        let value = self.shl(value, self.make_u32(bit));
+        let shadow = self.int_or(others, value);
+        self.builder.build_store(ptr, shadow);
+    }
+
+    /// With `shadow_flags`: raises `GuestFault::FlagMismatch` if `value`, the flag as stored, is not what the shadow has
+    fn check_shadow_flag(&mut self, flag: Flag, value: LlvmIntValue<'ctx>) {
+        if !self.options.shadow_flags {
+            return;
+        }
+        let ptr = self.build_ctx_shadow_flags_gep();
+        let shadow = self.builder.build_load(ptr, "shadow").into_int_value();
+        let expected = self.extract_bit(shadow, self.make_u32(flag.eflags_bit()));
+        let mismatch = self.builder.build_int_compare(
+            IntPredicate::NE,
+            value,
+            expected,
+            &*format!("{:?}_mismatch", flag),
+        );
+        let eip = self.current_eip;
+        self.ifelse(
+            mismatch,
+            |builder| {
+                let index = builder.make_u32(flag as u32);
+                builder.raise_fault(GuestFault::FlagMismatch { eip, flag }, index)
+            },
+            |_| {},
+        );
+    }
+
+    /// With `shadow_flags`: checks all the flags against the shadow. For the places that might look at any of them
+    /// (`ret`, the handlers), where nothing `flag_liveness` skipped can be stale anymore.
+    /// A `FLAG_UNDEFINED` counts as clear, like `load_flag` reads it
+    pub fn check_shadow_flags(&mut self) {
+        if !self.options.shadow_flags {
+            return;
+        }
+        for flag in Flag::iter() {
+            let value = match self.options.flag_storage {
+                FlagStorage::Packed => {
+                    let eflags = self.load_packed_flags();
+                    self.extract_bit(eflags, self.make_u32(flag.eflags_bit()))
+                }
+                FlagStorage::Bytes => {
+                    let ptr = self.build_ctx_flag_gep(self.ctx_ptr, flag);
+                    let byte = self.builder.build_load(ptr, "").into_int_value();
+                    self.builder.build_int_compare(
+                        IntPredicate::EQ,
+                        byte,
+                        self.make_u8(1),
+                        &*format!("{:?}", flag),
+                    )
+                }
+            };
+            self.check_shadow_flag(flag, value);
+        }
+    }
+
+    /// With `address_wraparound`, branches off the accesses that cross the top of the address space.
+    /// Returns the block for them & the one to continue in, leaving the builder in the block for the usual accesses
+    fn build_wraparound_check(
+        &mut self,
+        address: LlvmIntValue<'ctx>,
+        size: IntType,
+    ) -> Option<(BasicBlock<'ctx>, BasicBlock<'ctx>)> {
+        if !self.options.address_wraparound || size == IntType::I8 {
+            return None;
+        }
+
+        let last_start = u32::MAX - (size.byte_width() as u32 - 1);
+        if self
+            .constant_address(address)
+            .is_some_and(|address| address <= last_start)
+        {
+            return None;
+        }
+        let wraps = self.builder.build_int_compare(
+            IntPredicate::UGT,
+            address,
+            self.make_u32(last_start),
+            "wraps_around",
+        );
+
+        let direct_bb = self.context.append_basic_block(self.function, "");
+        let wrapped_bb = self
+            .context
+            .append_basic_block(self.function, "wraps_around");
+        let cont_bb = self.context.append_basic_block(self.function, "");
+        self.builder
+            .build_conditional_branch(wraps, wrapped_bb, direct_bb);
+
+        self.builder.position_at_end(direct_bb);
+        Some((wrapped_bb, cont_bb))
+    }
+
+    fn load_memory_direct(
+        &mut self,
+        size: IntType,
+        address: LlvmIntValue<'ctx>,
+    ) -> LlvmIntValue<'ctx> {
+        let hptr = self.get_host_pointer(address, size);
+        let hptr = self.builder.build_pointer_cast(
+            hptr,
+            self.int_type(size).ptr_type(AddressSpace::Generic),
+            "",
+        );
+
+        let val = self.builder.build_load(hptr, "");
+        val.as_instruction_value()
+            .unwrap()
+            .set_alignment(1)
+            .unwrap();
+        val.into_int_value()
+    }
+
+    /// `atomicrmw xchg`, the host does it with the same `xchg` (that doesn't need the address to be aligned either)
+    fn exchange_memory_direct(
+        &mut self,
+        address: LlvmIntValue<'ctx>,
+        value: LlvmIntValue<'ctx>,
+    ) -> LlvmIntValue<'ctx> {
+        let hptr = self.get_host_pointer(address, value.size());
+        let hptr = self.builder.build_pointer_cast(
+            hptr,
+            value.get_type().ptr_type(AddressSpace::Generic),
+            "",
+        );
+
+        self.builder
+            .build_atomicrmw(
+                AtomicRMWBinOp::Xchg,
+                hptr,
+                value,
+                AtomicOrdering::SequentiallyConsistent,
+            )
+            .unwrap()
+    }
+
+    fn store_memory_direct(&mut self, address: LlvmIntValue<'ctx>, value: LlvmIntValue<'ctx>) {
+        let hptr = self.get_host_pointer(address, value.size());
+        let hptr = self.builder.build_pointer_cast(
+            hptr,
+            value.get_type().ptr_type(AddressSpace::Generic),
+            "",
+        );
+
+        self.builder
+            .build_store(hptr, value)
+            .set_alignment(1)
+            .unwrap();
+    }
+
     // TODO: name map
     pub fn get_name_for(addr: u32) -> String {
         format!("sub_{:08x}", addr)
@@ -297,6 +1031,60 @@ impl<'ctx, 'a> LlvmBuilder<'ctx, 'a> {
         call.set_tail_call(tail_call)
     }
 
+    /// Tail-calls the basic block, either directly or through the dispatcher (if block chaining is disabled)
+    pub fn jump_to_basic_block(&mut self, target: u32) {
+        if self.options.block_chaining {
+            self.call_basic_block(target, true);
+        } else {
+            let target = self.types.i32.const_int(target as u64, false);
+            self.call_basic_block_indirect(target, true);
+        }
+    }
+
+    /// Lets the runtime know that we are about to execute the instruction at `eip`
+    pub fn instruction_hook(&mut self, eip: u32) {
+        let eip_ptr = self.build_ctx_eip_gep();
+        self.builder
+            .build_store(eip_ptr, self.types.i32.const_int(eip as u64, false));
+
+        let helper =
+            self.get_runtime_helper(INSTRUCTION_HOOK_HELPER, self.types.instruction_hook_fn);
+        self.builder.build_call(helper, &[self.ctx_ptr.into()], "");
+        self.build_exit_check();
+    }
+
+    /// Calls the runtime if ESP is below `limit` (see `TranslationOptions::stack_soft_limit`). It's in the middle of
+    /// an instruction (a `push` has moved ESP but not stored anything yet), so there's no stopping there
+    fn check_stack_soft_limit(&mut self, limit: u32) {
+        let esp = self.load_register(Register::ESP);
+        let below = self.icmp(ComparisonType::UnsignedLess, esp, self.make_u32(limit));
+        self.ifelse(
+            below,
+            |builder| {
+                let helper = builder
+                    .get_runtime_helper(STACK_SOFT_LIMIT_HELPER, builder.types.stack_soft_limit_fn);
+                builder
+                    .builder
+                    .build_call(helper, &[builder.ctx_ptr.into(), esp.into()], "");
+            },
+            |_| {},
+        );
+    }
+
+    /// Counts an execution of the guest basic block at `address` (see coverage.rs)
+    pub fn block_hit(&mut self, address: u32, size: u32) {
+        let helper = self.get_runtime_helper(BLOCK_HIT_HELPER, self.types.block_hit_fn);
+        self.builder.build_call(
+            helper,
+            &[
+                self.ctx_ptr.into(),
+                self.types.i32.const_int(address as u64, false).into(),
+                self.types.i32.const_int(size as u64, false).into(),
+            ],
+            "",
+        );
+    }
+
     pub fn handle_flow(&mut self, next_ip: u32, flow: ControlFlow<Self>) {
         match flow {
             ControlFlow::NextInstruction => {
@@ -309,7 +1097,7 @@ impl<'ctx, 'a> LlvmBuilder<'ctx, 'a> {
                 self.builder.position_at_end(next_bb);
             }
             ControlFlow::DirectJump(addr) => {
-                self.call_basic_block(addr, true);
+                self.jump_to_basic_block(addr);
                 // no need for ret; all recompiled funs are terminated with ret
             }
             ControlFlow::IndirectJump(addr) => {
@@ -331,11 +1119,38 @@ impl<'ctx, 'a> LlvmBuilder<'ctx, 'a> {
                     .build_conditional_branch(cond, branch_to, next_bb);
 
                 self.builder.position_at_end(branch_to);
-                self.call_basic_block(target, true);
+                self.jump_to_basic_block(target);
                 self.builder.build_return(None);
 
                 self.builder.position_at_end(next_bb);
             }
+            ControlFlow::Switch(index, targets, fallback) => {
+                let default_bb = self
+                    .context
+                    .append_basic_block(self.function, "switch_default");
+                let cases = targets
+                    .iter()
+                    .enumerate()
+                    .map(|(i, target)| {
+                        let bb = self.context.append_basic_block(
+                            self.function,
+                            format!("case_{}_{:08x}", i, target).as_str(),
+                        );
+                        (self.types.i32.const_int(i as u64, false), bb)
+                    })
+                    .collect::<Vec<_>>();
+
+                self.builder.build_switch(index, default_bb, &cases);
+
+                for ((_, bb), &target) in cases.iter().zip(&targets) {
+                    self.builder.position_at_end(*bb);
+                    self.jump_to_basic_block(target);
+                    self.builder.build_return(None);
+                }
+
+                self.builder.position_at_end(default_bb);
+                self.call_basic_block_indirect(fallback, true);
+            }
         }
     }
 
@@ -361,6 +1176,42 @@ impl<'ctx, 'a> LlvmBuilder<'ctx, 'a> {
             .unwrap()
             .into_int_value();
     }
+
+    /// A funnel shift of the value with itself is a rotate, with the amount taken modulo the width
+    fn call_rotate_intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        val: LlvmIntValue<'ctx>,
+        amount: LlvmIntValue<'ctx>,
+    ) -> LlvmIntValue<'ctx> {
+        let fun = intrinsic
+            .get_declaration(self.module, &[val.get_type().into()])
+            .unwrap();
+
+        self.builder
+            .build_call(fun, &[val.into(), val.into(), amount.into()], "")
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_int_value()
+    }
+
+    /// `llvm.cttz` & `llvm.ctlz`, with zero giving poison: the callers don't count the bits of zero
+    fn call_count_zeros_intrinsic(
+        &mut self,
+        intrinsic: Intrinsic,
+        val: LlvmIntValue<'ctx>,
+    ) -> LlvmIntValue<'ctx> {
+        let fun = intrinsic
+            .get_declaration(self.module, &[val.get_type().into()])
+            .unwrap();
+
+        let is_zero_poison = self.make_true();
+        self.builder
+            .build_call(fun, &[val.into(), is_zero_poison.into()], "")
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_int_value()
+    }
 }
 
 impl IntValue for LlvmIntValue<'_> {
@@ -397,6 +1248,19 @@ impl From<ComparisonType> for IntPredicate {
     }
 }
 
+impl From<FloatComparisonType> for FloatPredicate {
+    fn from(comp: FloatComparisonType) -> Self {
+        use FloatComparisonType::*;
+        use FloatPredicate::*;
+        match comp {
+            Equal => OEQ,
+            Less => OLT,
+            Greater => OGT,
+            Unordered => UNO,
+        }
+    }
+}
+
 impl<'ctx, 'a> crate::backend::Builder for LlvmBuilder<'ctx, 'a> {
     // kinda meh that we alias them, but this way we are fine without any newtype wrappers
 
@@ -440,6 +1304,8 @@ impl<'ctx, 'a> crate::backend::Builder for LlvmBuilder<'ctx, 'a> {
         }
     }
 
+    /// Registers aren't cached in the function: the store goes to the context right where the instruction writes
+    /// the register, so the context is up to date at every exit & fault and the stores come in the program order
     fn store_register(&mut self, register: Register, value: Self::IntValue) {
         assert_eq!(register.size(), IntValue::size(&value));
 
@@ -476,65 +1342,251 @@ impl<'ctx, 'a> crate::backend::Builder for LlvmBuilder<'ctx, 'a> {
 
             self.builder.build_store(base_ptr, res);
         }
+
+        if base == FullSizeGeneralPurposeRegister::ESP {
+            if let Some(limit) = self.options.stack_soft_limit {
+                self.check_stack_soft_limit(limit);
+            }
+        }
     }
 
     fn load_flag(&mut self, flag: Flag) -> Self::BoolValue {
-        match flag {
-            Flag::Carry => {}
-            Flag::Parity => unimplemented!(),
-            Flag::AuxiliaryCarry => unimplemented!(),
-            Flag::Zero => {}
-            Flag::Sign => {}
-            Flag::Overflow => {}
-            Flag::Direction => {}
-            Flag::Id => {}
-        };
+        let value = self.load_flag_value(flag);
+        self.check_shadow_flag(flag, value);
+        value
+    }
 
+    fn store_flag(&mut self, flag: Flag, value: Self::BoolValue) {
+        self.store_shadow_flag(flag, value);
+        if self.dead_flags.contains(flag.into()) {
+            return;
+        }
+        if self.options.flag_storage == FlagStorage::Packed {
+            let bit = flag.eflags_bit();
+            let eflags = self.load_packed_flags();
+            let others = self.int_and(eflags, self.make_u32(!(1 << bit)));
+            let value = self.zext(value, IntType::I32);
+            let value = self.shl(value, self.make_u32(bit));
+            let eflags = self.int_or(others, value);
+            // not `store_packed_flags`, the other shadow flags stay as they are
+            let ptr = self.build_ctx_eflags_gep();
+            self.builder.build_store(ptr, eflags);
+            return;
+        }
         let ptr = self.build_ctx_flag_gep(self.ctx_ptr, flag);
-        let i8_val = self.builder.build_load(ptr, "").into_int_value();
+        let value = self.zext(value, IntType::I8);
+        self.builder.build_store(ptr, value);
+    }
 
-        let zero = self.make_u8(0);
+    fn load_packed_flags(&mut self) -> Self::IntValue {
+        debug_assert_eq!(self.options.flag_storage, FlagStorage::Packed);
+        let ptr = self.build_ctx_eflags_gep();
+        self.builder.build_load(ptr, "eflags").into_int_value()
+    }
 
+    fn store_packed_flags(&mut self, value: Self::IntValue) {
+        debug_assert_eq!(self.options.flag_storage, FlagStorage::Packed);
+        let ptr = self.build_ctx_eflags_gep();
+        self.builder.build_store(ptr, value);
+        if self.options.shadow_flags {
+            let mask = Flag::iter().fold(0, |mask, flag| mask | 1 << flag.eflags_bit());
+            let shadow_ptr = self.build_ctx_shadow_flags_gep();
+            let shadow = self
+                .builder
+                .build_load(shadow_ptr, "shadow")
+                .into_int_value();
+            let others = self.int_and(shadow, self.make_u32(!mask));
+            let flags = self.int_and(value, self.make_u32(mask));
+            let shadow = self.int_or(others, flags);
+            self.builder.build_store(shadow_ptr, shadow);
+        }
+    }
+
+    fn load_interrupt_flag(&mut self) -> Self::BoolValue {
+        let ptr = self.build_ctx_interrupt_flag_gep();
+        let value = self.builder.build_load(ptr, "if").into_int_value();
         self.builder
-            .build_int_compare(IntPredicate::NE, i8_val, zero, &*format!("{:?}", flag))
+            .build_int_compare(IntPredicate::NE, value, self.types.i32.const_zero(), "")
     }
 
-    fn store_flag(&mut self, flag: Flag, value: Self::BoolValue) {
-        let ptr = self.build_ctx_flag_gep(self.ctx_ptr, flag);
-        let value = self.zext(value, IntType::I8);
+    fn store_interrupt_flag(&mut self, value: Self::BoolValue) {
+        let ptr = self.build_ctx_interrupt_flag_gep();
+        let value = self.zext(value, IntType::I32);
+        self.builder.build_store(ptr, value);
+    }
+
+    fn load_alignment_check_flag(&mut self) -> Self::BoolValue {
+        let ptr = self.build_ctx_alignment_check_gep();
+        let value = self.builder.build_load(ptr, "ac").into_int_value();
+        self.builder
+            .build_int_compare(IntPredicate::NE, value, self.types.i32.const_zero(), "")
+    }
+
+    fn store_alignment_check_flag(&mut self, value: Self::BoolValue) {
+        let ptr = self.build_ctx_alignment_check_gep();
+        let value = self.zext(value, IntType::I32);
         self.builder.build_store(ptr, value);
     }
 
     fn load_memory(&mut self, size: IntType, address: Self::IntValue) -> Self::IntValue {
-        let hptr = self.get_host_pointer(address);
-        let hptr = self.builder.build_pointer_cast(
-            hptr,
-            self.int_type(size).ptr_type(AddressSpace::Generic),
-            "",
-        );
+        self.build_alignment_check(address, size);
+        let (wrapped_bb, cont_bb) = match self.build_wraparound_check(address, size) {
+            Some(blocks) => blocks,
+            None => return self.load_memory_direct(size, address),
+        };
 
-        let val = self.builder.build_load(hptr, "");
+        let direct = self.load_memory_direct(size, address);
+        let direct_bb = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(cont_bb);
+
+        self.builder.position_at_end(wrapped_bb);
+        let mut wrapped = self.int_type(size).const_zero();
+        for i in 0..size.byte_width() as u64 {
+            let byte_address = self.add(address, self.make_u32(i as u32));
+            let byte = self.load_memory_direct(IntType::I8, byte_address);
+            let byte = self.zext(byte, size);
+            let byte = self.shl(byte, self.int_type(size).const_int(i * 8, false));
+            wrapped = self.int_or(wrapped, byte);
+        }
+        let wrapped_end_bb = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(cont_bb);
+
+        self.builder.position_at_end(cont_bb);
+        let res = self.builder.build_phi(self.int_type(size), "");
+        res.add_incoming(&[(&direct, direct_bb), (&wrapped, wrapped_end_bb)]);
+        res.as_basic_value().into_int_value()
+    }
+
+    fn probe_memory(&mut self, address: Self::IntValue) {
+        // the result is unused, so a plain load would be gone along with the fault
+        let val = self.load_memory_direct(IntType::I8, address);
         val.as_instruction_value()
             .unwrap()
-            .set_alignment(1)
+            .set_volatile(true)
             .unwrap();
-        val.into_int_value()
     }
 
     fn store_memory(&mut self, address: Self::IntValue, value: Self::IntValue) {
-        let hptr = self.get_host_pointer(address);
+        self.build_alignment_check(address, value.size());
+        let (wrapped_bb, cont_bb) = match self.build_wraparound_check(address, value.size()) {
+            Some(blocks) => blocks,
+            None => return self.store_memory_direct(address, value),
+        };
+
+        self.store_memory_direct(address, value);
+        self.builder.build_unconditional_branch(cont_bb);
+
+        self.builder.position_at_end(wrapped_bb);
+        // all of the bytes are checked before anything is written
+        let byte_ptrs = (0..value.size().byte_width() as u32)
+            .map(|i| {
+                let byte_address = self.add(address, self.make_u32(i));
+                (i, self.get_host_pointer(byte_address, IntType::I8))
+            })
+            .collect::<Vec<_>>();
+        for (i, ptr) in byte_ptrs {
+            let shift = value.get_type().const_int(i as u64 * 8, false);
+            let byte = self.lshr(value, shift);
+            let byte = self.trunc(byte, IntType::I8);
+            self.builder.build_store(ptr, byte);
+        }
+        self.builder.build_unconditional_branch(cont_bb);
+
+        self.builder.position_at_end(cont_bb);
+    }
+
+    fn store_memory_nontemporal(&mut self, address: Self::IntValue, value: Self::IntValue) {
+        let hptr = self.get_host_pointer(address, value.size());
         let hptr = self.builder.build_pointer_cast(
             hptr,
             value.get_type().ptr_type(AddressSpace::Generic),
             "",
         );
 
-        self.builder
-            .build_store(hptr, value)
-            .set_alignment(1)
+        let store = self.builder.build_store(hptr, value);
+        store.set_alignment(1).unwrap();
+        // the value of the node is always 1, see the LangRef
+        let node = self
+            .context
+            .metadata_node(&[self.types.i32.const_int(1, false).into()]);
+        store
+            .set_metadata(node, self.context.get_kind_id("nontemporal"))
             .unwrap();
     }
 
+    fn exchange_memory(
+        &mut self,
+        address: Self::IntValue,
+        value: Self::IntValue,
+    ) -> Self::IntValue {
+        self.build_alignment_check(address, value.size());
+        let (wrapped_bb, cont_bb) = match self.build_wraparound_check(address, value.size()) {
+            Some(blocks) => blocks,
+            None => return self.exchange_memory_direct(address, value),
+        };
+
+        let direct = self.exchange_memory_direct(address, value);
+        let direct_bb = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(cont_bb);
+
+        // the bytes at both ends of the address space are not one access anyway, the slow paths of a load & a store
+        // are as good as it gets
+        self.builder.position_at_end(wrapped_bb);
+        let wrapped = self.load_memory(value.size(), address);
+        self.store_memory(address, value);
+        let wrapped_end_bb = self.builder.get_insert_block().unwrap();
+        self.builder.build_unconditional_branch(cont_bb);
+
+        self.builder.position_at_end(cont_bb);
+        let res = self.builder.build_phi(value.get_type(), "");
+        res.add_incoming(&[(&direct, direct_bb), (&wrapped, wrapped_end_bb)]);
+        res.as_basic_value().into_int_value()
+    }
+
+    fn load_fpu_register(&mut self, index: Self::IntValue) -> Self::IntValue {
+        let ptr = self.build_ctx_fpu_reg_gep(index);
+        self.builder.build_load(ptr, "").into_int_value()
+    }
+
+    fn store_fpu_register(&mut self, index: Self::IntValue, value: Self::IntValue) {
+        assert_eq!(value.size(), IntType::I64);
+        let ptr = self.build_ctx_fpu_reg_gep(index);
+        self.builder.build_store(ptr, value);
+    }
+
+    fn load_fpu_word(&mut self, word: FpuWord) -> Self::IntValue {
+        let ptr = self.build_ctx_fpu_word_gep(word);
+        self.builder.build_load(ptr, "").into_int_value()
+    }
+
+    fn store_fpu_word(&mut self, word: FpuWord, value: Self::IntValue) {
+        assert_eq!(value.size(), IntType::I16);
+        let ptr = self.build_ctx_fpu_word_gep(word);
+        self.builder.build_store(ptr, value);
+    }
+
+    fn load_xmm_half(&mut self, index: u8, high: bool) -> Self::IntValue {
+        let ptr = self.build_ctx_xmm_gep(index, high);
+        self.builder.build_load(ptr, "").into_int_value()
+    }
+
+    fn store_xmm_half(&mut self, index: u8, high: bool, value: Self::IntValue) {
+        assert_eq!(value.size(), IntType::I64);
+        let ptr = self.build_ctx_xmm_gep(index, high);
+        self.builder.build_store(ptr, value);
+    }
+
+    fn load_mxcsr(&mut self) -> Self::IntValue {
+        let ptr = self.build_ctx_mxcsr_gep();
+        self.builder.build_load(ptr, "").into_int_value()
+    }
+
+    fn store_mxcsr(&mut self, value: Self::IntValue) {
+        assert_eq!(value.size(), IntType::I32);
+        let ptr = self.build_ctx_mxcsr_gep();
+        self.builder.build_store(ptr, value);
+    }
+
     fn add(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
         self.builder.build_int_add(lhs, rhs, "")
     }
@@ -579,6 +1631,36 @@ impl<'ctx, 'a> crate::backend::Builder for LlvmBuilder<'ctx, 'a> {
         self.builder.build_right_shift(lhs, rhs, true, "")
     }
 
+    fn rotl(&mut self, val: Self::IntValue, amount: Self::IntValue) -> Self::IntValue {
+        self.call_rotate_intrinsic(self.intrinsics.fshl, val, amount)
+    }
+
+    fn rotr(&mut self, val: Self::IntValue, amount: Self::IntValue) -> Self::IntValue {
+        self.call_rotate_intrinsic(self.intrinsics.fshr, val, amount)
+    }
+
+    fn cttz(&mut self, val: Self::IntValue) -> Self::IntValue {
+        self.call_count_zeros_intrinsic(self.intrinsics.cttz, val)
+    }
+
+    fn ctlz(&mut self, val: Self::IntValue) -> Self::IntValue {
+        self.call_count_zeros_intrinsic(self.intrinsics.ctlz, val)
+    }
+
+    fn bswap(&mut self, val: Self::IntValue) -> Self::IntValue {
+        let fun = self
+            .intrinsics
+            .bswap
+            .get_declaration(self.module, &[val.get_type().into()])
+            .unwrap();
+
+        self.builder
+            .build_call(fun, &[val.into()], "")
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_int_value()
+    }
+
     fn udiv(&mut self, lhs: Self::IntValue, rhs: Self::IntValue) -> Self::IntValue {
         self.builder.build_int_unsigned_div(lhs, rhs, "")
     }
@@ -624,6 +1706,57 @@ impl<'ctx, 'a> crate::backend::Builder for LlvmBuilder<'ctx, 'a> {
         self.call_binary_overflow_intrinsic(self.intrinsics.ssub_with_overflow, lhs, rhs)
     }
 
+    fn float_sqrt(&mut self, val: Self::IntValue) -> Self::IntValue {
+        self.call_unary_float_intrinsic(self.intrinsics.sqrt, val)
+    }
+
+    fn float_round(&mut self, val: Self::IntValue, mode: RoundingMode) -> Self::IntValue {
+        let intrinsic = match mode {
+            RoundingMode::NearestEven => self.intrinsics.roundeven,
+            RoundingMode::Down => self.intrinsics.floor,
+            RoundingMode::Up => self.intrinsics.ceil,
+            RoundingMode::Zero => self.intrinsics.trunc,
+        };
+        self.call_unary_float_intrinsic(intrinsic, val)
+    }
+
+    fn float_extend(&mut self, val: Self::IntValue) -> Self::IntValue {
+        assert_eq!(val.size(), IntType::I32);
+        let val = self.bits_to_float(val);
+        let res = self.builder.build_float_ext(val, self.types.f64, "");
+        self.float_to_bits(res)
+    }
+
+    fn float_truncate(&mut self, val: Self::IntValue) -> Self::IntValue {
+        assert_eq!(val.size(), IntType::I64);
+        let val = self.bits_to_float(val);
+        let res = self.builder.build_float_trunc(val, self.types.f32, "");
+        self.float_to_bits(res)
+    }
+
+    fn float_to_int(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue {
+        let val = self.bits_to_float(val);
+        self.builder
+            .build_float_to_signed_int(val, self.int_type(to), "")
+    }
+
+    fn int_to_float(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue {
+        let ty = self.float_type(to);
+        let res = self.builder.build_signed_int_to_float(val, ty, "");
+        self.float_to_bits(res)
+    }
+
+    fn fcmp(
+        &mut self,
+        cmp: FloatComparisonType,
+        lhs: Self::IntValue,
+        rhs: Self::IntValue,
+    ) -> Self::BoolValue {
+        let lhs = self.bits_to_float(lhs);
+        let rhs = self.bits_to_float(rhs);
+        self.builder.build_float_compare(cmp.into(), lhs, rhs, "")
+    }
+
     fn zext(&mut self, val: Self::IntValue, to: IntType) -> Self::IntValue {
         self.builder.build_int_z_extend(val, self.int_type(to), "")
     }
@@ -647,11 +1780,18 @@ impl<'ctx, 'a> crate::backend::Builder for LlvmBuilder<'ctx, 'a> {
 
     fn direct_call(&mut self, target: u32, _next_eip: u32) {
         self.call_basic_block(target, false);
+        // the callee might have requested an exit
+        self.build_exit_check();
         // TODO: compare EIP to expected return address
         // else we fail in case the binary mis-uses call or ret
         //todo!()
     }
 
+    fn indirect_call(&mut self, target: Self::IntValue, _next_eip: u32) {
+        self.call_basic_block_indirect(target, false);
+        self.build_exit_check();
+    }
+
     fn select(
         &mut self,
         cond: Self::BoolValue,
@@ -695,6 +1835,201 @@ impl<'ctx, 'a> crate::backend::Builder for LlvmBuilder<'ctx, 'a> {
         self.builder.build_call(trap, &[], "");
     }
 
+    fn begin_instruction(&mut self, instr: &Instr) {
+        self.current_eip = instr.ip;
+        self.next_eip = instr.next_ip();
+    }
+
+    fn instruction_start(&self) -> u32 {
+        self.current_eip
+    }
+
+    fn instruction_end(&self) -> u32 {
+        self.next_eip
+    }
+
+    fn segmentation(&self) -> &SegmentationPolicy {
+        &self.options.segmentation
+    }
+
+    fn code_mode(&self) -> CodeMode {
+        self.options.code_mode
+    }
+
+    fn strict_alignment(&self) -> bool {
+        self.options.strict_alignment
+    }
+
+    fn alignment_checks(&self) -> bool {
+        self.options.alignment_checks
+    }
+
+    fn undefined_flags(&self) -> UndefinedFlagsPolicy {
+        self.options.undefined_flags
+    }
+
+    fn flag_storage(&self) -> FlagStorage {
+        self.options.flag_storage
+    }
+
+    fn interrupt_vectors(&self) -> &InterruptVectorTable {
+        &self.options.interrupt_vectors
+    }
+
+    fn system_registers(&self) -> &SystemRegisterProfile {
+        &self.options.system_registers
+    }
+
+    fn poison_flag(&mut self, flag: Flag) {
+        // reads as clear
+        self.store_shadow_flag(flag, self.make_false());
+        if self.dead_flags.contains(flag.into()) {
+            return;
+        }
+        assert_eq!(
+            self.options.flag_storage,
+            FlagStorage::Bytes,
+            "no room for FLAG_UNDEFINED in the packed flags"
+        );
+        let ptr = self.build_ctx_flag_gep(self.ctx_ptr, flag);
+        self.builder.build_store(ptr, self.make_u8(FLAG_UNDEFINED));
+        let source_ptr = self.build_ctx_flag_source_gep(flag);
+        self.builder
+            .build_store(source_ptr, self.make_u32(self.current_eip));
+    }
+
+    fn raise_fault(&mut self, fault: GuestFault, address: Self::IntValue) {
+        if let GuestFault::Unimplemented { bytes, .. } = fault {
+            // the runtime reads the bytes back from the guest memory
+            let error_code_ptr = self.build_ctx_fault_error_code_gep();
+            self.builder
+                .build_store(error_code_ptr, self.make_u32(bytes.as_slice().len() as u32));
+        }
+        let eip_ptr = self.build_ctx_eip_gep();
+        self.builder
+            .build_store(eip_ptr, self.make_u32(self.current_eip));
+        let vector_ptr = self.build_ctx_fault_vector_gep();
+        self.builder
+            .build_store(vector_ptr, self.make_u32(fault.vector() as u32));
+        let address_ptr = self.build_ctx_fault_address_gep();
+        self.builder.build_store(address_ptr, address);
+        let exit_ptr = self.build_ctx_exit_gep();
+        self.builder
+            .build_store(exit_ptr, self.make_u32(EXIT_FAULT));
+
+        // the callers check the exit after every call, so this unwinds all the way to the host
+        self.builder.build_return(None);
+
+        let dead_bb = self
+            .context
+            .append_basic_block(self.function, "after_fault");
+        self.builder.position_at_end(dead_bb);
+    }
+
+    fn raise_page_fault(&mut self, address: Self::IntValue, error_code: u32) {
+        let error_code_ptr = self.build_ctx_fault_error_code_gep();
+        self.builder
+            .build_store(error_code_ptr, self.make_u32(error_code));
+        self.raise_fault(GuestFault::PageFault, address);
+    }
+
+    fn interrupt(&mut self, vector: u8, next_eip: u32) {
+        // let the handler know where we are
+        let eip_ptr = self.build_ctx_eip_gep();
+        self.builder.build_store(eip_ptr, self.make_u32(next_eip));
+
+        let helper = self.get_runtime_helper(INTERRUPT_HELPER, self.types.interrupt_fn);
+        let args = &[self.ctx_ptr.into(), self.make_u8(vector).into()];
+        self.builder.build_call(helper, args, "");
+        self.build_exit_check();
+    }
+
+    fn fast_syscall(&mut self, next_eip: u32) {
+        let eip_ptr = self.build_ctx_eip_gep();
+        self.builder.build_store(eip_ptr, self.make_u32(next_eip));
+
+        let helper = self.get_runtime_helper(FAST_SYSCALL_HELPER, self.types.fast_syscall_fn);
+        self.builder.build_call(helper, &[self.ctx_ptr.into()], "");
+        self.build_exit_check();
+
+        // the handler might have done a sysexit to somewhere else
+        let eip = self.builder.build_load(eip_ptr, "eip").into_int_value();
+        let redirected = self.builder.build_int_compare(
+            IntPredicate::NE,
+            eip,
+            self.make_u32(next_eip),
+            "redirected",
+        );
+
+        let redirect_bb = self.context.append_basic_block(self.function, "sysexit");
+        let cont_bb = self.context.append_basic_block(self.function, "");
+
+        self.builder
+            .build_conditional_branch(redirected, redirect_bb, cont_bb);
+
+        self.builder.position_at_end(redirect_bb);
+        self.call_basic_block_indirect(eip, true);
+        self.builder.build_return(None);
+
+        self.builder.position_at_end(cont_bb);
+    }
+
+    fn port_in(&mut self, port: Self::IntValue, size: IntType) -> Self::IntValue {
+        let helper = self.get_runtime_helper(PORT_IN_HELPER, self.types.port_in_fn);
+        let size_bytes = self.make_u8(size.byte_width());
+        let args = &[self.ctx_ptr.into(), port.into(), size_bytes.into()];
+        let val = self
+            .builder
+            .build_call(helper, args, "port_in")
+            .try_as_basic_value()
+            .unwrap_left()
+            .into_int_value();
+        self.build_exit_check();
+        self.trunc(val, size)
+    }
+
+    fn port_out(&mut self, port: Self::IntValue, value: Self::IntValue) {
+        let helper = self.get_runtime_helper(PORT_OUT_HELPER, self.types.port_out_fn);
+        let size_bytes = self.make_u8(value.size().byte_width());
+        let value = self.zext(value, IntType::I32);
+        let args = &[
+            self.ctx_ptr.into(),
+            port.into(),
+            size_bytes.into(),
+            value.into(),
+        ];
+        self.builder.build_call(helper, args, "");
+        self.build_exit_check();
+    }
+
+    fn control_register_write(&mut self, register: u8, value: Self::IntValue) {
+        let helper = self.get_runtime_helper(
+            CONTROL_REGISTER_WRITE_HELPER,
+            self.types.control_register_write_fn,
+        );
+        let args = &[
+            self.ctx_ptr.into(),
+            self.make_u8(register).into(),
+            value.into(),
+        ];
+        self.builder.build_call(helper, args, "");
+        self.build_exit_check();
+    }
+
+    fn msr_write(&mut self, index: Self::IntValue, value: Self::IntValue) {
+        let helper = self.get_runtime_helper(MSR_WRITE_HELPER, self.types.msr_write_fn);
+        let args = &[self.ctx_ptr.into(), index.into(), value.into()];
+        self.builder.build_call(helper, args, "");
+        self.build_exit_check();
+    }
+
+    fn cache_flush(&mut self, address: Self::IntValue) {
+        let helper = self.get_runtime_helper(CACHE_FLUSH_HELPER, self.types.cache_flush_fn);
+        let args = &[self.ctx_ptr.into(), address.into()];
+        self.builder.build_call(helper, args, "");
+        self.build_exit_check();
+    }
+
     fn repeat_until<B>(&mut self, body: B)
     where
         B: Fn(&mut Self) -> Self::BoolValue,
@@ -0,0 +1,197 @@
+//! Translated modules saved on disk, so that the next run of the same guest code skips the translation
+//!
+//! The translation is a pure function of the guest code, the entry points & the `TranslationOptions`, so a hash of
+//! those (plus the version of the codegen) names the file. The file holds the LLVM bitcode of the module (with the
+//! entry trampoline) and the fault site table. Anything that doesn't match - a different key, an unknown format,
+//! a module that doesn't parse - is a miss, and the fresh translation replaces the file
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use inkwell::context::Context;
+use inkwell::memory_buffer::MemoryBuffer;
+use inkwell::module::Module;
+use log::{debug, warn};
+
+use crate::config::TranslationOptions;
+use crate::llvm::FaultSites;
+use crate::memory_image::MemoryImage;
+
+/// Bump when the generated code changes for the same input, so that the old files are not used anymore
+pub const CODEGEN_VERSION: u32 = 9;
+
+const MAGIC: &[u8; 8] = b"RX86CACH";
+const HEADER_SIZE: usize = 8 + 4 + 8 + 4;
+
+/// What a cached translation is looked up by
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CacheKey(pub u64);
+
+impl CacheKey {
+    pub fn new(options: &TranslationOptions, image: &MemoryImage, basic_blocks: &[u32]) -> Self {
+        let mut hasher = Fnv1a::new();
+        hasher.write(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher.write(&CODEGEN_VERSION.to_le_bytes());
+        // Debug covers every field, and the Fallback policy as the address of the function, which is the best we can do
+        hasher.write(format!("{:?}", options).as_bytes());
+        for &addr in basic_blocks {
+            hasher.write(&addr.to_le_bytes());
+        }
+        for item in image.iter() {
+            hasher.write(&item.addr.to_le_bytes());
+            hasher.write(&item.protection.bits().to_le_bytes());
+            hasher.write(&(item.data.len() as u64).to_le_bytes());
+            hasher.write(&item.data);
+        }
+        Self(hasher.finish())
+    }
+}
+
+/// FNV-1a: the std hasher is not guaranteed to give the same results across the Rust versions, and the keys outlive
+/// the process
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Self(0xcbf29ce484222325)
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x100000001b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    /// Every miss is a translation
+    pub misses: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+}
+
+/// A directory of cached translations
+pub struct TranslationCache {
+    dir: PathBuf,
+    stats: CacheStats,
+}
+
+impl TranslationCache {
+    /// The directory is created when the first translation is stored
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self {
+            dir: dir.into(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    fn path(&self, key: CacheKey) -> PathBuf {
+        self.dir.join(format!("{:016x}.rx86", key.0))
+    }
+
+    /// The module stored under `key`, parsed into `context`. Counts a miss if there is none (or it's unusable)
+    pub fn load<'ctx>(
+        &mut self,
+        context: &'ctx Context,
+        key: CacheKey,
+    ) -> Option<(Module<'ctx>, FaultSites)> {
+        let res = fs::read(self.path(key))
+            .ok()
+            .and_then(|bytes| Self::parse(context, key, &bytes).map(|res| (res, bytes.len())));
+        match res {
+            Some((res, len)) => {
+                debug!("translation cache hit for {:016x}", key.0);
+                self.stats.hits += 1;
+                self.stats.bytes_read += len as u64;
+                Some(res)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn parse<'ctx>(
+        context: &'ctx Context,
+        key: CacheKey,
+        bytes: &[u8],
+    ) -> Option<(Module<'ctx>, FaultSites)> {
+        if bytes.len() < HEADER_SIZE || &bytes[..8] != MAGIC {
+            return None;
+        }
+        let u32_at = |pos: usize| u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap());
+        if u32_at(8) != CODEGEN_VERSION
+            || u64::from_le_bytes(bytes[12..20].try_into().unwrap()) != key.0
+        {
+            return None;
+        }
+
+        let site_count = u32_at(20) as usize;
+        let bitcode_start = HEADER_SIZE.checked_add(site_count.checked_mul(4)?)?;
+        if bytes.len() < bitcode_start {
+            return None;
+        }
+        let mut fault_sites = FaultSites::default();
+        for i in 0..site_count {
+            fault_sites.add(u32_at(HEADER_SIZE + i * 4));
+        }
+
+        let buffer =
+            MemoryBuffer::create_from_memory_range_copy(&bytes[bitcode_start..], "cached_module");
+        match Module::parse_bitcode_from_buffer(&buffer, context) {
+            Ok(module) => Some((module, fault_sites)),
+            Err(e) => {
+                warn!("ignoring the cached translation {:016x}: {}", key.0, e);
+                None
+            }
+        }
+    }
+
+    /// Saves the module under `key`, replacing whatever was there
+    pub fn store(
+        &mut self,
+        key: CacheKey,
+        module: &Module,
+        fault_sites: &FaultSites,
+    ) -> io::Result<()> {
+        let bitcode = module.write_bitcode_to_memory();
+
+        let mut bytes =
+            Vec::with_capacity(HEADER_SIZE + fault_sites.len() * 4 + bitcode.get_size());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&CODEGEN_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&key.0.to_le_bytes());
+        bytes.extend_from_slice(&(fault_sites.len() as u32).to_le_bytes());
+        for site in 0..fault_sites.len() as u32 {
+            bytes.extend_from_slice(&fault_sites.eip(site).unwrap().to_le_bytes());
+        }
+        bytes.extend_from_slice(bitcode.as_slice());
+
+        fs::create_dir_all(&self.dir)?;
+        // a concurrent reader sees either the old file or the new one, never a half-written one
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, &bytes)?;
+        fs::rename(&tmp, &path)?;
+
+        self.stats.bytes_written += bytes.len() as u64;
+        Ok(())
+    }
+}
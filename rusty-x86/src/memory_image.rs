@@ -118,6 +118,19 @@ impl MemoryImage {
         self.access_all_at(addr, Protection::EXECUTE)
     }
 
+    /// Like `read_all_at`, but only from the regions without the write access (so the bytes are there to stay)
+    pub fn read_only_at(&self, addr: u32) -> &[u8] {
+        self.regions
+            .iter()
+            .find(|item| {
+                item.protection.contains(Protection::READ)
+                    && !item.protection.contains(Protection::WRITE)
+                    && item.addr <= addr
+                    && addr < item.addr + item.data.len() as u32
+            })
+            .map_or(&[], |item| &item.data[(addr - item.addr) as usize..])
+    }
+
     pub fn push(&mut self, value: MemoryImageItem) {
         self.regions.push(value)
     }
@@ -129,6 +142,24 @@ impl MemoryImage {
     pub fn add_zero_region(&mut self, base_addr: u32, prot: Protection, len: u32) {
         self.add_region(base_addr, prot, vec![0; len as usize])
     }
+
+    /// Overwrites `[addr, addr + data.len())` in every region it overlaps, the bytes outside of the regions are
+    /// dropped. Whether any region was touched
+    pub fn patch(&mut self, addr: u32, data: &[u8]) -> bool {
+        let (start, end) = (addr as u64, addr as u64 + data.len() as u64);
+        let mut touched = false;
+        for item in self.regions.iter_mut() {
+            let item_start = item.addr as u64;
+            let item_end = item_start + item.data.len() as u64;
+            let (from, to) = (start.max(item_start), end.min(item_end));
+            if from < to {
+                item.data[(from - item_start) as usize..(to - item_start) as usize]
+                    .copy_from_slice(&data[(from - start) as usize..(to - start) as usize]);
+                touched = true;
+            }
+        }
+        touched
+    }
 }
 
 impl Default for MemoryImage {
@@ -225,4 +256,23 @@ mod tests {
         assert_eq!(*image.read_all_at(16), []);
         assert_eq!(*image.read_all_at(17), []);
     }
+
+    #[test_log::test]
+    fn patch() {
+        let mut image = readonly_image();
+
+        // across the gap: the bytes at 3 & 4 have nowhere to go
+        assert!(image.patch(2, &[0x13, 0x14, 0x15, 0x16]));
+        assert_eq!(*image.read_all_at(0), [1, 2, 0x13]);
+        assert_eq!(*image.read_all_at(5), [0x16, 6, 7]);
+        // the end of one region & the start of the next one
+        assert!(image.patch(7, &[0x18, 0x19]));
+        assert_eq!(*image.read_all_at(5), [0x16, 6, 0x18]);
+        assert_eq!(*image.read_all_at(8), [0x19]);
+
+        assert!(!image.patch(3, &[0, 0]));
+        assert!(!image.patch(9, &[0]));
+        assert!(!image.patch(0xffff_ffff, &[0]));
+        assert_eq!(*image.read_all_at(0), [1, 2, 0x13]);
+    }
 }
@@ -0,0 +1,669 @@
+//! Rewrites common idioms in a decoded basic block into cheaper internal instructions
+//!
+//! - `xor r, r` / `sub r, r` => `ZeroReg` (no need to actually compute anything, the flags are known)
+//! - `jcc` after a `ZeroReg`, with nothing touching the flags in between => `jmp` if the condition holds for
+//!   CF = OF = SF = 0, ZF = PF = 1 (the rest of the block is unreachable & dropped), `nop` otherwise
+//! - `test r, r; je/jne` => `TestJcc` (branch on the compare directly instead of going through ZF)
+//! - `cmp a, b; jcc` / `cmp a, b; cmovcc` => `CmpJcc` / `CmpCmovcc` (an `icmp` of `a` & `b` instead of reading
+//!   the flags back; any condition but the parity ones, as `cmp` doesn't compute PF outside of `cmp r, r`)
+//! - `setcc r8; movzx r32, r8` => `SetccZx` (`r8` a byte of `r32`, so the `movzx` overwrites it: the condition
+//!   goes straight into `r32`, without a partial write of the byte first)
+//! - `push ebp; mov ebp, esp` => `Prologue`
+//! - `lea r, [r + disp]` => `AddNoFlags`
+//!
+//! `mov r, 0` is left alone: unlike `xor` it doesn't touch the flags, so it's already as cheap as it gets.
+//!
+//! The flags must end up exactly the same as without the rewrite
+
+use crate::ir::{Condition, Instr, Mnemonic};
+use crate::types::{IntType, MemoryOperand, Operand, Register};
+
+/// Counters of what the translation did
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CompilationStats {
+    /// Instructions seen by the peephole pass
+    pub instructions: usize,
+    pub zero_idioms: usize,
+    pub test_branches: usize,
+    /// `cmp` fused with the `jcc` or `cmovcc` after it
+    pub fused_compares: usize,
+    /// `setcc` fused with the `movzx` of its result
+    pub fused_setccs: usize,
+    pub prologues: usize,
+    pub lea_adds: usize,
+    /// `jcc` with the outcome known from a zero idiom before it
+    pub known_branches: usize,
+    /// Flags written by some instruction & never read afterwards (see liveness.rs)
+    pub dead_flag_stores: usize,
+    /// Memory operands turned into `[disp32]` (see constprop.rs)
+    pub constant_addresses: usize,
+    /// Jumps through a switch table turned into `JmpTable` (see switch_table.rs)
+    pub switch_tables: usize,
+    /// Calls replaced with the body of the leaf function they call (see inlining.rs)
+    pub inlined_calls: usize,
+    /// Blocks cut at `TranslationOptions::max_block_instructions`
+    pub split_blocks: usize,
+    /// Instructions in the longest block lowered
+    pub longest_block: usize,
+}
+
+impl CompilationStats {
+    pub fn peephole_rewrites(&self) -> usize {
+        self.zero_idioms
+            + self.test_branches
+            + self.fused_compares
+            + self.fused_setccs
+            + self.prologues
+            + self.lea_adds
+            + self.known_branches
+    }
+}
+
+fn fuse(first: &Instr, second: &Instr, mnemonic: Mnemonic, operands: Vec<Operand>) -> Instr {
+    debug_assert_eq!(first.next_ip(), second.ip);
+    Instr::new(first.ip, first.len + second.len, mnemonic, operands)
+}
+
+fn rewrite_pair(first: &Instr, second: &Instr, stats: &mut CompilationStats) -> Option<Instr> {
+    use Mnemonic::*;
+
+    match (
+        first.mnemonic,
+        first.operands.as_slice(),
+        second.mnemonic,
+        second.operands.as_slice(),
+    ) {
+        (
+            Test,
+            [Operand::Register(a), Operand::Register(b)],
+            Jcc(cond @ (Condition::E | Condition::NE)),
+            [target @ Operand::Immediate32(_)],
+        ) if a == b && first.prefixes.is_empty() => {
+            stats.test_branches += 1;
+            Some(fuse(
+                first,
+                second,
+                TestJcc(cond),
+                vec![Operand::Register(*a), *target],
+            ))
+        }
+        (Cmp, [lhs, rhs], Jcc(cond), [target @ Operand::Immediate32(_)])
+            if first.prefixes.is_empty() && !matches!(cond, Condition::P | Condition::NP) =>
+        {
+            stats.fused_compares += 1;
+            Some(fuse(first, second, CmpJcc(cond), vec![*lhs, *rhs, *target]))
+        }
+        // only a register source: a memory one could fault, and the fault would be reported at the `cmp`
+        (Cmp, [lhs, rhs], Cmovcc(cond), [dst, src @ Operand::Register(_)])
+            if first.prefixes.is_empty()
+                && second.prefixes.is_empty()
+                && !matches!(cond, Condition::P | Condition::NP) =>
+        {
+            stats.fused_compares += 1;
+            Some(fuse(
+                first,
+                second,
+                CmpCmovcc(cond),
+                vec![*lhs, *rhs, *dst, *src],
+            ))
+        }
+        (
+            Setcc(cond),
+            [Operand::Register(byte)],
+            Movzx,
+            [dst @ Operand::Register(full), Operand::Register(src)],
+        ) if byte == src
+            && full.size() == IntType::I32
+            && full.base_register() == byte.base_register()
+            && first.prefixes.is_empty()
+            && second.prefixes.is_empty() =>
+        {
+            stats.fused_setccs += 1;
+            Some(fuse(first, second, SetccZx(cond), vec![*dst]))
+        }
+        (
+            Push,
+            [Operand::Register(Register::EBP)],
+            Mov,
+            [Operand::Register(Register::EBP), Operand::Register(Register::ESP)],
+        ) => {
+            stats.prologues += 1;
+            Some(fuse(first, second, Prologue, vec![]))
+        }
+        _ => None,
+    }
+}
+
+fn rewrite_single(instr: &Instr, stats: &mut CompilationStats) -> Option<Instr> {
+    use Mnemonic::*;
+
+    if !instr.prefixes.is_empty() {
+        return None;
+    }
+
+    let rewritten = |mnemonic, operands| Instr::new(instr.ip, instr.len, mnemonic, operands);
+
+    match (instr.mnemonic, instr.operands.as_slice()) {
+        (Xor | Sub, [Operand::Register(a), Operand::Register(b)]) if a == b => {
+            stats.zero_idioms += 1;
+            Some(rewritten(ZeroReg, vec![Operand::Register(*a)]))
+        }
+        (
+            Lea,
+            [Operand::Register(dst), Operand::Memory(MemoryOperand {
+                base: Some(base),
+                index: None,
+                displacement,
+                segment: None,
+                ..
+            })],
+        ) if dst == base && dst.size() == IntType::I32 => {
+            stats.lea_adds += 1;
+            Some(rewritten(
+                AddNoFlags,
+                vec![
+                    Operand::Register(*dst),
+                    Operand::Immediate32(*displacement as u32),
+                ],
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Instructions the flags of a `ZeroReg` survive
+fn keeps_flags(instr: &Instr) -> bool {
+    use Mnemonic::*;
+
+    instr.prefixes.is_empty()
+        && matches!(
+            instr.mnemonic,
+            Nop | Mov | Movzx | Movsx | Lea | Push | Pop | Not | Prologue | AddNoFlags
+        )
+}
+
+/// A `jcc` where the flags are those of `ZeroReg`
+fn fold_branch(instr: &Instr, stats: &mut CompilationStats) -> Option<Instr> {
+    match (instr.mnemonic, instr.operands.as_slice()) {
+        (Mnemonic::Jcc(cond), [target @ Operand::Immediate32(_)]) if instr.prefixes.is_empty() => {
+            let taken = cond.evaluate(false, true, false, false, true);
+            stats.known_branches += 1;
+            Some(if taken {
+                Instr::new(instr.ip, instr.len, Mnemonic::Jmp, vec![*target])
+            } else {
+                Instr::new(instr.ip, instr.len, Mnemonic::Nop, vec![])
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Applies all the rewrites to the block
+pub fn optimize(block: Vec<Instr>, stats: &mut CompilationStats) -> Vec<Instr> {
+    stats.instructions += block.len();
+
+    let mut res = Vec::with_capacity(block.len());
+    // the flags are those of a zero idiom
+    let mut zeroed = false;
+    let mut i = 0;
+    while i < block.len() {
+        if let Some(next) = block.get(i + 1) {
+            if let Some(fused) = rewrite_pair(&block[i], next, stats) {
+                zeroed = zeroed && keeps_flags(&fused);
+                res.push(fused);
+                i += 2;
+                continue;
+            }
+        }
+
+        if zeroed {
+            if let Some(folded) = fold_branch(&block[i], stats) {
+                let taken = folded.mnemonic == Mnemonic::Jmp;
+                res.push(folded);
+                if taken {
+                    break;
+                }
+                i += 1;
+                continue;
+            }
+        }
+
+        let instr = rewrite_single(&block[i], stats).unwrap_or_else(|| block[i].clone());
+        zeroed = instr.mnemonic == Mnemonic::ZeroReg || zeroed && keeps_flags(&instr);
+        res.push(instr);
+        i += 1;
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{optimize, CompilationStats};
+    use crate::assemble_x86;
+    use crate::ir::{
+        decode_block, CodeMode, Condition, Instr, InvalidOpcodePolicy, Mnemonic,
+        UnsupportedInstructionPolicy,
+    };
+
+    fn decode(code: &[u8]) -> Vec<Instr> {
+        decode_block(
+            code,
+            0x100,
+            usize::MAX,
+            InvalidOpcodePolicy::Fault,
+            UnsupportedInstructionPolicy::Fail,
+            CodeMode::Bits32,
+        )
+        .unwrap()
+    }
+
+    fn mnemonics(block: &[Instr]) -> Vec<Mnemonic> {
+        block.iter().map(|i| i.mnemonic).collect()
+    }
+
+    #[test_log::test]
+    fn rewrites() {
+        let code = assemble_x86!(
+            ; push ebp
+            ; mov ebp, esp
+            ; xor eax, eax
+            ; sub ecx, ecx
+            ; xor eax, ebx
+            ; lea esi, [esi + 0x10]
+            ; lea esi, [edi + 0x10]
+            ; mov edx, 0
+            ; test eax, eax
+            ; jne ->end
+            ; ->end:
+            ; ret
+        );
+        let block = decode(&code);
+        let mut stats = CompilationStats::default();
+        let optimized = optimize(block.clone(), &mut stats);
+
+        use Mnemonic::*;
+        assert_eq!(
+            mnemonics(&optimized),
+            vec![
+                Prologue,
+                ZeroReg,
+                ZeroReg,
+                Xor,
+                AddNoFlags,
+                Lea,
+                Mov,
+                TestJcc(Condition::NE),
+                Ret
+            ]
+        );
+        assert_eq!(
+            stats,
+            CompilationStats {
+                instructions: 11,
+                zero_idioms: 2,
+                test_branches: 1,
+                fused_compares: 0,
+                fused_setccs: 0,
+                prologues: 1,
+                lea_adds: 1,
+                known_branches: 0,
+                dead_flag_stores: 0,
+                constant_addresses: 0,
+                switch_tables: 0,
+                inlined_calls: 0,
+                split_blocks: 0,
+                longest_block: 0,
+            }
+        );
+        assert_eq!(stats.peephole_rewrites(), 5);
+
+        // the fused instructions cover the same bytes
+        assert_eq!(optimized[0].ip, block[0].ip);
+        assert_eq!(optimized[0].next_ip(), block[1].next_ip());
+        assert_eq!(optimized[7].next_ip(), block[9].next_ip());
+    }
+
+    #[test_log::test]
+    fn only_matching_pairs() {
+        let code = assemble_x86!(
+            ; test eax, ebx
+            ; je ->end
+            ; test eax, eax
+            ; jl ->end
+            ; ->end:
+            ; ret
+        );
+        let mut stats = CompilationStats::default();
+        let optimized = optimize(decode(&code), &mut stats);
+        assert_eq!(optimized.len(), 5);
+        assert_eq!(stats.peephole_rewrites(), 0);
+    }
+
+    #[test_log::test]
+    fn fused_compares() {
+        let code = assemble_x86!(
+            ; cmp eax, [ebx]
+            ; jb ->end
+            ; cmp cl, 1
+            ; cmovl eax, edx
+            ; cmp eax, ebx
+            ; jp ->end
+            ; cmp eax, ebx
+            ; cmovg eax, [ecx]
+            ; ->end:
+            ; ret
+        );
+        let mut stats = CompilationStats::default();
+        let optimized = optimize(decode(&code), &mut stats);
+
+        use Mnemonic::*;
+        assert_eq!(
+            mnemonics(&optimized),
+            vec![
+                CmpJcc(Condition::B),
+                CmpCmovcc(Condition::L),
+                Cmp,
+                Jcc(Condition::P),
+                Cmp,
+                Cmovcc(Condition::G),
+                Ret
+            ]
+        );
+        assert_eq!(stats.fused_compares, 2);
+        assert_eq!(optimized[0].to_string(), "cmpjb eax, dword [ebx], 0x11b");
+    }
+
+    #[test_log::test]
+    fn fused_setccs() {
+        let code = assemble_x86!(
+            ; setl al
+            ; movzx eax, al
+            ; setb ah
+            ; movzx eax, ah
+            ; sete cl
+            ; movzx edx, cl
+            ; setne bl
+            ; movzx bx, bl
+            ; seta BYTE [ebx]
+            ; movzx eax, BYTE [ebx]
+            ; ret
+        );
+        let mut stats = CompilationStats::default();
+        let optimized = optimize(decode(&code), &mut stats);
+
+        use Mnemonic::*;
+        assert_eq!(
+            mnemonics(&optimized),
+            vec![
+                SetccZx(Condition::L),
+                SetccZx(Condition::B),
+                Setcc(Condition::E),
+                Movzx,
+                Setcc(Condition::NE),
+                Movzx,
+                Setcc(Condition::A),
+                Movzx,
+                Ret
+            ]
+        );
+        assert_eq!(stats.fused_setccs, 2);
+        assert_eq!(optimized[0].to_string(), "setlzx eax");
+    }
+
+    #[test_log::test]
+    fn known_branches() {
+        let code = assemble_x86!(
+            ; xor eax, eax
+            ; jnz ->end
+            ; mov ecx, 1
+            ; jnp ->end
+            ; jz ->end
+            ; inc eax
+            ; ->end:
+            ; ret
+        );
+        let block = decode(&code);
+        let mut stats = CompilationStats::default();
+        let optimized = optimize(block.clone(), &mut stats);
+
+        use Mnemonic::*;
+        assert_eq!(mnemonics(&optimized), vec![ZeroReg, Nop, Mov, Nop, Jmp]);
+        assert_eq!(stats.known_branches, 3);
+        assert_eq!(optimized[4].operands, block[4].operands);
+        assert_eq!(optimized[4].next_ip(), block[4].next_ip());
+
+        // PF is known too
+        let code = assemble_x86!(
+            ; sub ecx, ecx
+            ; jp ->end
+            ; inc eax
+            ; ->end:
+            ; ret
+        );
+        let mut stats = CompilationStats::default();
+        let optimized = optimize(decode(&code), &mut stats);
+        assert_eq!(mnemonics(&optimized), vec![ZeroReg, Jmp]);
+        assert_eq!(stats.known_branches, 1);
+
+        // something in between touches the flags
+        let code = assemble_x86!(
+            ; sub ecx, ecx
+            ; inc edx
+            ; jz ->end
+            ; xor eax, eax
+            ; shl ebx, cl
+            ; jz ->end
+            ; ->end:
+            ; ret
+        );
+        let mut stats = CompilationStats::default();
+        let optimized = optimize(decode(&code), &mut stats);
+        assert_eq!(
+            mnemonics(&optimized),
+            vec![
+                ZeroReg,
+                Inc,
+                Jcc(Condition::E),
+                ZeroReg,
+                Shl,
+                Jcc(Condition::E),
+                Ret
+            ]
+        );
+        assert_eq!(stats.known_branches, 0);
+    }
+
+    #[cfg(feature = "interp")]
+    mod differential {
+        use super::decode;
+        use crate::assemble_x86;
+        use crate::handler::NullHandler;
+        use crate::interp::{Interpreter, StepResult};
+        use crate::peephole::{optimize, CompilationStats};
+        use crate::types::{CpuContext, Flag, FullSizeGeneralPurposeRegister::*};
+        use strum::IntoEnumIterator;
+
+        fn run(block: &[crate::ir::Instr], initial: &CpuContext) -> (CpuContext, Vec<u8>) {
+            let mut interp = Interpreter::new(vec![0; 0x1000], NullHandler);
+            interp.context = initial.clone();
+            for instr in block {
+                assert_eq!(interp.execute(instr), StepResult::Continue);
+                // a taken branch, the rest of the block isn't executed
+                if interp.context.eip != instr.next_ip() {
+                    break;
+                }
+            }
+            (interp.context, interp.memory)
+        }
+
+        /// Runs the block with and without the rewrites from a bunch of different states
+        fn check(code: &[u8]) {
+            let block = decode(code);
+            let mut stats = CompilationStats::default();
+            let optimized = optimize(block.clone(), &mut stats);
+            assert_ne!(stats.peephole_rewrites(), 0, "nothing was rewritten");
+
+            for value in [0, 1, 0x7f, 0x80000000, 0xffffffff] {
+                for flags in [false, true] {
+                    let mut initial = CpuContext::default();
+                    for reg in [EAX, EBX, ECX, EDX, ESI, EDI] {
+                        initial.set_gp_reg(reg, value);
+                    }
+                    initial.set_gp_reg(ESP, 0x800);
+                    initial.set_gp_reg(EBP, 0x900);
+                    for flag in Flag::iter() {
+                        initial.set_flag(flag, flags);
+                    }
+
+                    // all of the context: PF included, the zero idioms set it
+                    let (expected, got) = (run(&block, &initial), run(&optimized, &initial));
+                    assert_eq!(
+                        expected.0.get_flag(Flag::Parity),
+                        got.0.get_flag(Flag::Parity),
+                        "PF mismatch with value = 0x{:x}, flags = {}",
+                        value,
+                        flags
+                    );
+                    assert_eq!(
+                        expected, got,
+                        "mismatch with value = 0x{:x}, flags = {}",
+                        value, flags
+                    );
+                }
+            }
+        }
+
+        #[test_log::test]
+        fn zero_idioms() {
+            check(&assemble_x86!(
+                ; xor eax, eax
+            ));
+            check(&assemble_x86!(
+                ; sub ecx, ecx
+            ));
+            check(&assemble_x86!(
+                ; xor bl, bl
+            ));
+            check(&assemble_x86!(
+                ; xor ax, ax
+            ));
+        }
+
+        #[test_log::test]
+        fn zero_idiom_consumers() {
+            for cc in 0..16u8 {
+                for zero in [[0x31, 0xc0], [0x29, 0xc0]] {
+                    // xor/sub eax, eax; jcc back to the start; inc ecx
+                    let mut code = zero.to_vec();
+                    code.extend_from_slice(&[0x70 | cc, 0xfc, 0x41]);
+                    check(&code);
+                    // xor/sub eax, eax; setcc cl
+                    let mut code = zero.to_vec();
+                    code.extend_from_slice(&[0x0f, 0x90 | cc, 0xc1]);
+                    check(&code);
+                    // xor/sub eax, eax; cmovcc ecx, esp
+                    let mut code = zero.to_vec();
+                    code.extend_from_slice(&[0x0f, 0x40 | cc, 0xcc]);
+                    check(&code);
+                }
+            }
+            check(&assemble_x86!(
+                ; ->start:
+                ; xor bl, bl
+                ; mov edx, [esp]
+                ; push edx
+                ; jbe ->start
+                ; inc eax
+            ));
+            check(&assemble_x86!(
+                ; ->start:
+                ; sub cx, cx
+                ; jg ->start
+                ; jge ->start
+                ; inc eax
+            ));
+        }
+
+        #[test_log::test]
+        fn test_branches() {
+            // jump back, so that the taken & not taken branches end up at different eip
+            check(&assemble_x86!(
+                ; ->start:
+                ; test eax, eax
+                ; je ->start
+            ));
+            check(&assemble_x86!(
+                ; ->start:
+                ; test cl, cl
+                ; jne ->start
+            ));
+        }
+
+        #[test_log::test]
+        fn fused_compares() {
+            // every condition but jp & jnp, against the edges of the signed & unsigned ranges
+            for cc in (0..16u8).filter(|cc| !matches!(cc, 0xa | 0xb)) {
+                for imm in [0u32, 1, 0x7f, 0x80000000, 0xffffffff] {
+                    let imm = imm.to_le_bytes();
+                    // cmp eax, imm; jcc back to the cmp
+                    let mut code = vec![0x3d];
+                    code.extend_from_slice(&imm);
+                    code.extend_from_slice(&[0x70 | cc, 0xf9]);
+                    check(&code);
+                    // cmp eax, imm; cmovcc ecx, esp
+                    let mut code = vec![0x3d];
+                    code.extend_from_slice(&imm);
+                    code.extend_from_slice(&[0x0f, 0x40 | cc, 0xcc]);
+                    check(&code);
+                }
+            }
+            check(&assemble_x86!(
+                ; ->start:
+                ; cmp cl, BYTE -0x80
+                ; jl ->start
+            ));
+            check(&assemble_x86!(
+                ; ->start:
+                ; cmp DWORD [esp], eax
+                ; ja ->start
+            ));
+        }
+
+        #[test_log::test]
+        fn fused_setccs() {
+            // every condition, after a compare against the edges of the signed & unsigned ranges
+            for cc in 0..16u8 {
+                for imm in [0u32, 1, 0x7f, 0x80000000, 0xffffffff] {
+                    // cmp eax, imm; setcc al; movzx eax, al
+                    let mut code = vec![0x3d];
+                    code.extend_from_slice(&imm.to_le_bytes());
+                    code.extend_from_slice(&[0x0f, 0x90 | cc, 0xc0, 0x0f, 0xb6, 0xc0]);
+                    check(&code);
+                }
+            }
+            check(&assemble_x86!(
+                ; cmp cl, BYTE 0x10
+                ; setbe ch
+                ; movzx ecx, ch
+            ));
+        }
+
+        #[test_log::test]
+        fn prologue() {
+            check(&assemble_x86!(
+                ; push ebp
+                ; mov ebp, esp
+            ));
+        }
+
+        #[test_log::test]
+        fn lea_add() {
+            check(&assemble_x86!(
+                ; lea esi, [esi + 0x10]
+            ));
+            check(&assemble_x86!(
+                ; lea edx, [edx - 1]
+            ));
+        }
+    }
+}
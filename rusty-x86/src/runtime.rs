@@ -0,0 +1,1474 @@
+//! Glue needed to actually run the recompiled code: guest memory, JIT and the runtime helpers
+//! the generated code calls into (interrupts, syscalls, port I/O)
+
+use std::any::Any;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::ops::Range;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use inkwell::context::Context;
+use inkwell::execution_engine::JitFunction;
+use inkwell::OptimizationLevel;
+use log::{debug, trace, warn};
+use region::Allocation;
+use strum::IntoEnumIterator;
+
+use crate::config::{
+    ConfigError, OptLevel, RecompilerBuilder, RecompilerConfig, TranslationOptions,
+    FULL_MEMORY_SIZE,
+};
+use crate::coverage::{Coverage, CoverageModule};
+use crate::dos::{
+    program_segment_prefix, COM_INITIAL_SP, COM_LOAD_OFFSET, COM_MAX_SIZE, COM_SEGMENT,
+    COM_SEGMENT_SIZE,
+};
+use crate::handler::{InstructionBytes, VECTOR_FLAG_MISMATCH, VECTOR_UNIMPLEMENTED};
+use crate::ir::CodeMode;
+use crate::llvm::backend::{
+    EntryFunc, RuntimeHelpers, Types, BLOCK_HIT_HELPER, CACHE_FLUSH_HELPER,
+    CONTROL_REGISTER_WRITE_HELPER, FAST_SYSCALL_HELPER, INSTRUCTION_HOOK_HELPER, INTERRUPT_HELPER,
+    MSR_WRITE_HELPER, PORT_IN_HELPER, PORT_OUT_HELPER, STACK_SOFT_LIMIT_HELPER,
+    UNDEFINED_FLAG_HELPER,
+};
+use crate::llvm::cache::{CacheKey, CacheStats, TranslationCache};
+use crate::llvm::{
+    add_entry_trampoline, translate, try_translate, FaultSites, TranslateFailure, Translation,
+    ENTRY_TRAMPOLINE,
+};
+use crate::memory_image::{MemoryImage, MemoryImageItem, Protection};
+use crate::segmentation::SegmentationPolicy;
+use crate::types::{
+    CpuContext, Flag, FullSizeGeneralPurposeRegister, IntType, SegmentRegister, EXIT_FAULT,
+    EXIT_GUEST, EXIT_HOST_REQUEST, EXIT_NONE,
+};
+use crate::win32::{TebOptions, WindowsTeb};
+
+pub use crate::handler::{ExitReason, GuestFault, NullHandler, RuntimeHandler};
+
+pub const PAGE_SIZE: u32 = 0x1000;
+
+/// Never mapped, so that an access crossing the end of the address space faults instead of reaching whatever the
+/// host has after the reservation. Covers the widest access (16 bytes) with room to spare
+const GUARD_SIZE: u64 = PAGE_SIZE as u64;
+
+/// The guest address space, backed by a host reservation (the whole 4 GiB by default)
+///
+/// Nothing is accessible until mapped
+pub struct GuestMemory {
+    space: Allocation,
+    size: u64,
+    mappings: Vec<Allocation>,
+    // protection of every mapped page (by its address), for the host-side accessors
+    pages: BTreeMap<u32, Protection>,
+    // the guest ranges backed by an `ExternalBuffer`, never mapped over
+    external: Vec<Range<u64>>,
+}
+
+/// Host memory that can also be mapped into the guest (see `GuestMemory::map_external`), for sharing the buffers
+/// with the guest without copying them in & out
+///
+/// The generated code finds the guest memory at the reservation base + the guest address, so an arbitrary host
+/// buffer can't be handed to the guest. This one is a shared memory object instead, which is mapped twice: here &
+/// in the guest memory. Both see the same bytes
+#[cfg(target_os = "linux")]
+pub struct ExternalBuffer {
+    fd: libc::c_int,
+    ptr: *mut u8,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+impl ExternalBuffer {
+    /// `len` bytes of zeroes, rounded up to the whole pages
+    pub fn new(len: usize) -> io::Result<Self> {
+        let len = len.max(1).next_multiple_of(PAGE_SIZE as usize);
+
+        // SAFETY: plain syscalls, the fd is closed on every error path
+        unsafe {
+            let fd = libc::memfd_create(c"rusty-x86-external".as_ptr(), libc::MFD_CLOEXEC);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let ptr = if libc::ftruncate(fd, len as libc::off_t) == 0 {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    fd,
+                    0,
+                )
+            } else {
+                libc::MAP_FAILED
+            };
+            if ptr == libc::MAP_FAILED {
+                let error = io::Error::last_os_error();
+                libc::close(fd);
+                return Err(error);
+            }
+
+            Ok(Self {
+                fd,
+                ptr: ptr.cast(),
+                len,
+            })
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Host address of the first byte, the same for the whole life of the buffer
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.ptr
+    }
+
+    /// # Safety
+    ///
+    /// The guest writes to the buffer when it's mapped, so it must not run while the slice is alive
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        std::slice::from_raw_parts(self.ptr, self.len)
+    }
+
+    /// # Safety
+    ///
+    /// The guest must not run while the slice is alive, like with `as_slice`
+    pub unsafe fn as_mut_slice(&mut self) -> &mut [u8] {
+        std::slice::from_raw_parts_mut(self.ptr, self.len)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for ExternalBuffer {
+    fn drop(&mut self) {
+        // the guest mappings (if any) keep the memory object alive
+        unsafe {
+            libc::munmap(self.ptr.cast(), self.len);
+            libc::close(self.fd);
+        }
+    }
+}
+
+/// What the host pages are mapped with for the guest `protection`. The code is never run from them
+fn host_protection(protection: Protection) -> region::Protection {
+    let mut rprot = region::Protection::NONE;
+    if protection.contains(Protection::READ) {
+        rprot |= region::Protection::READ
+    }
+    if protection.contains(Protection::WRITE) {
+        rprot |= region::Protection::WRITE
+    }
+    rprot
+}
+
+/// The host tried to touch guest memory that is not mapped (or doesn't allow the access)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccessError {
+    /// The first byte that could not be accessed
+    pub address: u32,
+}
+
+impl Display for MemoryAccessError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "guest memory at 0x{:08x} is not accessible",
+            self.address
+        )
+    }
+}
+
+impl std::error::Error for MemoryAccessError {}
+
+/// What `Runtime::call_guest` pushes as the return address. The generated `ret` doesn't look at it, it's there for
+/// the stack layout (and for whoever looks at the stack from a handler)
+pub const GUEST_RETURN_ADDRESS: u32 = 0xcafebabe;
+
+/// Why `Runtime::call_guest` has no result
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallError {
+    /// The arguments don't fit on the stack ESP points to
+    Stack(MemoryAccessError),
+    /// The function didn't return
+    Exit(ExitReason),
+}
+
+impl Display for CallError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CallError::Stack(e) => write!(f, "could not push the arguments: {}", e),
+            CallError::Exit(exit) => write!(f, "the guest function did not return: {:?}", exit),
+        }
+    }
+}
+
+impl std::error::Error for CallError {}
+
+/// Where `Runtime::translate_many` is at, passed to the callback after every entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Progress {
+    /// The entries done so far, the failed ones included
+    pub done: usize,
+    pub failed: usize,
+    pub total: usize,
+    /// The entry just done
+    pub current: u32,
+}
+
+/// What `Runtime::translate_many` did
+#[derive(Debug, Clone, Default)]
+pub struct TranslateReport {
+    /// The guest basic blocks translated, per entry: a block reachable from several entries counts for each of them
+    pub blocks: usize,
+    /// The guest code in those blocks, in bytes
+    pub guest_bytes: u64,
+    pub elapsed: Duration,
+    /// The entries that didn't translate, in the order they were given
+    pub errors: Vec<(u32, TranslateFailure)>,
+}
+
+/// Types that can be made from any bytes (no padding, no invalid values), like zerocopy's `FromBytes`
+///
+/// # Safety
+///
+/// Every bit pattern of `size_of::<Self>()` bytes should be a valid `Self`
+pub unsafe trait FromBytes: Copy {}
+
+macro_rules! impl_from_bytes {
+    ($($ty:ty),*) => {
+        $(unsafe impl FromBytes for $ty {})*
+    };
+}
+
+impl_from_bytes!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+unsafe impl<T: FromBytes, const N: usize> FromBytes for [T; N] {}
+
+macro_rules! int_accessors {
+    ($($ty:ident: $read:ident, $write:ident;)*) => {
+        $(
+            pub fn $read(&self, addr: u32) -> Result<$ty, MemoryAccessError> {
+                let mut bytes = [0; std::mem::size_of::<$ty>()];
+                self.read_bytes(addr, &mut bytes)?;
+                Ok($ty::from_le_bytes(bytes))
+            }
+
+            pub fn $write(&mut self, addr: u32, value: $ty) -> Result<(), MemoryAccessError> {
+                self.write_bytes(addr, &value.to_le_bytes())
+            }
+        )*
+    };
+}
+
+impl GuestMemory {
+    pub fn new() -> region::Result<Self> {
+        Self::with_size(FULL_MEMORY_SIZE)
+    }
+
+    /// Only the first `size` bytes of the address space can be mapped
+    ///
+    /// The generated code should check the bounds if it's less than 4 GiB (see `RecompilerBuilder::bounds_checking`)
+    pub fn with_size(size: u64) -> region::Result<Self> {
+        // SAFETY: dragons ahead
+        // map the memory with no protection
+        // this way we can control all mappings in the whole virtualized 32-bit address space
+        let space = region::alloc((size + GUARD_SIZE) as usize, region::Protection::NONE)?;
+        Ok(Self {
+            space,
+            size,
+            mappings: Vec::new(),
+            pages: BTreeMap::new(),
+            external: Vec::new(),
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Maps `data` at `addr` (rounded down to the page boundary), padding it to the whole pages with zeroes
+    pub fn map(&mut self, addr: u32, protection: Protection, data: &[u8]) -> region::Result<()> {
+        let page_offset = (addr % PAGE_SIZE) as usize;
+        let page_addr = addr - page_offset as u32;
+
+        let len = (page_offset + data.len()).next_multiple_of(PAGE_SIZE as usize);
+
+        if page_addr as u64 + len as u64 > self.size {
+            return Err(region::Error::InvalidParameter(
+                "mapping does not fit in the guest address space",
+            ));
+        }
+        self.check_not_external(page_addr as u64..page_addr as u64 + len as u64)?;
+
+        let rprot = host_protection(protection);
+
+        let host_addr = unsafe { self.space.as_ptr::<u8>().add(page_addr as usize) };
+
+        // firstly map the page as read-write to pre-fill it with our data
+        let mut alloc = region::alloc_at(host_addr, len, region::Protection::READ_WRITE)?;
+
+        unsafe {
+            std::slice::from_raw_parts_mut(alloc.as_mut_ptr::<u8>().add(page_offset), data.len())
+                .copy_from_slice(data)
+        };
+
+        // now map the page as it will be used by the target
+        unsafe { region::protect(alloc.as_ptr::<u8>(), alloc.len(), rprot)? };
+
+        self.mappings.push(alloc);
+        for page in (page_addr as u64..page_addr as u64 + len as u64).step_by(PAGE_SIZE as usize) {
+            self.pages.insert(page as u32, protection);
+        }
+
+        Ok(())
+    }
+
+    /// Backs the guest pages at `addr` with `buffer`: whatever the guest writes there shows up in the buffer & the
+    /// other way around, no copies involved
+    ///
+    /// `addr` has to be page-aligned & the pages not mapped yet. The mapping stays for as long as the guest memory
+    /// does, even if the buffer is dropped before, and nothing can be mapped over it: the translated code might
+    /// still be using it. Not for the code, whose translations are made from a snapshot: EXECUTE is refused
+    #[cfg(target_os = "linux")]
+    pub fn map_external(
+        &mut self,
+        addr: u32,
+        protection: Protection,
+        buffer: &ExternalBuffer,
+    ) -> region::Result<()> {
+        let range = addr as u64..addr as u64 + buffer.len() as u64;
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(region::Error::InvalidParameter(
+                "external mappings have to be page-aligned",
+            ));
+        }
+        if protection.contains(Protection::EXECUTE) {
+            return Err(region::Error::InvalidParameter(
+                "external mappings can't be executable",
+            ));
+        }
+        if range.end > self.size {
+            return Err(region::Error::InvalidParameter(
+                "mapping does not fit in the guest address space",
+            ));
+        }
+        if self
+            .pages
+            .range(addr..)
+            .next()
+            .is_some_and(|(&page, _)| (page as u64) < range.end)
+        {
+            return Err(region::Error::InvalidParameter(
+                "the guest pages are already mapped",
+            ));
+        }
+
+        let mut prot = libc::PROT_NONE;
+        if protection.contains(Protection::READ) {
+            prot |= libc::PROT_READ;
+        }
+        if protection.contains(Protection::WRITE) {
+            prot |= libc::PROT_WRITE;
+        }
+
+        // SAFETY: replaces a part of our reservation that has nothing mapped in it
+        let mapped = unsafe {
+            libc::mmap(
+                self.space.as_mut_ptr::<u8>().add(addr as usize).cast(),
+                buffer.len(),
+                prot,
+                libc::MAP_SHARED | libc::MAP_FIXED,
+                buffer.fd,
+                0,
+            )
+        };
+        if mapped == libc::MAP_FAILED {
+            return Err(region::Error::SystemCall(io::Error::last_os_error()));
+        }
+
+        for page in range.clone().step_by(PAGE_SIZE as usize) {
+            self.pages.insert(page as u32, protection);
+        }
+        self.external.push(range);
+        Ok(())
+    }
+
+    fn check_not_external(&self, range: Range<u64>) -> region::Result<()> {
+        if self
+            .external
+            .iter()
+            .any(|external| external.start < range.end && range.start < external.end)
+        {
+            return Err(region::Error::InvalidParameter(
+                "the guest pages are backed by an external buffer",
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn map_image(&mut self, image: &MemoryImage) -> region::Result<()> {
+        for MemoryImageItem {
+            addr,
+            protection,
+            data,
+        } in image.iter()
+        {
+            self.map(*addr, *protection, data.as_slice())?;
+        }
+        Ok(())
+    }
+
+    /// Whether the host can do an `access` to all of `[addr, addr + len)`
+    ///
+    /// Checked before touching anything, so an object straddling into an unmapped page is never partially read
+    fn check_access(
+        &self,
+        addr: u32,
+        len: usize,
+        access: Protection,
+    ) -> Result<(), MemoryAccessError> {
+        let end = addr as u64 + len as u64;
+        let mut page = (addr - addr % PAGE_SIZE) as u64;
+        while page < end {
+            let accessible = page < self.size
+                && self
+                    .pages
+                    .get(&(page as u32))
+                    .is_some_and(|p| p.contains(access));
+            if !accessible {
+                return Err(MemoryAccessError {
+                    // past the end of the address space is reported as its end (wrapping around)
+                    address: page.max(addr as u64) as u32,
+                });
+            }
+            page += PAGE_SIZE as u64;
+        }
+        Ok(())
+    }
+
+    pub fn read_bytes(&self, addr: u32, data: &mut [u8]) -> Result<(), MemoryAccessError> {
+        self.check_access(addr, data.len(), Protection::READ)?;
+        // SAFETY: all the pages are mapped readable
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.as_ptr().add(addr as usize),
+                data.as_mut_ptr(),
+                data.len(),
+            )
+        };
+        Ok(())
+    }
+
+    /// Only writes to the pages the guest can write too (the others are not writable by the host either)
+    pub fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<(), MemoryAccessError> {
+        self.check_access(addr, data.len(), Protection::WRITE)?;
+        // SAFETY: all the pages are mapped writable
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                data.as_ptr(),
+                self.as_mut_ptr().add(addr as usize),
+                data.len(),
+            )
+        };
+        Ok(())
+    }
+
+    /// Writes to any mapped pages, whatever the guest is allowed to do with them. The host protection of the pages
+    /// is lifted for the time of the write. Returns the bytes that were there before
+    ///
+    /// All or nothing: if a page can't be written, the ones written before it get their bytes back
+    pub fn patch_bytes(&mut self, addr: u32, data: &[u8]) -> Result<Vec<u8>, MemoryAccessError> {
+        // being mapped at all is the only requirement
+        self.check_access(addr, data.len(), Protection::empty())?;
+
+        // (page, the part of `data` in it)
+        let mut pieces = Vec::new();
+        let end = addr as u64 + data.len() as u64;
+        let mut page = (addr - addr % PAGE_SIZE) as u64;
+        while page < end {
+            let from = page.max(addr as u64);
+            let to = (page + PAGE_SIZE as u64).min(end);
+            pieces.push((
+                page as u32,
+                (from - addr as u64) as usize..(to - addr as u64) as usize,
+            ));
+            page += PAGE_SIZE as u64;
+        }
+
+        let mut original = vec![0; data.len()];
+        for (i, (page, range)) in pieces.iter().enumerate() {
+            let at = addr + range.start as u32;
+            if let Err(e) = self.patch_page(
+                *page,
+                at,
+                &data[range.clone()],
+                &mut original[range.clone()],
+            ) {
+                for (page, range) in pieces[..i].iter().rev() {
+                    let at = addr + range.start as u32;
+                    let mut patched = vec![0; range.len()];
+                    // the same write to that page just worked
+                    if self
+                        .patch_page(*page, at, &original[range.clone()], &mut patched)
+                        .is_err()
+                    {
+                        warn!("could not restore the memory at 0x{:08x}", at);
+                    }
+                }
+                return Err(e);
+            }
+        }
+        Ok(original)
+    }
+
+    /// Copies `data` to `addr`, all in the `page`, with the host protection of the page lifted for the time of the
+    /// copy. The bytes that were there go to `original`. Nothing is written if it fails
+    fn patch_page(
+        &mut self,
+        page: u32,
+        addr: u32,
+        data: &[u8],
+        original: &mut [u8],
+    ) -> Result<(), MemoryAccessError> {
+        let protection = self.pages[&page];
+        let error = MemoryAccessError { address: addr };
+        // SAFETY: the page is mapped, and is given its protection back before anything else sees it
+        unsafe {
+            let host_page = self.as_mut_ptr().add(page as usize);
+            region::protect(
+                host_page,
+                PAGE_SIZE as usize,
+                region::Protection::READ_WRITE,
+            )
+            .map_err(|_| error)?;
+            let host = self.as_mut_ptr().add(addr as usize);
+            std::ptr::copy_nonoverlapping(host, original.as_mut_ptr(), data.len());
+            std::ptr::copy_nonoverlapping(data.as_ptr(), host, data.len());
+            if region::protect(host_page, PAGE_SIZE as usize, host_protection(protection)).is_err()
+            {
+                // still writable
+                std::ptr::copy_nonoverlapping(original.as_ptr(), host, data.len());
+                return Err(error);
+            }
+        }
+        Ok(())
+    }
+
+    /// The guest memory at `[addr, addr + len)` as a host slice, if the guest can both read & write all of it.
+    /// No copies: this is what the guest sees. The memory is borrowed, so the guest can't run meanwhile
+    ///
+    /// ```compile_fail
+    /// # use rusty_x86::runtime::{NullHandler, Runtime};
+    /// let mut runtime = Runtime::new(NullHandler).unwrap();
+    /// let slice = runtime.memory.host_slice(0x1000, 4).unwrap();
+    /// runtime.run(0x2000);
+    /// slice[0] = 1;
+    /// ```
+    pub fn host_slice(&mut self, addr: u32, len: usize) -> Option<&mut [u8]> {
+        self.check_access(addr, len, Protection::READ_WRITE).ok()?;
+        // SAFETY: all the pages are mapped readable & writable, and nothing else can touch them while borrowed
+        Some(unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr().add(addr as usize), len) })
+    }
+
+    int_accessors! {
+        u8: read_u8, write_u8;
+        u16: read_u16, write_u16;
+        u32: read_u32, write_u32;
+        u64: read_u64, write_u64;
+    }
+
+    /// A NUL-terminated string of at most `max_len` bytes (not counting the NUL)
+    ///
+    /// The bytes are read up to the terminator only, so a string right before an unmapped page is fine.
+    /// Not valid UTF-8 is replaced with U+FFFD
+    pub fn read_cstr(&self, ptr: u32, max_len: usize) -> Result<String, MemoryAccessError> {
+        let mut bytes = Vec::new();
+        let mut addr = ptr;
+        while bytes.len() < max_len {
+            // up to the end of the page
+            let chunk = ((PAGE_SIZE - addr % PAGE_SIZE) as usize).min(max_len - bytes.len());
+            let start = bytes.len();
+            bytes.resize(start + chunk, 0);
+            self.read_bytes(addr, &mut bytes[start..])?;
+
+            if let Some(nul) = bytes[start..].iter().position(|&b| b == 0) {
+                bytes.truncate(start + nul);
+                break;
+            }
+            addr = addr.wrapping_add(chunk as u32);
+        }
+        Ok(String::from_utf8_lossy(&bytes).into_owned())
+    }
+
+    /// Guest structures are little-endian, so are the hosts we run on
+    pub fn read_pod<T: FromBytes>(&self, addr: u32) -> Result<T, MemoryAccessError> {
+        // SAFETY: any bytes are a valid T, including zeroes
+        let mut value: T = unsafe { std::mem::zeroed() };
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut value as *mut T as *mut u8,
+                std::mem::size_of::<T>(),
+            )
+        };
+        self.read_bytes(addr, bytes)?;
+        Ok(value)
+    }
+
+    /// Host address of the guest address 0
+    pub fn as_ptr(&self) -> *const u8 {
+        self.space.as_ptr()
+    }
+
+    /// Host address of the guest address 0
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.space.as_mut_ptr()
+    }
+}
+
+thread_local! {
+    // the handler of the runtime currently executing on this thread
+    static ACTIVE_HANDLER: Cell<*mut c_void> = const { Cell::new(std::ptr::null_mut()) };
+    // and its coverage
+    static ACTIVE_COVERAGE: Cell<*mut Coverage> = const { Cell::new(std::ptr::null_mut()) };
+    // and whether its stack has been past the soft limit already
+    static ACTIVE_SOFT_LIMIT_HIT: Cell<*mut bool> = const { Cell::new(std::ptr::null_mut()) };
+    // and its guest memory
+    static ACTIVE_MEMORY: Cell<*mut GuestMemory> = const { Cell::new(std::ptr::null_mut()) };
+    // panics can't unwind through the generated code, so we stash them here and re-raise after it returns
+    static PENDING_PANIC: RefCell<Option<Box<dyn Any + Send>>> = const { RefCell::new(None) };
+}
+
+fn with_handler<H: RuntimeHandler, R: Default>(
+    ctx: *mut CpuContext,
+    f: impl FnOnce(&mut H, &mut CpuContext) -> R,
+) -> R {
+    // SAFETY: the pointers are set up by Runtime::run and are valid for the duration of the call
+    let handler = unsafe { &mut *(ACTIVE_HANDLER.with(|h| h.get()) as *mut H) };
+    let ctx = unsafe { &mut *ctx };
+
+    match catch_unwind(AssertUnwindSafe(|| f(handler, ctx))) {
+        Ok(r) => r,
+        Err(payload) => {
+            PENDING_PANIC.with(|p| *p.borrow_mut() = Some(payload));
+            ctx.exit = EXIT_HOST_REQUEST;
+            R::default()
+        }
+    }
+}
+
+/// The guest memory of the runtime executing on this thread, for the `RuntimeHandler` methods that need to look at
+/// what the guest points them to. `None` outside of `Runtime::run`
+pub fn with_active_memory<R>(f: impl FnOnce(&mut GuestMemory) -> R) -> Option<R> {
+    let memory = ACTIVE_MEMORY.with(|m| m.get());
+    // SAFETY: set up by Runtime::run, valid while the generated code (and so the handler it called) runs
+    unsafe { memory.as_mut() }.map(f)
+}
+
+fn size_from_bytes(size: u8) -> IntType {
+    match size {
+        1 => IntType::I8,
+        2 => IntType::I16,
+        4 => IntType::I32,
+        _ => unreachable!(),
+    }
+}
+
+extern "C" fn interrupt_helper<H: RuntimeHandler>(ctx: *mut CpuContext, vector: u8) {
+    with_handler::<H, _>(ctx, |h, ctx| h.interrupt(ctx, vector))
+}
+
+extern "C" fn fast_syscall_helper<H: RuntimeHandler>(ctx: *mut CpuContext) {
+    with_handler::<H, _>(ctx, |h, ctx| h.fast_syscall(ctx))
+}
+
+extern "C" fn undefined_flag_helper<H: RuntimeHandler>(
+    ctx: *mut CpuContext,
+    flag: u8,
+    source: u32,
+) {
+    let flag = Flag::iter().nth(flag as usize).unwrap();
+    with_handler::<H, _>(ctx, |h, ctx| h.undefined_flag(ctx, flag, source))
+}
+
+extern "C" fn port_in_helper<H: RuntimeHandler>(ctx: *mut CpuContext, port: u16, size: u8) -> u32 {
+    with_handler::<H, _>(ctx, |h, ctx| h.port_in(ctx, port, size_from_bytes(size)))
+}
+
+extern "C" fn instruction_hook_helper<H: RuntimeHandler>(ctx: *mut CpuContext) {
+    with_handler::<H, _>(ctx, |h, ctx| h.instruction(ctx))
+}
+
+extern "C" fn port_out_helper<H: RuntimeHandler>(
+    ctx: *mut CpuContext,
+    port: u16,
+    size: u8,
+    value: u32,
+) {
+    with_handler::<H, _>(ctx, |h, ctx| {
+        h.port_out(ctx, port, size_from_bytes(size), value)
+    })
+}
+
+extern "C" fn control_register_write_helper<H: RuntimeHandler>(
+    ctx: *mut CpuContext,
+    register: u8,
+    value: u32,
+) {
+    with_handler::<H, _>(ctx, |h, ctx| h.control_register_write(ctx, register, value))
+}
+
+extern "C" fn msr_write_helper<H: RuntimeHandler>(ctx: *mut CpuContext, index: u32, value: u64) {
+    with_handler::<H, _>(ctx, |h, ctx| h.msr_write(ctx, index, value))
+}
+
+extern "C" fn cache_flush_helper<H: RuntimeHandler>(ctx: *mut CpuContext, address: u32) {
+    with_handler::<H, _>(ctx, |h, ctx| h.cache_flush(ctx, address))
+}
+
+extern "C" fn stack_soft_limit_helper<H: RuntimeHandler>(ctx: *mut CpuContext, esp: u32) {
+    // SAFETY: set up by Runtime::run for the duration of the call, like the handler
+    let hit = unsafe { &mut *ACTIVE_SOFT_LIMIT_HIT.with(|h| h.get()) };
+    if !std::mem::replace(hit, true) {
+        with_handler::<H, _>(ctx, |h, ctx| h.stack_soft_limit(ctx, esp))
+    }
+}
+
+extern "C" fn block_hit_helper(_ctx: *mut CpuContext, address: u32, size: u32) {
+    // SAFETY: set up by Runtime::run for the duration of the call, like the handler
+    let coverage = unsafe { &mut *ACTIVE_COVERAGE.with(|c| c.get()) };
+    coverage.record(address, size);
+}
+
+/// The guest stack made by `Runtime::setup_stack`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestStack {
+    /// The lowest address of the stack. The page right under it is the guard page, never mapped
+    pub base: u32,
+    /// Where ESP starts
+    pub top: u32,
+    /// `RuntimeHandler::stack_soft_limit` is called when ESP first goes below it
+    pub soft_limit: Option<u32>,
+}
+
+/// The options the code is translated with: the ones of the config, with the soft limit of the stack if there's one
+fn translation_options(
+    config: &RecompilerConfig,
+    stack: Option<GuestStack>,
+) -> Cow<'_, TranslationOptions> {
+    match stack {
+        Some(stack) if stack.soft_limit != config.translation.stack_soft_limit => {
+            Cow::Owned(TranslationOptions {
+                stack_soft_limit: stack.soft_limit,
+                ..config.translation.clone()
+            })
+        }
+        _ => Cow::Borrowed(&config.translation),
+    }
+}
+
+/// Tries to translate the code reachable from each of the `entries` on its own, on up to `threads` threads with an
+/// LLVM context each. `on_result` gets the index of the entry & the blocks and bytes translated from it, on the
+/// calling thread, in the order the entries are done
+fn translate_entries(
+    options: &TranslationOptions,
+    image: &MemoryImage,
+    entries: &[u32],
+    threads: usize,
+    mut on_result: impl FnMut(usize, Result<(usize, u64), TranslateFailure>),
+) {
+    let translate_entry = |entry: u32| -> Result<(usize, u64), TranslateFailure> {
+        let context = Context::create();
+        let types = &Types::new(&context);
+        let rt_funs = &RuntimeHelpers::dummy(types);
+        let translation = try_translate(&context, types, rt_funs, options, image, &[entry])?;
+        Ok((translation.blocks, translation.guest_bytes))
+    };
+
+    if threads <= 1 {
+        for (i, &entry) in entries.iter().enumerate() {
+            on_result(i, translate_entry(entry));
+        }
+        return;
+    }
+
+    let (translate_entry, next) = (&translate_entry, &AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..threads.min(entries.len()) {
+            let sender = sender.clone();
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let entry = match entries.get(i) {
+                    Some(&entry) => entry,
+                    None => break,
+                };
+                // the receiving end stays until all the workers are done
+                sender.send((i, translate_entry(entry))).unwrap();
+            });
+        }
+        drop(sender);
+
+        for (i, result) in receiver {
+            on_result(i, result);
+        }
+    });
+}
+
+/// Owns the guest state and runs the code in it
+pub struct Runtime<H: RuntimeHandler> {
+    pub context: CpuContext,
+    pub memory: GuestMemory,
+    pub handler: H,
+    config: RecompilerConfig,
+    image: MemoryImage,
+    cache: Option<TranslationCache>,
+    coverage: Coverage,
+    stack: Option<GuestStack>,
+    soft_limit_hit: bool,
+    teb: Option<WindowsTeb>,
+    // what `write_code` replaced, the latest last (with `RecompilerBuilder::patch_log`)
+    patches: Vec<(u32, Vec<u8>)>,
+}
+
+impl<H: RuntimeHandler> Runtime<H> {
+    pub fn new(handler: H) -> region::Result<Self> {
+        Self::with_config(RecompilerConfig::default(), handler)
+    }
+
+    pub fn with_config(config: RecompilerConfig, handler: H) -> region::Result<Self> {
+        Ok(Self {
+            context: CpuContext::with_flag_storage(config.translation.flag_storage),
+            memory: GuestMemory::with_size(config.memory_size)?,
+            handler,
+            cache: config.translation_cache.clone().map(TranslationCache::new),
+            config,
+            image: MemoryImage::new(),
+            coverage: Coverage::new(),
+            stack: None,
+            soft_limit_hit: false,
+            teb: None,
+            patches: Vec::new(),
+        })
+    }
+
+    pub fn config(&self) -> &RecompilerConfig {
+        &self.config
+    }
+
+    /// `None` without `RecompilerBuilder::translation_cache`
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.cache.as_ref().map(|cache| cache.stats())
+    }
+
+    /// The blocks executed so far (in all the runs) with `RecompilerBuilder::coverage`, the executable regions as
+    /// the modules
+    pub fn coverage(&self) -> Coverage {
+        let modules = self
+            .image
+            .iter()
+            .map(|region| CoverageModule {
+                base: region.addr,
+                end: region.addr.saturating_add(region.data.len() as u32),
+                name: format!("region_{:08x}", region.addr),
+            })
+            .collect();
+        Coverage {
+            modules,
+            ..self.coverage.clone()
+        }
+    }
+
+    pub fn reset_coverage(&mut self) {
+        self.coverage = Coverage::new();
+    }
+
+    /// Maps the memory in the guest. Executable regions are also remembered for translation
+    pub fn map(&mut self, addr: u32, protection: Protection, data: &[u8]) -> region::Result<()> {
+        self.memory.map(addr, protection, data)?;
+        if protection.contains(Protection::EXECUTE) {
+            self.image.add_region(addr, protection, data.to_vec());
+        }
+        Ok(())
+    }
+
+    /// Overwrites the guest code (or anything else mapped) at `addr`, whatever the protection of the pages
+    ///
+    /// The translations are made from the snapshot of the executable regions taken by `map`, which is patched too:
+    /// the next `run` sees the new code. A cached translation of the old one is not used anymore, as the key
+    /// changes with the code. There's no invalidation of single blocks: the cache keeps whole modules, so the next
+    /// `run` translates all of the code again, not only the blocks the patch touched. With
+    /// `RecompilerBuilder::patch_log` the overwritten bytes are remembered for `revert_patches`. Nothing is written
+    /// if it fails
+    pub fn write_code(&mut self, addr: u32, bytes: &[u8]) -> Result<(), MemoryAccessError> {
+        let original = self.memory.patch_bytes(addr, bytes)?;
+        if self.image.patch(addr, bytes) {
+            debug!(
+                "patched the code at 0x{:08x}..0x{:08x}",
+                addr,
+                addr as u64 + bytes.len() as u64
+            );
+        }
+        if self.config.patch_log {
+            self.patches.push((addr, original));
+        }
+        Ok(())
+    }
+
+    /// Overwrites the guest data at `addr`. The protection of the pages is ignored unless
+    /// `RecompilerBuilder::strict_data_writes` is on
+    ///
+    /// Unlike `write_code` the translations are left alone: writing over the code here changes what the guest reads,
+    /// not what it runs
+    pub fn write_data(&mut self, addr: u32, bytes: &[u8]) -> Result<(), MemoryAccessError> {
+        if self.config.strict_data_writes {
+            self.memory.write_bytes(addr, bytes)
+        } else {
+            self.memory.patch_bytes(addr, bytes).map(drop)
+        }
+    }
+
+    /// Undoes the `write_code`s recorded with `RecompilerBuilder::patch_log`, the latest first. Returns how many
+    /// there were
+    pub fn revert_patches(&mut self) -> Result<usize, MemoryAccessError> {
+        let count = self.patches.len();
+        while let Some((addr, original)) = self.patches.pop() {
+            self.memory.patch_bytes(addr, &original)?;
+            self.image.patch(addr, &original);
+        }
+        Ok(count)
+    }
+
+    /// Adds an address known to be a start of a basic block
+    ///
+    /// Everything statically reachable from the entry is discovered anyway, but indirect jump targets are not
+    pub fn add_entry_point(&mut self, addr: u32) {
+        if !self.config.entry_points.contains(&addr) {
+            self.config.entry_points.push(addr);
+        }
+    }
+
+    /// Translates the code reachable from each of the `entries` up front, to find out which of them translate
+    ///
+    /// The ones that do are added as the entry points (see `add_entry_point`). The ones that don't - code that
+    /// doesn't decode with `UnsupportedInstructionPolicy::Fail`, a broken lowering in a debug build - are reported
+    /// & left out, so that the next `run` doesn't trip over them. The entries are tried on
+    /// `RecompilerBuilder::translation_threads` threads.
+    ///
+    /// With a translation cache, the code of all the entry points is then translated in one piece & stored in it,
+    /// under the key `run` looks up for any of them: the next `run` loads it instead of translating again. Without
+    /// one there's nowhere to keep it, and `run` translates everything itself
+    pub fn translate_many(
+        &mut self,
+        entries: &[u32],
+        mut on_progress: impl FnMut(Progress),
+    ) -> TranslateReport {
+        let start = Instant::now();
+        let mut report = TranslateReport::default();
+        let options = translation_options(&self.config, self.stack).into_owned();
+
+        // by the index of the entry, to report them in the order they were given
+        let mut failures = BTreeMap::new();
+        let mut done = 0;
+        translate_entries(
+            &options,
+            &self.image,
+            entries,
+            self.config.translation_threads,
+            |i, result| {
+                match result {
+                    Ok((blocks, guest_bytes)) => {
+                        report.blocks += blocks;
+                        report.guest_bytes += guest_bytes;
+                    }
+                    Err(e) => {
+                        warn!("could not translate the entry 0x{:08x}: {}", entries[i], e);
+                        failures.insert(i, e);
+                    }
+                }
+                done += 1;
+
+                on_progress(Progress {
+                    done,
+                    failed: failures.len(),
+                    total: entries.len(),
+                    current: entries[i],
+                });
+            },
+        );
+        for (i, &entry) in entries.iter().enumerate() {
+            match failures.remove(&i) {
+                Some(e) => report.errors.push((entry, e)),
+                None => self.add_entry_point(entry),
+            }
+        }
+
+        // `translation_roots` of any of the entry points
+        let roots = &self.config.entry_points;
+        if let Some(cache) = self.cache.as_mut().filter(|_| !roots.is_empty()) {
+            let context = Context::create();
+            let types = &Types::new(&context);
+            let rt_funs = &RuntimeHelpers::dummy(types);
+            match try_translate(&context, types, rt_funs, &options, &self.image, roots) {
+                Ok(Translation {
+                    module,
+                    fault_sites,
+                    ..
+                }) => {
+                    add_entry_trampoline(&context, &module, types);
+                    let key = CacheKey::new(&options, &self.image, roots);
+                    if let Err(e) = cache.store(key, &module, &fault_sites) {
+                        warn!(
+                            "could not save the translation to {}: {}",
+                            cache.dir().display(),
+                            e
+                        );
+                    }
+                }
+                // one of the entry points given to the builder, `run` is going to fail the same way
+                Err(e) => warn!("could not translate the entry points: {}", e),
+            }
+        }
+
+        report.elapsed = start.elapsed();
+        report
+    }
+
+    /// What a `run` of `entry` translates the code reachable from: the entry points, and `entry` itself if it's not
+    /// one of them. The same for all the entry points, so that they share a cached translation
+    fn translation_roots(&self, entry: u32) -> Vec<u32> {
+        let mut roots = self.config.entry_points.clone();
+        if !roots.contains(&entry) {
+            roots.insert(0, entry);
+        }
+        roots
+    }
+
+    /// Maps `size` bytes of stack at `base` (page-aligned) and points ESP to the top of it
+    ///
+    /// The page under `base` has to be unmapped: it's the guard page. With `RecompilerBuilder::fault_sites` a fault
+    /// in it comes out as `GuestFault::StackOverflow` instead of a plain `GuestFault::PageFault` (without, it's a
+    /// segfault like any other access to the unmapped memory). With a `soft_limit` every write to ESP is checked
+    /// against it, and `RuntimeHandler::stack_soft_limit` is called once ESP goes below it for the first time. Without
+    /// one, the limit given to `RecompilerBuilder::stack_soft_limit` (if any) is used
+    pub fn setup_stack(
+        &mut self,
+        base: u32,
+        size: u32,
+        soft_limit: Option<u32>,
+    ) -> region::Result<GuestStack> {
+        if !base.is_multiple_of(PAGE_SIZE) || base < PAGE_SIZE {
+            return Err(region::Error::InvalidParameter(
+                "the stack has to be page-aligned, with a page under it",
+            ));
+        }
+        if self.memory.pages.contains_key(&(base - PAGE_SIZE)) {
+            return Err(region::Error::InvalidParameter(
+                "the guard page under the stack is mapped",
+            ));
+        }
+        let size = size.max(1).next_multiple_of(PAGE_SIZE);
+        let top = base
+            .checked_add(size)
+            .ok_or(region::Error::InvalidParameter(
+                "the stack does not fit in the guest address space",
+            ))?;
+        self.memory
+            .map(base, Protection::READ_WRITE, &vec![0; size as usize])?;
+
+        let soft_limit = soft_limit.or(self.config.translation.stack_soft_limit);
+        let stack = GuestStack {
+            base,
+            top,
+            soft_limit,
+        };
+        self.context
+            .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, top);
+        self.stack = Some(stack);
+        self.soft_limit_hit = false;
+        if let Some(teb) = self.teb {
+            teb.set_stack_bounds(&mut self.memory, top, base)
+                .expect("the TEB is mapped writable");
+        }
+        Ok(stack)
+    }
+
+    /// The one of `setup_stack`
+    pub fn stack(&self) -> Option<GuestStack> {
+        self.stack
+    }
+
+    /// Maps a minimal Win32 TEB & PEB (see win32.rs) and points FS to the TEB
+    ///
+    /// Both have to be page-aligned, in the pages not mapped yet. The stack bounds in the TEB are the ones of
+    /// `setup_stack` (now or later), zero without it. The segmentation affects the code translated from now on
+    pub fn setup_teb(&mut self, options: &TebOptions) -> region::Result<WindowsTeb> {
+        for addr in [options.teb, options.peb] {
+            if !addr.is_multiple_of(PAGE_SIZE) {
+                return Err(region::Error::InvalidParameter(
+                    "the TEB & the PEB have to be page-aligned",
+                ));
+            }
+            if options.teb == options.peb || self.memory.pages.contains_key(&addr) {
+                return Err(region::Error::InvalidParameter(
+                    "the pages of the TEB & the PEB have to be free",
+                ));
+            }
+        }
+        for addr in [options.teb, options.peb] {
+            self.memory
+                .map(addr, Protection::READ_WRITE, &[0; PAGE_SIZE as usize])?;
+        }
+
+        let teb = WindowsTeb::init(&mut self.memory, options, self.stack)
+            .expect("the TEB & the PEB are mapped writable");
+        self.config
+            .translation
+            .segmentation
+            .set_segment(SegmentRegister::FS, teb.segment());
+        self.teb = Some(teb);
+        Ok(teb)
+    }
+
+    /// The one of `setup_teb`
+    pub fn teb(&self) -> Option<WindowsTeb> {
+        self.teb
+    }
+
+    /// Maps the 64 KiB segment of a DOS .COM program at address 0 (see dos.rs): the PSP, `program` at
+    /// `COM_LOAD_OFFSET` & the stack at the top, with ESP pointing to a word of zero. Returns the entry point
+    ///
+    /// Needs the 16-bit mode. A `ret` from the program ends the run with `ExitReason::Returned`, where DOS would
+    /// go to the `int 20h` in the PSP
+    pub fn load_com(&mut self, program: &[u8]) -> region::Result<u32> {
+        if self.config.translation.code_mode != CodeMode::Bits16 {
+            return Err(region::Error::InvalidParameter(
+                "a .COM program needs the 16-bit mode",
+            ));
+        }
+        if program.len() > COM_MAX_SIZE {
+            return Err(region::Error::InvalidParameter(
+                "the .COM program does not fit in its segment",
+            ));
+        }
+
+        let base = (COM_SEGMENT as u32) << 4;
+        let mut segment = vec![0; COM_SEGMENT_SIZE as usize];
+        segment[..COM_LOAD_OFFSET as usize].copy_from_slice(&program_segment_prefix());
+        segment[COM_LOAD_OFFSET as usize..][..program.len()].copy_from_slice(program);
+        self.map(base, Protection::READ_WRITE_EXECUTE, &segment)?;
+
+        self.context
+            .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, base + COM_INITIAL_SP);
+        Ok(base + COM_LOAD_OFFSET)
+    }
+
+    /// Changes what the segment registers point to, affects the code translated from now on
+    pub fn set_segmentation(&mut self, policy: SegmentationPolicy) {
+        self.config.translation.segmentation = policy;
+    }
+
+    /// Calls the guest function at `entry` with the cdecl convention: the `args` go on the stack (the first one at
+    /// the lowest address) under `GUEST_RETURN_ADDRESS`, the result comes in EAX. The stack is the one ESP points to,
+    /// and ESP is back where it was once the function returns. If it doesn't, the context is left as the exit found
+    /// it: a `GuestExit` from any depth comes back as `CallError::Exit`
+    pub fn call_guest(&mut self, entry: u32, args: &[u32]) -> Result<u32, CallError> {
+        let esp = self.context.get_gp_reg(FullSizeGeneralPurposeRegister::ESP);
+        let mut top = esp;
+        for &value in args.iter().rev().chain([&GUEST_RETURN_ADDRESS]) {
+            top = top.wrapping_sub(4);
+            self.memory
+                .write_u32(top, value)
+                .map_err(CallError::Stack)?;
+        }
+        self.context
+            .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, top);
+
+        match self.run(entry) {
+            ExitReason::Returned => {
+                // `ret` took the return address, the arguments are for the caller to pop
+                self.context
+                    .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, esp);
+                Ok(self.context.get_gp_reg(FullSizeGeneralPurposeRegister::EAX))
+            }
+            exit => Err(CallError::Exit(exit)),
+        }
+    }
+
+    /// Translates the code & runs it starting at `entry` until it returns or the handler asks to stop
+    pub fn run(&mut self, entry: u32) -> ExitReason {
+        // TODO: keep the translated code in memory between the runs
+        let context = Context::create();
+        let types = &Types::new(&context);
+        let rt_funs = &RuntimeHelpers::dummy(types);
+
+        let basic_blocks = self.translation_roots(entry);
+
+        // the context might have been replaced with one keeping the flags elsewhere
+        let storage = self.config.translation.flag_storage;
+        if self.context.flag_storage() != storage {
+            self.context.set_flag_storage(storage);
+        }
+
+        let options = translation_options(&self.config, self.stack);
+        // hashing all of the code is not free, only done when there's a cache to look into
+        let key = self
+            .cache
+            .is_some()
+            .then(|| CacheKey::new(&options, &self.image, &basic_blocks));
+        let cached = self
+            .cache
+            .as_mut()
+            .zip(key)
+            .and_then(|(cache, key)| cache.load(&context, key));
+        let (module, fault_sites) = match cached {
+            Some(cached) => cached,
+            None => {
+                let Translation {
+                    module,
+                    fault_sites,
+                    ..
+                } = translate(
+                    &context,
+                    types,
+                    rt_funs,
+                    &options,
+                    &self.image,
+                    &basic_blocks,
+                );
+                add_entry_trampoline(&context, &module, types);
+
+                if let (Some(cache), Some(key)) = (&mut self.cache, key) {
+                    // running without the cache is still better than not running
+                    if let Err(e) = cache.store(key, &module, &fault_sites) {
+                        warn!(
+                            "could not save the translation to {}: {}",
+                            cache.dir().display(),
+                            e
+                        );
+                    }
+                }
+                (module, fault_sites)
+            }
+        };
+
+        trace!("llvm ir:\n{}", module.print_to_string().to_string());
+
+        module.verify().unwrap();
+
+        let execution_engine = module
+            .create_jit_execution_engine(match self.config.opt_level {
+                OptLevel::None => OptimizationLevel::None,
+                OptLevel::Less => OptimizationLevel::Less,
+                OptLevel::Default => OptimizationLevel::Default,
+                OptLevel::Aggressive => OptimizationLevel::Aggressive,
+            })
+            .unwrap();
+
+        let helpers: [(&str, usize); 11] = [
+            (
+                INTERRUPT_HELPER,
+                interrupt_helper::<H> as *const () as usize,
+            ),
+            (
+                FAST_SYSCALL_HELPER,
+                fast_syscall_helper::<H> as *const () as usize,
+            ),
+            (
+                UNDEFINED_FLAG_HELPER,
+                undefined_flag_helper::<H> as *const () as usize,
+            ),
+            (PORT_IN_HELPER, port_in_helper::<H> as *const () as usize),
+            (PORT_OUT_HELPER, port_out_helper::<H> as *const () as usize),
+            (
+                CONTROL_REGISTER_WRITE_HELPER,
+                control_register_write_helper::<H> as *const () as usize,
+            ),
+            (
+                MSR_WRITE_HELPER,
+                msr_write_helper::<H> as *const () as usize,
+            ),
+            (
+                CACHE_FLUSH_HELPER,
+                cache_flush_helper::<H> as *const () as usize,
+            ),
+            (
+                INSTRUCTION_HOOK_HELPER,
+                instruction_hook_helper::<H> as *const () as usize,
+            ),
+            (BLOCK_HIT_HELPER, block_hit_helper as *const () as usize),
+            (
+                STACK_SOFT_LIMIT_HELPER,
+                stack_soft_limit_helper::<H> as *const () as usize,
+            ),
+        ];
+        for (name, addr) in helpers {
+            // only those that the code actually uses are declared
+            if let Some(fun) = module.get_function(name) {
+                execution_engine.add_global_mapping(&fun, addr);
+            }
+        }
+
+        let fun: JitFunction<EntryFunc> =
+            unsafe { execution_engine.get_function(ENTRY_TRAMPOLINE).unwrap() };
+
+        let prev_handler =
+            ACTIVE_HANDLER.with(|h| h.replace(&mut self.handler as *mut H as *mut c_void));
+        let prev_coverage = ACTIVE_COVERAGE.with(|c| c.replace(&mut self.coverage));
+        let prev_soft_limit_hit =
+            ACTIVE_SOFT_LIMIT_HIT.with(|h| h.replace(&mut self.soft_limit_hit));
+        let prev_memory = ACTIVE_MEMORY.with(|m| m.replace(&mut self.memory));
+        let exit = unsafe {
+            // do the thing!
+            if self.config.translation.fault_sites {
+                self.enter_guarded(fun.as_raw(), entry, &fault_sites)
+            } else {
+                fun.call(&mut self.context, self.memory.as_mut_ptr(), entry)
+            }
+        };
+        ACTIVE_HANDLER.with(|h| h.set(prev_handler));
+        ACTIVE_COVERAGE.with(|c| c.set(prev_coverage));
+        ACTIVE_SOFT_LIMIT_HIT.with(|h| h.set(prev_soft_limit_hit));
+        ACTIVE_MEMORY.with(|m| m.set(prev_memory));
+
+        if let Some(payload) = PENDING_PANIC.with(|p| p.borrow_mut().take()) {
+            resume_unwind(payload);
+        }
+
+        match exit {
+            EXIT_NONE => ExitReason::Returned,
+            EXIT_FAULT => ExitReason::Fault(match self.context.fault_vector as u8 {
+                VECTOR_UNIMPLEMENTED => self.unimplemented_fault(),
+                VECTOR_FLAG_MISMATCH => GuestFault::FlagMismatch {
+                    eip: self.context.eip,
+                    flag: Flag::iter()
+                        .find(|&flag| flag as u32 == self.context.fault_address)
+                        .expect("generated code reported an unknown flag"),
+                },
+                vector => {
+                    let fault = GuestFault::from_vector(vector)
+                        .expect("generated code raised an unknown fault");
+                    self.stack_overflow(fault).unwrap_or(fault)
+                }
+            }),
+            EXIT_GUEST => ExitReason::GuestExit {
+                code: self.context.exit_code,
+            },
+            _ => ExitReason::HostRequest,
+        }
+    }
+
+    /// A page fault in the guard page under the stack
+    fn stack_overflow(&self, fault: GuestFault) -> Option<GuestFault> {
+        let stack = self.stack?;
+        let guard = stack.base - PAGE_SIZE..stack.base;
+        (fault == GuestFault::PageFault && guard.contains(&self.context.fault_address)).then(|| {
+            GuestFault::StackOverflow {
+                esp: self.context.get_gp_reg(FullSizeGeneralPurposeRegister::ESP),
+                base: stack.base,
+                top: stack.top,
+            }
+        })
+    }
+
+    /// The bytes of the instruction at `CpuContext::eip`, `CpuContext::fault_error_code` of them
+    fn unimplemented_fault(&self) -> GuestFault {
+        let eip = self.context.eip;
+        let mut bytes = [0; 15];
+        let bytes = &mut bytes[..self.context.fault_error_code as usize];
+        self.memory
+            .read_bytes(eip, bytes)
+            .expect("the translated code is not in the guest memory anymore");
+        GuestFault::Unimplemented {
+            eip,
+            bytes: InstructionBytes::new(bytes),
+        }
+    }
+
+    /// Calls the entry with a SIGSEGV handler around, turning the faults on the guest memory into #PF.
+    /// The handler jumps straight back here, so a fault inside of a `RuntimeHandler` would skip its destructors
+    /// (use the checked accessors of `GuestMemory` there)
+    #[cfg(unix)]
+    unsafe fn enter_guarded(&mut self, fun: EntryFunc, entry: u32, sites: &FaultSites) -> u32 {
+        use std::sync::Once;
+
+        static INSTALL_HANDLER: Once = Once::new();
+        INSTALL_HANDLER.call_once(|| {
+            assert_eq!(
+                rusty_x86_install_fault_handler(),
+                0,
+                "could not install the fault handler"
+            )
+        });
+
+        let mut exit = EXIT_NONE;
+        let mut fault_address = 0;
+        let faulted = rusty_x86_guarded_enter(
+            fun,
+            &mut self.context,
+            self.memory.as_mut_ptr(),
+            // the guard page counts too, the fault address wraps around to the bottom of the guest memory
+            self.memory.size() + GUARD_SIZE,
+            entry,
+            &mut exit,
+            &mut fault_address,
+        );
+        if faulted == 0 {
+            return exit;
+        }
+
+        // the code stored the site before the access, so nothing after it has happened
+        let ctx = &mut self.context;
+        ctx.eip = sites
+            .eip(ctx.fault_site)
+            .expect("fault outside of the translated memory accesses");
+        ctx.fault_vector = GuestFault::PageFault.vector() as u32;
+        ctx.fault_address = fault_address;
+        // a data access, the fetch faults are raised by the code itself
+        ctx.fault_error_code = 0;
+        ctx.exit = EXIT_FAULT;
+        EXIT_FAULT
+    }
+
+    /// No signals to catch the faults with
+    #[cfg(not(unix))]
+    unsafe fn enter_guarded(&mut self, fun: EntryFunc, entry: u32, _sites: &FaultSites) -> u32 {
+        fun(&mut self.context, self.memory.as_mut_ptr(), entry)
+    }
+}
+
+// see src/fault_guard.c
+#[cfg(unix)]
+extern "C" {
+    fn rusty_x86_install_fault_handler() -> i32;
+    fn rusty_x86_guarded_enter(
+        entry: EntryFunc,
+        ctx: *mut CpuContext,
+        mem: *mut u8,
+        mem_size: u64,
+        eip: u32,
+        exit: *mut u32,
+        fault_address: *mut u32,
+    ) -> i32;
+}
+
+impl RecompilerBuilder {
+    /// Validates the config and makes a runtime with it
+    pub fn build_runtime<H: RuntimeHandler>(self, handler: H) -> Result<Runtime<H>, ConfigError> {
+        let config = self.build()?;
+        Runtime::with_config(config, handler).map_err(ConfigError::MemoryReservation)
+    }
+}
@@ -0,0 +1,183 @@
+//! What the segment registers mean for the guest
+//!
+//! There are no segment register loads (yet), so the embedder describes what each of them points to
+//! up front, along with the descriptors far transfers are allowed to go through:
+//!
+//! ```ignore
+//! let mut policy = SegmentationPolicy::checked();
+//! policy.set_segment(SegmentRegister::DS, SegmentDescriptor::new(0, 0xffff, Protection::READ_WRITE));
+//! policy.add_descriptor(0x1b, SegmentDescriptor::new(0, 0xffffffff, Protection::READ_EXECUTE));
+//! ```
+//!
+//! Everything is known at translation time, so the flat mode without any bases costs nothing
+
+use std::collections::HashMap;
+
+use crate::handler::GuestFault;
+use crate::memory_image::Protection;
+use crate::types::{FullSizeGeneralPurposeRegister, MemoryOperand, SegmentRegister};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SegmentDescriptor {
+    pub base: u32,
+    /// Offset of the last accessible byte
+    pub limit: u32,
+    pub rights: Protection,
+}
+
+impl SegmentDescriptor {
+    pub fn new(base: u32, limit: u32, rights: Protection) -> Self {
+        Self {
+            base,
+            limit,
+            rights,
+        }
+    }
+
+    /// The whole 4 GiB, everything allowed
+    pub fn flat() -> Self {
+        Self::new(0, u32::MAX, Protection::READ_WRITE_EXECUTE)
+    }
+
+    /// What a real-mode segment register with `selector` in it points to: 64 KiB at `selector << 4`
+    /// (for `CodeMode::Bits16`)
+    pub fn real_mode(selector: u16) -> Self {
+        Self::new(
+            (selector as u32) << 4,
+            0xffff,
+            Protection::READ_WRITE_EXECUTE,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SegmentationMode {
+    /// Bases are added (if set), limits & rights are ignored, far transfers just use the offset
+    #[default]
+    Flat,
+    /// Accesses outside of the limit or without the rights raise #GP.
+    /// Far transfers go through the descriptor table
+    Checked,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct SegmentationPolicy {
+    pub mode: SegmentationMode,
+    // what's loaded into the segment registers
+    segments: HashMap<SegmentRegister, SegmentDescriptor>,
+    // by selector (with the RPL bits cleared)
+    descriptors: HashMap<u16, SegmentDescriptor>,
+}
+
+impl SegmentationPolicy {
+    pub fn flat() -> Self {
+        Self::default()
+    }
+
+    pub fn checked() -> Self {
+        Self {
+            mode: SegmentationMode::Checked,
+            ..Self::default()
+        }
+    }
+
+    pub fn set_segment(&mut self, segment: SegmentRegister, descriptor: SegmentDescriptor) {
+        self.segments.insert(segment, descriptor);
+    }
+
+    /// Makes `selector` usable as a far jmp/call target
+    pub fn add_descriptor(&mut self, selector: u16, descriptor: SegmentDescriptor) {
+        self.descriptors.insert(selector & !3, descriptor);
+    }
+
+    /// What the segment register points to
+    ///
+    /// CS, DS, ES & SS are flat unless set, FS & GS point to OS-specific stuff, so there is no sensible default for them
+    pub fn segment(&self, segment: SegmentRegister) -> Option<SegmentDescriptor> {
+        use SegmentRegister::*;
+        match (self.segments.get(&segment), segment) {
+            (Some(descriptor), _) => Some(*descriptor),
+            (None, CS | DS | ES | SS) => Some(SegmentDescriptor::flat()),
+            (None, FS | GS) => None,
+        }
+    }
+
+    /// Where a far jmp/call to `selector:offset` goes
+    pub fn far_target(&self, selector: u16, offset: u32) -> Result<u32, GuestFault> {
+        match self.mode {
+            SegmentationMode::Flat => Ok(offset),
+            SegmentationMode::Checked => match self.descriptors.get(&(selector & !3)) {
+                Some(descriptor)
+                    if descriptor.rights.contains(Protection::EXECUTE)
+                        && offset <= descriptor.limit =>
+                {
+                    Ok(descriptor.base.wrapping_add(offset))
+                }
+                _ => Err(GuestFault::GeneralProtection),
+            },
+        }
+    }
+}
+
+/// The segment used by a memory operand without an override: SS for stack-based addressing, DS for everything else
+pub fn default_segment(op: &MemoryOperand) -> SegmentRegister {
+    match op.base.map(|b| b.base_register()) {
+        Some(FullSizeGeneralPurposeRegister::ESP | FullSizeGeneralPurposeRegister::EBP) => {
+            SegmentRegister::SS
+        }
+        _ => SegmentRegister::DS,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SegmentDescriptor, SegmentationPolicy};
+    use crate::handler::GuestFault;
+    use crate::memory_image::Protection;
+    use crate::types::SegmentRegister;
+
+    #[test_log::test]
+    fn far_targets() {
+        let flat = SegmentationPolicy::flat();
+        assert_eq!(flat.far_target(0x1b, 0x401000), Ok(0x401000));
+
+        let mut checked = SegmentationPolicy::checked();
+        checked.add_descriptor(
+            0x18,
+            SegmentDescriptor::new(0x10000, 0xfff, Protection::READ_EXECUTE),
+        );
+        checked.add_descriptor(0x20, SegmentDescriptor::new(0, 0xffff, Protection::READ));
+
+        // RPL doesn't matter
+        assert_eq!(checked.far_target(0x1b, 0x100), Ok(0x10100));
+        assert_eq!(checked.far_target(0x18, 0xfff), Ok(0x10fff));
+        assert_eq!(
+            checked.far_target(0x18, 0x1000),
+            Err(GuestFault::GeneralProtection)
+        );
+        // not executable
+        assert_eq!(
+            checked.far_target(0x20, 0x100),
+            Err(GuestFault::GeneralProtection)
+        );
+        // not in the table
+        assert_eq!(
+            checked.far_target(0x28, 0x100),
+            Err(GuestFault::GeneralProtection)
+        );
+    }
+
+    #[test_log::test]
+    fn segments() {
+        let mut policy = SegmentationPolicy::checked();
+        assert_eq!(
+            policy.segment(SegmentRegister::DS),
+            Some(SegmentDescriptor::flat())
+        );
+        assert_eq!(policy.segment(SegmentRegister::FS), None);
+
+        let teb = SegmentDescriptor::new(0x7ffde000, 0xfff, Protection::READ_WRITE);
+        policy.set_segment(SegmentRegister::FS, teb);
+        assert_eq!(policy.segment(SegmentRegister::FS), Some(teb));
+    }
+}
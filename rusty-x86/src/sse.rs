@@ -0,0 +1,716 @@
+//! SSE: XMM registers and the instructions using them, and the MMX ones sharing the encodings
+//!
+//! The builders don't have 128-bit values, so an XMM register (or a 128-bit memory operand) is a pair of I64 halves,
+//! the low one first. Lanes never cross the halves, so most of the instructions just work on them separately.
+//! An MMX register is just like one of the halves
+
+use crate::backend::{Builder, ComparisonType, FloatComparisonType, IntValue, RoundingMode};
+use crate::fpu::{self, round_by_control};
+use crate::handler::GuestFault;
+use crate::ir::{Instr, Mnemonic};
+use crate::memory_image::Protection;
+use crate::types::{Flag, IntType, MemoryOperand, Operand};
+use crate::{operands, operands_ty};
+
+/// The low & the high halves
+pub type Halves<B> = [<B as Builder>::IntValue; 2];
+
+/// Raises #GP if the address is not aligned to `align` bytes and the alignment is enforced
+fn check_alignment<B: Builder>(builder: &mut B, address: B::IntValue, align: u32) {
+    if !builder.strict_alignment() {
+        return;
+    }
+
+    let low_bits = builder.int_and(address, builder.make_u32(align - 1));
+    let misaligned = builder.icmp(ComparisonType::NotEqual, low_bits, builder.make_u32(0));
+    builder.ifelse(
+        misaligned,
+        |builder| builder.raise_fault(GuestFault::GeneralProtection, address),
+        |_| {},
+    );
+}
+
+fn immediate8(operand: Operand) -> u8 {
+    match operand {
+        Operand::Immediate8(v) => v,
+        op => panic!("Expected an imm8, got {:?}", op),
+    }
+}
+
+/// Lane `lane` of a half split into `ty`-sized lanes
+fn extract_lane<B: Builder>(
+    builder: &mut B,
+    half: B::IntValue,
+    ty: IntType,
+    lane: u8,
+) -> B::IntValue {
+    if ty == IntType::I64 {
+        return half;
+    }
+    let shift = lane as u64 * ty.bit_width() as u64;
+    let shifted = builder.lshr(half, builder.make_u64(shift));
+    builder.trunc(shifted, ty)
+}
+
+/// Replaces lane `lane` of a half split into `value`-sized lanes
+fn insert_lane<B: Builder>(
+    builder: &mut B,
+    half: B::IntValue,
+    lane: u8,
+    value: B::IntValue,
+) -> B::IntValue {
+    let width = value.size().bit_width() as u64;
+    if width == 64 {
+        return value;
+    }
+    let shift = lane as u64 * width;
+    let mask = ((1u64 << width) - 1) << shift;
+    let cleared = builder.int_and(half, builder.make_u64(!mask));
+    let value = builder.zext(value, IntType::I64);
+    let value = builder.shl(value, builder.make_u64(shift));
+    builder.int_or(cleared, value)
+}
+
+/// Applies `f` to each pair of the `ty`-sized lanes of two halves
+fn map_half_lanes<B: Builder>(
+    builder: &mut B,
+    ty: IntType,
+    lhs: B::IntValue,
+    rhs: B::IntValue,
+    f: &impl Fn(&mut B, B::IntValue, B::IntValue) -> B::IntValue,
+) -> B::IntValue {
+    let mut res = lhs;
+    for lane in 0..64 / ty.bit_width() {
+        let l = extract_lane(builder, lhs, ty, lane);
+        let r = extract_lane(builder, rhs, ty, lane);
+        let value = f(builder, l, r);
+        res = insert_lane(builder, res, lane, value);
+    }
+    res
+}
+
+/// Applies `f` to each pair of the `ty`-sized lanes
+fn map_lanes<B: Builder>(
+    builder: &mut B,
+    ty: IntType,
+    lhs: Halves<B>,
+    rhs: Halves<B>,
+    f: impl Fn(&mut B, B::IntValue, B::IntValue) -> B::IntValue,
+) -> Halves<B> {
+    [
+        map_half_lanes(builder, ty, lhs[0], rhs[0], &f),
+        map_half_lanes(builder, ty, lhs[1], rhs[1], &f),
+    ]
+}
+
+/// The lowest `ty`-sized lane of an xmm register, or a memory operand as big as the lane
+fn load_scalar<B: Builder>(builder: &mut B, operand: Operand, ty: IntType) -> B::IntValue {
+    match operand {
+        Operand::Xmm(i) => {
+            let half = builder.load_xmm_half(i, false);
+            extract_lane(builder, half, ty, 0)
+        }
+        _ => builder.load_operand(operand),
+    }
+}
+
+/// Applies `f` to each `ty`-sized lane
+fn map_each_lane<B: Builder>(
+    builder: &mut B,
+    ty: IntType,
+    value: Halves<B>,
+    f: impl Fn(&mut B, B::IntValue) -> B::IntValue,
+) -> Halves<B> {
+    map_lanes(builder, ty, value, value, |builder, v, _| f(builder, v))
+}
+
+/// Applies `f` to the 64-bit chunks of `dst` & `src`: either the MMX registers (or 64-bit memory)
+/// or the halves of the XMM ones
+fn map_packed<B: Builder>(
+    builder: &mut B,
+    dst: Operand,
+    src: Operand,
+    f: impl Fn(&mut B, B::IntValue, B::IntValue) -> B::IntValue,
+) {
+    match dst {
+        Operand::Mmx(i) => {
+            let lhs = fpu::load_mmx(builder, i);
+            let rhs = match src {
+                Operand::Mmx(j) => fpu::load_mmx(builder, j),
+                _ => builder.load_operand(src),
+            };
+            let value = f(builder, lhs, rhs);
+            fpu::store_mmx(builder, i, value);
+        }
+        _ => {
+            let lhs = load_xmm_operand(builder, dst, false);
+            let rhs = load_xmm_operand(builder, src, true);
+            let res = [f(builder, lhs[0], rhs[0]), f(builder, lhs[1], rhs[1])];
+            store_xmm_operand(builder, dst, res, false, false);
+        }
+    }
+}
+
+/// The low `ty` bits of an MMX or XMM register, or a general-purpose register or memory
+fn load_low<B: Builder>(builder: &mut B, operand: Operand, ty: IntType) -> B::IntValue {
+    match operand {
+        Operand::Mmx(i) => {
+            let value = fpu::load_mmx(builder, i);
+            extract_lane(builder, value, ty, 0)
+        }
+        _ => load_scalar(builder, operand, ty),
+    }
+}
+
+/// The opposite of `load_low`, zeroing the rest of the MMX or XMM register
+fn store_low<B: Builder>(builder: &mut B, operand: Operand, value: B::IntValue) {
+    let widened = match value.size() {
+        IntType::I64 => value,
+        _ => builder.zext(value, IntType::I64),
+    };
+    match operand {
+        Operand::Mmx(i) => fpu::store_mmx(builder, i, widened),
+        Operand::Xmm(_) => {
+            let res = [widened, builder.make_u64(0)];
+            store_xmm_operand(builder, operand, res, false, false);
+        }
+        _ => builder.store_operand(operand, value),
+    }
+}
+
+/// Applies `f` to the lowest `ty`-sized lanes of `dst` & `src`, keeping the rest of `dst`
+fn map_scalar<B: Builder>(
+    builder: &mut B,
+    ty: IntType,
+    dst: Operand,
+    src: Operand,
+    f: impl Fn(&mut B, B::IntValue, B::IntValue) -> B::IntValue,
+) {
+    let mut halves = load_xmm_operand(builder, dst, false);
+    let lhs = extract_lane(builder, halves[0], ty, 0);
+    let rhs = load_scalar(builder, src, ty);
+    let value = f(builder, lhs, rhs);
+    halves[0] = insert_lane(builder, halves[0], 0, value);
+    store_xmm_operand(builder, dst, halves, false, false);
+}
+
+/// The four words of a half picked by the 2-bit fields of `order`
+fn shuffle_words<B: Builder>(builder: &mut B, half: B::IntValue, order: u8) -> B::IntValue {
+    let mut res = builder.make_u64(0);
+    for lane in 0..4 {
+        let word = extract_lane(builder, half, IntType::I16, (order >> (lane * 2)) & 0b11);
+        res = insert_lane(builder, res, lane, word);
+    }
+    res
+}
+
+fn memory_address<B: Builder>(
+    builder: &mut B,
+    op: MemoryOperand,
+    access: Protection,
+    aligned: bool,
+) -> B::IntValue {
+    let address = builder.compute_linear_address(op, access);
+    if aligned {
+        check_alignment(builder, address, op.size.unwrap().byte_width() as u32);
+    }
+    address
+}
+
+pub fn load_xmm_operand<B: Builder>(builder: &mut B, operand: Operand, aligned: bool) -> Halves<B> {
+    match operand {
+        Operand::Xmm(i) => [
+            builder.load_xmm_half(i, false),
+            builder.load_xmm_half(i, true),
+        ],
+        Operand::Memory(op) => {
+            let address = memory_address(builder, op, Protection::READ, aligned);
+            let high_address = builder.add(address, builder.make_u32(8));
+            [
+                builder.load_memory(IntType::I64, address),
+                builder.load_memory(IntType::I64, high_address),
+            ]
+        }
+        op => panic!("Unsupported xmm operand: {:?}", op),
+    }
+}
+
+/// `nontemporal` only matters for the memory
+pub fn store_xmm_operand<B: Builder>(
+    builder: &mut B,
+    operand: Operand,
+    value: Halves<B>,
+    aligned: bool,
+    nontemporal: bool,
+) {
+    match operand {
+        Operand::Xmm(i) => {
+            builder.store_xmm_half(i, false, value[0]);
+            builder.store_xmm_half(i, true, value[1]);
+        }
+        Operand::Memory(op) => {
+            let address = memory_address(builder, op, Protection::WRITE, aligned);
+            let high_address = builder.add(address, builder.make_u32(8));
+            if nontemporal {
+                builder.store_memory_nontemporal(address, value[0]);
+                builder.store_memory_nontemporal(high_address, value[1]);
+            } else {
+                builder.store_memory(address, value[0]);
+                builder.store_memory(high_address, value[1]);
+            }
+        }
+        op => panic!("Unsupported xmm operand: {:?}", op),
+    }
+}
+
+const MXCSR_RC_SHIFT: u32 = 13;
+
+/// The rounding control field of MXCSR
+fn mxcsr_rounding<B: Builder>(builder: &mut B) -> B::IntValue {
+    let mxcsr = builder.load_mxcsr();
+    let rc = builder.lshr(mxcsr, builder.make_u32(MXCSR_RC_SHIFT));
+    builder.int_and(rc, builder.make_u32(0b11))
+}
+
+/// Rounds as selected by `rc` (see `mxcsr_rounding`), truncates if there is none
+fn round_for_conversion<B: Builder>(
+    builder: &mut B,
+    value: B::IntValue,
+    rc: Option<B::IntValue>,
+) -> B::IntValue {
+    match rc {
+        Some(rc) => round_by_control(builder, value, rc),
+        None => builder.float_round(value, RoundingMode::Zero),
+    }
+}
+
+/// A float or a double (already integral) -> i32, giving the "integer indefinite" 0x80000000
+/// if it doesn't fit (including NaNs)
+fn to_i32_or_indefinite<B: Builder>(builder: &mut B, value: B::IntValue) -> B::IntValue {
+    let float_const = |builder: &mut B, v: f64| match value.size() {
+        IntType::I32 => builder.make_u32((v as f32).to_bits()),
+        _ => builder.make_u64(v.to_bits()),
+    };
+    let min = float_const(builder, i32::MIN as f64);
+    let max = float_const(builder, -(i32::MIN as f64));
+
+    // the comparisons are false for NaNs
+    let below = builder.fcmp(FloatComparisonType::Less, value, min);
+    let not_below = builder.bool_not(below);
+    let fits = builder.fcmp(FloatComparisonType::Less, value, max);
+    let fits = builder.bool_and(fits, not_below);
+    let unordered = builder.fcmp(FloatComparisonType::Unordered, value, value);
+    let ordered = builder.bool_not(unordered);
+    let fits = builder.bool_and(fits, ordered);
+
+    let converted = builder.float_to_int(value, IntType::I32);
+    builder.select(fits, converted, builder.make_u32(0x80000000))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LaneShift {
+    Left,
+    Right,
+    Arithmetic,
+}
+
+fn lane_shift(mnemonic: Mnemonic) -> (IntType, LaneShift) {
+    use Mnemonic::*;
+    match mnemonic {
+        Psllw => (IntType::I16, LaneShift::Left),
+        Pslld => (IntType::I32, LaneShift::Left),
+        Psllq => (IntType::I64, LaneShift::Left),
+        Psrlw => (IntType::I16, LaneShift::Right),
+        Psrld => (IntType::I32, LaneShift::Right),
+        Psrlq => (IntType::I64, LaneShift::Right),
+        Psraw => (IntType::I16, LaneShift::Arithmetic),
+        Psrad => (IntType::I32, LaneShift::Arithmetic),
+        m => panic!("Not a packed shift: {:?}", m),
+    }
+}
+
+/// Shifts each `ty`-sized lane by an I64 `count`. Unlike the scalar shifts, the count is not masked:
+/// shifting by the lane width or more gives zeros (or the sign bits for the arithmetic shift)
+fn shift_lanes<B: Builder>(
+    builder: &mut B,
+    ty: IntType,
+    shift: LaneShift,
+    value: Halves<B>,
+    count: B::IntValue,
+) -> Halves<B> {
+    let width = ty.bit_width() as u64;
+    let too_big = builder.icmp(
+        ComparisonType::UnsignedGreaterOrEqual,
+        count,
+        builder.make_u64(width),
+    );
+    // keep the amount in range, the result is replaced (or is the right one) if it's clamped
+    let amount = match shift {
+        LaneShift::Arithmetic => builder.select(too_big, builder.make_u64(width - 1), count),
+        _ => builder.int_and(count, builder.make_u64(width - 1)),
+    };
+    let amount = match ty {
+        IntType::I64 => amount,
+        _ => builder.trunc(amount, ty),
+    };
+    let zero = builder.make_int_value(ty, 0, false);
+
+    map_each_lane(builder, ty, value, |builder, lane| match shift {
+        LaneShift::Left => {
+            let shifted = builder.shl(lane, amount);
+            builder.select(too_big, zero, shifted)
+        }
+        LaneShift::Right => {
+            let shifted = builder.lshr(lane, amount);
+            builder.select(too_big, zero, shifted)
+        }
+        LaneShift::Arithmetic => builder.ashr(lane, amount),
+    })
+}
+
+/// Shifts the whole 128 bits by `bytes` bytes to the left (towards the high half) or to the right
+fn shift_bytes<B: Builder>(builder: &mut B, value: Halves<B>, bytes: u8, left: bool) -> Halves<B> {
+    let bits = bytes as u64 * 8;
+    let zero = builder.make_u64(0);
+    // (the one the bits move from, the one they move to)
+    let (from, to) = if left { (0, 1) } else { (1, 0) };
+    let shift = |builder: &mut B, v, amount| {
+        if left {
+            builder.shl(v, builder.make_u64(amount))
+        } else {
+            builder.lshr(v, builder.make_u64(amount))
+        }
+    };
+    let unshift = |builder: &mut B, v, amount| {
+        if left {
+            builder.lshr(v, builder.make_u64(amount))
+        } else {
+            builder.shl(v, builder.make_u64(amount))
+        }
+    };
+
+    let mut res = [zero; 2];
+    match bits {
+        0 => return value,
+        1..=63 => {
+            res[from] = shift(builder, value[from], bits);
+            let kept = shift(builder, value[to], bits);
+            let carried = unshift(builder, value[from], 64 - bits);
+            res[to] = builder.int_or(kept, carried);
+        }
+        64..=127 => res[to] = shift(builder, value[from], bits - 64),
+        _ => {}
+    }
+    res
+}
+
+/// Narrows the signed `from`-sized lanes to `to`, clamping them to `[min, max]` (also signed).
+/// The lanes of `lhs` go to the low half of the result & the ones of `rhs` go to the high one
+fn pack_saturated<B: Builder>(
+    builder: &mut B,
+    from: IntType,
+    to: IntType,
+    (min, max): (i64, i64),
+    lhs: Halves<B>,
+    rhs: Halves<B>,
+) -> Halves<B> {
+    let min = builder.make_int_value(from, min as u64, false);
+    let max = builder.make_int_value(from, max as u64, false);
+    let lanes = 64 / from.bit_width();
+
+    let inputs = [lhs, rhs];
+    let mut res = [builder.make_u64(0); 2];
+    for (half, input) in res.iter_mut().zip(inputs) {
+        for (i, input_half) in input.into_iter().enumerate() {
+            for lane in 0..lanes {
+                let v = extract_lane(builder, input_half, from, lane);
+                let below = builder.icmp(ComparisonType::SignedLess, v, min);
+                let above = builder.icmp(ComparisonType::SignedGreater, v, max);
+                let v = builder.select(below, min, v);
+                let v = builder.select(above, max, v);
+                let v = builder.trunc(v, to);
+                *half = insert_lane(builder, *half, i as u8 * lanes + lane, v);
+            }
+        }
+    }
+    res
+}
+
+/// The lane type & the comparison for min/max
+fn float_lanes(mnemonic: Mnemonic) -> (IntType, FloatComparisonType) {
+    use Mnemonic::*;
+    let ty = match mnemonic {
+        Maxss | Minss | Maxps | Minps => IntType::I32,
+        _ => IntType::I64,
+    };
+    let op = match mnemonic {
+        Maxss | Maxsd | Maxps | Maxpd => FloatComparisonType::Greater,
+        _ => FloatComparisonType::Less,
+    };
+    (ty, op)
+}
+
+/// `lhs op rhs ? lhs : rhs`: a NaN on either side or a pair of zeros gives `rhs`,
+/// which is not what the IEEE minNum/maxNum (or the LLVM intrinsics) do
+fn min_max<B: Builder>(
+    builder: &mut B,
+    op: FloatComparisonType,
+    lhs: B::IntValue,
+    rhs: B::IntValue,
+) -> B::IntValue {
+    let pick_lhs = builder.fcmp(op, lhs, rhs);
+    builder.select(pick_lhs, lhs, rhs)
+}
+
+pub fn codegen_sse_instr<B: Builder>(builder: &mut B, instr: &Instr) {
+    use Mnemonic::*;
+
+    if instr
+        .operands
+        .iter()
+        .any(|op| matches!(op, Operand::Mmx(_)))
+    {
+        fpu::enter_mmx(builder);
+    }
+
+    match instr.mnemonic {
+        Movnti => {
+            operands!([dst, src], instr);
+
+            let value = builder.load_operand(src);
+            let address = match dst {
+                Operand::Memory(op) => builder.compute_linear_address(op, Protection::WRITE),
+                op => panic!("Expected movnti destination to be memory, got {:?}", op),
+            };
+            builder.store_memory_nontemporal(address, value);
+        }
+        Movntps | Movntpd | Movntdq => {
+            operands!([dst, src], instr);
+
+            let value = load_xmm_operand(builder, src, false);
+            store_xmm_operand(builder, dst, value, true, true);
+        }
+        Pextrw => {
+            operands!([dst, src, lane], instr);
+
+            // only the low 3 bits select the lane
+            let lane = immediate8(lane) & 7;
+            let halves = load_xmm_operand(builder, src, false);
+            let word = extract_lane(builder, halves[lane as usize / 4], IntType::I16, lane % 4);
+            let word = match dst.size() {
+                IntType::I16 => word,
+                size => builder.zext(word, size),
+            };
+            builder.store_operand(dst, word);
+        }
+        Pinsrw => {
+            operands!([dst, src, lane], instr);
+
+            let lane = immediate8(lane) & 7;
+            // the register form takes the low word of a 32-bit register
+            let word = builder.load_operand(src);
+            let word = match word.size() {
+                IntType::I16 => word,
+                _ => builder.trunc(word, IntType::I16),
+            };
+            let mut halves = load_xmm_operand(builder, dst, false);
+            let half = lane as usize / 4;
+            halves[half] = insert_lane(builder, halves[half], lane % 4, word);
+            store_xmm_operand(builder, dst, halves, false, false);
+        }
+        Pshuflw | Pshufhw => {
+            operands!([dst, src, order], instr);
+
+            let order = immediate8(order);
+            let mut halves = load_xmm_operand(builder, src, true);
+            let half = if instr.mnemonic == Pshuflw { 0 } else { 1 };
+            halves[half] = shuffle_words(builder, halves[half], order);
+            store_xmm_operand(builder, dst, halves, false, false);
+        }
+        Maxss | Maxsd | Minss | Minsd => {
+            operands!([dst, src], instr);
+
+            let (ty, op) = float_lanes(instr.mnemonic);
+            map_scalar(builder, ty, dst, src, |builder, l, r| {
+                min_max(builder, op, l, r)
+            });
+        }
+        Maxps | Maxpd | Minps | Minpd => {
+            operands!([dst, src], instr);
+
+            let (ty, op) = float_lanes(instr.mnemonic);
+            let lhs = load_xmm_operand(builder, dst, false);
+            let rhs = load_xmm_operand(builder, src, true);
+            let res = map_lanes(builder, ty, lhs, rhs, |builder, l, r| {
+                min_max(builder, op, l, r)
+            });
+            store_xmm_operand(builder, dst, res, false, false);
+        }
+        // the MXCSR rounding is not applied here: the default (nearest) is the only mode supported
+        Cvtdq2ps => {
+            operands!([dst, src], instr);
+
+            let value = load_xmm_operand(builder, src, true);
+            let res = map_each_lane(builder, IntType::I32, value, |builder, v| {
+                builder.int_to_float(v, IntType::I32)
+            });
+            store_xmm_operand(builder, dst, res, false, false);
+        }
+        Cvtps2dq | Cvttps2dq => {
+            operands!([dst, src], instr);
+
+            let rc = (instr.mnemonic == Cvtps2dq).then(|| mxcsr_rounding(builder));
+            let value = load_xmm_operand(builder, src, true);
+            let res = map_each_lane(builder, IntType::I32, value, |builder, v| {
+                let v = round_for_conversion(builder, v, rc);
+                to_i32_or_indefinite(builder, v)
+            });
+            store_xmm_operand(builder, dst, res, false, false);
+        }
+        // int -> double is always exact
+        Cvtdq2pd => {
+            operands!([dst, src], instr);
+
+            // the low two lanes, the memory operand is only 64 bits
+            let low = match src {
+                Operand::Xmm(i) => builder.load_xmm_half(i, false),
+                _ => builder.load_operand(src),
+            };
+            let mut res = [low; 2];
+            for (lane, half) in res.iter_mut().enumerate() {
+                let v = extract_lane(builder, low, IntType::I32, lane as u8);
+                *half = builder.int_to_float(v, IntType::I64);
+            }
+            store_xmm_operand(builder, dst, res, false, false);
+        }
+        Cvtpd2dq | Cvttpd2dq => {
+            operands!([dst, src], instr);
+
+            let rc = (instr.mnemonic == Cvtpd2dq).then(|| mxcsr_rounding(builder));
+            let value = load_xmm_operand(builder, src, true);
+            // the results go to the low half, the high one is zeroed
+            let mut low = builder.make_u64(0);
+            for (lane, v) in value.into_iter().enumerate() {
+                let v = round_for_conversion(builder, v, rc);
+                let v = to_i32_or_indefinite(builder, v);
+                low = insert_lane(builder, low, lane as u8, v);
+            }
+            let res = [low, builder.make_u64(0)];
+            store_xmm_operand(builder, dst, res, false, false);
+        }
+        Ldmxcsr => {
+            operands!([src], instr);
+            let value = builder.load_operand(src);
+            builder.store_mxcsr(value);
+        }
+        Stmxcsr => {
+            operands!([dst], instr);
+            let value = builder.load_mxcsr();
+            builder.store_operand(dst, value);
+        }
+        Psllw | Pslld | Psllq | Psrlw | Psrld | Psrlq | Psraw | Psrad => {
+            operands!([dst, count], instr);
+
+            let (ty, shift) = lane_shift(instr.mnemonic);
+            // the whole low 64 bits of the count operand, not just the low byte
+            let count = match count {
+                Operand::Immediate8(v) => builder.make_u64(v as u64),
+                _ => load_xmm_operand(builder, count, true)[0],
+            };
+            let value = load_xmm_operand(builder, dst, false);
+            let res = shift_lanes(builder, ty, shift, value, count);
+            store_xmm_operand(builder, dst, res, false, false);
+        }
+        Pslldq | Psrldq => {
+            operands!([dst, count], instr);
+
+            // anything above 15 clears the register
+            let bytes = immediate8(count).min(16);
+            let value = load_xmm_operand(builder, dst, false);
+            let res = shift_bytes(builder, value, bytes, instr.mnemonic == Pslldq);
+            store_xmm_operand(builder, dst, res, false, false);
+        }
+        Packsswb | Packuswb | Packssdw => {
+            operands!([dst, src], instr);
+
+            let (from, to, range) = match instr.mnemonic {
+                Packsswb => (IntType::I16, IntType::I8, (i8::MIN as i64, i8::MAX as i64)),
+                Packuswb => (IntType::I16, IntType::I8, (0, u8::MAX as i64)),
+                _ => (
+                    IntType::I32,
+                    IntType::I16,
+                    (i16::MIN as i64, i16::MAX as i64),
+                ),
+            };
+            let lhs = load_xmm_operand(builder, dst, false);
+            let rhs = load_xmm_operand(builder, src, true);
+            let res = pack_saturated(builder, from, to, range, lhs, rhs);
+            store_xmm_operand(builder, dst, res, false, false);
+        }
+        Emms => {
+            operands!([], instr);
+            fpu::emms(builder);
+        }
+        Movd | Movq => {
+            operands!([dst, src], instr);
+
+            let ty = if instr.mnemonic == Movd {
+                IntType::I32
+            } else {
+                IntType::I64
+            };
+            let value = load_low(builder, src, ty);
+            store_low(builder, dst, value);
+        }
+        Paddb | Paddw => {
+            operands!([dst, src], instr);
+
+            let ty = if instr.mnemonic == Paddb {
+                IntType::I8
+            } else {
+                IntType::I16
+            };
+            map_packed(builder, dst, src, |builder, l, r| {
+                map_half_lanes(builder, ty, l, r, &|builder, l, r| builder.add(l, r))
+            });
+        }
+        Pand => {
+            operands!([dst, src], instr);
+            map_packed(builder, dst, src, |builder, l, r| builder.int_and(l, r));
+        }
+        Por => {
+            operands!([dst, src], instr);
+            map_packed(builder, dst, src, |builder, l, r| builder.int_or(l, r));
+        }
+        Pxor => {
+            operands!([dst, src], instr);
+            map_packed(builder, dst, src, |builder, l, r| builder.int_xor(l, r));
+        }
+        // no exceptions are modeled, so the signaling compares are the same as the quiet ones
+        Ucomiss | Comiss | Ucomisd | Comisd => {
+            operands!([lhs, rhs], instr);
+
+            let ty = match instr.mnemonic {
+                Ucomiss | Comiss => IntType::I32,
+                _ => IntType::I64,
+            };
+            let lhs = load_scalar(builder, lhs, ty);
+            let rhs = load_scalar(builder, rhs, ty);
+
+            let unordered = builder.fcmp(FloatComparisonType::Unordered, lhs, rhs);
+            let less = builder.fcmp(FloatComparisonType::Less, lhs, rhs);
+            let equal = builder.fcmp(FloatComparisonType::Equal, lhs, rhs);
+
+            // unordered: ZF PF CF = 111, greater: 000, less: 001, equal: 100
+            let zf = builder.bool_or(unordered, equal);
+            let cf = builder.bool_or(unordered, less);
+            builder.store_flag(Flag::Zero, zf);
+            builder.store_flag(Flag::Parity, unordered);
+            builder.store_flag(Flag::Carry, cf);
+            for flag in [Flag::Overflow, Flag::AuxiliaryCarry, Flag::Sign] {
+                builder.store_flag(flag, builder.make_false());
+            }
+        }
+        m => panic!("Not an SSE instruction: {:?}", m),
+    }
+}
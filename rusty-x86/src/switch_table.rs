@@ -0,0 +1,314 @@
+//! Jump tables of the compiled `switch`es, resolved at the translation time
+//!
+//! A dense `switch` is lowered by the compilers into a bounds check & a jump through a table of addresses:
+//!
+//! ```text
+//! cmp eax, 4
+//! ja .default
+//! jmp dword [.table + eax * 4]
+//! ```
+//!
+//! The table usually lives next to the code in memory the guest can't write, so the targets can be read from the
+//! image and the `jmp` turned into `JmpTable`: a switch over the index with a direct jump to every case. The cases
+//! get translated along with the switch & chained to it instead of going through the dispatcher. An index past the
+//! end of the table still takes the jump through the memory, so nothing changes if the bounds check was lying.
+//!
+//! Anything that doesn't look exactly like that - no bounds check shortly before the jmp, the index written in
+//! between, a table in writable memory, an entry outside of the executable code - keeps the plain indirect jump
+
+use crate::ir::{Condition, Instr, Mnemonic};
+use crate::memory_image::MemoryImage;
+use crate::peephole::CompilationStats;
+use crate::segmentation::{default_segment, SegmentationMode, SegmentationPolicy};
+use crate::types::{IntType, MemoryOperand, Operand, Register};
+
+/// How many instructions the bounds check can be away from the `jmp`
+const WINDOW: usize = 4;
+/// Larger tables are left to the indirect jump
+pub const MAX_CASES: u32 = 1024;
+
+/// Rewrites the jump through a switch table ending the block into `JmpTable`
+pub fn resolve_tables(
+    mut block: Vec<Instr>,
+    image: &MemoryImage,
+    segmentation: &SegmentationPolicy,
+    stats: &mut CompilationStats,
+) -> Vec<Instr> {
+    let cases = match block.split_last() {
+        Some((jmp, preceding)) => resolve(jmp, preceding, image, segmentation),
+        None => None,
+    };
+    if let Some(cases) = cases {
+        let jmp = block.last_mut().unwrap();
+        jmp.mnemonic = Mnemonic::JmpTable;
+        jmp.operands
+            .extend(cases.into_iter().map(Operand::Immediate32));
+        stats.switch_tables += 1;
+    }
+    block
+}
+
+/// The cases of a `JmpTable`, in the order of the table (nothing for the other instructions)
+pub fn targets(instr: &Instr) -> Vec<u32> {
+    match (instr.mnemonic, instr.operands.split_first()) {
+        (Mnemonic::JmpTable, Some((_, cases))) => {
+            cases.iter().map(|&target| target.as_imm32()).collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn resolve(
+    jmp: &Instr,
+    preceding: &[Instr],
+    image: &MemoryImage,
+    segmentation: &SegmentationPolicy,
+) -> Option<Vec<u32>> {
+    let (table, index) = match (jmp.mnemonic, jmp.operands.as_slice()) {
+        (
+            Mnemonic::Jmp,
+            [Operand::Memory(
+                table @ MemoryOperand {
+                    base: None,
+                    index: Some(index),
+                    scale: 4,
+                    ..
+                },
+            )],
+        ) if index.size() == IntType::I32 => (table, *index),
+        _ => return None,
+    };
+
+    // the table address is the displacement only if the segment doesn't move it
+    let segment = table.segment.unwrap_or_else(|| default_segment(table));
+    let flat = segmentation.mode == SegmentationMode::Flat
+        && segmentation
+            .segment(segment)
+            .is_some_and(|descriptor| descriptor.base == 0);
+    if !flat {
+        return None;
+    }
+
+    let count = bound(preceding, index)?;
+    let entries = image
+        .read_only_at(table.displacement as u32)
+        .get(..count as usize * 4)?;
+    entries
+        .chunks_exact(4)
+        .map(|entry| {
+            let target = u32::from_le_bytes(entry.try_into().unwrap());
+            (!image.execute_all_at(target).is_empty()).then_some(target)
+        })
+        .collect()
+}
+
+/// The number of the table entries the bounds check on `index` before the `jmp` lets through
+fn bound(preceding: &[Instr], index: Register) -> Option<u32> {
+    for (i, instr) in preceding.iter().enumerate().rev().take(WINDOW) {
+        match (instr.mnemonic, instr.operands.as_slice()) {
+            (
+                Mnemonic::CmpJcc(condition),
+                [Operand::Register(register), Operand::Immediate32(limit), _],
+            ) if *register == index => return case_count(condition, *limit),
+            (Mnemonic::Jcc(condition), _) => {
+                let cmp = preceding[..i].last()?;
+                return match (cmp.mnemonic, cmp.operands.as_slice()) {
+                    (Mnemonic::Cmp, [Operand::Register(register), Operand::Immediate32(limit)])
+                        if *register == index =>
+                    {
+                        case_count(condition, *limit)
+                    }
+                    _ => None,
+                };
+            }
+            _ if keeps_register(instr, index) => {}
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// The branch to the default case is taken above the limit, the `jmp` is on the fallthrough
+fn case_count(condition: Condition, limit: u32) -> Option<u32> {
+    let count = match condition {
+        Condition::A => limit.checked_add(1)?,
+        Condition::AE => limit,
+        _ => return None,
+    };
+    (1..=MAX_CASES).contains(&count).then_some(count)
+}
+
+/// The instructions a compiler might schedule in between the bounds check & the `jmp`
+fn keeps_register(instr: &Instr, register: Register) -> bool {
+    use Mnemonic::*;
+    match (instr.mnemonic, instr.operands.as_slice()) {
+        (Mov | Movzx | Movsx | Lea, [Operand::Register(dst), _]) => {
+            dst.base_register() != register.base_register()
+        }
+        (Mov, [Operand::Memory(_), _]) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve_tables, targets};
+    use crate::assemble_x86;
+    use crate::ir::{
+        decode_block, CodeMode, Instr, InvalidOpcodePolicy, UnsupportedInstructionPolicy,
+    };
+    use crate::memory_image::{MemoryImage, Protection};
+    use crate::peephole::{self, CompilationStats};
+    use crate::segmentation::{SegmentDescriptor, SegmentationPolicy};
+    use crate::types::SegmentRegister;
+
+    const CODE_ADDR: u32 = 0x1000;
+    const TABLE_ADDR: u32 = 0x2000;
+
+    fn image(code: &[u8], table: &[u32], table_protection: Protection) -> MemoryImage {
+        let mut image = MemoryImage::new();
+        image.add_region(CODE_ADDR, Protection::READ_EXECUTE, code.to_vec());
+        let table = table.iter().flat_map(|entry| entry.to_le_bytes()).collect();
+        image.add_region(TABLE_ADDR, table_protection, table);
+        image
+    }
+
+    fn decode(image: &MemoryImage) -> Vec<Instr> {
+        decode_block(
+            image.execute_all_at(CODE_ADDR),
+            CODE_ADDR,
+            usize::MAX,
+            InvalidOpcodePolicy::Fault,
+            UnsupportedInstructionPolicy::Fail,
+            CodeMode::Bits32,
+        )
+        .unwrap()
+    }
+
+    /// The last instruction of the block after the pass & the number of the resolved tables
+    fn resolved(
+        image: &MemoryImage,
+        peephole: bool,
+        segmentation: &SegmentationPolicy,
+    ) -> (Instr, usize) {
+        let mut block = decode(image);
+        let mut stats = CompilationStats::default();
+        if peephole {
+            block = peephole::optimize(block, &mut stats);
+        }
+        let block = resolve_tables(block, image, segmentation, &mut stats);
+        (block.last().unwrap().clone(), stats.switch_tables)
+    }
+
+    /// The cases are `ret`s at `CODE_ADDR + 0x20..`
+    fn switch(limit: i32) -> Vec<u8> {
+        let mut code = assemble_x86!(
+            ; cmp ecx, BYTE limit
+            ; ja ->default
+            ; mov edx, [esp + 4]
+            ; jmp DWORD [ecx * 4 + TABLE_ADDR as i32]
+            ; ->default:
+            ; ret
+        );
+        code.resize(0x40, 0xc3);
+        code
+    }
+
+    #[test_log::test]
+    fn resolves() {
+        let cases = [0x1020, 0x1021, 0x1022, 0x1023, 0x1024];
+        let image = image(&switch(4), &cases, Protection::READ);
+
+        for peephole in [false, true] {
+            let (jmp, count) = resolved(&image, peephole, &SegmentationPolicy::flat());
+            assert_eq!(count, 1);
+            assert_eq!(targets(&jmp), cases);
+        }
+    }
+
+    #[test_log::test]
+    fn falls_back() {
+        let cases = [0x1020, 0x1021, 0x1022];
+        let flat = SegmentationPolicy::flat();
+        let unchanged = |image: &MemoryImage, segmentation: &SegmentationPolicy| {
+            let (jmp, count) = resolved(image, true, segmentation);
+            assert_eq!(count, 0);
+            assert_eq!(jmp.to_string(), "jmp dword [ecx*4+0x2000]");
+        };
+
+        // the guest might rewrite the table
+        unchanged(&image(&switch(2), &cases, Protection::READ_WRITE), &flat);
+        // a case outside of the code
+        unchanged(
+            &image(&switch(2), &[0x1020, 0x5000, 0x1022], Protection::READ),
+            &flat,
+        );
+        // the table is shorter than the bounds check says
+        unchanged(&image(&switch(3), &cases, Protection::READ), &flat);
+        // DS moves the table
+        let mut based = SegmentationPolicy::flat();
+        based.set_segment(
+            SegmentRegister::DS,
+            SegmentDescriptor::new(0x100, u32::MAX, Protection::READ_WRITE),
+        );
+        unchanged(&image(&switch(2), &cases, Protection::READ), &based);
+
+        // no bounds check at all
+        let code = assemble_x86!(
+            ; jmp DWORD [ecx * 4 + TABLE_ADDR as i32]
+        );
+        unchanged(&image(&code, &cases, Protection::READ), &flat);
+        // the index is changed after the check
+        let code = assemble_x86!(
+            ; cmp ecx, 2
+            ; ja ->default
+            ; lea ecx, [ecx + 1]
+            ; jmp DWORD [ecx * 4 + TABLE_ADDR as i32]
+            ; ->default:
+            ; ret
+        );
+        unchanged(&image(&code, &cases, Protection::READ), &flat);
+        // a signed check lets the negative indices through
+        let code = assemble_x86!(
+            ; cmp ecx, 2
+            ; jg ->default
+            ; jmp DWORD [ecx * 4 + TABLE_ADDR as i32]
+            ; ->default:
+            ; ret
+        );
+        unchanged(&image(&code, &cases, Protection::READ), &flat);
+    }
+
+    #[cfg(feature = "interp")]
+    #[test_log::test]
+    fn executes() {
+        use crate::handler::NullHandler;
+        use crate::interp::{Interpreter, StepResult};
+        use crate::types::FullSizeGeneralPurposeRegister::ECX;
+
+        let cases = [0x1020, 0x1024, 0x1028, 0x102c, 0x1030];
+        // the bounds check only lets the first three through, the last two are reached through the memory
+        let image = image(&switch(2), &cases, Protection::READ);
+        let original = decode(&image).pop().unwrap();
+        let (jmp, count) = resolved(&image, false, &SegmentationPolicy::flat());
+        assert_eq!(count, 1);
+        assert_eq!(targets(&jmp), cases[..3]);
+
+        let mut memory = vec![0; 0x3000];
+        for (i, case) in cases.iter().enumerate() {
+            let entry = TABLE_ADDR as usize + i * 4;
+            memory[entry..entry + 4].copy_from_slice(&case.to_le_bytes());
+        }
+        let target = |jmp: &Instr, index: u32| {
+            let mut interp = Interpreter::new(memory.clone(), NullHandler);
+            interp.context.set_gp_reg(ECX, index);
+            assert_eq!(interp.execute(jmp), StepResult::Continue);
+            interp.context.eip
+        };
+        for index in 0..8 {
+            let expected = cases.get(index as usize).copied().unwrap_or(0);
+            assert_eq!(target(&original, index), expected);
+            assert_eq!(target(&jmp, index), expected, "index {}", index);
+        }
+    }
+}
@@ -0,0 +1,155 @@
+//! What the guest sees in the control registers & the MSRs
+//!
+//! There is no paging or protection to control, so `mov eax, cr0`, `smsw`, `rdmsr` & friends read the constants
+//! the embedder puts in the profile, and the writes go nowhere (or to `RuntimeHandler::control_register_write` &
+//! `RuntimeHandler::msr_write`):
+//!
+//! ```ignore
+//! let profile = SystemRegisterProfile {
+//!     writes: ControlRegisterWrites::Record,
+//!     ..SystemRegisterProfile::default()
+//! };
+//! let config = Recompiler::builder().system_registers(profile).build()?;
+//! ```
+//!
+//! Everything is known at translation time, so a read is just a constant (or a few selects for `rdmsr`, the index
+//! of which comes from ECX)
+
+use std::collections::BTreeMap;
+
+/// Protection Enable
+pub const CR0_PE: u32 = 1 << 0;
+/// Extension Type (always set since the 486)
+pub const CR0_ET: u32 = 1 << 4;
+/// Numeric Error: x87 errors are reported with #MF
+pub const CR0_NE: u32 = 1 << 5;
+/// Write Protect
+pub const CR0_WP: u32 = 1 << 16;
+/// Paging
+pub const CR0_PG: u32 = 1 << 31;
+
+/// Performance-Monitoring Counter Enable: `rdpmc` is allowed in user mode
+pub const CR4_PCE: u32 = 1 << 8;
+
+/// The TSC frequency probes go for this one
+pub const MSR_PLATFORM_INFO: u32 = 0xce;
+
+/// The bits of CR0 `lmsw` can change (PE can be set, but not cleared)
+pub const MSW_WRITABLE: u32 = 0xf;
+
+/// What happens when the guest writes a control register (`mov cr0, eax`, `lmsw`) or an MSR (`wrmsr`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ControlRegisterWrites {
+    /// The write is dropped
+    #[default]
+    Ignore,
+    /// The value is passed to `RuntimeHandler::control_register_write` (`RuntimeHandler::msr_write`).
+    /// The reads still see the profile
+    Record,
+    /// #GP, as if the guest wasn't allowed to do that
+    Fault,
+}
+
+/// What `rdmsr` gives for the MSRs not in `SystemRegisterProfile::msrs`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnknownMsrReads {
+    /// Zero, so that the probing code gets an answer
+    #[default]
+    Zero,
+    /// #GP, like the hardware does for the MSRs it doesn't have
+    Fault,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemRegisterProfile {
+    /// The low word of it is the MSW (what `smsw` reads)
+    pub cr0: u32,
+    /// The last page fault address
+    pub cr2: u32,
+    pub cr3: u32,
+    pub cr4: u32,
+    /// What `rdmsr` reads, by the index
+    pub msrs: BTreeMap<u32, u64>,
+    pub unknown_msrs: UnknownMsrReads,
+    /// What `rdpmc` reads, whatever the counter. Always the same, so that the runs are reproducible
+    pub performance_counter: u64,
+    pub writes: ControlRegisterWrites,
+    /// Run as CPL 3: everything privileged (`mov` to & from the control registers, `lmsw`, `rdmsr`, `wrmsr`)
+    /// raises #GP regardless of `writes`. `smsw` is still allowed, like on the hardware without UMIP, and so is
+    /// `rdpmc` if `cr4` has `CR4_PCE`
+    pub user_mode: bool,
+}
+
+impl Default for SystemRegisterProfile {
+    /// A protected mode OS with paging
+    fn default() -> Self {
+        Self {
+            cr0: CR0_PG | CR0_WP | CR0_NE | CR0_ET | CR0_PE,
+            cr2: 0,
+            cr3: 0,
+            cr4: 0,
+            msrs: BTreeMap::new(),
+            unknown_msrs: UnknownMsrReads::default(),
+            performance_counter: 0,
+            writes: ControlRegisterWrites::default(),
+            user_mode: false,
+        }
+    }
+}
+
+impl SystemRegisterProfile {
+    /// Contents of CRn, `None` for the ones that don't exist (the access is #UD)
+    pub fn read(&self, register: u8) -> Option<u32> {
+        match register {
+            0 => Some(self.cr0),
+            2 => Some(self.cr2),
+            3 => Some(self.cr3),
+            4 => Some(self.cr4),
+            _ => None,
+        }
+    }
+
+    /// Makes `rdmsr` with ECX = `index` read `value`
+    pub fn set_msr(&mut self, index: u32, value: u64) {
+        self.msrs.insert(index, value);
+    }
+
+    pub fn msw(&self) -> u16 {
+        self.cr0 as u16
+    }
+
+    /// CR0 after `lmsw` with `msw`: only PE, MP, EM & TS are loaded, and PE stays set once it's set
+    pub fn cr0_after_lmsw(cr0: u32, msw: u16) -> u32 {
+        (cr0 & !MSW_WRITABLE) | (msw as u32 & MSW_WRITABLE) | (cr0 & CR0_PE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SystemRegisterProfile, CR0_PE};
+
+    #[test_log::test]
+    fn registers() {
+        let profile = SystemRegisterProfile {
+            cr3: 0x1234000,
+            ..SystemRegisterProfile::default()
+        };
+        assert_eq!(profile.read(0), Some(0x80010031));
+        assert_eq!(profile.msw(), 0x0031);
+        assert_eq!(profile.read(1), None);
+        assert_eq!(profile.read(3), Some(0x1234000));
+        assert_eq!(profile.read(8), None);
+    }
+
+    #[test_log::test]
+    fn lmsw() {
+        // sets TS & EM, leaves the upper bits alone
+        assert_eq!(
+            SystemRegisterProfile::cr0_after_lmsw(0x80010031, 0xfffc),
+            0x8001003d
+        );
+        // can't leave the protected mode
+        assert_eq!(SystemRegisterProfile::cr0_after_lmsw(CR0_PE, 0), CR0_PE);
+        assert_eq!(SystemRegisterProfile::cr0_after_lmsw(0, 1), CR0_PE);
+    }
+}
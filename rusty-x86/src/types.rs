@@ -6,6 +6,9 @@ use strum_macros::EnumIter;
 
 use crate::Builder;
 
+/// The 16 condition codes of `jcc`, `setcc` & `cmovcc`, see `evaluate_cc`
+pub use crate::ir::Condition as ConditionCode;
+
 // the numbers correspond to register numbers in ModR/M encoding
 #[derive(Debug, Clone, Copy, EnumIter, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub enum FullSizeGeneralPurposeRegister {
@@ -40,7 +43,7 @@ impl TryFrom<Register> for FullSizeGeneralPurposeRegister {
 
 // TODO add more registers
 // TODO add sub-registers meta-info (stuff like AX is the lower 16 bits of EAX)
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, EnumIter, Eq, PartialEq, Hash)]
 pub enum Register {
     EAX,
     EBX,
@@ -102,7 +105,7 @@ impl Register {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, EnumIter, Eq, PartialEq, Hash)]
 pub enum SegmentRegister {
     CS,
     DS,
@@ -115,7 +118,7 @@ pub enum SegmentRegister {
 #[derive(Debug, Display, Clone, Copy, EnumIter, PartialEq, Eq, Ord, PartialOrd)]
 pub enum Flag {
     Carry = 0,
-    Parity = 1,         // almost definitely can be ignored, not used much
+    Parity = 1, // only maintained by the SSE float comparisons & the zero idioms, other integer ops leave it alone
     AuxiliaryCarry = 2, // definitely can be ignored, as it's almost never used in modern (non-DOS) code
     Zero = 3,
     Sign = 4,
@@ -125,15 +128,174 @@ pub enum Flag {
     // !!! Make sure not to go out of bounds of CpuContext::flags
 }
 
+impl Flag {
+    /// Position of the flag in EFLAGS
+    pub fn eflags_bit(self) -> u32 {
+        match self {
+            Flag::Carry => 0,
+            Flag::Parity => 2,
+            Flag::AuxiliaryCarry => 4,
+            Flag::Zero => 6,
+            Flag::Sign => 7,
+            Flag::Direction => 10,
+            Flag::Overflow => 11,
+            Flag::Id => 21,
+        }
+    }
+}
+
+/// Where `CpuContext` keeps the flags. The generated code is made for one of them (`TranslationOptions::flag_storage`),
+/// and the context says which one it holds (`CpuContext::flag_storage`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlagStorage {
+    /// A byte per `Flag` in `CpuContext::flags`. The only one with room for `FLAG_UNDEFINED`
+    #[default]
+    Bytes = 0,
+    /// The bits of `CpuContext::eflags`, at their places in EFLAGS
+    Packed = 1,
+}
+
+impl FlagStorage {
+    /// The variable choosing the storage the test suites run with (`bytes` or `packed`), so that both get run
+    pub const TEST_ENV: &'static str = "RUSTY_X86_FLAG_STORAGE";
+
+    /// From `TEST_ENV`, `Bytes` if it's not set
+    pub fn from_env() -> Self {
+        match std::env::var(Self::TEST_ENV).as_deref() {
+            Ok("packed") => FlagStorage::Packed,
+            Ok("bytes") | Err(_) => FlagStorage::Bytes,
+            Ok(other) => panic!(
+                "{} should be `bytes` or `packed`, not {:?}",
+                Self::TEST_ENV,
+                other
+            ),
+        }
+    }
+}
+
+/// EFLAGS.IF, kept outside of `CpuContext::flags`
+pub const EFLAGS_IF_BIT: u32 = 9;
+/// EFLAGS.AC, also kept outside of `CpuContext::flags` (which is one byte per `Flag`, and all 8 are taken)
+pub const EFLAGS_AC_BIT: u32 = 18;
+/// Bit 1 of EFLAGS reads as 1
+pub const EFLAGS_FIXED: u32 = 1 << 1;
+
+/// What the instructions leaving some flags undefined (like `imul` does with ZF & SF) store there
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UndefinedFlagsPolicy {
+    /// Whatever the hardware usually produces
+    #[default]
+    Relaxed,
+    /// `FLAG_UNDEFINED`, reading it calls `RuntimeHandler::undefined_flag`.
+    /// For finding the guest code relying on the undefined behaviour (or checking our lowerings)
+    Strict,
+}
+
+/// The value of a poisoned flag in `CpuContext::flags`, only stored with `UndefinedFlagsPolicy::Strict`
+pub const FLAG_UNDEFINED: u8 = 0xff;
+
+/// x87 state that is not the registers themselves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FpuWord {
+    Control,
+    Status,
+    Tag,
+}
+
 #[repr(C)] // for interoperability with llvm-generated functions
-#[derive(Eq, PartialEq, Clone, Default)]
+#[derive(Eq, PartialEq, Clone)]
 pub struct CpuContext {
     // !!! If changing this struct - don't forget to update Types::new in llvm_backend.rs
     // also it would be best not to move fields around, as this breaks indices in build_ctx_*_gep
     pub gp_regs: [u32; 8],
     pub flags: [u8; 8],
+    // EIP is only kept up-to-date at the points where the generated code gives control to the host
+    pub eip: u32,
+    // non-zero value asks the generated code to return to the host ASAP (see EXIT_* constants)
+    pub exit: u32,
+    // valid when exit is EXIT_FAULT: the exception vector & the address that caused it (0 if not applicable)
+    pub fault_vector: u32,
+    pub fault_address: u32,
+    // x87 registers by their physical number (ST(i) is fpu_regs[(TOP + i) % 8])
+    // they are kept as doubles, so the extended precision is not there
+    pub fpu_regs: [u64; 8],
+    pub fpu_control: u16,
+    // TOP is in bits 11..=13
+    pub fpu_status: u16,
+    // two bits per physical register, 0b11 is empty
+    pub fpu_tag: u16,
+    // XMMi is xmm_regs[2 * i] (the low half) & xmm_regs[2 * i + 1]
+    pub xmm_regs: [u64; 16],
+    // only the rounding control (bits 13..=14) is looked at
+    pub mxcsr: u32,
+    // with the strict undefined flags policy: the instruction that made the flag undefined (indexed by Flag)
+    pub flag_sources: [u32; 8],
+    // with the fault sites on: the last memory access started (see llvm::FaultSites)
+    pub fault_site: u32,
+    // EFLAGS.IF, 0 or 1. Only the guest-side interrupt dispatch & iretd touch it (see InterruptVectorTable)
+    pub interrupt_flag: u32,
+    // valid with #PF: the error code, of which only PF_INSTRUCTION_FETCH is ever set.
+    // With VECTOR_UNIMPLEMENTED: the length of the instruction
+    pub fault_error_code: u32,
+    // EFLAGS.AC, 0 or 1. Misaligned accesses raise #AC while it's set, if the code was translated with alignment_checks
+    pub alignment_check: u32,
+    // with FlagStorage::Packed: the flags (and only them) at their EFLAGS bits, `flags` is unused then
+    pub eflags: u32,
+    // the FlagStorage the flags are kept in. Should match the one the code was translated for
+    pub flag_storage: u32,
+    // with TranslationOptions::shadow_flags: every flag as last computed, dead or not, at its EFLAGS bit
+    pub shadow_flags: u32,
+    // valid when exit is EXIT_GUEST: the exit code the guest ended with
+    pub exit_code: u32,
 }
 
+impl Default for CpuContext {
+    fn default() -> Self {
+        Self {
+            gp_regs: Default::default(),
+            flags: Default::default(),
+            eip: 0,
+            exit: EXIT_NONE,
+            fault_vector: 0,
+            fault_address: 0,
+            fpu_regs: Default::default(),
+            // the state after `fninit`
+            fpu_control: FPU_CONTROL_DEFAULT,
+            fpu_status: 0,
+            fpu_tag: 0xffff,
+            xmm_regs: Default::default(),
+            mxcsr: MXCSR_DEFAULT,
+            flag_sources: Default::default(),
+            fault_site: 0,
+            interrupt_flag: 1,
+            fault_error_code: 0,
+            alignment_check: 0,
+            eflags: 0,
+            flag_storage: FlagStorage::Bytes as u32,
+            shadow_flags: 0,
+            exit_code: 0,
+        }
+    }
+}
+
+/// All the exceptions masked, 64-bit precision, rounding to nearest
+pub const FPU_CONTROL_DEFAULT: u16 = 0x037f;
+
+/// The power-on MXCSR: all the exceptions masked, rounding to nearest
+pub const MXCSR_DEFAULT: u32 = 0x1f80;
+
+/// Generated code is running normally
+pub const EXIT_NONE: u32 = 0;
+/// The host (a runtime helper) asked to stop the execution
+pub const EXIT_HOST_REQUEST: u32 = 1;
+/// The guest code raised an exception (see `CpuContext::fault_vector`)
+pub const EXIT_FAULT: u32 = 2;
+/// The guest ended itself (`exit`, `ExitProcess`) with `CpuContext::exit_code`
+pub const EXIT_GUEST: u32 = 3;
+
+/// The #PF error code bit telling that the fault happened fetching an instruction (`CpuContext::fault_error_code`)
+pub const PF_INSTRUCTION_FETCH: u32 = 1 << 4;
+
 impl std::fmt::Debug for CpuContext {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         struct FlagsDebug(CpuContext);
@@ -154,6 +316,7 @@ impl std::fmt::Debug for CpuContext {
             s.field(format!("{:?}", gp).as_str(), &self.get_gp_reg(gp));
         }
         s.field("flags", &FlagsDebug { 0: self.clone() });
+        s.field("eip", &format_args!("0x{:08x}", self.eip));
         s.finish()
     }
 }
@@ -167,21 +330,75 @@ impl CpuContext {
         self.gp_regs[reg as usize] = val
     }
 
+    /// All the flags clear, kept in `storage`
+    pub fn with_flag_storage(storage: FlagStorage) -> Self {
+        Self {
+            flag_storage: storage as u32,
+            ..Self::default()
+        }
+    }
+
+    pub fn flag_storage(&self) -> FlagStorage {
+        if self.flag_storage == FlagStorage::Packed as u32 {
+            FlagStorage::Packed
+        } else {
+            FlagStorage::Bytes
+        }
+    }
+
+    /// Moves the flags over to `storage`. A `FLAG_UNDEFINED` reads as clear afterwards
+    pub fn set_flag_storage(&mut self, storage: FlagStorage) {
+        let poisoned = |ctx: &Self, flag: Flag| {
+            ctx.flag_storage() == FlagStorage::Bytes && ctx.flags[flag as usize] == FLAG_UNDEFINED
+        };
+        let values = Flag::iter()
+            .map(|flag| (flag, self.get_flag(flag) && !poisoned(self, flag)))
+            .collect::<Vec<_>>();
+        self.flags = Default::default();
+        self.eflags = 0;
+        self.flag_storage = storage as u32;
+        for (flag, value) in values {
+            self.set_flag(flag, value);
+        }
+    }
+
     pub fn get_flag(&self, flag: Flag) -> bool {
-        self.flags[flag as usize] != 0
+        match self.flag_storage() {
+            FlagStorage::Bytes => self.flags[flag as usize] != 0,
+            FlagStorage::Packed => self.eflags >> flag.eflags_bit() & 1 != 0,
+        }
     }
 
     pub fn set_flag(&mut self, flag: Flag, val: bool) {
-        self.flags[flag as usize] = if val { 1 } else { 0 }
+        // what the handlers change has to be seen by the shadow too
+        let bit = 1 << flag.eflags_bit();
+        self.shadow_flags = if val {
+            self.shadow_flags | bit
+        } else {
+            self.shadow_flags & !bit
+        };
+        match self.flag_storage() {
+            FlagStorage::Bytes => self.flags[flag as usize] = if val { 1 } else { 0 },
+            FlagStorage::Packed => {
+                let bit = 1 << flag.eflags_bit();
+                self.eflags = if val {
+                    self.eflags | bit
+                } else {
+                    self.eflags & !bit
+                };
+            }
+        }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum IntType {
     I8,
     I16,
     I32,
     I64,
+    /// Only used as a size of the memory operands: the values are handled as two I64 halves
+    I128,
 }
 
 impl IntType {
@@ -191,7 +408,7 @@ impl IntType {
             I8 => I16,
             I16 => I32,
             I32 => I64,
-            I64 => panic!("Can't created a double-sided type for I64"),
+            I64 | I128 => panic!("Can't created a double-sided type for {:?}", self),
         }
     }
 
@@ -202,6 +419,7 @@ impl IntType {
             I16 => 16,
             I32 => 32,
             I64 => 64,
+            I128 => 128,
         }
     }
 
@@ -210,9 +428,11 @@ impl IntType {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MemoryOperand {
     pub base: Option<Register>,
+    /// A 32-bit value sign-extended (disp8 & disp16 are sign-extended to 32 bits first), as the decoder makes it.
+    /// Only the low 32 bits matter for the address
     pub displacement: i64,
     pub scale: u8,
     pub index: Option<Register>,
@@ -220,7 +440,21 @@ pub struct MemoryOperand {
     pub segment: Option<SegmentRegister>,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl MemoryOperand {
+    /// `[disp32]`: no base & no index, like `mov eax, [0x1234]` (whether it's encoded with a SIB byte or not)
+    pub fn absolute(displacement: u32, size: Option<IntType>) -> Self {
+        Self {
+            base: None,
+            displacement: displacement as i32 as i64,
+            scale: 1,
+            index: None,
+            size,
+            segment: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Operand {
     Register(Register),
 
@@ -234,6 +468,15 @@ pub enum Operand {
 
     FarBranch(u16, u32),
 
+    // ST(i), relative to the top of the x87 stack
+    FpuRegister(u8),
+    // XMMi
+    Xmm(u8),
+    // MMi, aliased onto the x87 registers
+    Mmx(u8),
+    // CRi
+    ControlRegister(u8),
+
     Memory(MemoryOperand),
 }
 
@@ -250,6 +493,10 @@ impl Operand {
             Operand::Immediate32(_) => IntType::I32,
             Operand::Immediate64(_) => IntType::I64,
             Operand::FarBranch(_, _) => todo!(),
+            Operand::FpuRegister(_) => IntType::I64,
+            Operand::Xmm(_) => IntType::I128,
+            Operand::Mmx(_) => IntType::I64,
+            Operand::ControlRegister(_) => IntType::I32,
             Operand::Memory(m) => m.size.unwrap(),
         }
     }
@@ -269,6 +516,7 @@ pub enum ControlFlow<B: Builder> {
     IndirectJump(B::IntValue /* next EIP is dynamic and stored */),
     Return, /* return from a function. Value should be popped from the stack by the instruction implementation */
     Conditional(B::BoolValue, u32), /* if cond is true - jump to u32,  */
+    Switch(B::IntValue, Vec<u32>, B::IntValue), /* jump to the index-th u32, or to the second value past the end */
 }
 
 impl<B: Builder> ControlFlow<B> {
@@ -279,6 +527,7 @@ impl<B: Builder> ControlFlow<B> {
             ControlFlow::IndirectJump(_) => false,
             ControlFlow::Return => false,
             ControlFlow::Conditional(_, _) => true,
+            ControlFlow::Switch(_, _, _) => false,
         }
     }
 
@@ -289,6 +538,15 @@ impl<B: Builder> ControlFlow<B> {
             ControlFlow::IndirectJump(_) => None, /* can't statically know the addr */
             ControlFlow::Return => None,
             ControlFlow::Conditional(_, r) => Some(*r),
+            ControlFlow::Switch(_, _, _) => None, /* more than one, see `switch_targets` */
+        }
+    }
+
+    /// The statically known targets of a switch
+    pub fn switch_targets(&self) -> &[u32] {
+        match self {
+            ControlFlow::Switch(_, targets, _) => targets,
+            _ => &[],
         }
     }
 }
@@ -303,6 +561,7 @@ impl<B: Builder> Clone for ControlFlow<B> {
             IndirectJump(r) => IndirectJump(*r),
             Return => Return,
             Conditional(cond, r) => Conditional(*cond, *r),
+            Switch(index, targets, fallback) => Switch(*index, targets.clone(), *fallback),
         }
     }
 }
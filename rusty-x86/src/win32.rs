@@ -0,0 +1,169 @@
+//! Just enough of the Win32 thread & process environment for the code that looks at it through FS
+//!
+//! The CRT startup code, SEH & the TLS access all begin with `mov eax, fs:[0x18]` or `mov eax, fs:[0x30]`.
+//! `Runtime::setup_teb` maps a TEB & a PEB with the few fields those read filled in and points FS to the TEB:
+//!
+//! ```ignore
+//! runtime.setup_stack(0x100000, 0x100000, None)?;
+//! let teb = runtime.setup_teb(&TebOptions::default())?;
+//! ```
+//!
+//! Everything else in them is zero. The offsets are the ones of the 32-bit Windows, which didn't change since NT
+
+use crate::disasm::SymbolTable;
+use crate::handler::guest_exit;
+use crate::memory_image::Protection;
+use crate::runtime::{GuestMemory, GuestStack, MemoryAccessError, PAGE_SIZE};
+use crate::segmentation::SegmentDescriptor;
+use crate::types::{CpuContext, FullSizeGeneralPurposeRegister};
+
+/// `NT_TIB::ExceptionList`, the head of the SEH chain
+pub const TEB_EXCEPTION_LIST: u32 = 0x00;
+/// `NT_TIB::StackBase`, the top of the stack (the highest address, exclusive)
+pub const TEB_STACK_BASE: u32 = 0x04;
+/// `NT_TIB::StackLimit`, the lowest address of the stack
+pub const TEB_STACK_LIMIT: u32 = 0x08;
+/// `NT_TIB::Self`, the linear address of the TEB
+pub const TEB_SELF: u32 = 0x18;
+pub const TEB_PROCESS_ID: u32 = 0x20;
+pub const TEB_THREAD_ID: u32 = 0x24;
+/// The array of the per-module implicit TLS blocks (what `__declspec(thread)` goes through)
+pub const TEB_TLS_POINTER: u32 = 0x2c;
+pub const TEB_PEB: u32 = 0x30;
+pub const TEB_LAST_ERROR: u32 = 0x34;
+
+pub const PEB_BEING_DEBUGGED: u32 = 0x02;
+pub const PEB_IMAGE_BASE: u32 = 0x08;
+pub const PEB_PROCESS_HEAP: u32 = 0x18;
+
+/// What ends the SEH chain, an empty one included
+pub const END_OF_SEH_CHAIN: u32 = 0xffff_ffff;
+
+/// The interrupt `EXIT_PROCESS_STUB` hands the exit code to the host with
+pub const EXIT_PROCESS_VECTOR: u8 = 0x2e;
+
+/// The guest side of `ExitProcess(UINT uExitCode)`: map it & point the import at it, and have
+/// `RuntimeHandler::interrupt` call `exit_process`. Returns like a stdcall function if the host doesn't stop it
+#[rustfmt::skip]
+pub const EXIT_PROCESS_STUB: &[u8] = &[
+    0x8b, 0x44, 0x24, 0x04,    // mov eax, [esp + 4]
+    0xcd, EXIT_PROCESS_VECTOR, // int EXIT_PROCESS_VECTOR
+    0xc2, 0x04, 0x00,          // ret 4
+];
+
+/// For `RuntimeHandler::interrupt`: ends the execution with `ExitReason::GuestExit` if it's the `int` of
+/// `EXIT_PROCESS_STUB`, returns whether it was
+pub fn exit_process(ctx: &mut CpuContext, vector: u8) -> bool {
+    if vector != EXIT_PROCESS_VECTOR {
+        return false;
+    }
+    let code = ctx.get_gp_reg(FullSizeGeneralPurposeRegister::EAX);
+    guest_exit(ctx, code);
+    true
+}
+
+/// Where the TEB & the PEB go & what's in them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TebOptions {
+    /// Page-aligned, like the PEB
+    pub teb: u32,
+    pub peb: u32,
+    pub process_id: u32,
+    pub thread_id: u32,
+    pub image_base: u32,
+    pub process_heap: u32,
+    pub tls_pointer: u32,
+    pub being_debugged: bool,
+}
+
+impl Default for TebOptions {
+    /// Where Windows XP put them for the first thread
+    fn default() -> Self {
+        Self {
+            teb: 0x7ffd_e000,
+            peb: 0x7ffd_f000,
+            process_id: 0x100,
+            thread_id: 0x104,
+            image_base: 0x40_0000,
+            process_heap: 0,
+            tls_pointer: 0,
+            being_debugged: false,
+        }
+    }
+}
+
+/// The TEB & the PEB made by `Runtime::setup_teb`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WindowsTeb {
+    pub teb: u32,
+    pub peb: u32,
+}
+
+impl WindowsTeb {
+    /// Fills the TEB & the PEB in the (zeroed) pages at `options.teb` & `options.peb`
+    pub(crate) fn init(
+        memory: &mut GuestMemory,
+        options: &TebOptions,
+        stack: Option<GuestStack>,
+    ) -> Result<Self, MemoryAccessError> {
+        let teb = Self {
+            teb: options.teb,
+            peb: options.peb,
+        };
+
+        memory.write_u32(teb.teb + TEB_EXCEPTION_LIST, END_OF_SEH_CHAIN)?;
+        if let Some(stack) = stack {
+            teb.set_stack_bounds(memory, stack.top, stack.base)?;
+        }
+        memory.write_u32(teb.teb + TEB_SELF, teb.teb)?;
+        memory.write_u32(teb.teb + TEB_PROCESS_ID, options.process_id)?;
+        memory.write_u32(teb.teb + TEB_THREAD_ID, options.thread_id)?;
+        memory.write_u32(teb.teb + TEB_TLS_POINTER, options.tls_pointer)?;
+        memory.write_u32(teb.teb + TEB_PEB, teb.peb)?;
+
+        memory.write_u8(teb.peb + PEB_BEING_DEBUGGED, options.being_debugged as u8)?;
+        memory.write_u32(teb.peb + PEB_IMAGE_BASE, options.image_base)?;
+        memory.write_u32(teb.peb + PEB_PROCESS_HEAP, options.process_heap)?;
+
+        Ok(teb)
+    }
+
+    /// What FS points to
+    pub fn segment(&self) -> SegmentDescriptor {
+        SegmentDescriptor::new(self.teb, PAGE_SIZE - 1, Protection::READ_WRITE)
+    }
+
+    /// `(StackBase, StackLimit)`: the top of the stack & its lowest address
+    pub fn stack_bounds(&self, memory: &GuestMemory) -> Result<(u32, u32), MemoryAccessError> {
+        Ok((
+            memory.read_u32(self.teb + TEB_STACK_BASE)?,
+            memory.read_u32(self.teb + TEB_STACK_LIMIT)?,
+        ))
+    }
+
+    /// For the stacks not made by `Runtime::setup_stack`, or a thread switching to another one
+    pub fn set_stack_bounds(
+        &self,
+        memory: &mut GuestMemory,
+        base: u32,
+        limit: u32,
+    ) -> Result<(), MemoryAccessError> {
+        memory.write_u32(self.teb + TEB_STACK_BASE, base)?;
+        memory.write_u32(self.teb + TEB_STACK_LIMIT, limit)
+    }
+
+    /// The head of the SEH chain
+    pub fn exception_list(&self, memory: &GuestMemory) -> Result<u32, MemoryAccessError> {
+        memory.read_u32(self.teb + TEB_EXCEPTION_LIST)
+    }
+
+    pub fn last_error(&self, memory: &GuestMemory) -> Result<u32, MemoryAccessError> {
+        memory.read_u32(self.teb + TEB_LAST_ERROR)
+    }
+
+    /// Names the two pages, so that an address in them is reported as `TEB+0x30` instead of a bare number
+    pub fn add_symbols(&self, symbols: &mut SymbolTable) {
+        symbols.insert(self.teb, "TEB");
+        symbols.insert(self.peb, "PEB");
+    }
+}
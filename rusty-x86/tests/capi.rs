@@ -0,0 +1,100 @@
+#![cfg(feature = "capi")]
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn header_is_up_to_date() {
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let header_path = crate_dir.join("include/rusty_x86.h");
+
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml")).unwrap();
+    let mut generated = Vec::new();
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(crate_dir.join("src/capi.rs"))
+        // for CpuContext
+        .with_src(crate_dir.join("src/types.rs"))
+        .generate()
+        .unwrap()
+        .write(&mut generated);
+
+    if std::env::var_os("RUSTY_X86_BLESS").is_some() {
+        std::fs::write(&header_path, &generated).unwrap();
+    }
+
+    let existing = std::fs::read(&header_path).unwrap();
+
+    assert!(
+        existing == generated,
+        "include/rusty_x86.h is outdated, regenerate it with `RUSTY_X86_BLESS=1 cargo test --features capi --test capi`"
+    );
+}
+
+/// Builds the static library like a C user would, then links tests/capi/capi_test.c against it
+#[cfg(unix)]
+#[test_log::test]
+fn c_program() {
+    let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target = env!("RUSTY_X86_TARGET");
+    let out_dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("capi");
+
+    let output = Command::new(env!("CARGO"))
+        .args([
+            "rustc",
+            "--lib",
+            "--features",
+            "capi",
+            "--crate-type",
+            "staticlib",
+        ])
+        .arg("--manifest-path")
+        .arg(crate_dir.join("Cargo.toml"))
+        .args(["--target", target])
+        .arg("--target-dir")
+        .arg(&out_dir)
+        .args(["--", "--print", "native-static-libs"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        output.status.success(),
+        "building the static library failed:\n{}",
+        stderr
+    );
+
+    // the system libraries the static library needs
+    let native_libs = stderr
+        .lines()
+        .find_map(|line| line.split_once("native-static-libs: "))
+        .map(|(_, libs)| libs.split_whitespace().collect::<Vec<_>>())
+        .expect("rustc didn't print the native libraries of the static library");
+
+    let exe = out_dir.join("capi_test");
+    let status = cc::Build::new()
+        .target(target)
+        .host(target)
+        .opt_level(0)
+        .debug(false)
+        .cargo_metadata(false)
+        .warnings_into_errors(true)
+        .get_compiler()
+        .to_command()
+        .arg(crate_dir.join("tests/capi/capi_test.c"))
+        .arg("-I")
+        .arg(crate_dir.join("include"))
+        .arg("-o")
+        .arg(&exe)
+        .arg(out_dir.join(target).join("debug/librusty_x86.a"))
+        .args(native_libs)
+        .status()
+        .unwrap();
+    assert!(status.success(), "compiling tests/capi/capi_test.c failed");
+
+    let output = Command::new(&exe).output().unwrap();
+    assert!(
+        output.status.success(),
+        "the C test program failed:\n{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
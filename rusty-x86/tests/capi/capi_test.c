@@ -0,0 +1,115 @@
+// C side of the C API test (see tests/capi.rs)
+// linked against the static library of the crate and run by tests/capi.rs
+
+#include "rusty_x86.h"
+
+#include <stdio.h>
+
+#define CODE_ADDR 0x1000
+#define STACK_ADDR 0x80000
+#define STACK_SIZE 0x10000
+
+#define CHECK(cond)                                                            \
+    do {                                                                       \
+        if (!(cond)) {                                                         \
+            fprintf(stderr, "%s:%d: check failed: %s\n", __FILE__, __LINE__,   \
+                    #cond);                                                    \
+            return 1;                                                          \
+        }                                                                      \
+    } while (0)
+
+static const uint8_t code[] = {
+    0xb8, 0x78, 0x56, 0x34, 0x12, // mov eax, 0x12345678
+    0xe7, 0x42,                   // out 0x42, eax
+    0xe4, 0x10,                   // in al, 0x10
+    0xcd, 0x80,                   // int 0x80
+    0x43,                         // inc ebx
+    0xc3,                         // ret
+};
+
+struct state {
+    uint32_t out_value;
+    uint8_t out_size;
+    uint8_t vector;
+};
+
+static uint32_t port_in(void *user_data, RustyX86CpuContext *ctx,
+                        uint16_t port, uint8_t size) {
+    (void)user_data;
+    (void)ctx;
+    return port == 0x10 && size == 1 ? 0x99 : 0;
+}
+
+static void port_out(void *user_data, RustyX86CpuContext *ctx, uint16_t port,
+                     uint8_t size, uint32_t value) {
+    struct state *state = user_data;
+    (void)ctx;
+    if (port == 0x42) {
+        state->out_value = value;
+        state->out_size = size;
+    }
+}
+
+static void interrupt(void *user_data, RustyX86CpuContext *ctx,
+                      uint8_t vector) {
+    struct state *state = user_data;
+    state->vector = vector;
+    // syscall-like: eax is the "result"
+    ctx->gp_regs[0] += 1;
+}
+
+static int run_once(RustyX86Runtime *runtime, RustyX86ExitReason *reason) {
+    RustyX86CpuContext *ctx = rusty_x86_context(runtime);
+    ctx->gp_regs[3] = 0;                       // ebx
+    ctx->gp_regs[4] = STACK_ADDR + STACK_SIZE - 4; // esp
+    return rusty_x86_run(runtime, CODE_ADDR, reason) == RUSTY_X86_STATUS_OK;
+}
+
+int main(void) {
+    RustyX86Runtime *runtime = NULL;
+    RustyX86ExitReason reason;
+    struct state state = {0};
+    static uint8_t stack[STACK_SIZE];
+
+    CHECK(rusty_x86_runtime_new(&runtime) == RUSTY_X86_STATUS_OK);
+    CHECK(rusty_x86_map_memory(runtime, CODE_ADDR, code, sizeof(code),
+                               RUSTY_X86_PROT_READ | RUSTY_X86_PROT_EXECUTE) ==
+          RUSTY_X86_STATUS_OK);
+    CHECK(rusty_x86_map_memory(runtime, STACK_ADDR, stack, sizeof(stack),
+                               RUSTY_X86_PROT_READ | RUSTY_X86_PROT_WRITE) ==
+          RUSTY_X86_STATUS_OK);
+    CHECK(rusty_x86_map_memory(runtime, STACK_ADDR, stack, sizeof(stack), 0x80) ==
+          RUSTY_X86_STATUS_INVALID_ARGUMENT);
+
+    // no callbacks: the interrupt stops us right after itself
+    CHECK(run_once(runtime, &reason));
+    CHECK(reason == RUSTY_X86_EXIT_REASON_HOST_REQUEST);
+    CHECK(rusty_x86_context(runtime)->eip == CODE_ADDR + 11);
+    // nothing is connected to the port
+    CHECK(rusty_x86_context(runtime)->gp_regs[0] == 0x123456ff);
+    CHECK(rusty_x86_context(runtime)->gp_regs[3] == 0);
+
+    // now with all of them
+    RustyX86Callbacks callbacks = {
+        .user_data = &state,
+        .interrupt = interrupt,
+        .port_in = port_in,
+        .port_out = port_out,
+    };
+    CHECK(rusty_x86_set_callbacks(runtime, &callbacks) == RUSTY_X86_STATUS_OK);
+
+    CHECK(run_once(runtime, &reason));
+    CHECK(reason == RUSTY_X86_EXIT_REASON_RETURNED);
+    CHECK(state.out_value == 0x12345678);
+    CHECK(state.out_size == 4);
+    CHECK(state.vector == 0x80);
+    CHECK(rusty_x86_context(runtime)->gp_regs[0] == 0x1234569a);
+    CHECK(rusty_x86_context(runtime)->gp_regs[3] == 1);
+
+    rusty_x86_runtime_free(runtime);
+
+    CHECK(rusty_x86_run(NULL, CODE_ADDR, &reason) ==
+          RUSTY_X86_STATUS_INVALID_ARGUMENT);
+
+    return 0;
+}
@@ -1,15 +1,24 @@
 mod loader;
 
 use inkwell::execution_engine::JitFunction;
-use inkwell::values::BasicMetadataValueEnum;
 use inkwell::OptimizationLevel;
 use log::{debug, error, trace};
 use region::Allocation;
-use rusty_x86::llvm::backend::{BbFunc, FASTCC_CALLING_CONVENTION};
+use rusty_x86::config::TranslationOptions;
+use rusty_x86::disasm::{self, SymbolTable};
+use rusty_x86::handler::{record_checkpoint, GuestFault, VECTOR_CHECKPOINT, VECTOR_FLAG_MISMATCH};
+use rusty_x86::ir::Decoder;
+use rusty_x86::llvm::backend::{EntryFunc, INTERRUPT_HELPER};
+use rusty_x86::llvm::ENTRY_TRAMPOLINE;
 use rusty_x86::memory_image::{MemoryImage, MemoryImageItem, Protection};
-use rusty_x86::types::{CpuContext, Flag, FullSizeGeneralPurposeRegister};
+use rusty_x86::types::{
+    CpuContext, Flag, FlagStorage, FullSizeGeneralPurposeRegister, EXIT_FAULT, EXIT_HOST_REQUEST,
+    EXIT_NONE,
+};
 use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::{BTreeMap, HashSet};
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use strum::IntoEnumIterator;
 use unicorn;
@@ -26,6 +35,9 @@ const STACK_SIZE: u32 = 0x10000; // 64 KiB
 pub const MAGIC_RETURN_ADDR: u32 = 0xCAFEBABE;
 pub const PAGE_ALIGN: u32 = 0x1000;
 
+/// One in this many of the cases is run with the flag liveness & the shadow flags checking it
+const SHADOW_FLAGS_SHARE: u64 = 4;
+
 #[derive(Clone)]
 pub enum CodeToTest<'a> {
     Snippet(&'a [u8]),                // just the code
@@ -144,7 +156,59 @@ fn load_unicorn(
     (exec_range.0, exec_range.1, mem)
 }
 
-fn execute_unicorn(code: CodeToTest) -> (CpuContext, Vec<(u32, Vec<u8>)>, Vec<u32>) {
+/// Converts the unicorn registers to a rusty_x86 context
+fn unicorn_context(read: impl Fn(RegisterX86) -> u64) -> CpuContext {
+    let mut ctx = CpuContext::default();
+
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::EAX,
+        read(RegisterX86::EAX) as u32,
+    );
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::EBX,
+        read(RegisterX86::EBX) as u32,
+    );
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::ECX,
+        read(RegisterX86::ECX) as u32,
+    );
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::EDX,
+        read(RegisterX86::EDX) as u32,
+    );
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::ESP,
+        read(RegisterX86::ESP) as u32,
+    );
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::EBP,
+        read(RegisterX86::EBP) as u32,
+    );
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::ESI,
+        read(RegisterX86::ESI) as u32,
+    );
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::EDI,
+        read(RegisterX86::EDI) as u32,
+    );
+
+    let flags = read(RegisterX86::EFLAGS) as u32;
+
+    ctx.set_flag(Flag::Carry, flags & 0x1 != 0);
+    ctx.set_flag(Flag::Parity, flags & 0x2 != 0);
+    ctx.set_flag(Flag::AuxiliaryCarry, flags & 0x10 != 0);
+    ctx.set_flag(Flag::Zero, flags & 0x40 != 0);
+    ctx.set_flag(Flag::Sign, flags & 0x80 != 0);
+    ctx.set_flag(Flag::Overflow, flags & 0x800 != 0);
+
+    ctx
+}
+
+/// The context, the writable memory, the basic blocks & the contexts at the checkpoints
+fn execute_unicorn(
+    code: CodeToTest,
+) -> (CpuContext, Vec<(u32, Vec<u8>)>, Vec<u32>, Vec<CpuContext>) {
     let mut emu = CpuX86::new(unicorn::Mode::MODE_32).unwrap();
 
     // collect basic block addresses to use in lifting by rusty_x86
@@ -156,6 +220,17 @@ fn execute_unicorn(code: CodeToTest) -> (CpuContext, Vec<(u32, Vec<u8>)>, Vec<u3
     })
     .unwrap();
 
+    // `int VECTOR_CHECKPOINT` resumes at the next instruction once the hook returns
+    let checkpoints = Arc::new(RefCell::new(Vec::new()));
+    let local_checkpoints = checkpoints.clone();
+    emu.add_intr_hook(move |uc, vector| {
+        if vector == VECTOR_CHECKPOINT as u32 {
+            let ctx = unicorn_context(|reg| uc.reg_read(reg as i32).unwrap());
+            local_checkpoints.borrow_mut().push(ctx);
+        }
+    })
+    .unwrap();
+
     let (base_addr, end, regions) = load_unicorn(&mut emu, code);
 
     let res = emu.emu_start(base_addr, end.unwrap_or(0), 10 * unicorn::SECOND_SCALE, 0);
@@ -169,94 +244,64 @@ fn execute_unicorn(code: CodeToTest) -> (CpuContext, Vec<(u32, Vec<u8>)>, Vec<u3
         }
     };
 
-    let mut ctx = CpuContext::default();
-
-    // convert unicorn context to rust_x86 context
-    {
-        ctx.set_gp_reg(
-            FullSizeGeneralPurposeRegister::EAX,
-            emu.reg_read(RegisterX86::EAX).unwrap() as u32,
-        );
-        ctx.set_gp_reg(
-            FullSizeGeneralPurposeRegister::EBX,
-            emu.reg_read(RegisterX86::EBX).unwrap() as u32,
-        );
-        ctx.set_gp_reg(
-            FullSizeGeneralPurposeRegister::ECX,
-            emu.reg_read(RegisterX86::ECX).unwrap() as u32,
-        );
-        ctx.set_gp_reg(
-            FullSizeGeneralPurposeRegister::EDX,
-            emu.reg_read(RegisterX86::EDX).unwrap() as u32,
-        );
-        ctx.set_gp_reg(
-            FullSizeGeneralPurposeRegister::ESP,
-            emu.reg_read(RegisterX86::ESP).unwrap() as u32,
-        );
-        ctx.set_gp_reg(
-            FullSizeGeneralPurposeRegister::EBP,
-            emu.reg_read(RegisterX86::EBP).unwrap() as u32,
-        );
-        ctx.set_gp_reg(
-            FullSizeGeneralPurposeRegister::ESI,
-            emu.reg_read(RegisterX86::ESI).unwrap() as u32,
-        );
-        ctx.set_gp_reg(
-            FullSizeGeneralPurposeRegister::EDI,
-            emu.reg_read(RegisterX86::EDI).unwrap() as u32,
-        );
-
-        let flags = emu.reg_read(RegisterX86::EFLAGS).unwrap() as u32;
-
-        ctx.set_flag(Flag::Carry, flags & 0x1 != 0);
-        ctx.set_flag(Flag::Parity, flags & 0x2 != 0);
-        ctx.set_flag(Flag::AuxiliaryCarry, flags & 0x10 != 0);
-        ctx.set_flag(Flag::Zero, flags & 0x40 != 0);
-        ctx.set_flag(Flag::Sign, flags & 0x80 != 0);
-        ctx.set_flag(Flag::Overflow, flags & 0x800 != 0);
-    }
+    let ctx = unicorn_context(|reg| emu.reg_read(reg).unwrap());
 
     let mem = regions
         .iter()
         .map(|r| (r.0 as u32, emu.mem_read_as_vec(r.0, r.1 as usize).unwrap()))
         .collect();
 
-    (ctx, mem, basic_blocks.take().into_iter().collect())
+    (
+        ctx,
+        mem,
+        basic_blocks.take().into_iter().collect(),
+        checkpoints.take(),
+    )
 }
 
+thread_local! {
+    static CHECKPOINTS: RefCell<Vec<CpuContext>> = RefCell::new(Vec::new());
+}
+
+/// `int n` in the recompiled code: only the checkpoints go on
+extern "C" fn checkpoint_helper(ctx: *mut CpuContext, vector: u8) {
+    // SAFETY: the generated code passes its own context
+    let ctx = unsafe { &mut *ctx };
+    if !CHECKPOINTS.with(|c| record_checkpoint(ctx, vector, &mut c.borrow_mut())) {
+        ctx.exit = EXIT_HOST_REQUEST;
+    }
+}
+
+/// The context, the writable memory, `CpuContext::exit` & the contexts at the checkpoints
 fn execute_rusty_x86(
     code_and_args: CodeToTest,
     basic_blocks: &[u32],
-) -> (CpuContext, Vec<(u32, Vec<u8>)>) {
+) -> (CpuContext, Vec<(u32, Vec<u8>)>, u32, Vec<CpuContext>) {
     let context = inkwell::context::Context::create();
     let types = &rusty_x86::llvm::backend::Types::new(&context);
     let rt_funs = &rusty_x86::llvm::backend::RuntimeHelpers::dummy(types);
     let (image, entry) = code_and_args.get_code();
-    let module = rusty_x86::llvm::recompile(&context, types, rt_funs, &image, basic_blocks);
-
-    let entry_name = rusty_x86::llvm::backend::LlvmBuilder::get_name_for(entry);
-
-    const ENTRY_NAME: &str = "entry";
-
-    let entry = module.add_function(ENTRY_NAME, types.bb_fn, None);
-    let bb = context.append_basic_block(entry, ENTRY_NAME);
-
-    {
-        let builder = context.create_builder();
-        builder.position_at_end(bb);
-
-        let args: Vec<BasicMetadataValueEnum> =
-            entry.get_params().iter().map(|f| (*f).into()).collect();
-
-        let call = builder.build_call(
-            module.get_function(entry_name.as_str()).unwrap(),
-            args.as_slice(),
-            "res",
-        );
-        call.set_call_convention(FASTCC_CALLING_CONVENTION);
+    // `FlagStorage::TEST_ENV` picks the storage, so that the suite can be run with both
+    // the code picks the cases, so that a failure reproduces
+    let mut hasher = DefaultHasher::new();
+    image.iter().for_each(|item| item.data.hash(&mut hasher));
+    let shadow_flags = hasher.finish() % SHADOW_FLAGS_SHARE == 0;
+    let options = TranslationOptions {
+        flag_storage: FlagStorage::from_env(),
+        flag_liveness: shadow_flags,
+        shadow_flags,
+        ..TranslationOptions::default()
+    };
+    let module = rusty_x86::llvm::recompile_with_options(
+        &context,
+        types,
+        rt_funs,
+        &options,
+        &image,
+        basic_blocks,
+    );
 
-        builder.build_return(None);
-    }
+    rusty_x86::llvm::add_entry_trampoline(&context, &module, types);
 
     let _ir = module.print_to_string().to_string();
     // CLion is overwhelmed by this output and breaks
@@ -269,10 +314,14 @@ fn execute_rusty_x86(
             OptimizationLevel::Aggressive, /* TODO: do we want optimizations? */
         )
         .unwrap();
+    if let Some(fun) = module.get_function(INTERRUPT_HELPER) {
+        execution_engine.add_global_mapping(&fun, checkpoint_helper as *const () as usize);
+    }
 
-    let fun: JitFunction<BbFunc> = unsafe { execution_engine.get_function(ENTRY_NAME).unwrap() };
+    let fun: JitFunction<EntryFunc> =
+        unsafe { execution_engine.get_function(ENTRY_TRAMPOLINE).unwrap() };
 
-    let mut cpu_context = CpuContext::default();
+    let mut cpu_context = CpuContext::with_flag_storage(options.flag_storage);
 
     // SAFETY: dragons ahead
     // map 4 GiB of memory with no protection
@@ -343,10 +392,18 @@ fn execute_rusty_x86(
 
     cpu_context.set_gp_reg(FullSizeGeneralPurposeRegister::ESP, esp);
 
-    unsafe {
+    CHECKPOINTS.with(|c| c.borrow_mut().clear());
+    let exit = unsafe {
         // do the thing!
-        fun.call(&mut cpu_context, target_mem_region.as_mut_ptr());
+        fun.call(&mut cpu_context, target_mem_region.as_mut_ptr(), entry)
     };
+    let checkpoints = CHECKPOINTS.with(|c| c.take());
+    assert!(
+        exit != EXIT_FAULT || cpu_context.fault_vector != VECTOR_FLAG_MISMATCH as u32,
+        "flag #{} is stale at 0x{:08x}",
+        cpu_context.fault_address,
+        cpu_context.eip
+    );
 
     let mem = image
         .iter()
@@ -366,7 +423,7 @@ fn execute_rusty_x86(
         })
         .collect();
 
-    (cpu_context, mem)
+    (cpu_context, mem, exit, checkpoints)
 }
 
 fn context_to_gp_map(context: &CpuContext) -> BTreeMap<FullSizeGeneralPurposeRegister, u32> {
@@ -382,11 +439,16 @@ fn context_to_flag_list(context: &CpuContext, flags: &[Flag]) -> Vec<Flag> {
 }
 
 pub fn test_code(code: CodeToTest, flags: Vec<Flag>) {
-    // TODO: make it work
-    // debug!(
-    //     "CODE:\n{}",
-    //     rusty_x86::disasm::disassemble(code.get_code())
-    // );
+    let (image, entry) = code.get_code();
+    debug!(
+        "CODE:\n{}",
+        disasm::listing(
+            &image,
+            entry,
+            image.execute_all_at(entry).len() as u32,
+            &SymbolTable::new()
+        )
+    );
 
     let unicorn = execute_unicorn(code.clone());
 
@@ -412,6 +474,23 @@ pub fn test_code(code: CodeToTest, flags: Vec<Flag>) {
 
     debug!("Limiting flags to the following: {:?}", flags);
 
+    // the first divergence is the most telling, so the checkpoints come before the final state
+    assert_eq!(rusty_x86.3.len(), unicorn.3.len(), "checkpoints reached");
+    for (i, (rusty_x86, unicorn)) in rusty_x86.3.iter().zip(&unicorn.3).enumerate() {
+        assert_eq!(
+            context_to_gp_map(rusty_x86),
+            context_to_gp_map(unicorn),
+            "checkpoint #{}",
+            i
+        );
+        assert_eq!(
+            context_to_flag_list(rusty_x86, flags.as_slice()),
+            context_to_flag_list(unicorn, flags.as_slice()),
+            "checkpoint #{}",
+            i
+        );
+    }
+
     // We can't directly compare contexts because of flags (sometimes they are undefined on x86)
     // So we compare separately the values of registers and specified flags
     let rusty_x86_gp = context_to_gp_map(&rusty_x86.0);
@@ -429,3 +508,32 @@ pub fn test_code(code: CodeToTest, flags: Vec<Flag>) {
 
     assert_eq!(rusty_x86_mem, unicorn_mem);
 }
+
+/// For the snippets that have to stop with an exception at their last instruction. Unicorn has nothing to compare
+/// with there, so only the exception itself & where it happened are checked
+pub fn test_code_fault(code: CodeToTest, fault: GuestFault) {
+    let (image, entry) = code.get_code();
+    let mut decoder = Decoder::new(image.execute_all_at(entry), entry);
+    let mut last = entry;
+    while decoder.can_decode() {
+        last = decoder.decode().unwrap().ip;
+    }
+
+    let (ctx, _, exit, _) = execute_rusty_x86(code, &[entry]);
+    debug!("RESULT rusty_x86 = {:?}", ctx);
+
+    assert_ne!(
+        exit, EXIT_NONE,
+        "expected {:?}, the snippet ran to the end",
+        fault
+    );
+    assert_eq!(exit, EXIT_FAULT);
+    assert_eq!(
+        GuestFault::from_vector(ctx.fault_vector as u8),
+        Some(fault),
+        "vector {}",
+        ctx.fault_vector
+    );
+    // at the faulting instruction, not after it
+    assert_eq!(ctx.eip, last, "eip");
+}
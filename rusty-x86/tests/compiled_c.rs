@@ -0,0 +1,178 @@
+#![cfg(feature = "llvm")]
+
+//! Functions compiled from C (tests/fixtures/c_functions.c) called through the runtime, the results checked against
+//! the same algorithms on the host
+
+use rusty_x86::config::{Recompiler, RecompilerBuilder};
+use rusty_x86::memory_image::Protection;
+use rusty_x86::runtime::{NullHandler, Runtime};
+use rusty_x86::types::FullSizeGeneralPurposeRegister;
+
+/// Where c_functions.bin is linked at (see tests/fixtures/c_functions.ld)
+const C_FUNCTIONS_BASE: u32 = 0x10000;
+const C_FUNCTIONS: &[u8] = include_bytes!("fixtures/c_functions.bin");
+
+const DATA_ADDR: u32 = 0x20000;
+const DATA_SIZE: u32 = 0x1000;
+const STACK_ADDR: u32 = 0x30000;
+const STACK_SIZE: u32 = 0x1000;
+
+/// In the order of `exports` in c_functions.c
+#[derive(Debug, Clone, Copy)]
+enum Export {
+    Crc32 = 0,
+    Quicksort = 1,
+    Dispatch = 2,
+}
+
+fn runtime() -> Runtime<NullHandler> {
+    runtime_with(Recompiler::builder())
+}
+
+fn runtime_with(builder: RecompilerBuilder) -> Runtime<NullHandler> {
+    let mut runtime = builder.build_runtime(NullHandler).unwrap();
+    runtime
+        .map(C_FUNCTIONS_BASE, Protection::READ_EXECUTE, C_FUNCTIONS)
+        .unwrap();
+    runtime
+        .map(DATA_ADDR, Protection::READ_WRITE, &[0; DATA_SIZE as usize])
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, STACK_ADDR + STACK_SIZE);
+    runtime
+}
+
+fn address_of(runtime: &Runtime<NullHandler>, export: Export) -> u32 {
+    runtime
+        .memory
+        .read_u32(C_FUNCTIONS_BASE + export as u32 * 4)
+        .unwrap()
+}
+
+fn host_crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb88320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+#[test_log::test]
+fn crc32() {
+    let mut runtime = runtime();
+    let crc32 = address_of(&runtime, Export::Crc32);
+
+    let inputs: [&[u8]; 5] = [
+        b"",
+        b"a",
+        b"123456789",
+        b"The quick brown fox jumps over the lazy dog",
+        &[0xff; 300],
+    ];
+    for data in inputs {
+        runtime.memory.write_bytes(DATA_ADDR, data).unwrap();
+        let crc = runtime
+            .call_guest(crc32, &[DATA_ADDR, data.len() as u32])
+            .unwrap();
+        assert_eq!(crc, host_crc32(data), "crc32 of {:02x?}", data);
+    }
+
+    // the check value of the algorithm
+    assert_eq!(host_crc32(b"123456789"), 0xcbf43926);
+    // cdecl: the caller pops the arguments
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::ESP),
+        STACK_ADDR + STACK_SIZE
+    );
+}
+
+#[test_log::test]
+fn quicksort() {
+    let mut runtime = runtime();
+    let quicksort = address_of(&runtime, Export::Quicksort);
+
+    let mut seed = 0x12345678u32;
+    let mut random = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed as i32
+    };
+    let inputs: Vec<Vec<i32>> = vec![
+        vec![],
+        vec![1],
+        vec![2, 1],
+        vec![1, 2, 3, 4, 5],
+        vec![5, 4, 3, 2, 1],
+        vec![3, -1, 3, 0, -1, 3, i32::MIN, i32::MAX, 0],
+        (0..200).map(|_| random()).collect(),
+        (0..200).map(|_| random() % 8).collect(),
+    ];
+    for items in inputs {
+        let bytes = items
+            .iter()
+            .flat_map(|item| item.to_le_bytes())
+            .collect::<Vec<_>>();
+        runtime.memory.write_bytes(DATA_ADDR, &bytes).unwrap();
+        runtime
+            .call_guest(quicksort, &[DATA_ADDR, items.len() as u32])
+            .unwrap();
+
+        let sorted = (0..items.len() as u32)
+            .map(|i| runtime.memory.read_u32(DATA_ADDR + i * 4).unwrap() as i32)
+            .collect::<Vec<_>>();
+        let mut expected = items.clone();
+        expected.sort_unstable();
+        assert_eq!(sorted, expected, "sorting {:?}", items);
+    }
+}
+
+fn host_dispatch(op: i32, a: i32, b: i32) -> i32 {
+    match op {
+        0 => a.wrapping_add(b),
+        1 => a.wrapping_sub(b),
+        2 => a.wrapping_mul(b),
+        3 => a ^ (b << 3),
+        4 => (a >> 2) | b,
+        _ => -1,
+    }
+}
+
+#[test_log::test]
+fn dispatch() {
+    for switch_tables in [false, true] {
+        let mut runtime = runtime_with(Recompiler::builder().switch_tables(switch_tables));
+        let dispatch = address_of(&runtime, Export::Dispatch);
+
+        // every case, the default on both sides & an index that would be way past the table
+        for op in [-1, 0, 1, 2, 3, 4, 5, 6, 0x4000_0000, i32::MIN] {
+            for (a, b) in [(100, 7), (-13, 0x10001), (i32::MIN, -1)] {
+                let result = runtime
+                    .call_guest(dispatch, &[op as u32, a as u32, b as u32])
+                    .unwrap() as i32;
+                assert_eq!(
+                    result,
+                    host_dispatch(op, a, b),
+                    "dispatch({}, {}, {}) with switch_tables = {}",
+                    op,
+                    a,
+                    b,
+                    switch_tables
+                );
+            }
+        }
+    }
+}
@@ -1,3 +1,5 @@
+#![cfg(feature = "llvm")]
+
 // let's make the scope dirty!!
 #[macro_use]
 pub mod common;
@@ -0,0 +1,37 @@
+# A boot-sector-style program for CodeMode::Bits16, loaded at 0000:7c00.
+# The segment bases are not loaded by the code (there are no segment register loads),
+# the embedder sets them up: DS = 0x0800, ES = 0x0900, CS = SS = 0.
+# Vector 0x40 goes to `handler` through the InterruptVectorTable, int 0x20 stops the run.
+    .intel_syntax noprefix
+    .code16
+    .text
+start:
+    mov ax, 0x1234
+    mov di, 0
+    mov cx, 8
+    rep stosw                   # ES:0000, 8 words of 0x1234
+    mov eax, 0xdeadbeef
+    mov dword ptr [0x10], eax   # DS:0010
+    mov bx, 0x20
+    mov si, 2
+    mov byte ptr [bx + si], 0x55
+    call sum
+    int 0x40
+    mov word ptr [0x30], bx
+    mov word ptr [0x32], dx
+    int 0x20
+
+sum:
+    add bx, ax
+    push ax
+    pop dx
+    mov cx, 4
+1:
+    add dx, cx
+    dec cx
+    jnz 1b
+    ret
+
+handler:
+    inc bx
+    iret
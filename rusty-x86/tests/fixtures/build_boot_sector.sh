@@ -0,0 +1,8 @@
+#!/bin/sh
+# Rebuilds boot_sector.bin from boot_sector.s, needs GNU as & objcopy (binutils with i386 support)
+set -e
+cd "$(dirname "$0")"
+
+as --32 -o boot_sector.o boot_sector.s
+objcopy -O binary -j .text boot_sector.o boot_sector.bin
+rm boot_sector.o
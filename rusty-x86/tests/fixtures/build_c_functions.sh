@@ -0,0 +1,11 @@
+#!/bin/sh
+# Rebuilds c_functions.bin from c_functions.c, needs a gcc that can target i686 (-m32)
+set -e
+cd "$(dirname "$0")"
+
+gcc -m32 -march=i686 -O2 -ffreestanding -fno-pic -fno-stack-protector -fcf-protection=none \
+    -fno-asynchronous-unwind-tables -fno-tree-vectorize -c c_functions.c -o c_functions.o
+# the base address is in the script, keep it in sync with C_FUNCTIONS_BASE in tests/compiled_c.rs
+ld -m elf_i386 -nostdlib -T c_functions.ld -e 0 -o c_functions.elf c_functions.o
+objcopy -O binary c_functions.elf c_functions.bin
+rm c_functions.o c_functions.elf
@@ -0,0 +1,9 @@
+#!/bin/sh
+# Rebuilds hello.com from hello.s, needs GNU as, ld & objcopy (binutils with i386 support)
+set -e
+cd "$(dirname "$0")"
+
+as --32 -o hello.o hello.s
+ld -m elf_i386 -Ttext=0x100 -e 0x100 -o hello.elf hello.o
+objcopy -O binary -j .text hello.elf hello.com
+rm hello.o hello.elf
@@ -0,0 +1,8 @@
+#!/bin/sh
+# Rebuilds stack16.bin from stack16.s, needs GNU as & objcopy (binutils with i386 support)
+set -e
+cd "$(dirname "$0")"
+
+as --32 -o stack16.o stack16.s
+objcopy -O binary -j .text stack16.o stack16.bin
+rm stack16.o
@@ -0,0 +1,71 @@
+/*
+ * Guest code for tests/compiled_c.rs, built by build_c_functions.sh into a flat binary.
+ *
+ * No libc: everything the functions need is in here. The binary starts with `exports`,
+ * the addresses of the functions (the code is linked at C_FUNCTIONS_BASE).
+ */
+
+typedef unsigned int u32;
+typedef unsigned char u8;
+
+/* the reflected CRC-32 used by zlib & friends, a bit at a time */
+u32 crc32(const u8 *data, u32 len)
+{
+    u32 crc = 0xffffffff;
+    for (u32 i = 0; i < len; i++) {
+        crc ^= data[i];
+        for (int bit = 0; bit < 8; bit++)
+            crc = (crc >> 1) ^ (0xedb88320 & -(crc & 1));
+    }
+    return ~crc;
+}
+
+static void swap(int *a, int *b)
+{
+    int t = *a;
+    *a = *b;
+    *b = t;
+}
+
+/* Lomuto partition, recursing into the smaller half */
+void quicksort(int *items, int count)
+{
+    while (count > 1) {
+        int pivot = items[count - 1];
+        int store = 0;
+        for (int i = 0; i < count - 1; i++) {
+            if (items[i] < pivot)
+                swap(&items[i], &items[store++]);
+        }
+        swap(&items[store], &items[count - 1]);
+
+        if (store < count - store - 1) {
+            quicksort(items, store);
+            items += store + 1;
+            count -= store + 1;
+        } else {
+            quicksort(items + store + 1, count - store - 1);
+            count = store;
+        }
+    }
+}
+
+/* dense enough for a jump table, the default is out of line */
+int dispatch(int op, int a, int b)
+{
+    switch (op) {
+    case 0: return a + b;
+    case 1: return a - b;
+    case 2: return a * b;
+    case 3: return a ^ (b << 3);
+    case 4: return (a >> 2) | b;
+    default: return -1;
+    }
+}
+
+__attribute__((section(".exports"), used))
+const void *exports[] = {
+    (const void *)crc32,
+    (const void *)quicksort,
+    (const void *)dispatch,
+};
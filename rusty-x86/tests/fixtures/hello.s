@@ -0,0 +1,50 @@
+# A DOS .COM program for dos.rs, loaded at offset 0x100 of its segment (hence -Ttext=0x100 in the build).
+# Greets, asks for a name, reads it up to the Enter (echoed by the read), greets it & exits with its length.
+# The int 21h functions used: 09 (print a $-terminated string), 01 (read a character), 02 (write one), 4C (exit)
+    .intel_syntax noprefix
+    .code16
+    .text
+start:
+    mov dx, offset greeting
+    call print
+    mov dx, offset prompt
+    call print
+    xor bx, bx
+read:
+    mov ah, 0x01
+    int 0x21
+    cmp al, 0x0d
+    je done
+    mov byte ptr [bx + name], al
+    inc bx
+    jmp read
+done:
+    mov byte ptr [bx + name], '$'
+    # the read echoed the CR
+    mov dl, 0x0a
+    mov ah, 0x02
+    int 0x21
+    mov dx, offset hi
+    call print
+    mov dx, offset name
+    call print
+    mov dl, '!'
+    mov ah, 0x02
+    int 0x21
+    mov al, bl
+    mov ah, 0x4c
+    int 0x21
+
+print:
+    mov ah, 0x09
+    int 0x21
+    ret
+
+greeting:
+    .ascii "Hello, world!\r\n$"
+prompt:
+    .ascii "Name? $"
+hi:
+    .ascii "Hi, $"
+name:
+    .space 32
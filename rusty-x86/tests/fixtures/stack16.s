@@ -0,0 +1,19 @@
+# The 16-bit stack for CodeMode::Bits16, loaded at 0000:7c00.
+# The embedder sets up SS = 0x1000 & DS = 0x0700 and starts with SP = 0,
+# so the first push wraps around to SS:fffe (linear 0x1fffe). int 0x20 stops the run.
+    .intel_syntax noprefix
+    .code16
+    .text
+start:
+    mov ax, 0xbeef
+    push ax                     # SS:fffe
+    mov bp, sp
+    mov bx, word ptr [bp]       # SS is the default segment for bp
+    mov word ptr [0x10], sp     # DS:0010
+    call get_return_address     # SS:fffc
+    pop cx                      # SP wraps back to 0
+    int 0x20
+
+get_return_address:
+    mov dx, word ptr [bp - 2]
+    ret
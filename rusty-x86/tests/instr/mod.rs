@@ -47,6 +47,8 @@ mod mov {
 }
 
 mod movzx {
+    use crate::common::MEM_ADDR;
+
     test_snippets! {
         movzx_16_0: (
             ; mov ax, 0
@@ -88,9 +90,64 @@ mod movzx {
             ; movzx eax, al
         ) [CF ZF SF OF],
     }
+    test_snippets! {
+        movzx_8_other: (
+            ; mov eax, -0x2152_4111
+            ; mov ebx, -1
+            ; movzx ebx, al
+        ) [],
+        movzx_8_high: (
+            ; mov eax, 0x1234_f678
+            ; mov ebx, 0x1122_3344
+            ; mov ecx, 0x5566_7788
+            ; mov edx, -0x0100_0000
+            ; movzx esi, ah
+            ; movzx edi, bh
+            ; movzx ecx, ch
+            ; movzx edx, dh
+        ) [],
+        // the bits above the 16-bit destination stay
+        movzx_8_to_16: (
+            ; mov eax, 0x1234_5680
+            ; mov ebx, -1
+            ; mov ecx, 0x5566_7788
+            ; movzx bx, al
+            ; movzx cx, ah
+        ) [],
+        movzx_16_to_16: (
+            ; mov eax, 0x1234_8678
+            ; mov ebx, 0x5566_7788
+            // movzx bx, ax: the assembler won't take it, a plain mov in effect
+            ; .bytes [0x66_u8, 0x0f, 0xb7, 0xd8].iter().copied()
+        ) [],
+    }
+    test_snippets! {
+        movzx_mem_16: (
+            ; mov DWORD [MEM_ADDR as i32], -0x2152_4111
+            ; mov ecx, -1
+            ; movzx ecx, WORD [MEM_ADDR as i32]
+        ) [],
+        movzx_mem_16_high: (
+            ; mov DWORD [MEM_ADDR as i32], -0x2152_4111
+            ; movzx edx, WORD [MEM_ADDR as i32 + 2]
+        ) [],
+        movzx_mem_8: (
+            ; mov DWORD [MEM_ADDR as i32], -0x2152_4111
+            ; mov ebx, MEM_ADDR as i32
+            ; movzx eax, BYTE [ebx]
+            ; movzx esi, BYTE [ebx + 3]
+        ) [],
+        movzx_mem_8_to_16: (
+            ; mov DWORD [MEM_ADDR as i32], -0x2152_4111
+            ; mov edi, -1
+            ; movzx di, BYTE [MEM_ADDR as i32 + 1]
+        ) [],
+    }
 }
 
 mod movsx {
+    use crate::common::MEM_ADDR;
+
     test_snippets! {
         movsx_16_0: (
             ; mov ax, 0
@@ -132,6 +189,65 @@ mod movsx {
             ; movsx eax, al
         ) [CF ZF SF OF],
     }
+    // the flags are those of the cmp before
+    test_snippets! {
+        movsx_8_other: (
+            ; mov eax, 0x1234_56f8
+            ; cmp eax, 0x1234_56f8
+            ; movsx ebx, al
+            ; movsx ecx, al
+        ) [CF ZF SF OF],
+        movsx_8_high: (
+            ; mov eax, 0x1234_f678
+            ; mov ebx, 0x1122_3344
+            ; mov ecx, 0x5566_8788
+            ; mov edx, -0x00ff_0001
+            ; cmp eax, ebx
+            ; movsx esi, ah
+            ; movsx edi, bh
+            ; movsx ecx, ch
+            ; movsx edx, dh
+        ) [CF ZF SF OF],
+        // the bits above the 16-bit destination stay
+        movsx_8_to_16: (
+            ; mov eax, 0x1234_5680
+            ; mov ebx, 0x7777_7777
+            ; mov ecx, -1
+            ; cmp ecx, eax
+            ; movsx bx, al
+            ; movsx cx, ah
+        ) [CF ZF SF OF],
+        movsx_16_overlap: (
+            ; mov eax, 0x1234_8678
+            ; mov edx, 0x1234_7fff
+            ; cmp edx, eax
+            ; movsx eax, ax
+            ; movsx edx, dx
+        ) [CF ZF SF OF],
+    }
+    test_snippets! {
+        movsx_mem_16: (
+            ; mov DWORD [MEM_ADDR as i32], -0x2152_4111
+            ; mov ecx, 0
+            ; movsx ecx, WORD [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        movsx_mem_16_positive: (
+            ; mov DWORD [MEM_ADDR as i32], 0x1234_5678
+            ; mov edx, -1
+            ; movsx edx, WORD [MEM_ADDR as i32 + 2]
+        ) [CF ZF SF OF],
+        movsx_mem_8: (
+            ; mov DWORD [MEM_ADDR as i32], -0x2152_4111
+            ; mov ebx, MEM_ADDR as i32
+            ; movsx eax, BYTE [ebx]
+            ; movsx esi, BYTE [ebx + 2]
+        ) [CF ZF SF OF],
+        movsx_mem_8_to_16: (
+            ; mov DWORD [MEM_ADDR as i32], 0x0000_8000
+            ; mov edi, 0x7777_7777
+            ; movsx di, BYTE [MEM_ADDR as i32 + 1]
+        ) [CF ZF SF OF],
+    }
 }
 
 mod sub {
@@ -177,6 +293,48 @@ mod sub {
             ; sub eax, 0
         ) [CF ZF SF OF],
     }
+    test_snippets! {
+        sub_16_0x7fff_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x7fff
+            ; sub ax, 1
+        ) [CF ZF SF OF],
+        sub_16_neg_0x8000_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; sub ax, 1
+        ) [CF ZF SF OF],
+        sub_16_neg_0x8000_neg_0x8000: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; sub ax, -0x8000
+        ) [CF ZF SF OF],
+        sub_16_neg_1_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, -1
+            ; sub ax, 1
+        ) [CF ZF SF OF],
+        sub_8_0x7f_1: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x7f
+            ; sub al, 1
+        ) [CF ZF SF OF],
+        sub_8_neg_0x80_1: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; sub al, 1
+        ) [CF ZF SF OF],
+        sub_8_neg_0x80_neg_0x80: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; sub al, -0x80
+        ) [CF ZF SF OF],
+        sub_8_neg_1_1: (
+            ; mov eax, 0x12345678
+            ; mov al, -1
+            ; sub al, 1
+        ) [CF ZF SF OF],
+    }
 }
 
 mod stc_clc {
@@ -198,6 +356,68 @@ mod stc_clc {
     }
 }
 
+// the assembler doesn't know salc (0xd6)
+mod salc {
+    test_snippets! {
+        salc_cf: (
+            ; mov eax, 0x12345678
+            ; stc
+            ; .byte -0x2a
+        ) [CF ZF SF OF],
+        salc_no_cf: (
+            ; mov eax, 0x12345678
+            ; clc
+            ; .byte -0x2a
+        ) [CF ZF SF OF],
+    }
+}
+
+// the assembler only has the base 10 forms, the others are `.bytes [0xd4 (aam) or 0xd5 (aad), base]`
+mod bcd_adjust {
+    test_snippets! {
+        aam_10: (
+            ; mov eax, 0x1234_5663
+            ; aam
+        ) [ZF SF],
+        aam_10_zero: (
+            ; mov eax, 0x1234_5600
+            ; aam
+        ) [ZF SF],
+        aam_16: (
+            ; mov eax, 0x1234_56fc
+            ; .bytes [0xd4_u8, 16].iter().copied()
+        ) [ZF SF],
+        aam_2: (
+            ; mov eax, 0x1234_5603
+            ; .bytes [0xd4_u8, 2].iter().copied()
+        ) [ZF SF],
+        aam_255: (
+            ; mov eax, 0xfe
+            ; .bytes [0xd4_u8, 255].iter().copied()
+        ) [ZF SF],
+        aad_10: (
+            ; mov eax, 0x1234_0907
+            ; aad
+        ) [ZF SF],
+        aad_10_sign: (
+            ; mov eax, 0x1234_0c0a
+            ; aad
+        ) [ZF SF],
+        aad_16: (
+            ; mov eax, 0x1234_0a0b
+            ; .bytes [0xd5_u8, 16].iter().copied()
+        ) [ZF SF],
+        aad_2: (
+            ; mov eax, -1
+            ; .bytes [0xd5_u8, 2].iter().copied()
+        ) [ZF SF],
+        aad_2_zero: (
+            ; mov eax, 0x80
+            ; .bytes [0xd5_u8, 2].iter().copied()
+        ) [ZF SF],
+    }
+}
+
 mod sbb {
     test_snippets! {
         sbb_1_2: (
@@ -294,6 +514,147 @@ mod sbb {
     }
 }
 
+mod adc {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        adc_1_2: (
+            ; mov eax, 1
+            ; adc eax, 2
+        ) [CF ZF SF OF],
+        adc_neg_1_1: (
+            ; mov eax, -1
+            ; adc eax, 1
+        ) [CF ZF SF OF],
+        adc_0x7fffffff_1: (
+            ; mov eax, 0x7fffffff
+            ; adc eax, 1
+        ) [CF ZF SF OF],
+        adc_neg_0x80000000_neg_1: (
+            ; mov eax, -0x80000000
+            ; adc eax, -1
+        ) [CF ZF SF OF],
+
+        stc_adc_1_2: (
+            ; stc
+            ; mov eax, 1
+            ; adc eax, 2
+        ) [CF ZF SF OF],
+        stc_adc_neg_1_0: (
+            ; stc
+            ; mov eax, -1
+            ; adc eax, 0
+        ) [CF ZF SF OF],
+        stc_adc_neg_1_neg_1: (
+            ; stc
+            ; mov eax, -1
+            ; adc eax, -1
+        ) [CF ZF SF OF],
+        stc_adc_0x7fffffff_0: (
+            ; stc
+            ; mov eax, 0x7fffffff
+            ; adc eax, 0
+        ) [CF ZF SF OF],
+        stc_adc_neg_0x80000000_neg_1: (
+            ; stc
+            ; mov eax, -0x80000000
+            ; adc eax, -1
+        ) [CF ZF SF OF],
+        stc_adc_reg: (
+            ; stc
+            ; mov eax, 0x7ffffffe
+            ; mov ecx, 1
+            ; adc eax, ecx
+        ) [CF ZF SF OF],
+        stc_adc_mem: (
+            ; stc
+            ; mov DWORD [MEM_ADDR as i32], -2
+            ; mov eax, 1
+            ; adc eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_adc_to_mem: (
+            ; stc
+            ; mov DWORD [MEM_ADDR as i32], -2
+            ; adc DWORD [MEM_ADDR as i32], 1
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+    }
+    // the 64-bit adds of the 32-bit code
+    test_snippets! {
+        add_adc_carry: (
+            ; mov eax, 5
+            ; mov ebx, 7
+            ; add eax, -1
+            ; adc ebx, 0
+        ) [CF ZF SF OF],
+        add_adc_no_carry: (
+            ; mov eax, 0
+            ; mov ebx, 7
+            ; add eax, -1
+            ; adc ebx, 0
+        ) [CF ZF SF OF],
+        add_adc_chain: (
+            ; mov eax, -1
+            ; mov ebx, -1
+            ; mov ecx, 0x7fffffff
+            ; add eax, 1
+            ; adc ebx, 0
+            ; adc ecx, 0
+        ) [CF ZF SF OF],
+        add_adc_64: (
+            ; mov eax, -0x10
+            ; mov edx, 0x12345678
+            ; mov ecx, 0x20
+            ; mov ebx, -0x12345679
+            ; add eax, ecx
+            ; adc edx, ebx
+        ) [CF ZF SF OF],
+    }
+    test_snippets! {
+        stc_adc_16_0x7fff_0: (
+            ; mov eax, 0x12345678
+            ; stc
+            ; mov ax, 0x7fff
+            ; adc ax, 0
+        ) [CF ZF SF OF],
+        stc_adc_16_neg_1_0: (
+            ; mov eax, 0x12345678
+            ; stc
+            ; mov ax, -1
+            ; adc ax, 0
+        ) [CF ZF SF OF],
+        add_adc_16: (
+            ; mov eax, 0x12345678
+            ; mov ebx, 0x12345678
+            ; mov ax, -1
+            ; mov bx, 0x7fff
+            ; add ax, 1
+            ; adc bx, 0
+        ) [CF ZF SF OF],
+        stc_adc_8_0x7f_0: (
+            ; mov eax, 0x12345678
+            ; stc
+            ; mov al, 0x7f
+            ; adc al, 0
+        ) [CF ZF SF OF],
+        stc_adc_8_neg_0x80_neg_1: (
+            ; mov eax, 0x12345678
+            ; stc
+            ; mov al, -0x80
+            ; adc al, -1
+        ) [CF ZF SF OF],
+        add_adc_8: (
+            ; mov eax, 0x12345678
+            ; mov ebx, 0x12345678
+            ; mov al, -1
+            ; mov ah, -1
+            ; add al, 1
+            ; adc ah, 0
+            ; adc bl, ah
+        ) [CF ZF SF OF],
+    }
+}
+
 mod add {
     test_snippets! {
         add_borrow: (
@@ -309,7 +670,7 @@ mod add {
             ; ->L1:
             ; mov ebx, 2
             ; ->R:
-            ; mov edx, 1 // necessary because of funky control flow at the end of test snippets...
+            ; checkpoint
         ) [CF ZF SF OF],
         add_cmov_sign: (
             ; mov eax, 1
@@ -324,6 +685,48 @@ mod add {
             ; cmovs ebx, ecx
         ) [CF ZF SF OF],
     }
+    test_snippets! {
+        add_16_0x7fff_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x7fff
+            ; add ax, 1
+        ) [CF ZF SF OF],
+        add_16_neg_0x8000_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; add ax, 1
+        ) [CF ZF SF OF],
+        add_16_neg_0x8000_neg_0x8000: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; add ax, -0x8000
+        ) [CF ZF SF OF],
+        add_16_neg_1_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, -1
+            ; add ax, 1
+        ) [CF ZF SF OF],
+        add_8_0x7f_1: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x7f
+            ; add al, 1
+        ) [CF ZF SF OF],
+        add_8_neg_0x80_1: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; add al, 1
+        ) [CF ZF SF OF],
+        add_8_neg_0x80_neg_0x80: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; add al, -0x80
+        ) [CF ZF SF OF],
+        add_8_neg_1_1: (
+            ; mov eax, 0x12345678
+            ; mov al, -1
+            ; add al, 1
+        ) [CF ZF SF OF],
+    }
 }
 
 mod cmp {
@@ -369,6 +772,237 @@ mod cmp {
             ; cmp eax, 0x73fc32b6
         ) [CF ZF SF OF],
     }
+    test_snippets! {
+        cmp_16_0x7fff_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x7fff
+            ; cmp ax, 1
+        ) [CF ZF SF OF],
+        cmp_16_neg_0x8000_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; cmp ax, 1
+        ) [CF ZF SF OF],
+        cmp_16_neg_0x8000_neg_0x8000: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; cmp ax, -0x8000
+        ) [CF ZF SF OF],
+        cmp_16_neg_1_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, -1
+            ; cmp ax, 1
+        ) [CF ZF SF OF],
+        cmp_8_0x7f_1: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x7f
+            ; cmp al, 1
+        ) [CF ZF SF OF],
+        cmp_8_neg_0x80_1: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; cmp al, 1
+        ) [CF ZF SF OF],
+        cmp_8_neg_0x80_neg_0x80: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; cmp al, -0x80
+        ) [CF ZF SF OF],
+        cmp_8_neg_1_1: (
+            ; mov eax, 0x12345678
+            ; mov al, -1
+            ; cmp al, 1
+        ) [CF ZF SF OF],
+    }
+}
+
+mod setcc {
+    use crate::common::MEM_ADDR;
+
+    // each condition after a cmp that satisfies it & one that doesn't, into the bytes of registers set to -1
+    test_snippets! {
+        seto: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 0x7fff_ffff
+            ; cmp eax, -1
+            ; seto bl
+            ; mov ecx, 1
+            ; cmp ecx, 1
+            ; seto dh
+        ) [CF ZF SF OF],
+        setno: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 1
+            ; cmp eax, 1
+            ; setno bl
+            ; mov ecx, 0x7fff_ffff
+            ; cmp ecx, -1
+            ; setno dh
+        ) [CF ZF SF OF],
+        setb: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 1
+            ; cmp eax, 2
+            ; setb bl
+            ; mov ecx, 2
+            ; cmp ecx, 1
+            ; setb dh
+        ) [CF ZF SF OF],
+        setae: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 2
+            ; cmp eax, 2
+            ; setae bl
+            ; mov ecx, 1
+            ; cmp ecx, 2
+            ; setae dh
+        ) [CF ZF SF OF],
+        sete: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 5
+            ; cmp eax, 5
+            ; sete bl
+            ; mov ecx, 5
+            ; cmp ecx, 6
+            ; sete dh
+        ) [CF ZF SF OF],
+        setne: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 5
+            ; cmp eax, 6
+            ; setne bl
+            ; mov ecx, 5
+            ; cmp ecx, 5
+            ; setne dh
+        ) [CF ZF SF OF],
+        setbe: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 1
+            ; cmp eax, 1
+            ; setbe bl
+            ; mov ecx, 2
+            ; cmp ecx, 1
+            ; setbe dh
+        ) [CF ZF SF OF],
+        seta: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, -1
+            ; cmp eax, 1
+            ; seta bl
+            ; mov ecx, 1
+            ; cmp ecx, -1
+            ; seta dh
+        ) [CF ZF SF OF],
+        sets: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 1
+            ; cmp eax, 2
+            ; sets bl
+            ; mov ecx, 2
+            ; cmp ecx, 1
+            ; sets dh
+        ) [CF ZF SF OF],
+        setns: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 2
+            ; cmp eax, 1
+            ; setns bl
+            ; mov ecx, 1
+            ; cmp ecx, 2
+            ; setns dh
+        ) [CF ZF SF OF],
+        // PF comes from a float comparison, unordered with a NaN
+        setp: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 0x7fc0_0000
+            ; movd xmm0, eax
+            ; ucomiss xmm0, xmm0
+            ; setp bl
+            ; mov eax, 0x3f80_0000
+            ; movd xmm0, eax
+            ; ucomiss xmm0, xmm0
+            ; setp dh
+        ) [CF ZF SF OF],
+        setnp: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 0x3f80_0000
+            ; movd xmm0, eax
+            ; ucomiss xmm0, xmm0
+            ; setnp bl
+            ; mov eax, 0x7fc0_0000
+            ; movd xmm0, eax
+            ; ucomiss xmm0, xmm0
+            ; setnp dh
+        ) [CF ZF SF OF],
+        setl: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, -0x8000_0000
+            ; cmp eax, 1
+            ; setl bl
+            ; mov ecx, 1
+            ; cmp ecx, -1
+            ; setl dh
+        ) [CF ZF SF OF],
+        setge: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 1
+            ; cmp eax, -1
+            ; setge bl
+            ; mov ecx, -0x8000_0000
+            ; cmp ecx, 1
+            ; setge dh
+        ) [CF ZF SF OF],
+        setle: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, -1
+            ; cmp eax, -1
+            ; setle bl
+            ; mov ecx, 1
+            ; cmp ecx, -1
+            ; setle dh
+        ) [CF ZF SF OF],
+        setg: (
+            ; mov ebx, -1
+            ; mov edx, -1
+            ; mov eax, 1
+            ; cmp eax, -1
+            ; setg bl
+            ; mov ecx, -1
+            ; cmp ecx, -1
+            ; setg dh
+        ) [CF ZF SF OF],
+        setcc_mem: (
+            ; mov DWORD [MEM_ADDR as i32], -1
+            ; mov eax, 3
+            ; cmp eax, 7
+            ; setl BYTE [MEM_ADDR as i32]
+            ; seta BYTE [MEM_ADDR as i32 + 2]
+            ; mov ebx, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        // the flags of a narrower cmp
+        setcc_cmp8: (
+            ; mov eax, 0x1234_5680
+            ; cmp al, 1
+            ; setg cl
+            ; seto ch
+            ; setae dl
+        ) [CF ZF SF OF],
+    }
 }
 
 mod lea {
@@ -387,10 +1021,75 @@ mod lea {
             ; mov ebx, 337
             ; lea ecx, [eax + ebx*4 + 7]
         ) [CF ZF SF OF],
+        lea_idx_only: (
+            ; mov ebx, 337
+            ; lea ecx, [ebx*8 + 4]
+        ) [CF ZF SF OF],
+        lea_esp_base: (
+            ; lea ecx, [esp + 8]
+        ) [CF ZF SF OF],
+        lea_ebp_base: (
+            ; mov ebp, 1228
+            ; lea ecx, [ebp]
+        ) [CF ZF SF OF],
+        lea_16: (
+            ; mov eax, 0x12345678
+            ; mov ebx, 0x1111
+            ; mov ecx, -0x55555556
+            ; lea cx, [eax + ebx*2 + 7]
+        ) [CF ZF SF OF],
+        lea_wrap: (
+            ; mov eax, -16
+            ; mov ebx, 0x40000005
+            ; cmp eax, ebx
+            ; lea ecx, [eax + ebx*4 - 0x10]
+            ; lea dx, [eax + ebx*4 + 0x20]
+        ) [CF ZF SF OF],
+    }
+}
+
+// displacements below the base register
+mod negative_displacement {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        base_minus_8: (
+            ; mov eax, MEM_ADDR as i32 + 8
+            ; mov DWORD [eax - 8], 0x1337
+            ; mov ebx, [eax - 8]
+            ; add DWORD [eax - 8], -0x1338
+            ; mov ecx, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        base_index_minus_0x100: (
+            ; mov ecx, 3
+            ; mov ebx, MEM_ADDR as i32 + 0x100 - 12
+            ; mov DWORD [MEM_ADDR as i32], 0x55aa
+            ; mov eax, [ebx + ecx*4 - 0x100]
+            ; sub DWORD [ebx + ecx*4 - 0x100], 0x55ab
+            ; mov edx, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        frame_locals: (
+            ; push ebp
+            ; mov ebp, esp
+            ; sub esp, 8
+            ; mov DWORD [ebp - 4], 5
+            ; mov DWORD [ebp - 8], -7
+            ; add DWORD [ebp - 4], 3
+            ; mov eax, [ebp - 8]
+            ; xor eax, [ebp - 4]
+            ; lea ecx, [ebp - 8]
+            ; sub ecx, esp
+            ; mov edx, [ebp - 4]
+
+            ; leave
+            ; ret
+        ) [CF ZF SF OF],
     }
 }
 
 mod dec {
+    use crate::common::MEM_ADDR;
+
     test_snippets! {
         dec_0: (
             ; mov eax, 0
@@ -469,21 +1168,86 @@ mod dec {
             ; dec al
         ) [CF ZF SF OF],
     }
-}
-
-mod inc {
+    // CF is left alone, whatever it was
     test_snippets! {
-        inc_0: (
-            ; mov eax, 0
-            ; inc eax
+        stc_dec_neg_0x80000000: (
+            ; stc
+            ; mov eax, -0x80000000
+            ; dec eax
         ) [CF ZF SF OF],
-        inc_1: (
-            ; mov eax, 1
-            ; inc eax
+        stc_dec_16_neg_0x8000: (
+            ; stc
+            ; mov ax, -0x8000
+            ; dec ax
         ) [CF ZF SF OF],
-        inc_neg_1: (
-            ; mov eax, -1
-            ; inc eax
+        stc_dec_8_neg_0x80: (
+            ; stc
+            ; mov al, -0x80
+            ; dec al
+        ) [CF ZF SF OF],
+        stc_dec_mem_neg_0x80000000: (
+            ; stc
+            ; mov DWORD [MEM_ADDR as i32], -0x80000000
+            ; dec DWORD [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_dec_mem_16_neg_0x8000: (
+            ; stc
+            ; mov WORD [MEM_ADDR as i32], -0x8000
+            ; dec WORD [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_dec_mem_8_neg_0x80: (
+            ; stc
+            ; mov BYTE [MEM_ADDR as i32], -0x80
+            ; dec BYTE [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_dec_1: (
+            ; stc
+            ; mov eax, 1
+            ; dec eax
+        ) [CF ZF SF OF],
+        stc_dec_16_1: (
+            ; stc
+            ; mov ax, 1
+            ; dec ax
+        ) [CF ZF SF OF],
+        stc_dec_8_1: (
+            ; stc
+            ; mov al, 1
+            ; dec al
+        ) [CF ZF SF OF],
+        stc_dec_mem_1: (
+            ; stc
+            ; mov DWORD [MEM_ADDR as i32], 1
+            ; dec DWORD [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_dec_mem_16_1: (
+            ; stc
+            ; mov WORD [MEM_ADDR as i32], 1
+            ; dec WORD [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_dec_mem_8_1: (
+            ; stc
+            ; mov BYTE [MEM_ADDR as i32], 1
+            ; dec BYTE [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+    }
+}
+
+mod inc {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        inc_0: (
+            ; mov eax, 0
+            ; inc eax
+        ) [CF ZF SF OF],
+        inc_1: (
+            ; mov eax, 1
+            ; inc eax
+        ) [CF ZF SF OF],
+        inc_neg_1: (
+            ; mov eax, -1
+            ; inc eax
         ) [CF ZF SF OF],
         inc_neg_2: (
             ; mov eax, -2
@@ -550,9 +1314,74 @@ mod inc {
             ; inc al
         ) [CF ZF SF OF],
     }
+    // CF is left alone, whatever it was
+    test_snippets! {
+        stc_inc_0x7fffffff: (
+            ; stc
+            ; mov eax, 0x7fffffff
+            ; inc eax
+        ) [CF ZF SF OF],
+        stc_inc_16_0x7fff: (
+            ; stc
+            ; mov ax, 0x7fff
+            ; inc ax
+        ) [CF ZF SF OF],
+        stc_inc_8_0x7f: (
+            ; stc
+            ; mov al, 0x7f
+            ; inc al
+        ) [CF ZF SF OF],
+        stc_inc_mem_0x7fffffff: (
+            ; stc
+            ; mov DWORD [MEM_ADDR as i32], 0x7fffffff
+            ; inc DWORD [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_inc_mem_16_0x7fff: (
+            ; stc
+            ; mov WORD [MEM_ADDR as i32], 0x7fff
+            ; inc WORD [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_inc_mem_8_0x7f: (
+            ; stc
+            ; mov BYTE [MEM_ADDR as i32], 0x7f
+            ; inc BYTE [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_inc_neg_1: (
+            ; stc
+            ; mov eax, -1
+            ; inc eax
+        ) [CF ZF SF OF],
+        stc_inc_16_neg_1: (
+            ; stc
+            ; mov ax, -1
+            ; inc ax
+        ) [CF ZF SF OF],
+        stc_inc_8_neg_1: (
+            ; stc
+            ; mov al, -1
+            ; inc al
+        ) [CF ZF SF OF],
+        stc_inc_mem_neg_1: (
+            ; stc
+            ; mov DWORD [MEM_ADDR as i32], -1
+            ; inc DWORD [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_inc_mem_16_neg_1: (
+            ; stc
+            ; mov WORD [MEM_ADDR as i32], -1
+            ; inc WORD [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        stc_inc_mem_8_neg_1: (
+            ; stc
+            ; mov BYTE [MEM_ADDR as i32], -1
+            ; inc BYTE [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+    }
 }
 
 mod neg {
+    use crate::common::MEM_ADDR;
+
     test_snippets! {
         neg_0: (
             ; mov eax, 0
@@ -618,6 +1447,84 @@ mod neg {
             ; neg al
         ) [CF ZF SF OF],
     }
+    // CF is clear only for 0, OF is set only for the minimal signed value
+    test_snippets! {
+        neg_1: (
+            ; mov eax, 1
+            ; neg eax
+        ) [CF ZF SF OF],
+        neg_neg_0x80000000: (
+            ; mov eax, -0x80000000
+            ; neg eax
+        ) [CF ZF SF OF],
+        neg_0x7fffffff: (
+            ; mov eax, 0x7fffffff
+            ; neg eax
+        ) [CF ZF SF OF],
+        neg_16_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, 1
+            ; neg ax
+        ) [CF ZF SF OF],
+        neg_16_neg_0x8000: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; neg ax
+        ) [CF ZF SF OF],
+        neg_8_1: (
+            ; mov eax, 0x12345678
+            ; mov al, 1
+            ; neg al
+        ) [CF ZF SF OF],
+        neg_8_neg_0x80: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; neg al
+        ) [CF ZF SF OF],
+        neg_8_hi_neg_0x80: (
+            ; mov eax, 0x12345678
+            ; mov ah, -0x80
+            ; neg ah
+        ) [CF ZF SF OF],
+        stc_neg_0: (
+            ; stc
+            ; mov eax, 0
+            ; neg eax
+        ) [CF ZF SF OF],
+    }
+    test_snippets! {
+        neg_mem_0: (
+            ; stc
+            ; mov DWORD [MEM_ADDR as i32], 0
+            ; neg DWORD [MEM_ADDR as i32]
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        neg_mem_neg_0x80000000: (
+            ; mov DWORD [MEM_ADDR as i32], -0x80000000
+            ; neg DWORD [MEM_ADDR as i32]
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        neg_mem_16_1: (
+            ; mov WORD [MEM_ADDR as i32], 1
+            ; neg WORD [MEM_ADDR as i32]
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        neg_mem_16_neg_0x8000: (
+            ; mov WORD [MEM_ADDR as i32], -0x8000
+            ; neg WORD [MEM_ADDR as i32]
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        neg_mem_8_neg_0x80: (
+            ; mov BYTE [MEM_ADDR as i32], -0x80
+            ; neg BYTE [MEM_ADDR as i32]
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        neg_mem_8_42: (
+            ; mov BYTE [MEM_ADDR as i32], 42
+            ; neg BYTE [MEM_ADDR as i32]
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+    }
 }
 
 mod cdq {
@@ -665,6 +1572,56 @@ mod cdq {
             ; cwd
         ) [CF ZF SF OF],
     }
+    test_snippets! {
+        cwde_0x7fff: (
+            ; mov eax, 0x1234_7fff
+            ; cwde
+        ) [CF ZF SF OF],
+        cwde_neg_0x8000: (
+            ; mov eax, 0x1234_8000
+            ; cwde
+        ) [CF ZF SF OF],
+        cwde_neg_1: (
+            ; mov eax, 0xffff
+            ; cwde
+        ) [CF ZF SF OF],
+        // the upper half of EAX stays
+        cbw_0x7f: (
+            ; mov eax, 0x1234_567f
+            ; cbw
+        ) [CF ZF SF OF],
+        cbw_neg_0x80: (
+            ; mov eax, 0x1234_5680
+            ; cbw
+        ) [CF ZF SF OF],
+        cbw_zero: (
+            ; mov eax, -0x100
+            ; cbw
+        ) [CF ZF SF OF],
+    }
+    // what compilers emit for a signed division
+    test_snippets! {
+        cdq_idiv: (
+            ; mov eax, -1000
+            ; mov edx, 1337
+            ; mov ecx, 7
+            ; cdq
+            ; idiv ecx
+        ) [],
+        cwd_idiv: (
+            ; mov eax, 1000
+            ; mov edx, -1
+            ; mov ecx, -7
+            ; cwd
+            ; idiv cx
+        ) [],
+        cbw_idiv: (
+            ; mov eax, 0x1234_5690
+            ; mov ecx, 5
+            ; cbw
+            ; idiv cl
+        ) [],
+    }
 }
 
 mod mem {
@@ -678,7 +1635,193 @@ mod mem {
     }
 }
 
+// only the in-range cases, out of range ones raise #BR (see the interpreter tests for those)
+mod bound {
+    use crate::common::MEM_ADDR;
+    test_snippets! {
+        bound_within: (
+            ; mov DWORD [MEM_ADDR as i32], -5
+            ; mov DWORD [MEM_ADDR as i32 + 4], 10
+            ; mov eax, 3
+            ; bound eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        bound_equal_lower: (
+            ; mov DWORD [MEM_ADDR as i32], -5
+            ; mov DWORD [MEM_ADDR as i32 + 4], 10
+            ; mov eax, -5
+            ; bound eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+        bound_equal_upper: (
+            ; mov DWORD [MEM_ADDR as i32], -5
+            ; mov DWORD [MEM_ADDR as i32 + 4], 10
+            ; mov eax, 10
+            ; bound eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+    }
+}
+
+mod x87 {
+    use crate::common::MEM_ADDR;
+    // only the exact cases: the rusty-x86 side computes in doubles, not in 80 bits
+    test_snippets! {
+        fabs_neg_zero: (
+            ; mov DWORD [MEM_ADDR as i32 + 4], -0x80000000
+            ; fld QWORD [MEM_ADDR as i32]
+            ; fabs
+            ; fstp QWORD [MEM_ADDR as i32 + 8]
+        ) [CF ZF SF OF],
+        fchs: (
+            ; mov DWORD [MEM_ADDR as i32 + 4], 0x40040000
+            ; fld QWORD [MEM_ADDR as i32]
+            ; fchs
+            ; fstp QWORD [MEM_ADDR as i32 + 8]
+        ) [CF ZF SF OF],
+        fsqrt: (
+            ; mov DWORD [MEM_ADDR as i32 + 4], 0x40190000
+            ; fld QWORD [MEM_ADDR as i32]
+            ; fsqrt
+            ; fstp QWORD [MEM_ADDR as i32 + 8]
+        ) [CF ZF SF OF],
+        frndint_nearest: (
+            ; mov DWORD [MEM_ADDR as i32 + 4], 0x40040000
+            ; fld QWORD [MEM_ADDR as i32]
+            ; frndint
+            ; fstp QWORD [MEM_ADDR as i32 + 8]
+        ) [CF ZF SF OF],
+        frndint_truncate: (
+            ; mov DWORD [MEM_ADDR as i32 + 4], -0x3ffc0000
+            ; mov WORD [MEM_ADDR as i32 + 16], 0x0f7f
+            ; fldcw WORD [MEM_ADDR as i32 + 16]
+            ; fld QWORD [MEM_ADDR as i32]
+            ; frndint
+            ; fstp QWORD [MEM_ADDR as i32 + 8]
+            ; fnstcw WORD [MEM_ADDR as i32 + 18]
+        ) [CF ZF SF OF],
+        constants_fxch: (
+            ; fldz
+            ; fld1
+            ; fldpi
+            ; fxch st2
+            ; fstp QWORD [MEM_ADDR as i32]
+            ; fstp QWORD [MEM_ADDR as i32 + 8]
+            ; fstp QWORD [MEM_ADDR as i32 + 16]
+        ) [CF ZF SF OF],
+    }
+}
+
+mod mul {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        mul_8: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov al, 23
+            ; mov bl, 5
+            ; mul bl
+        ) [CF OF],
+        mul_8_boundary: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov al, 0x7f
+            ; mov bl, 2
+            ; mul bl
+        ) [CF OF],
+        mul_8_overflow: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov al, -0x80
+            ; mov bl, 2
+            ; mul bl
+        ) [CF OF],
+        mul_16: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, 23
+            ; mov bx, 5
+            ; mul bx
+        ) [CF OF],
+        mul_16_boundary: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, 0x7fff
+            ; mov bx, 2
+            ; mul bx
+        ) [CF OF],
+        mul_16_overflow: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, -0x8000
+            ; mov bx, 2
+            ; mul bx
+        ) [CF OF],
+        mul_32: (
+            ; mov eax, -0x80000000
+            ; mov ebx, 2
+            ; mul ebx
+        ) [CF OF],
+        mul_32_fits: (
+            ; mov eax, 0x10000
+            ; mov edx, 0x1abcdef0
+            ; mov ebx, 0xffff
+            ; mul ebx
+        ) [CF OF],
+        mul_32_boundary: (
+            ; mov eax, 0x7fffffff
+            ; mov ebx, 2
+            ; mul ebx
+        ) [CF OF],
+        mul_32_max: (
+            ; mov eax, -1
+            ; mov ebx, -1
+            ; mul ebx
+        ) [CF OF],
+        mul_32_eax: (
+            ; mov eax, 0x12345678
+            ; mul eax
+        ) [CF OF],
+    }
+    // the high half of a byte product goes to AH, which can be the source too
+    test_snippets! {
+        mul_8_ah: (
+            ; mov eax, 0x1234ff10
+            ; mov edx, 0x1abcdef0
+            ; mul ah
+        ) [CF OF],
+        mul_8_ah_fits: (
+            ; mov eax, 0x12340302
+            ; mul ah
+        ) [CF OF],
+    }
+    test_snippets! {
+        mul_8_mem: (
+            ; mov eax, 0x12345678
+            ; mov BYTE [MEM_ADDR as i32], -1
+            ; mul BYTE [MEM_ADDR as i32]
+        ) [CF OF],
+        mul_16_mem: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov WORD [MEM_ADDR as i32], 0x100
+            ; mul WORD [MEM_ADDR as i32]
+        ) [CF OF],
+        mul_32_mem: (
+            ; mov eax, 0x12345678
+            ; mov DWORD [MEM_ADDR as i32], 0x10
+            ; mul DWORD [MEM_ADDR as i32]
+        ) [CF OF],
+        mul_32_mem_fits: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov DWORD [MEM_ADDR as i32], 0xf
+            ; mul DWORD [MEM_ADDR as i32]
+        ) [CF OF],
+    }
+}
+
 mod imul {
+    use crate::common::MEM_ADDR;
+
     test_snippets! {
         imul_1op_eax_eax: (
             ; mov eax, 23
@@ -738,40 +1881,290 @@ mod imul {
             ; imul eax, ebx, 0x7fffffff
         ) [CF OF],
     }
-}
-
-mod xor {
+    // the imm8 form (sign-extended) & the imm32 one
     test_snippets! {
-        xor_zero_eax: (
-            ; mov eax, 228
-            ; xor eax, eax
-        ) [CF ZF SF OF],
-        xor_zero_eax_with_ebx: (
-            ; mov eax, 228
-            ; mov ebx, 228
-            ; xor eax, ebx
-        ) [CF ZF SF OF],
-        xor_eax_ebx_rnd1: (
-            ; mov eax, 0x79d1e0e9
-            ; mov ebx, -0x16d29593
-            ; xor eax, ebx
-        ) [CF ZF SF OF],
-        xor_eax_ebx_rnd2: (
-            ; mov eax, 0x79f9322a
-            ; mov ebx, 0x801efd8
-            ; xor eax, ebx
-        ) [CF ZF SF OF],
+        imul_3op_imm8_negative: (
+            ; mov ebx, 0x12345
+            ; imul eax, ebx, -3
+        ) [CF OF],
+        imul_3op_imm8_min: (
+            ; mov ecx, -5
+            ; imul ecx, ecx, -0x80
+        ) [CF OF],
+        imul_3op_imm8_overflow: (
+            ; mov ebx, 0x40000000
+            ; imul eax, ebx, 2
+        ) [CF OF],
+        imul_3op_imm8_negative_fits: (
+            ; mov ebx, 0x40000000
+            ; imul eax, ebx, -2
+        ) [CF OF],
+        imul_3op_imm32_fits: (
+            ; mov ebx, -7
+            ; imul edx, ebx, 0x1000000
+        ) [CF OF],
+        imul_3op_imm32_overflow: (
+            ; mov ebx, 0x10
+            ; imul edx, ebx, 0x12345678
+        ) [CF OF],
+        imul_3op_rnd1: (
+            ; mov ebx, 0x3c5a1d07
+            ; imul esi, ebx, -0x5e31
+        ) [CF OF],
+        imul_3op_rnd2: (
+            ; mov ebx, -0x1f2e
+            ; imul edi, ebx, 0x2b7c4
+        ) [CF OF],
+        imul_3op_rnd3: (
+            ; mov ebx, -0x6a0d9b12
+            ; imul eax, ebx, 0x51
+        ) [CF OF],
+        imul_3op_16_imm16: (
+            ; mov ecx, -0x65432110
+            ; mov ebx, 0x12340123
+            ; imul cx, bx, 0x1234
+        ) [CF OF],
+        imul_3op_16_imm8: (
+            ; mov ecx, -0x65432110
+            ; mov ebx, 0x12340123
+            ; imul cx, bx, -0x40
+        ) [CF OF],
+        imul_3op_32_mem: (
+            ; mov DWORD [MEM_ADDR as i32], -0x1234
+            ; imul ecx, DWORD [MEM_ADDR as i32], 0x10001
+        ) [CF OF],
+        imul_3op_32_mem_imm8: (
+            ; mov DWORD [MEM_ADDR as i32], 0x7fffffff
+            ; imul edx, DWORD [MEM_ADDR as i32], -1
+        ) [CF OF],
+        imul_3op_32_mem_overflow: (
+            ; mov DWORD [MEM_ADDR as i32], -0x40000001
+            ; imul edx, DWORD [MEM_ADDR as i32], 2
+        ) [CF OF],
     }
-}
-
-mod not {
     test_snippets! {
-        not_228: (
-            ; mov eax, 228
-            ; not eax
-        ) [CF ZF SF OF],
-        not_zero: (
-            ; mov eax, 0
+        imul_1op_8: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov al, 23
+            ; mov bl, 5
+            ; imul bl
+        ) [CF OF],
+        imul_1op_8_overflow: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov al, -0x80
+            ; mov bl, -1
+            ; imul bl
+        ) [CF OF],
+        imul_1op_16: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, 23
+            ; mov bx, 5
+            ; imul bx
+        ) [CF OF],
+        imul_1op_16_overflow: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, -0x8000
+            ; mov bx, -1
+            ; imul bx
+        ) [CF OF],
+        imul_1op_8_negative_fits: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x10
+            ; mov bl, 8
+            ; imul bl
+        ) [CF OF],
+        imul_1op_8_negative_overflow: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x10
+            ; mov bl, 9
+            ; imul bl
+        ) [CF OF],
+        imul_1op_8_ah: (
+            ; mov eax, 0x1234fd10
+            ; imul ah
+        ) [CF OF],
+        imul_1op_16_negative_fits: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, -0x100
+            ; mov bx, 0x80
+            ; imul bx
+        ) [CF OF],
+        imul_1op_32_negative_fits: (
+            ; mov eax, -0x10000
+            ; mov ebx, 0x7fff
+            ; imul ebx
+        ) [CF OF],
+        imul_1op_32_negative_overflow: (
+            ; mov eax, -0x10000
+            ; mov ebx, 0x8001
+            ; imul ebx
+        ) [CF OF],
+        imul_1op_32_min: (
+            ; mov eax, -0x80000000
+            ; mov ebx, -1
+            ; imul ebx
+        ) [CF OF],
+        imul_1op_32_mem: (
+            ; mov DWORD [MEM_ADDR as i32], -3
+            ; mov eax, 0x40000000
+            ; imul DWORD [MEM_ADDR as i32]
+        ) [CF OF],
+        imul_1op_16_mem: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov WORD [MEM_ADDR as i32], -2
+            ; mov ax, 0x4000
+            ; imul WORD [MEM_ADDR as i32]
+        ) [CF OF],
+    }
+    // memory sources, with the products right around what fits in the destination as a signed value
+    test_snippets! {
+        imul_2op_16_mem_fits: (
+            ; mov eax, 0x12345678
+            ; mov WORD [MEM_ADDR as i32], 0x4000
+            ; mov ax, -2
+            ; imul ax, WORD [MEM_ADDR as i32]
+        ) [CF OF],
+        imul_2op_16_mem_overflow: (
+            ; mov eax, 0x12345678
+            ; mov WORD [MEM_ADDR as i32], 0x4000
+            ; mov ax, 2
+            ; imul ax, WORD [MEM_ADDR as i32]
+        ) [CF OF],
+        imul_2op_16_mem_7fff: (
+            ; mov eax, 0x12345678
+            ; mov WORD [MEM_ADDR as i32], 0x7fff
+            ; mov ax, 1
+            ; imul ax, WORD [MEM_ADDR as i32]
+        ) [CF OF],
+        imul_2op_16_mem_8000: (
+            ; mov eax, 0x12345678
+            ; mov WORD [MEM_ADDR as i32], -0x8000
+            ; mov ax, -1
+            ; imul ax, WORD [MEM_ADDR as i32]
+        ) [CF OF],
+        imul_2op_16_mem_square: (
+            ; mov ecx, 0x12345678
+            ; mov WORD [MEM_ADDR as i32], 0xb5
+            ; mov cx, 0xb5
+            ; imul cx, WORD [MEM_ADDR as i32]
+        ) [CF OF],
+        imul_2op_16_mem_square_overflow: (
+            ; mov ecx, 0x12345678
+            ; mov WORD [MEM_ADDR as i32], 0xb6
+            ; mov cx, 0xb5
+            ; imul cx, WORD [MEM_ADDR as i32]
+        ) [CF OF],
+        imul_3op_16_mem: (
+            ; mov ebx, 0x12345678
+            ; mov WORD [MEM_ADDR as i32], 0x4000
+            ; imul bx, WORD [MEM_ADDR as i32], -2
+        ) [CF OF],
+        imul_3op_16_mem_overflow: (
+            ; mov ebx, 0x12345678
+            ; mov WORD [MEM_ADDR as i32], 0x4000
+            ; imul bx, WORD [MEM_ADDR as i32], 2
+        ) [CF OF],
+        imul_2op_32_mem: (
+            ; mov DWORD [MEM_ADDR as i32], 0xb504
+            ; mov eax, 0xb504
+            ; imul eax, DWORD [MEM_ADDR as i32]
+        ) [CF OF],
+        imul_2op_32_mem_overflow: (
+            ; mov DWORD [MEM_ADDR as i32], 0xb505
+            ; mov eax, 0xb504
+            ; imul eax, DWORD [MEM_ADDR as i32]
+        ) [CF OF],
+    }
+}
+
+mod xor {
+    test_snippets! {
+        xor_zero_eax: (
+            ; mov eax, 228
+            ; xor eax, eax
+        ) [CF ZF SF OF],
+        xor_zero_eax_with_ebx: (
+            ; mov eax, 228
+            ; mov ebx, 228
+            ; xor eax, ebx
+        ) [CF ZF SF OF],
+        xor_eax_ebx_rnd1: (
+            ; mov eax, 0x79d1e0e9
+            ; mov ebx, -0x16d29593
+            ; xor eax, ebx
+        ) [CF ZF SF OF],
+        xor_eax_ebx_rnd2: (
+            ; mov eax, 0x79f9322a
+            ; mov ebx, 0x801efd8
+            ; xor eax, ebx
+        ) [CF ZF SF OF],
+    }
+}
+
+mod zero_idiom {
+    test_snippets! {
+        zero_idiom_jcc: (
+            ; mov eax, -1
+            ; xor eax, eax
+            ; jz ->zero
+            ; mov ebx, 1
+            ; ->zero:
+            ; jb ->done
+            ; jle ->less_equal
+            ; mov ecx, 1
+            ; ->less_equal:
+            ; js ->done
+            ; jo ->done
+            ; mov edx, 1
+            ; ->done:
+        ) [CF ZF SF OF],
+        zero_idiom_setcc: (
+            ; mov ecx, 0x80
+            ; sub ecx, ecx
+            ; sete al
+            ; setne ah
+            ; setbe bl
+            ; setg bh
+            ; setge dl
+            ; sets dh
+        ) [CF ZF SF OF],
+        // the flags after a zero idiom are there in full, not just for the folded branches
+        zero_idiom_pushfd: (
+            ; mov eax, -1
+            ; add eax, 1
+            ; sub ecx, ecx
+            ; jnz >skip
+            ; pushf
+            ; pop ebx
+            ; and ebx, 0x8c1
+            ; skip:
+        ) [CF ZF SF OF],
+        zero_idiom_cmovcc: (
+            ; mov eax, 1
+            ; mov edx, 2
+            ; xor ax, ax
+            ; cmovz ebx, edx
+            ; cmova ecx, edx
+            ; cmovno esi, edx
+            ; cmovl edi, edx
+        ) [CF ZF SF OF],
+    }
+}
+
+mod not {
+    test_snippets! {
+        not_228: (
+            ; mov eax, 228
+            ; not eax
+        ) [CF ZF SF OF],
+        not_zero: (
+            ; mov eax, 0
             ; not eax
         ) [CF ZF SF OF],
         not_ffffffff: (
@@ -957,6 +2350,68 @@ mod shr {
             ; shr eax, 34
         ) [CF ZF SF],
     }
+    test_snippets! {
+        shr_16_0x7fff_one: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x7fff
+            ; shr ax, 1
+        ) [CF ZF SF OF],
+        shr_16_0x7fff_two: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x7fff
+            ; shr ax, 2
+        ) [CF ZF SF],
+        shr_16_neg_0x8000_one: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; shr ax, 1
+        ) [CF ZF SF OF],
+        shr_16_neg_0x8000_two: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; shr ax, 2
+        ) [CF ZF SF],
+        shr_16_0x4000_one: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x4000
+            ; shr ax, 1
+        ) [CF ZF SF OF],
+        shr_16_0x4000_two: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x4000
+            ; shr ax, 2
+        ) [CF ZF SF],
+        shr_8_0x7f_one: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x7f
+            ; shr al, 1
+        ) [CF ZF SF OF],
+        shr_8_0x7f_two: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x7f
+            ; shr al, 2
+        ) [CF ZF SF],
+        shr_8_neg_0x80_one: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; shr al, 1
+        ) [CF ZF SF OF],
+        shr_8_neg_0x80_two: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; shr al, 2
+        ) [CF ZF SF],
+        shr_8_0x40_one: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; shr al, 1
+        ) [CF ZF SF OF],
+        shr_8_0x40_two: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; shr al, 2
+        ) [CF ZF SF],
+    }
 }
 
 mod sar {
@@ -1053,6 +2508,68 @@ mod sar {
             ; sar eax, 0x21
         ) [CF ZF SF OF],
     }
+    test_snippets! {
+        sar_16_0x7fff_one: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x7fff
+            ; sar ax, 1
+        ) [CF ZF SF OF],
+        sar_16_0x7fff_two: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x7fff
+            ; sar ax, 2
+        ) [CF ZF SF],
+        sar_16_neg_0x8000_one: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; sar ax, 1
+        ) [CF ZF SF OF],
+        sar_16_neg_0x8000_two: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; sar ax, 2
+        ) [CF ZF SF],
+        sar_16_0x4000_one: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x4000
+            ; sar ax, 1
+        ) [CF ZF SF OF],
+        sar_16_0x4000_two: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x4000
+            ; sar ax, 2
+        ) [CF ZF SF],
+        sar_8_0x7f_one: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x7f
+            ; sar al, 1
+        ) [CF ZF SF OF],
+        sar_8_0x7f_two: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x7f
+            ; sar al, 2
+        ) [CF ZF SF],
+        sar_8_neg_0x80_one: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; sar al, 1
+        ) [CF ZF SF OF],
+        sar_8_neg_0x80_two: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; sar al, 2
+        ) [CF ZF SF],
+        sar_8_0x40_one: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; sar al, 1
+        ) [CF ZF SF OF],
+        sar_8_0x40_two: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; sar al, 2
+        ) [CF ZF SF],
+    }
 }
 
 mod shl {
@@ -1121,48 +2638,2217 @@ mod shl {
             ; mov eax, -228
             ; shl eax, 33
         ) [CF ZF SF OF],
-        shl_neg_229_one_wrap: (
-            ; mov eax, -229
-            ; shl eax, 33
+        shl_neg_229_one_wrap: (
+            ; mov eax, -229
+            ; shl eax, 33
+        ) [CF ZF SF OF],
+        shl_neg_64_one_wrap: (
+            ; mov eax, -64
+            ; shl eax, 33
+        ) [CF ZF SF OF],
+
+        shl_228_two_wrap: (
+            ; mov eax, 228
+            ; shl eax, 34
+        ) [CF ZF SF],
+        shl_229_two_wrap: (
+            ; mov eax, 229
+            ; shl eax, 34
+        ) [CF ZF SF],
+        shl_neg_228_two_wrap: (
+            ; mov eax, -228
+            ; shl eax, 34
+        ) [CF ZF SF],
+        shl_neg_229_two_wrap: (
+            ; mov eax, -229
+            ; shl eax, 34
+        ) [CF ZF SF],
+        shl_neg_64_two_wrap: (
+            ; mov eax, -64
+            ; shl eax, 34
+        ) [CF ZF SF],
+
+        shl_edge_case_byte: (
+            ; mov al, -0x08
+            ; shl al, 0x09
+        ) [CF ZF SF OF],
+        shl_edge_case_word: (
+            ; mov ax, -0x0888
+            ; shl ax, 0x11
+        ) [CF ZF SF OF],
+        shl_edge_case_dword: (
+            ; mov eax, -0x08888888
+            ; shl eax, 0x21
+        ) [CF ZF SF OF],
+    }
+    test_snippets! {
+        shl_16_0x7fff_one: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x7fff
+            ; shl ax, 1
+        ) [CF ZF SF OF],
+        shl_16_0x7fff_two: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x7fff
+            ; shl ax, 2
+        ) [CF ZF SF],
+        shl_16_neg_0x8000_one: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; shl ax, 1
+        ) [CF ZF SF OF],
+        shl_16_neg_0x8000_two: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x8000
+            ; shl ax, 2
+        ) [CF ZF SF],
+        shl_16_0x4000_one: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x4000
+            ; shl ax, 1
+        ) [CF ZF SF OF],
+        shl_16_0x4000_two: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x4000
+            ; shl ax, 2
+        ) [CF ZF SF],
+        shl_8_0x7f_one: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x7f
+            ; shl al, 1
+        ) [CF ZF SF OF],
+        shl_8_0x7f_two: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x7f
+            ; shl al, 2
+        ) [CF ZF SF],
+        shl_8_neg_0x80_one: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; shl al, 1
+        ) [CF ZF SF OF],
+        shl_8_neg_0x80_two: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x80
+            ; shl al, 2
+        ) [CF ZF SF],
+        shl_8_0x40_one: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; shl al, 1
+        ) [CF ZF SF OF],
+        shl_8_0x40_two: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; shl al, 2
+        ) [CF ZF SF],
+    }
+    // the counts past the operand width: the flags come out of the widened shift, OF too
+    test_snippets! {
+        shl_0x12345679_31: (
+            ; mov eax, 0x12345679
+            ; shl eax, 31
+        ) [CF ZF SF OF],
+        shl_0x12345678_31: (
+            ; mov eax, 0x12345678
+            ; shl eax, 31
+        ) [CF ZF SF OF],
+        shl_neg_1_31: (
+            ; mov eax, -1
+            ; shl eax, 31
+        ) [CF ZF SF OF],
+        shl_16_neg_1_15: (
+            ; mov eax, 0x12345678
+            ; mov ax, -1
+            ; shl ax, 15
+        ) [CF ZF SF OF],
+        shl_16_0x5555_16: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x5555
+            ; shl ax, 16
+        ) [CF ZF SF OF],
+        shl_16_0x5555_31: (
+            ; mov eax, 0x12345678
+            ; mov ax, 0x5555
+            ; shl ax, 31
+        ) [CF ZF SF OF],
+        shl_8_neg_1_7: (
+            ; mov eax, 0x12345678
+            ; mov al, -1
+            ; shl al, 7
+        ) [CF ZF SF OF],
+        shl_8_0x55_8: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x55
+            ; shl al, 8
+        ) [CF ZF SF OF],
+        shl_8_0x55_31: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x55
+            ; shl al, 31
+        ) [CF ZF SF OF],
+        shl_8_0x55_33: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x55
+            ; shl al, 33
+        ) [CF ZF SF OF],
+    }
+    // a count masked to zero leaves all the flags alone, whatever they were
+    test_snippets! {
+        shl_zero_keeps_flags: (
+            ; mov ebx, 0x7fffffff
+            ; add ebx, 1
+            ; stc
+            ; mov eax, 228
+            ; shl eax, 0
+        ) [CF ZF SF OF],
+        shl_32_keeps_flags: (
+            ; mov ebx, 0x7fffffff
+            ; add ebx, 1
+            ; stc
+            ; mov eax, 228
+            ; shl eax, 32
+        ) [CF ZF SF OF],
+        shl_cl_32_keeps_flags: (
+            ; xor ebx, ebx
+            ; stc
+            ; mov ecx, 32
+            ; mov eax, -1
+            ; shl eax, cl
+        ) [CF ZF SF OF],
+        shl_8_cl_32_keeps_flags: (
+            ; mov ebx, 0x7fffffff
+            ; add ebx, 1
+            ; mov ecx, 0x60
+            ; mov eax, 0x12345678
+            ; shl al, cl
+        ) [CF ZF SF OF],
+        shl_16_cl_33: (
+            ; mov ecx, 33
+            ; mov eax, 0x12345678
+            ; mov ax, -0x4000
+            ; shl ax, cl
+        ) [CF ZF SF OF],
+    }
+}
+
+mod shld {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        shld_16_0: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 0
+        ) [CF ZF SF OF],
+        shld_16_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 1
+        ) [CF ZF SF OF],
+        shld_16_2: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 2
+        ) [CF ZF SF],
+        shld_16_15: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 15
+        ) [CF ZF SF],
+        shld_16_16: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 16
+        ) [CF ZF SF],
+        shld_16_17: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 17
+        ) [CF ZF SF],
+        shld_16_20: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 20
+        ) [CF ZF SF],
+        shld_16_31: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 31
+        ) [CF ZF SF],
+        shld_16_32: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 32
+        ) [CF ZF SF OF],
+        shld_16_33: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shld ax, bx, 33
+        ) [CF ZF SF OF],
+        shld_32_0: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shld eax, ebx, 0
+        ) [CF ZF SF OF],
+        shld_32_1: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shld eax, ebx, 1
+        ) [CF ZF SF OF],
+        shld_32_2: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shld eax, ebx, 2
+        ) [CF ZF SF],
+        shld_32_16: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shld eax, ebx, 16
+        ) [CF ZF SF],
+        shld_32_31: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shld eax, ebx, 31
+        ) [CF ZF SF],
+        shld_32_32: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shld eax, ebx, 32
+        ) [CF ZF SF OF],
+        shld_32_33: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shld eax, ebx, 33
+        ) [CF ZF SF OF],
+        shld_16_cl_0: (
+            ; mov eax, 0x1234c001
+            ; mov ebx, 0x5678
+            ; xor ecx, ecx
+            ; stc
+            ; shld ax, bx, cl
+        ) [CF ZF SF OF],
+        shld_16_cl_24: (
+            ; mov eax, 0x1234c001
+            ; mov ebx, 0x5678
+            ; mov cl, 24
+            ; shld ax, bx, cl
+        ) [CF ZF SF],
+        shld_32_cl_31: (
+            ; mov eax, -0x7fff_ffff
+            ; mov ebx, -1
+            ; mov cl, 31
+            ; shld eax, ebx, cl
+        ) [CF ZF SF],
+        shld_32_cl_65: (
+            ; mov eax, 0x4000_0001
+            ; mov ebx, -0x7fff_fffe
+            ; mov cl, 65
+            ; shld eax, ebx, cl
+        ) [CF ZF SF OF],
+        shld_32_zero_result: (
+            ; mov eax, 0x4000_0000
+            ; xor ebx, ebx
+            ; shld eax, ebx, 2
+        ) [CF ZF SF],
+        shld_mem_32_4: (
+            ; mov DWORD [MEM_ADDR as i32], 0x1234_5678
+            ; mov ebx, -0x01234568
+            ; shld DWORD [MEM_ADDR as i32], ebx, 4
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF],
+        shld_mem_16_cl_3: (
+            ; mov WORD [MEM_ADDR as i32], 0x1234
+            ; mov ebx, 0xabcd
+            ; mov cl, 3
+            ; shld WORD [MEM_ADDR as i32], bx, cl
+            ; mov ax, [MEM_ADDR as i32]
+        ) [CF ZF SF],
+    }
+}
+
+mod shrd {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        shrd_16_0: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 0
+        ) [CF ZF SF OF],
+        shrd_16_1: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 1
+        ) [CF ZF SF OF],
+        shrd_16_2: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 2
+        ) [CF ZF SF],
+        shrd_16_15: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 15
+        ) [CF ZF SF],
+        shrd_16_16: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 16
+        ) [CF ZF SF],
+        shrd_16_17: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 17
+        ) [CF ZF SF],
+        shrd_16_20: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 20
+        ) [CF ZF SF],
+        shrd_16_31: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 31
+        ) [CF ZF SF],
+        shrd_16_32: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 32
+        ) [CF ZF SF OF],
+        shrd_16_33: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; mov bx, 0x5a5a
+            ; stc
+            ; shrd ax, bx, 33
+        ) [CF ZF SF OF],
+        shrd_32_0: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shrd eax, ebx, 0
+        ) [CF ZF SF OF],
+        shrd_32_1: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shrd eax, ebx, 1
+        ) [CF ZF SF OF],
+        shrd_32_2: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shrd eax, ebx, 2
+        ) [CF ZF SF],
+        shrd_32_16: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shrd eax, ebx, 16
+        ) [CF ZF SF],
+        shrd_32_31: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shrd eax, ebx, 31
+        ) [CF ZF SF],
+        shrd_32_32: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shrd eax, ebx, 32
+        ) [CF ZF SF OF],
+        shrd_32_33: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; mov ebx, 0x12345678
+            ; stc
+            ; shrd eax, ebx, 33
+        ) [CF ZF SF OF],
+        shrd_16_cl_0: (
+            ; mov eax, 0x1234c001
+            ; mov ebx, 0x5678
+            ; xor ecx, ecx
+            ; stc
+            ; shrd ax, bx, cl
+        ) [CF ZF SF OF],
+        shrd_16_cl_24: (
+            ; mov eax, 0x1234c001
+            ; mov ebx, 0x5678
+            ; mov cl, 24
+            ; shrd ax, bx, cl
+        ) [CF ZF SF],
+        shrd_32_cl_31: (
+            ; mov eax, -0x7fff_ffff
+            ; mov ebx, -1
+            ; mov cl, 31
+            ; shrd eax, ebx, cl
+        ) [CF ZF SF],
+        shrd_32_cl_65: (
+            ; mov eax, 0x4000_0001
+            ; mov ebx, -0x7fff_fffe
+            ; mov cl, 65
+            ; shrd eax, ebx, cl
+        ) [CF ZF SF OF],
+        shrd_32_zero_result: (
+            ; mov eax, 1
+            ; xor ebx, ebx
+            ; shrd eax, ebx, 1
+        ) [CF ZF SF OF],
+        shrd_mem_32_4: (
+            ; mov DWORD [MEM_ADDR as i32], 0x1234_5678
+            ; mov ebx, -0x01234568
+            ; shrd DWORD [MEM_ADDR as i32], ebx, 4
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF],
+        shrd_mem_16_cl_3: (
+            ; mov WORD [MEM_ADDR as i32], 0x1234
+            ; mov ebx, 0xabcd
+            ; mov cl, 3
+            ; shrd WORD [MEM_ADDR as i32], bx, cl
+            ; mov ax, [MEM_ADDR as i32]
+        ) [CF ZF SF],
+    }
+}
+
+mod bt {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        bt_32_set: (
+            ; mov eax, 0x1234_5678
+            ; mov ecx, 3
+            ; bt eax, ecx
+        ) [CF],
+        bt_32_clear: (
+            ; mov eax, 0x1234_5678
+            ; mov ecx, 2
+            ; stc
+            ; bt eax, ecx
+        ) [CF],
+        bt_32_wraps: (
+            ; mov eax, 0x1234_5678
+            ; mov ecx, -29
+            ; bt eax, ecx
+        ) [CF],
+        bt_16_wraps: (
+            ; mov eax, 0x1234_5678
+            ; mov ecx, 0x23
+            ; bt ax, cx
+        ) [CF],
+        bt_imm_msb: (
+            ; mov eax, -0x7fff_ffff
+            ; bt eax, 31
+        ) [CF],
+        bt_imm_wraps: (
+            ; mov eax, 0x1234_5678
+            ; bt eax, 36
+        ) [CF],
+        bts_32: (
+            ; mov eax, 0x1234_5678
+            ; mov ecx, 0
+            ; bts eax, ecx
+        ) [CF],
+        bts_16_imm: (
+            ; mov eax, 0x1234_5678
+            ; bts ax, 15
+        ) [CF],
+        btr_32: (
+            ; mov eax, 0x1234_5678
+            ; mov ecx, 4
+            ; btr eax, ecx
+        ) [CF],
+        btr_16_imm: (
+            ; mov eax, 0x1234_5678
+            ; btr ax, 0x1c
+        ) [CF],
+        btc_32: (
+            ; mov eax, 0x1234_5678
+            ; mov ecx, 31
+            ; btc eax, ecx
+        ) [CF],
+        btc_16_imm: (
+            ; mov eax, 0x1234_5678
+            ; btc ax, 3
+        ) [CF],
+        round_trip: (
+            ; mov eax, 0x1234_5678
+            ; mov ecx, 7
+            ; bts eax, ecx
+            ; btr eax, ecx
+            ; btc eax, ecx
+            ; btc eax, ecx
+            ; setc bl
+            ; bt eax, ecx
+        ) [CF],
+        mem_imm: (
+            ; mov DWORD [MEM_ADDR as i32], 0x1234_5678
+            ; btc DWORD [MEM_ADDR as i32], 35
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF],
+        mem_16_imm: (
+            ; mov WORD [MEM_ADDR as i32], 0x1234
+            ; bts WORD [MEM_ADDR as i32], 0x1f
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF],
+        bit_string_forward: (
+            ; mov DWORD [MEM_ADDR as i32 + 0x108], 0x1234_5678
+            ; mov ecx, 0x43
+            ; bts DWORD [MEM_ADDR as i32 + 0x100], ecx
+            ; mov eax, [MEM_ADDR as i32 + 0x108]
+        ) [CF],
+        bit_string_backward: (
+            ; mov DWORD [MEM_ADDR as i32 + 0xfc], -1
+            ; mov ebx, MEM_ADDR as i32 + 0x100
+            ; mov ecx, -1
+            ; btr DWORD [ebx], ecx
+            ; mov eax, [MEM_ADDR as i32 + 0xfc]
+        ) [CF],
+        bit_string_16: (
+            ; mov WORD [MEM_ADDR as i32 + 0xfc], 0x1234
+            ; mov ecx, 0xffef
+            ; btc WORD [MEM_ADDR as i32 + 0x100], cx
+            ; mov eax, [MEM_ADDR as i32 + 0xfc]
+        ) [CF],
+        bit_string_bt: (
+            ; mov DWORD [MEM_ADDR as i32 + 0x200], 0x10
+            ; mov ecx, 0x1004
+            ; bt DWORD [MEM_ADDR as i32], ecx
+        ) [CF],
+    }
+}
+
+mod bsf {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        bsf_32_zero: (
+            ; mov eax, 0x1234_5678
+            ; xor ebx, ebx
+            ; bsf eax, ebx
+        ) [ZF],
+        bsf_32_lsb: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 1
+            ; bsf eax, ebx
+        ) [ZF],
+        bsf_32_msb: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, -0x8000_0000
+            ; bsf eax, ebx
+        ) [ZF],
+        bsf_32_value: (
+            ; mov ebx, 0x0012_3400
+            ; bsf eax, ebx
+        ) [ZF],
+        bsf_32_all_ones: (
+            ; mov ebx, -1
+            ; bsf eax, ebx
+        ) [ZF],
+        bsf_16_zero: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 0x0001_0000
+            ; bsf ax, bx
+        ) [ZF],
+        bsf_16_lsb: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 1
+            ; bsf ax, bx
+        ) [ZF],
+        bsf_16_msb: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 0x8000
+            ; bsf ax, bx
+        ) [ZF],
+        bsf_16_value: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 0x0ff0
+            ; bsf ax, bx
+        ) [ZF],
+        bsf_mem: (
+            ; mov DWORD [MEM_ADDR as i32], 0x0104_0000
+            ; bsf eax, [MEM_ADDR as i32]
+        ) [ZF],
+        bsf_mem_zero: (
+            ; mov eax, 7
+            ; bsf eax, [MEM_ADDR as i32]
+        ) [ZF],
+    }
+}
+
+mod bsr {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        bsr_32_zero: (
+            ; mov eax, 0x1234_5678
+            ; xor ebx, ebx
+            ; bsr eax, ebx
+        ) [ZF],
+        bsr_32_lsb: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 1
+            ; bsr eax, ebx
+        ) [ZF],
+        bsr_32_msb: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, -0x8000_0000
+            ; bsr eax, ebx
+        ) [ZF],
+        bsr_32_value: (
+            ; mov ebx, 0x0012_3400
+            ; bsr eax, ebx
+        ) [ZF],
+        bsr_32_all_ones: (
+            ; mov ebx, -1
+            ; bsr eax, ebx
+        ) [ZF],
+        bsr_16_zero: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 0x0001_0000
+            ; bsr ax, bx
+        ) [ZF],
+        bsr_16_lsb: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 1
+            ; bsr ax, bx
+        ) [ZF],
+        bsr_16_msb: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 0x8000
+            ; bsr ax, bx
+        ) [ZF],
+        bsr_16_value: (
+            ; mov eax, 0x1234_5678
+            ; mov ebx, 0x0ff0
+            ; bsr ax, bx
+        ) [ZF],
+        bsr_mem: (
+            ; mov DWORD [MEM_ADDR as i32], 0x0104_0000
+            ; bsr eax, [MEM_ADDR as i32]
+        ) [ZF],
+        bsr_mem_zero: (
+            ; mov eax, 7
+            ; bsr eax, [MEM_ADDR as i32]
+        ) [ZF],
+    }
+}
+
+mod bswap {
+    test_snippets! {
+        // the flags are those of the cmp
+        bswap_eax: (
+            ; mov eax, 0x1234_5678
+            ; cmp eax, 0x7fff_ffff
+            ; bswap eax
+        ) [CF ZF SF OF],
+        bswap_esi: (
+            ; mov esi, -0x0100_0000
+            ; cmp esi, 0
+            ; bswap esi
+        ) [CF ZF SF OF],
+        bswap_edi_zero: (
+            ; xor edi, edi
+            ; bswap edi
+        ) [CF ZF SF OF],
+        bswap_twice: (
+            ; mov ebx, -0x2152_4111
+            ; mov ecx, ebx
+            ; bswap ebx
+            ; cmp ecx, ebx
+            ; bswap ebx
+        ) [CF ZF SF OF],
+        bswap_all: (
+            ; mov eax, 0x0102_0304
+            ; mov ebx, 0x0506_0708
+            ; mov ecx, 0x090a_0b0c
+            ; mov edx, 0x0d0e_0f10
+            ; mov esi, 0x1112_1314
+            ; mov edi, 0x1516_1718
+            ; bswap eax
+            ; bswap ebx
+            ; bswap ecx
+            ; bswap edx
+            ; bswap esi
+            ; bswap edi
+        ) [],
+    }
+}
+
+mod xchg {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        xchg_reg32: (
+            ; mov eax, 0x1122_3344
+            ; mov edx, -0x0100_0000
+            ; cmp eax, edx
+            ; xchg edx, eax
+            ; xchg ecx, esi
+        ) [CF ZF SF OF],
+        xchg_reg16: (
+            ; mov ebx, 0x1122_3344
+            ; mov edi, 0x5566_7788
+            ; xchg bx, di
+            ; xchg ax, cx
+        ) [],
+        xchg_reg8: (
+            ; mov eax, 0x1122_3344
+            ; mov ecx, 0x5566_7788
+            ; xchg ah, cl
+            ; xchg al, ch
+            ; xchg dl, dh
+        ) [],
+        // the one-byte forms with eax
+        xchg_eax: (
+            ; mov eax, 1
+            ; mov ebp, 2
+            ; xchg eax, ebp
+            ; mov ebx, 3
+            ; xchg ebx, eax
+            ; xchg ax, di
+        ) [],
+        xchg_same: (
+            ; mov eax, -1
+            ; mov ebx, 5
+            ; add eax, 1
+            ; xchg eax, eax
+            ; xchg ebx, ebx
+            ; xchg bl, bl
+        ) [CF ZF SF OF],
+        xchg_mem32: (
+            ; mov DWORD [MEM_ADDR as i32], 0x0a0b_0c0d
+            ; mov ecx, 0x1122_3344
+            ; xchg [MEM_ADDR as i32], ecx
+            ; mov edx, [MEM_ADDR as i32]
+        ) [],
+        xchg_mem16: (
+            ; mov DWORD [MEM_ADDR as i32], 0x0a0b_0c0d
+            ; mov esi, 0x1122_3344
+            ; mov ebx, MEM_ADDR as i32
+            ; xchg si, [ebx + 1]
+            ; mov edi, [ebx]
+        ) [],
+        xchg_mem8: (
+            ; mov DWORD [MEM_ADDR as i32], 0x0a0b_0c0d
+            ; mov eax, 0x1122_3344
+            ; xchg ah, [MEM_ADDR as i32 + 3]
+            ; mov edx, [MEM_ADDR as i32]
+        ) [],
+        xchg_mem_lock: (
+            ; mov DWORD [MEM_ADDR as i32], 7
+            ; mov eax, 9
+            ; mov ecx, MEM_ADDR as i32
+            // lock xchg [ecx], eax: the assembler won't put a LOCK on xchg
+            ; .bytes [0xf0_u8, 0x87, 0x01].iter().copied()
+            ; mov ebx, [MEM_ADDR as i32]
+        ) [],
+    }
+}
+
+mod checkpoint {
+    test_snippets! {
+        flags_in_between: (
+            ; mov eax, -1
+            ; add eax, 1
+            ; checkpoint
+            ; sub eax, 1
+            ; checkpoint
+            ; add eax, 0x7fff_ffff
+        ) [CF ZF SF OF],
+        in_a_loop: (
+            ; mov ecx, 3
+            ; xor eax, eax
+            ; ->top:
+            ; add eax, ecx
+            ; checkpoint
+            ; dec ecx
+            ; jnz ->top
+        ) [ZF SF],
+        both_branches: (
+            ; mov eax, 5
+            ; cmp eax, 3
+            ; jl ->less
+            ; checkpoint
+            ; mov ebx, 1
+            ; jmp ->done
+            ; ->less:
+            ; checkpoint
+            ; mov ebx, 2
+            ; ->done:
+            ; checkpoint
+        ) [CF ZF SF OF],
+    }
+}
+
+mod rol {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        rol_8_0x81_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rol al, 1
+        ) [CF ZF SF OF],
+        rol_8_0x81_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rol al, 1
+        ) [CF ZF SF OF],
+        rol_8_0x81_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rol al, 2
+        ) [CF ZF SF],
+        rol_8_0x81_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rol al, 2
+        ) [CF ZF SF],
+        rol_8_0x81_7_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rol al, 7
+        ) [CF ZF SF],
+        rol_8_0x81_7_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rol al, 7
+        ) [CF ZF SF],
+        rol_8_0x81_8_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rol al, 8
+        ) [CF ZF SF],
+        rol_8_0x81_8_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rol al, 8
+        ) [CF ZF SF],
+        rol_8_0x81_9_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rol al, 9
+        ) [CF ZF SF],
+        rol_8_0x81_9_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rol al, 9
+        ) [CF ZF SF],
+        rol_8_0x81_13_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rol al, 13
+        ) [CF ZF SF],
+        rol_8_0x81_13_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rol al, 13
+        ) [CF ZF SF],
+        rol_8_0x81_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rol al, 32
+        ) [CF ZF SF OF],
+        rol_8_0x81_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rol al, 32
+        ) [CF ZF SF OF],
+        rol_8_0x81_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rol al, 33
+        ) [CF ZF SF OF],
+        rol_8_0x81_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rol al, 33
+        ) [CF ZF SF OF],
+        rol_8_0x40_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rol al, 1
+        ) [CF ZF SF OF],
+        rol_8_0x40_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rol al, 1
+        ) [CF ZF SF OF],
+        rol_8_0x40_8_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rol al, 8
+        ) [CF ZF SF],
+        rol_8_0x40_8_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rol al, 8
+        ) [CF ZF SF],
+        rol_8_0x40_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rol al, 32
+        ) [CF ZF SF OF],
+        rol_8_0x40_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rol al, 32
+        ) [CF ZF SF OF],
+        rol_16_0x8001_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rol ax, 1
+        ) [CF ZF SF OF],
+        rol_16_0x8001_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rol ax, 1
+        ) [CF ZF SF OF],
+        rol_16_0x8001_3_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rol ax, 3
+        ) [CF ZF SF],
+        rol_16_0x8001_3_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rol ax, 3
+        ) [CF ZF SF],
+        rol_16_0x8001_15_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rol ax, 15
+        ) [CF ZF SF],
+        rol_16_0x8001_15_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rol ax, 15
+        ) [CF ZF SF],
+        rol_16_0x8001_16_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rol ax, 16
+        ) [CF ZF SF],
+        rol_16_0x8001_16_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rol ax, 16
+        ) [CF ZF SF],
+        rol_16_0x8001_17_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rol ax, 17
+        ) [CF ZF SF],
+        rol_16_0x8001_17_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rol ax, 17
+        ) [CF ZF SF],
+        rol_16_0x8001_20_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rol ax, 20
+        ) [CF ZF SF],
+        rol_16_0x8001_20_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rol ax, 20
+        ) [CF ZF SF],
+        rol_16_0x8001_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rol ax, 33
+        ) [CF ZF SF OF],
+        rol_16_0x8001_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rol ax, 33
+        ) [CF ZF SF OF],
+        rol_32_0x80000001_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rol eax, 1
+        ) [CF ZF SF OF],
+        rol_32_0x80000001_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rol eax, 1
+        ) [CF ZF SF OF],
+        rol_32_0x80000001_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rol eax, 2
+        ) [CF ZF SF],
+        rol_32_0x80000001_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rol eax, 2
+        ) [CF ZF SF],
+        rol_32_0x80000001_31_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rol eax, 31
+        ) [CF ZF SF],
+        rol_32_0x80000001_31_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rol eax, 31
+        ) [CF ZF SF],
+        rol_32_0x80000001_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rol eax, 32
+        ) [CF ZF SF OF],
+        rol_32_0x80000001_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rol eax, 32
+        ) [CF ZF SF OF],
+        rol_32_0x80000001_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rol eax, 33
+        ) [CF ZF SF OF],
+        rol_32_0x80000001_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rol eax, 33
+        ) [CF ZF SF OF],
+        rol_8_cl_0: (
+            ; mov eax, 0x12345681
+            ; xor ecx, ecx
+            ; stc
+            ; rol al, cl
+        ) [CF ZF SF OF],
+        rol_8_cl_16: (
+            ; mov eax, 0x12345681
+            ; mov cl, 16
+            ; clc
+            ; rol al, cl
+        ) [CF ZF SF],
+        rol_16_cl_19: (
+            ; mov eax, 0x12348001
+            ; mov cl, 19
+            ; stc
+            ; rol ax, cl
+        ) [CF ZF SF],
+        rol_32_cl_64: (
+            ; mov eax, 0x12348001
+            ; mov cl, 64
+            ; stc
+            ; rol eax, cl
+        ) [CF ZF SF OF],
+        rol_mem_8_12: (
+            ; mov BYTE [MEM_ADDR as i32], -0x7f
+            ; clc
+            ; rol BYTE [MEM_ADDR as i32], 12
+            ; mov al, [MEM_ADDR as i32]
+        ) [CF ZF SF],
+        rol_mem_32_1: (
+            ; mov DWORD [MEM_ADDR as i32], 0x40000001
+            ; clc
+            ; rol DWORD [MEM_ADDR as i32], 1
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+    }
+}
+
+mod ror {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        ror_8_0x81_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; ror al, 1
+        ) [CF ZF SF OF],
+        ror_8_0x81_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; ror al, 1
+        ) [CF ZF SF OF],
+        ror_8_0x81_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; ror al, 2
+        ) [CF ZF SF],
+        ror_8_0x81_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; ror al, 2
+        ) [CF ZF SF],
+        ror_8_0x81_7_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; ror al, 7
+        ) [CF ZF SF],
+        ror_8_0x81_7_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; ror al, 7
+        ) [CF ZF SF],
+        ror_8_0x81_8_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; ror al, 8
+        ) [CF ZF SF],
+        ror_8_0x81_8_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; ror al, 8
+        ) [CF ZF SF],
+        ror_8_0x81_9_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; ror al, 9
+        ) [CF ZF SF],
+        ror_8_0x81_9_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; ror al, 9
+        ) [CF ZF SF],
+        ror_8_0x81_13_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; ror al, 13
+        ) [CF ZF SF],
+        ror_8_0x81_13_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; ror al, 13
+        ) [CF ZF SF],
+        ror_8_0x81_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; ror al, 32
+        ) [CF ZF SF OF],
+        ror_8_0x81_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; ror al, 32
+        ) [CF ZF SF OF],
+        ror_8_0x81_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; ror al, 33
+        ) [CF ZF SF OF],
+        ror_8_0x81_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; ror al, 33
+        ) [CF ZF SF OF],
+        ror_8_0x40_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; ror al, 1
+        ) [CF ZF SF OF],
+        ror_8_0x40_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; ror al, 1
+        ) [CF ZF SF OF],
+        ror_8_0x40_8_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; ror al, 8
+        ) [CF ZF SF],
+        ror_8_0x40_8_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; ror al, 8
+        ) [CF ZF SF],
+        ror_8_0x40_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; ror al, 32
+        ) [CF ZF SF OF],
+        ror_8_0x40_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; ror al, 32
+        ) [CF ZF SF OF],
+        ror_16_0x8001_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; ror ax, 1
+        ) [CF ZF SF OF],
+        ror_16_0x8001_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; ror ax, 1
+        ) [CF ZF SF OF],
+        ror_16_0x8001_3_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; ror ax, 3
+        ) [CF ZF SF],
+        ror_16_0x8001_3_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; ror ax, 3
+        ) [CF ZF SF],
+        ror_16_0x8001_15_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; ror ax, 15
+        ) [CF ZF SF],
+        ror_16_0x8001_15_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; ror ax, 15
+        ) [CF ZF SF],
+        ror_16_0x8001_16_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; ror ax, 16
+        ) [CF ZF SF],
+        ror_16_0x8001_16_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; ror ax, 16
+        ) [CF ZF SF],
+        ror_16_0x8001_17_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; ror ax, 17
+        ) [CF ZF SF],
+        ror_16_0x8001_17_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; ror ax, 17
+        ) [CF ZF SF],
+        ror_16_0x8001_20_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; ror ax, 20
+        ) [CF ZF SF],
+        ror_16_0x8001_20_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; ror ax, 20
+        ) [CF ZF SF],
+        ror_16_0x8001_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; ror ax, 33
+        ) [CF ZF SF OF],
+        ror_16_0x8001_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; ror ax, 33
+        ) [CF ZF SF OF],
+        ror_32_0x80000001_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; ror eax, 1
+        ) [CF ZF SF OF],
+        ror_32_0x80000001_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; ror eax, 1
+        ) [CF ZF SF OF],
+        ror_32_0x80000001_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; ror eax, 2
+        ) [CF ZF SF],
+        ror_32_0x80000001_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; ror eax, 2
+        ) [CF ZF SF],
+        ror_32_0x80000001_31_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; ror eax, 31
+        ) [CF ZF SF],
+        ror_32_0x80000001_31_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; ror eax, 31
+        ) [CF ZF SF],
+        ror_32_0x80000001_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; ror eax, 32
+        ) [CF ZF SF OF],
+        ror_32_0x80000001_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; ror eax, 32
+        ) [CF ZF SF OF],
+        ror_32_0x80000001_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; ror eax, 33
+        ) [CF ZF SF OF],
+        ror_32_0x80000001_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; ror eax, 33
+        ) [CF ZF SF OF],
+        ror_8_cl_0: (
+            ; mov eax, 0x12345681
+            ; xor ecx, ecx
+            ; stc
+            ; ror al, cl
+        ) [CF ZF SF OF],
+        ror_8_cl_16: (
+            ; mov eax, 0x12345681
+            ; mov cl, 16
+            ; clc
+            ; ror al, cl
+        ) [CF ZF SF],
+        ror_16_cl_19: (
+            ; mov eax, 0x12348001
+            ; mov cl, 19
+            ; stc
+            ; ror ax, cl
+        ) [CF ZF SF],
+        ror_32_cl_64: (
+            ; mov eax, 0x12348001
+            ; mov cl, 64
+            ; stc
+            ; ror eax, cl
+        ) [CF ZF SF OF],
+        ror_mem_8_12: (
+            ; mov BYTE [MEM_ADDR as i32], -0x7f
+            ; clc
+            ; ror BYTE [MEM_ADDR as i32], 12
+            ; mov al, [MEM_ADDR as i32]
+        ) [CF ZF SF],
+        ror_mem_32_1: (
+            ; mov DWORD [MEM_ADDR as i32], 0x40000001
+            ; clc
+            ; ror DWORD [MEM_ADDR as i32], 1
+            ; mov eax, [MEM_ADDR as i32]
+        ) [CF ZF SF OF],
+    }
+}
+
+mod rcl {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        rcl_8_0x81_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcl al, 1
+        ) [CF ZF SF OF],
+        rcl_8_0x81_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcl al, 1
+        ) [CF ZF SF OF],
+        rcl_8_0x81_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcl al, 2
+        ) [CF ZF SF],
+        rcl_8_0x81_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcl al, 2
+        ) [CF ZF SF],
+        rcl_8_0x81_8_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcl al, 8
+        ) [CF ZF SF],
+        rcl_8_0x81_8_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcl al, 8
+        ) [CF ZF SF],
+        rcl_8_0x81_9_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcl al, 9
+        ) [CF ZF SF],
+        rcl_8_0x81_9_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcl al, 9
+        ) [CF ZF SF],
+        rcl_8_0x81_10_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcl al, 10
+        ) [CF ZF SF],
+        rcl_8_0x81_10_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcl al, 10
+        ) [CF ZF SF],
+        rcl_8_0x81_13_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcl al, 13
+        ) [CF ZF SF],
+        rcl_8_0x81_13_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcl al, 13
+        ) [CF ZF SF],
+        rcl_8_0x81_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcl al, 32
+        ) [CF ZF SF OF],
+        rcl_8_0x81_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcl al, 32
+        ) [CF ZF SF OF],
+        rcl_8_0x81_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcl al, 33
+        ) [CF ZF SF OF],
+        rcl_8_0x81_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcl al, 33
+        ) [CF ZF SF OF],
+        rcl_8_0x40_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcl al, 1
+        ) [CF ZF SF OF],
+        rcl_8_0x40_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcl al, 1
+        ) [CF ZF SF OF],
+        rcl_8_0x40_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcl al, 2
+        ) [CF ZF SF],
+        rcl_8_0x40_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcl al, 2
+        ) [CF ZF SF],
+        rcl_8_0x40_8_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcl al, 8
+        ) [CF ZF SF],
+        rcl_8_0x40_8_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcl al, 8
+        ) [CF ZF SF],
+        rcl_8_0x40_9_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcl al, 9
+        ) [CF ZF SF],
+        rcl_8_0x40_9_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcl al, 9
+        ) [CF ZF SF],
+        rcl_8_0x40_10_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcl al, 10
+        ) [CF ZF SF],
+        rcl_8_0x40_10_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcl al, 10
+        ) [CF ZF SF],
+        rcl_8_0x40_13_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcl al, 13
+        ) [CF ZF SF],
+        rcl_8_0x40_13_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcl al, 13
+        ) [CF ZF SF],
+        rcl_8_0x40_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcl al, 32
+        ) [CF ZF SF OF],
+        rcl_8_0x40_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcl al, 32
+        ) [CF ZF SF OF],
+        rcl_8_0x40_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcl al, 33
+        ) [CF ZF SF OF],
+        rcl_8_0x40_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcl al, 33
+        ) [CF ZF SF OF],
+        rcl_16_0x8001_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcl ax, 1
+        ) [CF ZF SF OF],
+        rcl_16_0x8001_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcl ax, 1
+        ) [CF ZF SF OF],
+        rcl_16_0x8001_3_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcl ax, 3
+        ) [CF ZF SF],
+        rcl_16_0x8001_3_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcl ax, 3
+        ) [CF ZF SF],
+        rcl_16_0x8001_16_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcl ax, 16
+        ) [CF ZF SF],
+        rcl_16_0x8001_16_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcl ax, 16
+        ) [CF ZF SF],
+        rcl_16_0x8001_17_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcl ax, 17
+        ) [CF ZF SF],
+        rcl_16_0x8001_17_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcl ax, 17
+        ) [CF ZF SF],
+        rcl_16_0x8001_20_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcl ax, 20
+        ) [CF ZF SF],
+        rcl_16_0x8001_20_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcl ax, 20
+        ) [CF ZF SF],
+        rcl_16_0x8001_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcl ax, 33
+        ) [CF ZF SF OF],
+        rcl_16_0x8001_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcl ax, 33
+        ) [CF ZF SF OF],
+        rcl_32_0x80000001_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rcl eax, 1
+        ) [CF ZF SF OF],
+        rcl_32_0x80000001_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rcl eax, 1
+        ) [CF ZF SF OF],
+        rcl_32_0x80000001_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rcl eax, 2
+        ) [CF ZF SF],
+        rcl_32_0x80000001_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rcl eax, 2
+        ) [CF ZF SF],
+        rcl_32_0x80000001_31_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rcl eax, 31
+        ) [CF ZF SF],
+        rcl_32_0x80000001_31_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rcl eax, 31
+        ) [CF ZF SF],
+        rcl_32_0x80000001_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rcl eax, 33
+        ) [CF ZF SF OF],
+        rcl_32_0x80000001_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rcl eax, 33
+        ) [CF ZF SF OF],
+        rcl_8_cl_9: (
+            ; mov eax, 0x12345681
+            ; mov cl, 9
+            ; stc
+            ; rcl al, cl
+        ) [CF ZF SF],
+        rcl_16_cl_19: (
+            ; mov eax, 0x12348001
+            ; mov cl, 19
+            ; stc
+            ; rcl ax, cl
+        ) [CF ZF SF],
+        rcl_mem_8_12: (
+            ; mov BYTE [MEM_ADDR as i32], -0x7f
+            ; clc
+            ; rcl BYTE [MEM_ADDR as i32], 12
+            ; mov al, [MEM_ADDR as i32]
+        ) [CF ZF SF],
+    }
+}
+
+mod rcr {
+    use crate::common::MEM_ADDR;
+
+    test_snippets! {
+        rcr_8_0x81_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcr al, 1
+        ) [CF ZF SF OF],
+        rcr_8_0x81_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcr al, 1
+        ) [CF ZF SF OF],
+        rcr_8_0x81_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcr al, 2
+        ) [CF ZF SF],
+        rcr_8_0x81_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcr al, 2
+        ) [CF ZF SF],
+        rcr_8_0x81_8_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcr al, 8
+        ) [CF ZF SF],
+        rcr_8_0x81_8_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcr al, 8
+        ) [CF ZF SF],
+        rcr_8_0x81_9_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcr al, 9
+        ) [CF ZF SF],
+        rcr_8_0x81_9_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcr al, 9
+        ) [CF ZF SF],
+        rcr_8_0x81_10_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcr al, 10
+        ) [CF ZF SF],
+        rcr_8_0x81_10_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcr al, 10
+        ) [CF ZF SF],
+        rcr_8_0x81_13_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcr al, 13
+        ) [CF ZF SF],
+        rcr_8_0x81_13_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcr al, 13
+        ) [CF ZF SF],
+        rcr_8_0x81_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcr al, 32
+        ) [CF ZF SF OF],
+        rcr_8_0x81_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcr al, 32
+        ) [CF ZF SF OF],
+        rcr_8_0x81_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; clc
+            ; rcr al, 33
+        ) [CF ZF SF OF],
+        rcr_8_0x81_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, -0x7f
+            ; stc
+            ; rcr al, 33
+        ) [CF ZF SF OF],
+        rcr_8_0x40_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcr al, 1
+        ) [CF ZF SF OF],
+        rcr_8_0x40_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcr al, 1
+        ) [CF ZF SF OF],
+        rcr_8_0x40_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcr al, 2
+        ) [CF ZF SF],
+        rcr_8_0x40_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcr al, 2
+        ) [CF ZF SF],
+        rcr_8_0x40_8_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcr al, 8
+        ) [CF ZF SF],
+        rcr_8_0x40_8_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcr al, 8
+        ) [CF ZF SF],
+        rcr_8_0x40_9_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcr al, 9
+        ) [CF ZF SF],
+        rcr_8_0x40_9_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcr al, 9
+        ) [CF ZF SF],
+        rcr_8_0x40_10_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcr al, 10
+        ) [CF ZF SF],
+        rcr_8_0x40_10_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcr al, 10
+        ) [CF ZF SF],
+        rcr_8_0x40_13_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcr al, 13
+        ) [CF ZF SF],
+        rcr_8_0x40_13_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcr al, 13
+        ) [CF ZF SF],
+        rcr_8_0x40_32_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcr al, 32
+        ) [CF ZF SF OF],
+        rcr_8_0x40_32_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcr al, 32
+        ) [CF ZF SF OF],
+        rcr_8_0x40_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; clc
+            ; rcr al, 33
+        ) [CF ZF SF OF],
+        rcr_8_0x40_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov al, 0x40
+            ; stc
+            ; rcr al, 33
+        ) [CF ZF SF OF],
+        rcr_16_0x8001_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcr ax, 1
+        ) [CF ZF SF OF],
+        rcr_16_0x8001_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcr ax, 1
+        ) [CF ZF SF OF],
+        rcr_16_0x8001_3_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcr ax, 3
+        ) [CF ZF SF],
+        rcr_16_0x8001_3_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcr ax, 3
+        ) [CF ZF SF],
+        rcr_16_0x8001_16_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcr ax, 16
+        ) [CF ZF SF],
+        rcr_16_0x8001_16_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcr ax, 16
+        ) [CF ZF SF],
+        rcr_16_0x8001_17_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcr ax, 17
+        ) [CF ZF SF],
+        rcr_16_0x8001_17_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcr ax, 17
+        ) [CF ZF SF],
+        rcr_16_0x8001_20_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcr ax, 20
+        ) [CF ZF SF],
+        rcr_16_0x8001_20_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcr ax, 20
+        ) [CF ZF SF],
+        rcr_16_0x8001_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; clc
+            ; rcr ax, 33
+        ) [CF ZF SF OF],
+        rcr_16_0x8001_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov ax, -0x7fff
+            ; stc
+            ; rcr ax, 33
         ) [CF ZF SF OF],
-        shl_neg_64_one_wrap: (
-            ; mov eax, -64
-            ; shl eax, 33
+        rcr_32_0x80000001_1_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rcr eax, 1
         ) [CF ZF SF OF],
-
-        shl_228_two_wrap: (
-            ; mov eax, 228
-            ; shl eax, 34
-        ) [CF ZF SF],
-        shl_229_two_wrap: (
-            ; mov eax, 229
-            ; shl eax, 34
+        rcr_32_0x80000001_1_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rcr eax, 1
+        ) [CF ZF SF OF],
+        rcr_32_0x80000001_2_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rcr eax, 2
         ) [CF ZF SF],
-        shl_neg_228_two_wrap: (
-            ; mov eax, -228
-            ; shl eax, 34
+        rcr_32_0x80000001_2_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rcr eax, 2
         ) [CF ZF SF],
-        shl_neg_229_two_wrap: (
-            ; mov eax, -229
-            ; shl eax, 34
+        rcr_32_0x80000001_31_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rcr eax, 31
         ) [CF ZF SF],
-        shl_neg_64_two_wrap: (
-            ; mov eax, -64
-            ; shl eax, 34
+        rcr_32_0x80000001_31_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rcr eax, 31
         ) [CF ZF SF],
-
-        shl_edge_case_byte: (
-            ; mov al, -0x08
-            ; shl al, 0x09
-        ) [CF ZF SF OF],
-        shl_edge_case_word: (
-            ; mov ax, -0x0888
-            ; shl ax, 0x11
+        rcr_32_0x80000001_33_clc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; clc
+            ; rcr eax, 33
         ) [CF ZF SF OF],
-        shl_edge_case_dword: (
-            ; mov eax, -0x08888888
-            ; shl eax, 0x21
+        rcr_32_0x80000001_33_stc: (
+            ; mov eax, 0x12345678
+            ; mov eax, -0x7fffffff
+            ; stc
+            ; rcr eax, 33
         ) [CF ZF SF OF],
+        rcr_8_cl_9: (
+            ; mov eax, 0x12345681
+            ; mov cl, 9
+            ; stc
+            ; rcr al, cl
+        ) [CF ZF SF],
+        rcr_16_cl_19: (
+            ; mov eax, 0x12348001
+            ; mov cl, 19
+            ; stc
+            ; rcr ax, cl
+        ) [CF ZF SF],
+        rcr_mem_8_12: (
+            ; mov BYTE [MEM_ADDR as i32], -0x7f
+            ; clc
+            ; rcr BYTE [MEM_ADDR as i32], 12
+            ; mov al, [MEM_ADDR as i32]
+        ) [CF ZF SF],
     }
 }
 
@@ -1209,15 +4895,35 @@ mod div {
             ; mov ebx, 2
             ; div ebx
         ) [],
-        // this should cause a division error
-        // TODO: how can we test this? (it's not how it behaves rn btw)
-        // ditto for division by zero
-        // div_big2: (
-        //     ; mov eax, 0
-        //     ; mov edx, 1
-        //     ; mov ebx, 1
-        //     ; div ebx
-        // ),
+        // the quotient doesn't fit
+        div_big2: (
+            ; mov eax, 0
+            ; mov edx, 1
+            ; mov ebx, 1
+            ; div ebx
+        ) [] => DivideError,
+        div_by_zero: (
+            ; mov eax, 1
+            ; mov edx, 0
+            ; mov ebx, 0
+            ; div ebx
+        ) [] => DivideError,
+        div_by_zero8: (
+            ; mov eax, 0x100
+            ; mov bl, 0
+            ; div bl
+        ) [] => DivideError,
+        div_big8: (
+            ; mov eax, 0x100
+            ; mov bl, 1
+            ; div bl
+        ) [] => DivideError,
+        div_big16: (
+            ; mov eax, 0
+            ; mov edx, 1
+            ; mov bx, 1
+            ; div bx
+        ) [] => DivideError,
         div_big_rnd1: (
             ; mov eax, -0x1895c25a
             ; mov edx, 0x6c8300d6
@@ -1237,9 +4943,43 @@ mod div {
             ; div ebx
         ) [],
     );
+    test_snippets!(
+        div_8_basic: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, 42
+            ; mov bl, 5
+            ; div bl
+        ) [],
+        div_8_boundary: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, 0x0fff
+            ; mov bl, 0x10
+            ; div bl
+        ) [],
+        div_16_basic: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov dx, 0
+            ; mov ax, 42
+            ; mov bx, 5
+            ; div bx
+        ) [],
+        div_16_boundary: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov dx, 0xf
+            ; mov ax, -1
+            ; mov bx, 0x10
+            ; div bx
+        ) [],
+    );
 }
 
 mod idiv {
+    use crate::common::MEM_ADDR;
+
     test_snippets!(
         idiv_basic1: (
             ; mov eax, 42
@@ -1304,15 +5044,31 @@ mod idiv {
             ; mov ebx, 3
             ; idiv ebx
         ) [],
-        // this should cause a idivision error
-        // TODO: how can we test this? (it's not how it behaves rn btw)
-        // ditto for idivision by zero
-        // idiv_big2: (
-        //     ; mov eax, 0
-        //     ; mov edx, 1
-        //     ; mov ebx, 1
-        //     ; idiv ebx
-        // ),
+        // the quotient doesn't fit
+        idiv_big2: (
+            ; mov eax, 0
+            ; mov edx, 1
+            ; mov ebx, 1
+            ; idiv ebx
+        ) [] => DivideError,
+        idiv_by_zero: (
+            ; mov eax, -1
+            ; mov edx, -1
+            ; mov ebx, 0
+            ; idiv ebx
+        ) [] => DivideError,
+        // -0x80000000 / -1 is 0x80000000, one too many for a positive i32
+        idiv_min_by_minus_one: (
+            ; mov eax, -0x80000000
+            ; cdq
+            ; mov ebx, -1
+            ; idiv ebx
+        ) [] => DivideError,
+        idiv_min_by_minus_one8: (
+            ; mov eax, -0x80
+            ; mov bl, -1
+            ; idiv bl
+        ) [] => DivideError,
         idiv_big_rnd1: (
             ; mov eax, -0x1895c25a
             ; mov edx, -0x0c8300d6
@@ -1332,6 +5088,145 @@ mod idiv {
             ; idiv ebx
         ) [],
     );
+    test_snippets!(
+        idiv_8_basic: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, 42
+            ; mov bl, 5
+            ; idiv bl
+        ) [],
+        idiv_8_neg_dividend: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, -7
+            ; mov bl, 2
+            ; idiv bl
+        ) [],
+        idiv_8_neg_divisor: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, 7
+            ; mov bl, -2
+            ; idiv bl
+        ) [],
+        idiv_8_boundary: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov ax, -0x100
+            ; mov bl, 2
+            ; idiv bl
+        ) [],
+        idiv_16_basic: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov dx, 0
+            ; mov ax, 42
+            ; mov bx, 5
+            ; idiv bx
+        ) [],
+        idiv_16_neg_dividend: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov dx, -1
+            ; mov ax, -7
+            ; mov bx, 2
+            ; idiv bx
+        ) [],
+        idiv_16_neg_divisor: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov dx, 0
+            ; mov ax, 7
+            ; mov bx, -2
+            ; idiv bx
+        ) [],
+        idiv_16_boundary: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov dx, -1
+            ; mov ax, 0
+            ; mov bx, 2
+            ; idiv bx
+        ) [],
+    );
+    // every combination of the signs, with a remainder (which takes the sign of the dividend)
+    test_snippets!(
+        idiv_pos_pos: (
+            ; mov eax, 1000003
+            ; cdq
+            ; mov ebx, 17
+            ; idiv ebx
+        ) [],
+        idiv_neg_pos: (
+            ; mov eax, -1000003
+            ; cdq
+            ; mov ebx, 17
+            ; idiv ebx
+        ) [],
+        idiv_pos_neg: (
+            ; mov eax, 1000003
+            ; cdq
+            ; mov ebx, -17
+            ; idiv ebx
+        ) [],
+        idiv_neg_neg: (
+            ; mov eax, -1000003
+            ; cdq
+            ; mov ebx, -17
+            ; idiv ebx
+        ) [],
+        idiv_min_by_one: (
+            ; mov eax, -0x80000000
+            ; cdq
+            ; mov ebx, 1
+            ; idiv ebx
+        ) [],
+        idiv_min_by_two: (
+            ; mov eax, -0x80000000
+            ; cdq
+            ; mov ebx, -2
+            ; idiv ebx
+        ) [],
+        idiv_by_min: (
+            ; mov eax, 0x7fffffff
+            ; cdq
+            ; mov ebx, -0x80000000
+            ; idiv ebx
+        ) [],
+        idiv_big_neg_rnd: (
+            ; mov eax, 0x5e42b8f1
+            ; mov edx, -0x2b7c4d1f
+            ; mov ebx, -0x6a0d9b12
+            ; idiv ebx
+        ) [],
+        idiv_big_pos_rnd: (
+            ; mov eax, -0x0f936a55
+            ; mov edx, 0x1d07c5a1
+            ; mov ebx, 0x3c5a1d07
+            ; idiv ebx
+        ) [],
+        idiv_32_mem: (
+            ; mov DWORD [MEM_ADDR as i32], -0x1234
+            ; mov eax, 0x7654321
+            ; cdq
+            ; idiv DWORD [MEM_ADDR as i32]
+        ) [],
+        idiv_16_mem: (
+            ; mov eax, 0x12345678
+            ; mov edx, 0x1abcdef0
+            ; mov WORD [MEM_ADDR as i32], 0x123
+            ; mov ax, -0x4321
+            ; cwd
+            ; idiv WORD [MEM_ADDR as i32]
+        ) [],
+        idiv_8_mem: (
+            ; mov eax, 0x12345678
+            ; mov BYTE [MEM_ADDR as i32], -9
+            ; mov ax, 0x3ff
+            ; idiv BYTE [MEM_ADDR as i32]
+        ) [],
+    );
 }
 
 mod stack {
@@ -1364,6 +5259,193 @@ mod stack {
             ; ret
         ) [CF ZF SF OF],
     );
+    test_snippets!(
+        pushad_popad: (
+            ; mov eax, 0x1111_1111
+            ; mov ecx, 0x2222_2222
+            ; mov edx, 0x3333_3333
+            ; mov ebx, 0x4444_4444
+            ; mov ebp, 0x6666_6666
+            ; mov esi, 0x7777_7777
+            ; mov edi, -0x7777_7778
+            ; cmp eax, ecx
+            ; pushad
+            ; xor eax, eax
+            ; mov ecx, eax
+            ; mov edx, eax
+            ; mov ebx, eax
+            ; mov ebp, eax
+            ; mov esi, eax
+            ; mov edi, eax
+            ; popad
+        ) [CF ZF SF OF],
+        // the ESP in the image is the one before the pushes, and popad doesn't load it
+        pushad_esp: (
+            ; mov eax, esp
+            ; pushad
+            ; mov ebx, [esp + 12]
+            ; sub ebx, eax
+            ; mov DWORD [esp + 12], 0
+            ; popad
+            ; sub eax, esp
+        ) [CF ZF SF OF],
+        pushad_changed: (
+            ; mov eax, 1
+            ; mov esi, 2
+            ; pushad
+            ; mov DWORD [esp], 0x1234
+            ; add DWORD [esp + 28], 0x10
+            ; popad
+        ) [CF ZF SF OF],
+        // pushaw & popaw, not known to the assembler
+        pushaw_popaw: (
+            ; mov eax, 0x1111_1111
+            ; mov ebx, 0x4444_4444
+            ; mov edi, -0x7777_7778
+            ; .bytes [0x66_u8, 0x60].iter().copied()
+            ; mov eax, -1
+            ; mov ebx, -1
+            ; mov edi, -1
+            ; mov WORD [esp + 8], 0x5555
+            ; .bytes [0x66_u8, 0x61].iter().copied()
+        ) [CF ZF SF OF],
+    );
+}
+
+// pushfd & popfd are `pushf` & `popf` to the assembler. Only CF, ZF, SF & OF are compared in the pushed images, the
+// rest (PF, AF, IF...) differs from the host's
+mod pushf {
+    test_snippets! {
+        pushfd_after_cmp: (
+            ; mov eax, 1
+            ; cmp eax, 2
+            ; pushf
+            ; pop ebx
+            ; and ebx, 0x8c1
+        ) [CF ZF SF OF],
+        pushfd_overflow: (
+            ; mov eax, 0x7fff_ffff
+            ; add eax, 1
+            ; pushf
+            ; pop ecx
+            ; and ecx, 0x8c1
+        ) [CF ZF SF OF],
+        pushf_16: (
+            ; mov eax, -1
+            ; add eax, 1
+            ; mov edx, -1
+            ; pushfw
+            ; pop dx
+            ; and dx, 0x8c1
+        ) [CF ZF SF OF],
+        popfd_round_trip: (
+            ; mov eax, 1
+            ; cmp eax, 2
+            ; pushf
+            ; xor ecx, ecx
+            ; popf
+        ) [CF ZF SF OF],
+        popfd_round_trip_zero: (
+            ; xor ecx, ecx
+            ; pushf
+            ; mov eax, 0x7fff_ffff
+            ; add eax, 1
+            ; popf
+        ) [CF ZF SF OF],
+        popf_16_round_trip: (
+            ; mov eax, 0x7fff_ffff
+            ; add eax, 1
+            ; pushfw
+            ; xor ecx, ecx
+            ; popfw
+        ) [CF ZF SF OF],
+        // the image changed on the stack
+        popfd_modified: (
+            ; mov eax, 1
+            ; cmp eax, 2
+            ; pushf
+            ; xor DWORD [esp], 0x8c1
+            ; popf
+        ) [CF ZF SF OF],
+        popf_16_modified: (
+            ; xor ecx, ecx
+            ; pushfw
+            ; or WORD [esp], 0x801
+            ; and WORD [esp], -0x41
+            ; popfw
+        ) [CF ZF SF OF],
+        popfd_constructed: (
+            ; push 0x882
+            ; popf
+        ) [CF ZF SF OF],
+    }
+}
+
+// instructions that use ESP while also moving it
+mod stack_esp_operands {
+    use crate::common::CODE_ADDR;
+
+    test_snippets!(
+        push_esp: (
+            ; push esp
+            ; pop eax
+            ; push esp
+        ) [CF ZF SF OF],
+        push_sp: (
+            ; push sp
+            ; pop bx
+        ) [CF ZF SF OF],
+        push_mem_esp: (
+            ; push DWORD [esp]
+            ; mov DWORD [esp - 4], 0x1337
+            ; push DWORD [esp - 4]
+        ) [CF ZF SF OF],
+        pop_esp: (
+            ; lea eax, [esp - 0x10]
+            ; push eax
+            ; pop esp
+            ; push DWORD 0x42
+        ) [CF ZF SF OF],
+        pop_mem_esp: (
+            ; push DWORD 0x1234
+            ; push DWORD 0x5678
+            ; pop DWORD [esp]
+        ) [CF ZF SF OF],
+        pop_mem_esp_disp: (
+            ; push DWORD 0x1234
+            ; push DWORD 0x5678
+            ; push DWORD 0x9abc
+            ; pop DWORD [esp + 4]
+        ) [CF ZF SF OF],
+        mov_esp_mem_esp: (
+            ; lea eax, [esp - 0x20]
+            ; push eax
+            ; mov esp, [esp]
+            ; push DWORD 0x42
+        ) [CF ZF SF OF],
+        // the target is read before the return address goes on the stack
+        call_mem_esp: (
+            ; push DWORD CODE_ADDR as i32 + 10
+            ; call DWORD [esp]
+            ; jmp BYTE ->end
+            // CODE_ADDR + 10
+            ; mov ebx, [esp]
+            ; ret
+            ; ->end:
+        ) [CF ZF SF OF],
+        call_mem_esp_disp: (
+            ; push DWORD CODE_ADDR as i32 + 16
+            ; push DWORD 0x1111
+            ; call DWORD [esp + 4]
+            ; jmp BYTE ->end
+            // CODE_ADDR + 16
+            ; mov ebx, [esp]
+            ; ret
+            ; ->end:
+            ; push DWORD [esp]
+            ; pop DWORD [esp + 4]
+        ) [CF ZF SF OF],
+    );
 }
 
 mod string {
@@ -1903,4 +5985,168 @@ mod string {
             ) [CF ZF SF OF],
         }
     }
+
+    mod lods {
+        use crate::common::MEM_ADDR;
+
+        test_snippets! {
+            lodsb: (
+                ; mov DWORD [MEM_ADDR as i32], 0x11121314
+                ; mov esi, MEM_ADDR as i32
+                ; mov eax, -1
+                ; lodsb
+            ) [CF ZF SF OF],
+            lodsw: (
+                ; mov DWORD [MEM_ADDR as i32], 0x11121314
+                ; mov esi, MEM_ADDR as i32
+                ; mov eax, -1
+                ; lodsw
+            ) [CF ZF SF OF],
+            lodsd_rep_0: (
+                ; mov DWORD [MEM_ADDR as i32], 0x11121314
+                ; mov esi, MEM_ADDR as i32
+                ; mov eax, -1
+                ; mov ecx, 0
+                ; rep lodsd
+            ) [CF ZF SF OF],
+            lodsd_rep_2: (
+                ; mov DWORD [MEM_ADDR as i32], 0x11121314
+                ; mov DWORD [MEM_ADDR as i32 + 4], 0x15161718
+                ; mov esi, MEM_ADDR as i32
+                ; mov ecx, 2
+                ; rep lodsd
+            ) [CF ZF SF OF],
+        }
+    }
+
+    mod cmps {
+        use crate::common::MEM_ADDR;
+
+        test_snippets! {
+            cmpsb_eq: (
+                ; mov BYTE [MEM_ADDR as i32], 0x11
+                ; mov BYTE [MEM_ADDR as i32 + 0x10], 0x11
+                ; mov esi, MEM_ADDR as i32
+                ; mov edi, MEM_ADDR as i32 + 0x10
+                ; cmpsb
+            ) [CF ZF SF OF],
+            cmpsb_less: (
+                ; mov BYTE [MEM_ADDR as i32], 0x10
+                ; mov BYTE [MEM_ADDR as i32 + 0x10], 0x11
+                ; mov esi, MEM_ADDR as i32
+                ; mov edi, MEM_ADDR as i32 + 0x10
+                ; cmpsb
+            ) [CF ZF SF OF],
+            cmpsd_greater_signed: (
+                ; mov DWORD [MEM_ADDR as i32], 2
+                ; mov DWORD [MEM_ADDR as i32 + 0x10], -1
+                ; mov esi, MEM_ADDR as i32
+                ; mov edi, MEM_ADDR as i32 + 0x10
+                ; cmpsd
+            ) [CF ZF SF OF],
+
+            cmpsb_repe_mismatch: (
+                ; mov DWORD [MEM_ADDR as i32], 0x04030201
+                ; mov DWORD [MEM_ADDR as i32 + 0x10], 0x04090201
+                ; mov esi, MEM_ADDR as i32
+                ; mov edi, MEM_ADDR as i32 + 0x10
+                ; mov ecx, 4
+                ; repe cmpsb
+            ) [CF ZF SF OF],
+            cmpsb_repe_all_equal: (
+                ; mov DWORD [MEM_ADDR as i32], 0x04030201
+                ; mov DWORD [MEM_ADDR as i32 + 0x10], 0x04030201
+                ; mov esi, MEM_ADDR as i32
+                ; mov edi, MEM_ADDR as i32 + 0x10
+                ; mov ecx, 4
+                ; repe cmpsb
+            ) [CF ZF SF OF],
+            cmpsw_repne_match: (
+                ; mov DWORD [MEM_ADDR as i32], 0x00020001
+                ; mov DWORD [MEM_ADDR as i32 + 0x10], 0x00020005
+                ; mov esi, MEM_ADDR as i32
+                ; mov edi, MEM_ADDR as i32 + 0x10
+                ; mov ecx, 2
+                ; repne cmpsw
+            ) [CF ZF SF OF],
+        }
+    }
+
+    // the order of the checks in the rep loop (see codegen_rep)
+    mod rep_termination {
+        use crate::common::MEM_ADDR;
+
+        test_snippets! {
+            // ECX = 0 on entry: nothing happens, the flags stay as they were
+            scasb_repe_ecx_0_flags_set: (
+                ; mov BYTE [MEM_ADDR as i32], 0x12
+                ; mov edi, MEM_ADDR as i32
+                ; mov al, 0x11
+                ; xor ecx, ecx
+                ; stc
+                ; repe scasb
+            ) [CF ZF SF OF],
+            scasb_repne_ecx_0_flags_set: (
+                ; mov BYTE [MEM_ADDR as i32], 0x11
+                ; mov edi, MEM_ADDR as i32
+                ; mov al, 0x11
+                ; mov ecx, -1
+                ; add ecx, 1
+                ; repne scasb
+            ) [CF ZF SF OF],
+            cmpsb_repe_ecx_0_flags_zf: (
+                ; mov BYTE [MEM_ADDR as i32], 0x11
+                ; mov BYTE [MEM_ADDR as i32 + 0x10], 0x12
+                ; mov esi, MEM_ADDR as i32
+                ; mov edi, MEM_ADDR as i32 + 0x10
+                ; mov ecx, 1
+                ; sub ecx, 1
+                ; repe cmpsb
+            ) [CF ZF SF OF],
+
+            // ECX hits zero on the very element that also fails the condition
+            scasb_repe_last_mismatch: (
+                ; mov DWORD [MEM_ADDR as i32], 0x12111111
+                ; mov edi, MEM_ADDR as i32
+                ; mov al, 0x11
+                ; mov ecx, 4
+                ; repe scasb
+            ) [CF ZF SF OF],
+            scasb_repne_last_match: (
+                ; mov DWORD [MEM_ADDR as i32], 0x11121212
+                ; mov edi, MEM_ADDR as i32
+                ; mov al, 0x11
+                ; mov ecx, 4
+                ; repne scasb
+            ) [CF ZF SF OF],
+            cmpsd_repe_last_mismatch: (
+                ; mov DWORD [MEM_ADDR as i32], 1
+                ; mov DWORD [MEM_ADDR as i32 + 4], 2
+                ; mov DWORD [MEM_ADDR as i32 + 0x10], 1
+                ; mov DWORD [MEM_ADDR as i32 + 0x14], 3
+                ; mov esi, MEM_ADDR as i32
+                ; mov edi, MEM_ADDR as i32 + 0x10
+                ; mov ecx, 2
+                ; repe cmpsd
+            ) [CF ZF SF OF],
+
+            // terminating early: EDI/ESI point one past the terminating element, ECX counts the rest
+            scasw_repne_early: (
+                ; mov DWORD [MEM_ADDR as i32], 0x00070005
+                ; mov DWORD [MEM_ADDR as i32 + 4], 0x00090008
+                ; mov edi, MEM_ADDR as i32
+                ; mov ax, 7
+                ; mov ecx, 4
+                ; repne scasw
+            ) [CF ZF SF OF],
+            cmpsb_repe_early: (
+                ; mov DWORD [MEM_ADDR as i32], 0x44332211
+                ; mov DWORD [MEM_ADDR as i32 + 0x10], 0x44330011
+                ; mov esi, MEM_ADDR as i32
+                ; mov edi, MEM_ADDR as i32 + 0x10
+                ; mov ecx, 4
+                ; repe cmpsb
+            ) [CF ZF SF OF],
+        }
+    }
 }
@@ -0,0 +1,139 @@
+#![cfg(all(feature = "llvm", feature = "interp"))]
+
+//! The cached interpreter (`Interpreter::run_cached`) against the recompiled code on loops, where the blocks are
+//! looked up over & over
+
+use rusty_x86::assemble_x86;
+use rusty_x86::config::Recompiler;
+use rusty_x86::handler::NullHandler;
+use rusty_x86::interp::{Interpreter, StepResult};
+use rusty_x86::memory_image::Protection;
+use rusty_x86::runtime::ExitReason;
+use rusty_x86::types::{CpuContext, Flag, FullSizeGeneralPurposeRegister};
+use strum::IntoEnumIterator;
+
+const CODE_ADDR: u32 = 0x1000;
+const DATA_ADDR: u32 = 0x2000;
+const DATA_SIZE: u32 = 0x1000;
+const STACK_ADDR: u32 = 0x8000;
+const STACK_SIZE: u32 = 0x1000;
+
+const FLAGS: [Flag; 4] = [Flag::Carry, Flag::Zero, Flag::Sign, Flag::Overflow];
+
+fn prepare_context(ctx: &mut CpuContext) {
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::ESP,
+        STACK_ADDR + STACK_SIZE - 4,
+    );
+}
+
+fn run_llvm(code: &[u8]) -> (CpuContext, Vec<u8>) {
+    let mut runtime = Recompiler::builder().build_runtime(NullHandler).unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, code)
+        .unwrap();
+    runtime
+        .map(DATA_ADDR, Protection::READ_WRITE, &[0; DATA_SIZE as usize])
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+
+    let mut data = vec![0; DATA_SIZE as usize];
+    runtime.memory.read_bytes(DATA_ADDR, &mut data).unwrap();
+    (runtime.context, data)
+}
+
+fn run_interp(code: &[u8]) -> (CpuContext, Vec<u8>) {
+    let mut memory = vec![0; (STACK_ADDR + STACK_SIZE) as usize];
+    memory[CODE_ADDR as usize..][..code.len()].copy_from_slice(code);
+    let mut interp = Interpreter::new(memory, NullHandler);
+    interp.context.eip = CODE_ADDR;
+    prepare_context(&mut interp.context);
+
+    assert_eq!(interp.run_cached(1_000_000), StepResult::Returned);
+    assert!(interp.block_cache.stats().fast_instructions > 0);
+
+    let data = interp.memory[DATA_ADDR as usize..][..DATA_SIZE as usize].to_vec();
+    (interp.context, data)
+}
+
+fn check(code: &[u8]) {
+    let (llvm, llvm_data) = run_llvm(code);
+    let (interp, interp_data) = run_interp(code);
+
+    for reg in FullSizeGeneralPurposeRegister::iter() {
+        assert_eq!(interp.get_gp_reg(reg), llvm.get_gp_reg(reg), "{:?}", reg);
+    }
+    for flag in FLAGS {
+        assert_eq!(interp.get_flag(flag), llvm.get_flag(flag), "{:?}", flag);
+    }
+    assert!(interp_data == llvm_data, "the data differs");
+}
+
+#[test_log::test]
+fn counting_loop() {
+    check(&assemble_x86!(
+        ; mov ecx, 100
+        ; xor eax, eax
+        ; mov esi, DATA_ADDR as i32
+        ; ->again:
+        ; mov edx, ecx
+        ; lea ebx, [eax + edx * 4 + 3]
+        ; mov [esi + ecx * 4], ebx
+        ; call ->mix
+        ; cmp ecx, 50
+        ; jbe ->skip
+        ; add eax, [esi + ecx * 4]
+        ; ->skip:
+        ; lea eax, [eax + 1]
+        ; dec ecx
+        ; jnz ->again
+        ; mov edi, eax
+        ; ret
+        ; ->mix:
+        ; xor eax, ebx
+        ; mov ebp, 7
+        ; ret
+    ));
+}
+
+#[test_log::test]
+fn signed_conditions() {
+    // a bubble sort of signed numbers: jl/jg read SF & OF left by cmp
+    check(&assemble_x86!(
+        ; mov esi, DATA_ADDR as i32
+        ; mov ecx, 32
+        ; mov eax, 0x1234567
+        ; ->fill:
+        ; imul eax, eax, 1103515245
+        ; add eax, 12345
+        ; mov [esi + ecx * 4 - 4], eax
+        ; dec ecx
+        ; jnz ->fill
+        ; mov edx, 31
+        ; ->outer:
+        ; xor ecx, ecx
+        ; ->inner:
+        ; mov eax, [esi + ecx * 4]
+        ; mov ebx, [esi + ecx * 4 + 4]
+        ; cmp eax, ebx
+        ; jle ->ordered
+        ; mov [esi + ecx * 4], ebx
+        ; mov [esi + ecx * 4 + 4], eax
+        ; ->ordered:
+        ; lea ecx, [ecx + 1]
+        ; cmp ecx, edx
+        ; jl ->inner
+        ; dec edx
+        ; jg ->outer
+        ; ret
+    ));
+}
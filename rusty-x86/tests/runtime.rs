@@ -0,0 +1,2280 @@
+#![cfg(feature = "llvm")]
+
+use std::fs;
+use std::hint::black_box;
+use std::path::Path;
+
+use rusty_x86::config::{OptLevel, Recompiler};
+use rusty_x86::disasm::SymbolTable;
+use rusty_x86::dos::{DosHandler, COM_INITIAL_SP, COM_LOAD_OFFSET, COM_MAX_SIZE};
+use rusty_x86::handler::{InstructionBytes, InterruptVectorTable};
+use rusty_x86::ir::{CodeMode, DecodeError, UnsupportedInstructionPolicy};
+use rusty_x86::llvm::TranslateFailure;
+use rusty_x86::memory_image::Protection;
+#[cfg(target_os = "linux")]
+use rusty_x86::runtime::ExternalBuffer;
+use rusty_x86::runtime::{
+    CallError, ExitReason, FromBytes, GuestFault, GuestMemory, MemoryAccessError, NullHandler,
+    Runtime, RuntimeHandler,
+};
+use rusty_x86::segmentation::{SegmentDescriptor, SegmentationPolicy};
+use rusty_x86::system_registers::{ControlRegisterWrites, SystemRegisterProfile, UnknownMsrReads};
+use rusty_x86::types::{
+    CpuContext, Flag, FullSizeGeneralPurposeRegister, IntType, SegmentRegister, EXIT_FAULT,
+    EXIT_HOST_REQUEST, PF_INSTRUCTION_FETCH,
+};
+use rusty_x86::win32::{TebOptions, END_OF_SEH_CHAIN, EXIT_PROCESS_STUB, PEB_IMAGE_BASE, TEB_PEB};
+
+const CODE_ADDR: u32 = 0x1000;
+const STACK_ADDR: u32 = 0x8000;
+const STACK_SIZE: u32 = 0x1000;
+
+#[rustfmt::skip]
+const CODE: &[u8] = &[
+    0xb8, 0x2a, 0x00, 0x00, 0x00, // mov eax, 42
+    0x43,                         // inc ebx
+    0x01, 0xd8,                   // add eax, ebx
+    0xc3,                         // ret
+];
+
+#[derive(Default)]
+struct TraceHandler {
+    trace: Vec<u32>,
+}
+
+impl RuntimeHandler for TraceHandler {
+    fn instruction(&mut self, ctx: &mut CpuContext) {
+        self.trace.push(ctx.eip);
+    }
+}
+
+fn prepare_context(ctx: &mut CpuContext) {
+    ctx.set_gp_reg(FullSizeGeneralPurposeRegister::EBX, 0);
+    ctx.set_gp_reg(
+        FullSizeGeneralPurposeRegister::ESP,
+        STACK_ADDR + STACK_SIZE - 4,
+    );
+}
+
+#[test_log::test]
+fn per_instruction_hook() {
+    let mut runtime = Recompiler::builder()
+        .opt_level(OptLevel::None)
+        .per_instruction(true)
+        .instruction_hook(true)
+        .build_runtime(TraceHandler::default())
+        .unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+        43
+    );
+    // every single instruction was seen
+    assert_eq!(runtime.handler.trace, vec![0x1000, 0x1005, 0x1006, 0x1008]);
+}
+
+#[test_log::test]
+fn default_config_has_no_hooks() {
+    let mut runtime = Recompiler::builder()
+        .build_runtime(TraceHandler::default())
+        .unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+        43
+    );
+    assert!(runtime.handler.trace.is_empty());
+}
+
+#[test_log::test]
+fn small_memory() {
+    let mut runtime = Recompiler::builder()
+        .memory_size(0x10000)
+        .bounds_checking(true)
+        .build_runtime(NullHandler)
+        .unwrap();
+
+    // doesn't fit
+    assert!(runtime
+        .map(0x10000, Protection::READ_WRITE, &[0; 0x1000])
+        .is_err());
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+
+    // the bounds checks don't get in the way of the accesses that are in bounds
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+        43
+    );
+}
+
+#[rustfmt::skip]
+const TWO_FUNCTIONS: &[u8] = &[
+    0xb8, 0x2a, 0x00, 0x00, 0x00, // mov eax, 42
+    0xc3,                         // ret
+    0xb8, 0x07, 0x00, 0x00, 0x00, // mov eax, 7
+    0x01, 0xd8,                   // add eax, ebx
+    0xc3,                         // ret
+];
+
+#[test_log::test]
+fn entry_at_any_block() {
+    let mut runtime = Recompiler::builder().build_runtime(NullHandler).unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, TWO_FUNCTIONS)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+
+    let eax = |runtime: &Runtime<NullHandler>| {
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EAX)
+    };
+
+    prepare_context(&mut runtime.context);
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::EBX, 3);
+    assert_eq!(runtime.run(CODE_ADDR + 6), ExitReason::Returned);
+    assert_eq!(eax(&runtime), 10);
+
+    // a stale exit from the previous run doesn't stop the next one
+    prepare_context(&mut runtime.context);
+    runtime.context.exit = EXIT_HOST_REQUEST;
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    assert_eq!(eax(&runtime), 42);
+}
+
+#[rustfmt::skip]
+const CLOBBER_CODE: &[u8] = &[
+    0xb8, 0xef, 0xbe, 0xad, 0xde, // mov eax, 0xdeadbeef
+    0x89, 0xc3,                   // mov ebx, eax
+    0x89, 0xc1,                   // mov ecx, eax
+    0x89, 0xc2,                   // mov edx, eax
+    0x89, 0xc6,                   // mov esi, eax
+    0x89, 0xc7,                   // mov edi, eax
+    0x89, 0xc5,                   // mov ebp, eax
+    0xc3,                         // ret
+];
+
+#[test_log::test]
+fn host_registers_survive() {
+    let mut runtime = Recompiler::builder()
+        .opt_level(OptLevel::Aggressive)
+        .build_runtime(NullHandler)
+        .unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, CLOBBER_CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+
+    // enough live values for some of them to end up in the callee-saved registers
+    let live = black_box([0x1111u64, 0x2222, 0x3333, 0x4444, 0x5555, 0x6666, 0x7777]);
+    let (a, b, c, d, e, f, g) = (
+        live[0], live[1], live[2], live[3], live[4], live[5], live[6],
+    );
+
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+
+    assert_eq!(
+        black_box((a, b, c, d, e, f, g)),
+        (0x1111, 0x2222, 0x3333, 0x4444, 0x5555, 0x6666, 0x7777)
+    );
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EBP),
+        0xdeadbeef
+    );
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::ESP),
+        STACK_ADDR + STACK_SIZE
+    );
+}
+
+#[rustfmt::skip]
+const UNMAPPED_LOAD_CODE: &[u8] = &[
+    0xa1, 0x00, 0x80, 0x00, 0x00,             // mov eax, [0x8000]
+    0xeb, 0x00,                               // jmp 0x1007
+    0x83, 0xc0, 0x01,                         // add eax, 1
+    0x8b, 0x1d, 0x00, 0x00, 0x02, 0x00,       // mov ebx, [0x20000]
+    0x89, 0x1d, 0x00, 0x80, 0x00, 0x00,       // mov [0x8000], ebx
+    0xc3,                                     // ret
+];
+
+#[test_log::test]
+fn fault_sites() {
+    for opt_level in [OptLevel::None, OptLevel::Aggressive] {
+        let mut runtime = Recompiler::builder()
+            .opt_level(opt_level)
+            .fault_sites(true)
+            .build_runtime(NullHandler)
+            .unwrap();
+
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, UNMAPPED_LOAD_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+        prepare_context(&mut runtime.context);
+
+        // the second load, in the chained block
+        assert_eq!(
+            runtime.run(CODE_ADDR),
+            ExitReason::Fault(GuestFault::PageFault)
+        );
+        assert_eq!(runtime.context.eip, CODE_ADDR + 10);
+        assert_eq!(runtime.context.fault_vector, 14);
+        assert_eq!(runtime.context.fault_address, 0x20000);
+        // everything before it is done
+        assert_eq!(
+            runtime
+                .context
+                .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+            1
+        );
+
+        // the runtime is still usable afterwards
+        runtime.map(0x20000, Protection::READ, &[0x2a]).unwrap();
+        prepare_context(&mut runtime.context);
+        assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+        assert_eq!(runtime.memory.read_u32(0x8000), Ok(0x2a));
+    }
+}
+
+#[rustfmt::skip]
+const RECURSION_CODE: &[u8] = &[
+    0xe8, 0xfb, 0xff, 0xff, 0xff, // call 0x1000
+];
+
+#[derive(Default)]
+struct SoftLimitHandler {
+    hits: Vec<u32>,
+}
+
+impl RuntimeHandler for SoftLimitHandler {
+    fn stack_soft_limit(&mut self, _ctx: &mut CpuContext, esp: u32) {
+        self.hits.push(esp);
+    }
+}
+
+#[test_log::test]
+fn stack_overflow() {
+    let mut runtime = Recompiler::builder()
+        .fault_sites(true)
+        .build_runtime(SoftLimitHandler::default())
+        .unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, RECURSION_CODE)
+        .unwrap();
+    let stack = runtime
+        .setup_stack(STACK_ADDR, STACK_SIZE, Some(STACK_ADDR + 0x800))
+        .unwrap();
+    assert_eq!(stack.top, STACK_ADDR + STACK_SIZE);
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::ESP),
+        stack.top
+    );
+
+    assert_eq!(
+        runtime.run(CODE_ADDR),
+        ExitReason::Fault(GuestFault::StackOverflow {
+            esp: STACK_ADDR - 4,
+            base: STACK_ADDR,
+            top: STACK_ADDR + STACK_SIZE,
+        })
+    );
+    assert_eq!(runtime.context.fault_address, STACK_ADDR - 4);
+    // only the first time it goes past the soft limit
+    assert_eq!(runtime.handler.hits, vec![STACK_ADDR + 0x7fc]);
+
+    // the guard page has to stay unmapped
+    assert!(runtime
+        .setup_stack(STACK_ADDR + STACK_SIZE, STACK_SIZE, None)
+        .is_err());
+    assert!(runtime
+        .setup_stack(STACK_ADDR + 1, STACK_SIZE, None)
+        .is_err());
+}
+
+#[test_log::test]
+fn stack_soft_limit_from_builder() {
+    let mut runtime = Recompiler::builder()
+        .fault_sites(true)
+        .stack_soft_limit(Some(STACK_ADDR + 0x800))
+        .build_runtime(SoftLimitHandler::default())
+        .unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, RECURSION_CODE)
+        .unwrap();
+    let stack = runtime.setup_stack(STACK_ADDR, STACK_SIZE, None).unwrap();
+    assert_eq!(stack.soft_limit, Some(STACK_ADDR + 0x800));
+
+    assert!(matches!(
+        runtime.run(CODE_ADDR),
+        ExitReason::Fault(GuestFault::StackOverflow { .. })
+    ));
+    assert_eq!(runtime.handler.hits, vec![STACK_ADDR + 0x7fc]);
+}
+
+#[test_log::test]
+fn setup_stack_twice() {
+    const SECOND_STACK_ADDR: u32 = 0x20000;
+
+    let mut runtime = Recompiler::builder()
+        .fault_sites(true)
+        .stack_soft_limit(Some(SECOND_STACK_ADDR + 0x800))
+        .build_runtime(SoftLimitHandler::default())
+        .unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, RECURSION_CODE)
+        .unwrap();
+    let stack = runtime
+        .setup_stack(STACK_ADDR, STACK_SIZE, Some(STACK_ADDR + 0x400))
+        .unwrap();
+    assert_eq!(stack.soft_limit, Some(STACK_ADDR + 0x400));
+
+    // the limit of the first stack is not kept around, it's the builder's one again
+    let stack = runtime
+        .setup_stack(SECOND_STACK_ADDR, STACK_SIZE, None)
+        .unwrap();
+    assert_eq!(stack.soft_limit, Some(SECOND_STACK_ADDR + 0x800));
+
+    assert!(matches!(
+        runtime.run(CODE_ADDR),
+        ExitReason::Fault(GuestFault::StackOverflow {
+            base: SECOND_STACK_ADDR,
+            ..
+        })
+    ));
+    assert_eq!(runtime.handler.hits, vec![SECOND_STACK_ADDR + 0x7fc]);
+}
+
+#[test_log::test]
+fn stack_overflow_without_stack() {
+    let mut runtime = Recompiler::builder()
+        .fault_sites(true)
+        .build_runtime(NullHandler)
+        .unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, RECURSION_CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+
+    assert_eq!(
+        runtime.run(CODE_ADDR),
+        ExitReason::Fault(GuestFault::PageFault)
+    );
+    assert_eq!(runtime.context.fault_address, STACK_ADDR - 4);
+}
+
+#[rustfmt::skip]
+const TEB_CODE: &[u8] = &[
+    0x64, 0xa1, 0x18, 0x00, 0x00, 0x00,       // mov eax, fs:[0x18]
+    0x64, 0x8b, 0x1d, 0x30, 0x00, 0x00, 0x00, // mov ebx, fs:[0x30]
+    0x64, 0x8b, 0x0d, 0x04, 0x00, 0x00, 0x00, // mov ecx, fs:[4]
+    0x64, 0x8b, 0x15, 0x08, 0x00, 0x00, 0x00, // mov edx, fs:[8]
+    0x64, 0x8b, 0x35, 0x00, 0x00, 0x00, 0x00, // mov esi, fs:[0]
+    0xc3,                                     // ret
+];
+
+#[rustfmt::skip]
+const PEB_OVERRUN_CODE: &[u8] = &[
+    0x64, 0xa1, 0x30, 0x00, 0x00, 0x00, // mov eax, fs:[0x30]
+    0x8b, 0x80, 0x00, 0x10, 0x00, 0x00, // mov eax, [eax + 0x1000]
+    0xc3,                               // ret
+];
+
+#[test_log::test]
+fn windows_teb() {
+    let mut runtime = Recompiler::builder()
+        .fault_sites(true)
+        .build_runtime(NullHandler)
+        .unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, TEB_CODE)
+        .unwrap();
+    runtime
+        .map(
+            CODE_ADDR + 0x1000,
+            Protection::READ_EXECUTE,
+            PEB_OVERRUN_CODE,
+        )
+        .unwrap();
+    runtime.setup_stack(STACK_ADDR, STACK_SIZE, None).unwrap();
+
+    let options = TebOptions {
+        teb: 0x20000,
+        peb: 0x30000,
+        ..TebOptions::default()
+    };
+    let teb = runtime.setup_teb(&options).unwrap();
+    assert_eq!(runtime.teb(), Some(teb));
+    // already there
+    assert!(runtime.setup_teb(&options).is_err());
+
+    let reg = |runtime: &Runtime<NullHandler>, reg| runtime.context.get_gp_reg(reg);
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    assert_eq!(reg(&runtime, FullSizeGeneralPurposeRegister::EAX), 0x20000);
+    assert_eq!(reg(&runtime, FullSizeGeneralPurposeRegister::EBX), 0x30000);
+    assert_eq!(
+        reg(&runtime, FullSizeGeneralPurposeRegister::ECX),
+        STACK_ADDR + STACK_SIZE
+    );
+    assert_eq!(
+        reg(&runtime, FullSizeGeneralPurposeRegister::EDX),
+        STACK_ADDR
+    );
+    assert_eq!(
+        reg(&runtime, FullSizeGeneralPurposeRegister::ESI),
+        END_OF_SEH_CHAIN
+    );
+    assert_eq!(
+        runtime.memory.read_u32(teb.peb + PEB_IMAGE_BASE),
+        Ok(options.image_base)
+    );
+
+    // a thread switching stacks
+    teb.set_stack_bounds(&mut runtime.memory, 0x50000, 0x40000)
+        .unwrap();
+    assert_eq!(teb.stack_bounds(&runtime.memory), Ok((0x50000, 0x40000)));
+    runtime
+        .setup_stack(STACK_ADDR + 0x2000, STACK_SIZE, None)
+        .unwrap();
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    assert_eq!(
+        reg(&runtime, FullSizeGeneralPurposeRegister::ECX),
+        STACK_ADDR + 0x2000 + STACK_SIZE
+    );
+
+    let mut symbols = SymbolTable::new();
+    teb.add_symbols(&mut symbols);
+    assert_eq!(
+        symbols.describe(teb.teb + TEB_PEB).as_deref(),
+        Some("TEB+0x30")
+    );
+    assert_eq!(
+        runtime.run(CODE_ADDR + 0x1000),
+        ExitReason::Fault(GuestFault::PageFault)
+    );
+    assert_eq!(
+        symbols.describe(runtime.context.fault_address).as_deref(),
+        Some("PEB+0x1000")
+    );
+}
+
+fn cached_runtime(cache_dir: &Path, code: &[u8]) -> Runtime<NullHandler> {
+    let mut runtime = Recompiler::builder()
+        .fault_sites(true)
+        .translation_cache(cache_dir)
+        .build_runtime(NullHandler)
+        .unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, code)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+    runtime
+}
+
+#[test_log::test]
+fn translation_cache() {
+    let dir = std::env::temp_dir().join(format!("rusty-x86-cache-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut runtime = cached_runtime(&dir, UNMAPPED_LOAD_CODE);
+    assert_eq!(
+        runtime.run(CODE_ADDR),
+        ExitReason::Fault(GuestFault::PageFault)
+    );
+    let stats = runtime.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (0, 1));
+    assert!(stats.bytes_written > 0);
+    let written = stats.bytes_written;
+
+    // a fresh runtime doesn't translate anything, the fault sites come from the cache too
+    let mut runtime = cached_runtime(&dir, UNMAPPED_LOAD_CODE);
+    assert_eq!(
+        runtime.run(CODE_ADDR),
+        ExitReason::Fault(GuestFault::PageFault)
+    );
+    assert_eq!(runtime.context.eip, CODE_ADDR + 10);
+    assert_eq!(runtime.context.fault_address, 0x20000);
+    let stats = runtime.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (1, 0));
+    assert_eq!(stats.bytes_read, written);
+
+    // different code, different key
+    let mut runtime = cached_runtime(&dir, CODE);
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+        43
+    );
+    assert_eq!(runtime.cache_stats().unwrap().misses, 1);
+
+    // a damaged file is a miss, and gets replaced with a good one
+    for entry in fs::read_dir(&dir).unwrap() {
+        fs::write(entry.unwrap().path(), b"RX86CACH and then garbage").unwrap();
+    }
+    for expected_hits in [0, 1] {
+        let mut runtime = cached_runtime(&dir, CODE);
+        assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+        assert_eq!(
+            runtime
+                .context
+                .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+            43
+        );
+        assert_eq!(runtime.cache_stats().unwrap().hits, expected_hits);
+    }
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+fn run_code(runtime: &mut Runtime<NullHandler>) -> u32 {
+    prepare_context(&mut runtime.context);
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    runtime
+        .context
+        .get_gp_reg(FullSizeGeneralPurposeRegister::EAX)
+}
+
+#[test_log::test]
+fn write_code() {
+    let dir = std::env::temp_dir().join(format!("rusty-x86-patch-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut runtime = Recompiler::builder()
+        .translation_cache(&dir)
+        .patch_log(true)
+        .build_runtime(NullHandler)
+        .unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    let hits_misses = |runtime: &Runtime<NullHandler>| {
+        let stats = runtime.cache_stats().unwrap();
+        (stats.hits, stats.misses)
+    };
+
+    assert_eq!(run_code(&mut runtime), 43);
+    assert_eq!(run_code(&mut runtime), 43);
+    assert_eq!(hits_misses(&runtime), (1, 1));
+
+    // the immediate of `mov eax, 42`, in the read-only code: translated anew
+    runtime
+        .write_code(CODE_ADDR + 1, &100u32.to_le_bytes())
+        .unwrap();
+    assert_eq!(runtime.memory.read_u32(CODE_ADDR + 1), Ok(100));
+    assert_eq!(run_code(&mut runtime), 101);
+    assert_eq!(hits_misses(&runtime), (1, 2));
+
+    // back to the original code, and to its translation
+    assert_eq!(runtime.revert_patches(), Ok(1));
+    assert_eq!(runtime.memory.read_u32(CODE_ADDR + 1), Ok(42));
+    assert_eq!(run_code(&mut runtime), 43);
+    assert_eq!(hits_misses(&runtime), (2, 2));
+    assert_eq!(runtime.revert_patches(), Ok(0));
+
+    // the data writes leave the translation alone, even over the code
+    runtime
+        .write_data(CODE_ADDR + 1, &100u32.to_le_bytes())
+        .unwrap();
+    assert_eq!(runtime.memory.read_u32(CODE_ADDR + 1), Ok(100));
+    assert_eq!(run_code(&mut runtime), 43);
+    assert_eq!(hits_misses(&runtime), (3, 2));
+
+    assert_eq!(
+        runtime.write_code(0x20000, &[0x90]),
+        Err(MemoryAccessError { address: 0x20000 })
+    );
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test_log::test]
+fn strict_data_writes() {
+    let mut runtime = Recompiler::builder()
+        .strict_data_writes(true)
+        .build_runtime(NullHandler)
+        .unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+
+    assert_eq!(
+        runtime.write_data(CODE_ADDR + 1, &100u32.to_le_bytes()),
+        Err(MemoryAccessError {
+            address: CODE_ADDR + 1
+        })
+    );
+    runtime.write_data(STACK_ADDR, &[1, 2, 3]).unwrap();
+    assert_eq!(runtime.memory.read_u32(STACK_ADDR), Ok(0x030201));
+
+    // the code can still be patched, but with no log there's nothing to revert
+    runtime
+        .write_code(CODE_ADDR + 1, &100u32.to_le_bytes())
+        .unwrap();
+    assert_eq!(run_code(&mut runtime), 101);
+    assert_eq!(runtime.revert_patches(), Ok(0));
+    assert_eq!(run_code(&mut runtime), 101);
+}
+
+const LEAF_ADDR: u32 = 0x3000;
+
+#[rustfmt::skip]
+const LEAF_CALLER_CODE: &[u8] = &[
+    0xb9, 0x0a, 0x00, 0x00, 0x00, // mov ecx, 10
+    0xe8, 0xf6, 0x1f, 0x00, 0x00, // call LEAF_ADDR
+    0x01, 0xd8,                   // add eax, ebx
+    0xc3,                         // ret
+];
+
+#[rustfmt::skip]
+const LEAF_CODE: &[u8] = &[
+    0x8d, 0x41, 0x05, // lea eax, [ecx + 5]
+    0xc3,             // ret
+];
+
+#[rustfmt::skip]
+const PATCHED_LEAF_CODE: &[u8] = &[
+    0x8d, 0x41, 0x07, // lea eax, [ecx + 7]
+    0xc3,             // ret
+];
+
+fn leaf_runtime(
+    leaf: &[u8],
+    inline_leaves: bool,
+    cache_dir: Option<&Path>,
+) -> Runtime<NullHandler> {
+    let mut builder = Recompiler::builder().inline_leaves(inline_leaves);
+    if let Some(dir) = cache_dir {
+        builder = builder.translation_cache(dir);
+    }
+    let mut runtime = builder.build_runtime(NullHandler).unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, LEAF_CALLER_CODE)
+        .unwrap();
+    runtime
+        .map(LEAF_ADDR, Protection::READ_EXECUTE, leaf)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+    runtime
+}
+
+fn run_leaf_caller(runtime: &mut Runtime<NullHandler>) -> u32 {
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    // back where it was, with the return address popped by the `ret` of the caller
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::ESP),
+        STACK_ADDR + STACK_SIZE
+    );
+    runtime
+        .context
+        .get_gp_reg(FullSizeGeneralPurposeRegister::EAX)
+}
+
+#[test_log::test]
+fn inline_leaves() {
+    for inline_leaves in [false, true] {
+        let mut runtime = leaf_runtime(LEAF_CODE, inline_leaves, None);
+        assert_eq!(run_leaf_caller(&mut runtime), 15);
+    }
+
+    // the caller is the same, but the copy of the leaf in it is not: the cached translation can't be reused
+    let dir = std::env::temp_dir().join(format!("rusty-x86-inline-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+
+    let mut runtime = leaf_runtime(LEAF_CODE, true, Some(&dir));
+    assert_eq!(run_leaf_caller(&mut runtime), 15);
+    assert_eq!(runtime.cache_stats().unwrap().misses, 1);
+
+    let mut runtime = leaf_runtime(PATCHED_LEAF_CODE, true, Some(&dir));
+    assert_eq!(run_leaf_caller(&mut runtime), 17);
+    let stats = runtime.cache_stats().unwrap();
+    assert_eq!((stats.hits, stats.misses), (0, 1));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test_log::test]
+fn long_straight_line() {
+    // xor eax, eax; 5000 x inc eax; ret
+    let mut code = vec![0x31, 0xc0];
+    code.extend(std::iter::repeat(0x40).take(5000));
+    code.push(0xc3);
+
+    // the default limit, a lot of small pieces, and the pieces going through the dispatcher
+    for (max_block_instructions, block_chaining) in
+        [(None, true), (Some(7), true), (Some(100), false)]
+    {
+        let mut builder = Recompiler::builder().block_chaining(block_chaining);
+        if let Some(max) = max_block_instructions {
+            builder = builder.max_block_instructions(max);
+        }
+        let mut runtime = builder.build_runtime(NullHandler).unwrap();
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, &code)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+
+        assert_eq!(run_code(&mut runtime), 5000);
+    }
+}
+
+#[rustfmt::skip]
+const ZERO_IDIOM_CODE: &[u8] = &[
+    0x31, 0xc0,                   // xor eax, eax
+    0x0f, 0x94, 0xc1,             // sete cl
+    0xba, 0x05, 0x00, 0x00, 0x00, // mov edx, 5
+    0x0f, 0x44, 0xc2,             // cmove eax, edx
+    0x0f, 0xb6, 0xc9,             // movzx ecx, cl
+    0x01, 0xc8,                   // add eax, ecx
+    0x29, 0xdb,                   // sub ebx, ebx
+    0x75, 0x03,                   // jne +3
+    0x83, 0xc0, 0x10,             // add eax, 0x10
+    0x29, 0xd2,                   // sub edx, edx
+    0x74, 0x03,                   // je +3
+    0x83, 0xc0, 0x20,             // add eax, 0x20
+    0xc3,                         // ret
+];
+
+#[test_log::test]
+fn zero_idiom_flags() {
+    // without the peephole, with the branches folded, and with the dead flags dropped on top of that
+    for (peephole, flag_liveness) in [(false, false), (true, false), (true, true)] {
+        let mut runtime = Recompiler::builder()
+            .peephole(peephole)
+            .flag_liveness(flag_liveness)
+            .build_runtime(NullHandler)
+            .unwrap();
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, ZERO_IDIOM_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+
+        assert_eq!(run_code(&mut runtime), 0x16);
+    }
+}
+
+#[rustfmt::skip]
+const ZERO_IDIOM_PUSHFD_CODE: &[u8] = &[
+    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+    0x83, 0xf8, 0x02,             // cmp eax, 2
+    0x31, 0xc0,                   // xor eax, eax
+    0x9c,                         // pushfd
+    0x58,                         // pop eax
+    0xc3,                         // ret
+];
+
+#[test_log::test]
+fn zero_idiom_pushfd() {
+    for peephole in [false, true] {
+        let mut runtime = Recompiler::builder()
+            .peephole(peephole)
+            .build_runtime(NullHandler)
+            .unwrap();
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, ZERO_IDIOM_PUSHFD_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+
+        // ZF & PF set, CF & SF of the cmp cleared
+        assert_eq!(run_code(&mut runtime) & 0xd5, 0x44);
+    }
+}
+
+#[rustfmt::skip]
+const WRAPAROUND_CODE: &[u8] = &[
+    0xa1, 0xfe, 0xff, 0xff, 0xff,                         // mov eax, [0xfffffffe]
+    0x66, 0x8b, 0x1d, 0xff, 0xff, 0xff, 0xff,             // mov bx, [0xffffffff]
+    0x8a, 0x0d, 0xff, 0xff, 0xff, 0xff,                   // mov cl, [0xffffffff]
+    0xba, 0x11, 0x22, 0x33, 0x44,                         // mov edx, 0x44332211
+    0x89, 0x15, 0xfd, 0xff, 0xff, 0xff,                   // mov [0xfffffffd], edx
+    0x66, 0xc7, 0x05, 0xff, 0xff, 0xff, 0xff, 0x66, 0x55, // mov word [0xffffffff], 0x5566
+    0xc3,                                                 // ret
+];
+
+fn map_wraparound(runtime: &mut Runtime<NullHandler>) {
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, WRAPAROUND_CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    runtime
+        .map(0, Protection::READ_WRITE, &[0x00, 0x01, 0x02, 0x03])
+        .unwrap();
+    runtime
+        .map(
+            0xfffffffc,
+            Protection::READ_WRITE,
+            &[0xfc, 0xfd, 0xfe, 0xff],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+}
+
+#[test_log::test]
+fn address_wraparound() {
+    for bounds_checking in [false, true] {
+        let mut runtime = Recompiler::builder()
+            .bounds_checking(bounds_checking)
+            .address_wraparound(true)
+            .build_runtime(NullHandler)
+            .unwrap();
+        map_wraparound(&mut runtime);
+
+        assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+        let reg = |reg| runtime.context.get_gp_reg(reg);
+        assert_eq!(reg(FullSizeGeneralPurposeRegister::EAX), 0x0100fffe);
+        assert_eq!(reg(FullSizeGeneralPurposeRegister::EBX) & 0xffff, 0x00ff);
+        assert_eq!(reg(FullSizeGeneralPurposeRegister::ECX) & 0xff, 0xff);
+
+        assert_eq!(runtime.memory.read_u32(0xfffffffc), Ok(0x6622_11fc));
+        assert_eq!(runtime.memory.read_u32(0), Ok(0x0302_0155));
+    }
+}
+
+#[test_log::test]
+fn address_wraparound_guard() {
+    // without the wraparound the first load runs into the guard page after the reservation, not into the host memory
+    let mut runtime = Recompiler::builder()
+        .fault_sites(true)
+        .build_runtime(NullHandler)
+        .unwrap();
+    map_wraparound(&mut runtime);
+
+    assert_eq!(
+        runtime.run(CODE_ADDR),
+        ExitReason::Fault(GuestFault::PageFault)
+    );
+    assert_eq!(runtime.context.eip, CODE_ADDR);
+    // the first byte past the top, wrapped around
+    assert_eq!(runtime.context.fault_address, 0);
+}
+
+#[rustfmt::skip]
+const INT_CODE: &[u8] = &[
+    0x39, 0xc0,                         // cmp eax, eax
+    0xf9,                               // stc
+    0xcd, 0x21,                         // int 0x21
+    0x8b, 0x1d, 0x00, 0x30, 0x00, 0x00, // mov ebx, [0x3000]
+    0xcd, 0x21,                         // int 0x21
+    0xc3,                               // ret
+];
+
+#[rustfmt::skip]
+const INT_HANDLER: &[u8] = &[
+    0xff, 0x05, 0x00, 0x30, 0x00, 0x00, // inc dword [0x3000]
+    0xf8,                               // clc
+    0xcf,                               // iretd
+];
+
+#[test_log::test]
+fn guest_interrupts() {
+    let mut table = InterruptVectorTable::new();
+    table.set_handler(0x21, 0x2000);
+    table.clear_if = true;
+    let mut runtime = Recompiler::builder()
+        .interrupt_vectors(table)
+        .build_runtime(NullHandler)
+        .unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, INT_CODE)
+        .unwrap();
+    runtime
+        .map(0x2000, Protection::READ_EXECUTE, INT_HANDLER)
+        .unwrap();
+    runtime.map(0x3000, Protection::READ_WRITE, &[0]).unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    assert_eq!(runtime.memory.read_u32(0x3000), Ok(2));
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EBX),
+        1
+    );
+    // restored by iretd
+    assert!(runtime.context.get_flag(Flag::Carry));
+    assert!(runtime.context.get_flag(Flag::Zero));
+    assert_eq!(runtime.context.interrupt_flag, 1);
+}
+
+#[rustfmt::skip]
+const EXIT_CODE: &[u8] = &[
+    0x85, 0xc9,                   // test ecx, ecx
+    0x74, 0x06,                   // jz exit
+    0x49,                         // dec ecx
+    0xe8, 0xf6, 0xff, 0xff, 0xff, // call 0x1000
+    // exit:
+    0xbb, 0x07, 0x00, 0x00, 0x00, // mov ebx, 7
+    0xb8, 0xfc, 0x00, 0x00, 0x00, // mov eax, 252 (exit_group)
+    0xcd, 0x80,                   // int 0x80
+    0x0f, 0x0b,                   // ud2
+];
+
+struct ExitHandler;
+
+impl RuntimeHandler for ExitHandler {
+    fn interrupt(&mut self, ctx: &mut CpuContext, vector: u8) {
+        if !rusty_x86::handler::linux_exit_syscall(ctx, vector)
+            && !rusty_x86::win32::exit_process(ctx, vector)
+        {
+            ctx.exit = EXIT_HOST_REQUEST;
+        }
+    }
+}
+
+#[test_log::test]
+fn guest_exit() {
+    let mut runtime = Recompiler::builder().build_runtime(ExitHandler).unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, EXIT_CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    let top = STACK_ADDR + STACK_SIZE - 4;
+
+    // ECX calls deep, all of them left unfinished
+    for depth in [0, 3] {
+        prepare_context(&mut runtime.context);
+        runtime
+            .context
+            .set_gp_reg(FullSizeGeneralPurposeRegister::ECX, depth);
+        assert_eq!(runtime.run(CODE_ADDR), ExitReason::GuestExit { code: 7 });
+        assert_eq!(runtime.context.exit_code, 7);
+        assert_eq!(
+            runtime
+                .context
+                .get_gp_reg(FullSizeGeneralPurposeRegister::ESP),
+            top - 4 * depth
+        );
+        assert_eq!(runtime.context.eip, CODE_ADDR + EXIT_CODE.len() as u32 - 2);
+    }
+
+    // the process is gone, the runtime is not
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::ECX, 2);
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, top);
+    assert_eq!(
+        runtime.call_guest(CODE_ADDR, &[]),
+        Err(CallError::Exit(ExitReason::GuestExit { code: 7 }))
+    );
+
+    // ExitProcess(9) through the stub
+    runtime
+        .map(0x2000, Protection::READ_EXECUTE, EXIT_PROCESS_STUB)
+        .unwrap();
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, top);
+    assert_eq!(
+        runtime.call_guest(0x2000, &[9]),
+        Err(CallError::Exit(ExitReason::GuestExit { code: 9 }))
+    );
+}
+
+#[rustfmt::skip]
+const IRET_CODE: &[u8] = &[
+    0xf8,                               // clc
+    0x68, 0xff, 0xfd, 0xff, 0xff,       // push 0xfffffdff (everything but IF)
+    0x68, 0x00, 0x00, 0x00, 0x00,       // push 0
+    0x68, 0x00, 0x20, 0x00, 0x00,       // push 0x2000
+    0xcf,                               // iretd
+    0xb8, 0x01, 0x00, 0x00, 0x00,       // mov eax, 1
+    0xc3,                               // ret
+];
+
+#[rustfmt::skip]
+const IRET_MARKER: &[u8] = &[
+    0xb8, 0x02, 0x00, 0x00, 0x00,       // mov eax, 2
+    0xc3,                               // ret
+];
+
+#[test_log::test]
+fn iretd_frame() {
+    let mut runtime = Recompiler::builder().build_runtime(NullHandler).unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, IRET_CODE)
+        .unwrap();
+    runtime
+        .map(0x2000, Protection::READ_EXECUTE, IRET_MARKER)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    // nothing jumps there statically
+    runtime.add_entry_point(0x2000);
+    prepare_context(&mut runtime.context);
+
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+        2
+    );
+    assert!(runtime.context.get_flag(Flag::Carry));
+    assert!(runtime.context.get_flag(Flag::Direction));
+    assert_eq!(runtime.context.interrupt_flag, 0);
+}
+
+const BOOT_SECTOR: &[u8] = include_bytes!("fixtures/boot_sector.bin");
+
+#[test_log::test]
+fn bits16_boot_sector() {
+    const LOAD_ADDR: u32 = 0x7c00;
+    let mut segmentation = SegmentationPolicy::flat();
+    segmentation.set_segment(SegmentRegister::DS, SegmentDescriptor::real_mode(0x0800));
+    segmentation.set_segment(SegmentRegister::ES, SegmentDescriptor::real_mode(0x0900));
+    let mut table = InterruptVectorTable::new();
+    table.set_handler(0x40, 0x7c3a);
+    let mut runtime = Recompiler::builder()
+        .code_mode(CodeMode::Bits16)
+        .segmentation(segmentation)
+        .interrupt_vectors(table)
+        .build_runtime(NullHandler)
+        .unwrap();
+
+    // the stack is right under the code, in the same page
+    let mut page = vec![0; 0x1000];
+    page[0xc00..][..BOOT_SECTOR.len()].copy_from_slice(BOOT_SECTOR);
+    runtime
+        .map(0x7000, Protection::READ_WRITE_EXECUTE, &page)
+        .unwrap();
+    runtime
+        .map(0x8000, Protection::READ_WRITE, &[0; 0x2000])
+        .unwrap();
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, LOAD_ADDR);
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::EBX, 0xaaaa_0000);
+
+    // int 0x20 at the end goes to the host
+    assert_eq!(runtime.run(LOAD_ADDR), ExitReason::HostRequest);
+    assert_eq!(runtime.context.eip, 0x7c2d);
+    assert_eq!(runtime.memory.read_u16(0x900e), Ok(0x1234));
+    assert_eq!(runtime.memory.read_u32(0x8010), Ok(0xdeadbeef));
+    assert_eq!(runtime.memory.read_u8(0x8022), Ok(0x55));
+    assert_eq!(runtime.memory.read_u16(0x8030), Ok(0xbf10));
+    assert_eq!(runtime.memory.read_u16(0x8032), Ok(0xbef9));
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EBX),
+        0xaaaa_bf10
+    );
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::ESP),
+        LOAD_ADDR
+    );
+}
+
+const STACK16: &[u8] = include_bytes!("fixtures/stack16.bin");
+
+#[test_log::test]
+fn bits16_stack_segment() {
+    const LOAD_ADDR: u32 = 0x7c00;
+    let mut segmentation = SegmentationPolicy::flat();
+    segmentation.set_segment(SegmentRegister::SS, SegmentDescriptor::real_mode(0x1000));
+    segmentation.set_segment(SegmentRegister::DS, SegmentDescriptor::real_mode(0x0700));
+    let mut runtime = Recompiler::builder()
+        .code_mode(CodeMode::Bits16)
+        .segmentation(segmentation)
+        .build_runtime(NullHandler)
+        .unwrap();
+
+    let mut page = vec![0; 0x1000];
+    page[0xc00..][..STACK16.len()].copy_from_slice(STACK16);
+    runtime
+        .map(0x7000, Protection::READ_WRITE_EXECUTE, &page)
+        .unwrap();
+    // all of SS, nothing is mapped at the flat addresses of the stack
+    runtime
+        .map(0x10000, Protection::READ_WRITE, &[0; 0x10000])
+        .unwrap();
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::ESP, 0x1234_0000);
+
+    // int 0x20 at the end goes to the host
+    assert_eq!(runtime.run(LOAD_ADDR), ExitReason::HostRequest);
+    assert_eq!(runtime.context.eip, 0x7c13);
+    // the push at SP = 0 went to SS:fffe, the return address right under it
+    assert_eq!(runtime.memory.read_u16(0x1fffe), Ok(0xbeef));
+    assert_eq!(runtime.memory.read_u16(0x1fffc), Ok(0x7c10));
+    assert_eq!(runtime.memory.read_u16(0x7010), Ok(0xfffe));
+
+    let reg = |register| runtime.context.get_gp_reg(register);
+    assert_eq!(reg(FullSizeGeneralPurposeRegister::EBX) & 0xffff, 0xbeef);
+    assert_eq!(reg(FullSizeGeneralPurposeRegister::ECX) & 0xffff, 0xbeef);
+    assert_eq!(reg(FullSizeGeneralPurposeRegister::EDX) & 0xffff, 0x7c10);
+    // SP wrapped around both ways, the upper half of ESP stays
+    assert_eq!(reg(FullSizeGeneralPurposeRegister::ESP), 0x1234_0000);
+}
+
+const HELLO_COM: &[u8] = include_bytes!("fixtures/hello.com");
+
+fn dos_runtime(input: &[u8]) -> Runtime<DosHandler> {
+    Recompiler::builder()
+        .code_mode(CodeMode::Bits16)
+        .build_runtime(DosHandler::new(input))
+        .unwrap()
+}
+
+#[test_log::test]
+fn dos_hello_world() {
+    let mut runtime = dos_runtime(b"DOS\r");
+    let entry = runtime.load_com(HELLO_COM).unwrap();
+    assert_eq!(entry, COM_LOAD_OFFSET);
+
+    assert_eq!(runtime.run(entry), ExitReason::GuestExit { code: 3 });
+    assert_eq!(
+        String::from_utf8_lossy(&runtime.handler.output),
+        "Hello, world!\r\nName? DOS\r\nHi, DOS!"
+    );
+    // the name went into the program segment
+    let mut name = [0; 4];
+    runtime.memory.read_bytes(0x161, &mut name).unwrap();
+    assert_eq!(&name, b"DOS$");
+
+    // just the Enter
+    let mut runtime = dos_runtime(b"\r");
+    let entry = runtime.load_com(HELLO_COM).unwrap();
+    assert_eq!(runtime.run(entry), ExitReason::GuestExit { code: 0 });
+    assert!(String::from_utf8_lossy(&runtime.handler.output).ends_with("Name? \r\nHi, !"));
+}
+
+#[rustfmt::skip]
+const DOS_RET_CODE: &[u8] = &[
+    0xb2, 0x2a, // mov dl, '*'
+    0xb4, 0x02, // mov ah, 2
+    0xcd, 0x21, // int 0x21
+    0xb4, 0x30, // mov ah, 0x30 (get the DOS version, not supported)
+    0xcd, 0x21, // int 0x21
+    0xc3,       // ret
+];
+
+#[test_log::test]
+fn dos_com_layout() {
+    let mut runtime = dos_runtime(b"");
+    let entry = runtime.load_com(DOS_RET_CODE).unwrap();
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::ESP),
+        COM_INITIAL_SP
+    );
+    assert_eq!(runtime.memory.read_u16(COM_INITIAL_SP), Ok(0));
+    // int 20h at the start of the PSP
+    assert_eq!(runtime.memory.read_u16(0), Ok(0x20cd));
+
+    // the unsupported function stops the run right after its `int`
+    assert_eq!(runtime.run(entry), ExitReason::HostRequest);
+    assert_eq!(runtime.context.eip, entry + 10);
+    assert_eq!(runtime.handler.output, b"*");
+
+    assert!(runtime.load_com(&[0x90; COM_MAX_SIZE + 1]).is_err());
+    let mut runtime = Recompiler::builder()
+        .build_runtime(DosHandler::default())
+        .unwrap();
+    assert!(runtime.load_com(DOS_RET_CODE).is_err());
+}
+
+#[rustfmt::skip]
+const STRADDLING_CODE: &[u8] = &[
+    0x85, 0xc9,                         // test ecx, ecx
+    0x74, 0x01,                         // jz +1
+    0xc3,                               // ret
+    0xb8, 0x01, 0x00,                   // mov eax, 1 (cut short by the end of the region)
+];
+
+#[test_log::test]
+fn fetch_straddling_the_end() {
+    let mut runtime = Recompiler::builder().build_runtime(NullHandler).unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, STRADDLING_CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+
+    // not reached, no fault
+    prepare_context(&mut runtime.context);
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::ECX, 1);
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+
+    prepare_context(&mut runtime.context);
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::ECX, 0);
+    assert_eq!(
+        runtime.run(CODE_ADDR),
+        ExitReason::Fault(GuestFault::PageFault)
+    );
+    assert_eq!(runtime.context.eip, CODE_ADDR + 5);
+    // the first byte that's not there
+    assert_eq!(runtime.context.fault_address, CODE_ADDR + 8);
+    assert_eq!(runtime.context.fault_error_code, PF_INSTRUCTION_FETCH);
+}
+
+#[rustfmt::skip]
+const JUMP_TO_DATA_CODE: &[u8] = &[
+    0xe9, 0xfb, 0x1f, 0x00, 0x00,       // jmp 0x3000
+];
+
+#[test_log::test]
+fn fetch_from_data_page() {
+    let mut runtime = Recompiler::builder()
+        .fetch_faults(true)
+        .build_runtime(NullHandler)
+        .unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, JUMP_TO_DATA_CODE)
+        .unwrap();
+    // perfectly good code, just not executable
+    runtime
+        .map(0x3000, Protection::READ_WRITE, &[0xc3])
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+
+    assert_eq!(
+        runtime.run(CODE_ADDR),
+        ExitReason::Fault(GuestFault::PageFault)
+    );
+    assert_eq!(runtime.context.eip, 0x3000);
+    assert_eq!(runtime.context.fault_address, 0x3000);
+    assert_eq!(runtime.context.fault_error_code, PF_INSTRUCTION_FETCH);
+}
+
+#[rustfmt::skip]
+const GET_EIP_CODE: &[u8] = &[
+    0xe8, 0x00, 0x00, 0x00, 0x00,       // call next
+    0x58,                               // next: pop eax
+    0x89, 0xc1,                         // mov ecx, eax
+    0x8d, 0x40, 0x0e,                   // lea eax, [eax + 14] (target)
+    0xff, 0xe0,                         // jmp eax
+    0xbb, 0x01, 0x00, 0x00, 0x00,       // mov ebx, 1
+    0xc3,                               // ret
+    0xbb, 0x02, 0x00, 0x00, 0x00,       // target: mov ebx, 2
+    0xc3,                               // ret
+];
+
+#[test_log::test]
+fn get_eip() {
+    let mut runtime = Recompiler::builder().build_runtime(NullHandler).unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, GET_EIP_CODE)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    // the jmp eax target
+    runtime.add_entry_point(CODE_ADDR + 19);
+    prepare_context(&mut runtime.context);
+
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    let reg = |reg| runtime.context.get_gp_reg(reg);
+    assert_eq!(reg(FullSizeGeneralPurposeRegister::ECX), CODE_ADDR + 5);
+    assert_eq!(reg(FullSizeGeneralPurposeRegister::EAX), CODE_ADDR + 19);
+    assert_eq!(reg(FullSizeGeneralPurposeRegister::EBX), 2);
+    assert_eq!(
+        reg(FullSizeGeneralPurposeRegister::ESP),
+        STACK_ADDR + STACK_SIZE
+    );
+}
+
+#[rustfmt::skip]
+const MISALIGNED_CODE: &[u8] = &[
+    0xa1, 0x00, 0x30, 0x00, 0x00,       // mov eax, [0x3000]
+    0x8b, 0x1d, 0x01, 0x30, 0x00, 0x00, // mov ebx, [0x3001]
+    0xc3,                               // ret
+];
+
+#[test_log::test]
+fn alignment_checks() {
+    for (checks, flag, exit) in [
+        (true, 0, ExitReason::Returned),
+        (true, 1, ExitReason::Fault(GuestFault::AlignmentCheck)),
+        (false, 1, ExitReason::Returned),
+    ] {
+        let mut runtime = Recompiler::builder()
+            .alignment_checks(checks)
+            .build_runtime(NullHandler)
+            .unwrap();
+
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, MISALIGNED_CODE)
+            .unwrap();
+        runtime
+            .map(0x3000, Protection::READ_WRITE, &[1, 2, 3, 4, 5, 6, 7, 8])
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+        prepare_context(&mut runtime.context);
+        runtime.context.alignment_check = flag;
+
+        assert_eq!(runtime.run(CODE_ADDR), exit, "{} {}", checks, flag);
+        // the aligned load is fine either way
+        assert_eq!(
+            runtime
+                .context
+                .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+            0x04030201
+        );
+        if exit != ExitReason::Returned {
+            assert_eq!(runtime.context.eip, CODE_ADDR + 5);
+            assert_eq!(runtime.context.fault_address, 0x3001);
+            assert_eq!(
+                runtime
+                    .context
+                    .get_gp_reg(FullSizeGeneralPurposeRegister::EBX),
+                0
+            );
+        }
+    }
+}
+
+#[rustfmt::skip]
+const CR0_CODE: &[u8] = &[
+    0x0f, 0x20, 0xc0, // mov eax, cr0
+    0x0f, 0x22, 0xc0, // mov cr0, eax
+    0xc3,             // ret
+];
+
+#[test_log::test]
+fn control_registers() {
+    for (writes, exit) in [
+        (ControlRegisterWrites::Ignore, ExitReason::Returned),
+        (
+            ControlRegisterWrites::Fault,
+            ExitReason::Fault(GuestFault::GeneralProtection),
+        ),
+    ] {
+        let profile = SystemRegisterProfile {
+            cr0: 0x80000011,
+            writes,
+            ..SystemRegisterProfile::default()
+        };
+        let mut runtime = Recompiler::builder()
+            .system_registers(profile)
+            .build_runtime(NullHandler)
+            .unwrap();
+
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, CR0_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+        prepare_context(&mut runtime.context);
+
+        assert_eq!(runtime.run(CODE_ADDR), exit);
+        assert_eq!(
+            runtime
+                .context
+                .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+            0x80000011
+        );
+        if exit != ExitReason::Returned {
+            assert_eq!(runtime.context.eip, CODE_ADDR + 3);
+        }
+    }
+}
+
+#[rustfmt::skip]
+const RDMSR_CODE: &[u8] = &[
+    0x0f, 0x32, // rdmsr
+    0xc3,       // ret
+];
+
+#[test_log::test]
+fn msrs() {
+    for (index, exit) in [
+        (0xce, ExitReason::Returned),
+        (0x10, ExitReason::Fault(GuestFault::GeneralProtection)),
+    ] {
+        let mut profile = SystemRegisterProfile {
+            unknown_msrs: UnknownMsrReads::Fault,
+            ..SystemRegisterProfile::default()
+        };
+        profile.set_msr(0xce, 0x1122334455667788);
+        let mut runtime = Recompiler::builder()
+            .system_registers(profile)
+            .build_runtime(NullHandler)
+            .unwrap();
+
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, RDMSR_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+        prepare_context(&mut runtime.context);
+        runtime
+            .context
+            .set_gp_reg(FullSizeGeneralPurposeRegister::ECX, index);
+
+        assert_eq!(runtime.run(CODE_ADDR), exit);
+        let reg = |reg| runtime.context.get_gp_reg(reg);
+        if exit == ExitReason::Returned {
+            assert_eq!(reg(FullSizeGeneralPurposeRegister::EDX), 0x11223344);
+            assert_eq!(reg(FullSizeGeneralPurposeRegister::EAX), 0x55667788);
+        } else {
+            assert_eq!(runtime.context.eip, CODE_ADDR);
+        }
+    }
+}
+
+#[rustfmt::skip]
+const CLFLUSH_CODE: &[u8] = &[
+    0x0f, 0xae, 0x38, // clflush [eax]
+    0x0f, 0x09,       // wbinvd
+    0xc3,             // ret
+];
+
+#[derive(Default)]
+struct FlushRecorder(Vec<u32>);
+
+impl RuntimeHandler for FlushRecorder {
+    fn cache_flush(&mut self, _: &mut CpuContext, address: u32) {
+        self.0.push(address);
+    }
+}
+
+#[test_log::test]
+fn cache_maintenance() {
+    // (the flushed address, user mode) -> the exit & what the handler saw
+    for (address, user_mode, exit, flushed) in [
+        (STACK_ADDR, false, ExitReason::Returned, vec![STACK_ADDR]),
+        (
+            0x20000,
+            false,
+            ExitReason::Fault(GuestFault::PageFault),
+            vec![],
+        ),
+        (
+            STACK_ADDR,
+            true,
+            ExitReason::Fault(GuestFault::GeneralProtection),
+            vec![STACK_ADDR],
+        ),
+    ] {
+        let mut runtime = Recompiler::builder()
+            .fault_sites(true)
+            .system_registers(SystemRegisterProfile {
+                user_mode,
+                ..SystemRegisterProfile::default()
+            })
+            .build_runtime(FlushRecorder::default())
+            .unwrap();
+
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, CLFLUSH_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+        prepare_context(&mut runtime.context);
+        runtime
+            .context
+            .set_gp_reg(FullSizeGeneralPurposeRegister::EAX, address);
+
+        assert_eq!(runtime.run(CODE_ADDR), exit);
+        assert_eq!(runtime.handler.0, flushed);
+        match exit {
+            ExitReason::Fault(GuestFault::PageFault) => {
+                assert_eq!(runtime.context.eip, CODE_ADDR);
+                assert_eq!(runtime.context.fault_address, 0x20000);
+            }
+            ExitReason::Fault(_) => assert_eq!(runtime.context.eip, CODE_ADDR + 3),
+            _ => {}
+        }
+    }
+}
+
+#[rustfmt::skip]
+const CPUID_CODE: &[u8] = &[
+    0x85, 0xc0,                   // test eax, eax
+    0x74, 0x02,                   // jz skip
+    0x0f, 0xa2,                   // cpuid
+    0xbb, 0x01, 0x00, 0x00, 0x00, // skip: mov ebx, 1
+    0xc3,                         // ret
+];
+
+#[test_log::test]
+fn unsupported_instruction_trap() {
+    for (eax, exit) in [
+        (0, ExitReason::Returned),
+        (
+            1,
+            ExitReason::Fault(GuestFault::Unimplemented {
+                eip: CODE_ADDR + 4,
+                bytes: InstructionBytes::new(&[0x0f, 0xa2]),
+            }),
+        ),
+    ] {
+        // the block with cpuid in it still translates
+        let mut runtime = Recompiler::builder()
+            .unsupported_instructions(UnsupportedInstructionPolicy::Trap)
+            .build_runtime(NullHandler)
+            .unwrap();
+
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, CPUID_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+        prepare_context(&mut runtime.context);
+        runtime
+            .context
+            .set_gp_reg(FullSizeGeneralPurposeRegister::EAX, eax);
+
+        assert_eq!(runtime.run(CODE_ADDR), exit);
+        if eax == 0 {
+            assert_eq!(
+                runtime
+                    .context
+                    .get_gp_reg(FullSizeGeneralPurposeRegister::EBX),
+                1
+            );
+        } else {
+            assert_eq!(runtime.context.eip, CODE_ADDR + 4);
+        }
+    }
+}
+
+#[rustfmt::skip]
+const PORT_STRING_CODE: &[u8] = &[
+    0x85, 0xc0,                   // test eax, eax
+    0x74, 0x10,                   // jz skip
+    0xbe, 0x1a, 0x10, 0x00, 0x00, // mov esi, data
+    0xb9, 0x03, 0x00, 0x00, 0x00, // mov ecx, 3
+    0x66, 0xba, 0x20, 0x00,       // mov dx, 0x20
+    0xf3, 0x6e,                   // rep outsb
+    0xbb, 0x01, 0x00, 0x00, 0x00, // skip: mov ebx, 1
+    0xc3,                         // ret
+    b'a', b'b', b'c',             // data
+];
+
+#[derive(Default)]
+struct PortRecorder {
+    written: Vec<(u16, u8)>,
+}
+
+impl RuntimeHandler for PortRecorder {
+    fn port_out(&mut self, _: &mut CpuContext, port: u16, _: IntType, value: u32) {
+        self.written.push((port, value as u8));
+    }
+}
+
+#[test_log::test]
+fn port_string_trap() {
+    // ins & outs are translated, not left to the unsupported instruction policy
+    for eax in [0, 1] {
+        let mut runtime = Recompiler::builder()
+            .unsupported_instructions(UnsupportedInstructionPolicy::Trap)
+            .build_runtime(PortRecorder::default())
+            .unwrap();
+
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, PORT_STRING_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+        prepare_context(&mut runtime.context);
+        runtime
+            .context
+            .set_gp_reg(FullSizeGeneralPurposeRegister::EAX, eax);
+
+        assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+        assert_eq!(
+            runtime
+                .context
+                .get_gp_reg(FullSizeGeneralPurposeRegister::EBX),
+            1
+        );
+        let expected = match eax {
+            0 => vec![],
+            _ => vec![(0x20, b'a'), (0x20, b'b'), (0x20, b'c')],
+        };
+        assert_eq!(runtime.handler.written, expected);
+    }
+}
+
+#[rustfmt::skip]
+const FUNCTIONS_CODE: &[u8] = &[
+    0xb8, 0x01, 0x00, 0x00, 0x00, // 0x1000: mov eax, 1
+    0xc3,                         //         ret
+    0x0f, 0xa2,                   // 0x1006: cpuid
+    0xc3,                         //         ret
+    0x8b, 0x44, 0x24, 0x04,       // 0x1009: mov eax, [esp + 4]
+    0x01, 0xc0,                   //         add eax, eax
+    0xc3,                         //         ret
+];
+
+#[test_log::test]
+fn translate_many() {
+    for threads in [1, 2] {
+        let dir =
+            std::env::temp_dir().join(format!("rusty-x86-many-{}-{}", std::process::id(), threads));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut runtime = Recompiler::builder()
+            .translation_cache(&dir)
+            .translation_threads(threads)
+            .build_runtime(NullHandler)
+            .unwrap();
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, FUNCTIONS_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+        prepare_context(&mut runtime.context);
+
+        let (one, cpuid, double) = (CODE_ADDR, CODE_ADDR + 6, CODE_ADDR + 9);
+        let mut progress = Vec::new();
+        let report = runtime.translate_many(&[one, cpuid, double], |p| progress.push(p));
+
+        // cpuid fails the decoding with the default policy, the rest is fine
+        assert_eq!(report.errors.len(), 1);
+        let (entry, error) = &report.errors[0];
+        assert_eq!(*entry, cpuid);
+        assert_eq!(
+            *error,
+            TranslateFailure::Decode(DecodeError::Unsupported {
+                ip: cpuid,
+                mnemonic: iced_x86::Mnemonic::Cpuid
+            })
+        );
+        assert_eq!(report.blocks, 2);
+        assert_eq!(report.guest_bytes, 6 + 7);
+        assert_eq!(
+            progress
+                .iter()
+                .map(|p| (p.done, p.total))
+                .collect::<Vec<_>>(),
+            [(1, 3), (2, 3), (3, 3)]
+        );
+        assert_eq!(progress[2].failed, 1);
+        if threads == 1 {
+            assert_eq!(
+                progress
+                    .iter()
+                    .map(|p| (p.failed, p.current))
+                    .collect::<Vec<_>>(),
+                [(0, one), (1, cpuid), (1, double)]
+            );
+        } else {
+            let mut done: Vec<_> = progress.iter().map(|p| p.current).collect();
+            done.sort();
+            assert_eq!(done, [one, cpuid, double]);
+        }
+        assert_eq!(runtime.config().entry_points, [one, double]);
+
+        // translated once, and kept: both calls load it from the cache
+        let stats = runtime.cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (0, 0));
+        assert_ne!(stats.bytes_written, 0);
+        assert_eq!(runtime.call_guest(one, &[]), Ok(1));
+        assert_eq!(runtime.call_guest(double, &[21]), Ok(42));
+        let stats = runtime.cache_stats().unwrap();
+        assert_eq!((stats.hits, stats.misses), (2, 0));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[rustfmt::skip]
+const STORE_CODE: &[u8] = &[
+    0xb8, 0x01, 0x00, 0x00, 0x00, // mov eax, 1
+    0xa3, 0xfe, 0x9f, 0x00, 0x00, // mov [0x9ffe], eax
+    0xc3,                         // ret
+];
+
+fn run_store(policy: SegmentationPolicy) -> (ExitReason, CpuContext) {
+    let mut runtime = Recompiler::builder()
+        .opt_level(OptLevel::None)
+        .segmentation(policy)
+        .build_runtime(NullHandler)
+        .unwrap();
+
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, STORE_CODE)
+        .unwrap();
+    runtime
+        .map(0x9000, Protection::READ_WRITE, &[0; 0x2000])
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+    prepare_context(&mut runtime.context);
+
+    let reason = runtime.run(CODE_ADDR);
+    (reason, runtime.context)
+}
+
+#[test_log::test]
+fn segment_limit() {
+    let mut policy = SegmentationPolicy::checked();
+    policy.set_segment(
+        SegmentRegister::DS,
+        SegmentDescriptor::new(0, 0x9fff, Protection::READ_WRITE),
+    );
+
+    // the dword store crosses the limit
+    let (reason, ctx) = run_store(policy);
+    assert_eq!(reason, ExitReason::Fault(GuestFault::GeneralProtection));
+    assert_eq!(ctx.exit, EXIT_FAULT);
+    assert_eq!(ctx.eip, CODE_ADDR + 5);
+    assert_eq!(ctx.fault_vector, 13);
+    assert_eq!(ctx.fault_address, 0x9ffe);
+
+    // no limits when flat
+    let (reason, ctx) = run_store(SegmentationPolicy::flat());
+    assert_eq!(reason, ExitReason::Returned);
+    assert_eq!(ctx.get_gp_reg(FullSizeGeneralPurposeRegister::EAX), 1);
+}
+
+fn guest_memory() -> GuestMemory {
+    let mut memory = GuestMemory::with_size(0x10000).unwrap();
+    memory
+        .map(0x2000, Protection::READ_WRITE, &[0; 0x2000])
+        .unwrap();
+    memory.map(0x4000, Protection::READ, b"hello\0").unwrap();
+    memory
+}
+
+#[test_log::test]
+fn guest_memory_endianness() {
+    let mut memory = guest_memory();
+
+    memory.write_u32(0x2000, 0x12345678).unwrap();
+    let mut bytes = [0; 4];
+    memory.read_bytes(0x2000, &mut bytes).unwrap();
+    assert_eq!(bytes, [0x78, 0x56, 0x34, 0x12]);
+    assert_eq!(memory.read_u16(0x2001), Ok(0x3456));
+    assert_eq!(memory.read_u8(0x2003), Ok(0x12));
+
+    memory.write_u64(0x2008, 0x1122334455667788).unwrap();
+    assert_eq!(memory.read_u64(0x2008), Ok(0x1122334455667788));
+    assert_eq!(memory.read_u32(0x200c), Ok(0x11223344));
+}
+
+#[test_log::test]
+fn guest_memory_boundaries() {
+    let mut memory = guest_memory();
+
+    // both pages are mapped
+    memory.write_u32(0x2ffe, 0xaabbccdd).unwrap();
+    assert_eq!(memory.read_u32(0x2ffe), Ok(0xaabbccdd));
+
+    // into the read-only page
+    assert_eq!(memory.read_u32(0x3ffe), Ok(0x6568_0000));
+    assert_eq!(
+        memory.write_u32(0x3ffe, 0),
+        Err(MemoryAccessError { address: 0x4000 })
+    );
+    // not even partially written
+    assert_eq!(memory.read_u16(0x3ffe), Ok(0));
+
+    // into the unmapped one
+    assert_eq!(
+        memory.read_u32(0x4ffe),
+        Err(MemoryAccessError { address: 0x5000 })
+    );
+    assert_eq!(
+        memory.read_u8(0x1fff),
+        Err(MemoryAccessError { address: 0x1fff })
+    );
+    // past the end of the address space
+    assert_eq!(
+        memory.read_u32(0xfffe),
+        Err(MemoryAccessError { address: 0xfffe })
+    );
+}
+
+#[test_log::test]
+fn guest_memory_patch() {
+    let mut memory = guest_memory();
+    memory.map(0x5000, Protection::empty(), &[0x11; 4]).unwrap();
+
+    // across the writable page into the read-only one, all at once
+    memory.write_u16(0x3ffe, 0xbbaa).unwrap();
+    assert_eq!(
+        memory.patch_bytes(0x3ffe, b"__J"),
+        Ok(vec![0xaa, 0xbb, b'h'])
+    );
+    assert_eq!(memory.read_cstr(0x3ffe, 16).as_deref(), Ok("__Jello"));
+    // and the guest still can't write there
+    assert_eq!(
+        memory.write_u8(0x4000, 0),
+        Err(MemoryAccessError { address: 0x4000 })
+    );
+
+    // a page with no access at all
+    assert_eq!(memory.patch_bytes(0x5000, &[0x22]), Ok(vec![0x11]));
+    assert_eq!(
+        memory.read_u8(0x5000),
+        Err(MemoryAccessError { address: 0x5000 })
+    );
+    assert_eq!(memory.patch_bytes(0x5000, &[0x11]), Ok(vec![0x22]));
+
+    // the pages still have to be mapped
+    assert_eq!(
+        memory.patch_bytes(0x5ffe, &[0; 4]),
+        Err(MemoryAccessError { address: 0x6000 })
+    );
+}
+
+#[test_log::test]
+fn guest_memory_strings() {
+    let mut memory = guest_memory();
+
+    assert_eq!(memory.read_cstr(0x4000, 100), Ok("hello".to_string()));
+    assert_eq!(memory.read_cstr(0x4000, 3), Ok("hel".to_string()));
+
+    // the terminator is right at the end of the mapping
+    memory
+        .map(0x8000, Protection::READ_WRITE, &[0; 0x1000])
+        .unwrap();
+    memory.write_bytes(0x8ffd, b"ab\0").unwrap();
+    assert_eq!(memory.read_cstr(0x8ffd, 100), Ok("ab".to_string()));
+    // no terminator before the unmapped page
+    memory.write_bytes(0x8ffd, b"abc").unwrap();
+    assert_eq!(memory.read_cstr(0x8ffd, 3), Ok("abc".to_string()));
+    assert_eq!(
+        memory.read_cstr(0x8ffd, 100),
+        Err(MemoryAccessError { address: 0x9000 })
+    );
+    // continues into the next mapped page
+    memory.write_bytes(0x3ffd, b"abc").unwrap();
+    assert_eq!(memory.read_cstr(0x3ffd, 100), Ok("abchello".to_string()));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+struct Timespec {
+    sec: u32,
+    nsec: u32,
+}
+
+unsafe impl FromBytes for Timespec {}
+
+#[test_log::test]
+fn guest_memory_pod() {
+    let mut memory = guest_memory();
+
+    memory
+        .write_bytes(0x2ff8, &[1, 0, 0, 0, 2, 0, 0, 0])
+        .unwrap();
+    assert_eq!(
+        memory.read_pod::<Timespec>(0x2ff8),
+        Ok(Timespec { sec: 1, nsec: 2 })
+    );
+    assert_eq!(memory.read_pod::<[u16; 2]>(0x2ffc), Ok([2, 0]));
+    assert_eq!(
+        memory.read_pod::<Timespec>(0x4ffc),
+        Err(MemoryAccessError { address: 0x5000 })
+    );
+}
+
+#[rustfmt::skip]
+const BRANCHY_CODE: &[u8] = &[
+    0xb9, 0x03, 0x00, 0x00, 0x00, // mov ecx, 3
+    0x31, 0xc0,                   // xor eax, eax
+    0xf6, 0xc1, 0x01,             // 0x1007: test cl, 1
+    0x74, 0x01,                   // je 0x100d
+    0x40,                         // inc eax
+    0x49,                         // 0x100d: dec ecx
+    0x75, 0xf7,                   // jne 0x1007
+    0xc3,                         // ret
+];
+
+#[test_log::test]
+fn coverage() {
+    for block_chaining in [true, false] {
+        let mut runtime = Recompiler::builder()
+            .block_chaining(block_chaining)
+            .coverage(true)
+            .build_runtime(NullHandler)
+            .unwrap();
+        runtime
+            .map(CODE_ADDR, Protection::READ_EXECUTE, BRANCHY_CODE)
+            .unwrap();
+        runtime
+            .map(
+                STACK_ADDR,
+                Protection::READ_WRITE,
+                &[0; STACK_SIZE as usize],
+            )
+            .unwrap();
+        prepare_context(&mut runtime.context);
+
+        assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+        assert_eq!(
+            runtime
+                .context
+                .get_gp_reg(FullSizeGeneralPurposeRegister::EAX),
+            2
+        );
+
+        // the code after a jcc counts on its own, whichever translated block it ends up in
+        let coverage = runtime.coverage();
+        let hits: Vec<_> = coverage
+            .blocks
+            .iter()
+            .map(|(&address, block)| (address, block.size, block.hits))
+            .collect();
+        assert_eq!(
+            hits,
+            vec![
+                (0x1000, 12, 1),
+                (0x1007, 5, 2),
+                (0x100c, 4, 2),
+                (0x100d, 3, 1),
+                (0x1010, 1, 1),
+            ],
+            "block_chaining = {}",
+            block_chaining
+        );
+
+        let mut drcov = Vec::new();
+        coverage.write_drcov(&mut drcov).unwrap();
+        let header = "\
+DRCOV VERSION: 2
+DRCOV FLAVOR: drcov
+Module Table: version 2, count 1
+Columns: id, base, end, entry, checksum, timestamp, path
+0, 0x00001000, 0x00001011, 0x0000000000000000, 0x00000000, 0x00000000, region_00001000
+BB Table: 5 bbs
+";
+        assert_eq!(&drcov[..header.len()], header.as_bytes());
+        let records = &drcov[header.len()..];
+        assert_eq!(records.len(), 5 * 8);
+        // 0x1007, 5 bytes, module 0
+        assert_eq!(
+            &records[8..16],
+            &[0x07, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00]
+        );
+
+        // accumulates over the runs until reset
+        prepare_context(&mut runtime.context);
+        assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+        assert_eq!(runtime.coverage().hits(0x1007), 4);
+        runtime.reset_coverage();
+        assert!(runtime.coverage().blocks.is_empty());
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test_log::test]
+fn external_buffer() {
+    const BUFFER_ADDR: u32 = 0x20000;
+
+    #[rustfmt::skip]
+    let code = &[
+        0xbf, 0x00, 0x00, 0x02, 0x00, // mov edi, BUFFER_ADDR
+        0xb9, 0x00, 0x04, 0x00, 0x00, // mov ecx, 0x400
+        0xb8, 0xef, 0xbe, 0xad, 0xde, // mov eax, 0xdeadbeef
+        0xf3, 0xab,                   // rep stosd
+        0x8b, 0x1d, 0x00, 0x10, 0x02, 0x00, // mov ebx, [BUFFER_ADDR + 0x1000]
+        0xc3,                         // ret
+    ];
+
+    let mut runtime = Recompiler::builder().build_runtime(NullHandler).unwrap();
+    runtime
+        .map(CODE_ADDR, Protection::READ_EXECUTE, code)
+        .unwrap();
+    runtime
+        .map(
+            STACK_ADDR,
+            Protection::READ_WRITE,
+            &[0; STACK_SIZE as usize],
+        )
+        .unwrap();
+
+    let mut buffer = ExternalBuffer::new(0x1800).unwrap();
+    assert_eq!(buffer.len(), 0x2000);
+    // the host fills the second page before the guest runs
+    unsafe { buffer.as_mut_slice()[0x1000..0x1004].copy_from_slice(&[1, 2, 3, 4]) };
+    runtime
+        .memory
+        .map_external(BUFFER_ADDR, Protection::READ_WRITE, &buffer)
+        .unwrap();
+
+    prepare_context(&mut runtime.context);
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EBX),
+        0x04030201
+    );
+
+    // the guest wrote the first page through its mapping, the host sees it in the buffer
+    let bytes = unsafe { buffer.as_slice() };
+    assert!(bytes[..0x1000]
+        .chunks(4)
+        .all(|chunk| chunk == 0xdeadbeefu32.to_le_bytes()));
+    assert_eq!(&bytes[0x1000..0x1004], &[1, 2, 3, 4]);
+    // and the other way around: the same bytes, not a copy
+    let slice = runtime.memory.host_slice(BUFFER_ADDR + 0xffc, 8).unwrap();
+    assert_eq!(slice, &[0xef, 0xbe, 0xad, 0xde, 1, 2, 3, 4]);
+    slice[0] = 0x42;
+    assert_eq!(unsafe { buffer.as_slice()[0xffc] }, 0x42);
+
+    // the code might still use it, so it can't be replaced
+    assert!(runtime
+        .map(BUFFER_ADDR + 0x1000, Protection::READ_WRITE, &[0; 4])
+        .is_err());
+    let other = ExternalBuffer::new(0x1000).unwrap();
+    assert!(runtime
+        .memory
+        .map_external(BUFFER_ADDR, Protection::READ_WRITE, &other)
+        .is_err());
+    // neither can the code go there
+    assert!(runtime
+        .memory
+        .map_external(0x30000, Protection::READ_EXECUTE, &other)
+        .is_err());
+    assert!(runtime
+        .memory
+        .map_external(0x30800, Protection::READ_WRITE, &other)
+        .is_err());
+
+    // the guest mapping outlives the host one
+    drop(buffer);
+    runtime
+        .context
+        .set_gp_reg(FullSizeGeneralPurposeRegister::EBX, 0);
+    prepare_context(&mut runtime.context);
+    assert_eq!(runtime.run(CODE_ADDR), ExitReason::Returned);
+    assert_eq!(runtime.memory.read_u32(BUFFER_ADDR).unwrap(), 0xdeadbeef);
+    assert_eq!(
+        runtime
+            .context
+            .get_gp_reg(FullSizeGeneralPurposeRegister::EBX),
+        0x04030201
+    );
+
+    // only the read-write guest memory is lent out
+    assert!(runtime.memory.host_slice(CODE_ADDR, 4).is_none());
+    assert!(runtime.memory.host_slice(BUFFER_ADDR + 0x1ffc, 8).is_none());
+}
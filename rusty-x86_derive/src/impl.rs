@@ -1,4 +1,4 @@
-use proc_macro2::{Ident, Span, TokenStream};
+use proc_macro2::{Ident, Span, TokenStream, TokenTree};
 use quote::{quote, ToTokens, TokenStreamExt};
 use syn::parse::{Parse, ParseStream, Parser};
 use syn::punctuated::Punctuated;
@@ -19,6 +19,8 @@ struct TestSnippet {
     asm: TokenStream,
     _bracket_token: token::Bracket,
     flags: Vec<CpuFlag>,
+    /// `=> DivideError`: the snippet has to stop with this `GuestFault` instead
+    fault: Option<Ident>,
 }
 
 struct Arg {
@@ -87,6 +89,11 @@ impl Parse for TestSnippet {
             asm: asm.parse()?,
             _bracket_token: bracketed!(flags in input),
             flags: flags.call(parse_flags)?,
+            fault: if input.parse::<Option<Token![=>]>>()?.is_some() {
+                Some(input.parse()?)
+            } else {
+                None
+            },
         })
     }
 }
@@ -202,6 +209,24 @@ impl Parse for TestPeFunction {
     }
 }
 
+/// `; checkpoint` is an instruction of its own: `int VECTOR_CHECKPOINT`, which dynasm can't encode (the imm8 is signed)
+fn expand_checkpoints(asm: &TokenStream) -> TokenStream {
+    let mut res = TokenStream::new();
+    let mut after_semicolon = false;
+    for token in asm.clone() {
+        match &token {
+            TokenTree::Ident(id) if after_semicolon && id == "checkpoint" => {
+                res.append_all(quote! {
+                    .bytes rusty_x86::handler::CHECKPOINT_INSTRUCTION.iter().copied()
+                });
+            }
+            _ => res.append(token.clone()),
+        }
+        after_semicolon = matches!(&token, TokenTree::Punct(p) if p.as_char() == ';');
+    }
+    res
+}
+
 impl ToTokens for CpuFlag {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let id = Ident::new(&format!("{:?}", self), Span::call_site());
@@ -214,9 +239,21 @@ impl ToTokens for CpuFlag {
 impl ToTokens for TestSnippet {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let name = &self.name;
-        let code = &self.asm;
+        let code = expand_checkpoints(&self.asm);
         let flags = &self.flags;
 
+        let test = match &self.fault {
+            None => quote! {
+                crate::common::test_code(crate::common::CodeToTest::Snippet(code.as_slice()), vec![#(#flags),*]);
+            },
+            Some(fault) => quote! {
+                crate::common::test_code_fault(
+                    crate::common::CodeToTest::Snippet(code.as_slice()),
+                    rusty_x86::handler::GuestFault::#fault,
+                );
+            },
+        };
+
         tokens.append_all(quote! {
              #[test_log::test]
              fn #name() {
@@ -224,7 +261,7 @@ impl ToTokens for TestSnippet {
                  let code = rusty_x86::assemble_x86!(
                      #code
                  );
-                 crate::common::test_code(crate::common::CodeToTest::Snippet(code.as_slice()), vec![#(#flags),*]);
+                 #test
              }
         });
     }
@@ -233,7 +270,7 @@ impl ToTokens for TestSnippet {
 impl ToTokens for TestFunction {
     fn to_tokens(&self, tokens: &mut TokenStream) {
         let name = &self.name;
-        let asm = &self.asm;
+        let asm = expand_checkpoints(&self.asm);
 
         let args: Vec<TokenStream> = self.args.iter().map(|args| {
             let name = &args.name;